@@ -1,40 +1,89 @@
 use citadel_apps::cli;
-#[cfg(all(feature = "umbrel", feature = "dev-tools"))]
-use citadel_apps::composegenerator::umbrel::types::Metadata as UmbrelMetadata;
+use citadel_apps::cli::single_app::{convert_single_app, ConversionContext};
 #[cfg(feature = "dev-tools")]
 use citadel_apps::{
     cli::dev_tools::update_app_file,
-    composegenerator::{
-        compose::types::ComposeSpecification,
-        convert_config, load_config,
-        types::ResultYml,
-        v3::{convert::v3_to_v4, types::SchemaItemContainers},
-        v4::types::AppYml,
-    },
+    composegenerator::{load_config, v3::convert::v3_to_v4, v4::diagnostics::Severity},
 };
-use clap::{Parser, Subcommand};
-#[cfg(any(feature = "umbrel", feature = "dev-tools"))]
+use clap::{CommandFactory, Parser, Subcommand};
 use std::path::Path;
 #[cfg(feature = "dev-tools")]
+use std::path::PathBuf;
+#[cfg(feature = "dev-tools")]
 use std::process::exit;
 
 #[derive(Subcommand, Debug)]
 enum SubCommand {
     /// Convert a citadel app.yml to a result.yml file
     Convert {
-        /// The citadel root dir
+        /// The citadel root dir, or - to convert a single app.yml from stdin to a docker-compose.yml on stdout
         citadel_root: String,
         /// The URL the Caddy admin api is listing on
         #[clap(short, long)]
         caddy_url: Option<String>,
+        /// Write state and outputs to this directory instead of the Citadel root
+        /// (for example if the Citadel root is mounted read-only)
+        #[clap(long)]
+        state_dir: Option<String>,
+        /// What to do if ports of installed apps need to change to make room for other apps
+        #[clap(long, value_enum, default_value_t)]
+        port_changes: cli::port_review::PortChangePolicy,
+        /// The app's ID (only when reading from stdin)
+        #[clap(long)]
+        app_id: Option<String>,
+        /// A JSON file with the port map, IP addresses and installed services (only when reading from stdin)
+        #[clap(long)]
+        context: Option<String>,
+        /// An IP address in the form NAME=IP (only when reading from stdin)
+        #[clap(long)]
+        ip: Vec<String>,
+        /// A port in the form container:internal_port:public_port (only when reading from stdin)
+        #[clap(long)]
+        port: Vec<String>,
+        /// An installed app or service (only when reading from stdin)
+        #[clap(long)]
+        installed_service: Vec<String>,
+        /// Only write the compose files of some apps, ports and IPs are still assigned for all apps
+        #[clap(flatten)]
+        filter: cli::app_filter::AppFilter,
+        /// Only convert this app, reusing the ports and IPs of the last conversion.
+        /// Fails if other apps would need new ones.
+        #[clap(long, conflicts_with = "app_id")]
+        app: Option<String>,
+        /// Run the whole conversion, but only print the files that would change instead of writing them
+        #[clap(long, conflicts_with = "app_id")]
+        dry_run: bool,
+        /// Fail without writing anything if any app fails to convert
+        #[clap(long, conflicts_with = "best_effort")]
+        strict: bool,
+        /// Skip apps that fail to convert and convert the others (the default unless app-manager.toml changes it)
+        #[clap(long)]
+        best_effort: bool,
+    },
+    /// Show how a conversion would change the generated files, as a unified diff
+    Diff {
+        /// The Citadel root directory
+        citadel_root: String,
+        /// Compare against the state directory the node uses instead of the Citadel root
+        #[clap(long)]
+        state_dir: Option<String>,
+        /// Only compare the compose files of some apps, ports and IPs are still assigned for all apps
+        #[clap(flatten)]
+        filter: cli::app_filter::AppFilter,
+        /// Only convert this app, reusing the ports and IPs of the last conversion
+        #[clap(long)]
+        app: Option<String>,
     },
     /// Get a JSON schema for the app.yml format
-    #[cfg(feature = "dev-tools")]
+    #[cfg(feature = "schema")]
     Schema {
         /// The version of the app.yml format to get the schema for
         /// (defaults to 4)
         #[clap(short, long, default_value = "4")]
         version: String,
+        /// Print the schema as YAML or JSON
+        #[clap(long, value_enum, default_value_t)]
+        format: cli::schema::SchemaFormat,
     },
     /// Convert an Umbrel app (by app directory path) to a Citadel app.yml file
     /// Manual fixes may be required to make the app.yml work
@@ -43,14 +92,60 @@ enum SubCommand {
         /// The app directory to run this on
         app_dir: String,
     },
-    /// Validate a Citadel app.yml file and check if it could be parsed & converted
+    /// Validate a Citadel app.yml file and list all problems that prevent converting it
     #[cfg(feature = "dev-tools")]
     Validate {
-        /// The app file to run this on
+        /// The app.yml or app directory to run this on
         app: String,
-        /// The app's ID
+        /// The app's ID, defaults to the name of the app directory
         #[clap(short, long)]
-        app_name: String,
+        app_name: Option<String>,
+    },
+    /// Parse an app.yml, serialize it again and show what changed,
+    /// to find fields the app manager drops or changes
+    #[cfg(feature = "dev-tools")]
+    Roundtrip {
+        /// The app.yml or app directory to run this on
+        app: String,
+    },
+    /// List deprecated fields in app.yml files, and optionally replace them
+    #[cfg(feature = "dev-tools")]
+    Lint {
+        /// App files, app directories or directories containing apps
+        #[clap(required = true)]
+        apps: Vec<String>,
+        /// Rewrite the files to use the replacements, comments and formatting are kept
+        #[clap(long)]
+        fix: bool,
+        #[clap(flatten)]
+        filter: cli::app_filter::AppFilter,
+    },
+    /// Render the Jinja templates of an app against a simulated node,
+    /// to preview them or to test them against expected outputs
+    #[cfg(feature = "dev-tools")]
+    RenderConfig {
+        /// The app directory
+        app_dir: String,
+        /// A YAML file describing the simulated node (env, installedServices and seed)
+        #[clap(long)]
+        env: Option<String>,
+        /// Where to write the rendered files, defaults to .preview in the app directory
+        #[clap(long)]
+        output_dir: Option<String>,
+        /// Compare the rendered files to the expected outputs and fail if they differ
+        #[clap(long)]
+        check: bool,
+        /// The expected outputs, defaults to the environment file's path without extension
+        /// (or tests/expected in the app directory without an environment file)
+        #[clap(long)]
+        expected: Option<String>,
+    },
+    /// Run all checks an app store needs on a submitted app and print a JSON verdict,
+    /// exits with an error if any check fails
+    #[cfg(feature = "dev-tools")]
+    CiCheck {
+        /// The app directory
+        app_dir: String,
     },
     /// Update the app inside an app.yml to its latest version
     #[cfg(feature = "dev-tools")]
@@ -86,6 +181,20 @@ enum SubCommand {
         /// The Citadel root directory
         citadel_root: String,
     },
+    /// Update apps one after another, rolling back an app that doesn't become healthy and stopping there
+    #[cfg(feature = "git")]
+    ApplyUpdates {
+        /// The Citadel root directory
+        citadel_root: String,
+        /// The apps to update, in this order. Defaults to all apps check-updates found updates for.
+        apps: Vec<String>,
+        /// The URL the Caddy admin api is listing on
+        #[clap(short, long)]
+        caddy_url: Option<String>,
+        /// Write state and outputs to this directory instead of the Citadel root
+        #[clap(long)]
+        state_dir: Option<String>,
+    },
     #[cfg(feature = "git")]
     Download {
         /// The app to download
@@ -94,6 +203,356 @@ enum SubCommand {
         #[clap(long)]
         citadel_root: String,
     },
+    /// Download the icons and images of stores and apps into the local cache
+    FetchAssets {
+        /// The Citadel root directory
+        citadel_root: String,
+        /// The state directory used during convert, if any
+        #[clap(long)]
+        state_dir: Option<String>,
+    },
+    /// Print the node CA certificate, which needs to be trusted by clients for LAN HTTPS
+    ExportCa {
+        /// The Citadel root directory
+        citadel_root: String,
+        /// The state directory used during convert, if any
+        #[clap(long)]
+        state_dir: Option<String>,
+    },
+    /// Request the port forwards of the last conversion again, so they don't expire.
+    /// serve and watch do this on their own, run it periodically otherwise.
+    RenewPortForwards {
+        /// The Citadel root directory
+        citadel_root: String,
+        /// The state directory used during convert, if any
+        #[clap(long)]
+        state_dir: Option<String>,
+    },
+    /// Check that apps implementing interfaces like electrum respond, run this after starting apps
+    ProbeInterfaces {
+        /// The Citadel root directory
+        citadel_root: String,
+        /// The state directory used during convert, if any
+        #[clap(long)]
+        state_dir: Option<String>,
+    },
+    /// Detect the node's hardware (architecture, RAM, KVM, GPU, AVX and storage type)
+    /// and store it for the next conversions
+    Probe {
+        /// The Citadel root directory
+        citadel_root: String,
+        /// The state directory used during convert, if any
+        #[clap(long)]
+        state_dir: Option<String>,
+    },
+    /// List all apps with their version, whether they are installed, their IP and the result of the last conversion
+    List {
+        /// The Citadel root directory
+        citadel_root: String,
+        /// The state directory used during convert, if any
+        #[clap(long)]
+        state_dir: Option<String>,
+    },
+    /// Show the ports, IPs, env vars, Tor and I2P entries and Caddy routes the last conversion generated for an app
+    Info {
+        /// The Citadel root directory
+        citadel_root: String,
+        /// The app to show
+        app: String,
+        /// The state directory used during convert, if any
+        #[clap(long)]
+        state_dir: Option<String>,
+    },
+    /// Show the names and paths the app manager derives for an app, like env vars, data and onion dirs
+    Paths {
+        /// The Citadel root directory
+        citadel_root: String,
+        /// The app to show
+        app: String,
+        /// Containers to show the names of, defaults to the containers of the last conversion
+        #[clap(long)]
+        container: Vec<String>,
+        /// Print the names as JSON
+        #[clap(long)]
+        json: bool,
+        /// The state directory used during convert, if any
+        #[clap(long)]
+        state_dir: Option<String>,
+    },
+    /// Show how much of the IPs and ports for apps is used
+    Capacity {
+        /// The Citadel root directory
+        citadel_root: String,
+        /// The state directory used during convert, if any
+        #[clap(long)]
+        state_dir: Option<String>,
+        /// Print JSON instead of text
+        #[clap(long)]
+        json: bool,
+    },
+    /// Show, assign or free the IPs of app containers in ips.yml
+    Ips {
+        /// The Citadel root directory
+        citadel_root: String,
+        /// The state directory used during convert, if any
+        #[clap(long)]
+        state_dir: Option<String>,
+        #[clap(subcommand)]
+        command: IpsCommand,
+    },
+    /// Show, reserve or release the outside ports in the port cache
+    Ports {
+        /// The Citadel root directory
+        citadel_root: String,
+        /// The state directory used during convert, if any
+        #[clap(long)]
+        state_dir: Option<String>,
+        #[clap(subcommand)]
+        command: PortsCommand,
+    },
+    /// Check that there is enough disk space to install or update apps, run this before downloading their images
+    Preflight {
+        /// The Citadel root directory
+        citadel_root: String,
+        /// The apps to install or update
+        #[clap(required = true)]
+        apps: Vec<String>,
+        /// Print the estimates as JSON
+        #[clap(long)]
+        json: bool,
+        /// The state directory used during convert, if any
+        #[clap(long)]
+        state_dir: Option<String>,
+    },
+    /// Show which app currently backs an interface like electrum, with its IP and ports
+    Which {
+        /// The Citadel root directory
+        citadel_root: String,
+        /// The interface to look up
+        interface: String,
+        /// Print the result as JSON
+        #[clap(long)]
+        json: bool,
+        /// The state directory used during convert, if any
+        #[clap(long)]
+        state_dir: Option<String>,
+    },
+    /// Prepare installing a bundle of apps, asking for its shared settings, and print the apps to install in order
+    InstallBundle {
+        /// The Citadel root directory
+        citadel_root: String,
+        /// The bundle to install
+        bundle: String,
+        /// A setting of the bundle as KEY=VALUE, settings that are not given are asked for
+        #[clap(long = "set")]
+        set: Vec<String>,
+        /// The state directory used during convert, if any
+        #[clap(long)]
+        state_dir: Option<String>,
+    },
+    /// Convert all apps, and convert them again whenever an app.yml, db/user.json or a template changes
+    Watch {
+        /// The Citadel root directory
+        citadel_root: String,
+        /// The URL the Caddy admin api is listing on, Caddy is reloaded after every conversion
+        #[clap(short, long)]
+        caddy_url: Option<String>,
+        /// The state directory used during convert, if any
+        #[clap(long)]
+        state_dir: Option<String>,
+    },
+    /// Show or change the settings of an app, run convert afterwards to apply them
+    Settings {
+        /// The Citadel root directory
+        citadel_root: String,
+        /// The app
+        app: String,
+        /// A setting to change as KEY=VALUE, an empty value unsets it
+        #[clap(long = "set")]
+        set: Vec<String>,
+        /// The state directory used during convert, if any
+        #[clap(long)]
+        state_dir: Option<String>,
+    },
+    /// Find apps whose data still contains credentials derived from a previous seed
+    VerifySecrets {
+        /// The Citadel root directory
+        citadel_root: String,
+        /// The state directory used during convert, if any
+        #[clap(long)]
+        state_dir: Option<String>,
+    },
+    /// Remove containers, networks and volumes of apps that no longer exist
+    Gc {
+        /// The Citadel root directory
+        citadel_root: String,
+        /// Don't ask for confirmation
+        #[clap(short, long)]
+        yes: bool,
+        /// The state directory used during convert, if any
+        #[clap(long)]
+        state_dir: Option<String>,
+    },
+    /// Show the CPU, memory and disk usage of running apps
+    Top {
+        /// What to sort the apps by
+        #[clap(long, value_enum, default_value_t)]
+        sort: cli::resources::SortBy,
+        /// The container engine the apps run on
+        #[clap(long, value_enum, default_value_t)]
+        engine: cli::runtime::ContainerEngine,
+        /// Print the usage as JSON
+        #[clap(long)]
+        json: bool,
+    },
+    /// Show the logs of an app
+    Logs {
+        /// The Citadel root directory
+        citadel_root: String,
+        /// The app to show the logs of
+        app: String,
+        /// Only show the logs of this container
+        service: Option<String>,
+        /// Keep following the logs
+        #[clap(short, long)]
+        follow: bool,
+        /// The state directory used during convert, if any
+        #[clap(long)]
+        state_dir: Option<String>,
+    },
+    /// Stop an app, delete its containers and data and set it up again from scratch
+    Reset {
+        /// The Citadel root directory
+        citadel_root: String,
+        /// The app to reset
+        app: String,
+        /// Only recreate the containers, keep the app's data
+        #[clap(long)]
+        keep_data: bool,
+        /// Don't ask for confirmation before deleting data
+        #[clap(short, long)]
+        yes: bool,
+        /// The URL the Caddy admin api is listing on, Caddy is reloaded after the conversion
+        #[clap(short, long)]
+        caddy_url: Option<String>,
+        /// The state directory used during convert, if any
+        #[clap(long)]
+        state_dir: Option<String>,
+    },
+    /// Run the init containers of an app again, like after restoring a backup
+    RerunInit {
+        /// The Citadel root directory
+        citadel_root: String,
+        /// The app to run the init containers of
+        app: String,
+        /// Only run this init container
+        job: Option<String>,
+        /// The state directory used during convert, if any
+        #[clap(long)]
+        state_dir: Option<String>,
+    },
+    /// Put an app into maintenance mode: its containers are stopped and visitors see a maintenance page
+    Maintenance {
+        /// The Citadel root directory
+        citadel_root: String,
+        /// The app to turn maintenance mode on or off for
+        app: String,
+        #[clap(value_enum)]
+        state: cli::maintenance::MaintenanceState,
+        /// The URL the Caddy admin api is listing on
+        #[clap(short, long)]
+        caddy_url: Option<String>,
+        /// The state directory used during convert, if any
+        #[clap(long)]
+        state_dir: Option<String>,
+    },
+    /// Serve convert, install, status and capacity as JSON-RPC over a unix socket, for services on the host.
+    /// Also receives store webhooks if they are configured in app-manager.toml.
+    Serve {
+        /// The Citadel root directory
+        citadel_root: String,
+        /// The socket to listen on, anyone who can write to it can use the API
+        #[clap(long, default_value = "/run/citadel/app-manager.sock")]
+        socket: String,
+        /// The URL the Caddy admin api is listing on
+        #[clap(short, long)]
+        caddy_url: Option<String>,
+        /// Write state and outputs to this directory instead of the Citadel root
+        #[clap(long)]
+        state_dir: Option<String>,
+    },
+    /// Run a command in a container of an app
+    Exec {
+        /// The Citadel root directory
+        citadel_root: String,
+        /// The app to run the command in
+        app: String,
+        /// The container to run the command in, defaults to the app's main container
+        service: Option<String>,
+        /// The state directory used during convert, if any
+        #[clap(long)]
+        state_dir: Option<String>,
+        /// The command to run
+        #[clap(last = true, required = true)]
+        command: Vec<String>,
+    },
+    /// Print a completion script, for example `app-cli completions bash > /etc/bash_completion.d/app-cli`
+    Completions {
+        /// The shell to complete commands in
+        #[clap(value_enum)]
+        shell: cli::completions::CompletionShell,
+    },
+    /// Print the app IDs to complete after the given words, used by the completion scripts
+    #[clap(hide = true)]
+    CompleteApps {
+        /// The words before the cursor, without app-cli
+        #[clap(last = true)]
+        words: Vec<String>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum IpsCommand {
+    /// List which app every IP belongs to
+    List,
+    /// Assign an IP to a container, the next free one if none is given
+    Assign {
+        app: String,
+        container: String,
+        ip: Option<String>,
+    },
+    /// Free the IPs of apps, so they can be assigned to other containers
+    Free {
+        apps: Vec<String>,
+        /// Also free the IPs of all apps that are not installed
+        #[clap(long)]
+        uninstalled: bool,
+        /// Free IPs of installed apps, they get new IPs on the next conversion
+        #[clap(long)]
+        force: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum PortsCommand {
+    /// List which app and container every outside port belongs to
+    List,
+    /// Reserve a port for a service outside of the app manager, apps won't be assigned it
+    Reserve {
+        port: u16,
+        /// The name of the service, shown instead of an app
+        name: String,
+    },
+    /// Release ports, the next conversion can assign them again
+    Release {
+        ports: Vec<u16>,
+        /// Also release all ports of apps that no longer exist
+        #[clap(long)]
+        stale: bool,
+        /// Release ports of existing apps, they get a new port on the next conversion
+        #[clap(long)]
+        force: bool,
+    },
 }
 
 /// Manage apps on Citadel
@@ -102,55 +561,305 @@ struct Cli {
     /// The subcommand to run
     #[clap(subcommand)]
     command: SubCommand,
+    /// Only print errors and the summary
+    #[clap(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+    /// Print debug logs and how long each step took
+    #[clap(long, global = true)]
+    verbose: bool,
+    /// When to color the output
+    #[clap(long, global = true, value_enum, default_value_t)]
+    color: cli::terminal::ColorChoice,
+    /// Print results as text, or as JSON for other programs
+    #[clap(long, global = true, value_enum, default_value_t)]
+    output: cli::output::OutputFormat,
 }
 
 fn main() {
-    tracing_subscriber::fmt::init();
     let args: Cli = Cli::parse();
+    let verbosity = cli::terminal::Verbosity::new(args.quiet, args.verbose);
+    let style = cli::terminal::Style::new(args.color);
+    // Log to stderr, stdout can be used for output
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .with_max_level(verbosity.log_level())
+        .with_ansi(args.color != cli::terminal::ColorChoice::Never)
+        .init();
+    let output = args.output;
+    if output.is_json() {
+        cli::output::print_panics_as_json();
+    }
     match args.command {
-        SubCommand::Convert { citadel_root, caddy_url } => {
-            cli::convert_dir(&citadel_root, &caddy_url).expect("Failed to convert");
+        SubCommand::Convert {
+            citadel_root,
+            caddy_url,
+            state_dir,
+            port_changes,
+            app_id,
+            context,
+            ip,
+            port,
+            installed_service,
+            filter,
+            app,
+            dry_run,
+            strict,
+            best_effort,
+        } => {
+            if citadel_root == "-" {
+                let app_id = app_id.expect("--app-id is required when reading from stdin");
+                let mut conversion_context = context
+                    .map(|context| {
+                        ConversionContext::load(Path::new(&context)).expect("Invalid context file")
+                    })
+                    .unwrap_or_default();
+                for ip in ip {
+                    conversion_context.add_ip(&ip).expect("Invalid IP address");
+                }
+                for port in port {
+                    conversion_context
+                        .add_port(&app_id, &port)
+                        .expect("Invalid port");
+                }
+                if !installed_service.is_empty() {
+                    conversion_context
+                        .installed_services
+                        .get_or_insert_with(Vec::new)
+                        .extend(installed_service);
+                }
+                convert_single_app(
+                    &app_id,
+                    std::io::stdin().lock(),
+                    &conversion_context,
+                    std::io::stdout().lock(),
+                )
+                .expect("Failed to convert");
+            } else if dry_run {
+                let changes =
+                    cli::dry_run::convert_dir(&citadel_root, &state_dir, &filter, app.as_deref())
+                        .expect("Failed to convert");
+                if output.is_json() {
+                    cli::output::print_json(&changes);
+                    return;
+                }
+                if changes.is_empty() {
+                    println!("No files would change");
+                }
+                for change in changes {
+                    println!("{change}");
+                }
+            } else {
+                let mode = if strict {
+                    Some(cli::failures::ErrorMode::Strict)
+                } else if best_effort {
+                    Some(cli::failures::ErrorMode::BestEffort)
+                } else {
+                    None
+                };
+                let report = cli::failures::convert_dir(
+                    &citadel_root,
+                    &caddy_url,
+                    &state_dir,
+                    port_changes,
+                    &filter,
+                    app.as_deref(),
+                    mode,
+                )
+                .expect("Failed to convert");
+                if output.is_json() {
+                    cli::output::print_json(&report);
+                    return;
+                }
+                print!(
+                    "{}",
+                    cli::terminal::format_conversion(&report, verbosity, style)
+                );
+            }
         }
-        #[cfg(feature = "dev-tools")]
-        SubCommand::Schema { version } => match version.as_str() {
-            "3" => {
-                let schema = schemars::schema_for!(SchemaItemContainers);
-                println!("{}", serde_yaml::to_string(&schema).unwrap());
-            }
-            "4" => {
-                let schema = schemars::schema_for!(AppYml);
-                println!("{}", serde_yaml::to_string(&schema).unwrap());
-            }
-            #[cfg(feature = "umbrel")]
-            "umbrel" => {
-                let schema = schemars::schema_for!(UmbrelMetadata);
-                println!("{}", serde_yaml::to_string(&schema).unwrap());
-            }
-            "result" => {
-                let schema = schemars::schema_for!(ResultYml);
-                println!("{}", serde_yaml::to_string(&schema).unwrap());
-            }
-            "compose" => {
-                let schema = schemars::schema_for!(ComposeSpecification);
-                println!("{}", serde_yaml::to_string(&schema).unwrap());
-            }
-            _ => {
-                eprintln!("Unsupported schema version!");
-                exit(1);
+        SubCommand::Diff {
+            citadel_root,
+            state_dir,
+            filter,
+            app,
+        } => {
+            let diff = cli::dry_run::diff(&citadel_root, &state_dir, &filter, app.as_deref())
+                .expect("Failed to convert");
+            if output.is_json() {
+                cli::output::print_json(&serde_json::json!({ "diff": diff }));
+                return;
             }
-        },
+            if diff.is_empty() {
+                println!("No files would change");
+            }
+            print!("{diff}");
+        }
+        #[cfg(feature = "schema")]
+        SubCommand::Schema { version, format } => {
+            let schema = cli::schema::schema(&version).expect("Unsupported schema version");
+            print!(
+                "{}",
+                cli::schema::render(&schema, format).expect("Failed to print the schema")
+            );
+        }
         #[cfg(feature = "umbrel")]
         SubCommand::UmbrelToCitadel { app_dir } => {
             let app_dir = Path::new(&app_dir);
-            cli::umbrel::convert(app_dir).expect("Conversion failed!");
+            cli::umbrel::convert(&cli::paths::CitadelPaths::new(app_dir, None), app_dir)
+                .expect("Conversion failed!");
         }
         #[cfg(feature = "dev-tools")]
         SubCommand::Validate { app, app_name } => {
-            let app_yml = std::fs::File::open(app).expect("Error opening app definition!");
-            convert_config(&app_name, &app_yml, &None, &None, &None).expect("App is invalid");
+            let (app_yml, diagnostics) =
+                cli::dev_tools::validate(Path::new(&app), app_name.as_deref())
+                    .expect("Error opening app definition!");
+            let app_dir = app_yml
+                .parent()
+                .filter(|dir| !dir.as_os_str().is_empty())
+                .unwrap_or_else(|| Path::new("."));
+            let portability_warnings = cli::dev_tools::portability_warnings(app_dir)
+                .expect("Failed to check app directory");
+            let valid = !diagnostics
+                .iter()
+                .any(|diagnostic| diagnostic.severity == Severity::Error);
+            if output.is_json() {
+                cli::output::print_json(&cli::output::ValidationReport {
+                    file: app_yml,
+                    valid,
+                    diagnostics,
+                    portability_warnings,
+                });
+                if !valid {
+                    exit(1);
+                }
+                return;
+            }
+            for diagnostic in &diagnostics {
+                match diagnostic.line {
+                    Some(line) => println!("{}:{}: {}", app_yml.display(), line, diagnostic),
+                    None => println!("{}: {}", app_yml.display(), diagnostic),
+                }
+            }
+            if !portability_warnings.is_empty() {
+                println!("Files that will cause problems on other operating systems:");
+                for warning in portability_warnings {
+                    println!("  - {}", warning);
+                }
+            }
+            if !valid {
+                exit(1);
+            }
             println!("App is valid!");
         }
         #[cfg(feature = "dev-tools")]
+        SubCommand::Roundtrip { app } => {
+            let differences =
+                cli::dev_tools::roundtrip(Path::new(&app)).expect("Failed to load app");
+            if output.is_json() {
+                let unchanged = differences.is_empty();
+                cli::output::print_json(&cli::output::Differences { differences });
+                if !unchanged {
+                    exit(1);
+                }
+                return;
+            }
+            if differences.is_empty() {
+                println!("The app.yml survives a round trip unchanged");
+            } else {
+                println!("Differences after a round trip:");
+                for difference in differences {
+                    println!("  - {}", difference);
+                }
+                exit(1);
+            }
+        }
+        #[cfg(feature = "dev-tools")]
+        SubCommand::Lint { apps, fix, filter } => {
+            let results = cli::dev_tools::lint(&apps, fix, &filter).expect("Failed to lint apps");
+            if output.is_json() {
+                let failed = !fix && !results.is_empty();
+                let results: Vec<cli::output::LintResult> = results
+                    .into_iter()
+                    .map(|(file, deprecations)| cli::output::LintResult { file, deprecations })
+                    .collect();
+                cli::output::print_json(&results);
+                if failed {
+                    exit(1);
+                }
+                return;
+            }
+            for (app_yml, deprecations) in &results {
+                println!("{}:", app_yml.display());
+                for deprecation in deprecations {
+                    println!("  - {}", deprecation);
+                }
+            }
+            if fix {
+                println!("Fixed {} files", results.len());
+            } else if !results.is_empty() {
+                exit(1);
+            }
+        }
+        #[cfg(feature = "dev-tools")]
+        SubCommand::RenderConfig {
+            app_dir,
+            env,
+            output_dir,
+            check,
+            expected,
+        } => {
+            let app_dir = Path::new(&app_dir);
+            let environment = env
+                .as_ref()
+                .map(|env| {
+                    cli::dev_tools::RenderEnvironment::load(Path::new(env))
+                        .expect("Failed to load the environment")
+                })
+                .unwrap_or_default();
+            let output_dir = output_dir.map_or_else(|| app_dir.join(".preview"), PathBuf::from);
+            cli::dev_tools::render_config(app_dir, &environment, &output_dir)
+                .expect("Failed to render the templates");
+            if !check {
+                if output.is_json() {
+                    cli::output::print_json(&serde_json::json!({ "outputDir": output_dir }));
+                } else {
+                    println!("Rendered the templates to {}", output_dir.display());
+                }
+                return;
+            }
+            let expected = expected.map(PathBuf::from).unwrap_or_else(|| match &env {
+                Some(env) => Path::new(env).with_extension(""),
+                None => app_dir.join("tests").join("expected"),
+            });
+            let differences = cli::dev_tools::check_rendered(&output_dir, &expected)
+                .expect("Failed to compare the rendered files");
+            if output.is_json() {
+                let matches = differences.is_empty();
+                cli::output::print_json(&cli::output::Differences { differences });
+                if !matches {
+                    exit(1);
+                }
+                return;
+            }
+            if differences.is_empty() {
+                println!("All templates render to the expected output");
+            } else {
+                println!("The rendered templates differ from {}:", expected.display());
+                for difference in differences {
+                    println!("  - {}", difference);
+                }
+                exit(1);
+            }
+        }
+        #[cfg(feature = "dev-tools")]
+        SubCommand::CiCheck { app_dir } => {
+            let report =
+                cli::dev_tools::ci_check(Path::new(&app_dir)).expect("Failed to check the app");
+            println!("{}", serde_json::to_string_pretty(&report).unwrap());
+            if !report.passed {
+                exit(1);
+            }
+        }
+        #[cfg(feature = "dev-tools")]
         SubCommand::Update {
             app,
             token,
@@ -260,7 +969,7 @@ fn main() {
                 }
                 citadel_apps::composegenerator::AppYmlFile::V3(app_yml) => {
                     let writer = std::fs::File::create(app).expect("Error opening app definition!");
-                    serde_yaml::to_writer(writer, &v3_to_v4(app_yml, &None))
+                    serde_yaml::to_writer(writer, &v3_to_v4(app_yml, None))
                         .expect("Error saving app definition!");
                 }
             }
@@ -278,8 +987,476 @@ fn main() {
             cli::repos::list_updates(&citadel_root).expect("Failed to check for updates");
         }
         #[cfg(feature = "git")]
+        SubCommand::ApplyUpdates {
+            citadel_root,
+            apps,
+            caddy_url,
+            state_dir,
+        } => {
+            let updated = cli::rollout::apply_updates(&citadel_root, &caddy_url, &state_dir, &apps)
+                .expect("Failed to apply updates");
+            if output.is_json() {
+                cli::output::print_json(&serde_json::json!({ "updated": updated }));
+                return;
+            }
+            for app in updated {
+                println!("Updated {app}");
+            }
+        }
+        #[cfg(feature = "git")]
         SubCommand::Download { citadel_root, app } => {
             cli::repos::download_app(&citadel_root, &app).expect("Failed to download app");
         }
+        SubCommand::FetchAssets {
+            citadel_root,
+            state_dir,
+        } => {
+            cli::http_cache::fetch_assets(&citadel_root, &state_dir)
+                .expect("Failed to fetch assets");
+        }
+        SubCommand::ExportCa {
+            citadel_root,
+            state_dir,
+        } => {
+            print!(
+                "{}",
+                cli::lan_tls::export_ca(&citadel_root, &state_dir).expect("Failed to export CA")
+            );
+        }
+        SubCommand::RenewPortForwards {
+            citadel_root,
+            state_dir,
+        } => {
+            let paths = cli::paths::CitadelPaths::new(
+                Path::new(&citadel_root),
+                state_dir.as_deref().map(Path::new),
+            );
+            cli::port_forwarding::renew(&paths).expect("Failed to renew port forwards");
+        }
+        SubCommand::ProbeInterfaces {
+            citadel_root,
+            state_dir,
+        } => {
+            let healthy = cli::interface_health::probe_interfaces(&citadel_root, &state_dir)
+                .expect("Failed to probe interfaces");
+            if !healthy {
+                std::process::exit(1);
+            }
+        }
+        SubCommand::Probe {
+            citadel_root,
+            state_dir,
+        } => {
+            let citadel_root = Path::new(&citadel_root);
+            let paths =
+                cli::paths::CitadelPaths::new(citadel_root, state_dir.as_deref().map(Path::new));
+            let facts = cli::host_facts::probe(&paths, citadel_root)
+                .expect("Failed to store the host facts");
+            println!("{}", serde_json::to_string_pretty(&facts).unwrap());
+        }
+        SubCommand::List {
+            citadel_root,
+            state_dir,
+        } => {
+            let apps = cli::app_list::list(&citadel_root, &state_dir).expect("Failed to list apps");
+            if output.is_json() {
+                cli::output::print_json(&apps);
+            } else {
+                print!("{}", cli::app_list::format_table(&apps));
+            }
+        }
+        SubCommand::Info {
+            citadel_root,
+            app,
+            state_dir,
+        } => {
+            let info = cli::app_info::info(&citadel_root, &state_dir, &app)
+                .expect("Failed to get the app's details");
+            if output.is_json() {
+                cli::output::print_json(&info);
+            } else {
+                print!("{}", cli::app_info::format_info(&info));
+            }
+        }
+        SubCommand::Paths {
+            citadel_root,
+            app,
+            mut container,
+            json,
+            state_dir,
+        } => {
+            let citadel_root = Path::new(&citadel_root);
+            if container.is_empty() {
+                let paths = cli::paths::CitadelPaths::new(
+                    citadel_root,
+                    state_dir.as_deref().map(Path::new),
+                );
+                let projects =
+                    cli::projects::load(&paths, citadel_root).expect("Failed to load projects");
+                container = projects
+                    .get(&app)
+                    .map(|project| project.containers.keys().cloned().collect())
+                    .unwrap_or_default();
+            }
+            let names = citadel_apps::naming::AppNames::new(
+                citadel_root,
+                &app,
+                container.iter().map(String::as_str),
+            );
+            if json || output.is_json() {
+                println!("{}", serde_json::to_string_pretty(&names).unwrap());
+            } else {
+                print!("{names}");
+            }
+        }
+        SubCommand::Capacity {
+            citadel_root,
+            state_dir,
+            json,
+        } => {
+            let capacity =
+                cli::capacity::load(&citadel_root, &state_dir).expect("Failed to load the pools");
+            if json || output.is_json() {
+                println!("{}", serde_json::to_string_pretty(&capacity).unwrap());
+            } else {
+                print!("{}", cli::capacity::format_capacity(&capacity));
+            }
+        }
+        SubCommand::Ips {
+            citadel_root,
+            state_dir,
+            command,
+        } => match command {
+            IpsCommand::List => {
+                let entries =
+                    cli::ip_map::list(&citadel_root, &state_dir).expect("Failed to load ips.yml");
+                if output.is_json() {
+                    cli::output::print_json(&entries);
+                } else {
+                    print!("{}", cli::ip_map::format_table(&entries));
+                }
+            }
+            IpsCommand::Assign { app, container, ip } => {
+                let ip =
+                    cli::ip_map::assign(&citadel_root, &state_dir, &app, &container, ip.as_deref())
+                        .expect("Failed to assign the IP");
+                if output.is_json() {
+                    cli::output::print_json(&serde_json::json!({ "ip": ip }));
+                } else {
+                    println!("{ip}");
+                }
+            }
+            IpsCommand::Free {
+                apps,
+                uninstalled,
+                force,
+            } => {
+                let freed = cli::ip_map::free(&citadel_root, &state_dir, &apps, uninstalled, force)
+                    .expect("Failed to free the IPs");
+                if output.is_json() {
+                    cli::output::print_json(&freed);
+                    return;
+                }
+                for entry in freed {
+                    println!("Freed {} ({})", entry.ip, entry.var);
+                }
+            }
+        },
+        SubCommand::Ports {
+            citadel_root,
+            state_dir,
+            command,
+        } => match command {
+            PortsCommand::List => {
+                let entries = cli::port_cache::list(&citadel_root, &state_dir)
+                    .expect("Failed to load the port cache");
+                if output.is_json() {
+                    cli::output::print_json(&entries);
+                } else {
+                    print!("{}", cli::port_cache::format_table(&entries));
+                }
+            }
+            PortsCommand::Reserve { port, name } => {
+                cli::port_cache::reserve(&citadel_root, &state_dir, port, &name)
+                    .expect("Failed to reserve the port");
+                if output.is_json() {
+                    cli::output::print_json(&serde_json::json!({ "port": port, "name": name }));
+                }
+            }
+            PortsCommand::Release {
+                ports,
+                stale,
+                force,
+            } => {
+                let released =
+                    cli::port_cache::release(&citadel_root, &state_dir, &ports, stale, force)
+                        .expect("Failed to release the ports");
+                if output.is_json() {
+                    cli::output::print_json(&released);
+                    return;
+                }
+                for port in released {
+                    println!("Released port {port}");
+                }
+            }
+        },
+        SubCommand::Preflight {
+            citadel_root,
+            apps,
+            json,
+            state_dir,
+        } => {
+            let preflight = cli::disk_space::preflight(&citadel_root, &state_dir, &apps)
+                .expect("Failed to check the disk space");
+            if json || output.is_json() {
+                println!(
+                    "{}",
+                    serde_json::to_string(&preflight).expect("Failed to serialize the estimates")
+                );
+            } else {
+                print!("{}", cli::disk_space::format_report(&preflight));
+            }
+            if !preflight.fits() {
+                eprintln!("Not enough disk space for {}", apps.join(", "));
+                if !preflight.warn_only {
+                    std::process::exit(1);
+                }
+            }
+        }
+        SubCommand::Which {
+            citadel_root,
+            interface,
+            json,
+            state_dir,
+        } => {
+            let Some(backend) = cli::virtual_apps::which(&citadel_root, &state_dir, &interface)
+                .expect("Failed to resolve the interface")
+            else {
+                cli::output::exit_with_error(
+                    output,
+                    &format!("No installed app implements {interface}"),
+                );
+            };
+            if json || output.is_json() {
+                println!(
+                    "{}",
+                    serde_json::to_string(&backend).expect("Failed to serialize the result")
+                );
+                return;
+            }
+            println!("{}", backend.app);
+            for port in &backend.ports {
+                match &backend.ip {
+                    Some(ip) => println!(
+                        "{}:{} (public port {})",
+                        ip, port.internal_port, port.public_port
+                    ),
+                    None => println!("public port {}", port.public_port),
+                }
+            }
+        }
+        SubCommand::InstallBundle {
+            citadel_root,
+            bundle,
+            set,
+            state_dir,
+        } => {
+            let given = set
+                .iter()
+                .map(|setting| {
+                    let (key, value) = setting
+                        .split_once('=')
+                        .expect("Settings must be given as KEY=VALUE");
+                    (key.to_string(), value.to_string())
+                })
+                .collect();
+            let apps = cli::bundles::install(
+                &citadel_root,
+                &state_dir,
+                &bundle,
+                given,
+                std::io::stdin().lock(),
+                std::io::stderr(),
+            )
+            .expect("Failed to prepare installing the bundle");
+            if output.is_json() {
+                cli::output::print_json(&apps);
+                return;
+            }
+            for app in apps {
+                println!("{app}");
+            }
+        }
+        SubCommand::Watch {
+            citadel_root,
+            caddy_url,
+            state_dir,
+        } => {
+            cli::watch::watch(&citadel_root, &caddy_url, &state_dir, |report| {
+                if output.is_json() {
+                    cli::output::print_json(report);
+                } else {
+                    print!(
+                        "{}",
+                        cli::terminal::format_conversion(report, verbosity, style)
+                    );
+                }
+            })
+            .expect("Failed to watch for changes");
+        }
+        SubCommand::Settings {
+            citadel_root,
+            app,
+            set,
+            state_dir,
+        } => {
+            let changes = set
+                .iter()
+                .map(|setting| {
+                    let (key, value) = setting
+                        .split_once('=')
+                        .expect("Settings must be given as KEY=VALUE");
+                    (key.to_string(), value.to_string())
+                })
+                .collect();
+            let settings = cli::app_settings::set(&citadel_root, &state_dir, &app, changes)
+                .expect("Failed to change the settings");
+            if output.is_json() {
+                cli::output::print_json(&settings);
+            } else {
+                print!("{}", cli::app_settings::format_settings(&settings));
+            }
+        }
+        SubCommand::VerifySecrets {
+            citadel_root,
+            state_dir,
+        } => {
+            let outdated = cli::secrets::verify_secrets(&citadel_root, &state_dir)
+                .expect("Failed to verify secrets");
+            if output.is_json() {
+                cli::output::print_json(&outdated);
+                if !outdated.is_empty() {
+                    std::process::exit(1);
+                }
+                return;
+            }
+            if outdated.is_empty() {
+                println!("No app uses credentials from a previous seed");
+                return;
+            }
+            for usage in &outdated {
+                println!(
+                    "{} uses credentials from previous seed #{} in:",
+                    usage.app, usage.seed
+                );
+                for file in &usage.files {
+                    println!("  - {}", file.display());
+                }
+            }
+            println!("Change these credentials in the apps, or reset the apps with `app-cli reset` to set them up with the current seed");
+            std::process::exit(1);
+        }
+        SubCommand::Gc {
+            citadel_root,
+            yes,
+            state_dir,
+        } => {
+            cli::gc::gc(
+                &citadel_root,
+                &state_dir,
+                yes,
+                std::io::stdin().lock(),
+                std::io::stderr(),
+            )
+            .expect("Failed to remove orphaned resources");
+        }
+        SubCommand::Top { sort, engine, json } => {
+            let usage = cli::resources::sample(engine, sort).expect("Failed to get resource usage");
+            if json || output.is_json() {
+                println!(
+                    "{}",
+                    serde_json::to_string(&usage).expect("Failed to serialize resource usage")
+                );
+            } else {
+                print!("{}", cli::resources::format_table(&usage));
+            }
+        }
+        SubCommand::Logs {
+            citadel_root,
+            app,
+            service,
+            follow,
+            state_dir,
+        } => {
+            let code = cli::compose::logs(&citadel_root, &state_dir, &app, service, follow)
+                .expect("Failed to show logs");
+            std::process::exit(code);
+        }
+        SubCommand::Reset {
+            citadel_root,
+            app,
+            keep_data,
+            yes,
+            caddy_url,
+            state_dir,
+        } => {
+            cli::compose::reset(&citadel_root, &caddy_url, &state_dir, &app, keep_data, yes)
+                .expect("Failed to reset app");
+        }
+        SubCommand::RerunInit {
+            citadel_root,
+            app,
+            job,
+            state_dir,
+        } => {
+            let code = cli::compose::rerun_init(&citadel_root, &state_dir, &app, job)
+                .expect("Failed to run init containers");
+            std::process::exit(code);
+        }
+        SubCommand::Maintenance {
+            citadel_root,
+            app,
+            state,
+            caddy_url,
+            state_dir,
+        } => {
+            cli::maintenance::set(&citadel_root, &caddy_url, &state_dir, &app, state)
+                .expect("Failed to change maintenance mode");
+        }
+        SubCommand::Serve {
+            citadel_root,
+            socket,
+            caddy_url,
+            state_dir,
+        } => {
+            cli::rpc::RpcServer::new(citadel_root, caddy_url, state_dir)
+                .serve(Path::new(&socket))
+                .expect("Failed to serve the RPC interface");
+        }
+        SubCommand::Exec {
+            citadel_root,
+            app,
+            service,
+            state_dir,
+            command,
+        } => {
+            let code = cli::compose::exec(&citadel_root, &state_dir, &app, service, &command)
+                .expect("Failed to run command");
+            std::process::exit(code);
+        }
+        SubCommand::Completions { shell } => {
+            print!(
+                "{}",
+                cli::completions::generate(shell, Cli::command(), "app-cli")
+                    .expect("Failed to generate completions")
+            );
+        }
+        SubCommand::CompleteApps { words } => {
+            // The completion scripts fall back to the generated completions on failure
+            match cli::completions::complete_apps(Cli::command(), &words) {
+                Some(apps) => println!("{}", apps.join("\n")),
+                None => std::process::exit(1),
+            }
+        }
     }
 }
@@ -1,7 +1,9 @@
 use std::{
-    collections::HashMap,
-    io::{Read, Write},
+    collections::{BTreeMap, BTreeSet, HashMap},
+    io::Write,
+    ops::RangeInclusive,
     path::Path,
+    time::Instant,
 };
 
 use serde::{Deserialize, Serialize};
@@ -9,25 +11,101 @@ use serde::{Deserialize, Serialize};
 use ::tera::Context;
 
 use crate::composegenerator::{
-    convert_config, load_config_as_v4,
-    types::OutputMetadata,
+    ir::AppDefinition,
+    load_definition_file_with,
+    output::labels::add_labels,
+    types::{CaddyEntry, Protocol, UnsupportedReason},
     v4::{
-        types::{PortMapElement, PortPriority, StringOrMap},
-        utils::{derive_entropy, get_main_container},
+        convert::convert_config,
+        types::{HiddenServices, PortMapElement, PortPriority, SecurityProfiles, StringOrMap},
+        utils::{derive_entropy, get_main_container, share_env_var},
     },
 };
 
-use anyhow::Result;
+use crate::naming;
+use crate::utils::{flatten, is_false};
+use anyhow::{bail, Context as _, Result};
 
+pub mod app_filter;
+pub mod app_info;
+pub mod app_list;
+pub mod app_settings;
+pub mod base_services;
+pub mod bundles;
+pub mod cache_recovery;
+pub mod caddy_adapt;
+pub mod caddy_routes;
+pub mod caddy_snippets;
+pub mod capacity;
+pub mod completions;
+pub mod compose;
+pub mod config_reload;
+mod dependencies;
 #[cfg(feature = "dev-tools")]
 pub mod dev_tools;
+pub mod disk_space;
+pub mod dns;
+pub mod dry_run;
+pub mod exports;
+pub mod exposure;
+pub mod failures;
+pub mod features;
+pub mod firewall;
+pub mod gc;
+pub mod host_facts;
+pub mod host_ports;
+pub mod http_cache;
+pub mod https;
+pub mod i2p;
+pub mod image_arch;
+pub mod image_registry;
+pub mod interface_health;
+pub mod ip_assignment;
+pub mod ip_map;
+pub mod lan_tls;
+pub mod limits;
+pub mod locale;
+pub mod maintenance;
+pub mod metrics;
+pub mod network_isolation;
+pub mod node_config;
+pub mod output;
+pub mod paths;
+pub mod port_cache;
+pub mod port_forwarding;
+pub mod port_review;
 mod preprocessing;
+pub mod projects;
+pub mod rate_limits;
+pub mod redaction;
+pub mod registries;
+mod registry;
 #[cfg(feature = "git")]
 pub mod repos;
+pub mod resources;
+pub mod rollout;
+pub mod rpc;
+pub mod runtime;
+#[cfg(feature = "schema")]
+pub mod schema;
+pub mod secrets;
+pub mod security;
+pub mod single_app;
+pub mod start_order;
+pub mod static_assets;
+pub mod stats;
+mod stores;
+pub mod switchover;
+pub mod template_env;
 pub(crate) mod tera;
+pub mod terminal;
 #[cfg(feature = "umbrel")]
 #[allow(clippy::collapsible_match, clippy::unnecessary_unwrap)]
 pub mod umbrel;
+mod validation;
+pub mod virtual_apps;
+pub mod watch;
+pub mod webhooks;
 
 // A port map as used during creating the port map
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
@@ -39,6 +117,9 @@ struct PortCacheMapEntry {
     dynamic: bool,
     implements: Option<String>,
     priority: PortPriority,
+    /// Reserved by the operator for a service outside of the app manager, `app` is its name
+    #[serde(default, skip_serializing_if = "is_false")]
+    reserved: bool,
 }
 
 // Outside port -> app
@@ -56,413 +137,1232 @@ struct UserJson {
     #[serde(rename = "installedApps")]
     installed_apps: Vec<String>,
     https: Option<serde_json::Value>,
+    #[serde(rename = "publicExposure", default)]
+    public_exposure: exposure::PublicExposure,
+    #[serde(rename = "vpnExposure", default)]
+    vpn_exposure: exposure::VpnExposure,
 }
 
-pub fn convert_dir(citadel_root: &str, caddy_url: &Option<String>) -> Result<()> {
-    let citadel_root = Path::new(&citadel_root);
-    let apps = std::fs::read_dir(citadel_root.join("apps")).expect("Error reading apps directory!");
-    let apps = apps.filter(|entry| {
-        let entry = entry.as_ref().expect("Error reading app directory!");
-        let path = entry.path();
-
-        path.is_dir()
-    });
-
-    let mut services = Vec::<String>::new();
-    let mut https_options = None;
-    let user_json = std::fs::File::open(citadel_root.join("db").join("user.json"));
-    if let Ok(user_json) = user_json {
-        let user_json = serde_json::from_reader::<_, UserJson>(user_json);
-        if let Ok(user_json) = user_json {
-            services = user_json.installed_apps;
-            https_options = user_json.https;
-        }
-    }
-    services.append(&mut vec!["bitcoind".to_string()]);
-
-    let mut citadel_seed = None;
-
-    let citadel_seed_file = citadel_root.join("db").join("citadel-seed").join("seed");
-
-    if citadel_seed_file.exists() {
-        let mut citadel_seed_file = std::fs::File::open(citadel_seed_file).unwrap();
-        let mut citadel_seed_str = String::new();
-        citadel_seed_file
-            .read_to_string(&mut citadel_seed_str)
-            .unwrap();
-        citadel_seed = Some(citadel_seed_str);
-    }
-
-    let ip_addresses_map_file = citadel_root.join("apps").join("ips.yml");
-    let mut ip_map: HashMap<String, String> = HashMap::new();
-    let mut current_suffix: u8 = 20;
-    if ip_addresses_map_file.exists() {
-        let ip_addresses_map_file = std::fs::File::open(ip_addresses_map_file.clone()).unwrap();
-        let ip_addresses_map: HashMap<String, String> =
-            serde_yaml::from_reader(ip_addresses_map_file).unwrap();
-        ip_map = ip_addresses_map;
-        current_suffix += ip_map.len() as u8;
-    }
-    // Later used for port assignment
-    let mut port_map = HashMap::<String, HashMap<String, Vec<PortMapElement>>>::new();
-    let mut port_map_cache: PortCacheMap = HashMap::new();
-    let port_map_file = citadel_root.join("apps").join("ports.yml");
-    let port_cache_map_file = citadel_root.join("apps").join("ports.cache.yml");
-    if port_cache_map_file.exists() {
-        let port_cache_map_file = std::fs::File::open(port_cache_map_file.clone()).unwrap();
-        let port_cache_map_file: PortCacheMap =
-            serde_yaml::from_reader(port_cache_map_file).expect("Failed to load port map!");
-        port_map_cache = port_cache_map_file;
-    }
-
-    let mut validate_port = |app: &str,
-                             container: &str,
-                             suggested_port: u16,
-                             priority: PortPriority,
-                             dynamic: bool,
-                             implements: Option<String>|
-     -> bool {
-        let get_new_port = |app: &str, container: &str, mut suggested_port: u16| -> u16 {
-            while RESERVED_PORTS.contains(&suggested_port)
-                || port_map_cache.contains_key(&suggested_port)
-            {
-                if let Some(cache_entry) = port_map_cache.get(&suggested_port) {
-                    if cache_entry.app == app && cache_entry.container == container {
-                        return suggested_port;
-                    }
-                }
-                suggested_port += 1;
-            }
+/// Marks an app as unsupported on this node
+fn mark_unsupported(
+    unsupported_apps: &mut BTreeMap<String, Vec<UnsupportedReason>>,
+    app_id: &str,
+    reason: UnsupportedReason,
+) {
+    tracing::warn!("App {} is not supported: {}", app_id, reason);
+    unsupported_apps
+        .entry(app_id.to_string())
+        .or_default()
+        .push(reason);
+}
+
+/// Fails if converting only `app_id` would change the ports or IPs assigned to other apps
+fn check_single_app_assignments(
+    app_id: &str,
+    cached_ports: &PortCacheMap,
+    port_map_cache: &PortCacheMap,
+    cached_ips: &HashMap<String, String>,
+    ip_map: &HashMap<String, String>,
+    name_owners: &HashMap<String, (String, String)>,
+) -> Result<()> {
+    let other_apps = |ports: &PortCacheMap| -> BTreeMap<u16, PortCacheMapEntry> {
+        ports
+            .iter()
+            .filter(|(_, entry)| entry.app != app_id)
+            .map(|(port, entry)| (*port, entry.clone()))
+            .collect()
+    };
+    let (before, after) = (other_apps(cached_ports), other_apps(port_map_cache));
+    if let Some(changed) = before
+        .iter()
+        .filter(|(port, entry)| after.get(port) != Some(entry))
+        .chain(
+            after
+                .iter()
+                .filter(|(port, entry)| before.get(port) != Some(entry)),
+        )
+        .map(|(_, entry)| &entry.app)
+        .next()
+    {
+        bail!("Converting {app_id} alone would change the ports of {changed}, run a full conversion instead");
+    }
+    if let Some(ip_var) = ip_map
+        .keys()
+        .filter(|ip_var| !cached_ips.contains_key(*ip_var))
+        .find(|ip_var| {
+            name_owners
+                .get(*ip_var)
+                .is_none_or(|(owner, _)| owner != app_id)
+        })
+    {
+        bail!("Converting {app_id} alone would assign {ip_var}, run a full conversion instead");
+    }
+    Ok(())
+}
 
-            suggested_port
+/// Rebuilds the port cache from ports.yml, for when ports.cache.yml is corrupt.
+/// ports.yml does not record priorities, so restored ports are treated as recommended.
+fn rebuild_port_cache(
+    paths: &paths::CitadelPaths,
+    port_map_file: &Path,
+    interface_owners: &BTreeMap<String, String>,
+) -> Result<PortCacheMap> {
+    let Some(port_map) = cache_recovery::load::<
+        HashMap<String, HashMap<String, Vec<PortMapElement>>>,
+    >(paths, port_map_file, |_| Ok(()))?
+    else {
+        tracing::warn!("No usable ports.yml found, ports of all apps are assigned again");
+        return Ok(HashMap::new());
+    };
+    let mut port_map_cache = HashMap::new();
+    for (key, containers) in port_map {
+        // The ports of an interface are listed under its name and belong to the app serving it
+        let (app, implements) = match interface_owners.get(&key) {
+            Some(owner) => (owner.clone(), Some(key)),
+            None => (key, None),
         };
-        if let Some(key) = port_map_cache.get(&suggested_port) {
-            if (key.app == app && key.container == container)
-                || (key.implements == implements && container == "service")
-            {
-                return true;
-            }
-            if key.priority < priority {
-                // Move the existing app to a new port
-                let new_port = get_new_port(&key.app, &key.container, suggested_port);
-                let new_port_map = port_map_cache.remove(&suggested_port).unwrap();
-                port_map_cache.insert(new_port, new_port_map);
-                // And insert the new app
+        for (container, ports) in containers {
+            for port in ports {
                 port_map_cache.insert(
-                    suggested_port,
+                    port.public_port,
+                    PortCacheMapEntry {
+                        app: app.clone(),
+                        internal_port: port.internal_port,
+                        container: container.clone(),
+                        dynamic: port.dynamic,
+                        implements: implements.clone(),
+                        priority: PortPriority::Recommended,
+                        reserved: false,
+                    },
+                );
+            }
+        }
+    }
+    Ok(port_map_cache)
+}
+
+/// The names the conversion generates for an app that other apps could generate as well:
+/// env vars of containers, shared data and shares, and the names of hidden services and I2P destinations.
+/// Returns name -> what it was generated for.
+fn generated_names(
+    app_id: &str,
+    app_yml: &AppDefinition,
+    main_container: &str,
+) -> Vec<(String, String)> {
+    let mut names = vec![
+        (
+            naming::shared_subdir_env_var(app_id),
+            "its shared data".to_string(),
+        ),
+        (
+            naming::hidden_service_name(app_id, None),
+            "its main hidden service".to_string(),
+        ),
+    ];
+    for (container, service) in &app_yml.services {
+        let what = format!("container {container}");
+        names.push((naming::ip_env_var(app_id, container), what.clone()));
+        names.push((naming::port_env_var(app_id, container), what));
+        for hidden_services in [&service.hidden_services, &service.i2p_tunnels]
+            .into_iter()
+            .flatten()
+        {
+            let services: Vec<&String> = match hidden_services {
+                HiddenServices::PortMap(_) if container == main_container => continue,
+                HiddenServices::PortMap(_) => vec![container],
+                HiddenServices::LayeredMap(map) => map.keys().collect(),
+            };
+            names.extend(services.into_iter().map(|name| {
+                (
+                    naming::hidden_service_name(app_id, Some(name)),
+                    format!("hidden service {name}"),
+                )
+            }));
+        }
+    }
+    for share in app_yml.metadata.shares.keys() {
+        names.push((share_env_var(app_id, share), format!("share {share}")));
+    }
+    names
+}
+
+/// Lists all app directories in the apps directory
+fn read_app_dirs(apps_dir: &Path) -> Result<Vec<std::fs::DirEntry>> {
+    let mut app_dirs = Vec::new();
+    let apps = std::fs::read_dir(apps_dir)
+        .with_context(|| format!("Failed to read {}", apps_dir.display()))?;
+    for entry in apps {
+        let entry = entry.with_context(|| format!("Failed to read {}", apps_dir.display()))?;
+        if entry.path().is_dir() {
+            app_dirs.push(entry);
+        }
+    }
+    Ok(app_dirs)
+}
+
+// Container -> ports, for an app or interface
+type PortMap = HashMap<String, HashMap<String, Vec<PortMapElement>>>;
+
+fn ips_file(citadel_root: &Path) -> std::path::PathBuf {
+    citadel_root.join("apps").join("ips.yml")
+}
+
+fn ports_file(citadel_root: &Path) -> std::path::PathBuf {
+    citadel_root.join("apps").join("ports.yml")
+}
+
+fn port_cache_file(citadel_root: &Path) -> std::path::PathBuf {
+    citadel_root.join("apps").join("ports.cache.yml")
+}
+
+/// Hands out host ports, moving apps with a lower priority out of the way
+struct PortAssigner {
+    cache: PortCacheMap,
+    /// Ports used by other processes on the host, they are treated like reserved ports
+    external_ports: BTreeSet<u16>,
+    /// Ports of other apps moved to make room for required ports
+    moved: Vec<port_review::PortChange>,
+}
+
+impl PortAssigner {
+    fn new(cache: PortCacheMap, config: &host_ports::HostPortsConfig) -> Result<Self> {
+        let external_ports = host_ports::listening_ports(config)?
+            .into_iter()
+            .filter(|port| !cache.contains_key(port))
+            .chain(config.reserved.iter().copied())
+            .collect();
+        Ok(Self {
+            cache,
+            external_ports,
+            moved: Vec::new(),
+        })
+    }
+
+    fn is_reserved(&self, port: &u16) -> bool {
+        RESERVED_PORTS.contains(port) || self.external_ports.contains(port)
+    }
+
+    /// Whether an app or another process uses the port
+    fn is_taken(&self, port: u16) -> bool {
+        self.is_reserved(&port) || self.cache.contains_key(&port)
+    }
+
+    fn new_port(
+        &self,
+        app: &str,
+        container: &str,
+        internal_port: u16,
+        mut suggested_port: u16,
+    ) -> u16 {
+        while self.is_reserved(&suggested_port) || self.cache.contains_key(&suggested_port) {
+            if let Some(cache_entry) = self.cache.get(&suggested_port) {
+                // A container can have multiple ports (e.g. for additional UI entries)
+                if cache_entry.app == app
+                    && cache_entry.container == container
+                    && (cache_entry.dynamic || cache_entry.internal_port == internal_port)
+                {
+                    return suggested_port;
+                }
+            }
+            suggested_port += 1;
+        }
+
+        suggested_port
+    }
+
+    /// Assigns host ports for `ports` of a container.
+    /// Fails with the app using a port if both need it.
+    fn assign(
+        &mut self,
+        app: &str,
+        container: &str,
+        ports: RangeInclusive<u16>,
+        priority: PortPriority,
+        dynamic: bool,
+        implements: Option<String>,
+    ) -> Result<(), String> {
+        // A range is reserved as a unit, so nothing is reserved if any port of it is taken
+        for port in ports.clone() {
+            if let Some(key) = self.cache.get(&port) {
+                let same_app = (key.app == app
+                    && key.container == container
+                    && (key.dynamic || key.internal_port == port))
+                    || (key.implements == implements && container == "service");
+                if !same_app
+                    && key.priority == PortPriority::Required
+                    && priority == PortPriority::Required
+                {
+                    return Err(key.app.clone());
+                }
+            }
+        }
+        let range_end = *ports.end();
+        for suggested_port in ports {
+            if let Some(key) = self.cache.get(&suggested_port) {
+                if (key.app == app
+                    && key.container == container
+                    && (key.dynamic || key.internal_port == suggested_port))
+                    || (key.implements == implements && container == "service")
+                {
+                    continue;
+                }
+                if key.priority < priority {
+                    // Move the existing app to a new port after the range
+                    let new_port =
+                        self.new_port(&key.app, &key.container, key.internal_port, range_end + 1);
+                    self.moved.push(port_review::PortChange {
+                        app: key.app.clone(),
+                        container: key.container.clone(),
+                        old_port: suggested_port,
+                        new_port,
+                        taken_by: app.to_string(),
+                    });
+                    let new_port_map = self.cache.remove(&suggested_port).unwrap();
+                    self.cache.insert(new_port, new_port_map);
+                    // And insert the new app
+                    self.cache.insert(
+                        suggested_port,
+                        PortCacheMapEntry {
+                            app: app.to_string(),
+                            internal_port: suggested_port,
+                            container: container.to_string(),
+                            dynamic,
+                            implements: implements.clone(),
+                            priority,
+                            reserved: false,
+                        },
+                    );
+                } else if key.priority == PortPriority::Required
+                    && priority == PortPriority::Required
+                {
+                    // The app currently using the port
+                    return Err(key.app.clone());
+                } else {
+                    // Move the new app to a new port
+                    let new_port = self.new_port(app, container, suggested_port, suggested_port);
+                    self.cache.insert(
+                        new_port,
+                        PortCacheMapEntry {
+                            app: app.to_string(),
+                            internal_port: if dynamic { new_port } else { suggested_port },
+                            container: container.to_string(),
+                            dynamic,
+                            implements: implements.clone(),
+                            priority,
+                            reserved: false,
+                        },
+                    );
+                }
+            } else if self.is_reserved(&suggested_port) {
+                let new_port = self.new_port(app, container, suggested_port, suggested_port);
+                self.cache.insert(
+                    new_port,
                     PortCacheMapEntry {
                         app: app.to_string(),
                         internal_port: suggested_port,
                         container: container.to_string(),
                         dynamic,
-                        implements,
+                        implements: implements.clone(),
                         priority,
+                        reserved: false,
                     },
                 );
-            } else if key.priority == PortPriority::Required && priority == PortPriority::Required {
-                return false;
             } else {
-                // Move the new app to a new port
-                let new_port = get_new_port(app, container, suggested_port);
-                port_map_cache.insert(
-                    new_port,
+                self.cache.insert(
+                    suggested_port,
                     PortCacheMapEntry {
                         app: app.to_string(),
-                        internal_port: if dynamic { new_port } else { suggested_port },
+                        internal_port: suggested_port,
                         container: container.to_string(),
                         dynamic,
-                        implements,
+                        implements: implements.clone(),
                         priority,
+                        reserved: false,
                     },
                 );
             }
-        } else if RESERVED_PORTS.contains(&suggested_port) {
-            let new_port = get_new_port(app, container, suggested_port);
-            port_map_cache.insert(
-                new_port,
-                PortCacheMapEntry {
-                    app: app.to_string(),
-                    internal_port: suggested_port,
-                    container: container.to_string(),
-                    dynamic,
-                    implements,
-                    priority,
-                },
-            );
-        } else {
-            port_map_cache.insert(
-                suggested_port,
-                PortCacheMapEntry {
-                    app: app.to_string(),
-                    internal_port: suggested_port,
-                    container: container.to_string(),
-                    dynamic,
-                    implements,
-                    priority,
-                },
-            );
         }
-        true
-    };
+        Ok(())
+    }
 
-    if citadel_seed.is_none() {
-        tracing::warn!("Citadel does not seem to be set up yet!");
+    /// The port map apps are converted with, without ports reserved by the operator
+    fn port_map(&self) -> PortMap {
+        let mut port_map = PortMap::new();
+        for (port_number, cache_entry) in &self.cache {
+            if cache_entry.reserved {
+                continue;
+            }
+            let key = match cache_entry.implements {
+                Some(ref implements) if cache_entry.container == "service" => implements,
+                _ => &cache_entry.app,
+            };
+            port_map
+                .entry(key.clone())
+                .or_default()
+                .entry(cache_entry.container.clone())
+                .or_default()
+                .push(PortMapElement {
+                    dynamic: cache_entry.dynamic,
+                    internal_port: cache_entry.internal_port,
+                    public_port: *port_number,
+                });
+        }
+        port_map
     }
+}
+
+/// The ports and IPs assigned to the apps
+struct Assignments {
+    ip_map: HashMap<String, String>,
+    ports: PortAssigner,
+    /// The assignments of the last conversion, only kept when converting a single app
+    cached: Option<(PortCacheMap, HashMap<String, String>)>,
+    /// Set once the port changes are accepted
+    port_map: PortMap,
+    /// Interface -> the app serving it
+    interface_owners: BTreeMap<String, String>,
+}
 
-    preprocessing::preprocess_apps(citadel_root, &citadel_root.join("apps"))
-        .expect("Preprocessing apps failed");
+/// What generating the compose files of the apps produced for the node-wide files
+#[derive(Default)]
+struct AppOutputs {
+    virtual_apps: HashMap<String, Vec<String>>,
+    /// Installed app -> its permissions, used to determine the start order
+    app_permissions: HashMap<String, Vec<String>>,
+    store_stats: stats::StoreStats,
+    tor_entries: Vec<String>,
+    i2p_entries: Vec<String>,
+    caddy_entries: HashMap<String, Vec<CaddyEntry>>,
+    /// App ID -> name, shown on maintenance pages
+    app_names: HashMap<String, String>,
+    app_projects: BTreeMap<String, projects::AppProject>,
+    /// Installed app -> its static assets directory
+    static_assets: BTreeMap<String, String>,
+    /// Ports published by installed apps, for the firewall
+    published_ports: BTreeSet<(u16, Protocol)>,
+}
 
-    let mut data_dirs = HashMap::new();
-    let mut unsupported_apps = Vec::new();
-    for app in apps {
-        let app = app.expect("Error reading app directory!");
-        let app_id = app.file_name();
-        let app_id = app_id.to_str().unwrap();
-        let app_yml = app.path().join("app.yml");
-        let Ok(app_yml) = std::fs::File::open(app_yml) else {
-            tracing::error!("Missing app.yml for app {}", app_id);
-            continue;
+/// A Caddyfile written by a conversion
+struct Caddyfile {
+    contents: String,
+    /// App ID -> the addresses its site blocks can be recognized by, to attribute errors to apps
+    app_addresses: BTreeMap<String, Vec<String>>,
+    /// Whether Caddy checks the Caddyfile before it is loaded
+    validate: bool,
+}
+
+impl Caddyfile {
+    /// Loads the Caddyfile into Caddy, unless Caddy rejects it
+    fn load(&self, caddy_url: &str) -> Result<()> {
+        let caddy_url = url::Url::parse(caddy_url)?;
+        let mut valid = true;
+        if self.validate {
+            match caddy_adapt::adapt(&caddy_url, &self.contents, &self.app_addresses) {
+                Ok(result) => {
+                    for warning in &result.warnings {
+                        tracing::warn!("Caddy warning: {}", warning);
+                    }
+                    for error in &result.errors {
+                        tracing::error!("Caddy rejected the Caddyfile: {}", error);
+                    }
+                    valid = result.errors.is_empty();
+                }
+                Err(err) => tracing::warn!("Failed to validate the Caddy config: {:#}", err),
+            }
+        }
+        if valid {
+            let parsed_caddyfile = caddyfile_parser::parse_caddyfile("Caddyfile", &self.contents);
+            let caddy_url = caddy_url.join("/load")?;
+            if let Err(err) = reqwest::blocking::Client::new()
+                .post(caddy_url)
+                .header("Content-Type", "application/json")
+                .body(parsed_caddyfile)
+                .send()
+            {
+                tracing::warn!("Failed to update Caddy config: {:#?}", err);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The inputs and results shared by the stages of a conversion
+struct Conversion<'a> {
+    citadel_root: &'a Path,
+    paths: paths::CitadelPaths,
+    node_config: node_config::NodeConfig,
+    host_facts: host_facts::HostFacts,
+    app_stores: Vec<stores::AppStoreInfo>,
+    /// Installed apps and the services the node provides
+    services: Vec<String>,
+    https_options: Option<https::HttpsOptions>,
+    public_exposure: exposure::PublicExposure,
+    vpn_exposure: exposure::VpnExposure,
+    citadel_seed: Option<String>,
+    single_app: Option<&'a str>,
+    dry_run: bool,
+    metrics: metrics::ConversionMetrics,
+    /// App -> why it can't be installed
+    unsupported_apps: BTreeMap<String, Vec<UnsupportedReason>>,
+}
+
+impl<'a> Conversion<'a> {
+    fn load(
+        citadel_root: &'a Path,
+        paths: paths::CitadelPaths,
+        app_stores: Vec<stores::AppStoreInfo>,
+        single_app: Option<&'a str>,
+        dry_run: bool,
+    ) -> Result<Self> {
+        let metrics = metrics::ConversionMetrics::start();
+        let mut node_config = node_config::NodeConfig::load(&paths, citadel_root)?;
+        if dry_run {
+            node_config.port_forwarding.enabled = false;
+        }
+        // Probed on the first conversion, templates rendered during preprocessing read them too
+        let host_facts = host_facts::load(&paths, citadel_root)?;
+
+        let mut services = Vec::<String>::new();
+        let mut https_options: Option<https::HttpsOptions> = None;
+        let mut public_exposure = exposure::PublicExposure::default();
+        let mut vpn_exposure = exposure::VpnExposure::default();
+        let user_json = paths.open(&citadel_root.join("db").join("user.json"));
+        if let Ok(user_json) = user_json {
+            let user_json = serde_json::from_reader::<_, UserJson>(user_json);
+            if let Ok(user_json) = user_json {
+                services = user_json.installed_apps;
+                https_options = user_json.https.and_then(|https| {
+                    serde_json::from_value::<https::HttpsOptions>(https)
+                        .map_err(anyhow::Error::from)
+                        .and_then(|options| options.validate().map(|_| options))
+                        .map_err(|err| {
+                            tracing::error!("Invalid https options in user.json: {}", err)
+                        })
+                        .ok()
+                });
+                public_exposure = user_json.public_exposure;
+                vpn_exposure = user_json.vpn_exposure;
+            }
+        }
+        node_config.base_services.add_to(&mut services);
+
+        let mut citadel_seed = None;
+
+        let citadel_seed_file = citadel_root.join("db").join("citadel-seed").join("seed");
+
+        if paths.exists(&citadel_seed_file) {
+            citadel_seed = Some(paths.read_to_string(&citadel_seed_file)?);
+        }
+        Ok(Self {
+            citadel_root,
+            paths,
+            node_config,
+            host_facts,
+            app_stores,
+            services,
+            https_options,
+            public_exposure,
+            vpn_exposure,
+            citadel_seed,
+            single_app,
+            dry_run,
+            metrics,
+            unsupported_apps: BTreeMap::new(),
+        })
+    }
+
+    /// Loads ips.yml and ports.cache.yml, rebuilding them if they are corrupt
+    fn load_assignments(&self) -> Result<Assignments> {
+        let paths = &self.paths;
+        let ip_addresses_map_file = ips_file(self.citadel_root);
+        let had_ip_map = paths.exists(&ip_addresses_map_file);
+        let ip_map =
+            cache_recovery::load(paths, &ip_addresses_map_file, cache_recovery::validate_ips)?;
+        let recovered_ip_map = had_ip_map && ip_map.is_none();
+        let env_file = self.citadel_root.join(".env");
+        let ip_map: HashMap<String, String> = match ip_map {
+            Some(ip_map) => ip_map,
+            // Every assigned IP was also saved to the .env file
+            None if recovered_ip_map && paths.exists(&env_file) => {
+                cache_recovery::ips_from_env(&paths.read_to_string(&env_file)?)
+            }
+            None => HashMap::new(),
         };
-        let app_yml = load_config_as_v4(app_yml, &Some(&services.to_vec()));
-        let Ok(app_yml) = app_yml else {
-            tracing::error!("Error processing app.yml: {}", app_yml.unwrap_err());
-            continue;
+        let port_cache_map_file = port_cache_file(self.citadel_root);
+        let had_port_map_cache = paths.exists(&port_cache_map_file);
+        let port_map_cache = cache_recovery::load(
+            paths,
+            &port_cache_map_file,
+            |cache: &PortCacheMap| match cache
+                .iter()
+                .find(|(port, entry)| **port == 0 || entry.app.is_empty())
+            {
+                Some((port, _)) => bail!("Invalid entry for port {port}"),
+                None => Ok(()),
+            },
+        )?;
+        let recovered_port_map_cache = had_port_map_cache && port_map_cache.is_none();
+        let port_map_cache: PortCacheMap = match port_map_cache {
+            Some(port_map_cache) => port_map_cache,
+            None if recovered_port_map_cache => rebuild_port_cache(
+                paths,
+                &ports_file(self.citadel_root),
+                &switchover::load_owners(paths, self.citadel_root)?,
+            )?,
+            None => HashMap::new(),
         };
+        let cached = match self.single_app {
+            Some(_) if !had_port_map_cache || !had_ip_map => {
+                bail!("No ports and IPs have been assigned yet, run a full conversion first")
+            }
+            Some(_) if recovered_port_map_cache || recovered_ip_map => {
+                bail!("The port or IP assignments had to be rebuilt, run a full conversion first")
+            }
+            Some(_) => Some((port_map_cache.clone(), ip_map.clone())),
+            None => None,
+        };
+        Ok(Assignments {
+            ip_map,
+            ports: PortAssigner::new(port_map_cache, &self.node_config.host_ports)?,
+            cached,
+            port_map: PortMap::new(),
+            interface_owners: BTreeMap::new(),
+        })
+    }
 
-        //Part 2: IP & Port assignment, also save data dirs
-        let main_container = get_main_container(&app_yml.services)?;
-        let has_service = app_yml.services.contains_key("service");
-        for (service_name, service) in &app_yml.services {
-            let ip_name = format!(
-                "APP_{}_{}_IP",
-                app_id.to_uppercase().replace('-', "_"),
-                service_name.to_uppercase().replace('-', "_")
+    /// Renders the app.yml templates, returns app -> error for apps that failed
+    fn preprocess(&mut self) -> Result<BTreeMap<String, String>> {
+        let apps_dir = self.citadel_root.join("apps");
+        let mut failed_apps =
+            preprocessing::preprocess_apps(&self.paths, &apps_dir, self.single_app)
+                .context("Preprocessing apps failed")?;
+        let unsupported_file = apps_dir.join("unsupported.json");
+        if self.single_app.is_some() && self.paths.exists(&unsupported_file) {
+            // The templates of other apps were not rendered again, so their last result still applies
+            let previous: BTreeMap<String, Vec<UnsupportedReason>> =
+                serde_json::from_str(&self.paths.read_to_string(&unsupported_file)?)
+                    .context("Failed to load unsupported.json")?;
+            for (app_id, reasons) in previous {
+                for reason in reasons {
+                    if let UnsupportedReason::ConversionFailed { error } = reason {
+                        if Some(app_id.as_str()) != self.single_app {
+                            failed_apps.insert(app_id.clone(), error);
+                        }
+                    }
+                }
+            }
+        }
+        for (app_id, error) in &failed_apps {
+            self.metrics.add_failure(app_id, "preprocess", error);
+        }
+        self.metrics.finish_stage("preprocess");
+        for (app_id, error) in &failed_apps {
+            mark_unsupported(
+                &mut self.unsupported_apps,
+                app_id,
+                UnsupportedReason::ConversionFailed {
+                    error: error.clone(),
+                },
             );
-            if let std::collections::hash_map::Entry::Vacant(e) = ip_map.entry(ip_name) {
-                if current_suffix == 255 {
-                    panic!("Too many apps!");
+        }
+        Ok(failed_apps)
+    }
+
+    /// Parses the app.yml files of all apps whose templates rendered
+    fn load_apps(
+        &mut self,
+        app_ids: &[String],
+        failed_apps: &BTreeMap<String, String>,
+    ) -> HashMap<String, AppDefinition> {
+        let mut app_ymls = HashMap::new();
+        for app_id in app_ids {
+            // A failed template may have left an outdated app.yml behind
+            if failed_apps.contains_key(app_id) {
+                continue;
+            }
+            let app_yml = self.citadel_root.join("apps").join(app_id).join("app.yml");
+            if !self.paths.exists(&app_yml) {
+                tracing::error!("Missing app.yml for app {}", app_id);
+                self.metrics
+                    .add_failure(app_id, "assign", "Missing app.yml");
+                continue;
+            }
+            match load_definition_file_with(&app_yml, Some(&self.services), &|path| {
+                self.paths.read_path(path)
+            }) {
+                Ok(app_yml) => {
+                    app_ymls.insert(app_id.clone(), app_yml);
+                }
+                Err(err) => {
+                    tracing::error!("Error processing app.yml: {:#}", err);
+                    self.metrics
+                        .add_failure(app_id, "assign", &format!("{err:#}"));
                 }
-                let ip = "10.21.21.".to_owned() + current_suffix.to_string().as_str();
-                e.insert(ip);
-                current_suffix += 1;
             }
-            if let Some(main_port) = service.port {
-                let port_available = validate_port(
-                    app_id,
-                    service_name,
-                    main_port,
-                    service.port_priority.unwrap_or(PortPriority::Optional),
-                    false,
-                    app_yml.metadata.implements.clone(),
-                );
-                assert!(
-                    port_available,
-                    "Failed to get an available port for {} {} {}",
-                    app_id, service_name, main_port
-                );
-            } else if main_container == service_name {
-                let port_available = validate_port(
+        }
+        app_ymls
+    }
+
+    /// Assigns host ports to the containers of all apps, in the order of `app_ids`.
+    /// Apps whose required ports are used by another app are marked as unsupported.
+    fn assign_ports(
+        &mut self,
+        app_ids: &[String],
+        app_ymls: &HashMap<String, AppDefinition>,
+        ports: &mut PortAssigner,
+    ) -> Result<()> {
+        for (app_id, app_yml) in app_ids
+            .iter()
+            .filter_map(|app_id| Some((app_id.as_str(), app_ymls.get(app_id)?)))
+        {
+            let main_container = get_main_container(
+                &app_yml.services,
+                app_yml.metadata.main_container.as_deref(),
+            )?;
+            let implements = &app_yml.metadata.implements;
+            for (service_name, service) in &app_yml.services {
+                if let Some(main_port) = service.port {
+                    let port_available = ports.assign(
+                        app_id,
+                        service_name,
+                        main_port..=main_port,
+                        service.port_priority.unwrap_or(PortPriority::Optional),
+                        false,
+                        implements.clone(),
+                    );
+                    if let Err(used_by) = port_available {
+                        bail!(
+                            "Failed to get an available port for {} {} {}, it is used by {}",
+                            app_id,
+                            service_name,
+                            main_port,
+                            used_by
+                        );
+                    }
+                } else if main_container == service_name {
+                    let port_available = ports.assign(
+                        app_id,
+                        service_name,
+                        3000..=3000,
+                        PortPriority::Optional,
+                        true,
+                        implements.clone(),
+                    );
+                    // Optional ports should alwas be available
+                    if let Err(used_by) = port_available {
+                        bail!(
+                            "Failed to get an available port for {} {} {}, it is used by {}",
+                            app_id,
+                            service_name,
+                            3000,
+                            used_by
+                        );
+                    }
+                }
+                let Some(required_ports) = &service.required_ports else {
+                    continue;
+                };
+                let single_ports = [("TCP", &required_ports.tcp), ("UDP", &required_ports.udp)]
+                    .into_iter()
+                    .flat_map(|(protocol, ports)| {
+                        ports
+                            .iter()
+                            .flat_map(|ports| ports.keys())
+                            .map(move |port| (protocol.to_string(), *port..=*port))
+                    });
+                let ranges = required_ports
+                    .ranges
+                    .iter()
+                    .map(|range| (range.protocol.to_string(), range.ports()));
+                for (protocol, host_ports) in single_ports.chain(ranges) {
+                    let port = *host_ports.start();
+                    if let Err(used_by) = ports.assign(
+                        app_id,
+                        service_name,
+                        host_ports,
+                        PortPriority::Required,
+                        false,
+                        implements.clone(),
+                    ) {
+                        mark_unsupported(
+                            &mut self.unsupported_apps,
+                            app_id,
+                            UnsupportedReason::PortConflict {
+                                container: service_name.to_string(),
+                                port,
+                                protocol,
+                                used_by,
+                            },
+                        );
+                    }
+                }
+            }
+            // Additional UI entries get their own port
+            for entry in &app_yml.metadata.entries {
+                let port_available = ports.assign(
                     app_id,
-                    service_name,
-                    3000,
+                    &entry.container,
+                    entry.port..=entry.port,
                     PortPriority::Optional,
-                    true,
-                    app_yml.metadata.implements.clone(),
-                );
-                // Optional ports should alwas be available
-                assert!(
-                    port_available,
-                    "Failed to get an available port for {} {} {}",
-                    app_id, service_name, 3000
+                    false,
+                    implements.clone(),
                 );
+                if let Err(used_by) = port_available {
+                    bail!(
+                        "Failed to get an available port for {} {} {}, it is used by {}",
+                        app_id,
+                        entry.container,
+                        entry.port,
+                        used_by
+                    );
+                }
             }
-            if let Some(ports) = &service.required_ports {
-                if let Some(tcp_ports) = &ports.tcp {
-                    for host_port in tcp_ports.keys() {
-                        let port_available = validate_port(
+        }
+        Ok(())
+    }
+
+    /// Marks apps as unsupported if shares, sockets or dependencies they use are missing,
+    /// the host doesn't meet their requirements or their images don't support it
+    fn check_requirements(&mut self, app_ymls: &HashMap<String, AppDefinition>) -> Result<()> {
+        let unsupported_apps = &mut self.unsupported_apps;
+        for (app_id, app_yml) in app_ymls {
+            for (service_name, service) in &app_yml.services {
+                for mount in &service.shared_mounts {
+                    let definition = app_ymls
+                        .get(&mount.app)
+                        .and_then(|other_app| other_app.metadata.shares.get(&mount.share));
+                    match definition {
+                        None => mark_unsupported(
+                            unsupported_apps,
                             app_id,
-                            service_name,
-                            *host_port,
-                            PortPriority::Required,
-                            false,
-                            app_yml.metadata.implements.clone(),
-                        );
-                        if !port_available {
-                            tracing::warn!(
-                                "App {} (container {}) requires port {} (on TCP), but this port is already in use!",
+                            UnsupportedReason::MissingShare {
+                                container: service_name.to_string(),
+                                app: mount.app.clone(),
+                                share: mount.share.clone(),
+                            },
+                        ),
+                        Some(definition) if mount.writable && !definition.writable => {
+                            mark_unsupported(
+                                unsupported_apps,
                                 app_id,
-                                service_name,
-                                host_port,
-                            );
-                            unsupported_apps.push(app_id.to_owned());
+                                UnsupportedReason::ReadOnlyShare {
+                                    container: service_name.to_string(),
+                                    app: mount.app.clone(),
+                                    share: mount.share.clone(),
+                                },
+                            )
                         }
+                        Some(_) => {}
                     }
                 }
-                if let Some(udp_ports) = &ports.udp {
-                    for host_port in udp_ports.keys() {
-                        let port_available = validate_port(
+                for socket in &service.sockets {
+                    let Some(other_app) =
+                        socket.app.as_ref().filter(|other_app| *other_app != app_id)
+                    else {
+                        continue;
+                    };
+                    if !app_ymls
+                        .get(other_app)
+                        .is_some_and(|other_app| other_app.metadata.sockets.contains(&socket.name))
+                    {
+                        mark_unsupported(
+                            unsupported_apps,
                             app_id,
-                            service_name,
-                            *host_port,
-                            PortPriority::Required,
-                            false,
-                            app_yml.metadata.implements.clone(),
+                            UnsupportedReason::MissingSocket {
+                                container: service_name.to_string(),
+                                app: other_app.clone(),
+                                socket: socket.name.clone(),
+                            },
                         );
-                        if !port_available {
-                            tracing::warn!(
-                                "App {} (container {}) requires port {} (on UDP), but this port is already in use!",
-                                app_id,
-                                service_name,
-                                host_port,
-                            );
-                            unsupported_apps.push(app_id.to_owned());
-                        }
                     }
                 }
             }
-            if let Some(mounts) = &service.mounts {
-                if let Some(shared_data) = mounts.get("shared_data") {
-                    if let StringOrMap::String(_) = shared_data {
-                        tracing::warn!(
-                            "App {} defines a string instead of an hashmap as shared data mount",
-                            app_id
-                        );
-                        continue;
-                    } else if let StringOrMap::Map(map) = shared_data {
-                        if map.len() != 1 {
-                            tracing::warn!(
-                                "App {} has multiple shared data mounts, this is not supported!",
-                                app_id
-                            );
-                            continue;
-                        }
-                        if (has_service && service_name == "service")
-                            || (!has_service && service_name == main_container)
-                        {
-                            data_dirs.insert(
-                                app_id.to_lowercase().clone(),
-                                map.keys().next().unwrap().clone(),
-                            );
-                        } else {
-                            tracing::warn!("App either has no service container and a shared_data mount in a container that is not the main container, or it has a service container and a shared_data mount in a container that is not the service container. This is not supported!");
-                        }
+        }
+        for (app_id, app_yml) in app_ymls {
+            for reason in self.host_facts.unmet_requirements(app_yml) {
+                mark_unsupported(unsupported_apps, app_id, reason);
+            }
+            // Dependencies on the service already resolve to the node
+            if self.node_config.base_services.provides(app_id) {
+                mark_unsupported(unsupported_apps, app_id, UnsupportedReason::ProvidedByNode);
+            }
+        }
+        // Without this, an image missing for this architecture only fails on docker compose up
+        if self.node_config.image_checks.check_architecture && !self.node_config.http_cache.offline
+        {
+            let mut checker = image_arch::ArchitectureChecker::new(
+                &self.node_config.image_checks,
+                &self.node_config.registries,
+                &self.host_facts,
+            )?;
+            for (app_id, app_yml) in app_ymls {
+                for (service_name, service) in &app_yml.services {
+                    if let Some(reason) = checker.check(service_name, &service.image) {
+                        mark_unsupported(unsupported_apps, app_id, reason);
                     }
                 }
             }
         }
+        Ok(())
     }
-    // Part 3: Convert port cache map to port map
-    for (port_number, cache_entry) in port_map_cache.clone() {
-        let mut key = cache_entry.app;
-        if cache_entry.implements.is_some() && cache_entry.container == "service" {
-            key = cache_entry.implements.unwrap();
+
+    /// Asks the operator to accept moved ports of installed apps, or logs them in a dry run
+    fn review_port_changes(
+        &mut self,
+        port_changes: port_review::PortChangePolicy,
+        ports: &mut PortAssigner,
+    ) -> Result<()> {
+        // Changed ports of apps that are not installed don't affect anything yet
+        let services = &self.services;
+        ports.moved.retain(|change| services.contains(&change.app));
+        if self.dry_run && !ports.moved.is_empty() {
+            tracing::warn!("{}", port_review::report(&ports.moved).trim_end());
         }
-        if !port_map.contains_key(&key) {
-            port_map.insert(key.clone(), HashMap::new());
+        if !port_review::confirm(
+            port_changes,
+            &ports.moved,
+            std::io::stdin().lock(),
+            std::io::stderr(),
+        )? {
+            bail!("Port changes were not accepted, the port map was not changed");
         }
-        let app_port_map = port_map.get_mut(&key).unwrap();
-        if !app_port_map.contains_key(&cache_entry.container) {
-            app_port_map.insert(cache_entry.container.clone(), Vec::new());
+        self.metrics.set_port_changes(&ports.moved);
+        self.metrics.finish_stage("port_review");
+        Ok(())
+    }
+
+    /// Picks the apps serving interfaces and writes the ports, IPs and interface owners
+    fn write_assignments(
+        &self,
+        app_ids: &[String],
+        app_ymls: &HashMap<String, AppDefinition>,
+        assignments: &mut Assignments,
+    ) -> Result<()> {
+        let paths = &self.paths;
+        let citadel_root = self.citadel_root;
+        assignments.port_map = assignments.ports.port_map();
+        // Only one installed app serves an interface, a new one takes over once it is ready
+        let mut implementations: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+        for (app_id, app_yml) in app_ymls {
+            if let Some(interface) = &app_yml.metadata.implements {
+                if self.services.contains(app_id) && !self.unsupported_apps.contains_key(app_id) {
+                    implementations
+                        .entry(interface.clone())
+                        .or_default()
+                        .insert(app_id.clone());
+                }
+            }
         }
-        let service_port_map = app_port_map.get_mut(&cache_entry.container).unwrap();
-        service_port_map.push(PortMapElement {
-            dynamic: cache_entry.dynamic,
-            internal_port: cache_entry.internal_port,
-            public_port: port_number,
-        });
+        assignments.interface_owners = switchover::select_owners(
+            &switchover::load_owners(paths, citadel_root)?,
+            &implementations,
+            |app_id, interface| {
+                interface_health::is_ready(
+                    app_id,
+                    interface,
+                    &assignments.ip_map,
+                    &assignments.port_map,
+                    &self.node_config.interface_probes,
+                )
+            },
+        );
+        paths.write(
+            &ports_file(citadel_root),
+            serde_yaml::to_string(&assignments.port_map)?,
+        )?;
+        paths.write(
+            &port_cache_file(citadel_root),
+            serde_yaml::to_string(&assignments.ports.cache)?,
+        )?;
+        paths.write(
+            &ips_file(citadel_root),
+            serde_yaml::to_string(&assignments.ip_map)?,
+        )?;
+        capacity::warn_if_low(&capacity::compute(
+            app_ids,
+            &self.node_config.ip_assignment.subnet,
+            &assignments.ip_map,
+            &assignments.ports.cache,
+            &assignments.ports.external_ports,
+        ));
+        let owners_file = citadel_root.join("apps").join("interface-owners.json");
+        paths.write(
+            &owners_file,
+            serde_json::to_string(&assignments.interface_owners)?,
+        )?;
+        let external_ports_file = citadel_root.join("apps").join("external-ports.json");
+        paths.write(
+            &external_ports_file,
+            serde_json::to_string(&assignments.ports.external_ports)?,
+        )?;
+        Ok(())
     }
-    // Part 4: Write port map to file
-    {
-        let mut port_map_file =
-            std::fs::File::create(port_map_file).expect("Error opening port map file!");
-        port_map_file
-            .write_all(serde_yaml::to_string(&port_map).unwrap().as_bytes())
-            .expect("Error writing port map file!");
-        let mut port_cache_map_file =
-            std::fs::File::create(port_cache_map_file).expect("Error opening port cache map file!");
-        port_cache_map_file
-            .write_all(serde_yaml::to_string(&port_map_cache).unwrap().as_bytes())
-            .expect("Error writing port cache map file!");
-        let ip_map_file =
-            std::fs::File::create(ip_addresses_map_file).expect("Error opening ip map file!");
-        serde_yaml::to_writer(ip_map_file, &ip_map).expect("Error writing ip map file!");
-    }
-
-    // Part 5: Save IP addresses
-    {
+
+    /// Saves IPs, data directories, shares and exports to the .env file.
+    /// Returns app -> the settings set on the dashboard or in the .env file.
+    fn update_env_file(
+        &self,
+        app_ymls: &HashMap<String, AppDefinition>,
+        ip_map: &HashMap<String, String>,
+    ) -> Result<HashMap<String, BTreeMap<String, String>>> {
+        let setting_values = app_settings::load_values(&self.paths, self.citadel_root)?;
+        let mut configured_settings = HashMap::new();
         let mut env_string = String::new();
         // Load the existing env file
-        if let Ok(mut env_file) = std::fs::File::open(citadel_root.join(".env")) {
-            env_file
-                .read_to_string(&mut env_string)
-                .expect("Error reading env file!");
+        let env_file = self.citadel_root.join(".env");
+        if self.paths.exists(&env_file) {
+            env_string = self.paths.read_to_string(&env_file)?;
         }
-        for (key, value) in &ip_map {
-            let to_append = format!("{key}={value}");
+        let previous_env = env_string.clone();
+        let data_dirs = shared_data_dirs(app_ymls);
+        let share_dirs = share_dirs(app_ymls);
+        let lines =
+            ip_map
+                .iter()
+                .map(|(key, value)| format!("{key}={value}"))
+                .chain(data_dirs.iter().map(|(key, value)| {
+                    format!("{}={}", naming::shared_subdir_env_var(key), value)
+                }))
+                .chain(
+                    share_dirs
+                        .iter()
+                        .map(|(key, value)| format!("{key}={value}")),
+                );
+        for to_append in lines {
             if !env_string.contains(&to_append) {
                 env_string.push_str(&(to_append + "\n"));
             }
         }
-        for (key, value) in &data_dirs {
-            let to_append = format!(
-                "APP_{}_SHARED_SUBDIR={}",
-                key.to_uppercase().replace('-', "_"),
-                value
+        // Exports are resolved against the values saved so far and replaced, their values can change
+        let env_values: HashMap<String, String> = env_string
+            .lines()
+            .filter_map(|line| line.split_once('='))
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        for (app_id, app_yml) in app_ymls {
+            configured_settings.insert(
+                app_id.clone(),
+                app_settings::configured(app_yml, &setting_values, app_id, &env_values),
             );
-            if !env_string.contains(&to_append) {
-                env_string.push_str(&(to_append + "\n"));
+        }
+        let mut app_exports = BTreeMap::new();
+        for (app_id, app_yml) in app_ymls
+            .iter()
+            .filter(|(app_id, _)| self.services.contains(app_id))
+        {
+            match exports::resolve(app_id, app_yml, &env_values) {
+                Ok(resolved) => app_exports.extend(resolved),
+                Err(err) => tracing::warn!("Ignoring the exports of {}: {:#}", app_id, err),
             }
         }
-        let mut env_file =
-            std::fs::File::create(citadel_root.join(".env")).expect("Error opening env file!");
-        env_file
-            .write_all(env_string.as_bytes())
-            .expect("Error writing env file!");
+        env_string = exports::update_env_file(&env_string, &app_exports);
+        let redactor = redaction::Redactor::new(&self.node_config.redaction);
+        for change in redactor.env_changes(&previous_env, &env_string) {
+            tracing::debug!(".env: {}", change);
+        }
+        self.paths.write(&env_file, env_string)?;
+        Ok(configured_settings)
     }
 
-    // Part 6: Loop through the appps again and run the actual conversion process
-    let apps = std::fs::read_dir(citadel_root.join("apps")).expect("Error reading apps directory!");
-    let mut app_registry: Vec<OutputMetadata> = Vec::new();
-    let mut virtual_apps: HashMap<String, Vec<String>> = HashMap::new();
-
-    let mut tor_entries: Vec<String> = Vec::new();
-    let mut i2p_entries: Vec<String> = Vec::new();
-
-    let mut caddy_entries = HashMap::new();
-
-    for app in apps {
-        let app = app.expect("Error reading app directory!");
-        let app_id = app.file_name();
-        let app_id = app_id.to_str().unwrap();
-        let app_yml_path = app.path().join("app.yml");
-        let docker_compose_yml_path = app.path().join("docker-compose.yml");
-        // Skip if app.yml does not exist
-        if !app_yml_path.exists() || unsupported_apps.contains(&app_id.to_string()) {
-            // Delete docker-compose.yml if it exists
-            if docker_compose_yml_path.exists() {
-                std::fs::remove_file(docker_compose_yml_path)
-                    .expect("Error deleting docker-compose.yml!");
+    /// Generates the compose files of the apps selected by `filter`, and registers all apps.
+    /// Apps that failed to load or are unsupported lose their compose files.
+    fn generate_compose_files(
+        &mut self,
+        app_ids: &[String],
+        mut app_ymls: HashMap<String, AppDefinition>,
+        mut configured_settings: HashMap<String, BTreeMap<String, String>>,
+        assignments: &Assignments,
+        filter: &app_filter::AppFilter,
+    ) -> Result<AppOutputs> {
+        let paths = &self.paths;
+        let citadel_root = self.citadel_root;
+        let node_config = &self.node_config;
+        let services = &self.services;
+        let port_map = &assignments.port_map;
+        let mut outputs = AppOutputs::default();
+        let mut app_registry = registry::RegistryWriter::create(
+            &paths.write_path(&citadel_root.join("apps").join("registry.json"))?,
+            &paths.write_path(&citadel_root.join("apps").join("registry.index.json"))?,
+        )?;
+        // Apps that are filtered out keep the project of their last conversion
+        let mut previous_projects = projects::load(paths, citadel_root)?;
+        let mut port_forwarder =
+            port_forwarding::PortForwarder::new(paths, &node_config.port_forwarding)?;
+        // Keep the result of the last interface probe until the next one runs
+        let interface_health = interface_health::load_health(paths, citadel_root)?;
+        let dependency_resolver = dependencies::DependencyResolver::new(
+            app_ymls
+                .iter()
+                .map(|(app_id, app_yml)| (app_id, app_yml.metadata.implements.as_ref())),
+            &self.app_stores,
+            &node_config.security.trusted_stores,
+            &node_config.base_services.services,
+        );
+        for (app_id, app_yml) in &app_ymls {
+            for dependency in dependency_resolver.unavailable(&app_yml.metadata.permissions) {
+                mark_unsupported(
+                    &mut self.unsupported_apps,
+                    app_id,
+                    UnsupportedReason::MissingDependency { dependency },
+                );
             }
-            continue;
         }
-        let app_yml = std::fs::File::open(app_yml_path).expect("Error opening app.yml!");
-        let conversion_result = convert_config(
-            app_id,
-            app_yml,
-            &Some(port_map.clone()),
-            &Some(services.clone()),
-            &Some(ip_map.clone()),
-        );
-        if let Ok(result_data) = conversion_result {
-            let mut docker_compose_yml_file = std::fs::File::create(docker_compose_yml_path)
-                .expect("Error opening docker-compose.yml!");
-            serde_yaml::to_writer(&mut docker_compose_yml_file, &result_data.spec)
-                .expect("Error writing docker-compose.yml!");
-            tor_entries.push(result_data.new_tor_entries + "\n");
-            i2p_entries.push(result_data.new_i2p_entries + "\n");
+
+        // App -> apps it shares a network with, if apps are isolated
+        let app_links = network_isolation::links(&app_ymls);
+        let redactor = redaction::Redactor::new(&node_config.redaction);
+        for app_id in app_ids {
+            let app_started = Instant::now();
+            let app_id = app_id.as_str();
+            let app_dir = citadel_root.join("apps").join(app_id);
+            let docker_compose_yml_path = app_dir.join("docker-compose.yml");
+            let docker_compose_override_path = app_dir.join(compose::OVERRIDE_FILE);
+            // Skip if app.yml does not exist or could not be loaded
+            let Some(mut app_yml) = app_ymls.remove(app_id) else {
+                // Delete docker-compose.yml if it exists
+                paths.remove_file(&docker_compose_yml_path)?;
+                paths.remove_file(&docker_compose_override_path)?;
+                continue;
+            };
+            node_config.apply_defaults(&mut app_yml);
+            let configured = configured_settings.remove(app_id).unwrap_or_default();
+            app_settings::apply(&mut app_yml, &configured);
+            let store = self
+                .app_stores
+                .iter()
+                .find(|store| store.apps.contains_key(app_id));
+            let security_profiles: HashMap<String, SecurityProfiles> = app_yml
+                .services
+                .iter()
+                .filter_map(|(name, service)| {
+                    Some((name.clone(), service.security_profiles.clone()?))
+                })
+                .collect();
+            let static_assets_dir = app_yml.metadata.static_assets.clone();
+            let shared_containers = network_isolation::shared_containers(&app_yml)?;
+            // Apps that are filtered out keep their compose files, but stay in the registry
+            let selected = filter.matches(
+                app_id,
+                &app_yml.metadata.category,
+                store.map(|store| store.id.as_str()),
+                services.iter().any(|service| service == app_id),
+            ) && self
+                .single_app
+                .is_none_or(|single_app| single_app == app_id);
+            // Until it takes over, an implementation does not get the interface's ports
+            let waiting_for = app_yml.metadata.implements.as_ref().filter(|interface| {
+                assignments
+                    .interface_owners
+                    .get(*interface)
+                    .is_some_and(|owner| owner != app_id)
+            });
+            let app_port_map = waiting_for.map(|interface| {
+                let mut app_port_map = port_map.clone();
+                app_port_map.remove(interface);
+                app_port_map
+            });
+            let conversion_result = convert_config(
+                app_id,
+                app_yml,
+                Some(app_port_map.as_ref().unwrap_or(port_map)),
+                Some(services),
+                Some(&assignments.ip_map),
+            );
+            if let Some(reasons) = self.unsupported_apps.get(app_id) {
+                // Keep the app in the registry, so the dashboard can explain why it can't be installed
+                if selected {
+                    paths.remove_file(&docker_compose_yml_path)?;
+                    paths.remove_file(&docker_compose_override_path)?;
+                }
+                let reasons_text: Vec<String> = reasons.iter().map(ToString::to_string).collect();
+                self.metrics.add_skipped(app_id, &reasons_text.join(", "));
+                if let Ok(result_data) = conversion_result {
+                    let mut metadata = result_data.metadata;
+                    metadata.unsupported = reasons.clone();
+                    outputs.store_stats.add_app(&metadata, &result_data.spec);
+                    app_registry.push(&metadata)?;
+                }
+                continue;
+            }
+            let conversion_result = conversion_result.and_then(|mut result_data| {
+                security::apply_security_options(
+                    paths,
+                    &node_config.security,
+                    app_id,
+                    store.map(|store| store.id.as_str()),
+                    &security_profiles,
+                    &mut result_data.spec,
+                    self.dry_run,
+                )?;
+                runtime::adjust_for_runtime(&node_config.runtime, app_id, &mut result_data.spec);
+                runtime::adjust_for_engine(&node_config.runtime, app_id, &mut result_data.spec);
+                locale::inject_locale(&node_config.locale, app_id, &mut result_data.spec);
+                dns::apply_dns(&node_config.dns, &mut result_data.spec);
+                node_config.registries.apply_mirrors(&mut result_data.spec);
+                if node_config.network_isolation.enabled {
+                    network_isolation::isolate(
+                        app_id,
+                        &shared_containers,
+                        app_links.get(app_id),
+                        &mut result_data.spec,
+                    );
+                }
+                Ok(result_data)
+            });
+            let mut result_data = match conversion_result {
+                Ok(result_data) => result_data,
+                Err(error) => {
+                    // Delete docker-compose.yml if it exists
+                    if selected {
+                        paths.remove_file(&docker_compose_yml_path)?;
+                        paths.remove_file(&docker_compose_override_path)?;
+                    }
+                    tracing::error!("Error converting app.yml for app {}: {}", app_id, error);
+                    self.metrics
+                        .add_failure(app_id, "convert", &error.to_string());
+                    self.metrics.add_app_time(app_id, app_started.elapsed());
+                    continue;
+                }
+            };
+            add_labels(
+                &mut result_data.spec,
+                app_id,
+                &result_data.metadata.version,
+                store.map(|store| store.id.as_str()),
+            );
+            // Apps only reachable through a VPN ignore the configured addresses
+            let vpn_addresses = self.vpn_exposure.bind_addresses(app_id);
+            let bind_addresses = if vpn_addresses.is_empty() {
+                node_config.port_binding.bind_addresses(app_id)
+            } else {
+                &vpn_addresses
+            };
+            exposure::bind_ports(&mut result_data.spec, bind_addresses);
+            if selected {
+                let (base_spec, generated_spec) = compose::split_generated(&result_data.spec);
+                let docker_compose_yml_file = paths.create(&docker_compose_yml_path)?;
+                serde_yaml::to_writer(docker_compose_yml_file, &base_spec)
+                    .with_context(|| format!("Failed to write docker-compose.yml for {app_id}"))?;
+                let docker_compose_override_file = paths.create(&docker_compose_override_path)?;
+                serde_yaml::to_writer(docker_compose_override_file, &generated_spec).with_context(
+                    || format!("Failed to write {} for {app_id}", compose::OVERRIDE_FILE),
+                )?;
+                outputs.app_projects.insert(
+                    app_id.to_string(),
+                    projects::describe(app_id, &result_data.spec),
+                );
+                self.metrics.add_converted(app_id);
+            } else {
+                if let Some(project) = previous_projects.remove(app_id) {
+                    outputs.app_projects.insert(app_id.to_string(), project);
+                }
+                self.metrics.add_skipped(app_id, "filtered out");
+            }
+            outputs.tor_entries.push(result_data.new_tor_entries + "\n");
+            outputs.i2p_entries.push(result_data.new_i2p_entries + "\n");
             let mut metadata = result_data.metadata;
             if metadata.default_password.clone().unwrap_or_default() == "$APP_SEED" {
-                if let Some(ref citadel_seed) = citadel_seed {
+                if let Some(ref citadel_seed) = self.citadel_seed {
                     metadata.default_password = Some(derive_entropy(
                         citadel_seed,
                         format!("app-{app_id}-seed").as_str(),
@@ -471,53 +1371,125 @@ pub fn convert_dir(citadel_root: &str, caddy_url: &Option<String>) -> Result<()>
                     metadata.default_password = Some("Please reboot your node, default password does not seem to be available yet.".to_string());
                 }
             }
+            let store_id = store.map(|store| store.id.as_str());
+            metadata.cross_store_dependencies = dependency_resolver.cross_store_dependencies(
+                &metadata.permissions,
+                store_id,
+                services,
+            );
+            metadata.bundles = bundles::memberships(store, app_id);
+            // The values of secrets are not shown on the dashboard
+            for setting in metadata
+                .settings
+                .iter_mut()
+                .filter(|setting| !redactor.is_secret(&setting.id))
+            {
+                setting.value = configured.get(&setting.id).cloned();
+            }
             if let Some(ref implements) = metadata.implements {
-                if let std::collections::hash_map::Entry::Vacant(entry) =
-                    virtual_apps.entry(implements.clone())
+                outputs
+                    .virtual_apps
+                    .entry(implements.clone())
+                    .or_default()
+                    .push(app_id.to_string());
+            }
+            if services.contains(&app_id.to_string()) {
+                if let Some(missing) = &metadata.missing_dependencies {
+                    tracing::error!(
+                        "App {} is missing dependencies: {}",
+                        app_id,
+                        dependency_resolver.describe_missing(missing, store_id)
+                    );
+                }
+                for dependency in metadata
+                    .cross_store_dependencies
+                    .iter()
+                    .filter(|dependency| !dependency.trusted)
                 {
-                    entry.insert(vec![app_id.to_string()]);
-                } else {
-                    virtual_apps
-                        .get_mut(implements)
-                        .unwrap()
-                        .push(app_id.to_string());
+                    tracing::warn!(
+                        "App {} depends on {}, which is provided by {} from the untrusted store {}",
+                        app_id,
+                        dependency.dependency,
+                        dependency.app,
+                        dependency.store
+                    );
                 }
+                metadata.health = interface_health
+                    .get(app_id)
+                    .filter(|health| metadata.implements.as_ref() == Some(&health.interface))
+                    .cloned();
+                port_forwarder.forward(app_id, &mut metadata.port_forwards);
+                outputs
+                    .published_ports
+                    .extend(firewall::published_ports(&result_data.spec));
+                outputs.published_ports.extend(
+                    result_data
+                        .caddy_entries
+                        .iter()
+                        .map(|entry| (entry.public_port, Protocol::Tcp)),
+                );
+                if let Some(dir) = static_assets_dir {
+                    outputs.static_assets.insert(app_id.to_string(), dir);
+                }
+                outputs.app_permissions.insert(
+                    app_id.to_string(),
+                    flatten(&metadata.permissions)
+                        .into_iter()
+                        .cloned()
+                        .collect(),
+                );
             }
-            app_registry.push(metadata);
-            caddy_entries.insert(app_id.to_owned(), result_data.caddy_entries);
-        } else {
-            // Delete docker-compose.yml if it exists
-            if docker_compose_yml_path.exists() {
-                std::fs::remove_file(docker_compose_yml_path)?;
-            }
-            tracing::error!(
-                "Error converting app.yml for app {}: {}",
-                app_id,
-                conversion_result.unwrap_err()
-            );
+            outputs.store_stats.add_app(&metadata, &result_data.spec);
+            app_registry.push(&metadata)?;
+            outputs
+                .caddy_entries
+                .insert(app_id.to_owned(), result_data.caddy_entries);
+            outputs
+                .app_names
+                .insert(app_id.to_owned(), metadata.name.clone());
+            self.metrics.add_app_time(app_id, app_started.elapsed());
         }
+        self.metrics.finish_stage("convert");
+        app_registry.finish()?;
+        port_forwarder.finish(services)?;
+        Ok(outputs)
     }
 
-    // Part 7: Save registry & virtual apps
-    {
-        let app_registry_file = citadel_root.join("apps").join("registry.json");
-        let mut app_registry_file = std::fs::File::create(app_registry_file)?;
-        serde_json::to_writer(&mut app_registry_file, &app_registry)
-            .expect("Error writing registry.json!");
-        let virtual_apps_file = citadel_root.join("apps").join("virtual-apps.json");
-        let mut virtual_apps_file = std::fs::File::create(virtual_apps_file)?;
-        serde_json::to_writer(&mut virtual_apps_file, &virtual_apps)?;
-
-        let tor_entries_file = citadel_root.join("tor").join("torrc-apps");
-        let tor_entries_file_2 = citadel_root.join("tor").join("torrc-apps-2");
-        let tor_entries_file_3 = citadel_root.join("tor").join("torrc-apps-3");
-        let mut tor_entries_file = std::fs::File::create(tor_entries_file)?;
-        let mut tor_entries_file_2 = std::fs::File::create(tor_entries_file_2)?;
-        let mut tor_entries_file_3 = std::fs::File::create(tor_entries_file_3)?;
+    /// Writes the files describing all apps: virtual apps, start order, unsupported apps,
+    /// store statistics and compose projects
+    fn write_registry(&self, outputs: &AppOutputs) -> Result<()> {
+        let apps_dir = self.citadel_root.join("apps");
+        self.paths.write(
+            &apps_dir.join("virtual-apps.json"),
+            serde_json::to_string(&outputs.virtual_apps)?,
+        )?;
+        let start_order = start_order::compute(&outputs.app_permissions, &outputs.virtual_apps);
+        self.paths.write(
+            &apps_dir.join("start-order.json"),
+            serde_json::to_string(&start_order)?,
+        )?;
+        self.paths.write(
+            &apps_dir.join("unsupported.json"),
+            serde_json::to_string(&self.unsupported_apps)?,
+        )?;
+        self.paths.write(
+            &apps_dir.join("stats.json"),
+            serde_json::to_string(&outputs.store_stats)?,
+        )?;
+        projects::save(&self.paths, self.citadel_root, &outputs.app_projects)
+    }
+
+    /// Writes the hidden services to the Tor configs and the I2P tunnels
+    fn write_tor_and_i2p(&self, outputs: &AppOutputs) -> Result<()> {
+        let paths = &self.paths;
+        let tor_dir = self.citadel_root.join("tor");
+        let mut tor_entries_file = paths.create(&tor_dir.join("torrc-apps"))?;
+        let mut tor_entries_file_2 = paths.create(&tor_dir.join("torrc-apps-2"))?;
+        let mut tor_entries_file_3 = paths.create(&tor_dir.join("torrc-apps-3"))?;
         // Split entries into 3 groups of the same size
         let mut current_file = 1;
 
-        for entry in tor_entries {
+        for entry in &outputs.tor_entries {
             if current_file == 1 {
                 tor_entries_file.write_all(entry.as_bytes())?;
                 current_file = 2;
@@ -529,58 +1501,598 @@ pub fn convert_dir(citadel_root: &str, caddy_url: &Option<String>) -> Result<()>
                 current_file = 1;
             }
         }
-        let i2p_entries_dir = citadel_root.join("i2p").join("tunnels.d");
-        std::fs::create_dir_all(i2p_entries_dir.clone())?;
-        let i2p_entries_file = i2p_entries_dir.join("apps.conf");
-        let mut i2p_entries_file = std::fs::File::create(i2p_entries_file)?;
-        i2p_entries_file.write_all(i2p_entries.join("\n").as_bytes())?;
+        let i2p_entries_dir = self.citadel_root.join("i2p").join("tunnels.d");
+        paths.create_dir_all(&i2p_entries_dir)?;
+        paths.write(
+            &i2p_entries_dir.join("apps.conf"),
+            outputs.i2p_entries.join("\n"),
+        )
     }
 
-    // Part 8: Preprocess config jinja files
-    preprocessing::preprocess_config_files(citadel_root, &citadel_root.join("apps"))?;
-
-    // Part 9: Configure caddy
-    {
+    /// Renders the Caddyfile
+    fn generate_caddyfile(
+        &self,
+        outputs: &mut AppOutputs,
+        assignments: &Assignments,
+    ) -> Result<Caddyfile> {
+        let paths = &self.paths;
+        let citadel_root = self.citadel_root;
+        let node_config = &self.node_config;
+        let ip_map = &assignments.ip_map;
+        let caddy_entries = std::mem::take(&mut outputs.caddy_entries);
         let caddy_file = citadel_root.join("caddy").join("Caddyfile");
         let caddy_entry_template = citadel_root.join("templates").join("Caddyfile.jinja");
-        let caddy_entry_tmpl = std::fs::read_to_string(caddy_entry_template)?;
+        let caddy_entry_tmpl = paths.read_to_string(&caddy_entry_template)?;
         let mut tera_context = Context::new();
+        // Snippets are rendered from caddy.snippet.jinja with the config files
+        let mut app_snippets = caddy_snippets::load_snippets(
+            paths,
+            &citadel_root.join("apps"),
+            caddy_entries.keys(),
+            |app_id| {
+                self.app_stores
+                    .iter()
+                    .find(|store| store.apps.contains_key(app_id))
+                    .is_some_and(|store| node_config.security.trusted_stores.contains(&store.id))
+            },
+        );
+        // Rate limits are added to the snippets, so they apply wherever an app is served
+        for (app_id, directives) in node_config.rate_limits.directives(caddy_entries.keys()) {
+            let snippet = app_snippets.entry(app_id).or_default();
+            snippet.insert_str(0, &directives);
+        }
+        // Apps in maintenance mode are served a maintenance page instead, ahead of everything else
+        for app_id in maintenance::load(paths, citadel_root)? {
+            let Some(app_name) = outputs.app_names.get(&app_id) else {
+                continue;
+            };
+            let snippet = app_snippets.entry(app_id).or_default();
+            snippet.insert_str(0, &maintenance::directives(app_name));
+        }
+        // Options for the template's reverse_proxy directives, like connection limits
+        let reverse_proxy_options: HashMap<&String, String> = caddy_entries
+            .keys()
+            .map(|app_id| (app_id, node_config.rate_limits.for_app(app_id).transport()))
+            .collect();
+        tera_context.insert("reverse_proxy_options", &reverse_proxy_options);
+        // Certificates from the node CA, for the template's tls directives
+        if node_config.lan_tls.enabled {
+            let tls_dir = citadel_root.join("tls");
+            let lan_certificates =
+                lan_tls::NodeCa::load_or_create(paths, &tls_dir, &node_config.lan_tls)
+                    .and_then(|ca| {
+                        ca.issue_app_certificates(paths, &node_config.lan_tls, caddy_entries.keys())
+                    })
+                    .context("Failed to issue LAN certificates")?;
+            tera_context.insert("lan_certificates", &lan_certificates);
+        }
+        let static_assets = static_assets::resolve_dirs(
+            &node_config.static_assets,
+            &citadel_root.join("apps"),
+            &outputs.static_assets,
+        );
+        tera_context.insert(
+            "static_assets",
+            &static_assets::generate_caddy_config(&static_assets),
+        );
+        let app_addresses = caddy_adapt::app_addresses(&caddy_entries, ip_map);
+        // Apps only reachable through a VPN get their own site blocks
+        let (vpn_caddy_entries, caddy_entries): (HashMap<_, _>, HashMap<_, _>) = caddy_entries
+            .into_iter()
+            .partition(|(app_id, _)| !self.vpn_exposure.bind_addresses(app_id).is_empty());
         tera_context.insert("caddy_entries", &caddy_entries);
+        // The same entries in the order they should be written in
+        tera_context.insert(
+            "caddy_routes",
+            &caddy_routes::ordered_routes(&caddy_entries),
+        );
+        tera_context.insert("caddy_snippets", &app_snippets);
         for (var, value) in ip_map.iter() {
             tera_context.insert(var, value);
         }
-        tera_context.insert("ip_map", &ip_map);
+        tera_context.insert("ip_map", ip_map);
+        let env_file = paths.read_path(&citadel_root.join(".env"));
         #[allow(deprecated)]
-        if let Ok(dot_env) = dotenv::from_filename_iter(citadel_root.join(".env")) {
-            for env_var in dot_env {
-                if let Ok(env_var) = env_var {
-                    tera_context.insert(env_var.0.as_str(), &env_var.1);
-                } else {
-                    tracing::error!("{}", env_var.unwrap_err());
+        if let Ok(dot_env) = dotenv::from_filename_iter(env_file) {
+            let redactor = redaction::Redactor::new(&node_config.redaction);
+            let env_vars = dot_env.filter_map(|env_var| match env_var {
+                Ok(env_var) => Some(env_var),
+                Err(err) => {
+                    tracing::error!("{}", redactor.env_error(&err));
+                    None
                 }
+            });
+            // Secrets of apps are only needed by their own templates
+            for (key, value) in
+                template_env::for_caddyfile(env_vars, &redactor, &node_config.template_env)
+            {
+                tera_context.insert(key, &value);
             }
         }
-        if let Some(https_options) = https_options {
-            tera_context.insert("https_options", &https_options);
+        // How plain HTTP is handled for each app
+        let http_modes = self
+            .https_options
+            .clone()
+            .unwrap_or_default()
+            .http_modes(caddy_entries.keys());
+        tera_context.insert("http_modes", &http_modes);
+        if let Some(https_options) = &self.https_options {
+            tera_context.insert("https_options", https_options);
         }
-        let caddy_file_contents = ::tera::Tera::one_off(&caddy_entry_tmpl, &tera_context, false)
-            .expect("Error rendering Caddyfile.jinja!");
+        let mut caddy_file_contents = tera::render_cached(
+            &mut ::tera::Tera::default(),
+            &caddy_entry_tmpl,
+            &tera_context,
+        )
+        .context("Error rendering Caddyfile.jinja!")?;
+        // Apps with their own domain
+        if let Some(https_options) = &self.https_options {
+            caddy_file_contents.push_str(&https_options.generate_caddy_config(
+                &caddy_entries,
+                ip_map,
+                &node_config.rate_limits,
+            ));
+        }
+        caddy_file_contents.push_str(&exposure::generate_vpn_caddy_config(
+            &self.vpn_exposure,
+            &vpn_caddy_entries,
+            ip_map,
+            &app_snippets,
+            &node_config.rate_limits,
+        ));
+        // Apps the user explicitly exposed to the WAN
+        caddy_file_contents.push_str(&exposure::generate_caddy_config(
+            &self.public_exposure,
+            &caddy_entries,
+            ip_map,
+            &app_snippets,
+            &node_config.rate_limits,
+            |port| assignments.ports.is_taken(port),
+        ));
         let caddy_file_contents = caddyfile_parser::format_caddyfile(&caddy_file_contents);
-        let mut caddy_file = std::fs::File::create(caddy_file)?;
-        caddy_file.write_all(caddy_file_contents.as_bytes())?;
-        if let Some(caddy_url) = caddy_url {
-            let parsed_caddyfile = caddyfile_parser::parse_caddyfile("Caddyfile", &caddy_file_contents);
-            let caddy_url = url::Url::parse(&caddy_url)?;
-            let caddy_url = caddy_url.join("/load")?;
-            if let Err(err) = reqwest::blocking::Client::new()
-                .post(caddy_url)
-                .header("Content-Type", "application/json")
-                .body(parsed_caddyfile)
-                .send() {
-                    tracing::warn!("Failed to update Caddy config: {:#?}", err);
-                }
+        paths.write(&caddy_file, &caddy_file_contents)?;
+        Ok(Caddyfile {
+            contents: caddy_file_contents,
+            app_addresses,
+            validate: node_config.caddy_validation.enabled,
+        })
+    }
+
+    /// Writes firewall rules for the ports published by installed apps, if enabled
+    fn write_firewall_rules(&self, mut published_ports: BTreeSet<(u16, Protocol)>) -> Result<()> {
+        let Some(format) = self.node_config.firewall.format else {
+            return Ok(());
+        };
+        published_ports.extend(
+            self.public_exposure
+                .apps
+                .iter()
+                .filter(|(app_id, _)| self.services.contains(app_id))
+                .map(|(_, exposed)| (exposed.port, Protocol::Tcp)),
+        );
+        let firewall_dir = self.citadel_root.join("firewall");
+        self.paths.create_dir_all(&firewall_dir)?;
+        let file_name = match format {
+            firewall::FirewallFormat::Nftables => "citadel-apps.nft",
+            firewall::FirewallFormat::Ufw => "citadel-apps.ufw",
+        };
+        self.paths.write(
+            &firewall_dir.join(file_name),
+            firewall::generate_rules(&self.node_config.firewall, format, &published_ports),
+        )
+    }
+}
+
+/// Fails if apps generate the same names, for example because one's ID is a prefix of another's.
+/// Returns name -> (app, what) it was generated for.
+fn check_generated_names(
+    app_ids: &[String],
+    app_ymls: &HashMap<String, AppDefinition>,
+) -> Result<HashMap<String, (String, String)>> {
+    let mut name_owners: HashMap<String, (String, String)> = HashMap::new();
+    let mut env_var_collisions = Vec::new();
+    for (app_id, app_yml) in app_ids
+        .iter()
+        .filter_map(|app_id| Some((app_id, app_ymls.get(app_id)?)))
+    {
+        let main_container = get_main_container(
+            &app_yml.services,
+            app_yml.metadata.main_container.as_deref(),
+        )?;
+        for (name, what) in generated_names(app_id, app_yml, main_container) {
+            let owner = (app_id.to_string(), what);
+            if let Some((other_app, other_what)) = name_owners
+                .insert(name.clone(), owner.clone())
+                .filter(|other| *other != owner)
+            {
+                env_var_collisions.push(format!(
+                    "{name} is used by both {other_app} ({other_what}) and {app_id} ({})",
+                    owner.1
+                ));
+            }
         }
     }
+    if !env_var_collisions.is_empty() {
+        bail!(
+            "Found conflicting generated names:\n{}",
+            env_var_collisions.join("\n")
+        );
+    }
+    Ok(name_owners)
+}
 
+/// Assigns an IP to every container which doesn't have one yet, in the order of `app_ids`
+fn assign_ips(
+    app_ids: &[String],
+    app_ymls: &HashMap<String, AppDefinition>,
+    config: &ip_assignment::IpAssignmentConfig,
+    ip_map: &mut HashMap<String, String>,
+) -> Result<()> {
+    let mut ip_allocator = ip_assignment::IpAllocator::new(config, ip_map);
+    for (app_id, app_yml) in app_ids
+        .iter()
+        .filter_map(|app_id| Some((app_id, app_ymls.get(app_id)?)))
+    {
+        for service_name in app_yml.services.keys() {
+            let ip_name = naming::ip_env_var(app_id, service_name);
+            if let std::collections::hash_map::Entry::Vacant(e) = ip_map.entry(ip_name) {
+                let ip = ip_allocator.allocate(e.key())?;
+                e.insert(ip);
+            }
+        }
+    }
     Ok(())
 }
+
+/// App -> the directory of its shared data mount, relative to the shared data directory
+fn shared_data_dirs(app_ymls: &HashMap<String, AppDefinition>) -> HashMap<String, String> {
+    let mut data_dirs = HashMap::new();
+    for (app_id, app_yml) in app_ymls {
+        let Ok(main_container) = get_main_container(
+            &app_yml.services,
+            app_yml.metadata.main_container.as_deref(),
+        ) else {
+            continue;
+        };
+        let has_service = app_yml.services.contains_key("service");
+        for (service_name, service) in &app_yml.services {
+            let Some(shared_data) = service
+                .mounts
+                .as_ref()
+                .and_then(|mounts| mounts.get("shared_data"))
+            else {
+                continue;
+            };
+            match shared_data {
+                StringOrMap::String(_) => {
+                    tracing::warn!(
+                        "App {} defines a string instead of an hashmap as shared data mount",
+                        app_id
+                    );
+                }
+                StringOrMap::Map(map) if map.len() != 1 => {
+                    tracing::warn!(
+                        "App {} has multiple shared data mounts, this is not supported!",
+                        app_id
+                    );
+                }
+                StringOrMap::Map(map) => {
+                    if (has_service && service_name == "service")
+                        || (!has_service && service_name == main_container)
+                    {
+                        data_dirs.insert(
+                            app_id.to_lowercase().clone(),
+                            map.keys().next().unwrap().clone(),
+                        );
+                    } else {
+                        tracing::warn!("App either has no service container and a shared_data mount in a container that is not the main container, or it has a service container and a shared_data mount in a container that is not the service container. This is not supported!");
+                    }
+                }
+            }
+        }
+    }
+    data_dirs
+}
+
+/// Shares exported by apps, as env var -> directory relative to the app's data dir
+fn share_dirs(app_ymls: &HashMap<String, AppDefinition>) -> HashMap<String, String> {
+    let mut share_dirs = HashMap::new();
+    for (app_id, app_yml) in app_ymls {
+        for (share, definition) in &app_yml.metadata.shares {
+            share_dirs.insert(
+                share_env_var(app_id, share),
+                definition.path.trim_start_matches('/').to_string(),
+            );
+        }
+    }
+    share_dirs
+}
+
+/// Converts all apps in a Citadel root.
+/// If a state dir is given, all files are written there instead of to the Citadel root.
+/// `port_changes` decides what happens if ports of installed apps would change.
+/// With `single_app`, only that app's templates and compose files are generated again,
+/// using the port and IP assignments of the last conversion.
+/// A `dry_run` doesn't change port forwards on the router or install AppArmor profiles,
+/// see [dry_run::convert_dir].
+/// Apps that fail to convert are skipped and listed in the returned report,
+/// see [failures::convert_dir] for failing the conversion instead.
+pub fn convert_dir(
+    citadel_root: &str,
+    caddy_url: &Option<String>,
+    state_dir: &Option<String>,
+    port_changes: port_review::PortChangePolicy,
+    filter: &app_filter::AppFilter,
+    single_app: Option<&str>,
+    dry_run: bool,
+) -> Result<metrics::ConversionReport> {
+    let (report, caddyfile) = convert_files(
+        citadel_root,
+        state_dir,
+        port_changes,
+        filter,
+        single_app,
+        dry_run,
+    )?;
+    if let Some(caddy_url) = caddy_url {
+        caddyfile.load(caddy_url)?;
+    }
+    Ok(report)
+}
+
+/// Converts all apps like [convert_dir], but leaves loading the Caddyfile to the caller
+fn convert_files(
+    citadel_root: &str,
+    state_dir: &Option<String>,
+    port_changes: port_review::PortChangePolicy,
+    filter: &app_filter::AppFilter,
+    single_app: Option<&str>,
+    dry_run: bool,
+) -> Result<(metrics::ConversionReport, Caddyfile)> {
+    let citadel_root = Path::new(&citadel_root);
+    let paths = paths::CitadelPaths::new(citadel_root, state_dir.as_deref().map(Path::new));
+    let app_ids: Vec<String> = read_app_dirs(&citadel_root.join("apps"))?
+        .iter()
+        .map(|app| app.file_name().to_string_lossy().to_string())
+        .collect();
+    let app_stores = stores::load_stores(&paths, citadel_root)?;
+    validation::validate_app_ids(&app_ids, &app_stores)?;
+    if let Some(app_id) = single_app {
+        if !app_ids.iter().any(|id| id == app_id) {
+            bail!("App {app_id} does not exist");
+        }
+    }
+    let mut conversion = Conversion::load(citadel_root, paths, app_stores, single_app, dry_run)?;
+    let mut assignments = conversion.load_assignments()?;
+    if conversion.citadel_seed.is_none() {
+        tracing::warn!("Citadel does not seem to be set up yet!");
+    }
+
+    let failed_apps = conversion.preprocess()?;
+
+    // Part 2: IP & port assignment
+    let app_ymls = conversion.load_apps(&app_ids, &failed_apps);
+    let name_owners = check_generated_names(&app_ids, &app_ymls)?;
+    assign_ips(
+        &app_ids,
+        &app_ymls,
+        &conversion.node_config.ip_assignment,
+        &mut assignments.ip_map,
+    )?;
+    conversion.assign_ports(&app_ids, &app_ymls, &mut assignments.ports)?;
+    conversion.check_requirements(&app_ymls)?;
+    if let (Some(app_id), Some((cached_ports, cached_ips))) = (single_app, &assignments.cached) {
+        check_single_app_assignments(
+            app_id,
+            cached_ports,
+            &assignments.ports.cache,
+            cached_ips,
+            &assignments.ip_map,
+            &name_owners,
+        )?;
+    }
+    conversion.metrics.finish_stage("assign");
+    conversion.review_port_changes(port_changes, &mut assignments.ports)?;
+
+    // Part 3: Save ports and IPs
+    conversion.write_assignments(&app_ids, &app_ymls, &mut assignments)?;
+    let configured_settings = conversion.update_env_file(&app_ymls, &assignments.ip_map)?;
+    conversion.metrics.finish_stage("ports");
+
+    // Part 4: Generate the compose files
+    let mut outputs = conversion.generate_compose_files(
+        &app_ids,
+        app_ymls,
+        configured_settings,
+        &assignments,
+        filter,
+    )?;
+
+    // Part 5: Save the registry and the Tor and I2P configs
+    conversion.write_registry(&outputs)?;
+    conversion.write_tor_and_i2p(&outputs)?;
+    conversion.metrics.finish_stage("registry");
+
+    // Part 6: Preprocess config jinja files
+    let failed_config_files = preprocessing::preprocess_config_files(
+        &conversion.paths,
+        &citadel_root.join("apps"),
+        single_app,
+    )?;
+    for (app_id, error) in &failed_config_files {
+        conversion
+            .metrics
+            .add_failure(app_id, "config_files", error);
+    }
+    conversion.metrics.finish_stage("config_files");
+
+    // Part 7: Generate the Caddyfile
+    let caddyfile = conversion.generate_caddyfile(&mut outputs, &assignments)?;
+    conversion.metrics.finish_stage("caddy");
+
+    // Part 8: Generate firewall rules
+    conversion.write_firewall_rules(outputs.published_ports)?;
+    conversion.metrics.finish_stage("firewall");
+
+    let report = conversion.metrics.report();
+    tracing::info!(
+        "Conversion took {} ms, {} bytes written, {} template cache hits and {} misses",
+        report.total_millis,
+        report.bytes_written,
+        report.template_cache.hits,
+        report.template_cache.misses
+    );
+    conversion.paths.write(
+        &citadel_root.join("apps").join("conversion-report.json"),
+        serde_json::to_string_pretty(&report)?,
+    )?;
+
+    Ok((report, caddyfile))
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::{check_single_app_assignments, generated_names, PortCacheMap, PortCacheMapEntry};
+    use crate::composegenerator::{ir::AppDefinition, v4::types::PortPriority};
+
+    #[test]
+    fn generated_names_of_apps_can_collide() {
+        let mut lnd = AppDefinition::default();
+        for (container, definition) in [
+            ("main", "image: lnd"),
+            ("tools", "image: tools\nhidden_services:\n  9000: 9000"),
+        ] {
+            lnd.services.insert(
+                container.to_string(),
+                serde_yaml::from_str(definition).unwrap(),
+            );
+        }
+        let mut lnd_tools = AppDefinition::default();
+        lnd_tools.services.insert(
+            "web".to_string(),
+            serde_yaml::from_str("image: lnd-tools").unwrap(),
+        );
+        lnd_tools.metadata.shares = serde_yaml::from_str("files:\n  path: /files").unwrap();
+
+        let lnd_names = generated_names("lnd", &lnd, "main");
+        let lnd_tools_names = generated_names("lnd-tools", &lnd_tools, "web");
+        let name = |names: &[(String, String)], name: &str| {
+            names
+                .iter()
+                .find(|(generated, _)| generated == name)
+                .map(|(_, what)| what.clone())
+        };
+        assert_eq!(
+            name(&lnd_names, "APP_LND_TOOLS_IP"),
+            Some("container tools".to_string())
+        );
+        assert_eq!(
+            name(&lnd_names, "app-lnd-tools"),
+            Some("hidden service tools".to_string())
+        );
+        assert_eq!(
+            name(&lnd_tools_names, "app-lnd-tools"),
+            Some("its main hidden service".to_string())
+        );
+        assert_eq!(
+            name(&lnd_tools_names, "APP_LND_TOOLS_SHARED_SUBDIR"),
+            Some("its shared data".to_string())
+        );
+        assert_eq!(
+            name(&lnd_tools_names, "APP_LND_TOOLS_SHARE_FILES_DIR"),
+            Some("share files".to_string())
+        );
+        // The main container uses the app's hidden service
+        assert_eq!(name(&lnd_names, "app-lnd-main"), None);
+    }
+
+    fn port(app: &str, internal_port: u16) -> PortCacheMapEntry {
+        PortCacheMapEntry {
+            app: app.to_string(),
+            internal_port,
+            container: "main".to_string(),
+            dynamic: false,
+            implements: None,
+            priority: PortPriority::Optional,
+            reserved: false,
+        }
+    }
+
+    fn ips(vars: &[&str]) -> HashMap<String, String> {
+        vars.iter()
+            .enumerate()
+            .map(|(i, var)| (var.to_string(), format!("10.21.21.{}", i + 10)))
+            .collect()
+    }
+
+    #[test]
+    fn single_app_can_change_its_own_assignments() {
+        let cached_ports =
+            PortCacheMap::from([(3000, port("lnd", 3000)), (8332, port("bitcoin", 8332))]);
+        let mut port_map_cache = cached_ports.clone();
+        port_map_cache.insert(3001, port("lnd", 9735));
+        let cached_ips = ips(&["APP_LND_MAIN_IP", "APP_BITCOIN_MAIN_IP"]);
+        let ip_map = ips(&["APP_LND_MAIN_IP", "APP_BITCOIN_MAIN_IP", "APP_LND_TOR_IP"]);
+        let name_owners = HashMap::from([(
+            "APP_LND_TOR_IP".to_string(),
+            ("lnd".to_string(), "container tor".to_string()),
+        )]);
+        check_single_app_assignments(
+            "lnd",
+            &cached_ports,
+            &port_map_cache,
+            &cached_ips,
+            &ip_map,
+            &name_owners,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn single_app_fails_if_other_apps_need_new_assignments() {
+        let cached_ports =
+            PortCacheMap::from([(3000, port("lnd", 3000)), (8332, port("bitcoin", 8332))]);
+        let cached_ips = ips(&["APP_LND_MAIN_IP", "APP_BITCOIN_MAIN_IP"]);
+        let no_owners = HashMap::new();
+
+        // lnd now requires 8332, so bitcoin is moved to another port
+        let mut port_map_cache = cached_ports.clone();
+        port_map_cache.insert(8332, port("lnd", 8332));
+        port_map_cache.insert(3001, port("bitcoin", 8332));
+        let err = check_single_app_assignments(
+            "lnd",
+            &cached_ports,
+            &port_map_cache,
+            &cached_ips,
+            &cached_ips,
+            &no_owners,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("change the ports of bitcoin"));
+
+        // A new container of bitcoin can only get its IP in a full conversion
+        let ip_map = ips(&[
+            "APP_LND_MAIN_IP",
+            "APP_BITCOIN_MAIN_IP",
+            "APP_BITCOIN_TOR_IP",
+        ]);
+        let name_owners = HashMap::from([(
+            "APP_BITCOIN_TOR_IP".to_string(),
+            ("bitcoin".to_string(), "container tor".to_string()),
+        )]);
+        for name_owners in [&no_owners, &name_owners] {
+            let err = check_single_app_assignments(
+                "lnd",
+                &cached_ports,
+                &cached_ports,
+                &cached_ips,
+                &ip_map,
+                name_owners,
+            )
+            .unwrap_err();
+            assert!(err.to_string().contains("assign APP_BITCOIN_TOR_IP"));
+        }
+    }
+}
@@ -0,0 +1,67 @@
+/// Limits an operation to some apps, shared by several subcommands
+#[derive(clap::Args, Debug, Default, Clone, PartialEq, Eq)]
+pub struct AppFilter {
+    /// Only apps from this store, can be given multiple times
+    #[clap(long)]
+    pub store: Vec<String>,
+    /// Only apps in this category, can be given multiple times
+    #[clap(long)]
+    pub category: Vec<String>,
+    /// Only these apps, separated by commas
+    #[clap(long, value_delimiter = ',')]
+    pub only: Vec<String>,
+    /// Only apps that are installed
+    #[clap(long)]
+    pub installed_only: bool,
+}
+
+impl AppFilter {
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+
+    /// Whether an app passes all filters that are set. Categories are compared case-insensitively.
+    pub fn matches(
+        &self,
+        app_id: &str,
+        category: &str,
+        store: Option<&str>,
+        installed: bool,
+    ) -> bool {
+        (self.only.is_empty() || self.only.iter().any(|app| app == app_id))
+            && (self.category.is_empty()
+                || self
+                    .category
+                    .iter()
+                    .any(|filter| filter.eq_ignore_ascii_case(category)))
+            && (self.store.is_empty()
+                || store.is_some_and(|store| self.store.iter().any(|filter| filter == store)))
+            && (!self.installed_only || installed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::AppFilter;
+
+    #[test]
+    fn filters_apps() {
+        assert!(AppFilter::default().matches("lnd", "Lightning", None, false));
+        let filter = AppFilter {
+            store: vec!["community".to_string()],
+            category: vec!["finance".to_string()],
+            ..Default::default()
+        };
+        assert!(filter.matches("btcpay", "Finance", Some("community"), false));
+        assert!(!filter.matches("btcpay", "Finance", None, false));
+        assert!(!filter.matches("lnd", "Lightning", Some("community"), false));
+        let filter = AppFilter {
+            only: vec!["lnd".to_string(), "btcpay".to_string()],
+            installed_only: true,
+            ..Default::default()
+        };
+        assert!(filter.matches("lnd", "Lightning", None, true));
+        assert!(!filter.matches("lnd", "Lightning", None, false));
+        assert!(!filter.matches("electrs", "Bitcoin", None, true));
+    }
+}
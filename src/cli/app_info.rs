@@ -0,0 +1,367 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+
+use super::{
+    app_list::{app_ids, installed_apps, load_ips, load_registry, main_ip},
+    node_config::NodeConfig,
+    paths::CitadelPaths,
+    redaction::Redactor,
+    template_env::{EnvScope, TemplateEnvConfig},
+    PortCacheMap,
+};
+use crate::naming;
+
+/// A public port assigned to a container of the app
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct AssignedPort {
+    pub public_port: u16,
+    pub container: String,
+    pub internal_port: u16,
+    /// The app can choose the port with an env var
+    pub dynamic: bool,
+}
+
+/// A Tor hidden service or I2P tunnel of the app
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Tunnel {
+    /// The name of the hidden service dir or the tunnel section, like app-lnd-rpc
+    pub name: String,
+    /// The onion address, if Tor already created it
+    pub hostname: Option<String>,
+    /// The forwarded ports, like "80 10.21.22.3:3000"
+    pub targets: Vec<String>,
+}
+
+/// A route through Caddy to one of the app's web UIs
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Route {
+    pub name: String,
+    pub public_port: u16,
+    /// The container's IP and port, or only the port if the IP is unknown
+    pub upstream: String,
+    pub path: Option<String>,
+}
+
+/// Everything the last conversion generated for an app
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct AppInfo {
+    pub id: String,
+    pub version: Option<String>,
+    pub installed: bool,
+    pub ports: Vec<AssignedPort>,
+    /// Env var -> IP of each container
+    pub ips: BTreeMap<String, String>,
+    /// The app's env vars from the .env file, secrets are masked
+    pub env: BTreeMap<String, String>,
+    pub hidden_services: Vec<Tunnel>,
+    pub i2p_tunnels: Vec<Tunnel>,
+    pub routes: Vec<Route>,
+}
+
+/// Whether a hidden service or tunnel name like app-lnd-rpc belongs to the app.
+/// Names of other apps whose ID starts with the app's ID, like app-lnd-tools, don't.
+fn owns_tunnel(app_id: &str, name: &str, app_ids: &[&str]) -> bool {
+    let matches = |app_id: &str| {
+        name.strip_prefix("app-")
+            .and_then(|name| name.strip_prefix(app_id))
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('-'))
+    };
+    matches(app_id)
+        && !app_ids
+            .iter()
+            .any(|other| other.len() > app_id.len() && matches(other))
+}
+
+/// Parses the hidden services of a torrc file
+fn parse_torrc(torrc: &str) -> Vec<Tunnel> {
+    let mut services: Vec<Tunnel> = Vec::new();
+    for line in torrc.lines() {
+        if let Some(dir) = line.strip_prefix("HiddenServiceDir ") {
+            services.push(Tunnel {
+                name: dir
+                    .trim()
+                    .rsplit('/')
+                    .next()
+                    .unwrap_or_default()
+                    .to_string(),
+                hostname: None,
+                targets: Vec::new(),
+            });
+        } else if let (Some(port), Some(service)) =
+            (line.strip_prefix("HiddenServicePort "), services.last_mut())
+        {
+            service.targets.push(port.trim().to_string());
+        }
+    }
+    services
+}
+
+/// Parses the tunnels of an i2pd tunnels file
+fn parse_i2p_tunnels(tunnels: &str) -> Vec<Tunnel> {
+    let mut result: Vec<Tunnel> = Vec::new();
+    let mut host = None;
+    for line in tunnels.lines().map(str::trim) {
+        if let Some(name) = line
+            .strip_prefix('[')
+            .and_then(|line| line.strip_suffix(']'))
+        {
+            result.push(Tunnel {
+                name: name.to_string(),
+                hostname: None,
+                targets: Vec::new(),
+            });
+            host = None;
+        } else if let (Some((key, value)), Some(tunnel)) = (line.split_once('='), result.last_mut())
+        {
+            match key.trim() {
+                "host" => host = Some(value.trim().to_string()),
+                "port" => tunnel.targets.push(format!(
+                    "{}:{}",
+                    host.as_deref().unwrap_or("?"),
+                    value.trim()
+                )),
+                _ => {}
+            }
+        }
+    }
+    result
+}
+
+/// Collects what the last conversion generated for an app from the state files in the Citadel root
+pub fn info(citadel_root: &str, state_dir: &Option<String>, app_id: &str) -> Result<AppInfo> {
+    let citadel_root = Path::new(citadel_root);
+    let paths = CitadelPaths::new(citadel_root, state_dir.as_deref().map(Path::new));
+    let apps_dir = citadel_root.join("apps");
+    let app_ids = app_ids(&apps_dir)?;
+    if !app_ids.contains(app_id) {
+        bail!("App {app_id} does not exist");
+    }
+    let node_config = NodeConfig::load(&paths, citadel_root)?;
+    let redactor = Redactor::new(&node_config.redaction);
+    let scope = EnvScope::new(
+        app_ids.iter().map(String::as_str),
+        &TemplateEnvConfig::default(),
+    );
+    let other_apps: Vec<&str> = app_ids.iter().map(String::as_str).collect();
+    let registry = load_registry(&paths, &apps_dir)?;
+    let metadata = registry.get(app_id);
+    let all_ips = load_ips(&paths, &apps_dir)?;
+
+    let port_cache_file = apps_dir.join("ports.cache.yml");
+    let port_cache: PortCacheMap = if paths.exists(&port_cache_file) {
+        serde_yaml::from_reader(paths.open(&port_cache_file)?)
+            .context("Failed to load ports.cache.yml")?
+    } else {
+        HashMap::new()
+    };
+    let mut ports: Vec<AssignedPort> = port_cache
+        .iter()
+        .filter(|(_, entry)| entry.app == app_id)
+        .map(|(public_port, entry)| AssignedPort {
+            public_port: *public_port,
+            container: entry.container.clone(),
+            internal_port: entry.internal_port,
+            dynamic: entry.dynamic,
+        })
+        .collect();
+    ports.sort_by_key(|port| port.public_port);
+
+    let ips = all_ips
+        .iter()
+        .filter(|(key, _)| key.ends_with("_IP") && scope.owner(key) == Some(app_id))
+        .map(|(key, ip)| (key.clone(), ip.clone()))
+        .collect();
+
+    let mut env = BTreeMap::new();
+    #[allow(deprecated)]
+    if let Ok(dot_env) = dotenv::from_filename_iter(paths.read_path(&citadel_root.join(".env"))) {
+        for (key, value) in dot_env.flatten() {
+            if scope.owner(&key) == Some(app_id) {
+                let value = redactor.value(&key, &value).to_string();
+                env.insert(key, value);
+            }
+        }
+    }
+
+    let mut hidden_services = Vec::new();
+    for torrc in ["torrc-apps", "torrc-apps-2", "torrc-apps-3"] {
+        let torrc = citadel_root.join("tor").join(torrc);
+        if !paths.exists(&torrc) {
+            continue;
+        }
+        for mut service in parse_torrc(&paths.read_to_string(&torrc)?) {
+            if !owns_tunnel(app_id, &service.name, &other_apps) {
+                continue;
+            }
+            let hostname_file = citadel_root
+                .join("tor")
+                .join("data")
+                .join(&service.name)
+                .join("hostname");
+            service.hostname = std::fs::read_to_string(hostname_file)
+                .ok()
+                .map(|hostname| hostname.trim().to_string());
+            hidden_services.push(service);
+        }
+    }
+    hidden_services.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let i2p_file = citadel_root.join("i2p").join("tunnels.d").join("apps.conf");
+    let i2p_tunnels = if paths.exists(&i2p_file) {
+        parse_i2p_tunnels(&paths.read_to_string(&i2p_file)?)
+            .into_iter()
+            .filter(|tunnel| owns_tunnel(app_id, &tunnel.name, &other_apps))
+            .collect()
+    } else {
+        Vec::new()
+    };
+
+    let mut routes = Vec::new();
+    if let Some(metadata) = metadata {
+        let container_ip =
+            |container: &str| all_ips.get(&naming::ip_env_var(app_id, container)).cloned();
+        let main_ip = main_ip(app_id, Some(metadata), &all_ips, &scope);
+        if metadata.port != 0 {
+            routes.push(Route {
+                name: metadata.name.clone(),
+                public_port: metadata.port,
+                upstream: match &main_ip {
+                    Some(ip) => format!("{ip}:{}", metadata.internal_port),
+                    None => format!(":{}", metadata.internal_port),
+                },
+                path: metadata.path.clone(),
+            });
+        }
+        for entry in &metadata.entries {
+            routes.push(Route {
+                name: entry.name.clone(),
+                public_port: entry.port,
+                upstream: match container_ip(&entry.container) {
+                    Some(ip) => format!("{ip}:{}", entry.internal_port),
+                    None => format!(":{}", entry.internal_port),
+                },
+                path: entry.path.clone(),
+            });
+        }
+    }
+
+    Ok(AppInfo {
+        id: app_id.to_string(),
+        version: metadata.map(|metadata| metadata.version.clone()),
+        installed: installed_apps(&paths, citadel_root).contains(&app_id.to_string()),
+        ports,
+        ips,
+        env,
+        hidden_services,
+        i2p_tunnels,
+        routes,
+    })
+}
+
+fn format_tunnels(output: &mut String, title: &str, tunnels: &[Tunnel]) {
+    if tunnels.is_empty() {
+        return;
+    }
+    let _ = writeln!(output, "\n{title}:");
+    for tunnel in tunnels {
+        let _ = write!(output, "  {}", tunnel.name);
+        if let Some(hostname) = &tunnel.hostname {
+            let _ = write!(output, " ({hostname})");
+        }
+        let _ = writeln!(output);
+        for target in &tunnel.targets {
+            let _ = writeln!(output, "    {target}");
+        }
+    }
+}
+
+/// Formats the details of an app for operators
+pub fn format_info(info: &AppInfo) -> String {
+    let mut output = format!(
+        "{} {} ({})\n",
+        info.id,
+        info.version.as_deref().unwrap_or("(not converted)"),
+        if info.installed {
+            "installed"
+        } else {
+            "available"
+        }
+    );
+    if !info.ports.is_empty() {
+        let _ = writeln!(output, "\nPorts:");
+        for port in &info.ports {
+            let _ = writeln!(
+                output,
+                "  {:>5} -> {}:{}{}",
+                port.public_port,
+                port.container,
+                port.internal_port,
+                if port.dynamic { " (dynamic)" } else { "" }
+            );
+        }
+    }
+    if !info.ips.is_empty() {
+        let _ = writeln!(output, "\nIPs:");
+        for (key, ip) in &info.ips {
+            let _ = writeln!(output, "  {key}={ip}");
+        }
+    }
+    if !info.env.is_empty() {
+        let _ = writeln!(output, "\nEnv vars:");
+        for (key, value) in &info.env {
+            let _ = writeln!(output, "  {key}={value}");
+        }
+    }
+    format_tunnels(&mut output, "Hidden services", &info.hidden_services);
+    format_tunnels(&mut output, "I2P tunnels", &info.i2p_tunnels);
+    if !info.routes.is_empty() {
+        let _ = writeln!(output, "\nCaddy routes:");
+        for route in &info.routes {
+            let _ = writeln!(
+                output,
+                "  :{}{} -> {} ({})",
+                route.public_port,
+                route.path.as_deref().unwrap_or(""),
+                route.upstream,
+                route.name
+            );
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod test {
+    use super::{owns_tunnel, parse_i2p_tunnels, parse_torrc};
+
+    #[test]
+    fn finds_tunnels_of_an_app() {
+        let services = parse_torrc(
+            "HiddenServiceDir /var/lib/tor/app-lnd\nHiddenServicePort 80 host.docker.internal:3000\n\nHiddenServiceDir /var/lib/tor/app-lnd-tools\nHiddenServicePort 80 10.21.22.4:80\nHiddenServiceDir /var/lib/tor/app-lnd-rpc\nHiddenServicePort 10009 10.21.22.3:10009\n",
+        );
+        let app_ids = ["lnd", "lnd-tools"];
+        let lnd: Vec<&str> = services
+            .iter()
+            .filter(|service| owns_tunnel("lnd", &service.name, &app_ids))
+            .map(|service| service.name.as_str())
+            .collect();
+        assert_eq!(lnd, vec!["app-lnd", "app-lnd-rpc"]);
+        assert_eq!(services[0].targets, vec!["80 host.docker.internal:3000"]);
+
+        let tunnels = parse_i2p_tunnels(
+            "[app-lnd]\nhost = host.docker.internal\nport = 3000\nkeys = app-lnd.dat\n[app-lnd-node-9735]\nhost = 10.21.22.3\nport = 9735\ninport = 9735\n",
+        );
+        assert_eq!(tunnels.len(), 2);
+        assert_eq!(tunnels[1].name, "app-lnd-node-9735");
+        assert_eq!(tunnels[1].targets, vec!["10.21.22.3:9735"]);
+        assert!(owns_tunnel("lnd", &tunnels[1].name, &app_ids));
+    }
+}
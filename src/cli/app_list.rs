@@ -0,0 +1,247 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    metrics::AppFailure,
+    paths::CitadelPaths,
+    template_env::{EnvScope, TemplateEnvConfig},
+    UserJson,
+};
+use crate::{composegenerator::types::OutputMetadata, naming};
+
+/// The result of the last conversion of an app
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ConversionStatus {
+    Converted,
+    /// Converted, but it can't be installed on this node
+    Unsupported,
+    /// The stage it failed in
+    Failed(String),
+    /// Not in the registry, the apps dir changed since the last conversion
+    Unknown,
+}
+
+impl std::fmt::Display for ConversionStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConversionStatus::Converted => write!(f, "converted"),
+            ConversionStatus::Unsupported => write!(f, "unsupported"),
+            ConversionStatus::Failed(stage) => write!(f, "failed ({stage})"),
+            ConversionStatus::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+/// An app in the apps dir and its state on the node
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct AppState {
+    pub id: String,
+    /// The version from registry.json
+    pub version: Option<String>,
+    pub installed: bool,
+    /// The IP of the app's main container
+    pub ip: Option<String>,
+    pub conversion: ConversionStatus,
+}
+
+/// The part of apps/conversion-report.json needed here
+#[derive(Deserialize, Default)]
+struct LastReport {
+    #[serde(default)]
+    failures: Vec<AppFailure>,
+}
+
+/// The IP of an app's main container, or of its only container.
+/// The main container is the one serving the app's first UI.
+pub(super) fn main_ip(
+    app_id: &str,
+    metadata: Option<&OutputMetadata>,
+    ips: &BTreeMap<String, String>,
+    scope: &EnvScope,
+) -> Option<String> {
+    if let Some(entry) = metadata.and_then(|metadata| metadata.entries.first()) {
+        let ip_var = naming::ip_env_var(app_id, &entry.container);
+        if let Some(ip) = ips.get(&ip_var) {
+            return Some(ip.clone());
+        }
+    }
+    let mut own_ips = ips
+        .iter()
+        .filter(|(key, _)| key.ends_with("_IP") && scope.owner(key) == Some(app_id));
+    match (own_ips.next(), own_ips.next()) {
+        (Some((_, ip)), None) => Some(ip.clone()),
+        _ => None,
+    }
+}
+
+/// The IDs of all apps in the apps dir
+pub(super) fn app_ids(apps_dir: &Path) -> Result<BTreeSet<String>> {
+    let mut app_ids = BTreeSet::new();
+    for entry in std::fs::read_dir(apps_dir)
+        .with_context(|| format!("Failed to read {}", apps_dir.display()))?
+    {
+        let entry = entry?;
+        if entry.path().join("app.yml").is_file() {
+            app_ids.insert(entry.file_name().to_string_lossy().to_string());
+        }
+    }
+    Ok(app_ids)
+}
+
+/// The apps in registry.json by ID, empty if nothing was converted yet
+pub(super) fn load_registry(
+    paths: &CitadelPaths,
+    apps_dir: &Path,
+) -> Result<HashMap<String, OutputMetadata>> {
+    let registry_file = apps_dir.join("registry.json");
+    if !paths.exists(&registry_file) {
+        return Ok(HashMap::new());
+    }
+    Ok(
+        serde_json::from_str::<Vec<OutputMetadata>>(&paths.read_to_string(&registry_file)?)
+            .context("Failed to load registry.json")?
+            .into_iter()
+            .map(|metadata| (metadata.id.clone(), metadata))
+            .collect(),
+    )
+}
+
+/// Env var -> IP from ips.yml, empty if nothing was converted yet
+pub(super) fn load_ips(paths: &CitadelPaths, apps_dir: &Path) -> Result<BTreeMap<String, String>> {
+    let ips_file = apps_dir.join("ips.yml");
+    if !paths.exists(&ips_file) {
+        return Ok(BTreeMap::new());
+    }
+    serde_yaml::from_reader(paths.open(&ips_file)?).context("Failed to load ips.yml")
+}
+
+pub(super) fn installed_apps(paths: &CitadelPaths, citadel_root: &Path) -> Vec<String> {
+    paths
+        .open(&citadel_root.join("db").join("user.json"))
+        .ok()
+        .and_then(|user_json| serde_json::from_reader::<_, UserJson>(user_json).ok())
+        .map(|user_json| user_json.installed_apps)
+        .unwrap_or_default()
+}
+
+/// Collects the state of all apps from the apps dir, registry.json, ips.yml, user.json
+/// and the last conversion report
+pub fn list(citadel_root: &str, state_dir: &Option<String>) -> Result<Vec<AppState>> {
+    let citadel_root = Path::new(citadel_root);
+    let paths = CitadelPaths::new(citadel_root, state_dir.as_deref().map(Path::new));
+    let apps_dir = citadel_root.join("apps");
+    let app_ids = app_ids(&apps_dir)?;
+    let registry = load_registry(&paths, &apps_dir)?;
+    let ips = load_ips(&paths, &apps_dir)?;
+    let installed_apps = installed_apps(&paths, citadel_root);
+    let report_file = apps_dir.join("conversion-report.json");
+    let report: LastReport = if paths.exists(&report_file) {
+        serde_json::from_str(&paths.read_to_string(&report_file)?)
+            .context("Failed to load conversion-report.json")?
+    } else {
+        LastReport::default()
+    };
+
+    let scope = EnvScope::new(
+        app_ids.iter().map(String::as_str),
+        &TemplateEnvConfig::default(),
+    );
+    Ok(app_ids
+        .iter()
+        .map(|app_id| {
+            let metadata = registry.get(app_id);
+            let conversion = if let Some(failure) = report
+                .failures
+                .iter()
+                .find(|failure| failure.app == *app_id)
+            {
+                ConversionStatus::Failed(failure.stage.clone())
+            } else {
+                match metadata {
+                    Some(metadata) if !metadata.unsupported.is_empty() => {
+                        ConversionStatus::Unsupported
+                    }
+                    Some(_) => ConversionStatus::Converted,
+                    None => ConversionStatus::Unknown,
+                }
+            };
+            AppState {
+                id: app_id.clone(),
+                version: metadata.map(|metadata| metadata.version.clone()),
+                installed: installed_apps.contains(app_id),
+                ip: main_ip(app_id, metadata, &ips, &scope),
+                conversion,
+            }
+        })
+        .collect())
+}
+
+/// Formats the apps as a table
+pub fn format_table(apps: &[AppState]) -> String {
+    let mut table = format!(
+        "{:<24} {:<12} {:<10} {:<15} {}\n",
+        "APP", "VERSION", "STATUS", "IP", "CONVERSION"
+    );
+    for app in apps {
+        table.push_str(&format!(
+            "{:<24} {:<12} {:<10} {:<15} {}\n",
+            app.id,
+            app.version.as_deref().unwrap_or("-"),
+            if app.installed {
+                "installed"
+            } else {
+                "available"
+            },
+            app.ip.as_deref().unwrap_or("-"),
+            app.conversion
+        ));
+    }
+    table
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use super::main_ip;
+    use crate::cli::template_env::{EnvScope, TemplateEnvConfig};
+    use crate::composegenerator::types::{OutputMetadata, OutputUiEntry};
+
+    #[test]
+    fn finds_main_container_ip() {
+        let scope = EnvScope::new(["lnd", "lnd-tools"], &TemplateEnvConfig::default());
+        let ips = BTreeMap::from([
+            ("APP_LND_WEB_IP".to_string(), "10.21.22.2".to_string()),
+            ("APP_LND_SERVICE_IP".to_string(), "10.21.22.3".to_string()),
+            (
+                "APP_LND_TOOLS_MAIN_IP".to_string(),
+                "10.21.22.4".to_string(),
+            ),
+        ]);
+        let metadata = OutputMetadata {
+            entries: vec![OutputUiEntry {
+                name: "Web".to_string(),
+                container: "web".to_string(),
+                port: 3000,
+                internal_port: 80,
+                path: None,
+            }],
+            ..Default::default()
+        };
+        assert_eq!(
+            main_ip("lnd", Some(&metadata), &ips, &scope).as_deref(),
+            Some("10.21.22.2")
+        );
+        // lnd has two containers, the main one is unknown without the registry
+        assert_eq!(main_ip("lnd", None, &ips, &scope), None);
+        assert_eq!(
+            main_ip("lnd-tools", None, &ips, &scope).as_deref(),
+            Some("10.21.22.4")
+        );
+    }
+}
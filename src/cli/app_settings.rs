@@ -0,0 +1,237 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use lazy_static::lazy_static;
+use regex::{Captures, Regex};
+
+use super::paths::CitadelPaths;
+use crate::composegenerator::{
+    compose::types::StringOrIntOrBool, ir::AppDefinition, load_definition_file_with,
+    types::OutputSetting,
+};
+
+lazy_static! {
+    // $$ is an escaped $ in compose files
+    static ref ENV_VAR_REGEX: Regex = Regex::new(r"\$\$|\$\{(\w+)\}|\$(\w+)").unwrap();
+}
+
+/// Values of app settings set on the dashboard, app ID -> env var -> value, stored in db/app-settings.json
+pub type SettingValues = BTreeMap<String, BTreeMap<String, String>>;
+
+fn values_file(citadel_root: &Path) -> PathBuf {
+    citadel_root.join("db").join("app-settings.json")
+}
+
+pub fn load_values(paths: &CitadelPaths, citadel_root: &Path) -> Result<SettingValues> {
+    let values_file = values_file(citadel_root);
+    if !paths.exists(&values_file) {
+        return Ok(SettingValues::new());
+    }
+    serde_json::from_str(&paths.read_to_string(&values_file)?)
+        .context("Failed to load app-settings.json")
+}
+
+/// The settings set on the dashboard for an app, as env vars for its templates
+pub(crate) fn app_env_vars(values: &SettingValues, app_id: &str) -> HashMap<String, String> {
+    values
+        .get(app_id)
+        .into_iter()
+        .flatten()
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect()
+}
+
+/// The settings of an app that are set, on the dashboard or in the .env file.
+/// Values set on the dashboard take precedence.
+pub(crate) fn configured(
+    app_yml: &AppDefinition,
+    values: &SettingValues,
+    app_id: &str,
+    env: &HashMap<String, String>,
+) -> BTreeMap<String, String> {
+    let app_values = values.get(app_id);
+    app_yml
+        .settings
+        .keys()
+        .filter_map(|key| {
+            let value = app_values
+                .and_then(|app_values| app_values.get(key))
+                .or_else(|| env.get(key))
+                .filter(|value| !value.is_empty())?;
+            Some((key.clone(), value.clone()))
+        })
+        .collect()
+}
+
+/// Replaces the settings in the environment of the app's containers with their values,
+/// or their defaults if they are not set
+pub(crate) fn apply(app_yml: &mut AppDefinition, configured: &BTreeMap<String, String>) {
+    let values: HashMap<&str, &String> = app_yml
+        .settings
+        .iter()
+        .filter_map(|(key, rule)| {
+            let value = configured.get(key).or(rule.default.as_ref())?;
+            // Values from the .env file can contain $, compose still gets those from there
+            (!value.contains('$')).then_some((key.as_str(), value))
+        })
+        .collect();
+    if values.is_empty() {
+        return;
+    }
+    for service in app_yml.services.values_mut() {
+        for value in service
+            .environment
+            .iter_mut()
+            .flat_map(|env| env.values_mut())
+        {
+            let StringOrIntOrBool::String(value) = value else {
+                continue;
+            };
+            *value = ENV_VAR_REGEX
+                .replace_all(value, |captures: &Captures| {
+                    captures
+                        .get(1)
+                        .or_else(|| captures.get(2))
+                        .and_then(|name| values.get(name.as_str()))
+                        .map_or_else(|| captures[0].to_string(), |value| value.to_string())
+                })
+                .into_owned();
+        }
+    }
+}
+
+fn describe(
+    app_yml: &AppDefinition,
+    values: Option<&BTreeMap<String, String>>,
+) -> Vec<OutputSetting> {
+    app_yml
+        .settings
+        .iter()
+        .map(|(id, rule)| OutputSetting {
+            id: id.clone(),
+            rule: rule.clone(),
+            value: values.and_then(|values| values.get(id)).cloned(),
+        })
+        .collect()
+}
+
+/// Changes the settings of an app, after checking them against the rules in its app.yml.
+/// An empty value unsets a setting. Returns all settings of the app with the values set on the dashboard.
+pub fn set(
+    citadel_root: &str,
+    state_dir: &Option<String>,
+    app_id: &str,
+    changes: BTreeMap<String, String>,
+) -> Result<Vec<OutputSetting>> {
+    let citadel_root = Path::new(citadel_root);
+    let paths = CitadelPaths::new(citadel_root, state_dir.as_deref().map(Path::new));
+    let app_yml_path = citadel_root.join("apps").join(app_id).join("app.yml");
+    if !paths.exists(&app_yml_path) {
+        bail!("App {app_id} does not exist");
+    }
+    let app_yml = load_definition_file_with(&app_yml_path, None, &|path| paths.read_path(path))
+        .with_context(|| format!("Failed to load the app.yml of {app_id}"))?;
+    let mut all_values = load_values(&paths, citadel_root)?;
+    if changes.is_empty() {
+        return Ok(describe(&app_yml, all_values.get(app_id)));
+    }
+    let values = all_values.entry(app_id.to_string()).or_default();
+    for (key, value) in changes {
+        let Some(rule) = app_yml.settings.get(&key) else {
+            bail!("{app_id} has no setting {key}");
+        };
+        if value.is_empty() {
+            values.remove(&key);
+        } else if value.contains('$') {
+            // It would be read as an env var in the compose file
+            bail!("{key} can't contain $");
+        } else if let Some(problem) = rule.check(&value) {
+            bail!("{key} {problem}");
+        } else {
+            values.insert(key, value);
+        }
+    }
+    let settings = describe(&app_yml, Some(values));
+    if values.is_empty() {
+        all_values.remove(app_id);
+    }
+    paths.write(
+        &values_file(citadel_root),
+        serde_json::to_string_pretty(&all_values)?,
+    )?;
+    Ok(settings)
+}
+
+/// Formats the settings of an app for the terminal
+pub fn format_settings(settings: &[OutputSetting]) -> String {
+    let mut output = String::new();
+    for setting in settings {
+        let value = match (&setting.value, &setting.rule.default) {
+            (Some(value), _) => value.clone(),
+            (None, Some(default)) => format!("{default} (default)"),
+            (None, None) => "not set".to_string(),
+        };
+        let _ = writeln!(output, "{}: {}", setting.id, value);
+        if let Some(description) = &setting.rule.description {
+            let _ = writeln!(output, "    {description}");
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use super::apply;
+    use crate::composegenerator::{compose::types::StringOrIntOrBool, ir::AppDefinition};
+
+    #[test]
+    fn applies_setting_values() {
+        let mut app_yml = AppDefinition::default();
+        app_yml.services.insert(
+            "main".to_string(),
+            serde_yaml::from_str(
+                "
+image: lnd
+environment:
+  ALIAS: ${APP_LND_ALIAS}
+  NETWORK: --network=$APP_LND_NETWORK
+  FEE_RATE: $APP_LND_FEE_RATE
+  PRICE: $$APP_LND_ALIAS
+  IP: $APP_LND_MAIN_IP
+",
+            )
+            .unwrap(),
+        );
+        app_yml.settings = serde_yaml::from_str(
+            "
+APP_LND_ALIAS: {}
+APP_LND_NETWORK:
+  default: mainnet
+APP_LND_FEE_RATE:
+  default: '1'
+",
+        )
+        .unwrap();
+        let configured = BTreeMap::from([
+            ("APP_LND_ALIAS".to_string(), "citadel".to_string()),
+            ("APP_LND_FEE_RATE".to_string(), "$FEE".to_string()),
+        ]);
+        apply(&mut app_yml, &configured);
+        let environment = app_yml.services["main"].environment.as_ref().unwrap();
+        let value = |key: &str| match &environment[key] {
+            StringOrIntOrBool::String(value) => value.as_str(),
+            _ => unreachable!(),
+        };
+        assert_eq!(value("ALIAS"), "citadel");
+        assert_eq!(value("NETWORK"), "--network=mainnet");
+        assert_eq!(value("FEE_RATE"), "$APP_LND_FEE_RATE");
+        assert_eq!(value("PRICE"), "$$APP_LND_ALIAS");
+        assert_eq!(value("IP"), "$APP_LND_MAIN_IP");
+    }
+}
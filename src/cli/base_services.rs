@@ -0,0 +1,52 @@
+use serde::{Deserialize, Serialize};
+
+/// Services the node provides without an app for them, in app-manager.toml
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct BaseServicesConfig {
+    /// Services apps can depend on that are always available.
+    /// Remove bitcoind on nodes without it, or add services another machine provides, like a remote lnd.
+    pub services: Vec<String>,
+}
+
+impl Default for BaseServicesConfig {
+    fn default() -> Self {
+        Self {
+            services: vec!["bitcoind".to_string()],
+        }
+    }
+}
+
+impl BaseServicesConfig {
+    pub fn provides(&self, service: &str) -> bool {
+        self.services.iter().any(|provided| provided == service)
+    }
+
+    /// Adds the base services to the installed apps, so dependencies on them are satisfied
+    pub fn add_to(&self, installed: &mut Vec<String>) {
+        for service in &self.services {
+            if !installed.contains(service) {
+                installed.push(service.clone());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::BaseServicesConfig;
+
+    #[test]
+    fn adds_base_services() {
+        let mut installed = vec!["lnd".to_string()];
+        BaseServicesConfig::default().add_to(&mut installed);
+        assert_eq!(installed, vec!["lnd", "bitcoind"]);
+
+        // A lightning-only node using a remote lnd
+        let config: BaseServicesConfig = toml::from_str("services = [\"lnd\"]").unwrap();
+        assert!(config.provides("lnd") && !config.provides("bitcoind"));
+        let mut installed = vec!["lnd".to_string()];
+        config.add_to(&mut installed);
+        assert_eq!(installed, vec!["lnd"]);
+    }
+}
@@ -0,0 +1,268 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{BufRead, Write},
+    path::Path,
+};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    node_config::NodeConfig, paths::CitadelPaths, start_order, stores::AppStoreInfo, UserJson,
+};
+use crate::{
+    composegenerator::{load_definition_file_with, types::Permissions},
+    naming,
+};
+
+/// A group of apps that are installed together, defined in bundles/<id>.yml of a store
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Bundle {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    /// The apps of the bundle, they have to be in the same store
+    pub apps: Vec<String>,
+    /// Values shared by all apps of the bundle, asked for once when installing it
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub settings: Vec<BundleSetting>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BundleSetting {
+    /// Templates of the bundle's apps get the value as BUNDLE_<ID>
+    pub id: String,
+    /// The question shown when installing the bundle
+    pub prompt: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default: Option<String>,
+}
+
+/// Values of bundle settings, bundle ID -> setting ID -> value, stored in db/bundles.json
+pub type BundleValues = BTreeMap<String, BTreeMap<String, String>>;
+
+/// Loads all bundle definitions from the bundles dir of a store, bundle ID -> bundle
+pub fn load_dir(bundles_dir: &Path) -> BTreeMap<String, Bundle> {
+    let mut bundles = BTreeMap::new();
+    let Ok(entries) = std::fs::read_dir(bundles_dir) else {
+        return bundles;
+    };
+    for path in entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+    {
+        if path.extension().unwrap_or_default() != "yml" {
+            continue;
+        }
+        let Some(bundle_id) = path.file_stem().and_then(|stem| stem.to_str()) else {
+            continue;
+        };
+        let bundle = std::fs::File::open(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|file| Ok(serde_yaml::from_reader::<_, Bundle>(file)?));
+        match bundle {
+            Ok(bundle) => {
+                bundles.insert(bundle_id.to_string(), bundle);
+            }
+            Err(err) => tracing::warn!("Ignoring bundle {}: {:#}", path.display(), err),
+        }
+    }
+    bundles
+}
+
+fn values_file(citadel_root: &Path) -> std::path::PathBuf {
+    citadel_root.join("db").join("bundles.json")
+}
+
+pub fn load_values(paths: &CitadelPaths, citadel_root: &Path) -> Result<BundleValues> {
+    let values_file = values_file(citadel_root);
+    if !paths.exists(&values_file) {
+        return Ok(BundleValues::new());
+    }
+    serde_json::from_str(&paths.read_to_string(&values_file)?)
+        .context("Failed to load bundles.json")
+}
+
+pub const ENV_PREFIX: &str = "BUNDLE_";
+
+/// The env var templates get a setting's value in
+pub fn env_var(setting_id: &str) -> String {
+    format!("{}{}", ENV_PREFIX, naming::env_name(setting_id))
+}
+
+/// The bundles of its store an app is part of
+pub(crate) fn memberships(store: Option<&AppStoreInfo>, app_id: &str) -> Vec<String> {
+    store
+        .into_iter()
+        .flat_map(|store| &store.bundles)
+        .filter(|(_, bundle)| bundle.apps.iter().any(|app| app == app_id))
+        .map(|(bundle_id, _)| bundle_id.clone())
+        .collect()
+}
+
+/// The settings of the installed bundles an app is part of, as env vars for its templates
+pub(crate) fn app_env_vars(
+    stores: &[AppStoreInfo],
+    values: &BundleValues,
+    app_id: &str,
+) -> HashMap<String, String> {
+    let store = stores.iter().find(|store| store.apps.contains_key(app_id));
+    memberships(store, app_id)
+        .iter()
+        .filter_map(|bundle_id| values.get(bundle_id))
+        .flatten()
+        .map(|(setting, value)| (env_var(setting), value.clone()))
+        .collect()
+}
+
+/// Asks for every setting that was not given, an empty answer takes the default
+fn ask_settings(
+    bundle: &Bundle,
+    mut given: BTreeMap<String, String>,
+    mut input: impl BufRead,
+    mut output: impl Write,
+) -> Result<BTreeMap<String, String>> {
+    let mut values = BTreeMap::new();
+    for setting in &bundle.settings {
+        if let Some(value) = given.remove(&setting.id) {
+            values.insert(setting.id.clone(), value);
+            continue;
+        }
+        match &setting.default {
+            Some(default) => write!(output, "{} [{}]: ", setting.prompt, default)?,
+            None => write!(output, "{}: ", setting.prompt)?,
+        }
+        output.flush()?;
+        let mut answer = String::new();
+        input.read_line(&mut answer)?;
+        let answer = answer.trim();
+        let value = match (answer.is_empty(), &setting.default) {
+            (false, _) => answer.to_string(),
+            (true, Some(default)) => default.clone(),
+            (true, None) => bail!("A value for {} is required", setting.id),
+        };
+        values.insert(setting.id.clone(), value);
+    }
+    if let Some(unknown) = given.keys().next() {
+        bail!("Bundle {} has no setting {}", bundle.name, unknown);
+    }
+    Ok(values)
+}
+
+/// Prepares installing a bundle: asks for its settings, saves them to db/bundles.json
+/// and returns the apps that still need to be installed, in the order to install them.
+pub fn install(
+    citadel_root: &str,
+    state_dir: &Option<String>,
+    bundle_id: &str,
+    given: BTreeMap<String, String>,
+    input: impl BufRead,
+    output: impl Write,
+) -> Result<Vec<String>> {
+    let citadel_root = Path::new(citadel_root);
+    let paths = CitadelPaths::new(citadel_root, state_dir.as_deref().map(Path::new));
+    let stores = super::stores::load_stores(&paths, citadel_root)?;
+    let Some(bundle) = stores.iter().find_map(|store| store.bundles.get(bundle_id)) else {
+        bail!("No store provides a bundle {}", bundle_id);
+    };
+    let installed: Vec<String> = paths
+        .open(&citadel_root.join("db").join("user.json"))
+        .ok()
+        .and_then(|user_json| serde_json::from_reader::<_, UserJson>(user_json).ok())
+        .map(|user_json| user_json.installed_apps)
+        .unwrap_or_default();
+    let virtual_apps_file = citadel_root.join("apps").join("virtual-apps.json");
+    let virtual_apps: HashMap<String, Vec<String>> = if paths.exists(&virtual_apps_file) {
+        serde_json::from_str(&paths.read_to_string(&virtual_apps_file)?)
+            .context("Failed to load virtual-apps.json")?
+    } else {
+        HashMap::new()
+    };
+    let base_services = NodeConfig::load(&paths, citadel_root)?.base_services;
+    let is_available = |dependency: &String| {
+        base_services.provides(dependency)
+            || installed.contains(dependency)
+            || bundle.apps.contains(dependency)
+            || virtual_apps.get(dependency).is_some_and(|implementations| {
+                implementations
+                    .iter()
+                    .any(|app| installed.contains(app) || bundle.apps.contains(app))
+            })
+    };
+
+    let mut permissions = HashMap::new();
+    for app_id in &bundle.apps {
+        let app_yml_path = citadel_root.join("apps").join(app_id).join("app.yml");
+        if !paths.exists(&app_yml_path) {
+            bail!("App {} of bundle {} is not available", app_id, bundle_id);
+        }
+        let app_yml = load_definition_file_with(&app_yml_path, None, &|path| paths.read_path(path))
+            .with_context(|| format!("Failed to load app.yml of {app_id}"))?;
+        let mut app_permissions = Vec::new();
+        for permission in &app_yml.metadata.permissions {
+            let alternatives = match permission {
+                Permissions::OneDependency(dependency) => std::slice::from_ref(dependency),
+                Permissions::AlternativeDependency(dependencies) => dependencies.as_slice(),
+            };
+            if !alternatives.iter().any(is_available) {
+                bail!(
+                    "App {} needs {}, which is neither installed nor part of the bundle",
+                    app_id,
+                    alternatives.join(" or ")
+                );
+            }
+            app_permissions.extend(alternatives.iter().cloned());
+        }
+        permissions.insert(app_id.clone(), app_permissions);
+    }
+
+    let values = ask_settings(bundle, given, input, output)?;
+    let mut all_values = load_values(&paths, citadel_root)?;
+    all_values.insert(bundle_id.to_string(), values);
+    paths.write(
+        &values_file(citadel_root),
+        serde_json::to_string_pretty(&all_values)?,
+    )?;
+
+    // Apps the others depend on come first
+    Ok(start_order::compute(&permissions, &virtual_apps)
+        .stages
+        .into_iter()
+        .flatten()
+        .filter(|app_id| !installed.contains(app_id))
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ask_settings, Bundle};
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn asks_for_settings_once() {
+        let bundle: Bundle = serde_yaml::from_str(
+            "name: Media stack
+apps: [jellyfin, sonarr, radarr]
+settings:
+  - id: media-dir
+    prompt: Where is your media stored?
+    default: /mnt/media
+  - id: timezone
+    prompt: Your time zone
+",
+        )
+        .unwrap();
+        let given = BTreeMap::from([("timezone".to_string(), "Europe/Berlin".to_string())]);
+        let mut output = Vec::new();
+        let values = ask_settings(&bundle, given, "\n".as_bytes(), &mut output).unwrap();
+        assert_eq!(values["media-dir"], "/mnt/media");
+        assert_eq!(values["timezone"], "Europe/Berlin");
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "Where is your media stored? [/mnt/media]: "
+        );
+        assert_eq!(super::env_var("media-dir"), "BUNDLE_MEDIA_DIR");
+
+        assert!(ask_settings(&bundle, BTreeMap::new(), "\n\n".as_bytes(), Vec::new()).is_err());
+    }
+}
@@ -0,0 +1,97 @@
+use std::{
+    collections::HashMap,
+    net::Ipv4Addr,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::{bail, Result};
+use serde::de::DeserializeOwned;
+
+use super::paths::CitadelPaths;
+
+/// Moves a corrupt file aside as <name>.corrupt-<unix time>, so it can be inspected later
+pub fn quarantine(paths: &CitadelPaths, path: &Path) -> Result<PathBuf> {
+    let timestamp = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(format!(".corrupt-{timestamp}"));
+    let backup = PathBuf::from(backup);
+    paths.write(&backup, std::fs::read(paths.read_path(path))?)?;
+    paths.remove_file(path)?;
+    paths.write_path(&backup)
+}
+
+/// Loads a YAML cache file, None if it does not exist.
+/// A file that can't be parsed or fails `validate` is quarantined and treated like a missing one.
+pub fn load<T: DeserializeOwned>(
+    paths: &CitadelPaths,
+    path: &Path,
+    validate: impl Fn(&T) -> Result<()>,
+) -> Result<Option<T>> {
+    if !paths.exists(path) {
+        return Ok(None);
+    }
+    let error = match serde_yaml::from_reader::<_, T>(paths.open(path)?) {
+        Ok(cache) => match validate(&cache) {
+            Ok(()) => return Ok(Some(cache)),
+            Err(err) => err,
+        },
+        Err(err) => err.into(),
+    };
+    let backup = quarantine(paths, path)?;
+    tracing::warn!(
+        "{} is corrupt ({:#}), moved it to {} and rebuilding it",
+        path.display(),
+        error,
+        backup.display()
+    );
+    Ok(None)
+}
+
+/// Checks that every entry of ips.yml is an IPv4 address
+pub fn validate_ips(ips: &HashMap<String, String>) -> Result<()> {
+    for (ip_var, ip) in ips {
+        if ip.parse::<Ipv4Addr>().is_err() {
+            bail!("{ip_var} is not an IP address: {ip}");
+        }
+    }
+    Ok(())
+}
+
+/// The IPs of app containers saved in the .env file, which the last conversions appended them to
+pub fn ips_from_env(env: &str) -> HashMap<String, String> {
+    env.lines()
+        .filter_map(|line| line.split_once('='))
+        .filter(|(key, value)| {
+            key.starts_with("APP_") && key.ends_with("_IP") && value.parse::<Ipv4Addr>().is_ok()
+        })
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ips_from_env, validate_ips};
+
+    #[test]
+    fn recovers_ips_from_env() {
+        let ips = ips_from_env(
+            "BITCOIN_NETWORK=mainnet
+APP_LND_WEB_IP=10.21.22.2
+APP_LND_SERVICE_IP=not-an-ip
+APP_MEMPOOL_API_IP=10.21.22.3
+APP_LND_WEB_IP=10.21.22.4
+",
+        );
+        assert_eq!(ips.len(), 2);
+        // Later entries win, like when the env file is loaded
+        assert_eq!(ips["APP_LND_WEB_IP"], "10.21.22.4");
+        assert!(validate_ips(&ips).is_ok());
+        let mut invalid = ips;
+        invalid.insert("APP_LND_SERVICE_IP".to_string(), "10.21.22".to_string());
+        assert!(validate_ips(&invalid).is_err());
+    }
+}
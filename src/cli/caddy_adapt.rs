@@ -0,0 +1,241 @@
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::{Context, Result};
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::{composegenerator::types::CaddyEntry, naming};
+
+lazy_static! {
+    static ref CADDYFILE_LINE: Regex = Regex::new(r"Caddyfile:(\d+)").unwrap();
+}
+
+/// Checking the generated Caddyfile with Caddy before loading it, in app-manager.toml
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct CaddyValidationConfig {
+    /// Let Caddy adapt the Caddyfile first and only load it if that worked
+    pub enabled: bool,
+}
+
+/// An error or warning Caddy reported for the generated Caddyfile
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AdaptProblem {
+    /// 1-based line in the Caddyfile, if Caddy reported one
+    pub line: Option<usize>,
+    /// The apps whose site block contains the line
+    pub apps: Vec<String>,
+    pub message: String,
+}
+
+impl std::fmt::Display for AdaptProblem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if !self.apps.is_empty() {
+            write!(f, "{}: ", self.apps.join(", "))?;
+        }
+        if let Some(line) = self.line {
+            write!(f, "line {line}: ")?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+/// What Caddy reported when adapting the Caddyfile
+#[derive(Debug, Default)]
+pub struct AdaptResult {
+    pub errors: Vec<AdaptProblem>,
+    pub warnings: Vec<AdaptProblem>,
+}
+
+#[derive(Deserialize)]
+struct AdaptWarning {
+    line: Option<usize>,
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct AdaptResponse {
+    #[serde(default)]
+    error: Option<String>,
+    #[serde(default)]
+    warnings: Vec<AdaptWarning>,
+}
+
+/// The addresses the site blocks of apps can be recognized by, app ID -> addresses.
+/// These are the public ports (like :3000) and the upstreams (like 10.21.21.9:3000).
+pub fn app_addresses(
+    caddy_entries: &HashMap<String, Vec<CaddyEntry>>,
+    ip_map: &HashMap<String, String>,
+) -> BTreeMap<String, Vec<String>> {
+    let mut addresses = BTreeMap::new();
+    for (app_id, entries) in caddy_entries {
+        let app_addresses: &mut Vec<String> = addresses.entry(app_id.clone()).or_default();
+        for entry in entries {
+            app_addresses.push(format!(":{}", entry.public_port));
+            let ip_var = naming::ip_env_var(app_id, &entry.container_name);
+            if let Some(ip) = ip_map.get(&ip_var) {
+                app_addresses.push(format!("{}:{}", ip, entry.internal_port));
+            }
+        }
+    }
+    addresses
+}
+
+/// Whether `text` contains `address` not directly followed by another digit
+fn contains_address(text: &str, address: &str) -> bool {
+    text.match_indices(address).any(|(index, _)| {
+        !text[index + address.len()..]
+            .chars()
+            .next()
+            .is_some_and(|c| c.is_ascii_digit())
+    })
+}
+
+/// Finds the apps whose top-level block contains the 1-based `line`
+fn apps_for_line(
+    caddyfile: &str,
+    line: usize,
+    app_addresses: &BTreeMap<String, Vec<String>>,
+) -> Vec<String> {
+    let lines: Vec<&str> = caddyfile.lines().collect();
+    let mut depth = 0usize;
+    let mut block_start = 0;
+    for (index, text) in lines.iter().enumerate() {
+        let code = text.split('#').next().unwrap_or_default();
+        if depth == 0 {
+            block_start = index;
+        }
+        depth += code.matches('{').count();
+        depth = depth.saturating_sub(code.matches('}').count());
+        if depth == 0 && index + 1 >= line {
+            let block = lines[block_start..=index].join("\n");
+            return app_addresses
+                .iter()
+                .filter(|(_, addresses)| {
+                    addresses
+                        .iter()
+                        .any(|address| contains_address(&block, address))
+                })
+                .map(|(app_id, _)| app_id.clone())
+                .collect();
+        }
+    }
+    Vec::new()
+}
+
+fn problem(
+    caddyfile: &str,
+    line: Option<usize>,
+    message: String,
+    app_addresses: &BTreeMap<String, Vec<String>>,
+) -> AdaptProblem {
+    AdaptProblem {
+        line,
+        apps: line
+            .map(|line| apps_for_line(caddyfile, line, app_addresses))
+            .unwrap_or_default(),
+        message,
+    }
+}
+
+/// Maps the errors and warnings in a response of Caddy's /adapt endpoint to the apps they come from
+fn parse_response(
+    caddyfile: &str,
+    response: &str,
+    app_addresses: &BTreeMap<String, Vec<String>>,
+) -> Result<AdaptResult> {
+    let response: AdaptResponse =
+        serde_json::from_str(response).context("Failed to parse the response of Caddy")?;
+    let mut result = AdaptResult::default();
+    if let Some(error) = response.error {
+        let line = CADDYFILE_LINE
+            .captures(&error)
+            .and_then(|captures| captures[1].parse().ok());
+        result
+            .errors
+            .push(problem(caddyfile, line, error, app_addresses));
+    }
+    for warning in response.warnings {
+        result.warnings.push(problem(
+            caddyfile,
+            warning.line,
+            warning.message,
+            app_addresses,
+        ));
+    }
+    Ok(result)
+}
+
+/// Lets Caddy adapt the Caddyfile without loading it
+pub fn adapt(
+    caddy_url: &url::Url,
+    caddyfile: &str,
+    app_addresses: &BTreeMap<String, Vec<String>>,
+) -> Result<AdaptResult> {
+    let response = reqwest::blocking::Client::new()
+        .post(caddy_url.join("/adapt")?)
+        .header("Content-Type", "text/caddyfile")
+        .body(caddyfile.to_string())
+        .send()
+        .context("Failed to reach Caddy")?
+        .text()?;
+    parse_response(caddyfile, &response, app_addresses)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{app_addresses, parse_response};
+    use crate::composegenerator::types::CaddyEntry;
+    use std::collections::HashMap;
+
+    #[test]
+    fn maps_errors_to_apps() {
+        let caddy_entries = HashMap::from([
+            (
+                "lnd".to_string(),
+                vec![CaddyEntry {
+                    public_port: 3000,
+                    internal_port: 80,
+                    container_name: "web".to_string(),
+                    is_primary: true,
+                    priority: 0,
+                }],
+            ),
+            (
+                "btcpay".to_string(),
+                vec![CaddyEntry {
+                    public_port: 30000,
+                    internal_port: 80,
+                    container_name: "main".to_string(),
+                    is_primary: true,
+                    priority: 0,
+                }],
+            ),
+        ]);
+        let ip_map = HashMap::from([
+            ("APP_LND_WEB_IP".to_string(), "10.21.21.9".to_string()),
+            ("APP_BTCPAY_MAIN_IP".to_string(), "10.21.21.10".to_string()),
+        ]);
+        let addresses = app_addresses(&caddy_entries, &ip_map);
+        let caddyfile = ":30000 {\n\treverse_proxy 10.21.21.10:80\n}\n:3000 {\n\treverse_proxy 10.21.21.9:80\n\tbroken\n}\n";
+        let result = parse_response(
+            caddyfile,
+            r#"{"error":"adapting config using caddyfile: Caddyfile:6: unrecognized directive: broken"}"#,
+            &addresses,
+        )
+        .unwrap();
+        assert_eq!(result.errors.len(), 1);
+        assert_eq!(result.errors[0].line, Some(6));
+        assert_eq!(result.errors[0].apps, vec!["lnd"]);
+
+        let result = parse_response(
+            caddyfile,
+            r#"{"result":{},"warnings":[{"file":"Caddyfile","line":2,"message":"deprecated"}]}"#,
+            &addresses,
+        )
+        .unwrap();
+        assert!(result.errors.is_empty());
+        assert_eq!(result.warnings[0].apps, vec!["btcpay"]);
+    }
+}
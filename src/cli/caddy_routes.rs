@@ -0,0 +1,77 @@
+use std::collections::HashMap;
+
+use serde::Serialize;
+
+use crate::composegenerator::types::CaddyEntry;
+
+/// A caddy entry together with the app it belongs to
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct CaddyRoute<'a> {
+    pub app: &'a str,
+    #[serde(flatten)]
+    pub entry: &'a CaddyEntry,
+}
+
+/// Orders the caddy entries of all apps, so the generated config does not depend on map order.
+/// Higher priorities come first, entries with the same priority are ordered by app ID.
+pub fn ordered_routes(caddy_entries: &HashMap<String, Vec<CaddyEntry>>) -> Vec<CaddyRoute<'_>> {
+    let mut routes: Vec<CaddyRoute> = caddy_entries
+        .iter()
+        .flat_map(|(app, entries)| {
+            entries.iter().map(move |entry| CaddyRoute {
+                app: app.as_str(),
+                entry,
+            })
+        })
+        .collect();
+    routes.sort_by(|a, b| {
+        b.entry
+            .priority
+            .cmp(&a.entry.priority)
+            .then(a.app.cmp(b.app))
+            .then_with(|| a.entry.route_cmp(b.entry))
+    });
+    routes
+}
+
+#[cfg(test)]
+mod test {
+    use super::ordered_routes;
+    use crate::composegenerator::types::CaddyEntry;
+    use std::collections::HashMap;
+
+    fn entry(public_port: u16, is_primary: bool, priority: i32) -> CaddyEntry {
+        CaddyEntry {
+            public_port,
+            internal_port: 80,
+            container_name: "web".to_string(),
+            is_primary,
+            priority,
+        }
+    }
+
+    #[test]
+    fn orders_routes_by_priority() {
+        let caddy_entries = HashMap::from([
+            (
+                "btcpay".to_string(),
+                vec![entry(3003, false, 0), entry(3002, true, 0)],
+            ),
+            ("lnd".to_string(), vec![entry(3001, true, 0)]),
+            ("auth".to_string(), vec![entry(3004, true, 10)]),
+        ]);
+        let order: Vec<(&str, u16)> = ordered_routes(&caddy_entries)
+            .iter()
+            .map(|route| (route.app, route.entry.public_port))
+            .collect();
+        assert_eq!(
+            order,
+            vec![
+                ("auth", 3004),
+                ("btcpay", 3002),
+                ("btcpay", 3003),
+                ("lnd", 3001)
+            ]
+        );
+    }
+}
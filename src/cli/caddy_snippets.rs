@@ -0,0 +1,265 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::{bail, Result};
+
+use super::paths::CitadelPaths;
+
+/// Directives apps from untrusted stores may use in their snippets
+const UNTRUSTED_DIRECTIVES: [&str; 11] = [
+    "encode",
+    "handle",
+    "handle_path",
+    "header",
+    "redir",
+    "request_header",
+    "respond",
+    "rewrite",
+    "route",
+    "try_files",
+    "uri",
+];
+
+/// A token of a Caddyfile
+#[derive(Debug)]
+struct Token {
+    text: String,
+    /// Quoted tokens and heredocs are never block delimiters
+    quoted: bool,
+    /// The lines the token starts and ends on, quoted tokens can span lines
+    line: usize,
+    end_line: usize,
+}
+
+/// Splits a snippet into tokens the way Caddy does: tokens are separated by whitespace,
+/// can be quoted with "" (with \" escapes) or ``, heredocs start with <<MARKER,
+/// comments start with # and an escaped newline continues a line
+fn tokenize(snippet: &str) -> Result<Vec<Token>> {
+    let chars: Vec<char> = snippet.chars().collect();
+    let mut tokens = Vec::new();
+    let mut line = 1;
+    let mut i = 0;
+    while let Some(&c) = chars.get(i) {
+        if c == '\\' && chars.get(i + 1) == Some(&'\n') {
+            i += 2;
+            continue;
+        }
+        if c == '\n' {
+            line += 1;
+        }
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+        if c == '#' {
+            while chars.get(i).is_some_and(|c| *c != '\n') {
+                i += 1;
+            }
+            continue;
+        }
+        let start_line = line;
+        let mut text = String::new();
+        let mut quoted = true;
+        let heredoc_marker: String = chars[i..]
+            .iter()
+            .skip(2)
+            .take_while(|c| c.is_ascii_alphanumeric() || **c == '_' || **c == '-')
+            .collect();
+        if c == '"' || c == '`' {
+            i += 1;
+            loop {
+                let Some(&next) = chars.get(i) else {
+                    bail!("Snippet has an unclosed quote");
+                };
+                i += 1;
+                if next == c {
+                    break;
+                }
+                if c == '"' && next == '\\' && chars.get(i) == Some(&'"') {
+                    text.push('"');
+                    i += 1;
+                    continue;
+                }
+                if next == '\n' {
+                    line += 1;
+                }
+                text.push(next);
+            }
+        } else if chars[i..].starts_with(&['<', '<'])
+            && !heredoc_marker.is_empty()
+            && chars.get(i + 2 + heredoc_marker.len()) == Some(&'\n')
+        {
+            i += 3 + heredoc_marker.len();
+            line += 1;
+            loop {
+                if i >= chars.len() {
+                    bail!("Snippet has an unclosed heredoc");
+                }
+                let heredoc_line: String = chars[i..].iter().take_while(|c| **c != '\n').collect();
+                i += heredoc_line.chars().count();
+                if heredoc_line.trim() == heredoc_marker {
+                    break;
+                }
+                text.push_str(&heredoc_line);
+                text.push('\n');
+                if chars.get(i) == Some(&'\n') {
+                    i += 1;
+                    line += 1;
+                }
+            }
+        } else {
+            quoted = false;
+            while let Some(&next) = chars.get(i) {
+                if next.is_whitespace() || (next == '\\' && chars.get(i + 1) == Some(&'\n')) {
+                    break;
+                }
+                text.push(next);
+                i += 1;
+            }
+        }
+        tokens.push(Token {
+            text,
+            quoted,
+            line: start_line,
+            end_line: line,
+        });
+    }
+    Ok(tokens)
+}
+
+/// Checks that a snippet stays inside its site block.
+/// Snippets of apps from untrusted stores are also limited to a small set of directives.
+pub fn validate_snippet(snippet: &str, trusted: bool) -> Result<()> {
+    // Caddy replaces {$VAR} with environment variables before parsing
+    if !trusted && snippet.contains("{$") {
+        bail!("Environment and file placeholders are only allowed for apps from trusted stores");
+    }
+    // A token starts a new line if it starts after the end of the previous one
+    let mut lines: Vec<Vec<Token>> = Vec::new();
+    let mut end_line = 0;
+    for token in tokenize(snippet)? {
+        if token.line > end_line || lines.is_empty() {
+            lines.push(Vec::new());
+        }
+        end_line = token.end_line;
+        lines.last_mut().unwrap().push(token);
+    }
+    // For every open block, whether it contains directives (like route) or options (like header)
+    let mut blocks: Vec<bool> = Vec::new();
+    for line in lines {
+        let first = &line[0];
+        let directive = first.text.as_str();
+        let is_directive = blocks.last().copied().unwrap_or(true);
+        let closes_block = !first.quoted && directive == "}";
+        if !trusted && is_directive && !closes_block && !UNTRUSTED_DIRECTIVES.contains(&directive) {
+            bail!("Directive {directive} is only allowed for apps from trusted stores");
+        }
+        for token in &line {
+            if !trusted && (token.text.contains("{env.") || token.text.contains("{file.")) {
+                bail!(
+                    "Environment and file placeholders are only allowed for apps from trusted stores"
+                );
+            }
+            if token.quoted {
+                continue;
+            }
+            match token.text.as_str() {
+                "{" => blocks
+                    .push(is_directive && ["handle", "handle_path", "route"].contains(&directive)),
+                "}" if blocks.pop().is_none() => {
+                    bail!("Snippet closes a block it did not open");
+                }
+                _ => {}
+            }
+        }
+    }
+    if !blocks.is_empty() {
+        bail!("Snippet has unclosed blocks");
+    }
+    Ok(())
+}
+
+/// Loads the rendered caddy.snippet files of apps, invalid snippets are skipped
+pub fn load_snippets<'a>(
+    paths: &CitadelPaths,
+    apps_dir: &Path,
+    app_ids: impl Iterator<Item = &'a String>,
+    is_trusted: impl Fn(&str) -> bool,
+) -> HashMap<String, String> {
+    let mut snippets = HashMap::new();
+    for app_id in app_ids {
+        let snippet_file = apps_dir.join(app_id).join("caddy.snippet");
+        if !paths.exists(&snippet_file) {
+            continue;
+        }
+        let snippet = match paths.read_to_string(&snippet_file) {
+            Ok(snippet) => snippet,
+            Err(err) => {
+                tracing::warn!("Failed to read Caddy snippet of app {}: {:#}", app_id, err);
+                continue;
+            }
+        };
+        if let Err(err) = validate_snippet(&snippet, is_trusted(app_id)) {
+            tracing::warn!("Ignoring Caddy snippet of app {}: {:#}", app_id, err);
+            continue;
+        }
+        snippets.insert(app_id.clone(), snippet);
+    }
+    snippets
+}
+
+#[cfg(test)]
+mod test {
+    use super::validate_snippet;
+
+    #[test]
+    fn sandboxes_untrusted_snippets() {
+        let snippet = "redir /admin /admin/
+header {
+  X-Robots-Tag none
+}
+handle_path /api/* {
+  rewrite * /v1{uri}
+}
+";
+        assert!(validate_snippet(snippet, false).is_ok());
+        assert!(validate_snippet("reverse_proxy 1.1.1.1:80", false).is_err());
+        assert!(validate_snippet("route {\n  file_server\n}", false).is_err());
+        assert!(validate_snippet("reverse_proxy 10.21.21.2:80", true).is_ok());
+        assert!(validate_snippet("respond {env.APP_SEED}", false).is_err());
+        assert!(validate_snippet("}\nexample.com {", true).is_err());
+        assert!(validate_snippet("route {", true).is_err());
+    }
+
+    #[test]
+    fn tokenizes_like_caddy() {
+        // Braces in quotes don't open or close blocks
+        let snippet = "respond \"{\"\nreverse_proxy 127.0.0.1:2019\nrespond \"}\"";
+        assert!(validate_snippet(snippet, false).is_err());
+        assert!(validate_snippet(snippet, true).is_ok());
+        assert!(validate_snippet(
+            "respond `{`\nreverse_proxy 127.0.0.1:2019\nrespond `}`",
+            false
+        )
+        .is_err());
+        assert!(
+            validate_snippet("respond \"\\\" {\"\nreverse_proxy 127.0.0.1:2019", false).is_err()
+        );
+        assert!(validate_snippet(
+            "respond <<EOF\n  {\n  EOF\nreverse_proxy 127.0.0.1:2019",
+            false
+        )
+        .is_err());
+        // An escaped newline continues the line, so reverse_proxy is an argument here
+        assert!(validate_snippet("respond \\\n  reverse_proxy", false).is_ok());
+        assert!(validate_snippet(
+            "respond \"multiple\nlines\" 200\nheader {\n  X-Frame-Options DENY\n}",
+            false
+        )
+        .is_ok());
+        assert!(validate_snippet("respond \"{env.APP_SEED}\"", false).is_err());
+        assert!(validate_snippet("respond {$APP_SEED}", false).is_err());
+        assert!(validate_snippet("respond \"unclosed", true).is_err());
+        assert!(validate_snippet("header { # }\n  X-Robots-Tag none\n}", false).is_ok());
+    }
+}
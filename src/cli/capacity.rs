@@ -0,0 +1,248 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::Write;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use super::{
+    app_list::{app_ids, load_ips},
+    ip_assignment::{pool_size, Subnet},
+    node_config::NodeConfig,
+    paths::CitadelPaths,
+    template_env::{EnvScope, TemplateEnvConfig},
+    PortCacheMap, RESERVED_PORTS,
+};
+use crate::composegenerator::v4::types::PortPriority;
+
+/// Ports above this are in Linux' default range for outgoing connections
+const LAST_PRACTICAL_PORT: u16 = 32767;
+/// Ports below this can only be bound by root
+const FIRST_PRACTICAL_PORT: u16 = 1024;
+/// Conversions warn once this share of a pool is used
+const WARN_AT: f64 = 0.9;
+
+/// How much of the IPs or ports apps can get is used
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Pool {
+    pub used: usize,
+    pub total: usize,
+    /// How many more apps fit, going by what an app uses on average. None if no app uses any.
+    pub apps_until_full: Option<usize>,
+}
+
+impl Pool {
+    fn new(used: usize, total: usize, apps: usize) -> Self {
+        Self {
+            used,
+            total,
+            apps_until_full: (used > 0 && apps > 0)
+                .then(|| total.saturating_sub(used) * apps / used),
+        }
+    }
+
+    pub fn usage(&self) -> f64 {
+        self.used as f64 / self.total as f64
+    }
+}
+
+/// Usage of the IP and port pools, with a breakdown of the assigned ports
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Capacity {
+    pub ips: Pool,
+    /// Ports from 1024 to 32767, which don't clash with outgoing connections
+    pub ports: Pool,
+    /// Assigned ports outside of that range
+    pub ports_outside_range: usize,
+    /// Ports apps accept any value for
+    pub dynamic_ports: usize,
+    pub required_ports: usize,
+    /// Ports the operator reserved for services outside of the app manager
+    pub reserved_ports: usize,
+}
+
+/// Computes the usage from ips.yml and ports.cache.yml.
+/// `unavailable` are ports used by processes on the host.
+pub(super) fn compute(
+    app_ids: &[String],
+    subnet: &Subnet,
+    ips: &HashMap<String, String>,
+    port_cache: &PortCacheMap,
+    unavailable: &BTreeSet<u16>,
+) -> Capacity {
+    let scope = EnvScope::new(
+        app_ids.iter().map(String::as_str),
+        &TemplateEnvConfig::default(),
+    );
+    let app_ips: Vec<&String> = ips
+        .iter()
+        .filter(|(_, ip)| subnet.is_app_ip(ip))
+        .map(|(var, _)| var)
+        .collect();
+    let ip_apps: BTreeSet<&str> = app_ips.iter().filter_map(|var| scope.owner(var)).collect();
+
+    let practical = FIRST_PRACTICAL_PORT..=LAST_PRACTICAL_PORT;
+    let unusable = RESERVED_PORTS
+        .iter()
+        .chain(unavailable)
+        .filter(|port| practical.contains(*port) && !port_cache.contains_key(port))
+        .collect::<BTreeSet<_>>()
+        .len();
+    let ports_in_range = port_cache
+        .keys()
+        .filter(|port| practical.contains(*port))
+        .count();
+    let port_apps: BTreeSet<&str> = port_cache
+        .values()
+        .filter(|entry| !entry.reserved)
+        .map(|entry| entry.app.as_str())
+        .collect();
+
+    Capacity {
+        ips: Pool::new(app_ips.len(), pool_size(), ip_apps.len()),
+        ports: Pool::new(ports_in_range, practical.len() - unusable, port_apps.len()),
+        ports_outside_range: port_cache.len() - ports_in_range,
+        dynamic_ports: port_cache.values().filter(|entry| entry.dynamic).count(),
+        required_ports: port_cache
+            .values()
+            .filter(|entry| !entry.reserved && entry.priority == PortPriority::Required)
+            .count(),
+        reserved_ports: port_cache.values().filter(|entry| entry.reserved).count(),
+    }
+}
+
+/// Warns about pools that are almost used up, before assigning fails
+pub(super) fn warn_if_low(capacity: &Capacity) {
+    for (name, pool) in [("IPs", &capacity.ips), ("ports", &capacity.ports)] {
+        if pool.usage() >= WARN_AT {
+            tracing::warn!(
+                "{} of {} {} for apps are used, there is room for about {} more apps",
+                pool.used,
+                pool.total,
+                name,
+                pool.apps_until_full.unwrap_or_default()
+            );
+        }
+    }
+}
+
+/// The usage of the IP and port pools as of the last conversion
+pub fn load(citadel_root: &str, state_dir: &Option<String>) -> Result<Capacity> {
+    let citadel_root = Path::new(citadel_root);
+    let paths = CitadelPaths::new(citadel_root, state_dir.as_deref().map(Path::new));
+    let apps_dir = citadel_root.join("apps");
+    let app_ids: Vec<String> = app_ids(&apps_dir)?.into_iter().collect();
+    let ips: HashMap<String, String> = load_ips(&paths, &apps_dir)?.into_iter().collect();
+    let port_cache_file = apps_dir.join("ports.cache.yml");
+    let port_cache: PortCacheMap = if paths.exists(&port_cache_file) {
+        serde_yaml::from_reader(paths.open(&port_cache_file)?)
+            .context("Failed to load ports.cache.yml")?
+    } else {
+        HashMap::new()
+    };
+    let external_ports_file = apps_dir.join("external-ports.json");
+    let unavailable: BTreeSet<u16> = if paths.exists(&external_ports_file) {
+        serde_json::from_str(&paths.read_to_string(&external_ports_file)?)
+            .context("Failed to load external-ports.json")?
+    } else {
+        BTreeSet::new()
+    };
+    let subnet = NodeConfig::load(&paths, citadel_root)?.ip_assignment.subnet;
+    Ok(compute(&app_ids, &subnet, &ips, &port_cache, &unavailable))
+}
+
+fn format_pool(name: &str, pool: &Pool) -> String {
+    let room = match pool.apps_until_full {
+        Some(apps) => format!(", room for about {apps} more apps"),
+        None => String::new(),
+    };
+    format!(
+        "{name:<7}{} of {} used ({:.0}%){room}\n",
+        pool.used,
+        pool.total,
+        pool.usage() * 100.0
+    )
+}
+
+/// Formats the usage for the terminal
+pub fn format_capacity(capacity: &Capacity) -> String {
+    let mut output = format_pool("IPs:", &capacity.ips);
+    output.push_str(&format_pool("Ports:", &capacity.ports));
+    let _ = writeln!(
+        output,
+        "       {} ports outside of {FIRST_PRACTICAL_PORT}-{LAST_PRACTICAL_PORT}",
+        capacity.ports_outside_range
+    );
+    let _ = writeln!(
+        output,
+        "       {} dynamic, {} required, {} reserved",
+        capacity.dynamic_ports, capacity.required_ports, capacity.reserved_ports
+    );
+    output
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::{BTreeSet, HashMap};
+
+    use super::compute;
+    use crate::cli::ip_assignment::Subnet;
+    use crate::cli::PortCacheMapEntry;
+    use crate::composegenerator::v4::types::PortPriority;
+
+    #[test]
+    fn computes_pool_usage() {
+        let app_ids = vec!["lnd".to_string(), "mempool".to_string()];
+        let ips = HashMap::from([
+            ("APP_LND_WEB_IP".to_string(), "10.21.21.20".to_string()),
+            ("APP_LND_API_IP".to_string(), "10.21.21.21".to_string()),
+            ("APP_MEMPOOL_WEB_IP".to_string(), "10.21.21.22".to_string()),
+            ("APP_MEMPOOL_API_IP".to_string(), "10.21.21.23".to_string()),
+        ]);
+        let entry =
+            |app: &str, dynamic: bool, priority: PortPriority, reserved: bool| PortCacheMapEntry {
+                app: app.to_string(),
+                internal_port: 80,
+                container: "web".to_string(),
+                dynamic,
+                implements: None,
+                priority,
+                reserved,
+            };
+        let port_cache = HashMap::from([
+            (3000, entry("lnd", false, PortPriority::Required, false)),
+            (3001, entry("mempool", true, PortPriority::Optional, false)),
+            (9000, entry("nginx", false, PortPriority::Required, true)),
+            (
+                50000,
+                entry("mempool", false, PortPriority::Optional, false),
+            ),
+        ]);
+
+        let capacity = compute(
+            &app_ids,
+            &Subnet::default(),
+            &ips,
+            &port_cache,
+            &BTreeSet::from([8080, 3000]),
+        );
+        assert_eq!(capacity.ips.used, 4);
+        assert_eq!(capacity.ips.total, 235);
+        // 2 IPs per app
+        assert_eq!(capacity.ips.apps_until_full, Some(115));
+        assert_eq!(capacity.ports.used, 3);
+        // 8333 and 8080 can't be used
+        assert_eq!(capacity.ports.total, 31742);
+        assert_eq!(capacity.ports_outside_range, 1);
+        assert_eq!(
+            (
+                capacity.dynamic_ports,
+                capacity.required_ports,
+                capacity.reserved_ports
+            ),
+            (1, 1, 1)
+        );
+    }
+}
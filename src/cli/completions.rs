@@ -0,0 +1,218 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use clap::{Arg, Command};
+
+use super::app_list::app_ids;
+
+/// Arguments that take app IDs
+const APP_ARGS: [&str; 3] = ["app", "apps", "only"];
+
+/// The shells completions can be generated for
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompletionShell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+/// Calls `<bin> complete-apps` first and falls back to the generated completions if it fails
+fn app_completion(shell: CompletionShell, bin: &str, generated: &str) -> String {
+    match shell {
+        CompletionShell::Bash => format!(
+            r#"
+_{bin}_with_apps() {{
+    local apps
+    if apps=$({bin} complete-apps -- "${{COMP_WORDS[@]:1:COMP_CWORD-1}}" 2>/dev/null); then
+        COMPREPLY=($(compgen -W "$apps" -- "${{COMP_WORDS[COMP_CWORD]}}"))
+        return 0
+    fi
+    {generated} "$@"
+}}
+
+complete -F _{bin}_with_apps -o bashdefault -o default {bin}
+"#
+        ),
+        CompletionShell::Zsh => format!(
+            r#"_{bin}() {{
+    local apps
+    if apps=$({bin} complete-apps -- "${{words[@]:1:CURRENT-2}}" 2>/dev/null); then
+        compadd -- ${{(f)apps}}
+        return 0
+    fi
+    {generated} "$@"
+}}
+
+"#
+        ),
+        CompletionShell::Fish => format!(
+            r#"
+function __{bin}_apps
+    {bin} complete-apps -- (commandline -opc)[2..-1] 2>/dev/null
+end
+
+complete -c {bin} -n "__{bin}_apps >/dev/null" -f -a "(__{bin}_apps)"
+"#
+        ),
+    }
+}
+
+/// Generates a completion script for `command`, installed as `bin`.
+/// App IDs are completed by calling the `complete-apps` subcommand, which runs [complete_apps].
+pub fn generate(shell: CompletionShell, mut command: Command, bin: &str) -> Result<String> {
+    let generator = match shell {
+        CompletionShell::Bash => clap_complete::Shell::Bash,
+        CompletionShell::Zsh => clap_complete::Shell::Zsh,
+        CompletionShell::Fish => clap_complete::Shell::Fish,
+    };
+    let mut script = Vec::new();
+    clap_complete::generate(generator, &mut command, bin, &mut script);
+    let script = String::from_utf8(script)?;
+    Ok(match shell {
+        CompletionShell::Bash => {
+            // Characters bash doesn't allow are replaced in the name of the generated function
+            let generated = script
+                .lines()
+                .find_map(|line| line.trim_start().strip_prefix("complete -F "))
+                .and_then(|line| line.split_whitespace().next())
+                .context("The generated bash completions don't register a function")?;
+            let completion = app_completion(shell, bin, generated);
+            // clap_complete names the subcommands of binaries with a dash differently than it looks them up
+            script.replace(&bin.replace('-', "__subcmd__"), &bin.replace('-', "__")) + &completion
+        }
+        CompletionShell::Zsh => {
+            // zsh calls the function named after the binary, so the wrapper takes its name
+            let generated = format!("_{bin}_generated");
+            let script =
+                script.replacen(&format!("\n_{bin}() {{"), &format!("\n{generated}() {{"), 1);
+            // It has to be defined before the script calls it, when zsh loads it on the first completion
+            let end = script
+                .find(&format!("if [ \"$funcstack[1]\" = \"_{bin}\" ]"))
+                .unwrap_or(script.len());
+            format!(
+                "{}{}{}",
+                &script[..end],
+                app_completion(shell, bin, &generated),
+                &script[end..]
+            )
+        }
+        CompletionShell::Fish => script + &app_completion(shell, bin, ""),
+    })
+}
+
+fn find_option<'a>(command: &'a Command, word: &str) -> Option<&'a Arg> {
+    if let Some(long) = word.strip_prefix("--") {
+        command
+            .get_arguments()
+            .find(|arg| arg.get_long() == Some(long))
+    } else {
+        let short = word.chars().nth(1)?;
+        command
+            .get_arguments()
+            .find(|arg| arg.get_short() == Some(short))
+    }
+}
+
+/// The app IDs to complete after `words`, the words before the cursor without the binary name.
+/// None if the next word is not an app, or if the Citadel root is not known yet.
+pub fn complete_apps(mut command: Command, words: &[String]) -> Option<Vec<String>> {
+    command.build();
+    let mut command = &command;
+    let mut positionals = 0;
+    let mut citadel_root = None;
+    // The option the next word is the value of
+    let mut option: Option<&Arg> = None;
+    for word in words {
+        if let Some(arg) = option.take() {
+            if arg.get_id() == "citadel_root" {
+                citadel_root = Some(word.as_str());
+            }
+        } else if word.starts_with('-') && word != "-" {
+            // Values after = or after a short flag are part of the word
+            if !word.contains('=') && (word.starts_with("--") || word.len() == 2) {
+                option = find_option(command, word).filter(|arg| arg.get_action().takes_values());
+            }
+        } else if let Some(subcommand) = command
+            .find_subcommand(word)
+            .filter(|_| positionals >= command.get_positionals().count())
+        {
+            command = subcommand;
+            positionals = 0;
+        } else {
+            if command
+                .get_positionals()
+                .nth(positionals)
+                .is_some_and(|arg| arg.get_id() == "citadel_root")
+            {
+                citadel_root = Some(word.as_str());
+            }
+            positionals += 1;
+        }
+    }
+    let arg = match option {
+        Some(arg) => arg,
+        // Arguments with multiple values take all remaining words
+        None => command.get_positionals().nth(positionals).or_else(|| {
+            command
+                .get_positionals()
+                .last()
+                .filter(|arg| arg.get_num_args().is_some_and(|num| num.max_values() > 1))
+        })?,
+    };
+    if !APP_ARGS.contains(&arg.get_id().as_str()) {
+        return None;
+    }
+    let apps_dir = Path::new(citadel_root.filter(|root| *root != "-")?).join("apps");
+    Some(app_ids(&apps_dir).ok()?.into_iter().collect())
+}
+
+#[cfg(test)]
+mod test {
+    use clap::{Arg, ArgAction, Command};
+    use tempdir::TempDir;
+
+    use super::complete_apps;
+
+    #[test]
+    fn completes_app_ids() {
+        let root = TempDir::new("completions").unwrap();
+        for app in ["lnd", "mempool"] {
+            std::fs::create_dir_all(root.path().join("apps").join(app)).unwrap();
+            std::fs::write(root.path().join("apps").join(app).join("app.yml"), "").unwrap();
+        }
+        let citadel_root = root.path().to_str().unwrap();
+        let command = Command::new("app-cli")
+            .subcommand(
+                Command::new("info")
+                    .arg(Arg::new("citadel_root"))
+                    .arg(Arg::new("app"))
+                    .arg(Arg::new("state_dir").long("state-dir")),
+            )
+            .subcommand(
+                Command::new("convert")
+                    .arg(Arg::new("citadel_root"))
+                    .arg(Arg::new("app").long("app"))
+                    .arg(
+                        Arg::new("dry_run")
+                            .long("dry-run")
+                            .action(ArgAction::SetTrue),
+                    ),
+            );
+        let complete = |words: &[&str]| {
+            let words: Vec<String> = words.iter().map(|word| word.to_string()).collect();
+            complete_apps(command.clone(), &words)
+        };
+        let apps = Some(vec!["lnd".to_string(), "mempool".to_string()]);
+
+        assert_eq!(complete(&["info", citadel_root]), apps);
+        assert_eq!(
+            complete(&["info", "--state-dir", "/state", citadel_root]),
+            apps
+        );
+        assert_eq!(complete(&["convert", citadel_root, "--app"]), apps);
+        assert_eq!(complete(&["info"]), None);
+        assert_eq!(complete(&["info", citadel_root, "lnd"]), None);
+        assert_eq!(complete(&["convert", citadel_root, "--dry-run"]), None);
+        assert_eq!(complete(&["convert", "-", "--app"]), None);
+    }
+}
@@ -0,0 +1,446 @@
+use std::{
+    collections::BTreeMap,
+    io::{BufRead, Write},
+    path::Path,
+    process::Command,
+};
+
+use anyhow::{bail, Context, Result};
+
+use super::{
+    app_filter::AppFilter, node_config::NodeConfig, paths::CitadelPaths,
+    port_review::PortChangePolicy, secrets, validation,
+};
+use crate::{
+    composegenerator::{
+        load_definition_file_with,
+        output::{
+            labels::compose_project_name,
+            types::{ComposeSpecification, Service},
+        },
+        v4::{types::ContainerKind, utils::get_main_container},
+    },
+    naming,
+};
+
+/// The values assigned by the app manager, layered over docker-compose.yml
+pub const OVERRIDE_FILE: &str = "docker-compose.citadel.yml";
+
+/// Splits a generated compose file into docker-compose.yml, which only changes when the app changes,
+/// and an override with the values assigned by the app manager: IPs, published ports, labels and DNS
+pub fn split_generated(
+    spec: &ComposeSpecification,
+) -> (ComposeSpecification, ComposeSpecification) {
+    let mut base = spec.clone();
+    let mut overrides = BTreeMap::new();
+    for (service_name, service) in base.services.iter_mut().flatten() {
+        overrides.insert(
+            service_name.clone(),
+            Service {
+                networks: service.networks.take(),
+                ports: std::mem::take(&mut service.ports),
+                labels: std::mem::take(&mut service.labels),
+                dns: std::mem::take(&mut service.dns),
+                dns_search: std::mem::take(&mut service.dns_search),
+                ..Default::default()
+            },
+        );
+    }
+    let generated = ComposeSpecification {
+        services: Some(overrides),
+        ..Default::default()
+    };
+    (base, generated)
+}
+
+/// A docker compose (or nerdctl compose) command for an app,
+/// using the generated docker-compose.yml and its override
+pub(super) fn compose_command(paths: &CitadelPaths, app_id: &str) -> Result<Command> {
+    let app_dir = paths.root().join("apps").join(app_id);
+    let compose_file = paths.read_path(&app_dir.join("docker-compose.yml"));
+    if !compose_file.exists() {
+        bail!("App {app_id} is not installed or has not been converted yet");
+    }
+    let engine = NodeConfig::load(paths, paths.root())?.runtime.engine;
+    let mut command = Command::new(engine.binary());
+    command
+        .arg("compose")
+        .arg("--project-name")
+        .arg(compose_project_name(app_id))
+        .arg("--file")
+        .arg(compose_file);
+    let override_file = paths.read_path(&app_dir.join(OVERRIDE_FILE));
+    if override_file.exists() {
+        command.arg("--file").arg(override_file);
+    }
+    command
+        .arg("--env-file")
+        .arg(paths.read_path(&paths.root().join(".env")));
+    Ok(command)
+}
+
+/// Returns the container to use, defaulting to the app's main container
+fn resolve_service(paths: &CitadelPaths, app_id: &str, service: Option<String>) -> Result<String> {
+    let app_dir = paths.root().join("apps").join(app_id);
+    let compose_file = paths.open(&app_dir.join("docker-compose.yml"))?;
+    let spec: ComposeSpecification = serde_yaml::from_reader(compose_file)
+        .with_context(|| format!("Failed to load docker-compose.yml of {app_id}"))?;
+    let services = spec.services.unwrap_or_default();
+    let service = match service {
+        Some(service) => service,
+        None => {
+            let app_yml = load_definition_file_with(&app_dir.join("app.yml"), None, &|path| {
+                paths.read_path(path)
+            })?;
+            get_main_container(
+                &app_yml.services,
+                app_yml.metadata.main_container.as_deref(),
+            )?
+            .to_string()
+        }
+    };
+    if !services.contains_key(&service) {
+        bail!(
+            "App {app_id} has no container {service}, available containers: {}",
+            services.keys().cloned().collect::<Vec<_>>().join(", ")
+        );
+    }
+    Ok(service)
+}
+
+pub(super) fn run(mut command: Command) -> Result<i32> {
+    let status = command.status().context("Failed to run docker compose")?;
+    Ok(status.code().unwrap_or(1))
+}
+
+fn logs_command(
+    paths: &CitadelPaths,
+    app_id: &str,
+    service: Option<String>,
+    follow: bool,
+) -> Result<Command> {
+    let mut command = compose_command(paths, app_id)?;
+    command.arg("logs");
+    if follow {
+        command.arg("--follow");
+    }
+    if let Some(service) = service {
+        command.arg(resolve_service(paths, app_id, Some(service))?);
+    }
+    Ok(command)
+}
+
+/// Shows the logs of an app, or only one of its containers. Returns the exit code of docker compose.
+pub fn logs(
+    citadel_root: &str,
+    state_dir: &Option<String>,
+    app_id: &str,
+    service: Option<String>,
+    follow: bool,
+) -> Result<i32> {
+    let paths = CitadelPaths::new(Path::new(citadel_root), state_dir.as_deref().map(Path::new));
+    run(logs_command(&paths, app_id, service, follow)?)
+}
+
+fn exec_command(
+    paths: &CitadelPaths,
+    app_id: &str,
+    service: Option<String>,
+    cmd: &[String],
+) -> Result<Command> {
+    if cmd.is_empty() {
+        bail!("No command given");
+    }
+    let mut command = compose_command(paths, app_id)?;
+    command
+        .arg("exec")
+        .arg(resolve_service(paths, app_id, service)?)
+        .args(cmd);
+    Ok(command)
+}
+
+/// Runs a command in a container of an app (by default the main container).
+/// Returns the exit code of docker compose.
+pub fn exec(
+    citadel_root: &str,
+    state_dir: &Option<String>,
+    app_id: &str,
+    service: Option<String>,
+    cmd: &[String],
+) -> Result<i32> {
+    let paths = CitadelPaths::new(Path::new(citadel_root), state_dir.as_deref().map(Path::new));
+    run(exec_command(&paths, app_id, service, cmd)?)
+}
+
+/// Runs the init containers of an app again, or only `job`.
+/// Returns the exit code of the first one that fails, or 0.
+pub fn rerun_init(
+    citadel_root: &str,
+    state_dir: &Option<String>,
+    app_id: &str,
+    job: Option<String>,
+) -> Result<i32> {
+    let paths = CitadelPaths::new(Path::new(citadel_root), state_dir.as_deref().map(Path::new));
+    let app_dir = paths.root().join("apps").join(app_id);
+    let app_yml = load_definition_file_with(&app_dir.join("app.yml"), None, &|path| {
+        paths.read_path(path)
+    })?;
+    let mut init_containers: Vec<String> = app_yml
+        .services
+        .into_iter()
+        .filter(|(_, container)| container.kind == ContainerKind::Init)
+        .map(|(name, _)| name)
+        .collect();
+    init_containers.sort();
+    if let Some(job) = job {
+        if !init_containers.contains(&job) {
+            bail!(
+                "App {app_id} has no init container {job}, available init containers: {}",
+                init_containers.join(", ")
+            );
+        }
+        init_containers = vec![job];
+    } else if init_containers.is_empty() {
+        bail!("App {app_id} has no init containers");
+    }
+    for init_container in init_containers {
+        let mut command = compose_command(&paths, app_id)?;
+        command
+            .arg("up")
+            .arg("--force-recreate")
+            .arg("--no-deps")
+            .arg("--exit-code-from")
+            .arg(&init_container)
+            .arg(&init_container);
+        let code = run(command)?;
+        if code != 0 {
+            tracing::error!("Init container {} of {} failed", init_container, app_id);
+            return Ok(code);
+        }
+    }
+    Ok(0)
+}
+
+/// Asks the operator to confirm deleting the data of an app by typing its ID
+fn confirm_reset(app_id: &str, mut input: impl BufRead, mut output: impl Write) -> Result<bool> {
+    write!(
+        output,
+        "This deletes all data of {app_id}. Type the app ID to continue: "
+    )?;
+    output.flush()?;
+    let mut answer = String::new();
+    input.read_line(&mut answer)?;
+    Ok(answer.trim() == app_id)
+}
+
+/// Resets an app: stops it, removes its containers and (unless `keep_data` is set) its data,
+/// then converts it again and starts it if it has a compose file afterwards.
+/// Unless `yes` is set, deleting data needs to be confirmed on stdin.
+/// Caddy is reloaded after the conversion if `caddy_url` is set.
+pub fn reset(
+    citadel_root: &str,
+    caddy_url: &Option<String>,
+    state_dir: &Option<String>,
+    app_id: &str,
+    keep_data: bool,
+    yes: bool,
+) -> Result<()> {
+    validation::validate_app_id(app_id)?;
+    let paths = CitadelPaths::new(Path::new(citadel_root), state_dir.as_deref().map(Path::new));
+    let citadel_root = paths.root();
+    if !paths.exists(&citadel_root.join("apps").join(app_id).join("app.yml")) {
+        bail!("App {app_id} does not exist");
+    }
+    if !keep_data && !yes && !confirm_reset(app_id, std::io::stdin().lock(), std::io::stderr())? {
+        bail!("Reset of {app_id} was cancelled");
+    }
+
+    if let Ok(mut command) = compose_command(&paths, app_id) {
+        command.arg("down").arg("--remove-orphans");
+        if !keep_data {
+            command.arg("--volumes");
+        }
+        if run(command)? != 0 {
+            bail!("Failed to stop {app_id}");
+        }
+    }
+    if !keep_data {
+        paths.remove_dir_all(&naming::data_dir(citadel_root, app_id))?;
+        // New credentials are derived from the current seed
+        secrets::clear_rotation(&paths, citadel_root, app_id)?;
+        // The LAN certificate is issued again during the conversion
+        let tls_dir = citadel_root.join("tls").join("apps");
+        paths.remove_file(&tls_dir.join(format!("{app_id}.crt")))?;
+        paths.remove_file(&tls_dir.join(format!("{app_id}.key")))?;
+    }
+
+    super::failures::convert_dir(
+        &citadel_root.to_string_lossy(),
+        caddy_url,
+        state_dir,
+        PortChangePolicy::default(),
+        &AppFilter {
+            only: vec![app_id.to_string()],
+            ..Default::default()
+        },
+        None,
+        None,
+    )?;
+    if let Ok(mut command) = compose_command(&paths, app_id) {
+        command.arg("up").arg("--detach");
+        if run(command)? != 0 {
+            bail!("Failed to start {app_id}");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::{path::Path, process::Command};
+
+    use super::{
+        compose_command, confirm_reset, exec_command, logs_command, resolve_service,
+        split_generated, OVERRIDE_FILE,
+    };
+    use crate::{
+        bmap,
+        cli::paths::CitadelPaths,
+        composegenerator::output::types::{ComposeSpecification, NetworkEntry, Service},
+    };
+
+    /// A Citadel root with a converted app lnd, whose main container is web
+    fn converted_app() -> tempdir::TempDir {
+        let root = tempdir::TempDir::new("compose").unwrap();
+        let app_dir = root.path().join("apps").join("lnd");
+        std::fs::create_dir_all(&app_dir).unwrap();
+        std::fs::write(
+            app_dir.join("app.yml"),
+            "citadel_version: 4
+metadata:
+  name: LND
+  version: 1.0.0
+  category: Bitcoin
+  tagline: Lightning
+  developers: {}
+  permissions: []
+  repo: {}
+  support: https://example.com
+  description: Lightning
+  main_container: web
+services:
+  lnd:
+    image: lnd
+  web:
+    image: web
+    port: 3000
+",
+        )
+        .unwrap();
+        std::fs::write(
+            app_dir.join("docker-compose.yml"),
+            "services:\n  lnd:\n    image: lnd\n  web:\n    image: web\n",
+        )
+        .unwrap();
+        std::fs::write(app_dir.join(OVERRIDE_FILE), "services: {}\n").unwrap();
+        root
+    }
+
+    fn args(command: &Command) -> Vec<String> {
+        command
+            .get_args()
+            .map(|arg| arg.to_string_lossy().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn builds_compose_commands() {
+        let root = converted_app();
+        let paths = CitadelPaths::new(root.path(), None);
+        let app_dir = root.path().join("apps").join("lnd");
+        let path = |path: &Path| path.to_string_lossy().to_string();
+
+        let command = compose_command(&paths, "lnd").unwrap();
+        assert_eq!(command.get_program(), "docker");
+        assert_eq!(
+            args(&command),
+            vec![
+                "compose".to_string(),
+                "--project-name".to_string(),
+                "lnd".to_string(),
+                "--file".to_string(),
+                path(&app_dir.join("docker-compose.yml")),
+                "--file".to_string(),
+                path(&app_dir.join(OVERRIDE_FILE)),
+                "--env-file".to_string(),
+                path(&root.path().join(".env")),
+            ]
+        );
+        assert!(compose_command(&paths, "mempool").is_err());
+
+        let logs = args(&logs_command(&paths, "lnd", None, true).unwrap());
+        assert_eq!(logs[logs.len() - 2..], ["logs", "--follow"]);
+        let logs = args(&logs_command(&paths, "lnd", Some("lnd".to_string()), false).unwrap());
+        assert_eq!(logs[logs.len() - 2..], ["logs", "lnd"]);
+
+        let cmd = ["lncli".to_string(), "getinfo".to_string()];
+        let exec = args(&exec_command(&paths, "lnd", None, &cmd).unwrap());
+        assert_eq!(exec[exec.len() - 4..], ["exec", "web", "lncli", "getinfo"]);
+        assert!(exec_command(&paths, "lnd", None, &[]).is_err());
+    }
+
+    #[test]
+    fn resolves_containers() {
+        let root = converted_app();
+        let paths = CitadelPaths::new(root.path(), None);
+        assert_eq!(resolve_service(&paths, "lnd", None).unwrap(), "web");
+        assert_eq!(
+            resolve_service(&paths, "lnd", Some("lnd".to_string())).unwrap(),
+            "lnd"
+        );
+        let error = resolve_service(&paths, "lnd", Some("tor".to_string())).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "App lnd has no container tor, available containers: lnd, web"
+        );
+        // Unknown containers are rejected before docker compose runs
+        assert!(logs_command(&paths, "lnd", Some("tor".to_string()), false).is_err());
+        assert!(exec_command(&paths, "lnd", Some("tor".to_string()), &["sh".to_string()]).is_err());
+    }
+
+    #[test]
+    fn reset_needs_the_app_id() {
+        assert!(confirm_reset("lnd", &b"lnd\n"[..], Vec::new()).unwrap());
+        assert!(!confirm_reset("lnd", &b"y\n"[..], Vec::new()).unwrap());
+    }
+
+    #[test]
+    fn splits_generated_values() {
+        let spec = ComposeSpecification {
+            services: Some(bmap! {
+                "web" => Service {
+                    image: Some("example/web".to_string()),
+                    ports: vec!["9735:9735".to_string()],
+                    networks: Some(bmap! {
+                        "default" => NetworkEntry {
+                            ipv4_address: Some("$APP_LND_WEB_IP".to_string())
+                        }
+                    }),
+                    ..Default::default()
+                }
+            }),
+            ..Default::default()
+        };
+        let (base, generated) = split_generated(&spec);
+        let base_service = &base.services.unwrap()["web"];
+        assert_eq!(base_service.image.as_deref(), Some("example/web"));
+        assert!(base_service.ports.is_empty() && base_service.networks.is_none());
+        let generated_service = &generated.services.unwrap()["web"];
+        assert_eq!(generated_service.ports, vec!["9735:9735"]);
+        assert!(generated_service.image.is_none());
+        assert_eq!(
+            generated_service.networks,
+            spec.services.unwrap()["web"].networks
+        );
+    }
+}
@@ -0,0 +1,312 @@
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+    sync::mpsc::{Receiver, RecvTimeoutError},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use super::{node_config::NodeConfig, paths::CitadelPaths};
+
+/// How often a long-running process checks the configuration for changes
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Deserialize)]
+struct Source {
+    repo: String,
+}
+
+/// The configuration a long-running process picks up without a restart:
+/// app-manager.toml and the stores apps are downloaded from in apps/sources.yml
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ConfigSnapshot {
+    /// The top-level settings and tables of app-manager.toml
+    pub settings: toml::Table,
+    /// app-manager.toml as the app manager uses it
+    pub node: NodeConfig,
+    pub sources: BTreeSet<String>,
+}
+
+impl ConfigSnapshot {
+    pub fn load(paths: &CitadelPaths, citadel_root: &Path) -> Result<Self> {
+        let config_file = config_file(citadel_root);
+        let settings = if paths.exists(&config_file) {
+            toml::from_str(&paths.read_to_string(&config_file)?)
+                .with_context(|| format!("Failed to parse {}", config_file.display()))?
+        } else {
+            toml::Table::new()
+        };
+        let node = toml::Value::Table(settings.clone())
+            .try_into()
+            .with_context(|| format!("Failed to parse {}", config_file.display()))?;
+        let sources_file = sources_file(citadel_root);
+        let sources = if paths.exists(&sources_file) {
+            serde_yaml::from_str::<Vec<Source>>(&paths.read_to_string(&sources_file)?)
+                .context("Failed to parse sources.yml")?
+                .into_iter()
+                .map(|source| source.repo)
+                .collect()
+        } else {
+            BTreeSet::new()
+        };
+        Ok(Self {
+            settings,
+            node,
+            sources,
+        })
+    }
+}
+
+/// What changed in the configuration
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReloadEvent {
+    SourceAdded(String),
+    SourceRemoved(String),
+    /// A setting or table of app-manager.toml, the next conversion uses it
+    SettingsChanged(String),
+    /// The subnet app containers get their IPs in, old -> new.
+    /// Only containers without an IP in ips.yml get one in the new subnet.
+    SubnetChanged(String, String),
+}
+
+impl ReloadEvent {
+    pub fn log(&self) {
+        match self {
+            Self::SourceAdded(repo) => tracing::info!("Reloaded config: new store {}", repo),
+            Self::SourceRemoved(repo) => tracing::info!(
+                "Reloaded config: store {} was removed, its apps stay until they are removed",
+                repo
+            ),
+            Self::SettingsChanged(key) => {
+                tracing::info!("Reloaded config: {} in app-manager.toml changed", key)
+            }
+            Self::SubnetChanged(old, new) => tracing::warn!(
+                "Reloaded config: the app subnet changed from {} to {}, apps keep their IPs until they are freed with `ips free`",
+                old,
+                new
+            ),
+        }
+    }
+}
+
+/// The events for the changes from `old` to `new`
+pub fn diff(old: &ConfigSnapshot, new: &ConfigSnapshot) -> Vec<ReloadEvent> {
+    let mut events: Vec<ReloadEvent> = new
+        .sources
+        .difference(&old.sources)
+        .map(|repo| ReloadEvent::SourceAdded(repo.clone()))
+        .chain(
+            old.sources
+                .difference(&new.sources)
+                .map(|repo| ReloadEvent::SourceRemoved(repo.clone())),
+        )
+        .collect();
+    let keys: BTreeSet<&String> = old.settings.keys().chain(new.settings.keys()).collect();
+    events.extend(
+        keys.into_iter()
+            .filter(|key| old.settings.get(*key) != new.settings.get(*key))
+            .map(|key| ReloadEvent::SettingsChanged(key.clone())),
+    );
+    let (old_subnet, new_subnet) = (old.node.ip_assignment.subnet, new.node.ip_assignment.subnet);
+    if old_subnet != new_subnet {
+        events.push(ReloadEvent::SubnetChanged(
+            old_subnet.to_string(),
+            new_subnet.to_string(),
+        ));
+    }
+    events
+}
+
+fn config_file(citadel_root: &Path) -> PathBuf {
+    citadel_root.join("app-manager.toml")
+}
+
+fn sources_file(citadel_root: &Path) -> PathBuf {
+    citadel_root.join("apps").join("sources.yml")
+}
+
+/// Whether `path` is one of the files a snapshot is loaded from
+pub fn is_config_file(citadel_root: &Path, path: &Path) -> bool {
+    path == config_file(citadel_root) || path == sources_file(citadel_root)
+}
+
+/// Keeps the last loaded configuration to report what changed when it is reloaded
+pub struct ConfigReloader {
+    paths: CitadelPaths,
+    snapshot: ConfigSnapshot,
+}
+
+impl ConfigReloader {
+    pub fn new(paths: CitadelPaths) -> Result<Self> {
+        let snapshot = ConfigSnapshot::load(&paths, paths.root())?;
+        Ok(Self { paths, snapshot })
+    }
+
+    /// Loads the configuration again and logs what changed.
+    /// If it can't be loaded, the previous configuration is kept.
+    pub fn reload(&mut self) -> Result<Vec<ReloadEvent>> {
+        let snapshot = ConfigSnapshot::load(&self.paths, self.paths.root())?;
+        let events = diff(&self.snapshot, &snapshot);
+        for event in &events {
+            event.log();
+        }
+        self.snapshot = snapshot;
+        Ok(events)
+    }
+
+    /// The last loaded app-manager.toml
+    pub fn node_config(&self) -> &NodeConfig {
+        &self.snapshot.node
+    }
+
+    /// Downloads the apps of stores added to sources.yml
+    pub fn apply(&self, events: &[ReloadEvent]) {
+        if !events
+            .iter()
+            .any(|event| matches!(event, ReloadEvent::SourceAdded(_)))
+        {
+            return;
+        }
+        #[cfg(feature = "git")]
+        if let Err(err) = super::repos::download_new_apps(&self.paths.root().to_string_lossy()) {
+            tracing::error!("Failed to download the apps of new stores: {:#}", err);
+        }
+        #[cfg(not(feature = "git"))]
+        tracing::warn!("Not downloading new stores, the app manager was built without git support");
+    }
+}
+
+/// Reloads the configuration of a daemon whenever app-manager.toml or apps/sources.yml change,
+/// `reloaded` is called with the events of every reload that changed something, it can apply them.
+/// Runs until `stop` receives or is dropped.
+pub fn watch(
+    paths: CitadelPaths,
+    stop: &Receiver<()>,
+    mut reloaded: impl FnMut(&ConfigReloader, &[ReloadEvent]),
+) {
+    let mut reloader = ConfigReloader::new(paths.clone()).unwrap_or_else(|err| {
+        // Everything is reported as changed once the configuration can be loaded
+        tracing::error!("Failed to load the configuration: {:#}", err);
+        ConfigReloader {
+            paths,
+            snapshot: ConfigSnapshot::default(),
+        }
+    });
+    while let Err(RecvTimeoutError::Timeout) = stop.recv_timeout(POLL_INTERVAL) {
+        match reloader.reload() {
+            Ok(events) if !events.is_empty() => reloaded(&reloader, &events),
+            Ok(_) => {}
+            Err(err) => tracing::error!("Failed to reload the configuration: {:#}", err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tempdir::TempDir;
+
+    use super::{diff, ConfigReloader, ConfigSnapshot, ReloadEvent};
+    use crate::cli::paths::CitadelPaths;
+
+    #[test]
+    fn reports_config_changes() {
+        let old = ConfigSnapshot {
+            settings: toml::from_str(
+                "[host_ports]\nreserved = [8080, 9000]\n[logging]\ndriver = \"local\"",
+            )
+            .unwrap(),
+            sources: ["https://github.com/citadel-core/apps".to_string()].into(),
+            ..Default::default()
+        };
+        assert_eq!(diff(&old, &old.clone()), vec![]);
+
+        let new = ConfigSnapshot {
+            settings: toml::from_str(
+                "[host_ports]\nreserved = [9000, 9001]\n[logging]\ndriver = \"local\"\n[security]\nno_new_privileges = true",
+            )
+            .unwrap(),
+            sources: ["https://example.com/store".to_string()].into(),
+            ..Default::default()
+        };
+        assert_eq!(
+            diff(&old, &new),
+            vec![
+                ReloadEvent::SourceAdded("https://example.com/store".to_string()),
+                ReloadEvent::SourceRemoved("https://github.com/citadel-core/apps".to_string()),
+                ReloadEvent::SettingsChanged("host_ports".to_string()),
+                ReloadEvent::SettingsChanged("security".to_string()),
+            ]
+        );
+        assert_eq!(
+            diff(&new, &ConfigSnapshot::default()),
+            vec![
+                ReloadEvent::SourceRemoved("https://example.com/store".to_string()),
+                ReloadEvent::SettingsChanged("host_ports".to_string()),
+                ReloadEvent::SettingsChanged("logging".to_string()),
+                ReloadEvent::SettingsChanged("security".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn reloads_changed_files() {
+        let dir = TempDir::new("config_reload").unwrap();
+        std::fs::create_dir(dir.path().join("apps")).unwrap();
+        let mut reloader = ConfigReloader::new(CitadelPaths::new(dir.path(), None)).unwrap();
+        assert_eq!(reloader.reload().unwrap(), vec![]);
+
+        std::fs::write(
+            dir.path().join("apps").join("sources.yml"),
+            "- repo: https://github.com/citadel-core/apps\n  branch: main\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.path().join("app-manager.toml"),
+            "[logging]\ndriver = \"local\"\n",
+        )
+        .unwrap();
+        assert_eq!(
+            reloader.reload().unwrap(),
+            vec![
+                ReloadEvent::SourceAdded("https://github.com/citadel-core/apps".to_string()),
+                ReloadEvent::SettingsChanged("logging".to_string()),
+            ]
+        );
+        assert_eq!(reloader.reload().unwrap(), vec![]);
+        assert_eq!(
+            reloader.node_config().logging.as_ref().unwrap().driver,
+            Some("local".to_string())
+        );
+
+        // An invalid file keeps the previous configuration
+        std::fs::write(dir.path().join("app-manager.toml"), "[logging").unwrap();
+        assert!(reloader.reload().is_err());
+        std::fs::write(
+            dir.path().join("app-manager.toml"),
+            "[port_forwarding]\nenabled = \"yes\"\n",
+        )
+        .unwrap();
+        assert!(reloader.reload().is_err());
+        std::fs::write(
+            dir.path().join("app-manager.toml"),
+            "[logging]\ndriver = \"local\"\n",
+        )
+        .unwrap();
+        assert_eq!(reloader.reload().unwrap(), vec![]);
+
+        std::fs::write(
+            dir.path().join("app-manager.toml"),
+            "[logging]\ndriver = \"local\"\n[ip_assignment]\nsubnet = \"10.30.0.0/24\"\n",
+        )
+        .unwrap();
+        assert_eq!(
+            reloader.reload().unwrap(),
+            vec![
+                ReloadEvent::SettingsChanged("ip_assignment".to_string()),
+                ReloadEvent::SubnetChanged("10.21.21.0/24".to_string(), "10.30.0.0/24".to_string()),
+            ]
+        );
+    }
+}
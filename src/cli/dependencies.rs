@@ -0,0 +1,298 @@
+use std::collections::HashMap;
+
+use super::stores::AppStoreInfo;
+use crate::composegenerator::types::{CrossStoreDependency, Permissions};
+
+/// An app that can satisfy a dependency
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Provider {
+    app: String,
+    store: Option<String>,
+}
+
+/// Resolves dependencies of apps to the apps providing them, across all stores
+pub struct DependencyResolver {
+    /// App ID or interface -> apps providing it
+    providers: HashMap<String, Vec<Provider>>,
+    trusted_stores: Vec<String>,
+    /// Services the node provides itself, dependencies on them never resolve to an app
+    base_services: Vec<String>,
+}
+
+impl DependencyResolver {
+    /// `apps` are all available apps and the interface they implement, if any
+    pub fn new<'a>(
+        apps: impl Iterator<Item = (&'a String, Option<&'a String>)>,
+        stores: &[AppStoreInfo],
+        trusted_stores: &[String],
+        base_services: &[String],
+    ) -> Self {
+        let mut providers: HashMap<String, Vec<Provider>> = HashMap::new();
+        for (app_id, implements) in apps {
+            let provider = Provider {
+                app: app_id.clone(),
+                store: stores
+                    .iter()
+                    .find(|store| store.apps.contains_key(app_id))
+                    .map(|store| store.id.clone()),
+            };
+            if let Some(interface) = implements {
+                providers
+                    .entry(interface.clone())
+                    .or_default()
+                    .push(provider.clone());
+            }
+            providers.entry(app_id.clone()).or_default().push(provider);
+        }
+        Self {
+            providers,
+            trusted_stores: trusted_stores.to_vec(),
+            base_services: base_services.to_vec(),
+        }
+    }
+
+    fn is_trusted(&self, store: &Option<String>) -> bool {
+        store
+            .as_ref()
+            .is_some_and(|store| self.trusted_stores.contains(store))
+    }
+
+    /// Picks the app providing one of the alternative dependencies.
+    /// Installed apps are preferred, then apps from the same store, then apps from trusted stores.
+    fn resolve<'a>(
+        &'a self,
+        alternatives: &'a [String],
+        store: Option<&str>,
+        installed: &[String],
+    ) -> Option<(&'a String, &'a Provider)> {
+        alternatives
+            .iter()
+            .flat_map(|dependency| {
+                self.providers
+                    .get(dependency)
+                    .into_iter()
+                    .flatten()
+                    .map(move |provider| (dependency, provider))
+            })
+            .min_by_key(|(_, provider)| {
+                (
+                    !installed.contains(&provider.app),
+                    provider.store.as_deref() != store,
+                    !self.is_trusted(&provider.store),
+                    &provider.app,
+                )
+            })
+    }
+
+    /// Returns the dependencies of an app that resolve to apps from other stores
+    pub fn cross_store_dependencies(
+        &self,
+        permissions: &[Permissions],
+        store: Option<&str>,
+        installed: &[String],
+    ) -> Vec<CrossStoreDependency> {
+        let mut dependencies = Vec::new();
+        for permission in permissions {
+            let alternatives = match permission {
+                Permissions::OneDependency(dependency) => std::slice::from_ref(dependency),
+                Permissions::AlternativeDependency(dependencies) => dependencies.as_slice(),
+            };
+            if alternatives
+                .iter()
+                .any(|dependency| self.base_services.contains(dependency))
+            {
+                continue;
+            }
+            let Some((dependency, provider)) = self.resolve(alternatives, store, installed) else {
+                continue;
+            };
+            let Some(provider_store) = &provider.store else {
+                continue;
+            };
+            if Some(provider_store.as_str()) == store {
+                continue;
+            }
+            dependencies.push(CrossStoreDependency {
+                dependency: dependency.clone(),
+                app: provider.app.clone(),
+                store: provider_store.clone(),
+                trusted: self.is_trusted(&provider.store),
+                installed: installed.contains(&provider.app),
+            });
+        }
+        dependencies
+    }
+
+    /// The dependencies no available app and no base service provides, so the app can't be installed.
+    /// Alternatives are joined with " or ".
+    pub fn unavailable(&self, permissions: &[Permissions]) -> Vec<String> {
+        permissions
+            .iter()
+            .filter_map(|permission| {
+                let alternatives = match permission {
+                    Permissions::OneDependency(dependency) => std::slice::from_ref(dependency),
+                    Permissions::AlternativeDependency(dependencies) => dependencies.as_slice(),
+                };
+                let available = alternatives.iter().any(|dependency| {
+                    self.base_services.contains(dependency)
+                        || self.providers.contains_key(dependency)
+                });
+                (!available).then(|| alternatives.join(" or "))
+            })
+            .collect()
+    }
+
+    /// Explains where missing dependencies can be installed from
+    pub fn describe_missing(&self, missing: &[Permissions], store: Option<&str>) -> String {
+        let describe = |dependency: &String| match self
+            .resolve(std::slice::from_ref(dependency), store, &[])
+            .map(|(_, provider)| provider)
+        {
+            Some(Provider {
+                app,
+                store: Some(provider_store),
+            }) if app == dependency => format!("{dependency} (from store {provider_store})"),
+            Some(Provider {
+                app,
+                store: Some(provider_store),
+            }) => format!("{dependency} (for example {app} from store {provider_store})"),
+            Some(Provider { store: None, .. }) => dependency.clone(),
+            None => format!("{dependency} (not provided by any store)"),
+        };
+        missing
+            .iter()
+            .map(|permission| match permission {
+                Permissions::OneDependency(dependency) => describe(dependency),
+                Permissions::AlternativeDependency(dependencies) => dependencies
+                    .iter()
+                    .map(describe)
+                    .collect::<Vec<_>>()
+                    .join(" or "),
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::DependencyResolver;
+    use crate::{
+        cli::stores::AppStoreInfo,
+        composegenerator::types::{CrossStoreDependency, Permissions, UnsupportedReason},
+    };
+    use std::collections::{BTreeMap, HashMap};
+
+    fn store(id: &str, apps: &[&str]) -> AppStoreInfo {
+        AppStoreInfo {
+            id: id.to_string(),
+            name: id.to_string(),
+            tagline: String::new(),
+            icon: String::new(),
+            developers: String::new(),
+            license: String::new(),
+            apps: apps
+                .iter()
+                .map(|app| (app.to_string(), String::new()))
+                .collect(),
+            commit: String::new(),
+            repo: String::new(),
+            branch: String::new(),
+            subdir: String::new(),
+            bundles: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn resolves_dependencies_across_stores() {
+        let stores = vec![
+            store("citadel", &["lnd", "btc-rpc-explorer"]),
+            store("community", &["electrs", "fulcrum"]),
+        ];
+        let apps: HashMap<String, Option<String>> = HashMap::from([
+            ("lnd".to_string(), None),
+            ("btc-rpc-explorer".to_string(), None),
+            ("electrs".to_string(), Some("electrum".to_string())),
+            ("fulcrum".to_string(), Some("electrum".to_string())),
+        ]);
+        let resolver = DependencyResolver::new(
+            apps.iter()
+                .map(|(id, implements)| (id, implements.as_ref())),
+            &stores,
+            &["community".to_string()],
+            &["bitcoind".to_string()],
+        );
+        let permissions = vec![
+            Permissions::OneDependency("lnd".to_string()),
+            Permissions::OneDependency("electrum".to_string()),
+        ];
+        let installed = vec!["fulcrum".to_string()];
+        assert_eq!(
+            resolver.cross_store_dependencies(&permissions, Some("citadel"), &installed),
+            vec![CrossStoreDependency {
+                dependency: "electrum".to_string(),
+                app: "fulcrum".to_string(),
+                store: "community".to_string(),
+                trusted: true,
+                installed: true,
+            }]
+        );
+        assert_eq!(
+            resolver.describe_missing(
+                &[
+                    Permissions::OneDependency("lnd".to_string()),
+                    Permissions::OneDependency("electrum".to_string()),
+                    Permissions::OneDependency("nostr-relay".to_string())
+                ],
+                Some("citadel")
+            ),
+            "lnd (from store citadel), electrum (for example electrs from store community), nostr-relay (not provided by any store)"
+        );
+
+        // A remote electrum server provided by the node is used instead of an app
+        let resolver = DependencyResolver::new(
+            apps.iter()
+                .map(|(id, implements)| (id, implements.as_ref())),
+            &stores,
+            &["community".to_string()],
+            &["electrum".to_string()],
+        );
+        assert!(resolver
+            .cross_store_dependencies(&permissions, Some("citadel"), &installed)
+            .is_empty());
+    }
+
+    #[test]
+    fn finds_unavailable_dependencies() {
+        let apps: HashMap<String, Option<String>> = HashMap::from([
+            ("lnd".to_string(), None),
+            ("electrs".to_string(), Some("electrum".to_string())),
+        ]);
+        let resolver = DependencyResolver::new(
+            apps.iter()
+                .map(|(id, implements)| (id, implements.as_ref())),
+            &[],
+            &[],
+            &["bitcoind".to_string()],
+        );
+        let unavailable = resolver.unavailable(&[
+            Permissions::OneDependency("bitcoind".to_string()),
+            Permissions::OneDependency("electrum".to_string()),
+            Permissions::OneDependency("nostr-relay".to_string()),
+            Permissions::AlternativeDependency(vec!["lnd".to_string(), "c-lightning".to_string()]),
+            Permissions::AlternativeDependency(vec![
+                "c-lightning".to_string(),
+                "eclair".to_string(),
+            ]),
+        ]);
+        assert_eq!(unavailable, vec!["nostr-relay", "c-lightning or eclair"]);
+        // As recorded in unsupported.json
+        assert_eq!(
+            serde_json::to_value(UnsupportedReason::MissingDependency {
+                dependency: unavailable[0].clone()
+            })
+            .unwrap(),
+            serde_json::json!({ "reason": "missingDependency", "dependency": "nostr-relay" })
+        );
+    }
+}
@@ -1,12 +1,38 @@
+use std::collections::{BTreeMap, HashMap};
 use std::io::{Read, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-use super::tera::convert_app_yml_for_update;
+use super::app_filter::AppFilter;
+use super::features::FeatureFlags;
+use super::host_facts::HostFacts;
+use super::limits::ConversionLimits;
+use super::template_env::EnvScope;
+use super::tera::{
+    convert_app_yml, convert_app_yml_for_update, render_app_config_files, shared_context,
+    write_rendered_files, TemplateVars,
+};
+use crate::composegenerator::output::types::ComposeSpecification;
+use crate::composegenerator::v4::deprecations::{
+    find_deprecations, find_line, fix_deprecations, Deprecation,
+};
+use crate::composegenerator::v4::diagnostics::{self, Diagnostic, Severity};
 use crate::composegenerator::v4::types::AppYml as AppYmlV4;
-use crate::composegenerator::AppYmlFile;
+use crate::composegenerator::{
+    convert_config, load_config_with_unknown_fields, load_definition_file, AppYmlFile,
+};
 use crate::{composegenerator::load_config, updates::update_app};
 
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// Names Windows does not allow for files, regardless of the extension
+const WINDOWS_RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Used if a render environment has no seed, so rendered files are the same on every run
+const TEST_SEED: &str = "0000000000000000000000000000000000000000000000000000000000000000";
 
 async fn update_app_yml(path: &Path, include_prerelease: &Option<bool>) -> Result<()> {
     let app_yml = std::fs::File::open(path)?;
@@ -29,13 +55,17 @@ async fn update_app_yml(path: &Path, include_prerelease: &Option<bool>) -> Resul
 }
 
 async fn update_app_yml_jinja(path: &Path, include_prerelease: &Option<bool>) -> Result<()> {
-    let app_id = path
+    // Relative paths like app.yml.jinja have no usable parent, so resolve the path first
+    let app_file = path
+        .canonicalize()
+        .with_context(|| format!("Failed to resolve {}", path.display()))?;
+    let Some(app_id) = app_file
         .parent()
-        .unwrap()
-        .file_name()
-        .unwrap()
-        .to_str()
-        .unwrap();
+        .and_then(|dir| dir.file_name())
+        .and_then(|name| name.to_str())
+    else {
+        bail!("Failed to determine the app ID of {}", path.display());
+    };
     let mut app_yml = convert_app_yml_for_update(path, app_id)?;
     let app_definition: AppYmlV4 = serde_yaml::from_str(&app_yml)?;
     let original_version = app_definition.metadata.version.clone();
@@ -73,3 +103,655 @@ pub async fn update_app_file(path: &Path, include_prerelease: &Option<bool>) ->
         }
     }
 }
+
+/// Checks an app directory for files that cause problems when working on the app
+/// on macOS or Windows, or when it was edited there and is then used on Linux.
+pub fn portability_warnings(app_dir: &Path) -> Result<Vec<String>> {
+    let mut warnings = Vec::new();
+    check_dir_portability(app_dir, app_dir, &mut warnings)?;
+    Ok(warnings)
+}
+
+fn check_dir_portability(root: &Path, dir: &Path, warnings: &mut Vec<String>) -> Result<()> {
+    let mut seen: HashMap<String, String> = HashMap::new();
+    let mut entries = std::fs::read_dir(dir)
+        .with_context(|| format!("Failed to read {}", dir.display()))?
+        .collect::<std::io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let path = entry.path();
+        let relative = path
+            .strip_prefix(root)
+            .unwrap_or(&path)
+            .display()
+            .to_string();
+        let Some(name) = entry.file_name().to_str().map(str::to_string) else {
+            warnings.push(format!("{relative} is not valid UTF-8"));
+            continue;
+        };
+        if let Some(other) = seen.insert(name.to_lowercase(), name.clone()) {
+            warnings.push(format!(
+                "{relative} and {other} only differ in case, only one of them can exist on macOS and Windows"
+            ));
+        }
+        let stem = name.split('.').next().unwrap_or_default().to_uppercase();
+        if name.contains([':', '*', '?', '"', '<', '>', '|', '\\'])
+            || name.ends_with(['.', ' '])
+            || WINDOWS_RESERVED_NAMES.contains(&stem.as_str())
+        {
+            warnings.push(format!("{relative} is not a valid file name on Windows"));
+        }
+        let file_type = entry.file_type()?;
+        if file_type.is_symlink() {
+            warnings.push(format!(
+                "{relative} is a symlink, which may not be checked out correctly on Windows"
+            ));
+        } else if file_type.is_dir() {
+            check_dir_portability(root, &path, warnings)?;
+        }
+    }
+    Ok(())
+}
+
+/// Records where `roundtripped` differs from `original`, new null values are ignored
+fn diff_values(
+    path: &str,
+    original: &serde_yaml::Value,
+    roundtripped: &serde_yaml::Value,
+    differences: &mut Vec<String>,
+) {
+    use serde_yaml::Value;
+    let child_path = |key: &str| {
+        if path.is_empty() {
+            key.to_string()
+        } else {
+            format!("{path}.{key}")
+        }
+    };
+    match (original, roundtripped) {
+        (Value::Mapping(original), Value::Mapping(roundtripped)) => {
+            for (key, value) in original {
+                let key_str = serde_yaml::to_string(key).unwrap_or_default();
+                let key_path = child_path(key_str.trim());
+                match roundtripped.get(key) {
+                    Some(other) => diff_values(&key_path, value, other, differences),
+                    None => differences.push(format!("{key_path} was dropped")),
+                }
+            }
+            for (key, value) in roundtripped {
+                if !original.contains_key(key) && !value.is_null() {
+                    let key_str = serde_yaml::to_string(key).unwrap_or_default();
+                    differences.push(format!("{} was added", child_path(key_str.trim())));
+                }
+            }
+        }
+        (Value::Sequence(original), Value::Sequence(roundtripped))
+            if original.len() == roundtripped.len() =>
+        {
+            for (i, (value, other)) in original.iter().zip(roundtripped).enumerate() {
+                diff_values(&child_path(&i.to_string()), value, other, differences);
+            }
+        }
+        (original, roundtripped) if original != roundtripped => {
+            let show = |value: &Value| {
+                serde_yaml::to_string(value)
+                    .unwrap_or_default()
+                    .trim()
+                    .replace('\n', " ")
+            };
+            differences.push(format!(
+                "{path} changed from {} to {}",
+                show(original),
+                show(roundtripped)
+            ));
+        }
+        _ => {}
+    }
+}
+
+/// Parses an app.yml into the typed structs, serializes it again and returns how the result
+/// differs from the original, to find fields the types silently drop or change.
+/// `app` can be an app.yml or an app directory.
+pub fn roundtrip(app: &Path) -> Result<Vec<String>> {
+    let app_yml = if app.is_dir() {
+        app.join("app.yml")
+    } else {
+        app.to_path_buf()
+    };
+    let contents =
+        std::fs::read(&app_yml).with_context(|| format!("Failed to read {}", app_yml.display()))?;
+    let original: serde_yaml::Value = serde_yaml::from_slice(&contents)?;
+    let (parsed, _) = load_config_with_unknown_fields(contents.as_slice())?;
+    let roundtripped = match parsed {
+        AppYmlFile::V3(app_yml) => serde_yaml::to_value(app_yml)?,
+        AppYmlFile::V4(app_yml) => serde_yaml::to_value(app_yml)?,
+    };
+    let mut differences = Vec::new();
+    diff_values("", &original, &roundtripped, &mut differences);
+    Ok(differences)
+}
+
+/// Validates an app.yml and reports all problems found, with the field and line they are about.
+/// `app` can be an app.yml or an app directory, the app ID defaults to the name of the directory.
+/// Returns the app.yml that was checked and the problems, errors first.
+pub fn validate(app: &Path, app_id: Option<&str>) -> Result<(PathBuf, Vec<Diagnostic>)> {
+    let app_yml = if app.is_dir() {
+        app.join("app.yml")
+    } else {
+        app.to_path_buf()
+    };
+    let contents = std::fs::read_to_string(&app_yml)
+        .with_context(|| format!("Failed to read {}", app_yml.display()))?;
+    let app_id = match app_id {
+        Some(app_id) => app_id.to_string(),
+        None => app_yml
+            .canonicalize()?
+            .parent()
+            .and_then(|dir| dir.file_name())
+            .map(|name| name.to_string_lossy().to_string())
+            .context("Failed to determine the app ID, please pass it explicitly")?,
+    };
+    let definition = match load_definition_file(&app_yml, Some(&["bitcoind".to_string()])) {
+        Ok(definition) => definition,
+        Err(err) => return Ok((app_yml, vec![Diagnostic::error("", format!("{err:#}"))])),
+    };
+    let mut diagnostics = diagnostics::check(&definition, &contents);
+    if let Ok((_, unknown_fields)) = load_config_with_unknown_fields(contents.as_bytes()) {
+        for field in unknown_fields {
+            let mut diagnostic = Diagnostic::warning(
+                field.as_str(),
+                "Unknown field, ignored by this version of the app manager",
+            );
+            diagnostic.line = find_line(&contents, &field);
+            diagnostics.push(diagnostic);
+        }
+    }
+    if diagnostics
+        .iter()
+        .all(|diagnostic| diagnostic.severity != Severity::Error)
+    {
+        // Catches everything the semantic checks don't know about yet
+        if let Err(err) = convert_config(&app_id, contents.as_bytes(), None, None, None) {
+            diagnostics.push(Diagnostic::error("", format!("{err:#}")));
+        }
+    }
+    diagnostics.sort_by_key(|diagnostic| diagnostic.severity);
+    Ok((app_yml, diagnostics))
+}
+
+/// Collects the app.yml files in `path`, which can be an app.yml, an app directory
+/// or a directory containing app directories, like an app store
+fn find_app_ymls(path: &Path) -> Result<Vec<PathBuf>> {
+    if !path.is_dir() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+    if path.join("app.yml").is_file() {
+        return Ok(vec![path.join("app.yml")]);
+    }
+    let mut app_ymls = Vec::new();
+    let entries =
+        std::fs::read_dir(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    for entry in entries {
+        let app_yml = entry?.path().join("app.yml");
+        if app_yml.is_file() {
+            app_ymls.push(app_yml);
+        }
+    }
+    app_ymls.sort();
+    Ok(app_ymls)
+}
+
+/// Finds deprecated fields in app.yml files and, if `fix` is set, replaces them in place.
+/// Comments and formatting are kept, and a fix is only written if the app still parses to the same definition.
+/// Returns the deprecated fields of every file that has any.
+/// Apps are identified by their directory name for `filter`.
+pub fn lint(
+    paths: &[String],
+    fix: bool,
+    filter: &AppFilter,
+) -> Result<BTreeMap<PathBuf, Vec<Deprecation>>> {
+    if !filter.store.is_empty() || filter.installed_only {
+        bail!("--store and --installed-only can only be used with a Citadel root");
+    }
+    let mut results = BTreeMap::new();
+    for path in paths {
+        for app_yml in find_app_ymls(Path::new(path))? {
+            let contents = std::fs::read_to_string(&app_yml)
+                .with_context(|| format!("Failed to read {}", app_yml.display()))?;
+            if !filter.is_empty() {
+                let app_id = app_yml
+                    .parent()
+                    .and_then(|dir| dir.file_name())
+                    .map(|name| name.to_string_lossy().to_string())
+                    .unwrap_or_default();
+                let category = serde_yaml::from_str::<serde_yaml::Value>(&contents)
+                    .ok()
+                    .and_then(|app| app["metadata"]["category"].as_str().map(str::to_string))
+                    .unwrap_or_default();
+                if !filter.matches(&app_id, &category, None, true) {
+                    continue;
+                }
+            }
+            let (fixed, deprecations) = fix_deprecations(&contents);
+            if deprecations.is_empty() {
+                continue;
+            }
+            if fix {
+                if let Ok(original) = serde_yaml::from_str::<AppYmlV4>(&contents) {
+                    if serde_yaml::from_str::<AppYmlV4>(&fixed).ok() != Some(original) {
+                        bail!(
+                            "Fixing {} would change the app definition, please migrate it manually",
+                            app_yml.display()
+                        );
+                    }
+                }
+                std::fs::write(&app_yml, fixed)
+                    .with_context(|| format!("Failed to write {}", app_yml.display()))?;
+            }
+            results.insert(app_yml, deprecations);
+        }
+    }
+    Ok(results)
+}
+
+/// A simulated node to render the config templates of an app against, loaded from YAML
+#[derive(Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(default, rename_all = "camelCase")]
+pub struct RenderEnvironment {
+    /// Env vars of the node, like in the .env file of a Citadel root
+    pub env: HashMap<String, String>,
+    /// Installed apps and services, bitcoind is always installed
+    pub installed_services: Vec<String>,
+    /// The Citadel seed, a fixed test seed is used if it is not set
+    pub seed: Option<String>,
+    /// Feature flags set by the operator, like in app-manager.toml
+    pub features: FeatureFlags,
+    /// Hardware of the node, like in db/host-facts.json
+    pub host: HostFacts,
+}
+
+impl RenderEnvironment {
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = std::fs::File::open(path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        serde_yaml::from_reader(file).with_context(|| format!("Failed to parse {}", path.display()))
+    }
+}
+
+/// Renders app.yml.jinja and all other Jinja templates of an app into `output_dir`.
+/// Templates using gen_seed or tor_hash produce different output on every run.
+pub fn render_config(
+    app_dir: &Path,
+    environment: &RenderEnvironment,
+    output_dir: &Path,
+) -> Result<()> {
+    if output_dir.exists() {
+        std::fs::remove_dir_all(output_dir)
+            .with_context(|| format!("Failed to remove {}", output_dir.display()))?;
+    }
+    std::fs::create_dir_all(output_dir)
+        .with_context(|| format!("Failed to create {}", output_dir.display()))?;
+    let mut services = environment.installed_services.clone();
+    services.push("bitcoind".to_string());
+    let seed = Some(
+        environment
+            .seed
+            .clone()
+            .unwrap_or_else(|| TEST_SEED.to_string()),
+    );
+    let limits = ConversionLimits::default();
+    let scope = EnvScope::default();
+    let shared_context = shared_context(&services, &environment.features, &environment.host);
+    let env_vars = TemplateVars {
+        node: Default::default(),
+        app: environment.env.clone(),
+    };
+    convert_app_yml(
+        app_dir,
+        output_dir,
+        &shared_context,
+        &env_vars,
+        &scope,
+        &seed,
+        &limits,
+    )?;
+    // No hidden services or I2P destinations exist yet, so their addresses are placeholders
+    let i2p_dir = output_dir.join(".i2p");
+    write_rendered_files(render_app_config_files(
+        app_dir,
+        output_dir,
+        &services,
+        &shared_context,
+        &seed,
+        Some(&env_vars),
+        &scope,
+        &None,
+        &i2p_dir,
+        &limits,
+    )?)
+}
+
+fn list_files(dir: &Path) -> Result<Vec<String>> {
+    let mut files = Vec::new();
+    let entries =
+        std::fs::read_dir(dir).with_context(|| format!("Failed to read {}", dir.display()))?;
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            files.push(entry.file_name().to_string_lossy().to_string());
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Compares rendered files to the expected files committed by the app developer.
+/// Returns a description of every difference.
+pub fn check_rendered(output_dir: &Path, expected_dir: &Path) -> Result<Vec<String>> {
+    let rendered = list_files(output_dir)?;
+    let expected = list_files(expected_dir)?;
+    let mut differences = Vec::new();
+    for file in &expected {
+        if !rendered.contains(file) {
+            differences.push(format!("{file} was not rendered"));
+            continue;
+        }
+        let expected_content = std::fs::read_to_string(expected_dir.join(file))?;
+        let rendered_content = std::fs::read_to_string(output_dir.join(file))?;
+        if expected_content != rendered_content {
+            let line = expected_content
+                .lines()
+                .zip(rendered_content.lines())
+                .position(|(expected, rendered)| expected != rendered)
+                .unwrap_or_else(|| {
+                    expected_content
+                        .lines()
+                        .count()
+                        .min(rendered_content.lines().count())
+                });
+            differences.push(format!("{file} differs from line {}", line + 1));
+        }
+    }
+    for file in rendered.iter().filter(|file| !expected.contains(file)) {
+        differences.push(format!("{file} has no expected output"));
+    }
+    Ok(differences)
+}
+
+/// The result of one check of `ci_check`
+#[derive(Serialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct CiCheckResult {
+    pub name: &'static str,
+    pub passed: bool,
+    /// Problems that make the check fail
+    pub errors: Vec<String>,
+    /// Problems that should be looked at, but don't fail the check
+    pub warnings: Vec<String>,
+}
+
+impl CiCheckResult {
+    fn new(name: &'static str, errors: Vec<String>, warnings: Vec<String>) -> Self {
+        Self {
+            name,
+            passed: errors.is_empty(),
+            errors,
+            warnings,
+        }
+    }
+}
+
+/// The verdict of `ci_check` for an app submitted to a store
+#[derive(Serialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct CiReport {
+    pub app: String,
+    pub passed: bool,
+    pub checks: Vec<CiCheckResult>,
+}
+
+/// Checks the converted compose file against what app stores accept from community apps
+fn security_policy_problems(spec: &ComposeSpecification) -> (Vec<String>, Vec<String>) {
+    let mut errors = Vec::new();
+    let mut warnings = Vec::new();
+    for (name, service) in spec.services.iter().flatten() {
+        if service.network_mode.as_deref() == Some("host") {
+            errors.push(format!("Container {name} uses the network of the host"));
+        }
+        for capability in service.cap_add.iter().flatten() {
+            errors.push(format!("Container {name} adds the capability {capability}"));
+        }
+        for option in &service.security_opt {
+            if option.ends_with("unconfined") {
+                errors.push(format!("Container {name} disables {option}"));
+            }
+        }
+        for volume in &service.volumes {
+            if volume.contains("docker.sock") {
+                errors.push(format!("Container {name} mounts the Docker socket"));
+            }
+        }
+        if service
+            .image
+            .as_ref()
+            .is_some_and(|image| !image.contains("@sha256:"))
+        {
+            warnings.push(format!("The image of {name} is not pinned to a digest"));
+        }
+        if matches!(
+            service.user.as_deref(),
+            Some("0" | "root" | "0:0" | "root:root")
+        ) {
+            warnings.push(format!("Container {name} explicitly runs as root"));
+        }
+    }
+    (errors, warnings)
+}
+
+/// Runs all checks an app store needs on a submitted app directory: the app.yml schema,
+/// deprecated fields, rendering the templates, converting to a compose file and the security policy.
+/// Failing checks are part of the report, only problems reading the app directory are errors.
+pub fn ci_check(app_dir: &Path) -> Result<CiReport> {
+    let app_dir = app_dir
+        .canonicalize()
+        .with_context(|| format!("Failed to resolve {}", app_dir.display()))?;
+    let Some(app_id) = app_dir.file_name().and_then(|name| name.to_str()) else {
+        bail!("Failed to determine the app ID of {}", app_dir.display());
+    };
+    let mut checks = Vec::new();
+
+    checks.push(CiCheckResult::new(
+        "files",
+        Vec::new(),
+        portability_warnings(&app_dir)?,
+    ));
+
+    let output = tempdir::TempDir::new("ci-check")?;
+    let rendered = render_config(&app_dir, &RenderEnvironment::default(), output.path());
+    checks.push(CiCheckResult::new(
+        "templates",
+        rendered
+            .err()
+            .map(|err| vec![format!("{err:#}")])
+            .unwrap_or_default(),
+        Vec::new(),
+    ));
+
+    // Apps without app.yml.jinja are used as they are
+    let app_yml = if app_dir.join("app.yml.jinja").exists() {
+        output.path().join("app.yml")
+    } else {
+        app_dir.join("app.yml")
+    };
+    let Ok(contents) = std::fs::read(&app_yml) else {
+        checks.push(CiCheckResult::new(
+            "schema",
+            vec![format!("{} could not be read", app_yml.display())],
+            Vec::new(),
+        ));
+        return Ok(CiReport {
+            app: app_id.to_string(),
+            passed: false,
+            checks,
+        });
+    };
+
+    match load_config_with_unknown_fields(contents.as_slice()) {
+        Ok((_, unknown_fields)) => checks.push(CiCheckResult::new(
+            "schema",
+            Vec::new(),
+            unknown_fields
+                .into_iter()
+                .map(|field| format!("{field} is not a known field and is ignored"))
+                .collect(),
+        )),
+        Err(err) => checks.push(CiCheckResult::new(
+            "schema",
+            vec![format!("{err:#}")],
+            Vec::new(),
+        )),
+    }
+
+    let deprecations = find_deprecations(&String::from_utf8_lossy(&contents));
+    checks.push(CiCheckResult::new(
+        "lint",
+        deprecations.iter().map(ToString::to_string).collect(),
+        Vec::new(),
+    ));
+
+    let services = ["bitcoind".to_string()];
+    match convert_config(app_id, contents.as_slice(), None, Some(&services), None) {
+        Ok(result) => {
+            checks.push(CiCheckResult::new("compose", Vec::new(), Vec::new()));
+            let (errors, warnings) = security_policy_problems(&result.spec);
+            checks.push(CiCheckResult::new("security", errors, warnings));
+        }
+        Err(err) => {
+            checks.push(CiCheckResult::new(
+                "compose",
+                vec![format!("{err:#}")],
+                Vec::new(),
+            ));
+            checks.push(CiCheckResult::new(
+                "security",
+                vec!["The app could not be converted to a compose file".to_string()],
+                Vec::new(),
+            ));
+        }
+    }
+
+    Ok(CiReport {
+        app: app_id.to_string(),
+        passed: checks.iter().all(|check| check.passed),
+        checks,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check_rendered, ci_check, diff_values, portability_warnings};
+
+    #[test]
+    fn finds_unportable_files() {
+        let dir = std::env::temp_dir().join(format!("citadel-portability-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("data")).unwrap();
+        std::fs::write(dir.join("app.yml"), "").unwrap();
+        std::fs::write(dir.join("data").join("aux.conf"), "").unwrap();
+        std::fs::write(dir.join("data").join("config?.json"), "").unwrap();
+        let warnings = portability_warnings(&dir).unwrap();
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings
+            .iter()
+            .all(|warning| warning.contains("not a valid file name on Windows")));
+    }
+
+    #[test]
+    fn finds_roundtrip_differences() {
+        let original: serde_yaml::Value =
+            serde_yaml::from_str("metadata:\n  name: Example\n  foo: bar\nports: [80]\n").unwrap();
+        let roundtripped: serde_yaml::Value = serde_yaml::from_str(
+            "metadata:\n  name: Example\n  tagline: null\nports: ['80']\nversion: 4\n",
+        )
+        .unwrap();
+        let mut differences = Vec::new();
+        diff_values("", &original, &roundtripped, &mut differences);
+        assert_eq!(
+            differences,
+            vec![
+                "metadata.foo was dropped",
+                "ports.0 changed from 80 to '80'",
+                "version was added"
+            ]
+        );
+    }
+
+    #[test]
+    fn compares_rendered_files() {
+        let dir = tempdir::TempDir::new("render-config").unwrap();
+        let rendered = dir.path().join("rendered");
+        let expected = dir.path().join("expected");
+        std::fs::create_dir_all(&rendered).unwrap();
+        std::fs::create_dir_all(&expected).unwrap();
+        std::fs::write(rendered.join("lnd.conf"), "alias=test\nport=9735\n").unwrap();
+        std::fs::write(expected.join("lnd.conf"), "alias=test\nport=9736\n").unwrap();
+        std::fs::write(rendered.join("app.yml"), "").unwrap();
+        std::fs::write(expected.join("tor.conf"), "").unwrap();
+        assert_eq!(
+            check_rendered(&rendered, &expected).unwrap(),
+            vec![
+                "lnd.conf differs from line 2",
+                "tor.conf was not rendered",
+                "app.yml has no expected output"
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_ci_verdict() {
+        let dir = tempdir::TempDir::new("ci-check").unwrap();
+        let app_dir = dir.path().join("example");
+        std::fs::create_dir_all(&app_dir).unwrap();
+        std::fs::write(
+            app_dir.join("app.yml"),
+            "citadel_version: 4
+metadata:
+  name: Example
+  version: 1.0.0
+  category: Example
+  tagline: Example
+  developers:
+    Citadel: https://runcitadel.space
+  permissions: []
+  repo:
+    Public: https://github.com/runcitadel/example
+  support: https://t.me/citadeldevelopers
+  description: Example
+  main_container: main
+services:
+  main:
+    image: example:1.0.0
+    port: 3000
+    network_mode: host
+",
+        )
+        .unwrap();
+        let report = ci_check(&app_dir).unwrap();
+        assert_eq!(report.app, "example");
+        assert!(!report.passed);
+        let failed: Vec<&str> = report
+            .checks
+            .iter()
+            .filter(|check| !check.passed)
+            .map(|check| check.name)
+            .collect();
+        assert_eq!(failed, vec!["lint", "security"]);
+        let security = report.checks.last().unwrap();
+        assert_eq!(
+            security.errors,
+            vec!["Container main uses the network of the host"]
+        );
+        assert_eq!(
+            security.warnings,
+            vec!["The image of main is not pinned to a digest"]
+        );
+    }
+}
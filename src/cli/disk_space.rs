@@ -0,0 +1,246 @@
+use std::{collections::BTreeSet, path::Path, process::Command};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    host_facts,
+    image_registry::{ImageReference, Manifest, RegistryClient},
+    node_config::NodeConfig,
+    paths::CitadelPaths,
+    resources::format_size,
+};
+use crate::composegenerator::{ir::AppDefinition, load_definition_file_with};
+
+/// The disk space check before installing or updating apps, in app-manager.toml
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct DiskSpaceConfig {
+    /// How much space has to stay free on the data partition, in MiB
+    pub min_free: u64,
+    /// Only warn instead of refusing when there is not enough space
+    pub warn_only: bool,
+}
+
+impl Default for DiskSpaceConfig {
+    fn default() -> Self {
+        Self {
+            min_free: 2048,
+            warn_only: false,
+        }
+    }
+}
+
+/// The estimated disk space an app needs, in bytes
+#[derive(Serialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct AppEstimate {
+    pub app: String,
+    /// The compressed size of the app's images, they need more space once extracted
+    pub images: u64,
+    /// The space the app declares for its data
+    pub data: u64,
+    /// Images whose size could not be determined
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub unknown_images: Vec<String>,
+}
+
+impl AppEstimate {
+    pub fn total(&self) -> u64 {
+        self.images + self.data
+    }
+}
+
+/// The result of the disk space check
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Preflight {
+    /// Free space on the data partition, in bytes
+    pub free: u64,
+    /// Space that has to stay free, in bytes
+    pub min_free: u64,
+    /// Installing the apps anyway is allowed
+    pub warn_only: bool,
+    pub apps: Vec<AppEstimate>,
+}
+
+impl Preflight {
+    pub fn required(&self) -> u64 {
+        self.apps.iter().map(AppEstimate::total).sum()
+    }
+
+    /// Whether the free space stays above the threshold after installing the apps
+    pub fn fits(&self) -> bool {
+        self.free
+            .checked_sub(self.required())
+            .is_some_and(|left| left >= self.min_free)
+    }
+}
+
+fn manifest_size(manifest: &Manifest) -> u64 {
+    manifest.config.as_ref().map_or(0, |config| config.size)
+        + manifest.layers.iter().map(|layer| layer.size).sum::<u64>()
+}
+
+/// The download size of an image for the given architecture
+fn image_size(client: &RegistryClient, image: &str, architecture: &str) -> Result<u64> {
+    let image = ImageReference::parse(image);
+    let mut manifest = client.manifest(&image, &image.reference)?;
+    if !manifest.manifests.is_empty() {
+        let Some(entry) = manifest.manifests.iter().find(|entry| {
+            entry
+                .platform
+                .as_ref()
+                .is_some_and(|platform| platform.architecture == architecture)
+        }) else {
+            bail!("The image is not available for {}", architecture);
+        };
+        manifest = client.manifest(&image, &entry.digest)?;
+    }
+    Ok(manifest_size(&manifest))
+}
+
+fn estimate_app(
+    client: &RegistryClient,
+    app_id: &str,
+    app_yml: &AppDefinition,
+    architecture: &str,
+) -> AppEstimate {
+    let mut estimate = AppEstimate {
+        app: app_id.to_string(),
+        data: app_yml.metadata.required_storage.unwrap_or_default() * 1024 * 1024,
+        ..Default::default()
+    };
+    let images: BTreeSet<&String> = app_yml
+        .services
+        .values()
+        .map(|service| &service.image)
+        .collect();
+    for image in images {
+        match image_size(client, image, architecture) {
+            Ok(size) => estimate.images += size,
+            Err(err) => {
+                tracing::warn!("Failed to get the size of {}: {:#}", image, err);
+                estimate.unknown_images.push(image.clone());
+            }
+        }
+    }
+    estimate
+}
+
+/// Parses the output of `df --output=avail`
+fn parse_df(output: &str) -> Option<u64> {
+    output.lines().nth(1)?.trim().parse().ok()
+}
+
+/// The free space on the partition of `path`, in bytes
+fn free_space(path: &Path) -> Result<u64> {
+    let output = Command::new("df")
+        .args(["--output=avail", "-B1"])
+        .arg(path)
+        .output()
+        .context("Failed to run df")?;
+    if !output.status.success() {
+        bail!(
+            "df failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    parse_df(&String::from_utf8_lossy(&output.stdout))
+        .with_context(|| format!("Failed to get the free space of {}", path.display()))
+}
+
+/// Estimates the disk space the given apps need and compares it to the free space on the data partition
+pub fn preflight(
+    citadel_root: &str,
+    state_dir: &Option<String>,
+    apps: &[String],
+) -> Result<Preflight> {
+    let citadel_root = Path::new(citadel_root);
+    let paths = CitadelPaths::new(citadel_root, state_dir.as_deref().map(Path::new));
+    let node_config = NodeConfig::load(&paths, citadel_root)?;
+    let architecture = match &node_config.image_checks.architecture {
+        Some(architecture) => architecture.clone(),
+        None => host_facts::load(&paths, citadel_root)?.architecture,
+    };
+    let client = RegistryClient::new(&node_config.registries)?;
+    let mut estimates = Vec::new();
+    for app_id in apps {
+        let app_yml_path = citadel_root.join("apps").join(app_id).join("app.yml");
+        let app_yml = load_definition_file_with(&app_yml_path, None, &|path| paths.read_path(path))
+            .with_context(|| format!("Failed to load app.yml of {app_id}"))?;
+        estimates.push(estimate_app(&client, app_id, &app_yml, &architecture));
+    }
+    let data_dir = citadel_root.join("app-data");
+    let data_dir = if data_dir.is_dir() {
+        data_dir.as_path()
+    } else {
+        citadel_root
+    };
+    Ok(Preflight {
+        free: free_space(data_dir)?,
+        min_free: node_config.disk_space.min_free * 1024 * 1024,
+        warn_only: node_config.disk_space.warn_only,
+        apps: estimates,
+    })
+}
+
+/// Formats the estimates of all apps as a table
+pub fn format_report(preflight: &Preflight) -> String {
+    let mut report = format!(
+        "{:<24} {:>12} {:>12} {:>12}\n",
+        "APP", "IMAGES", "DATA", "TOTAL"
+    );
+    for app in &preflight.apps {
+        report.push_str(&format!(
+            "{:<24} {:>12} {:>12} {:>12}\n",
+            app.app,
+            format_size(app.images),
+            format_size(app.data),
+            format_size(app.total())
+        ));
+        for image in &app.unknown_images {
+            report.push_str(&format!("  size of {image} unknown\n"));
+        }
+    }
+    report.push_str(&format!(
+        "Required: {}, free: {}, has to stay free: {}\n",
+        format_size(preflight.required()),
+        format_size(preflight.free),
+        format_size(preflight.min_free)
+    ));
+    report
+}
+
+#[cfg(test)]
+mod test {
+    use super::{manifest_size, parse_df, AppEstimate, Preflight};
+
+    #[test]
+    fn estimates_disk_space() {
+        assert_eq!(parse_df("   Avail\n1073741824\n"), Some(1073741824));
+        let manifest = serde_json::from_str(
+            r#"{
+                "config": {"digest": "sha256:c", "size": 100},
+                "layers": [{"digest": "sha256:1", "size": 1000}, {"digest": "sha256:2", "size": 24}]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(manifest_size(&manifest), 1124);
+
+        let mut preflight = Preflight {
+            free: 10_000,
+            min_free: 2_000,
+            warn_only: false,
+            apps: vec![AppEstimate {
+                app: "lnd".to_string(),
+                images: 3_000,
+                data: 5_000,
+                ..Default::default()
+            }],
+        };
+        assert!(preflight.fits());
+        preflight.apps[0].data = 6_000;
+        assert!(!preflight.fits());
+    }
+}
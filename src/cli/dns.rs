@@ -0,0 +1,62 @@
+use std::net::IpAddr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::composegenerator::output::types::ComposeSpecification;
+
+/// The resolvers app containers use, in app-manager.toml
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct DnsConfig {
+    /// DNS servers for all app containers, like the node's dnsmasq or a DoH proxy container.
+    /// Docker's default resolver is used if this is empty.
+    pub servers: Vec<IpAddr>,
+    /// Search domains for all app containers
+    pub search: Vec<String>,
+}
+
+/// Makes all containers of an app use the configured DNS servers.
+/// Containers sharing the network of the host or another container are skipped,
+/// Docker does not allow DNS settings for them.
+pub fn apply_dns(config: &DnsConfig, spec: &mut ComposeSpecification) {
+    if config.servers.is_empty() && config.search.is_empty() {
+        return;
+    }
+    for service in spec
+        .services
+        .iter_mut()
+        .flat_map(|services| services.values_mut())
+        .filter(|service| service.network_mode.is_none())
+    {
+        service.dns = config.servers.iter().map(IpAddr::to_string).collect();
+        service.dns_search = config.search.clone();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{apply_dns, DnsConfig};
+    use crate::{
+        bmap,
+        composegenerator::output::types::{ComposeSpecification, Service},
+    };
+
+    #[test]
+    fn sets_dns_servers() {
+        let config: DnsConfig = toml::from_str(r#"servers = ["10.21.21.2"]"#).unwrap();
+        let mut spec = ComposeSpecification {
+            services: Some(bmap! {
+                "web" => Service::default(),
+                "vpn" => Service {
+                    network_mode: Some("host".to_string()),
+                    ..Default::default()
+                }
+            }),
+            ..Default::default()
+        };
+        apply_dns(&config, &mut spec);
+        let services = spec.services.unwrap();
+        assert_eq!(services["web"].dns, vec!["10.21.21.2"]);
+        assert!(services["vpn"].dns.is_empty());
+    }
+}
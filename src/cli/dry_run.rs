@@ -0,0 +1,589 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use tempdir::TempDir;
+
+use super::{
+    app_filter::AppFilter,
+    metrics::ConversionReport,
+    node_config::NodeConfig,
+    paths::{CitadelPaths, TOMBSTONES_DIR},
+    port_review::PortChangePolicy,
+    redaction::{Redactor, REDACTED},
+    Caddyfile,
+};
+
+/// Files that change on every conversion, they are not reported
+const VOLATILE_FILES: [&str; 1] = ["apps/conversion-report.json"];
+/// Unchanged lines shown around changes in a diff
+const DIFF_CONTEXT: usize = 3;
+/// Changed parts of files with more lines than this (old lines * new lines) are shown as replaced
+const MAX_DIFF_SIZE: usize = 4_000_000;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    Created,
+    Modified,
+    Removed,
+}
+
+impl std::fmt::Display for ChangeKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChangeKind::Created => write!(f, "created"),
+            ChangeKind::Modified => write!(f, "modified"),
+            ChangeKind::Removed => write!(f, "removed"),
+        }
+    }
+}
+
+/// A file a conversion would write or delete
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FileChange {
+    /// The path relative to the Citadel root
+    pub path: PathBuf,
+    pub kind: ChangeKind,
+}
+
+impl std::fmt::Display for FileChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.kind, self.path.display())
+    }
+}
+
+/// All files below `dir`, relative to it
+fn list_files(dir: &Path) -> Result<BTreeSet<PathBuf>> {
+    let mut files = BTreeSet::new();
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(current) = dirs.pop() {
+        let entries = std::fs::read_dir(&current)
+            .with_context(|| format!("Failed to read {}", current.display()))?;
+        for entry in entries {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                dirs.push(entry.path());
+            } else {
+                files.insert(entry.path().strip_prefix(dir)?.to_path_buf());
+            }
+        }
+    }
+    Ok(files)
+}
+
+fn copy_file(from: &Path, to: &Path) -> Result<()> {
+    if let Some(parent) = to.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create {}", parent.display()))?;
+    }
+    std::fs::copy(from, to).with_context(|| format!("Failed to copy {}", from.display()))?;
+    Ok(())
+}
+
+/// A temporary state directory a conversion writes to instead of the node's files
+pub(super) struct Sandbox {
+    citadel_root: PathBuf,
+    /// Where the conversion would read and write without the sandbox
+    original: CitadelPaths,
+    dir: TempDir,
+    /// Files copied from the state directory before the conversion, so their removal can be detected
+    seeded: BTreeSet<PathBuf>,
+}
+
+impl Sandbox {
+    pub(super) fn new(citadel_root: &Path, state_dir: Option<&Path>) -> Result<Self> {
+        let dir = TempDir::new("dry-run")?;
+        let original = CitadelPaths::new(citadel_root, state_dir);
+        if let Some(state_dir) = state_dir.filter(|state_dir| state_dir.is_dir()) {
+            for file in list_files(state_dir)? {
+                copy_file(&state_dir.join(&file), &dir.path().join(&file))?;
+            }
+        }
+        let seeded = list_files(dir.path())?;
+        Ok(Self {
+            citadel_root: citadel_root.to_path_buf(),
+            original,
+            dir,
+            seeded,
+        })
+    }
+
+    /// Where the conversion in the sandbox reads and writes
+    fn paths(&self) -> CitadelPaths {
+        CitadelPaths::new(&self.citadel_root, Some(self.dir.path()))
+    }
+
+    /// Compares the files in the sandbox to the ones the node currently uses
+    fn changes(&self) -> Result<Vec<FileChange>> {
+        let files = list_files(self.dir.path())?;
+        let (tombstones, files): (BTreeSet<&PathBuf>, BTreeSet<&PathBuf>) = files
+            .iter()
+            .partition(|file| file.starts_with(TOMBSTONES_DIR));
+        let mut changes = Vec::new();
+        for file in files.iter().filter(|file| {
+            !VOLATILE_FILES
+                .iter()
+                .any(|volatile| file.as_path() == Path::new(volatile))
+        }) {
+            let original = self.original.read_path(&self.citadel_root.join(file));
+            let kind = if !original.exists() {
+                ChangeKind::Created
+            } else if std::fs::read(&original)? != std::fs::read(self.dir.path().join(file))? {
+                ChangeKind::Modified
+            } else {
+                continue;
+            };
+            changes.push(FileChange {
+                path: file.to_path_buf(),
+                kind,
+            });
+        }
+        // Removed files are either missing from the sandbox or have a tombstone there
+        let removed: BTreeSet<PathBuf> = self
+            .seeded
+            .iter()
+            .filter(|file| !files.contains(file))
+            .cloned()
+            .chain(tombstones.into_iter().filter_map(|tombstone| {
+                let removed = tombstone.strip_prefix(TOMBSTONES_DIR).ok()?;
+                Some(
+                    removed
+                        .with_file_name(removed.file_name()?.to_str()?.strip_suffix(".deleted")?),
+                )
+            }))
+            .collect();
+        let paths = self.paths();
+        for file in removed {
+            let path = self.citadel_root.join(&file);
+            if self.original.exists(&path) && !paths.exists(&path) {
+                changes.push(FileChange {
+                    path: file,
+                    kind: ChangeKind::Removed,
+                });
+            }
+        }
+        changes.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(changes)
+    }
+
+    /// Converts all apps, all files are written to the sandbox
+    pub(super) fn convert(
+        &self,
+        port_changes: PortChangePolicy,
+        filter: &AppFilter,
+        single_app: Option<&str>,
+        dry_run: bool,
+    ) -> Result<(ConversionReport, Caddyfile)> {
+        super::convert_files(
+            &self.citadel_root.to_string_lossy(),
+            &Some(self.dir.path().to_string_lossy().to_string()),
+            port_changes,
+            filter,
+            single_app,
+            dry_run,
+        )
+    }
+
+    /// Writes the files the conversion changed to where the node reads them from
+    pub(super) fn apply(&self) -> Result<()> {
+        let volatile = VOLATILE_FILES
+            .iter()
+            .map(PathBuf::from)
+            .filter(|file| self.dir.path().join(file).exists())
+            .map(|path| FileChange {
+                path,
+                kind: ChangeKind::Modified,
+            });
+        for change in self.changes()?.into_iter().chain(volatile) {
+            let target = self.citadel_root.join(&change.path);
+            match change.kind {
+                ChangeKind::Removed if self.original.read_path(&target).is_dir() => {
+                    self.original.remove_dir_all(&target)?
+                }
+                ChangeKind::Removed => self.original.remove_file(&target)?,
+                ChangeKind::Created | ChangeKind::Modified => {
+                    let source = self.dir.path().join(&change.path);
+                    let contents = std::fs::read(&source)
+                        .with_context(|| format!("Failed to read {}", source.display()))?;
+                    self.original.write(&target, contents)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The contents of a file before and after the conversion, empty if it does not exist
+    fn contents(&self, change: &FileChange) -> Result<(Vec<u8>, Vec<u8>)> {
+        let read = |path: PathBuf| -> Result<Vec<u8>> {
+            if path.exists() {
+                std::fs::read(&path).with_context(|| format!("Failed to read {}", path.display()))
+            } else {
+                Ok(Vec::new())
+            }
+        };
+        Ok((
+            read(
+                self.original
+                    .read_path(&self.citadel_root.join(&change.path)),
+            )?,
+            read(self.dir.path().join(&change.path))?,
+        ))
+    }
+}
+
+/// Compares two lists of lines, returns them marked with ' ' (unchanged), '-' or '+'
+fn diff_lines<'a>(old: &[&'a str], new: &[&'a str]) -> Vec<(char, &'a str)> {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let old_changed = &old[prefix..old.len() - suffix];
+    let new_changed = &new[prefix..new.len() - suffix];
+    let mut lines: Vec<(char, &str)> = old[..prefix].iter().map(|line| (' ', *line)).collect();
+    if old_changed.len() * new_changed.len() > MAX_DIFF_SIZE {
+        lines.extend(old_changed.iter().map(|line| ('-', *line)));
+        lines.extend(new_changed.iter().map(|line| ('+', *line)));
+    } else {
+        // Length of the longest common subsequence of old_changed[i..] and new_changed[j..]
+        let (n, m) = (old_changed.len(), new_changed.len());
+        let mut common = vec![vec![0u32; m + 1]; n + 1];
+        for i in (0..n).rev() {
+            for j in (0..m).rev() {
+                common[i][j] = if old_changed[i] == new_changed[j] {
+                    common[i + 1][j + 1] + 1
+                } else {
+                    common[i + 1][j].max(common[i][j + 1])
+                };
+            }
+        }
+        let (mut i, mut j) = (0, 0);
+        while i < n || j < m {
+            if i < n && j < m && old_changed[i] == new_changed[j] {
+                lines.push((' ', old_changed[i]));
+                i += 1;
+                j += 1;
+            } else if i < n && (j == m || common[i + 1][j] >= common[i][j + 1]) {
+                lines.push(('-', old_changed[i]));
+                i += 1;
+            } else {
+                lines.push(('+', new_changed[j]));
+                j += 1;
+            }
+        }
+    }
+    lines.extend(old[old.len() - suffix..].iter().map(|line| (' ', *line)));
+    lines
+}
+
+/// Formats the changes between two versions of a file as a unified diff
+fn unified_diff(change: &FileChange, old: &str, new: &str) -> String {
+    let path = change.path.display();
+    let old_name = match change.kind {
+        ChangeKind::Created => "/dev/null".to_string(),
+        _ => format!("a/{path}"),
+    };
+    let new_name = match change.kind {
+        ChangeKind::Removed => "/dev/null".to_string(),
+        _ => format!("b/{path}"),
+    };
+    let mut output = format!("--- {old_name}\n+++ {new_name}\n");
+    let old_lines: Vec<&str> = old.lines().collect();
+    let new_lines: Vec<&str> = new.lines().collect();
+    let lines = diff_lines(&old_lines, &new_lines);
+    // Ranges of lines shown together, changes with their context
+    let mut hunks: Vec<(usize, usize)> = Vec::new();
+    for (index, _) in lines
+        .iter()
+        .enumerate()
+        .filter(|(_, (kind, _))| *kind != ' ')
+    {
+        let start = index.saturating_sub(DIFF_CONTEXT);
+        let end = (index + DIFF_CONTEXT + 1).min(lines.len());
+        match hunks.last_mut() {
+            Some(last) if start <= last.1 => last.1 = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+    for (start, end) in hunks {
+        let count = |range: &[(char, &str)], skip: char| {
+            range.iter().filter(|(kind, _)| *kind != skip).count()
+        };
+        let (old_before, new_before) = (count(&lines[..start], '+'), count(&lines[..start], '-'));
+        let (old_count, new_count) = (
+            count(&lines[start..end], '+'),
+            count(&lines[start..end], '-'),
+        );
+        // Empty ranges start at the line before them
+        let old_start = old_before + usize::from(old_count > 0);
+        let new_start = new_before + usize::from(new_count > 0);
+        let _ = writeln!(
+            output,
+            "@@ -{old_start},{old_count} +{new_start},{new_count} @@"
+        );
+        for (kind, line) in &lines[start..end] {
+            let _ = writeln!(output, "{kind}{line}");
+        }
+    }
+    output
+}
+
+/// Masks the values of secret env vars in two versions of an env file,
+/// secrets that changed are marked so the change is still visible
+fn redact_env_files(old: &str, new: &str, redactor: &Redactor) -> (String, String) {
+    let old_values: HashMap<&str, &str> = old
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .collect();
+    let redact = |env: &str, mark_changes: bool| -> String {
+        env.lines()
+            .map(|line| match line.split_once('=') {
+                Some((key, value)) if redactor.is_secret(key.trim()) => {
+                    let changed = mark_changes && old_values.get(key) != Some(&value);
+                    format!(
+                        "{key}={}{}\n",
+                        REDACTED,
+                        if changed { " (changed)" } else { "" }
+                    )
+                }
+                _ => format!("{line}\n"),
+            })
+            .collect()
+    };
+    (redact(old, false), redact(new, true))
+}
+
+/// Runs a conversion in a sandbox
+pub(super) fn convert_in_sandbox(
+    citadel_root: &str,
+    state_dir: &Option<String>,
+    filter: &AppFilter,
+    single_app: Option<&str>,
+) -> Result<(Sandbox, ConversionReport)> {
+    let sandbox = Sandbox::new(Path::new(citadel_root), state_dir.as_deref().map(Path::new))?;
+    // Moved ports are logged instead of asking for confirmation
+    let (report, _) = sandbox.convert(PortChangePolicy::Apply, filter, single_app, true)?;
+    Ok((sandbox, report))
+}
+
+/// Runs a full conversion, including port and IP assignment, but writes nothing to the node.
+/// The router is not contacted for port forwards and AppArmor profiles are not installed.
+/// Returns the files that would be created, modified or removed.
+pub fn convert_dir(
+    citadel_root: &str,
+    state_dir: &Option<String>,
+    filter: &AppFilter,
+    single_app: Option<&str>,
+) -> Result<Vec<FileChange>> {
+    convert_in_sandbox(citadel_root, state_dir, filter, single_app)?
+        .0
+        .changes()
+}
+
+/// Runs a full conversion without writing anything to the node,
+/// and returns a unified diff of the files it would change. Secrets are masked.
+pub fn diff(
+    citadel_root: &str,
+    state_dir: &Option<String>,
+    filter: &AppFilter,
+    single_app: Option<&str>,
+) -> Result<String> {
+    let (sandbox, _) = convert_in_sandbox(citadel_root, state_dir, filter, single_app)?;
+    let changes = sandbox.changes()?;
+    let node_config = NodeConfig::load(&sandbox.original, &sandbox.citadel_root)?;
+    let mut redactor = Redactor::new(&node_config.redaction);
+    let mut contents = Vec::new();
+    for change in changes {
+        let (old, new) = sandbox.contents(&change)?;
+        let (Ok(old), Ok(new)) = (String::from_utf8(old), String::from_utf8(new)) else {
+            contents.push((change, None));
+            continue;
+        };
+        // Secrets from the .env file are also masked where templates used them
+        if change.path == Path::new(".env") {
+            for env in [&old, &new] {
+                let env: HashMap<String, String> = env
+                    .lines()
+                    .filter_map(|line| line.split_once('='))
+                    .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+                    .collect();
+                redactor.add_env(&env);
+            }
+        }
+        contents.push((change, Some((old, new))));
+    }
+    let mut output = String::new();
+    for (change, contents) in contents {
+        let Some((old, new)) = contents else {
+            let _ = writeln!(
+                output,
+                "Binary file {} {}",
+                change.path.display(),
+                change.kind
+            );
+            continue;
+        };
+        let (old, new) = if change.path == Path::new(".env") {
+            redact_env_files(&old, &new, &redactor)
+        } else {
+            (redactor.text(&old), redactor.text(&new))
+        };
+        output.push_str(&unified_diff(&change, &old, &new));
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod test {
+    #[cfg(feature = "umbrel")]
+    use super::{convert_dir, list_files};
+    use super::{redact_env_files, unified_diff, ChangeKind, FileChange, Sandbox};
+    #[cfg(feature = "umbrel")]
+    use crate::cli::app_filter::AppFilter;
+    use crate::cli::paths::CitadelPaths;
+    use crate::cli::redaction::{RedactionConfig, Redactor};
+    use std::path::PathBuf;
+
+    #[test]
+    fn detects_changed_files() {
+        let root = tempdir::TempDir::new("dry-run-root").unwrap();
+        let app_dir = root.path().join("apps").join("lnd");
+        std::fs::create_dir_all(&app_dir).unwrap();
+        std::fs::write(app_dir.join("docker-compose.yml"), "services: {}\n").unwrap();
+        std::fs::write(root.path().join(".env"), "APP_LND_IP=10.21.22.2\n").unwrap();
+        std::fs::write(root.path().join("apps").join("ips.yml"), "{}\n").unwrap();
+
+        let sandbox = Sandbox::new(root.path(), None).unwrap();
+        let paths = CitadelPaths::new(root.path(), Some(sandbox.dir.path()));
+        paths
+            .write(&root.path().join(".env"), "APP_LND_IP=10.21.22.3\n")
+            .unwrap();
+        paths
+            .write(&root.path().join("apps").join("ips.yml"), "{}\n")
+            .unwrap();
+        paths
+            .write(&root.path().join("apps").join("ports.yml"), "{}\n")
+            .unwrap();
+        paths
+            .remove_file(&app_dir.join("docker-compose.yml"))
+            .unwrap();
+        let change = |path: &str, kind| FileChange {
+            path: PathBuf::from(path),
+            kind,
+        };
+        assert_eq!(
+            sandbox.changes().unwrap(),
+            vec![
+                change(".env", ChangeKind::Modified),
+                change("apps/lnd/docker-compose.yml", ChangeKind::Removed),
+                change("apps/ports.yml", ChangeKind::Created),
+            ]
+        );
+        // Nothing was written to the Citadel root
+        assert!(app_dir.join("docker-compose.yml").exists());
+        assert!(!root.path().join("apps").join("ports.yml").exists());
+
+        sandbox.apply().unwrap();
+        assert!(!app_dir.join("docker-compose.yml").exists());
+        assert_eq!(
+            std::fs::read_to_string(root.path().join(".env")).unwrap(),
+            "APP_LND_IP=10.21.22.3\n"
+        );
+        assert!(root.path().join("apps").join("ports.yml").exists());
+    }
+
+    #[cfg(feature = "umbrel")]
+    #[test]
+    fn converts_umbrel_apps_without_writing_to_root() {
+        let root = tempdir::TempDir::new("dry-run-root").unwrap();
+        let app_dir = root.path().join("apps").join("example");
+        std::fs::create_dir_all(&app_dir).unwrap();
+        std::fs::create_dir_all(root.path().join("templates")).unwrap();
+        std::fs::write(root.path().join("templates").join("Caddyfile.jinja"), "").unwrap();
+        std::fs::write(
+            app_dir.join("umbrel-app.yml"),
+            "manifestVersion: 1
+id: example
+name: Example
+version: 1.0.0
+category: Utilities
+tagline: An example
+developer: Example
+website: https://example.com
+repo: https://example.com/repo
+support: https://example.com/support
+port: 3001
+description: An example app
+",
+        )
+        .unwrap();
+        std::fs::write(
+            app_dir.join("docker-compose.yml"),
+            "version: \"3.7\"
+services:
+  web:
+    image: example/web:1.0.0
+    restart: on-failure
+",
+        )
+        .unwrap();
+        let before = list_files(root.path()).unwrap();
+
+        let changes = convert_dir(
+            &root.path().to_string_lossy(),
+            &None,
+            &AppFilter::default(),
+            None,
+        )
+        .unwrap();
+        assert!(changes.contains(&FileChange {
+            path: PathBuf::from("apps/example/app.yml"),
+            kind: ChangeKind::Created,
+        }));
+        assert_eq!(list_files(root.path()).unwrap(), before);
+        assert!(!app_dir.join("app.yml").exists());
+    }
+
+    #[test]
+    fn formats_unified_diffs() {
+        let change = FileChange {
+            path: PathBuf::from(".env"),
+            kind: ChangeKind::Modified,
+        };
+        let (old, new) = redact_env_files(
+            "A=1\nB=2\nC=3\nD=4\nE=5\nF=6\nAPP_LND_PASSWORD=old\nG=7\n",
+            "A=1\nB=2\nC=3\nD=4\nE=5\nF=6\nAPP_LND_PASSWORD=new\nG=8\nH=9\n",
+            &Redactor::new(&RedactionConfig::default()),
+        );
+        assert_eq!(
+            unified_diff(&change, &old, &new),
+            "--- a/.env
++++ b/.env
+@@ -4,5 +4,6 @@
+ D=4
+ E=5
+ F=6
+-APP_LND_PASSWORD=<redacted>
+-G=7
++APP_LND_PASSWORD=<redacted> (changed)
++G=8
++H=9
+"
+        );
+        let created = FileChange {
+            path: PathBuf::from("apps/ports.yml"),
+            kind: ChangeKind::Created,
+        };
+        assert_eq!(
+            unified_diff(&created, "", "{}\n"),
+            "--- /dev/null\n+++ b/apps/ports.yml\n@@ -0,0 +1,1 @@\n+{}\n"
+        );
+    }
+}
@@ -0,0 +1,121 @@
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::{bail, Result};
+
+use crate::{
+    composegenerator::{ir::AppDefinition, v4::permissions::is_allowed_by_permissions},
+    naming,
+    utils::{find_env_vars, flatten},
+};
+
+/// Env vars of exports contain this after the app ID
+const EXPORT_INFIX: &str = "_EXPORT_";
+
+/// The env var apps with a permission for `app_id` read an export from, like APP_MEMPOOL_EXPORT_API_URL
+pub fn env_var(app_id: &str, name: &str) -> String {
+    format!("APP_{}{}{}", naming::env_name(app_id), EXPORT_INFIX, name)
+}
+
+fn is_export_var(key: &str) -> bool {
+    key.starts_with("APP_") && key.contains(EXPORT_INFIX)
+}
+
+/// Resolves the exports of an app, env var -> value.
+/// Values can reference the env vars the app itself may use, like $APP_MEMPOOL_API_IP.
+pub fn resolve(
+    app_id: &str,
+    app_yml: &AppDefinition,
+    env: &HashMap<String, String>,
+) -> Result<BTreeMap<String, String>> {
+    let permissions = flatten(&app_yml.metadata.permissions);
+    let mut exports = BTreeMap::new();
+    for (name, value) in &app_yml.exports {
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_')
+        {
+            bail!("Export {name} must only contain uppercase letters, digits and underscores");
+        }
+        let mut value = value.clone();
+        let env_vars: Vec<String> = find_env_vars(&value)
+            .into_iter()
+            .map(str::to_string)
+            .collect();
+        for env_var in &env_vars {
+            if !is_allowed_by_permissions(app_id, env_var, &permissions) {
+                bail!(
+                    "Export {name} uses {env_var}, which is not allowed by the app's permissions"
+                );
+            }
+            let Some(replacement) = env.get(env_var.as_str()) else {
+                bail!("Export {name} uses {env_var}, which is not set");
+            };
+            value = value
+                .replace(&format!("${{{env_var}}}"), replacement)
+                .replace(&format!("${env_var}"), replacement);
+        }
+        exports.insert(env_var(app_id, name), value);
+    }
+    Ok(exports)
+}
+
+/// Replaces all exports in the contents of the .env file, exports that no longer exist are removed
+pub fn update_env_file(env: &str, exports: &BTreeMap<String, String>) -> String {
+    let mut updated: String = env
+        .lines()
+        .filter(|line| {
+            let key = line.split('=').next().unwrap_or_default();
+            !is_export_var(key)
+        })
+        .map(|line| format!("{line}\n"))
+        .collect();
+    for (key, value) in exports {
+        updated.push_str(&format!("{key}={value}\n"));
+    }
+    updated
+}
+
+#[cfg(test)]
+mod test {
+    use super::{resolve, update_env_file};
+    use crate::composegenerator::{ir::AppDefinition, types::Permissions};
+    use std::collections::{BTreeMap, HashMap};
+
+    #[test]
+    fn resolves_exports() {
+        let mut app_yml = AppDefinition::default();
+        app_yml.metadata.permissions = vec![Permissions::OneDependency("bitcoind".to_string())];
+        app_yml.exports = BTreeMap::from([(
+            "API_URL".to_string(),
+            "http://${APP_MEMPOOL_API_IP}:8999/api".to_string(),
+        )]);
+        let env = HashMap::from([
+            ("APP_MEMPOOL_API_IP".to_string(), "10.21.22.3".to_string()),
+            ("APP_LND_SERVICE_IP".to_string(), "10.21.22.4".to_string()),
+        ]);
+        let exports = resolve("mempool", &app_yml, &env).unwrap();
+        assert_eq!(
+            exports,
+            BTreeMap::from([(
+                "APP_MEMPOOL_EXPORT_API_URL".to_string(),
+                "http://10.21.22.3:8999/api".to_string()
+            )])
+        );
+        assert_eq!(
+            update_env_file(
+                "BITCOIN_NETWORK=mainnet\nAPP_MEMPOOL_EXPORT_API_URL=old\nAPP_GONE_EXPORT_TOKEN=x\n",
+                &exports
+            ),
+            "BITCOIN_NETWORK=mainnet\nAPP_MEMPOOL_EXPORT_API_URL=http://10.21.22.3:8999/api\n"
+        );
+
+        // Values of apps the exporting app has no permission for can't be exported
+        app_yml
+            .exports
+            .insert("LND".to_string(), "$APP_LND_SERVICE_IP".to_string());
+        assert!(resolve("mempool", &app_yml, &env).is_err());
+        app_yml.exports = BTreeMap::from([("api-url".to_string(), "x".to_string())]);
+        assert!(resolve("mempool", &app_yml, &env).is_err());
+    }
+}
@@ -0,0 +1,414 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::net::IpAddr;
+
+use serde::{Deserialize, Serialize};
+
+use super::{
+    caddy_routes::{ordered_routes, CaddyRoute},
+    rate_limits::RateLimitConfig,
+};
+use crate::{
+    composegenerator::{output::types::ComposeSpecification, types::CaddyEntry},
+    naming,
+};
+
+/// Apps the operator explicitly allows to be reached from the WAN, stored in user.json
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct PublicExposure {
+    /// App ID -> how the app is exposed
+    #[serde(default)]
+    pub apps: BTreeMap<String, ExposedApp>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct ExposedApp {
+    /// The port Caddy listens on for this app
+    pub port: u16,
+    /// A domain to serve the app on, enables automatic HTTPS.
+    /// Without a domain, the app is served over plain HTTP.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub domain: Option<String>,
+    /// Maximum number of requests per minute and client IP, requires Caddy's rate_limit module
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rate_limit: Option<u32>,
+    /// Additional response headers
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+}
+
+/// Profiles for apps that should only be reachable through a VPN, stored in user.json
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct VpnExposure {
+    /// Profile name (like wireguard or tailscale) -> profile
+    #[serde(default)]
+    pub profiles: BTreeMap<String, VpnProfile>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct VpnProfile {
+    /// The node's address on the VPN interface
+    pub address: IpAddr,
+    /// Apps that are only reachable through this VPN
+    #[serde(default)]
+    pub apps: Vec<String>,
+}
+
+impl VpnExposure {
+    /// The VPN addresses an app is bound to, empty if it is reachable on all interfaces
+    pub fn bind_addresses(&self, app_id: &str) -> Vec<IpAddr> {
+        self.profiles
+            .values()
+            .filter(|profile| profile.apps.iter().any(|app| app == app_id))
+            .map(|profile| profile.address)
+            .collect()
+    }
+}
+
+/// Host addresses published ports are bound to in app-manager.toml, for nodes where apps
+/// should only be reachable through Caddy or on a specific interface
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct PortBindingConfig {
+    /// Addresses the ports of all apps are bound to, all interfaces if empty
+    pub addresses: Vec<IpAddr>,
+    /// App ID -> addresses its ports are bound to instead
+    pub apps: BTreeMap<String, Vec<IpAddr>>,
+}
+
+impl PortBindingConfig {
+    /// The addresses an app's ports are bound to, empty if they are published on all interfaces
+    pub fn bind_addresses(&self, app_id: &str) -> &[IpAddr] {
+        self.apps.get(app_id).unwrap_or(&self.addresses)
+    }
+}
+
+/// Formats an address for Docker's port syntax, which needs IPv6 addresses in brackets
+fn host_address(address: &IpAddr) -> String {
+    match address {
+        IpAddr::V4(address) => address.to_string(),
+        IpAddr::V6(address) => format!("[{address}]"),
+    }
+}
+
+/// Binds the published ports of an app to specific host addresses
+pub fn bind_ports(spec: &mut ComposeSpecification, addresses: &[IpAddr]) {
+    if addresses.is_empty() {
+        return;
+    }
+    for (_, service) in spec.services.iter_mut().flatten() {
+        service.ports = service
+            .ports
+            .iter()
+            .flat_map(|port| {
+                // Ports which are already bound to an address are kept
+                let bound = port.split('/').next().unwrap_or(port).matches(':').count() > 1;
+                let ports: Vec<String> = if bound {
+                    vec![port.clone()]
+                } else {
+                    addresses
+                        .iter()
+                        .map(|address| format!("{}:{port}", host_address(address)))
+                        .collect()
+                };
+                ports
+            })
+            .collect();
+    }
+}
+
+/// Generates Caddy site blocks for apps which are only reachable through a VPN.
+/// These apps' entries must not be passed to the Caddyfile template, which binds to all interfaces.
+pub fn generate_vpn_caddy_config(
+    vpn_exposure: &VpnExposure,
+    caddy_entries: &HashMap<String, Vec<CaddyEntry>>,
+    ip_map: &HashMap<String, String>,
+    snippets: &HashMap<String, String>,
+    limits: &RateLimitConfig,
+) -> String {
+    let mut config = String::new();
+    for CaddyRoute { app: app_id, entry } in ordered_routes(caddy_entries) {
+        let addresses = vpn_exposure.bind_addresses(app_id);
+        if addresses.is_empty() {
+            continue;
+        }
+        let addresses: Vec<String> = addresses.iter().map(IpAddr::to_string).collect();
+        let ip_var = naming::ip_env_var(app_id, &entry.container_name);
+        let Some(ip) = ip_map.get(&ip_var) else {
+            tracing::warn!(
+                "App {} can't be exposed via VPN, {} is not set",
+                app_id,
+                ip_var
+            );
+            continue;
+        };
+        let _ = writeln!(config, "http://:{} {{", entry.public_port);
+        let _ = writeln!(config, "bind {}", addresses.join(" "));
+        if let Some(snippet) = snippets.get(app_id) {
+            let _ = writeln!(config, "{}", snippet.trim_end());
+        }
+        let upstream = format!("{ip}:{}", entry.internal_port);
+        let _ = writeln!(config, "{}", limits.reverse_proxy(app_id, &upstream));
+        let _ = writeln!(config, "}}");
+    }
+    config
+}
+
+/// Generates Caddy site blocks for all publicly exposed apps.
+/// Apps that are not installed or don't have a web UI are skipped,
+/// as are apps that would listen on a port for which `port_in_use` returns true.
+pub fn generate_caddy_config(
+    exposure: &PublicExposure,
+    caddy_entries: &HashMap<String, Vec<CaddyEntry>>,
+    ip_map: &HashMap<String, String>,
+    snippets: &HashMap<String, String>,
+    limits: &RateLimitConfig,
+    port_in_use: impl Fn(u16) -> bool,
+) -> String {
+    let mut config = String::new();
+    for (app_id, exposed) in &exposure.apps {
+        let Some(entry) = caddy_entries
+            .get(app_id)
+            .and_then(|entries| entries.iter().find(|entry| entry.is_primary))
+        else {
+            tracing::warn!(
+                "App {} can't be exposed publicly, it is not installed or has no web UI",
+                app_id
+            );
+            continue;
+        };
+        if port_in_use(exposed.port) {
+            tracing::warn!(
+                "App {} can't be exposed publicly on port {}, the port is already in use",
+                app_id,
+                exposed.port
+            );
+            continue;
+        }
+        let ip_var = naming::ip_env_var(app_id, &entry.container_name);
+        let Some(ip) = ip_map.get(&ip_var) else {
+            tracing::warn!(
+                "App {} can't be exposed publicly, {} is not set",
+                app_id,
+                ip_var
+            );
+            continue;
+        };
+        let address = match &exposed.domain {
+            Some(domain) => format!("{}:{}", domain, exposed.port),
+            None => format!("http://:{}", exposed.port),
+        };
+        let mut headers = BTreeMap::from([
+            ("X-Content-Type-Options".to_string(), "nosniff".to_string()),
+            ("X-Frame-Options".to_string(), "SAMEORIGIN".to_string()),
+            (
+                "Referrer-Policy".to_string(),
+                "strict-origin-when-cross-origin".to_string(),
+            ),
+        ]);
+        if exposed.domain.is_some() {
+            headers.insert(
+                "Strict-Transport-Security".to_string(),
+                "max-age=31536000".to_string(),
+            );
+        }
+        headers.extend(exposed.headers.clone());
+
+        let _ = writeln!(config, "{address} {{");
+        let _ = writeln!(config, "header {{");
+        let _ = writeln!(config, "-Server");
+        for (name, value) in headers {
+            let _ = writeln!(config, "{name} \"{}\"", value.replace('"', "\\\""));
+        }
+        let _ = writeln!(config, "}}");
+        if let Some(snippet) = snippets.get(app_id) {
+            let _ = writeln!(config, "{}", snippet.trim_end());
+        }
+        let _ = writeln!(config, "route {{");
+        if let Some(rate_limit) = exposed.rate_limit {
+            let _ = writeln!(config, "rate_limit {{");
+            let _ = writeln!(
+                config,
+                "zone public_{} {{",
+                naming::env_name(app_id).to_lowercase()
+            );
+            let _ = writeln!(config, "key {{remote_host}}");
+            let _ = writeln!(config, "events {rate_limit}");
+            let _ = writeln!(config, "window 1m");
+            let _ = writeln!(config, "}}");
+            let _ = writeln!(config, "}}");
+        }
+        let upstream = format!("{ip}:{}", entry.internal_port);
+        let _ = writeln!(config, "{}", limits.reverse_proxy(app_id, &upstream));
+        let _ = writeln!(config, "}}");
+        let _ = writeln!(config, "}}");
+    }
+    config
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        bind_ports, generate_caddy_config, generate_vpn_caddy_config, ExposedApp,
+        PortBindingConfig, PublicExposure, VpnExposure, VpnProfile,
+    };
+    use crate::cli::rate_limits::RateLimitConfig;
+    use crate::composegenerator::{
+        output::types::{ComposeSpecification, Service},
+        types::CaddyEntry,
+    };
+    use std::collections::{BTreeMap, HashMap};
+
+    #[test]
+    fn only_exposes_configured_apps() {
+        let exposure = PublicExposure {
+            apps: BTreeMap::from([
+                (
+                    "example-app".to_string(),
+                    ExposedApp {
+                        port: 8080,
+                        rate_limit: Some(60),
+                        ..Default::default()
+                    },
+                ),
+                (
+                    "missing-app".to_string(),
+                    ExposedApp {
+                        port: 8081,
+                        ..Default::default()
+                    },
+                ),
+            ]),
+        };
+        let caddy_entries = HashMap::from([(
+            "example-app".to_string(),
+            vec![CaddyEntry {
+                public_port: 3000,
+                internal_port: 3000,
+                container_name: "main".to_string(),
+                is_primary: true,
+                priority: 0,
+            }],
+        )]);
+        let ip_map = HashMap::from([(
+            "APP_EXAMPLE_APP_MAIN_IP".to_string(),
+            "10.21.21.20".to_string(),
+        )]);
+        let config = generate_caddy_config(
+            &exposure,
+            &caddy_entries,
+            &ip_map,
+            &HashMap::new(),
+            &RateLimitConfig::default(),
+            |_| false,
+        );
+        assert!(config.starts_with("http://:8080 {"));
+        assert!(config.contains("reverse_proxy 10.21.21.20:3000"));
+        assert!(config.contains("events 60"));
+        assert!(!config.contains("8081"));
+
+        let config = generate_caddy_config(
+            &exposure,
+            &caddy_entries,
+            &ip_map,
+            &HashMap::new(),
+            &RateLimitConfig::default(),
+            |port| port == 8080,
+        );
+        assert!(config.is_empty());
+    }
+
+    #[test]
+    fn binds_vpn_apps_to_vpn_address() {
+        let vpn_exposure = VpnExposure {
+            profiles: BTreeMap::from([(
+                "tailscale".to_string(),
+                VpnProfile {
+                    address: "100.64.0.1".parse().unwrap(),
+                    apps: vec!["lnd".to_string()],
+                },
+            )]),
+        };
+        assert!(vpn_exposure.bind_addresses("bitcoind").is_empty());
+
+        let mut spec = ComposeSpecification {
+            services: Some(BTreeMap::from([(
+                "main".to_string(),
+                Service {
+                    ports: vec!["9735:9735".to_string(), "127.0.0.1:8080:8080".to_string()],
+                    ..Default::default()
+                },
+            )])),
+            ..Default::default()
+        };
+        bind_ports(&mut spec, &vpn_exposure.bind_addresses("lnd"));
+        assert_eq!(
+            spec.services.unwrap()["main"].ports,
+            vec!["100.64.0.1:9735:9735", "127.0.0.1:8080:8080"]
+        );
+
+        let caddy_entries = HashMap::from([(
+            "lnd".to_string(),
+            vec![CaddyEntry {
+                public_port: 3000,
+                internal_port: 3001,
+                container_name: "web".to_string(),
+                is_primary: true,
+                priority: 0,
+            }],
+        )]);
+        let ip_map = HashMap::from([("APP_LND_WEB_IP".to_string(), "10.21.21.9".to_string())]);
+        let config = generate_vpn_caddy_config(
+            &vpn_exposure,
+            &caddy_entries,
+            &ip_map,
+            &HashMap::new(),
+            &RateLimitConfig::default(),
+        );
+        assert_eq!(
+            config,
+            "http://:3000 {\nbind 100.64.0.1\nreverse_proxy 10.21.21.9:3001\n}\n"
+        );
+    }
+
+    #[test]
+    fn binds_ports_to_configured_addresses() {
+        let config: PortBindingConfig = toml::from_str(
+            r#"
+addresses = ["127.0.0.1"]
+[apps]
+lnd = ["192.168.1.10", "::1"]
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.bind_addresses("bitcoind"),
+            &["127.0.0.1".parse::<std::net::IpAddr>().unwrap()]
+        );
+
+        let mut spec = ComposeSpecification {
+            services: Some(BTreeMap::from([(
+                "main".to_string(),
+                Service {
+                    ports: vec!["9735:9735".to_string(), "9911:9911/udp".to_string()],
+                    ..Default::default()
+                },
+            )])),
+            ..Default::default()
+        };
+        bind_ports(&mut spec, config.bind_addresses("lnd"));
+        assert_eq!(
+            spec.services.unwrap()["main"].ports,
+            vec![
+                "192.168.1.10:9735:9735",
+                "[::1]:9735:9735",
+                "192.168.1.10:9911:9911/udp",
+                "[::1]:9911:9911/udp"
+            ]
+        );
+    }
+}
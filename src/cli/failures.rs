@@ -0,0 +1,144 @@
+use std::collections::BTreeSet;
+use std::fmt::Write;
+use std::path::Path;
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    app_filter::AppFilter,
+    dry_run::Sandbox,
+    metrics::{AppFailure, ConversionReport},
+    node_config::NodeConfig,
+    paths::CitadelPaths,
+    port_review::PortChangePolicy,
+};
+
+/// What to do if apps fail to convert
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum ErrorMode {
+    /// Skip the apps and convert the others, they are listed in apps/conversion-report.json
+    #[default]
+    BestEffort,
+    /// Fail the whole conversion without writing anything
+    Strict,
+}
+
+/// How a conversion handles failing apps, in app-manager.toml
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct FailureConfig {
+    /// The mode used if neither --strict nor --best-effort is passed
+    pub mode: ErrorMode,
+}
+
+/// Formats failed apps for the operator
+pub fn describe(failures: &[AppFailure]) -> String {
+    let mut description = String::new();
+    for failure in failures {
+        let _ = writeln!(
+            description,
+            "  {} ({}): {}",
+            failure.app, failure.stage, failure.error
+        );
+    }
+    description
+}
+
+/// The number of apps that failed, an app can fail in multiple stages
+fn failed_apps(failures: &[AppFailure]) -> usize {
+    failures
+        .iter()
+        .map(|failure| failure.app.as_str())
+        .collect::<BTreeSet<_>>()
+        .len()
+}
+
+/// Converts all apps, with `mode` or the mode from app-manager.toml.
+/// In strict mode, the conversion runs in a sandbox and its files are only written to the node
+/// if no app failed. Port forwards and AppArmor profiles are still updated during the conversion.
+pub fn convert_dir(
+    citadel_root: &str,
+    caddy_url: &Option<String>,
+    state_dir: &Option<String>,
+    port_changes: PortChangePolicy,
+    filter: &AppFilter,
+    single_app: Option<&str>,
+    mode: Option<ErrorMode>,
+) -> Result<ConversionReport> {
+    let mode = match mode {
+        Some(mode) => mode,
+        None => {
+            let paths =
+                CitadelPaths::new(Path::new(citadel_root), state_dir.as_deref().map(Path::new));
+            NodeConfig::load(&paths, Path::new(citadel_root))?
+                .failures
+                .mode
+        }
+    };
+    if mode == ErrorMode::Strict {
+        let sandbox = Sandbox::new(Path::new(citadel_root), state_dir.as_deref().map(Path::new))?;
+        let (report, caddyfile) = sandbox.convert(port_changes, filter, single_app, false)?;
+        if !report.failures.is_empty() {
+            bail!(
+                "{} app(s) failed to convert, nothing was written:\n{}",
+                failed_apps(&report.failures),
+                describe(&report.failures).trim_end()
+            );
+        }
+        sandbox.apply()?;
+        if let Some(caddy_url) = caddy_url {
+            caddyfile.load(caddy_url)?;
+        }
+        return Ok(report);
+    }
+    let report = super::convert_dir(
+        citadel_root,
+        caddy_url,
+        state_dir,
+        port_changes,
+        filter,
+        single_app,
+        false,
+    )?;
+    if !report.failures.is_empty() {
+        tracing::warn!(
+            "{} app(s) failed to convert and were skipped:\n{}",
+            failed_apps(&report.failures),
+            describe(&report.failures).trim_end()
+        );
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{describe, failed_apps, ErrorMode, FailureConfig};
+    use crate::cli::metrics::AppFailure;
+
+    #[test]
+    fn describes_failures() {
+        let config: FailureConfig = toml::from_str("mode = \"strict\"").unwrap();
+        assert_eq!(config.mode, ErrorMode::Strict);
+        assert_eq!(FailureConfig::default().mode, ErrorMode::BestEffort);
+
+        let failures = vec![
+            AppFailure {
+                app: "lnd".to_string(),
+                stage: "convert".to_string(),
+                error: "Main container web does not exist".to_string(),
+            },
+            AppFailure {
+                app: "mempool".to_string(),
+                stage: "assign".to_string(),
+                error: "Missing app.yml".to_string(),
+            },
+        ];
+        assert_eq!(
+            describe(&failures),
+            "  lnd (convert): Main container web does not exist\n  mempool (assign): Missing app.yml\n"
+        );
+        assert_eq!(failed_apps(&failures), 2);
+    }
+}
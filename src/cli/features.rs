@@ -0,0 +1,61 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+/// Flags every app can check for, they are false unless the operator enables them
+pub const KNOWN_FLAGS: [&str; 3] = [
+    // The node runs on constrained hardware, like a Raspberry Pi
+    "low_power",
+    // App data is stored on an external drive
+    "external_storage",
+    // The node is reachable under a public domain
+    "public_domain",
+];
+
+/// Operator-set feature flags in app-manager.toml, flag -> enabled.
+/// Templates of apps can check them as `features.<flag>`.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct FeatureFlags(BTreeMap<String, bool>);
+
+impl FeatureFlags {
+    pub fn is_enabled(&self, flag: &str) -> bool {
+        self.0.get(flag).copied().unwrap_or_default()
+    }
+
+    /// All known and operator-defined flags, so templates can check known flags that are not set
+    pub fn all(&self) -> BTreeMap<String, bool> {
+        let mut flags: BTreeMap<String, bool> = KNOWN_FLAGS
+            .iter()
+            .map(|flag| (flag.to_string(), false))
+            .collect();
+        flags.extend(self.0.clone());
+        flags
+    }
+
+    /// Inserts the flags into a template context as `features`
+    pub fn insert_into(&self, context: &mut tera::Context) {
+        context.insert("features", &self.all());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::FeatureFlags;
+
+    #[test]
+    fn renders_feature_conditionals() {
+        let flags: FeatureFlags = toml::from_str("low_power = true\nsolar = true\n").unwrap();
+        assert!(flags.is_enabled("low_power"));
+        assert!(!flags.is_enabled("public_domain"));
+        let mut context = tera::Context::new();
+        flags.insert_into(&mut context);
+        let rendered = tera::Tera::one_off(
+            "{% if features.low_power %}1{% else %}4{% endif %} {{ features.public_domain }} {{ features.solar }}",
+            &context,
+            false,
+        )
+        .unwrap();
+        assert_eq!(rendered, "1 false true");
+    }
+}
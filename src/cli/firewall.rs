@@ -0,0 +1,200 @@
+use std::collections::BTreeSet;
+use std::fmt::Write;
+use std::net::IpAddr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::composegenerator::{output::types::ComposeSpecification, types::Protocol};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FirewallFormat {
+    /// An nftables ruleset, written to firewall/citadel-apps.nft
+    Nftables,
+    /// A ufw application profile, written to firewall/citadel-apps.ufw
+    Ufw,
+}
+
+/// Firewall settings in app-manager.toml.
+/// The rules only filter traffic to the host. Docker forwards published ports to containers
+/// through the forward chain, which these rules don't cover, but only apps' published ports
+/// are forwarded and those are allowed anyway. Use the DOCKER-USER chain to restrict them further.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct FirewallConfig {
+    /// The ruleset to generate, none is generated by default
+    pub format: Option<FirewallFormat>,
+    /// TCP ports that are always allowed, like SSH and the dashboard
+    pub management_ports: Vec<u16>,
+    /// Interfaces of Docker networks, containers can reach all ports of the host through them.
+    /// Only used for nftables, ufw needs `ufw allow in on <interface>` for each of them.
+    pub bridge_interfaces: Vec<String>,
+}
+
+impl Default for FirewallConfig {
+    fn default() -> Self {
+        Self {
+            format: None,
+            management_ports: vec![22, 80, 443, 8333],
+            bridge_interfaces: vec!["docker0".to_string(), "br-*".to_string()],
+        }
+    }
+}
+
+/// Whether a published port is only bound to a loopback address, like 127.0.0.1:10009:10009
+fn is_loopback(host_ip: &str) -> bool {
+    host_ip
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+        .is_ok_and(|ip| ip.is_loopback())
+}
+
+/// Returns the host ports published by the containers of a compose file,
+/// except ports that are only published on a loopback address
+pub fn published_ports(spec: &ComposeSpecification) -> BTreeSet<(u16, Protocol)> {
+    let mut ports = BTreeSet::new();
+    for service in spec.services.iter().flat_map(|services| services.values()) {
+        for port in &service.ports {
+            let (port, protocol) = match port.strip_suffix("/udp") {
+                Some(port) => (port, Protocol::Udp),
+                None => (port.strip_suffix("/tcp").unwrap_or(port), Protocol::Tcp),
+            };
+            // The host port is the second to last part of [ip:]host_port:container_port
+            let mut parts = port.rsplitn(3, ':');
+            let (Some(_), Some(host_port)) = (parts.next(), parts.next()) else {
+                continue;
+            };
+            if parts.next().is_some_and(is_loopback) {
+                continue;
+            }
+            // Port ranges are published as start-end:start-end
+            let (start, end) = host_port.split_once('-').unwrap_or((host_port, host_port));
+            if let (Ok(start), Ok(end)) = (start.parse::<u16>(), end.parse::<u16>()) {
+                ports.extend((start..=end).map(|port| (port, protocol)));
+            }
+        }
+    }
+    ports
+}
+
+fn port_list(ports: &BTreeSet<(u16, Protocol)>, protocol: Protocol) -> Vec<String> {
+    ports
+        .iter()
+        .filter(|(_, port_protocol)| *port_protocol == protocol)
+        .map(|(port, _)| port.to_string())
+        .collect()
+}
+
+/// Generates a firewall ruleset that only allows the management ports and `ports`
+pub fn generate_rules(
+    config: &FirewallConfig,
+    format: FirewallFormat,
+    ports: &BTreeSet<(u16, Protocol)>,
+) -> String {
+    let mut ports = ports.clone();
+    ports.extend(
+        config
+            .management_ports
+            .iter()
+            .map(|port| (*port, Protocol::Tcp)),
+    );
+    let tcp_ports = port_list(&ports, Protocol::Tcp);
+    let udp_ports = port_list(&ports, Protocol::Udp);
+    let mut rules = String::new();
+    match format {
+        FirewallFormat::Nftables => {
+            let _ = writeln!(rules, "# Generated by the Citadel app manager, do not edit");
+            let _ = writeln!(
+                rules,
+                "# Only filters traffic to the host, Docker forwards published ports to containers in the forward chain"
+            );
+            let _ = writeln!(rules, "table inet citadel_apps");
+            let _ = writeln!(rules, "delete table inet citadel_apps");
+            let _ = writeln!(rules, "table inet citadel_apps {{");
+            let _ = writeln!(rules, "  chain input {{");
+            let _ = writeln!(rules, "    type filter hook input priority 0; policy drop;");
+            let _ = writeln!(rules, "    ct state established,related accept");
+            let _ = writeln!(rules, "    iif lo accept");
+            for interface in &config.bridge_interfaces {
+                let _ = writeln!(rules, "    iifname \"{interface}\" accept");
+            }
+            let _ = writeln!(rules, "    meta l4proto {{ icmp, ipv6-icmp }} accept");
+            if !tcp_ports.is_empty() {
+                let _ = writeln!(rules, "    tcp dport {{ {} }} accept", tcp_ports.join(", "));
+            }
+            if !udp_ports.is_empty() {
+                let _ = writeln!(rules, "    udp dport {{ {} }} accept", udp_ports.join(", "));
+            }
+            let _ = writeln!(rules, "  }}");
+            let _ = writeln!(rules, "}}");
+        }
+        FirewallFormat::Ufw => {
+            let mut ufw_ports = Vec::new();
+            if !tcp_ports.is_empty() {
+                ufw_ports.push(format!("{}/tcp", tcp_ports.join(",")));
+            }
+            if !udp_ports.is_empty() {
+                ufw_ports.push(format!("{}/udp", udp_ports.join(",")));
+            }
+            let _ = writeln!(rules, "[citadel-apps]");
+            let _ = writeln!(rules, "title=Citadel apps");
+            let _ = writeln!(
+                rules,
+                "description=Ports published by the Citadel app manager"
+            );
+            let _ = writeln!(rules, "ports={}", ufw_ports.join("|"));
+        }
+    }
+    rules
+}
+
+#[cfg(test)]
+mod test {
+    use super::{generate_rules, published_ports, FirewallConfig, FirewallFormat};
+    use crate::composegenerator::{
+        output::types::{ComposeSpecification, Service},
+        types::Protocol,
+    };
+    use std::collections::{BTreeMap, BTreeSet};
+
+    #[test]
+    fn allows_published_ports() {
+        let spec = ComposeSpecification {
+            services: Some(BTreeMap::from([(
+                "main".to_string(),
+                Service {
+                    ports: vec![
+                        "9735:9735".to_string(),
+                        "127.0.0.1:10009:10009".to_string(),
+                        "[::1]:8080:80".to_string(),
+                        "192.168.1.2:3000:3000".to_string(),
+                        "51413:51413/udp".to_string(),
+                    ],
+                    ..Default::default()
+                },
+            )])),
+            ..Default::default()
+        };
+        let ports = published_ports(&spec);
+        assert_eq!(
+            ports,
+            BTreeSet::from([
+                (3000, Protocol::Tcp),
+                (9735, Protocol::Tcp),
+                (51413, Protocol::Udp)
+            ])
+        );
+        let config = FirewallConfig {
+            management_ports: vec![22],
+            ..Default::default()
+        };
+        let rules = generate_rules(&config, FirewallFormat::Nftables, &ports);
+        assert!(rules.contains("iifname \"docker0\" accept"));
+        assert!(rules.contains("iifname \"br-*\" accept"));
+        assert!(rules.contains("tcp dport { 22, 3000, 9735 } accept"));
+        assert!(rules.contains("udp dport { 51413 } accept"));
+        let rules = generate_rules(&config, FirewallFormat::Ufw, &ports);
+        assert!(rules.contains("ports=22,3000,9735/tcp|51413/udp"));
+    }
+}
@@ -0,0 +1,213 @@
+use std::{
+    collections::BTreeSet,
+    io::{BufRead, Write},
+    path::Path,
+};
+
+use anyhow::{bail, Result};
+
+use super::{
+    app_list,
+    node_config::NodeConfig,
+    paths::CitadelPaths,
+    resources::{labeled_entries, run_engine},
+    runtime::ContainerEngine,
+};
+use crate::composegenerator::output::labels::APP_LABEL;
+
+/// The label docker compose puts on everything it creates
+const PROJECT_LABEL: &str = "com.docker.compose.project";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ResourceKind {
+    Container,
+    Network,
+    Volume,
+}
+
+impl std::fmt::Display for ResourceKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ResourceKind::Container => write!(f, "container"),
+            ResourceKind::Network => write!(f, "network"),
+            ResourceKind::Volume => write!(f, "volume"),
+        }
+    }
+}
+
+/// A Docker resource of an app which no longer exists
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Orphan {
+    pub kind: ResourceKind,
+    pub name: String,
+    pub app: String,
+}
+
+/// Parses lines of "name<TAB>app" and returns the ones whose app is not known
+fn find_orphaned(kind: ResourceKind, listing: &str, apps: &BTreeSet<String>) -> Vec<Orphan> {
+    listing
+        .lines()
+        .filter_map(|line| line.split_once('\t'))
+        .filter(|(_, app)| !app.is_empty() && !apps.contains(*app))
+        .map(|(name, app)| Orphan {
+            kind,
+            name: name.to_string(),
+            app: app.to_string(),
+        })
+        .collect()
+}
+
+/// Lists the resources of a kind with the label as "name<TAB>value" lines
+fn list(engine: ContainerEngine, kind: ResourceKind, label: &str) -> Result<String> {
+    let filter = format!("label={label}");
+    let (args, name_field) = match kind {
+        ResourceKind::Container => (vec!["ps", "--all"], "Names"),
+        ResourceKind::Network => (vec!["network", "ls"], "Name"),
+        ResourceKind::Volume => (vec!["volume", "ls"], "Name"),
+    };
+    let listing = run_engine(
+        engine,
+        &[&args[..], &["--filter", &filter, "--format", "{{json .}}"]].concat(),
+    )?;
+    Ok(labeled_entries(&listing, name_field, label)?
+        .into_iter()
+        .map(|(name, value)| format!("{name}\t{value}\n"))
+        .collect())
+}
+
+/// The apps that still exist: all app directories and the installed apps in user.json.
+/// The registry is not used, apps that failed to convert are missing from it.
+fn known_apps(paths: &CitadelPaths) -> Result<BTreeSet<String>> {
+    let mut apps: BTreeSet<String> = super::read_app_dirs(&paths.root().join("apps"))?
+        .into_iter()
+        .map(|app_dir| app_dir.file_name().to_string_lossy().to_string())
+        .collect();
+    apps.extend(app_list::installed_apps(paths, paths.root()));
+    Ok(apps)
+}
+
+/// Finds containers, networks and volumes of apps which don't exist anymore
+pub fn find_orphans(paths: &CitadelPaths) -> Result<Vec<Orphan>> {
+    let apps = known_apps(paths)?;
+    let engine = NodeConfig::load(paths, paths.root())?.runtime.engine;
+    let mut orphans = find_orphaned(
+        ResourceKind::Container,
+        &list(engine, ResourceKind::Container, APP_LABEL)?,
+        &apps,
+    );
+    orphans.extend(find_orphaned(
+        ResourceKind::Volume,
+        &list(engine, ResourceKind::Volume, APP_LABEL)?,
+        &apps,
+    ));
+    // Networks are not labeled by us, so only networks of compose projects we know belonged to an app are included
+    let orphaned_apps: BTreeSet<String> = orphans.iter().map(|orphan| orphan.app.clone()).collect();
+    orphans.extend(
+        find_orphaned(
+            ResourceKind::Network,
+            &list(engine, ResourceKind::Network, PROJECT_LABEL)?,
+            &apps,
+        )
+        .into_iter()
+        .filter(|network| orphaned_apps.contains(&network.app)),
+    );
+    orphans.sort();
+    Ok(orphans)
+}
+
+/// Removes orphans, containers first so their networks and volumes are no longer in use
+pub fn remove_orphans(engine: ContainerEngine, orphans: &[Orphan]) -> Result<()> {
+    for orphan in orphans {
+        let result = match orphan.kind {
+            ResourceKind::Container => run_engine(engine, &["rm", "--force", &orphan.name]),
+            ResourceKind::Network => run_engine(engine, &["network", "rm", &orphan.name]),
+            ResourceKind::Volume => run_engine(engine, &["volume", "rm", &orphan.name]),
+        };
+        if let Err(err) = result {
+            tracing::error!(
+                "Failed to remove {} {}: {:#}",
+                orphan.kind,
+                orphan.name,
+                err
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Lists orphaned Docker resources and removes them once the operator confirms.
+/// Returns the orphans that were found.
+pub fn gc(
+    citadel_root: &str,
+    state_dir: &Option<String>,
+    yes: bool,
+    mut input: impl BufRead,
+    mut output: impl Write,
+) -> Result<Vec<Orphan>> {
+    let paths = CitadelPaths::new(Path::new(citadel_root), state_dir.as_deref().map(Path::new));
+    let orphans = find_orphans(&paths)?;
+    let engine = NodeConfig::load(&paths, paths.root())?.runtime.engine;
+    if orphans.is_empty() {
+        writeln!(output, "No orphaned containers, networks or volumes found")?;
+        return Ok(orphans);
+    }
+    for orphan in &orphans {
+        writeln!(
+            output,
+            "{} {} (app {})",
+            orphan.kind, orphan.name, orphan.app
+        )?;
+    }
+    if !yes {
+        write!(output, "Remove these? [y/N] ")?;
+        output.flush()?;
+        let mut answer = String::new();
+        input.read_line(&mut answer)?;
+        if !matches!(answer.trim(), "y" | "Y" | "yes") {
+            bail!("Nothing was removed");
+        }
+    }
+    remove_orphans(engine, &orphans)?;
+    Ok(orphans)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{find_orphaned, known_apps, ResourceKind};
+    use crate::cli::paths::CitadelPaths;
+    use std::collections::BTreeSet;
+    use tempdir::TempDir;
+
+    #[test]
+    fn finds_orphaned_resources() {
+        let apps = BTreeSet::from(["lnd".to_string()]);
+        let listing = "lnd-main-1\tlnd\nold-app-main-1\told-app\nunlabeled\t\n";
+        let orphans = find_orphaned(ResourceKind::Container, listing, &apps);
+        assert_eq!(orphans.len(), 1);
+        assert_eq!(orphans[0].name, "old-app-main-1");
+        assert_eq!(orphans[0].app, "old-app");
+    }
+
+    #[test]
+    fn keeps_apps_that_failed_to_convert() {
+        let root = TempDir::new("gc").unwrap();
+        let root = root.path();
+        // broken is not in registry.json, because its app.yml is invalid
+        for app in ["lnd", "broken"] {
+            std::fs::create_dir_all(root.join("apps").join(app)).unwrap();
+        }
+        std::fs::write(root.join("apps/broken/app.yml"), "invalid").unwrap();
+        std::fs::write(root.join("apps/registry.json"), r#"[{"id": "lnd"}]"#).unwrap();
+        std::fs::create_dir_all(root.join("db")).unwrap();
+        std::fs::write(
+            root.join("db/user.json"),
+            r#"{"installedApps": ["lnd", "being-downloaded"]}"#,
+        )
+        .unwrap();
+        let apps = known_apps(&CitadelPaths::new(root, None)).unwrap();
+        let listing = "lnd-main-1\tlnd\nbroken-main-1\tbroken\nbeing-downloaded-main-1\tbeing-downloaded\nold-app-main-1\told-app\n";
+        let orphans = find_orphaned(ResourceKind::Container, listing, &apps);
+        assert_eq!(orphans.len(), 1);
+        assert_eq!(orphans[0].app, "old-app");
+    }
+}
@@ -0,0 +1,245 @@
+use std::{
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use super::{image_arch::host_architecture, paths::CitadelPaths};
+use crate::composegenerator::{
+    ir::AppDefinition,
+    types::{HardwareCapability, UnsupportedReason},
+};
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageType {
+    Ssd,
+    Hdd,
+    /// Network storage, device mapper setups and containers where the disk can't be determined
+    #[default]
+    Unknown,
+}
+
+/// Facts about the node's hardware, detected by `probe` and stored in db/host-facts.json.
+/// Templates of apps can check them as `host.<fact>`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct HostFacts {
+    /// The Docker name of the node's architecture, like arm64
+    pub architecture: String,
+    /// Total RAM in MiB, None if it could not be determined
+    pub memory_mb: Option<u64>,
+    pub kvm: bool,
+    pub gpu: bool,
+    pub avx: bool,
+    pub avx2: bool,
+    /// The kind of disk app data is stored on
+    pub storage: StorageType,
+}
+
+impl Default for HostFacts {
+    fn default() -> Self {
+        Self {
+            architecture: host_architecture().to_string(),
+            memory_mb: None,
+            kvm: false,
+            gpu: false,
+            avx: false,
+            avx2: false,
+            storage: StorageType::Unknown,
+        }
+    }
+}
+
+fn facts_file(citadel_root: &Path) -> PathBuf {
+    citadel_root.join("db").join("host-facts.json")
+}
+
+/// Parses the total RAM in MiB from /proc/meminfo
+fn parse_meminfo(meminfo: &str) -> Option<u64> {
+    let line = meminfo.lines().find(|line| line.starts_with("MemTotal:"))?;
+    let kib: u64 = line
+        .trim_start_matches("MemTotal:")
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse()
+        .ok()?;
+    Some(kib / 1024)
+}
+
+/// The CPU flags listed in /proc/cpuinfo, they are the same for all cores
+fn cpu_flags(cpuinfo: &str) -> Vec<&str> {
+    cpuinfo
+        .lines()
+        .find(|line| line.starts_with("flags"))
+        .and_then(|line| line.split_once(':'))
+        .map(|(_, flags)| flags.split_whitespace().collect())
+        .unwrap_or_default()
+}
+
+/// Whether the disk `path` is stored on is rotational, read from sysfs
+fn storage_type(path: &Path) -> StorageType {
+    let Ok(metadata) = std::fs::metadata(path) else {
+        return StorageType::Unknown;
+    };
+    let dev = metadata.dev();
+    let major = ((dev >> 8) & 0xfff) | ((dev >> 32) & !0xfff);
+    let minor = (dev & 0xff) | ((dev >> 12) & !0xff);
+    let device = PathBuf::from(format!("/sys/dev/block/{major}:{minor}"));
+    // Partitions don't have a queue, their disk does
+    let rotational = std::fs::read_to_string(device.join("queue").join("rotational"))
+        .or_else(|_| std::fs::read_to_string(device.join("..").join("queue").join("rotational")));
+    match rotational.as_deref().map(str::trim) {
+        Ok("0") => StorageType::Ssd,
+        Ok("1") => StorageType::Hdd,
+        _ => StorageType::Unknown,
+    }
+}
+
+impl HostFacts {
+    /// Detects the facts of the machine app-manager runs on, app data is expected in `data_dir`
+    pub fn detect(data_dir: &Path) -> Self {
+        let cpuinfo = std::fs::read_to_string("/proc/cpuinfo").unwrap_or_default();
+        let flags = cpu_flags(&cpuinfo);
+        Self {
+            architecture: host_architecture().to_string(),
+            memory_mb: std::fs::read_to_string("/proc/meminfo")
+                .ok()
+                .and_then(|meminfo| parse_meminfo(&meminfo)),
+            kvm: Path::new("/dev/kvm").exists(),
+            gpu: Path::new("/dev/dri").exists() || Path::new("/dev/nvidia0").exists(),
+            avx: flags.contains(&"avx"),
+            avx2: flags.contains(&"avx2"),
+            storage: storage_type(data_dir),
+        }
+    }
+
+    pub fn has(&self, capability: HardwareCapability) -> bool {
+        match capability {
+            HardwareCapability::Kvm => self.kvm,
+            HardwareCapability::Gpu => self.gpu,
+            HardwareCapability::Avx => self.avx,
+            HardwareCapability::Avx2 => self.avx2,
+            HardwareCapability::Ssd => self.storage == StorageType::Ssd,
+        }
+    }
+
+    /// Why the node can't run an app because of its hardware requirements.
+    /// Requirements on facts that could not be detected are not enforced.
+    pub fn unmet_requirements(&self, app_yml: &AppDefinition) -> Vec<UnsupportedReason> {
+        let mut reasons = Vec::new();
+        if let (Some(required), Some(available)) =
+            (app_yml.metadata.required_memory, self.memory_mb)
+        {
+            if required > available {
+                reasons.push(UnsupportedReason::InsufficientMemory {
+                    required,
+                    available,
+                });
+            }
+        }
+        for capability in &app_yml.metadata.required_hardware {
+            if *capability == HardwareCapability::Ssd && self.storage == StorageType::Unknown {
+                continue;
+            }
+            if !self.has(*capability) {
+                reasons.push(UnsupportedReason::MissingHardware {
+                    capability: *capability,
+                });
+            }
+        }
+        reasons
+    }
+
+    /// Inserts the facts into a template context as `host`
+    pub fn insert_into(&self, context: &mut tera::Context) {
+        context.insert("host", self);
+    }
+}
+
+/// Detects the facts of this node and writes them to db/host-facts.json
+pub fn probe(paths: &CitadelPaths, citadel_root: &Path) -> Result<HostFacts> {
+    let data_dir = citadel_root.join("app-data");
+    let data_dir = if data_dir.is_dir() {
+        data_dir
+    } else {
+        citadel_root.to_path_buf()
+    };
+    let facts = HostFacts::detect(&data_dir);
+    paths.write(
+        &facts_file(citadel_root),
+        serde_json::to_string_pretty(&facts)?,
+    )?;
+    Ok(facts)
+}
+
+/// Loads the facts from db/host-facts.json, they are probed if the file does not exist yet
+pub fn load(paths: &CitadelPaths, citadel_root: &Path) -> Result<HostFacts> {
+    let facts_file = facts_file(citadel_root);
+    if !paths.exists(&facts_file) {
+        return probe(paths, citadel_root);
+    }
+    serde_json::from_str(&paths.read_to_string(&facts_file)?)
+        .context("Failed to load host-facts.json")
+}
+
+#[cfg(test)]
+mod test {
+    use super::{cpu_flags, parse_meminfo, HostFacts, StorageType};
+    use crate::composegenerator::{
+        ir::AppDefinition,
+        types::{HardwareCapability, UnsupportedReason},
+    };
+
+    #[test]
+    fn checks_hardware_requirements() {
+        assert_eq!(
+            parse_meminfo("MemTotal:        8041380 kB\nMemFree:         1084996 kB\n"),
+            Some(7852)
+        );
+        let flags = cpu_flags("processor\t: 0\nflags\t\t: fpu sse2 avx avx2\n");
+        assert!(flags.contains(&"avx2"));
+
+        let facts = HostFacts {
+            architecture: "arm64".to_string(),
+            memory_mb: Some(4096),
+            kvm: false,
+            gpu: true,
+            avx: false,
+            avx2: false,
+            storage: StorageType::Unknown,
+        };
+        let mut app_yml = AppDefinition::default();
+        app_yml.metadata.required_memory = Some(8192);
+        app_yml.metadata.required_hardware = vec![
+            HardwareCapability::Gpu,
+            HardwareCapability::Kvm,
+            HardwareCapability::Ssd,
+        ];
+        assert_eq!(
+            facts.unmet_requirements(&app_yml),
+            vec![
+                UnsupportedReason::InsufficientMemory {
+                    required: 8192,
+                    available: 4096
+                },
+                UnsupportedReason::MissingHardware {
+                    capability: HardwareCapability::Kvm
+                }
+            ]
+        );
+
+        let mut context = tera::Context::new();
+        facts.insert_into(&mut context);
+        let rendered = tera::Tera::one_off(
+            "{% if host.memory_mb < 8192 %}small{% endif %} {{ host.storage }}",
+            &context,
+            false,
+        )
+        .unwrap();
+        assert_eq!(rendered, "small unknown");
+    }
+}
@@ -0,0 +1,83 @@
+use std::{collections::BTreeSet, path::PathBuf};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// Settings for ports used by other processes on the host, in app-manager.toml
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct HostPortsConfig {
+    /// Scan the ports processes on the host are listening on
+    pub scan_listeners: bool,
+    /// Where the tcp/udp socket tables are read from,
+    /// this needs to be the host's /proc/net if the app manager runs in a container
+    pub proc_net_dir: PathBuf,
+    /// Ports that are always considered to be in use
+    pub reserved: Vec<u16>,
+}
+
+impl Default for HostPortsConfig {
+    fn default() -> Self {
+        Self {
+            scan_listeners: false,
+            proc_net_dir: PathBuf::from("/proc/net"),
+            reserved: Vec::new(),
+        }
+    }
+}
+
+/// Parses a socket table from /proc/net and returns the local ports of sockets in `state`
+fn parse_socket_table(contents: &str, state: &str) -> BTreeSet<u16> {
+    contents
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.get(3) != Some(&state) {
+                return None;
+            }
+            let (_, port) = fields.get(1)?.rsplit_once(':')?;
+            u16::from_str_radix(port, 16).ok()
+        })
+        .collect()
+}
+
+/// Returns the ports processes on the host are listening on, if scanning is enabled.
+/// Ports published by Citadel apps also show up here, so they need to be removed by the caller.
+pub fn listening_ports(config: &HostPortsConfig) -> Result<BTreeSet<u16>> {
+    let mut ports = BTreeSet::new();
+    if !config.scan_listeners {
+        return Ok(ports);
+    }
+    // Listening TCP sockets are in state 0A, bound UDP sockets in state 07
+    for (table, state) in [("tcp", "0A"), ("tcp6", "0A"), ("udp", "07"), ("udp6", "07")] {
+        let path = config.proc_net_dir.join(table);
+        if !path.exists() {
+            continue;
+        }
+        let contents = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        ports.extend(parse_socket_table(&contents, state));
+    }
+    Ok(ports)
+}
+
+#[cfg(test)]
+mod test {
+    use super::parse_socket_table;
+
+    #[test]
+    fn parses_listening_sockets() {
+        let table = "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode
+   0: 00000000:0016 00000000:0000 0A 00000000:00000000 00:00000000 00000000     0        0 21325 1 0000000000000000 100 0 0 10 0
+   1: 0100007F:1F90 00000000:0000 0A 00000000:00000000 00:00000000 00000000     0        0 21326 1 0000000000000000 100 0 0 10 0
+   2: 0F02000A:0016 0202000A:D1A6 01 00000000:00000000 02:0008A1B9 00000000     0        0 31852 4 0000000000000000 20 4 1 10 -1
+";
+        assert_eq!(
+            parse_socket_table(table, "0A")
+                .into_iter()
+                .collect::<Vec<_>>(),
+            vec![22, 8080]
+        );
+    }
+}
@@ -0,0 +1,297 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    sync::{Condvar, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::{bail, Context, Result};
+use reqwest::{
+    blocking::Client,
+    header::{HeaderName, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED},
+    StatusCode,
+};
+use serde::{Deserialize, Serialize};
+
+use super::{node_config::NodeConfig, paths::CitadelPaths, stores::load_stores};
+use crate::composegenerator::types::OutputMetadata;
+
+/// Settings for fetching remote resources in app-manager.toml
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct HttpCacheConfig {
+    /// Never use the network, only serve resources that are already cached
+    pub offline: bool,
+    /// How many requests may run at the same time
+    pub max_concurrent: usize,
+    /// Minimum time between two requests to the same host, in milliseconds
+    pub min_interval: u64,
+    /// Cached resources younger than this are used without asking the server, in seconds
+    pub max_age: u64,
+}
+
+impl Default for HttpCacheConfig {
+    fn default() -> Self {
+        Self {
+            offline: false,
+            max_concurrent: 4,
+            min_interval: 250,
+            max_age: 24 * 60 * 60,
+        }
+    }
+}
+
+/// Stored next to every cached response
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+struct CacheEntry {
+    url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    etag: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_modified: Option<String>,
+    /// Seconds since the Unix epoch
+    fetched_at: u64,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// A shared HTTP client for remote resources referenced by app metadata.
+/// Responses are cached on disk and revalidated with ETags, requests are limited per host and in total.
+pub struct HttpCache {
+    client: Client,
+    config: HttpCacheConfig,
+    cache_dir: PathBuf,
+    running: Mutex<usize>,
+    finished: Condvar,
+    /// Host -> when the last request to it was started
+    last_request: Mutex<HashMap<String, Instant>>,
+}
+
+impl HttpCache {
+    pub fn new(cache_dir: &Path, config: HttpCacheConfig) -> Result<Self> {
+        std::fs::create_dir_all(cache_dir)
+            .with_context(|| format!("Failed to create {}", cache_dir.display()))?;
+        let client = Client::builder()
+            .user_agent(concat!("citadel-app-manager/", env!("CARGO_PKG_VERSION")))
+            .timeout(Duration::from_secs(30))
+            .build()?;
+        Ok(Self {
+            client,
+            config,
+            cache_dir: cache_dir.to_path_buf(),
+            running: Mutex::new(0),
+            finished: Condvar::new(),
+            last_request: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// The file a URL's body is cached in
+    pub fn cache_path(&self, url: &str) -> PathBuf {
+        self.cache_dir
+            .join(hex::encode(&hmac_sha256::Hash::hash(url.as_bytes())[..16]))
+    }
+
+    fn load_entry(&self, url: &str) -> Option<CacheEntry> {
+        let body_file = self.cache_path(url);
+        if !body_file.exists() {
+            return None;
+        }
+        let entry = std::fs::read_to_string(body_file.with_extension("json")).ok()?;
+        serde_json::from_str::<CacheEntry>(&entry)
+            .ok()
+            .filter(|entry| entry.url == url)
+    }
+
+    /// Waits until this request may run without exceeding the limits
+    fn acquire(&self, host: &str) {
+        let mut running = self.running.lock().unwrap();
+        while *running >= self.config.max_concurrent.max(1) {
+            running = self.finished.wait(running).unwrap();
+        }
+        *running += 1;
+        drop(running);
+        let min_interval = Duration::from_millis(self.config.min_interval);
+        let wait = {
+            let mut last_request = self.last_request.lock().unwrap();
+            let now = Instant::now();
+            let start = last_request
+                .get(host)
+                .map_or(now, |last| (*last + min_interval).max(now));
+            last_request.insert(host.to_string(), start);
+            start - now
+        };
+        std::thread::sleep(wait);
+    }
+
+    fn release(&self) {
+        *self.running.lock().unwrap() -= 1;
+        self.finished.notify_one();
+    }
+
+    fn request(&self, url: &str, cached: Option<&CacheEntry>) -> Result<Option<CacheEntry>> {
+        let mut request = self.client.get(url);
+        if let Some(cached) = cached {
+            if let Some(etag) = &cached.etag {
+                request = request.header(IF_NONE_MATCH, etag);
+            }
+            if let Some(last_modified) = &cached.last_modified {
+                request = request.header(IF_MODIFIED_SINCE, last_modified);
+            }
+        }
+        let response = request.send()?;
+        if response.status() == StatusCode::NOT_MODIFIED && cached.is_some() {
+            return Ok(None);
+        }
+        let response = response.error_for_status()?;
+        let header = |name: HeaderName| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+        let entry = CacheEntry {
+            url: url.to_string(),
+            etag: header(ETAG),
+            last_modified: header(LAST_MODIFIED),
+            fetched_at: now(),
+        };
+        std::fs::write(self.cache_path(url), response.bytes()?)?;
+        Ok(Some(entry))
+    }
+
+    /// Fetches a URL and returns the file its body is cached in.
+    /// If the server can't be reached, an older cached version is used.
+    pub fn fetch(&self, url: &str) -> Result<PathBuf> {
+        let parsed = url::Url::parse(url).with_context(|| format!("Invalid URL {url}"))?;
+        let cached = self.load_entry(url);
+        let body_file = self.cache_path(url);
+        if let Some(cached) = &cached {
+            if self.config.offline || now().saturating_sub(cached.fetched_at) < self.config.max_age
+            {
+                return Ok(body_file);
+            }
+        } else if self.config.offline {
+            bail!("{url} is not cached and offline mode is enabled");
+        }
+
+        self.acquire(parsed.host_str().unwrap_or_default());
+        let result = self.request(url, cached.as_ref());
+        self.release();
+        let entry = match result {
+            Ok(Some(entry)) => entry,
+            Ok(None) => CacheEntry {
+                fetched_at: now(),
+                ..cached.unwrap()
+            },
+            Err(err) if cached.is_some() => {
+                tracing::warn!(
+                    "Failed to fetch {}, using the cached version: {:#}",
+                    url,
+                    err
+                );
+                return Ok(body_file);
+            }
+            Err(err) => return Err(err.context(format!("Failed to fetch {url}"))),
+        };
+        std::fs::write(
+            body_file.with_extension("json"),
+            serde_json::to_string(&entry)?,
+        )?;
+        Ok(body_file)
+    }
+
+    /// Fetches multiple URLs in parallel, within the concurrency limit.
+    /// Returns URL -> cached file for all URLs that could be fetched.
+    pub fn fetch_all(&self, urls: &[String]) -> BTreeMap<String, PathBuf> {
+        std::thread::scope(|scope| {
+            let handles: Vec<_> = urls
+                .iter()
+                .map(|url| (url, scope.spawn(|| self.fetch(url))))
+                .collect();
+            handles
+                .into_iter()
+                .filter_map(|(url, handle)| match handle.join() {
+                    Ok(Ok(path)) => Some((url.clone(), path)),
+                    Ok(Err(err)) => {
+                        tracing::warn!("{:#}", err);
+                        None
+                    }
+                    Err(_) => None,
+                })
+                .collect()
+        })
+    }
+}
+
+/// Downloads the icons and gallery images referenced by stores and apps into the cache.
+/// URL -> cached file is written to apps/assets.json, so the dashboard doesn't need to fetch them itself.
+pub fn fetch_assets(citadel_root: &str, state_dir: &Option<String>) -> Result<()> {
+    let citadel_root = Path::new(citadel_root);
+    let paths = CitadelPaths::new(citadel_root, state_dir.as_deref().map(Path::new));
+    let config = NodeConfig::load(&paths, citadel_root)?.http_cache;
+    let mut urls: Vec<String> = load_stores(&paths, citadel_root)?
+        .into_iter()
+        .map(|store| store.icon)
+        .collect();
+    let registry_file = citadel_root.join("apps").join("registry.json");
+    if paths.exists(&registry_file) {
+        let registry: Vec<OutputMetadata> =
+            serde_json::from_str(&paths.read_to_string(&registry_file)?)
+                .context("Failed to load registry.json")?;
+        urls.extend(
+            registry
+                .into_iter()
+                .flat_map(|app| app.gallery.unwrap_or_default()),
+        );
+    }
+    urls.retain(|url| url.starts_with("https://") || url.starts_with("http://"));
+    urls.sort();
+    urls.dedup();
+
+    let cache = HttpCache::new(
+        &paths.create_dir_all(&citadel_root.join("cache").join("http"))?,
+        config,
+    )?;
+    let assets = cache.fetch_all(&urls);
+    paths.write(
+        &citadel_root.join("apps").join("assets.json"),
+        serde_json::to_string(&assets)?,
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{HttpCache, HttpCacheConfig};
+
+    #[test]
+    fn serves_cached_resources_offline() {
+        let dir = tempdir::TempDir::new("http-cache").unwrap();
+        let cache = HttpCache::new(
+            dir.path(),
+            HttpCacheConfig {
+                offline: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let url = "https://images.example.com/icon.svg";
+        assert!(cache.fetch(url).is_err());
+
+        let body_file = cache.cache_path(url);
+        std::fs::write(&body_file, "<svg/>").unwrap();
+        std::fs::write(
+            body_file.with_extension("json"),
+            format!(r#"{{"url":"{url}","etag":"\"abc\"","fetchedAt":0}}"#),
+        )
+        .unwrap();
+        assert_eq!(cache.fetch(url).unwrap(), body_file);
+    }
+}
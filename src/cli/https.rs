@@ -0,0 +1,244 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::path::PathBuf;
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+use super::rate_limits::RateLimitConfig;
+use crate::{composegenerator::types::CaddyEntry, naming};
+
+/// How plain HTTP requests to an app are handled
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum HttpMode {
+    /// Redirect HTTP requests to HTTPS
+    Redirect,
+    /// Serve the app over both HTTP and HTTPS
+    #[default]
+    Both,
+    /// Only serve the app over HTTP, for example for access via Tor
+    HttpOnly,
+}
+
+/// A certificate to use instead of obtaining one automatically
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Certificate {
+    /// PEM certificate (chain), as seen by Caddy
+    pub cert_file: PathBuf,
+    /// PEM private key, as seen by Caddy
+    pub key_file: PathBuf,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct AppHttpsOptions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub http_mode: Option<HttpMode>,
+    /// A domain to serve the app's main UI on
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub domain: Option<String>,
+    /// Overrides the default certificate for this app's domain
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub certificate: Option<Certificate>,
+}
+
+/// The https options in user.json
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct HttpsOptions {
+    /// The default for all apps
+    #[serde(default)]
+    pub http_mode: HttpMode,
+    /// Email address for the ACME account used to obtain certificates
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    /// The default certificate for app domains, if not set certificates are obtained automatically
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub certificate: Option<Certificate>,
+    /// App ID -> options overriding the defaults
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub apps: BTreeMap<String, AppHttpsOptions>,
+    /// Other options, these are passed to the Caddyfile template unchanged
+    #[serde(flatten)]
+    pub other: serde_json::Map<String, serde_json::Value>,
+}
+
+impl HttpsOptions {
+    /// The HTTP mode of an app
+    pub fn http_mode(&self, app_id: &str) -> HttpMode {
+        self.apps
+            .get(app_id)
+            .and_then(|options| options.http_mode)
+            .unwrap_or(self.http_mode)
+    }
+
+    /// The HTTP mode of every app, for the Caddyfile template
+    pub fn http_modes<'a>(
+        &self,
+        app_ids: impl Iterator<Item = &'a String>,
+    ) -> HashMap<String, HttpMode> {
+        app_ids
+            .map(|app_id| (app_id.clone(), self.http_mode(app_id)))
+            .collect()
+    }
+
+    /// Checks the options for mistakes which would only show up when Caddy loads the config
+    pub fn validate(&self) -> Result<()> {
+        if let Some(email) = &self.email {
+            if !email.contains('@') || email.chars().any(char::is_whitespace) {
+                bail!("Invalid email address {email}");
+            }
+        }
+        let mut domains: HashMap<&str, &str> = HashMap::new();
+        for (app_id, options) in &self.apps {
+            let Some(domain) = &options.domain else {
+                if options.certificate.is_some() {
+                    bail!("App {app_id} has a certificate, but no domain");
+                }
+                continue;
+            };
+            let valid = !domain.is_empty()
+                && domain.split('.').all(|label| {
+                    !label.is_empty()
+                        && !label.starts_with('-')
+                        && !label.ends_with('-')
+                        && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+                });
+            if !valid {
+                bail!("Invalid domain {domain} for app {app_id}");
+            }
+            if let Some(other_app) = domains.insert(domain, app_id) {
+                bail!("Domain {domain} is used by both {other_app} and {app_id}");
+            }
+            let certificate = options.certificate.as_ref().or(self.certificate.as_ref());
+            if certificate.is_some() && self.http_mode(app_id) == HttpMode::HttpOnly {
+                bail!("App {app_id} is HTTP only, but has a certificate");
+            }
+        }
+        Ok(())
+    }
+
+    /// Generates Caddy site blocks for apps with a domain
+    pub fn generate_caddy_config(
+        &self,
+        caddy_entries: &HashMap<String, Vec<CaddyEntry>>,
+        ip_map: &HashMap<String, String>,
+        limits: &RateLimitConfig,
+    ) -> String {
+        let mut config = String::new();
+        for (app_id, options) in &self.apps {
+            let Some(domain) = &options.domain else {
+                continue;
+            };
+            let Some(entry) = caddy_entries
+                .get(app_id)
+                .and_then(|entries| entries.iter().find(|entry| entry.is_primary))
+            else {
+                tracing::warn!(
+                    "App {} has a domain, but is not installed or has no web UI",
+                    app_id
+                );
+                continue;
+            };
+            let ip_var = naming::ip_env_var(app_id, &entry.container_name);
+            let Some(ip) = ip_map.get(&ip_var) else {
+                tracing::warn!(
+                    "App {} can't be served on {}, {} is not set",
+                    app_id,
+                    domain,
+                    ip_var
+                );
+                continue;
+            };
+            let address = match self.http_mode(app_id) {
+                // Caddy redirects HTTP to HTTPS by default
+                HttpMode::Redirect => domain.clone(),
+                HttpMode::Both => format!("http://{domain}, https://{domain}"),
+                HttpMode::HttpOnly => format!("http://{domain}"),
+            };
+            let _ = writeln!(config, "{address} {{");
+            if let Some(certificate) = options.certificate.as_ref().or(self.certificate.as_ref()) {
+                let _ = writeln!(
+                    config,
+                    "tls {} {}",
+                    certificate.cert_file.display(),
+                    certificate.key_file.display()
+                );
+            } else if let Some(email) = &self.email {
+                let _ = writeln!(config, "tls {email}");
+            }
+            let _ = write!(config, "{}", limits.for_app(app_id).directives(app_id));
+            let upstream = format!("{ip}:{}", entry.internal_port);
+            let _ = writeln!(config, "{}", limits.reverse_proxy(app_id, &upstream));
+            let _ = writeln!(config, "}}");
+        }
+        config
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{HttpMode, HttpsOptions};
+    use crate::cli::rate_limits::RateLimitConfig;
+    use crate::composegenerator::types::CaddyEntry;
+    use std::collections::HashMap;
+
+    #[test]
+    fn resolves_http_modes() {
+        let options: HttpsOptions = serde_json::from_str(
+            r#"{
+                "httpMode": "redirect",
+                "apps": { "btc-rpc-explorer": { "httpMode": "httpOnly" } },
+                "acmeCa": "https://acme.example.com/directory"
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(options.http_mode("lnd"), HttpMode::Redirect);
+        assert_eq!(options.http_mode("btc-rpc-explorer"), HttpMode::HttpOnly);
+        // Unknown options are kept for the template
+        assert_eq!(
+            serde_json::to_value(&options).unwrap()["acmeCa"],
+            "https://acme.example.com/directory"
+        );
+        assert_eq!(HttpsOptions::default().http_mode("lnd"), HttpMode::Both);
+    }
+
+    #[test]
+    fn generates_domain_config() {
+        let options: HttpsOptions = serde_json::from_str(
+            r#"{
+                "email": "admin@example.com",
+                "apps": {
+                    "lnd": { "domain": "lnd.example.com" },
+                    "mempool": { "domain": "mempool.example.com", "httpMode": "both" }
+                }
+            }"#,
+        )
+        .unwrap();
+        options.validate().unwrap();
+        let caddy_entries = HashMap::from([(
+            "lnd".to_string(),
+            vec![CaddyEntry {
+                public_port: 3000,
+                internal_port: 8080,
+                container_name: "main".to_string(),
+                is_primary: true,
+                priority: 0,
+            }],
+        )]);
+        let ip_map = HashMap::from([("APP_LND_MAIN_IP".to_string(), "10.21.21.9".to_string())]);
+        let config =
+            options.generate_caddy_config(&caddy_entries, &ip_map, &RateLimitConfig::default());
+        assert_eq!(
+            config,
+            "http://lnd.example.com, https://lnd.example.com {\ntls admin@example.com\nreverse_proxy 10.21.21.9:8080\n}\n"
+        );
+
+        let invalid: HttpsOptions =
+            serde_json::from_str(r#"{ "apps": { "lnd": { "domain": "-lnd.example.com" } } }"#)
+                .unwrap();
+        assert!(invalid.validate().is_err());
+    }
+}
@@ -0,0 +1,101 @@
+use std::path::Path;
+
+use anyhow::Result;
+
+use crate::naming;
+
+const BASE32_ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+/// Encodes bytes as lowercase base32 without padding, like in .b32.i2p addresses
+fn base32(bytes: &[u8]) -> String {
+    let mut result = String::with_capacity((bytes.len() * 8).div_ceil(5));
+    let mut buffer = 0u16;
+    let mut bits = 0;
+    for byte in bytes {
+        buffer = (buffer << 8) | u16::from(*byte);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            result.push(BASE32_ALPHABET[usize::from((buffer >> bits) & 31)] as char);
+        }
+    }
+    if bits > 0 {
+        result.push(BASE32_ALPHABET[usize::from((buffer << (5 - bits)) & 31)] as char);
+    }
+    result
+}
+
+/// The .b32.i2p address of a destination, from the private keys file i2pd writes for it
+pub fn b32_address(keys: &[u8]) -> Option<String> {
+    // The destination is a 256 byte public key and a 128 byte signing key,
+    // followed by a certificate with a 1 byte type and a 2 byte payload length
+    let cert_len = usize::from(u16::from_be_bytes([*keys.get(385)?, *keys.get(386)?]));
+    let destination = keys.get(..387 + cert_len)?;
+    Some(format!(
+        "{}.b32.i2p",
+        base32(&hmac_sha256::Hash::hash(destination))
+    ))
+}
+
+/// The env var with the address of a destination of an app, None is the app's main destination
+pub fn env_var(app_id: &str, destination: Option<&str>) -> String {
+    match destination {
+        Some(destination) => format!(
+            "{}{}_I2P",
+            naming::env_prefix(app_id),
+            naming::env_name(destination)
+        ),
+        None => format!("APP_{}_I2P", naming::env_name(app_id)),
+    }
+}
+
+/// Reads the addresses of an app's I2P destinations from the keys files in `i2p_dir`, env var -> address
+pub fn app_addresses(app_id: &str, i2p_dir: &Path) -> Result<Vec<(String, String)>> {
+    let mut addresses = Vec::new();
+    if !i2p_dir.is_dir() {
+        return Ok(addresses);
+    }
+    let main_destination = format!("app-{app_id}");
+    let prefix = format!("app-{app_id}-");
+    for entry in std::fs::read_dir(i2p_dir)? {
+        let path = entry?.path();
+        if path.extension().unwrap_or_default() != "dat" {
+            continue;
+        }
+        let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) else {
+            continue;
+        };
+        let key = if name == main_destination {
+            env_var(app_id, None)
+        } else if let Some(destination) = name.strip_prefix(&prefix) {
+            env_var(app_id, Some(destination))
+        } else {
+            continue;
+        };
+        match b32_address(&std::fs::read(&path)?) {
+            Some(address) => addresses.push((key, address)),
+            None => tracing::warn!("Invalid I2P keys file {}", path.display()),
+        }
+    }
+    Ok(addresses)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{b32_address, base32, env_var};
+
+    #[test]
+    fn derives_b32_addresses() {
+        assert_eq!(base32(b"foobar"), "mzxw6ytboi");
+        let mut keys = vec![0; 384];
+        // A key certificate with a 4 byte payload, followed by (truncated) private keys
+        keys.extend([5, 0, 4, 0, 7, 0, 0, 1, 2, 3]);
+        assert_eq!(
+            b32_address(&keys).as_deref(),
+            Some("yki53a2rw6v5eakh6nan3tj46xhnbsutmbktawuaz7d7l5r5smca.b32.i2p")
+        );
+        assert_eq!(b32_address(&keys[..388]), None);
+        assert_eq!(env_var("my-app", None), "APP_MY_APP_I2P");
+        assert_eq!(env_var("my-app", Some("rpc")), "APP_MY_APP_RPC_I2P");
+    }
+}
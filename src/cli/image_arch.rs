@@ -0,0 +1,128 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    host_facts::HostFacts,
+    image_registry::{ImageReference, Manifest, RegistryClient},
+    registries::RegistriesConfig,
+};
+use crate::composegenerator::types::UnsupportedReason;
+
+/// Checks of the images used by apps in app-manager.toml
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct ImageChecksConfig {
+    /// Query the registries for the platforms of all images
+    /// and mark apps as unsupported if one of them is not available for this node
+    pub check_architecture: bool,
+    /// The Docker name of the node's architecture, like arm64. Taken from the host facts if not set.
+    pub architecture: Option<String>,
+}
+
+/// The Docker name of the architecture app-manager was built for
+pub fn host_architecture() -> &'static str {
+    match std::env::consts::ARCH {
+        "x86_64" => "amd64",
+        "aarch64" => "arm64",
+        "x86" => "386",
+        "powerpc64" => "ppc64le",
+        other => other,
+    }
+}
+
+/// Looks up which architectures images are available for and remembers them for this run
+pub struct ArchitectureChecker {
+    client: RegistryClient,
+    architecture: String,
+    /// Image -> its architectures, None if they could not be determined
+    known: HashMap<String, Option<Vec<String>>>,
+}
+
+impl ArchitectureChecker {
+    pub fn new(
+        config: &ImageChecksConfig,
+        registries: &RegistriesConfig,
+        host: &HostFacts,
+    ) -> Result<Self> {
+        Ok(Self {
+            client: RegistryClient::new(registries)?,
+            architecture: config
+                .architecture
+                .clone()
+                .unwrap_or_else(|| host.architecture.clone()),
+            known: HashMap::new(),
+        })
+    }
+
+    fn fetch_architectures(&self, image: &str) -> Result<Vec<String>> {
+        let image = ImageReference::parse(image);
+        let manifest = self.client.manifest(&image, &image.reference)?;
+        if let Some(config) = &manifest.config {
+            // A single-platform image, its platform is only stored in the config
+            return Ok(vec![self.client.platform(&image, config)?.architecture]);
+        }
+        Ok(architectures(manifest))
+    }
+
+    /// Returns why the image of a container can't run on this node, if it can't.
+    /// Images whose manifests can't be fetched are not flagged.
+    pub fn check(&mut self, container: &str, image: &str) -> Option<UnsupportedReason> {
+        if !self.known.contains_key(image) {
+            let architectures = match self.fetch_architectures(image) {
+                Ok(architectures) => Some(architectures),
+                Err(error) => {
+                    tracing::warn!("Failed to check the platforms of {}: {:#}", image, error);
+                    None
+                }
+            };
+            self.known.insert(image.to_string(), architectures);
+        }
+        let architectures = self.known[image].as_ref()?;
+        if architectures.contains(&self.architecture) {
+            return None;
+        }
+        Some(UnsupportedReason::ArchitectureMismatch {
+            container: container.to_string(),
+            image: image.to_string(),
+            architecture: self.architecture.clone(),
+            available: architectures.clone(),
+        })
+    }
+}
+
+/// The architectures listed in an image index, without attestation entries
+fn architectures(manifest: Manifest) -> Vec<String> {
+    let mut architectures: Vec<String> = manifest
+        .manifests
+        .into_iter()
+        .filter_map(|entry| entry.platform)
+        .map(|platform| platform.architecture)
+        .filter(|architecture| architecture != "unknown")
+        .collect();
+    architectures.sort();
+    architectures.dedup();
+    architectures
+}
+
+#[cfg(test)]
+mod test {
+    use super::{architectures, Manifest};
+
+    #[test]
+    fn reads_image_platforms() {
+        let manifest: Manifest = serde_json::from_str(
+            r#"{
+                "mediaType": "application/vnd.oci.image.index.v1+json",
+                "manifests": [
+                    {"digest": "sha256:1", "platform": {"architecture": "amd64", "os": "linux"}},
+                    {"digest": "sha256:2", "platform": {"architecture": "arm", "os": "linux", "variant": "v7"}},
+                    {"digest": "sha256:3", "platform": {"architecture": "unknown", "os": "unknown"}}
+                ]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(architectures(manifest), vec!["amd64", "arm"]);
+    }
+}
@@ -0,0 +1,234 @@
+use anyhow::{bail, Context, Result};
+use reqwest::{
+    blocking::Client,
+    header::{ACCEPT, WWW_AUTHENTICATE},
+    StatusCode,
+};
+use serde::Deserialize;
+
+use super::registries::RegistriesConfig;
+
+const MANIFEST_TYPES: &str = "application/vnd.oci.image.index.v1+json, application/vnd.docker.distribution.manifest.list.v2+json, application/vnd.oci.image.manifest.v1+json, application/vnd.docker.distribution.manifest.v2+json";
+
+/// An image reference split into the parts the registry API needs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageReference {
+    pub registry: String,
+    pub repository: String,
+    /// A digest if the image is pinned, otherwise the tag
+    pub reference: String,
+}
+
+impl ImageReference {
+    pub fn parse(image: &str) -> Self {
+        let (name, digest) = match image.split_once('@') {
+            Some((name, digest)) => (name, Some(digest)),
+            None => (image, None),
+        };
+        // A colon after the last slash separates the tag, a colon before it belongs to the registry port
+        let (name, tag) = match name.rsplit_once(':') {
+            Some((repo, tag)) if !tag.contains('/') => (repo, tag),
+            _ => (name, "latest"),
+        };
+        let (registry, repository) = match name.split_once('/') {
+            Some((first, rest))
+                if first.contains('.') || first.contains(':') || first == "localhost" =>
+            {
+                (first.to_string(), rest.to_string())
+            }
+            _ if name.contains('/') => ("registry-1.docker.io".to_string(), name.to_string()),
+            _ => (
+                "registry-1.docker.io".to_string(),
+                format!("library/{name}"),
+            ),
+        };
+        Self {
+            registry: if registry == "docker.io" {
+                "registry-1.docker.io".to_string()
+            } else {
+                registry
+            },
+            repository,
+            reference: digest.unwrap_or(tag).to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Platform {
+    pub architecture: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct IndexEntry {
+    pub digest: String,
+    pub platform: Option<Platform>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Descriptor {
+    pub digest: String,
+    #[serde(default)]
+    pub size: u64,
+}
+
+/// The parts of an image index or image manifest app-manager uses
+#[derive(Deserialize, Debug)]
+pub struct Manifest {
+    /// The platform-specific manifests of an image index
+    #[serde(default)]
+    pub manifests: Vec<IndexEntry>,
+    /// The config of a single-platform image
+    pub config: Option<Descriptor>,
+    #[serde(default)]
+    pub layers: Vec<Descriptor>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    #[serde(alias = "access_token")]
+    token: String,
+}
+
+/// Extracts a parameter like realm from a WWW-Authenticate header
+fn auth_param<'a>(header: &'a str, name: &str) -> Option<&'a str> {
+    let start = header.find(&format!("{name}=\""))? + name.len() + 2;
+    let len = header[start..].find('"')?;
+    Some(&header[start..start + len])
+}
+
+/// A client for the registry API, which uses the configured mirrors and credentials
+pub struct RegistryClient {
+    client: Client,
+    registries: RegistriesConfig,
+}
+
+impl RegistryClient {
+    pub fn new(registries: &RegistriesConfig) -> Result<Self> {
+        Ok(Self {
+            client: Client::builder()
+                .user_agent(concat!("citadel-app-manager/", env!("CARGO_PKG_VERSION")))
+                .build()?,
+            registries: registries.clone(),
+        })
+    }
+
+    /// Sends a request, authenticating if the registry asks for it
+    fn get(&self, registry: &str, url: &str, accept: &str) -> Result<String> {
+        let response = self.client.get(url).header(ACCEPT, accept).send()?;
+        if response.status() != StatusCode::UNAUTHORIZED {
+            return Ok(response.error_for_status()?.text()?);
+        }
+        let Some(challenge) = response
+            .headers()
+            .get(WWW_AUTHENTICATE)
+            .and_then(|header| header.to_str().ok())
+        else {
+            bail!("The registry requires authentication");
+        };
+        let credentials = self.registries.credentials(registry);
+        if challenge.starts_with("Basic") {
+            let Some(credentials) = credentials else {
+                bail!("The registry requires credentials, but none are configured for {registry}");
+            };
+            return Ok(self
+                .client
+                .get(url)
+                .header(ACCEPT, accept)
+                .basic_auth(&credentials.username, Some(&credentials.password))
+                .send()?
+                .error_for_status()?
+                .text()?);
+        }
+        let Some(realm) = auth_param(challenge, "realm") else {
+            bail!("Unsupported authentication challenge: {}", challenge);
+        };
+        let mut query = Vec::new();
+        for param in ["service", "scope"] {
+            if let Some(value) = auth_param(challenge, param) {
+                query.push((param, value));
+            }
+        }
+        let mut token_request = self.client.get(realm).query(&query);
+        // Without credentials, registries hand out anonymous tokens for public images
+        if let Some(credentials) = credentials {
+            token_request =
+                token_request.basic_auth(&credentials.username, Some(&credentials.password));
+        }
+        let token: TokenResponse =
+            serde_json::from_str(&token_request.send()?.error_for_status()?.text()?)
+                .context("Failed to parse the token response")?;
+        Ok(self
+            .client
+            .get(url)
+            .header(ACCEPT, accept)
+            .bearer_auth(token.token)
+            .send()?
+            .error_for_status()?
+            .text()?)
+    }
+
+    /// Fetches the manifest of an image, `reference` is a tag or digest
+    pub fn manifest(&self, image: &ImageReference, reference: &str) -> Result<Manifest> {
+        let image = self.registries.mirror(image);
+        serde_json::from_str(&self.get(
+            &image.registry,
+            &format!(
+                "https://{}/v2/{}/manifests/{}",
+                image.registry, image.repository, reference
+            ),
+            MANIFEST_TYPES,
+        )?)
+        .context("Failed to parse the image manifest")
+    }
+
+    /// Fetches the platform from the config of a single-platform image
+    pub fn platform(&self, image: &ImageReference, config: &Descriptor) -> Result<Platform> {
+        let image = self.registries.mirror(image);
+        serde_json::from_str(&self.get(
+            &image.registry,
+            &format!(
+                "https://{}/v2/{}/blobs/{}",
+                image.registry, image.repository, config.digest
+            ),
+            "*/*",
+        )?)
+        .context("Failed to parse the image config")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{auth_param, ImageReference};
+
+    #[test]
+    fn parses_image_references() {
+        assert_eq!(
+            ImageReference::parse("nginx"),
+            ImageReference {
+                registry: "registry-1.docker.io".to_string(),
+                repository: "library/nginx".to_string(),
+                reference: "latest".to_string(),
+            }
+        );
+        assert_eq!(
+            ImageReference::parse("localhost:5000/lncm/bitcoind:v25.0@sha256:abc"),
+            ImageReference {
+                registry: "localhost:5000".to_string(),
+                repository: "lncm/bitcoind".to_string(),
+                reference: "sha256:abc".to_string(),
+            }
+        );
+        assert_eq!(
+            ImageReference::parse("ghcr.io/runcitadel/lnd:v0.16").repository,
+            "runcitadel/lnd"
+        );
+        assert_eq!(
+            auth_param(
+                r#"Bearer realm="https://ghcr.io/token",service="ghcr.io",scope="repository:runcitadel/lnd:pull""#,
+                "scope"
+            ),
+            Some("repository:runcitadel/lnd:pull")
+        );
+    }
+}
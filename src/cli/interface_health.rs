@@ -0,0 +1,296 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{BufRead, BufReader, Write},
+    net::{IpAddr, SocketAddr, TcpStream},
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use super::{node_config::NodeConfig, paths::CitadelPaths, registry::RegistryWriter, UserJson};
+use crate::{
+    composegenerator::{
+        types::{InterfaceHealth, OutputMetadata},
+        v4::types::PortMapElement,
+    },
+    naming,
+};
+
+/// Interface probe settings in app-manager.toml
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct InterfaceProbeConfig {
+    /// How long to wait for an app to respond, in seconds
+    pub timeout: u64,
+    /// The interfaces to probe, all interfaces are probed if this is empty
+    pub interfaces: Vec<String>,
+}
+
+impl Default for InterfaceProbeConfig {
+    fn default() -> Self {
+        Self {
+            timeout: 5,
+            interfaces: Vec::new(),
+        }
+    }
+}
+
+/// Written to apps/events.jsonl when the health of an interface changes
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "event", rename_all = "camelCase")]
+pub enum HealthEvent {
+    #[serde(rename_all = "camelCase")]
+    InterfaceHealthy {
+        app: String,
+        interface: String,
+        time: u64,
+    },
+    #[serde(rename_all = "camelCase")]
+    InterfaceUnhealthy {
+        app: String,
+        interface: String,
+        error: String,
+        time: u64,
+    },
+}
+
+/// Loads the result of the last probe, app ID -> health of the interface it implements
+pub fn load_health(
+    paths: &CitadelPaths,
+    citadel_root: &Path,
+) -> Result<BTreeMap<String, InterfaceHealth>> {
+    let health_file = citadel_root.join("apps").join("interface-health.json");
+    if !paths.exists(&health_file) {
+        return Ok(BTreeMap::new());
+    }
+    serde_json::from_str(&paths.read_to_string(&health_file)?)
+        .context("Failed to load interface-health.json")
+}
+
+/// Checks that an app responds on an interface's port.
+/// Electrum servers also have to answer a ping, so a port that accepts connections
+/// without a working server behind it is detected too.
+fn probe(interface: &str, address: SocketAddr, timeout: Duration) -> Result<()> {
+    let mut stream = TcpStream::connect_timeout(&address, timeout)
+        .with_context(|| format!("Nothing responds on {address}"))?;
+    if interface == "electrum" {
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        stream.write_all(
+            b"{\"jsonrpc\":\"2.0\",\"id\":0,\"method\":\"server.ping\",\"params\":[]}\n",
+        )?;
+        let mut response = String::new();
+        BufReader::new(stream)
+            .read_line(&mut response)
+            .with_context(|| format!("No response to server.ping on {address}"))?;
+        let response: serde_json::Value = serde_json::from_str(&response)
+            .with_context(|| format!("Invalid response to server.ping on {address}"))?;
+        if response.get("result").is_none() {
+            bail!("server.ping failed on {address}: {}", response["error"]);
+        }
+    }
+    Ok(())
+}
+
+/// Probes every port mapped for an interface on the service container of the implementing app
+fn probe_app(
+    app_id: &str,
+    interface: &str,
+    ip_map: &HashMap<String, String>,
+    port_map: &HashMap<String, HashMap<String, Vec<PortMapElement>>>,
+    timeout: Duration,
+) -> Result<()> {
+    let ip_var = naming::ip_env_var(app_id, "service");
+    let ip: IpAddr = ip_map
+        .get(&ip_var)
+        .with_context(|| format!("{ip_var} is not set"))?
+        .parse()
+        .with_context(|| format!("{ip_var} is not a valid IP address"))?;
+    let ports = port_map
+        .get(interface)
+        .and_then(|containers| containers.get("service"))
+        .filter(|ports| !ports.is_empty())
+        .with_context(|| format!("No port is mapped for {interface}"))?;
+    for port in ports {
+        probe(interface, SocketAddr::new(ip, port.internal_port), timeout)?;
+    }
+    Ok(())
+}
+
+/// Whether an app responds on an interface and can start serving it
+pub fn is_ready(
+    app_id: &str,
+    interface: &str,
+    ip_map: &HashMap<String, String>,
+    port_map: &HashMap<String, HashMap<String, Vec<PortMapElement>>>,
+    config: &InterfaceProbeConfig,
+) -> bool {
+    let result = probe_app(
+        app_id,
+        interface,
+        ip_map,
+        port_map,
+        Duration::from_secs(config.timeout),
+    );
+    if let Err(err) = &result {
+        tracing::info!(
+            "App {} is not ready to serve {}: {:#}",
+            app_id,
+            interface,
+            err
+        );
+    }
+    result.is_ok()
+}
+
+/// Sets the health of every app in registry.json
+fn update_registry(
+    paths: &CitadelPaths,
+    apps_dir: &Path,
+    health: &BTreeMap<String, InterfaceHealth>,
+) -> Result<()> {
+    let registry_file = apps_dir.join("registry.json");
+    let registry: Vec<OutputMetadata> =
+        serde_json::from_str(&paths.read_to_string(&registry_file)?)
+            .context("Failed to load registry.json")?;
+    let mut writer = RegistryWriter::create(
+        &paths.write_path(&registry_file)?,
+        &paths.write_path(&apps_dir.join("registry.index.json"))?,
+    )?;
+    for mut metadata in registry {
+        metadata.health = health.get(&metadata.id).cloned();
+        writer.push(&metadata)?;
+    }
+    writer.finish()
+}
+
+/// Probes the interfaces implemented by installed apps, like electrum.
+/// The result is stored in the registry, and an event is written to apps/events.jsonl for every change.
+/// Returns false if any interface is unhealthy.
+pub fn probe_interfaces(citadel_root: &str, state_dir: &Option<String>) -> Result<bool> {
+    let citadel_root = Path::new(citadel_root);
+    let paths = CitadelPaths::new(citadel_root, state_dir.as_deref().map(Path::new));
+    let config = NodeConfig::load(&paths, citadel_root)?.interface_probes;
+    let apps_dir = citadel_root.join("apps");
+    let virtual_apps: HashMap<String, Vec<String>> =
+        serde_json::from_str(&paths.read_to_string(&apps_dir.join("virtual-apps.json"))?)
+            .context("Failed to load virtual-apps.json")?;
+    let port_map: HashMap<String, HashMap<String, Vec<PortMapElement>>> =
+        serde_yaml::from_reader(paths.open(&apps_dir.join("ports.yml"))?)
+            .context("Failed to load ports.yml")?;
+    let ip_map: HashMap<String, String> =
+        serde_yaml::from_reader(paths.open(&apps_dir.join("ips.yml"))?)
+            .context("Failed to load ips.yml")?;
+    let installed_apps = paths
+        .open(&citadel_root.join("db").join("user.json"))
+        .ok()
+        .and_then(|user_json| serde_json::from_reader::<_, UserJson>(user_json).ok())
+        .map(|user_json| user_json.installed_apps)
+        .unwrap_or_default();
+
+    let previous = load_health(&paths, citadel_root)?;
+    let timeout = Duration::from_secs(config.timeout);
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let mut health = BTreeMap::new();
+    let mut events = Vec::new();
+    for (interface, implementations) in &virtual_apps {
+        if !config.interfaces.is_empty() && !config.interfaces.contains(interface) {
+            continue;
+        }
+        for app_id in implementations
+            .iter()
+            .filter(|app_id| installed_apps.contains(app_id))
+        {
+            let result = probe_app(app_id, interface, &ip_map, &port_map, timeout);
+            let was_healthy = previous.get(app_id).is_none_or(|old| old.healthy);
+            match &result {
+                Ok(()) if !was_healthy => {
+                    tracing::info!("App {} responds on {} again", app_id, interface);
+                    events.push(HealthEvent::InterfaceHealthy {
+                        app: app_id.clone(),
+                        interface: interface.clone(),
+                        time: now,
+                    });
+                }
+                Err(err) => {
+                    tracing::warn!(
+                        "App {} does not respond on {}: {:#}",
+                        app_id,
+                        interface,
+                        err
+                    );
+                    if was_healthy {
+                        events.push(HealthEvent::InterfaceUnhealthy {
+                            app: app_id.clone(),
+                            interface: interface.clone(),
+                            error: format!("{err:#}"),
+                            time: now,
+                        });
+                    }
+                }
+                _ => {}
+            }
+            health.insert(
+                app_id.clone(),
+                InterfaceHealth {
+                    interface: interface.clone(),
+                    healthy: result.is_ok(),
+                    error: result.err().map(|err| format!("{err:#}")),
+                    checked_at: now,
+                },
+            );
+        }
+    }
+
+    paths.write(
+        &apps_dir.join("interface-health.json"),
+        serde_json::to_string(&health)?,
+    )?;
+    if !events.is_empty() {
+        let mut events_file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(paths.write_path(&apps_dir.join("events.jsonl"))?)
+            .context("Failed to open events.jsonl")?;
+        for event in &events {
+            writeln!(events_file, "{}", serde_json::to_string(event)?)?;
+        }
+    }
+    update_registry(&paths, &apps_dir, &health)?;
+    Ok(health.values().all(|health| health.healthy))
+}
+
+#[cfg(test)]
+mod test {
+    use super::probe;
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        time::Duration,
+    };
+
+    #[test]
+    fn probes_electrum_servers() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut request = String::new();
+            BufReader::new(&stream).read_line(&mut request).unwrap();
+            assert!(request.contains("server.ping"));
+            (&stream)
+                .write_all(b"{\"jsonrpc\":\"2.0\",\"id\":0,\"result\":null}\n")
+                .unwrap();
+        });
+        probe("electrum", address, Duration::from_secs(5)).unwrap();
+        server.join().unwrap();
+
+        // Nothing listens on the port anymore
+        assert!(probe("electrum", address, Duration::from_secs(1)).is_err());
+    }
+}
@@ -0,0 +1,222 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt,
+    net::Ipv4Addr,
+};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// Addresses below this are used by Citadel's own containers
+const FIRST_SUFFIX: u8 = 20;
+const LAST_SUFFIX: u8 = 254;
+
+/// How many IPs app containers can get
+pub fn pool_size() -> usize {
+    (LAST_SUFFIX - FIRST_SUFFIX) as usize + 1
+}
+
+/// The /24 subnet app containers get their IPs in, like 10.21.21.0/24
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct Subnet([u8; 3]);
+
+impl Default for Subnet {
+    fn default() -> Self {
+        Self([10, 21, 21])
+    }
+}
+
+impl TryFrom<String> for Subnet {
+    type Error = anyhow::Error;
+
+    fn try_from(subnet: String) -> Result<Self> {
+        let Some(address) = subnet.strip_suffix("/24") else {
+            bail!("Subnet {subnet} is not a /24 subnet");
+        };
+        let address: Ipv4Addr = address
+            .parse()
+            .with_context(|| format!("Subnet {subnet} is not an IPv4 subnet"))?;
+        let [a, b, c, d] = address.octets();
+        if d != 0 {
+            bail!("Subnet {subnet} must end with .0/24");
+        }
+        Ok(Self([a, b, c]))
+    }
+}
+
+impl From<Subnet> for String {
+    fn from(subnet: Subnet) -> Self {
+        subnet.to_string()
+    }
+}
+
+impl fmt::Display for Subnet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c] = self.0;
+        write!(f, "{a}.{b}.{c}.0/24")
+    }
+}
+
+impl Subnet {
+    fn suffix(&self, ip: &str) -> Option<u8> {
+        let [a, b, c, d] = ip.parse::<Ipv4Addr>().ok()?.octets();
+        ([a, b, c] == self.0).then_some(d)
+    }
+
+    fn ip(&self, suffix: u8) -> String {
+        let [a, b, c] = self.0;
+        format!("{a}.{b}.{c}.{suffix}")
+    }
+
+    /// Whether an IP is in the range app containers get their IPs from
+    pub fn is_app_ip(&self, ip: &str) -> bool {
+        self.suffix(ip)
+            .is_some_and(|suffix| (FIRST_SUFFIX..=LAST_SUFFIX).contains(&suffix))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum IpScheme {
+    /// Containers get the next free IP in the order they are first seen
+    #[default]
+    Sequential,
+    /// The IP is derived from a hash of the app and container name, so an app gets the same IPs
+    /// when it is installed again after ips.yml was reset. Collisions fall back to sequential IPs.
+    Hashed,
+}
+
+/// IP assignment settings in app-manager.toml
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct IpAssignmentConfig {
+    pub scheme: IpScheme,
+    /// Changing it does not move existing containers, they keep their IPs in ips.yml
+    pub subnet: Subnet,
+}
+
+/// Hands out IPs for containers which don't have one in ips.yml yet
+pub struct IpAllocator {
+    scheme: IpScheme,
+    subnet: Subnet,
+    used: BTreeSet<u8>,
+    next_suffix: u16,
+}
+
+impl IpAllocator {
+    /// `ip_map` are the IPs assigned so far, env var -> IP
+    pub fn new(config: &IpAssignmentConfig, ip_map: &HashMap<String, String>) -> Self {
+        let subnet = config.subnet;
+        // IPs outside of the subnet are left from before it was changed
+        let used: BTreeSet<u8> = ip_map.values().filter_map(|ip| subnet.suffix(ip)).collect();
+        Self {
+            scheme: config.scheme,
+            subnet,
+            next_suffix: FIRST_SUFFIX as u16 + used.len() as u16,
+            used,
+        }
+    }
+
+    fn hashed_suffix(ip_name: &str) -> u8 {
+        let hash = hmac_sha256::Hash::hash(ip_name.as_bytes());
+        let range = (LAST_SUFFIX - FIRST_SUFFIX) as u32 + 1;
+        let value = u32::from_be_bytes([hash[0], hash[1], hash[2], hash[3]]);
+        FIRST_SUFFIX + (value % range) as u8
+    }
+
+    fn sequential_suffix(&mut self) -> Result<u8> {
+        while self.next_suffix <= LAST_SUFFIX as u16 {
+            let suffix = self.next_suffix as u8;
+            self.next_suffix += 1;
+            if !self.used.contains(&suffix) {
+                return Ok(suffix);
+            }
+        }
+        // Earlier addresses may have been skipped because they were used by hashed IPs
+        match (FIRST_SUFFIX..=LAST_SUFFIX).find(|suffix| !self.used.contains(suffix)) {
+            Some(suffix) => Ok(suffix),
+            None => bail!("Too many apps, no IP is left in {}", self.subnet),
+        }
+    }
+
+    /// Assigns an IP to the container the env var `ip_name` (like APP_LND_WEB_IP) belongs to
+    pub fn allocate(&mut self, ip_name: &str) -> Result<String> {
+        let suffix = match self.scheme {
+            IpScheme::Hashed => {
+                let suffix = Self::hashed_suffix(ip_name);
+                if self.used.contains(&suffix) {
+                    tracing::debug!(
+                        "The hashed IP of {} is already used, assigning the next free one",
+                        ip_name
+                    );
+                    self.sequential_suffix()?
+                } else {
+                    suffix
+                }
+            }
+            IpScheme::Sequential => self.sequential_suffix()?,
+        };
+        self.used.insert(suffix);
+        Ok(self.subnet.ip(suffix))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{IpAllocator, IpAssignmentConfig, IpScheme};
+    use std::collections::HashMap;
+
+    #[test]
+    fn assigns_stable_ips() {
+        let sequential_config = IpAssignmentConfig::default();
+        let hashed_config = IpAssignmentConfig {
+            scheme: IpScheme::Hashed,
+            ..Default::default()
+        };
+        let mut sequential = IpAllocator::new(&sequential_config, &HashMap::new());
+        assert_eq!(
+            sequential.allocate("APP_LND_WEB_IP").unwrap(),
+            "10.21.21.20"
+        );
+        assert_eq!(
+            sequential.allocate("APP_LND_API_IP").unwrap(),
+            "10.21.21.21"
+        );
+
+        let hashed_ip = IpAllocator::new(&hashed_config, &HashMap::new())
+            .allocate("APP_LND_WEB_IP")
+            .unwrap();
+        // Other apps don't change the IP
+        let ip_map = HashMap::from([("APP_BTCPAY_WEB_IP".to_string(), "10.21.21.20".to_string())]);
+        let mut hashed = IpAllocator::new(&hashed_config, &ip_map);
+        assert_eq!(hashed.allocate("APP_LND_WEB_IP").unwrap(), hashed_ip);
+
+        // On a collision, the next free IP is used
+        let ip_map = HashMap::from([("APP_OTHER_WEB_IP".to_string(), hashed_ip.clone())]);
+        let mut hashed = IpAllocator::new(&hashed_config, &ip_map);
+        let fallback_ip = hashed.allocate("APP_LND_WEB_IP").unwrap();
+        assert_ne!(fallback_ip, hashed_ip);
+        assert!(fallback_ip.starts_with("10.21.21."));
+    }
+
+    #[test]
+    fn assigns_ips_in_configured_subnet() {
+        let config: IpAssignmentConfig = toml::from_str("subnet = \"10.30.0.0/24\"").unwrap();
+        assert_eq!(config.subnet.to_string(), "10.30.0.0/24");
+        assert!(config.subnet.is_app_ip("10.30.0.20"));
+        assert!(!config.subnet.is_app_ip("10.21.21.20"));
+        assert!(!config.subnet.is_app_ip("10.30.0.2"));
+
+        // IPs from the previous subnet don't take up IPs in the new one
+        let ip_map = HashMap::from([("APP_LND_WEB_IP".to_string(), "10.21.21.20".to_string())]);
+        let mut allocator = IpAllocator::new(&config, &ip_map);
+        assert_eq!(allocator.allocate("APP_LND_API_IP").unwrap(), "10.30.0.20");
+
+        for subnet in ["10.30.0.0/16", "10.30.0.1/24", "fd00::/24", "10.30.0/24"] {
+            assert!(
+                toml::from_str::<IpAssignmentConfig>(&format!("subnet = \"{subnet}\"")).is_err()
+            );
+        }
+    }
+}
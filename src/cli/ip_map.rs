@@ -0,0 +1,237 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Result};
+use serde::Serialize;
+
+use super::{
+    app_list::{app_ids, installed_apps, load_ips},
+    ip_assignment::IpAllocator,
+    node_config::NodeConfig,
+    paths::CitadelPaths,
+    template_env::{EnvScope, TemplateEnvConfig},
+};
+use crate::naming;
+
+/// An IP in ips.yml
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct IpEntry {
+    pub ip: String,
+    /// The env var of the container, like APP_LND_WEB_IP
+    pub var: String,
+    /// None if no app in the apps dir generates the env var
+    pub app: Option<String>,
+    pub installed: bool,
+}
+
+impl IpEntry {
+    /// The app no longer exists, the IP can be freed
+    pub fn is_stale(&self) -> bool {
+        self.app.is_none()
+    }
+}
+
+fn ips_file(citadel_root: &Path) -> PathBuf {
+    citadel_root.join("apps").join("ips.yml")
+}
+
+fn save(paths: &CitadelPaths, citadel_root: &Path, ips: &BTreeMap<String, String>) -> Result<()> {
+    paths.write_atomic(&ips_file(citadel_root), serde_yaml::to_string(ips)?)
+}
+
+fn entries(
+    ips: &BTreeMap<String, String>,
+    app_ids: &[String],
+    installed_apps: &[String],
+) -> Vec<IpEntry> {
+    let scope = EnvScope::new(
+        app_ids.iter().map(String::as_str),
+        &TemplateEnvConfig::default(),
+    );
+    let mut entries: Vec<IpEntry> = ips
+        .iter()
+        .map(|(var, ip)| {
+            let app = scope.owner(var).map(str::to_string);
+            IpEntry {
+                ip: ip.clone(),
+                var: var.clone(),
+                installed: app.as_ref().is_some_and(|app| installed_apps.contains(app)),
+                app,
+            }
+        })
+        .collect();
+    entries.sort_by_key(|entry| entry.ip.parse::<Ipv4Addr>().ok());
+    entries
+}
+
+/// Lists all IPs in ips.yml, ordered by IP
+pub fn list(citadel_root: &str, state_dir: &Option<String>) -> Result<Vec<IpEntry>> {
+    let citadel_root = Path::new(citadel_root);
+    let paths = CitadelPaths::new(citadel_root, state_dir.as_deref().map(Path::new));
+    let apps_dir = citadel_root.join("apps");
+    let app_ids: Vec<String> = app_ids(&apps_dir)?.into_iter().collect();
+    Ok(entries(
+        &load_ips(&paths, &apps_dir)?,
+        &app_ids,
+        &installed_apps(&paths, citadel_root),
+    ))
+}
+
+/// Assigns an IP to a container of an app, the next free one if `ip` is None.
+/// The container gets it when the app is converted and started again. Returns the IP.
+pub fn assign(
+    citadel_root: &str,
+    state_dir: &Option<String>,
+    app_id: &str,
+    container: &str,
+    ip: Option<&str>,
+) -> Result<String> {
+    let citadel_root = Path::new(citadel_root);
+    let paths = CitadelPaths::new(citadel_root, state_dir.as_deref().map(Path::new));
+    let apps_dir = citadel_root.join("apps");
+    if !app_ids(&apps_dir)?.contains(app_id) {
+        bail!("App {app_id} does not exist");
+    }
+    let var = naming::ip_env_var(app_id, container);
+    let mut ips = load_ips(&paths, &apps_dir)?;
+    let config = NodeConfig::load(&paths, citadel_root)?.ip_assignment;
+    let ip = match ip {
+        Some(ip) => {
+            if !config.subnet.is_app_ip(ip) {
+                bail!("{ip} is not in the range app containers get their IPs from");
+            }
+            if let Some((other_var, _)) = ips
+                .iter()
+                .find(|(other_var, other_ip)| *other_ip == ip && **other_var != var)
+            {
+                bail!("{ip} is already assigned to {other_var}");
+            }
+            ip.to_string()
+        }
+        None => {
+            if let Some(ip) = ips.get(&var) {
+                return Ok(ip.clone());
+            }
+            let ip_map: HashMap<String, String> = ips.clone().into_iter().collect();
+            IpAllocator::new(&config, &ip_map).allocate(&var)?
+        }
+    };
+    ips.insert(var, ip.clone());
+    save(&paths, citadel_root, &ips)?;
+    Ok(ip)
+}
+
+/// Frees the IPs of `apps`, and with `uninstalled` those of all apps that are not installed.
+/// IPs of installed apps are only freed with `force`, their containers still use them.
+/// Apps in the apps dir get a new IP on the next conversion. Returns the freed entries.
+pub fn free(
+    citadel_root: &str,
+    state_dir: &Option<String>,
+    apps: &[String],
+    uninstalled: bool,
+    force: bool,
+) -> Result<Vec<IpEntry>> {
+    let citadel_root = Path::new(citadel_root);
+    let paths = CitadelPaths::new(citadel_root, state_dir.as_deref().map(Path::new));
+    let apps_dir = citadel_root.join("apps");
+    let app_ids: Vec<String> = app_ids(&apps_dir)?.into_iter().collect();
+    let installed_apps = installed_apps(&paths, citadel_root);
+    for app in apps {
+        if installed_apps.contains(app) && !force {
+            bail!("{app} is installed, pass --force to assign it new IPs");
+        }
+    }
+    let mut ips = load_ips(&paths, &apps_dir)?;
+    let freed: Vec<IpEntry> = entries(&ips, &app_ids, &installed_apps)
+        .into_iter()
+        .filter(|entry| {
+            // Removed apps can't be freed by name, the owner of their env vars is unknown
+            entry.app.as_ref().is_some_and(|app| apps.contains(app))
+                || (uninstalled && !entry.installed)
+        })
+        .collect();
+    if !freed.is_empty() {
+        ips.retain(|var, _| !freed.iter().any(|entry| entry.var == *var));
+        save(&paths, citadel_root, &ips)?;
+    }
+    Ok(freed)
+}
+
+/// Formats the IPs as a table
+pub fn format_table(entries: &[IpEntry]) -> String {
+    let mut table = format!("{:<15} {:<40} {:<24} {}\n", "IP", "ENV VAR", "APP", "NOTE");
+    for entry in entries {
+        let note = if entry.is_stale() {
+            "stale"
+        } else if entry.installed {
+            "installed"
+        } else {
+            ""
+        };
+        let _ = writeln!(
+            table,
+            "{:<15} {:<40} {:<24} {}",
+            entry.ip,
+            entry.var,
+            entry.app.as_deref().unwrap_or("-"),
+            note
+        );
+    }
+    table
+}
+
+#[cfg(test)]
+mod test {
+    use tempdir::TempDir;
+
+    use super::{assign, free, list};
+
+    #[test]
+    fn assigns_and_frees_ips() {
+        let root = TempDir::new("ip-map").unwrap();
+        for app in ["lnd", "mempool"] {
+            std::fs::create_dir_all(root.path().join("apps").join(app)).unwrap();
+            std::fs::write(root.path().join("apps").join(app).join("app.yml"), "").unwrap();
+        }
+        std::fs::create_dir_all(root.path().join("db")).unwrap();
+        std::fs::write(
+            root.path().join("db").join("user.json"),
+            r#"{"installedApps": ["lnd"]}"#,
+        )
+        .unwrap();
+        std::fs::write(
+            root.path().join("apps").join("ips.yml"),
+            "APP_LND_WEB_IP: 10.21.21.20\nAPP_MEMPOOL_API_IP: 10.21.21.21\nAPP_REMOVED_WEB_IP: 10.21.21.22\n",
+        )
+        .unwrap();
+        let citadel_root = root.path().to_str().unwrap();
+
+        assert_eq!(
+            assign(citadel_root, &None, "lnd", "web", None).unwrap(),
+            "10.21.21.20"
+        );
+        assert_eq!(
+            assign(citadel_root, &None, "lnd", "grpc", None).unwrap(),
+            "10.21.21.23"
+        );
+        assert!(assign(citadel_root, &None, "lnd", "grpc", Some("10.21.21.21")).is_err());
+        assert!(assign(citadel_root, &None, "lnd", "grpc", Some("10.21.21.2")).is_err());
+        assert!(free(citadel_root, &None, &["lnd".to_string()], false, false).is_err());
+
+        let freed: Vec<String> = free(citadel_root, &None, &[], true, false)
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.var)
+            .collect();
+        assert_eq!(freed, vec!["APP_MEMPOOL_API_IP", "APP_REMOVED_WEB_IP"]);
+        let vars: Vec<String> = list(citadel_root, &None)
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.var)
+            .collect();
+        assert_eq!(vars, vec!["APP_LND_WEB_IP", "APP_LND_GRPC_IP"]);
+    }
+}
@@ -0,0 +1,313 @@
+use std::{
+    collections::HashMap,
+    io::Write,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context, Result};
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, CidrSubnet, DistinguishedName, DnType,
+    GeneralSubtree, IsCa, KeyPair, KeyUsagePurpose, NameConstraints, SanType,
+};
+use serde::{Deserialize, Serialize};
+
+use super::{https::Certificate as CertificateFiles, node_config::NodeConfig, paths::CitadelPaths};
+
+/// App certificates are reissued after this time, well before they expire
+const REISSUE_AFTER: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+/// Settings for LAN certificates in app-manager.toml
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct LanTlsConfig {
+    /// Issue certificates for apps from a node CA
+    pub enabled: bool,
+    /// The node's hostname on the LAN, apps get certificates for it and <app id>.<hostname>
+    pub hostname: String,
+    /// The node's IP addresses on the LAN, app certificates are also valid for them
+    pub addresses: Vec<IpAddr>,
+    /// Where Caddy sees the tls directory of the Citadel root, if it is mounted somewhere else
+    pub caddy_dir: Option<PathBuf>,
+}
+
+impl Default for LanTlsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            hostname: "citadel.local".to_string(),
+            addresses: Vec::new(),
+            caddy_dir: None,
+        }
+    }
+}
+
+/// The names the node CA may issue certificates for, so a leaked CA key can't be used
+/// to impersonate other sites to clients that trust it.
+/// IP addresses are excluded unless the node's addresses are configured.
+fn name_constraints(config: &LanTlsConfig) -> NameConstraints {
+    let mut permitted_subtrees = vec![GeneralSubtree::DnsName(config.hostname.clone())];
+    permitted_subtrees.extend(config.addresses.iter().map(|address| {
+        let prefix = if address.is_ipv4() { 32 } else { 128 };
+        GeneralSubtree::IpAddress(CidrSubnet::from_addr_prefix(*address, prefix))
+    }));
+    let excluded_subtrees = if config.addresses.is_empty() {
+        vec![
+            GeneralSubtree::IpAddress(CidrSubnet::from_addr_prefix(
+                Ipv4Addr::UNSPECIFIED.into(),
+                0,
+            )),
+            GeneralSubtree::IpAddress(CidrSubnet::from_addr_prefix(
+                Ipv6Addr::UNSPECIFIED.into(),
+                0,
+            )),
+        ]
+    } else {
+        Vec::new()
+    };
+    NameConstraints {
+        permitted_subtrees,
+        excluded_subtrees,
+    }
+}
+
+/// The hostname and addresses a CA was created for, stored in ca.json
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+struct CaNames {
+    hostname: String,
+    addresses: Vec<IpAddr>,
+}
+
+impl CaNames {
+    fn new(config: &LanTlsConfig) -> Self {
+        Self {
+            hostname: config.hostname.clone(),
+            addresses: config.addresses.clone(),
+        }
+    }
+}
+
+fn ca_params(key_pair: KeyPair, config: &LanTlsConfig) -> CertificateParams {
+    let mut params = CertificateParams::new(Vec::new());
+    let mut distinguished_name = DistinguishedName::new();
+    distinguished_name.push(DnType::OrganizationName, "Citadel");
+    distinguished_name.push(DnType::CommonName, "Citadel node CA");
+    params.distinguished_name = distinguished_name;
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+    params.name_constraints = Some(name_constraints(config));
+    params.key_pair = Some(key_pair);
+    params
+}
+
+/// The current year, good enough for certificate validity periods
+fn current_year() -> i32 {
+    let secs = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    1970 + (secs / 31_556_952) as i32
+}
+
+/// Writes a private key which only the owner can read, it is never readable by others while it is written
+fn write_private_key(paths: &CitadelPaths, path: &Path, key: String) -> Result<()> {
+    let write_path = paths.write_path(path)?;
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options
+        .open(&write_path)
+        .with_context(|| format!("Failed to create {}", write_path.display()))?;
+    // The mode only applies to new files
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+    }
+    file.write_all(key.as_bytes())
+        .with_context(|| format!("Failed to write {}", write_path.display()))?;
+    Ok(())
+}
+
+/// The node CA, its key is stored next to the certificate in the tls directory
+pub struct NodeCa {
+    ca: Certificate,
+    tls_dir: PathBuf,
+}
+
+impl NodeCa {
+    /// Loads the node CA or creates it if it does not exist yet.
+    /// The CA is limited to the hostname and addresses in `config` when it is created.
+    pub fn load_or_create(
+        paths: &CitadelPaths,
+        tls_dir: &Path,
+        config: &LanTlsConfig,
+    ) -> Result<Self> {
+        let key_file = tls_dir.join("ca.key");
+        let cert_file = tls_dir.join("ca.crt");
+        let names_file = tls_dir.join("ca.json");
+        let ca = if paths.exists(&key_file) && paths.exists(&cert_file) {
+            let names = if paths.exists(&names_file) {
+                Some(
+                    serde_json::from_str::<CaNames>(&paths.read_to_string(&names_file)?)
+                        .context("Failed to load ca.json")?,
+                )
+            } else {
+                None
+            };
+            match names {
+                Some(names) if names == CaNames::new(config) => {}
+                Some(names) => tracing::warn!(
+                    "The node CA is only valid for {} and {:?}, delete {} and {} to create one for the new hostname or addresses",
+                    names.hostname,
+                    names.addresses,
+                    key_file.display(),
+                    cert_file.display()
+                ),
+                None => tracing::warn!(
+                    "The node CA is valid for all names, delete {} and {} to create one limited to the node",
+                    key_file.display(),
+                    cert_file.display()
+                ),
+            }
+            // The certificate is recreated from the key with the same subject,
+            // so certificates it signs chain up to the CA certificate clients already trust
+            let key_pair = KeyPair::from_pem(&paths.read_to_string(&key_file)?)
+                .context("Failed to load the CA key")?;
+            Certificate::from_params(ca_params(key_pair, config))?
+        } else {
+            let mut params = ca_params(KeyPair::generate(&rcgen::PKCS_ECDSA_P256_SHA256)?, config);
+            params.not_before = rcgen::date_time_ymd(current_year(), 1, 1);
+            params.not_after = rcgen::date_time_ymd(current_year() + 20, 1, 1);
+            let ca = Certificate::from_params(params)?;
+            paths.create_dir_all(tls_dir)?;
+            write_private_key(paths, &key_file, ca.serialize_private_key_pem())?;
+            paths.write(&cert_file, ca.serialize_pem()?)?;
+            paths.write(&names_file, serde_json::to_string(&CaNames::new(config))?)?;
+            ca
+        };
+        Ok(Self {
+            ca,
+            tls_dir: tls_dir.to_path_buf(),
+        })
+    }
+
+    fn certificate_pem(paths: &CitadelPaths, tls_dir: &Path) -> Result<String> {
+        paths.read_to_string(&tls_dir.join("ca.crt"))
+    }
+
+    fn issue(&self, names: Vec<String>, addresses: &[IpAddr]) -> Result<(String, String)> {
+        let mut params = CertificateParams::new(names.clone());
+        params
+            .subject_alt_names
+            .extend(addresses.iter().copied().map(SanType::IpAddress));
+        params
+            .distinguished_name
+            .push(DnType::CommonName, names[0].as_str());
+        // Clients don't accept leaf certificates valid for much longer than two years
+        params.not_before = rcgen::date_time_ymd(current_year(), 1, 1);
+        params.not_after = rcgen::date_time_ymd(current_year() + 2, 1, 1);
+        let certificate = Certificate::from_params(params)?;
+        Ok((
+            certificate.serialize_pem_with_signer(&self.ca)?,
+            certificate.serialize_private_key_pem(),
+        ))
+    }
+
+    /// Issues certificates for apps which don't have a recent one yet.
+    /// Returns app ID -> certificate files, as seen by Caddy.
+    pub fn issue_app_certificates<'a>(
+        &self,
+        paths: &CitadelPaths,
+        config: &LanTlsConfig,
+        app_ids: impl Iterator<Item = &'a String>,
+    ) -> Result<HashMap<String, CertificateFiles>> {
+        let apps_dir = self.tls_dir.join("apps");
+        paths.create_dir_all(&apps_dir)?;
+        let caddy_dir = config.caddy_dir.as_ref().unwrap_or(&self.tls_dir);
+        let mut certificates = HashMap::new();
+        for app_id in app_ids {
+            let cert_file = apps_dir.join(format!("{app_id}.crt"));
+            let key_file = apps_dir.join(format!("{app_id}.key"));
+            let is_recent = std::fs::metadata(paths.read_path(&cert_file))
+                .and_then(|metadata| metadata.modified())
+                .ok()
+                .and_then(|modified| modified.elapsed().ok())
+                .is_some_and(|age| age < REISSUE_AFTER);
+            if !is_recent || !paths.exists(&key_file) {
+                let (cert, key) = self.issue(
+                    vec![
+                        config.hostname.clone(),
+                        format!("{app_id}.{}", config.hostname),
+                    ],
+                    &config.addresses,
+                )?;
+                paths.write(&cert_file, cert)?;
+                write_private_key(paths, &key_file, key)?;
+            }
+            certificates.insert(
+                app_id.clone(),
+                CertificateFiles {
+                    cert_file: caddy_dir.join("apps").join(format!("{app_id}.crt")),
+                    key_file: caddy_dir.join("apps").join(format!("{app_id}.key")),
+                },
+            );
+        }
+        Ok(certificates)
+    }
+}
+
+/// Returns the node CA certificate to install on client devices, the CA is created if necessary
+pub fn export_ca(citadel_root: &str, state_dir: &Option<String>) -> Result<String> {
+    let paths = CitadelPaths::new(Path::new(citadel_root), state_dir.as_deref().map(Path::new));
+    let tls_dir = paths.root().join("tls");
+    let config = NodeConfig::load(&paths, paths.root())?.lan_tls;
+    NodeCa::load_or_create(&paths, &tls_dir, &config)?;
+    NodeCa::certificate_pem(&paths, &tls_dir)
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::{LanTlsConfig, NodeCa};
+    use crate::cli::paths::CitadelPaths;
+
+    #[test]
+    fn reuses_ca_key() {
+        let root = std::env::temp_dir().join(format!("citadel-lan-tls-{}", std::process::id()));
+        let tls_dir = root.join("tls");
+        std::fs::create_dir_all(&root).unwrap();
+        let paths = CitadelPaths::new(&root, None);
+
+        let config = LanTlsConfig {
+            addresses: vec!["192.168.1.2".parse().unwrap()],
+            ..Default::default()
+        };
+        let ca = NodeCa::load_or_create(&paths, &tls_dir, &config).unwrap();
+        let ca_cert = NodeCa::certificate_pem(&paths, &tls_dir).unwrap();
+        let app_ids = vec!["lnd".to_string()];
+        let certificates = ca
+            .issue_app_certificates(&paths, &config, app_ids.iter())
+            .unwrap();
+        assert_eq!(
+            certificates["lnd"].cert_file,
+            tls_dir.join("apps").join("lnd.crt")
+        );
+        for key_file in [tls_dir.join("ca.key"), tls_dir.join("apps").join("lnd.key")] {
+            let mode = std::fs::metadata(key_file).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        assert!(tls_dir.join("ca.json").exists());
+
+        let reloaded = NodeCa::load_or_create(&paths, &tls_dir, &config).unwrap();
+        assert_eq!(reloaded.ca.get_key_identifier(), ca.ca.get_key_identifier());
+        assert_eq!(NodeCa::certificate_pem(&paths, &tls_dir).unwrap(), ca_cert);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
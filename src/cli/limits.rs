@@ -0,0 +1,177 @@
+use std::{
+    collections::HashSet,
+    io::Write,
+    sync::{mpsc, Mutex, OnceLock},
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+
+/// Limits for converting a single app in app-manager.toml,
+/// so an app with a broken template can't stop the other apps from being converted
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct ConversionLimits {
+    /// How long rendering the templates of an app may take, in seconds
+    pub timeout: u64,
+    /// The maximum size of a rendered template, in bytes
+    pub max_output_size: usize,
+}
+
+impl Default for ConversionLimits {
+    fn default() -> Self {
+        Self {
+            timeout: 30,
+            max_output_size: 4 * 1024 * 1024,
+        }
+    }
+}
+
+impl ConversionLimits {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout)
+    }
+
+    /// A buffer to render a template into
+    pub fn output(&self) -> LimitedOutput {
+        LimitedOutput {
+            buffer: Vec::new(),
+            limit: self.max_output_size,
+        }
+    }
+}
+
+/// A buffer that fails writes once it would grow beyond the limit,
+/// so a template is stopped as soon as its output is too large
+pub struct LimitedOutput {
+    buffer: Vec<u8>,
+    limit: usize,
+}
+
+impl LimitedOutput {
+    pub fn into_string(self) -> Result<String> {
+        Ok(String::from_utf8(self.buffer)?)
+    }
+}
+
+impl Write for LimitedOutput {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.buffer.len() + buf.len() > self.limit {
+            return Err(std::io::Error::other(format!(
+                "The rendered template is more than the limit of {} bytes",
+                self.limit
+            )));
+        }
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Apps with a task that is still running, a task that exceeded its timeout stays in here until it finishes
+static RUNNING: OnceLock<Mutex<HashSet<String>>> = OnceLock::new();
+
+/// Removes an app from `RUNNING` when its task finishes or panics
+struct RunningGuard(String);
+
+impl Drop for RunningGuard {
+    fn drop(&mut self) {
+        if let Some(running) = RUNNING.get() {
+            running
+                .lock()
+                .unwrap_or_else(|err| err.into_inner())
+                .remove(&self.0);
+        }
+    }
+}
+
+/// Runs `task` for an app on its own thread and gives up waiting for it after `timeout`.
+/// A thread can't be stopped from the outside, so a task that never finishes keeps running
+/// in the background until the app manager exits, but everything else can continue.
+/// No other task is started for the app while it is still running,
+/// so long-running processes don't pile up stuck threads.
+pub fn run_with_timeout<T: Send + 'static>(
+    name: &str,
+    app_id: &str,
+    timeout: Duration,
+    task: impl FnOnce() -> Result<T> + Send + 'static,
+) -> Result<T> {
+    let running = RUNNING.get_or_init(Default::default);
+    if !running
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .insert(app_id.to_string())
+    {
+        bail!("{name} was not started, an earlier one for {app_id} is still running");
+    }
+    let guard = RunningGuard(app_id.to_string());
+    let (sender, receiver) = mpsc::channel();
+    std::thread::Builder::new()
+        .name(name.to_string())
+        .spawn(move || {
+            let result = task();
+            // Before sending, so the app can run a task again as soon as this one returned
+            drop(guard);
+            let _ = sender.send(result);
+        })?;
+    match receiver.recv_timeout(timeout) {
+        Ok(result) => result,
+        Err(mpsc::RecvTimeoutError::Timeout) => {
+            bail!("{name} did not finish within {} seconds", timeout.as_secs())
+        }
+        Err(mpsc::RecvTimeoutError::Disconnected) => Err(anyhow!("{name} crashed")),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{run_with_timeout, ConversionLimits};
+    use std::{io::Write, time::Duration};
+
+    #[test]
+    fn stops_waiting_after_timeout() {
+        assert_eq!(
+            run_with_timeout("fast", "fast", Duration::from_secs(5), || Ok(42)).unwrap(),
+            42
+        );
+        assert_eq!(
+            run_with_timeout("fast", "fast", Duration::from_secs(5), || Ok(43)).unwrap(),
+            43
+        );
+        let err = run_with_timeout("slow", "slow", Duration::from_millis(50), || {
+            std::thread::sleep(Duration::from_secs(1));
+            Ok(())
+        })
+        .unwrap_err();
+        assert!(err.to_string().contains("did not finish"));
+        // The first one is still running
+        let err = run_with_timeout("slow", "slow", Duration::from_secs(5), || Ok(())).unwrap_err();
+        assert!(err.to_string().contains("still running"));
+        std::thread::sleep(Duration::from_secs(2));
+        assert!(run_with_timeout("slow", "slow", Duration::from_secs(5), || Ok(())).is_ok());
+        assert!(run_with_timeout(
+            "panicking",
+            "panicking",
+            Duration::from_secs(5),
+            || -> anyhow::Result<()> { panic!("broken template") }
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn limits_output_size() {
+        let limits = ConversionLimits {
+            max_output_size: 8,
+            ..Default::default()
+        };
+        let mut output = limits.output();
+        output.write_all(b"12345").unwrap();
+        assert!(output.write_all(b"6789").is_err());
+        output.write_all(b"678").unwrap();
+        assert_eq!(output.into_string().unwrap(), "12345678");
+    }
+}
@@ -0,0 +1,93 @@
+use serde::{Deserialize, Serialize};
+
+use crate::composegenerator::{
+    compose::types::StringOrIntOrBool, output::types::ComposeSpecification,
+};
+
+/// The time zone and locale of the node in app-manager.toml, passed to all apps
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct LocaleConfig {
+    /// A time zone like Europe/Berlin, passed as TZ
+    pub timezone: Option<String>,
+    /// A locale like de_DE.UTF-8, passed as LANG
+    pub locale: Option<String>,
+    /// Apps which don't get these env vars
+    pub exclude: Vec<String>,
+}
+
+/// Sets TZ and LANG in every container of an app, unless the app sets them itself
+pub fn inject_locale(config: &LocaleConfig, app_id: &str, spec: &mut ComposeSpecification) {
+    if config.exclude.iter().any(|app| app == app_id) {
+        return;
+    }
+    let vars: Vec<(&str, &String)> = [("TZ", &config.timezone), ("LANG", &config.locale)]
+        .into_iter()
+        .filter_map(|(name, value)| Some((name, value.as_ref()?)))
+        .collect();
+    if vars.is_empty() {
+        return;
+    }
+    for service in spec
+        .services
+        .iter_mut()
+        .flat_map(|services| services.values_mut())
+    {
+        let environment = service.environment.get_or_insert_with(Default::default);
+        for (name, value) in &vars {
+            environment
+                .entry(name.to_string())
+                .or_insert_with(|| StringOrIntOrBool::String(value.to_string()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{inject_locale, LocaleConfig};
+    use crate::{
+        bmap,
+        composegenerator::{
+            compose::types::StringOrIntOrBool,
+            output::types::{ComposeSpecification, Service},
+        },
+    };
+
+    #[test]
+    fn injects_time_zone() {
+        let config = LocaleConfig {
+            timezone: Some("Europe/Berlin".to_string()),
+            locale: None,
+            exclude: vec!["bitcoind".to_string()],
+        };
+        let mut spec = ComposeSpecification {
+            services: Some(bmap! {
+                "web" => Service::default(),
+                "worker" => Service {
+                    environment: Some(bmap! {
+                        "TZ".to_string() => StringOrIntOrBool::String("UTC".to_string())
+                    }),
+                    ..Default::default()
+                }
+            }),
+            ..Default::default()
+        };
+        let mut excluded = spec.clone();
+        inject_locale(&config, "nextcloud", &mut spec);
+        let services = spec.services.unwrap();
+        assert_eq!(
+            services["web"].environment,
+            Some(bmap! {
+                "TZ".to_string() => StringOrIntOrBool::String("Europe/Berlin".to_string())
+            })
+        );
+        // Values set by the app are kept
+        assert_eq!(
+            services["worker"].environment.as_ref().unwrap()["TZ"],
+            StringOrIntOrBool::String("UTC".to_string())
+        );
+
+        inject_locale(&config, "bitcoind", &mut excluded);
+        assert!(excluded.services.unwrap()["web"].environment.is_none());
+    }
+}
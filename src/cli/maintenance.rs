@@ -0,0 +1,112 @@
+use std::{collections::BTreeSet, path::Path};
+
+use anyhow::{bail, Context, Result};
+
+use super::{app_filter::AppFilter, compose, paths::CitadelPaths, port_review::PortChangePolicy};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum MaintenanceState {
+    On,
+    Off,
+}
+
+fn maintenance_file(citadel_root: &Path) -> std::path::PathBuf {
+    citadel_root.join("db").join("maintenance.json")
+}
+
+/// Loads the IDs of the apps that are in maintenance mode from db/maintenance.json
+pub fn load(paths: &CitadelPaths, citadel_root: &Path) -> Result<BTreeSet<String>> {
+    let maintenance_file = maintenance_file(citadel_root);
+    if !paths.exists(&maintenance_file) {
+        return Ok(BTreeSet::new());
+    }
+    serde_json::from_str(&paths.read_to_string(&maintenance_file)?)
+        .context("Failed to load maintenance.json")
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Caddy directives that answer every request with a maintenance page instead of proxying it
+pub fn directives(app_name: &str) -> String {
+    let app_name = escape_html(app_name);
+    format!(
+        "route {{
+  header Content-Type \"text/html; charset=utf-8\"
+  header Retry-After 300
+  respond \"<!DOCTYPE html><html><head><title>{app_name} is under maintenance</title></head><body><h1>{app_name} is under maintenance</h1><p>It will be back shortly.</p></body></html>\" 503
+}}
+"
+    )
+}
+
+/// Turns maintenance mode of an app on or off.
+/// The app's containers are stopped after its route was swapped to the maintenance page,
+/// and started again before the original route is restored.
+pub fn set(
+    citadel_root: &str,
+    caddy_url: &Option<String>,
+    state_dir: &Option<String>,
+    app_id: &str,
+    state: MaintenanceState,
+) -> Result<()> {
+    let paths = CitadelPaths::new(Path::new(citadel_root), state_dir.as_deref().map(Path::new));
+    let citadel_root = paths.root();
+    // Fails if the app is not installed
+    compose::compose_command(&paths, app_id)?;
+    let mut apps = load(&paths, citadel_root)?;
+    let changed = match state {
+        MaintenanceState::On => apps.insert(app_id.to_string()),
+        MaintenanceState::Off => apps.remove(app_id),
+    };
+    if !changed {
+        tracing::info!("Maintenance mode of {} is already {:?}", app_id, state);
+    }
+
+    if state == MaintenanceState::Off {
+        let mut command = compose::compose_command(&paths, app_id)?;
+        command.arg("up").arg("--detach");
+        if compose::run(command)? != 0 {
+            bail!("Failed to start {app_id}");
+        }
+    }
+    paths.write(
+        &maintenance_file(citadel_root),
+        serde_json::to_string_pretty(&apps)?,
+    )?;
+    super::failures::convert_dir(
+        &citadel_root.to_string_lossy(),
+        caddy_url,
+        state_dir,
+        PortChangePolicy::default(),
+        &AppFilter::default(),
+        None,
+        None,
+    )?;
+    if state == MaintenanceState::On {
+        let mut command = compose::compose_command(&paths, app_id)?;
+        command.arg("stop");
+        if compose::run(command)? != 0 {
+            bail!("Failed to stop {app_id}");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::directives;
+    use crate::cli::caddy_snippets::validate_snippet;
+
+    #[test]
+    fn generates_maintenance_page() {
+        let directives = directives("Ride <The> Lightning");
+        assert!(directives.contains("<h1>Ride &lt;The&gt; Lightning is under maintenance</h1>"));
+        assert!(directives.contains("\" 503\n"));
+        assert!(validate_snippet(&directives, true).is_ok());
+    }
+}
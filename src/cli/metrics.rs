@@ -0,0 +1,213 @@
+use std::{
+    collections::BTreeMap,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::port_review::PortChange;
+
+static TEMPLATE_CACHE_HITS: AtomicU64 = AtomicU64::new(0);
+static TEMPLATE_CACHE_MISSES: AtomicU64 = AtomicU64::new(0);
+static BYTES_WRITTEN: AtomicU64 = AtomicU64::new(0);
+
+/// Counts a lookup in the compiled template cache
+pub fn record_template_lookup(hit: bool) {
+    let counter = if hit {
+        &TEMPLATE_CACHE_HITS
+    } else {
+        &TEMPLATE_CACHE_MISSES
+    };
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Counts bytes written to generated files
+pub fn record_write(bytes: usize) {
+    BYTES_WRITTEN.fetch_add(bytes as u64, Ordering::Relaxed);
+}
+
+/// The process-wide counters, which are compared before and after a conversion
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct Counters {
+    hits: u64,
+    misses: u64,
+    bytes: u64,
+}
+
+impl Counters {
+    fn now() -> Self {
+        Self {
+            hits: TEMPLATE_CACHE_HITS.load(Ordering::Relaxed),
+            misses: TEMPLATE_CACHE_MISSES.load(Ordering::Relaxed),
+            bytes: BYTES_WRITTEN.load(Ordering::Relaxed),
+        }
+    }
+
+    fn since(self, start: Self) -> Self {
+        Self {
+            hits: self.hits - start.hits,
+            misses: self.misses - start.misses,
+            bytes: self.bytes - start.bytes,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct StageTiming {
+    pub name: String,
+    pub millis: u64,
+}
+
+#[derive(Serialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// An app that failed to convert
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct AppFailure {
+    pub app: String,
+    /// The stage it failed in, like convert
+    pub stage: String,
+    pub error: String,
+}
+
+/// Timing and cache statistics of a conversion, written to apps/conversion-report.json
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct ConversionReport {
+    pub total_millis: u64,
+    pub stages: Vec<StageTiming>,
+    /// App ID -> time spent converting it, in milliseconds
+    pub apps: BTreeMap<String, u64>,
+    /// Lookups in the cache of compiled templates
+    pub template_cache: CacheStats,
+    /// Bytes written to generated files through the state paths
+    pub bytes_written: u64,
+    /// Apps whose compose files were written
+    pub converted: Vec<String>,
+    /// Apps that were not converted on purpose, app ID -> reason
+    pub skipped: BTreeMap<String, String>,
+    /// Apps that were skipped because they failed to convert
+    pub failures: Vec<AppFailure>,
+    /// Public ports of installed apps that moved
+    pub port_changes: Vec<PortChange>,
+}
+
+/// Collects the metrics of a conversion while it runs
+pub struct ConversionMetrics {
+    started: Instant,
+    stage_started: Instant,
+    counters: Counters,
+    stages: Vec<StageTiming>,
+    apps: BTreeMap<String, Duration>,
+    converted: Vec<String>,
+    skipped: BTreeMap<String, String>,
+    failures: Vec<AppFailure>,
+    port_changes: Vec<PortChange>,
+}
+
+impl ConversionMetrics {
+    pub fn start() -> Self {
+        let now = Instant::now();
+        Self {
+            started: now,
+            stage_started: now,
+            counters: Counters::now(),
+            stages: Vec::new(),
+            apps: BTreeMap::new(),
+            converted: Vec::new(),
+            skipped: BTreeMap::new(),
+            failures: Vec::new(),
+            port_changes: Vec::new(),
+        }
+    }
+
+    /// Ends the current stage, the next one starts now
+    pub fn finish_stage(&mut self, name: &str) {
+        let now = Instant::now();
+        self.stages.push(StageTiming {
+            name: name.to_string(),
+            millis: (now - self.stage_started).as_millis() as u64,
+        });
+        self.stage_started = now;
+    }
+
+    /// Adds time spent on an app
+    pub fn add_app_time(&mut self, app_id: &str, duration: Duration) {
+        *self.apps.entry(app_id.to_string()).or_default() += duration;
+    }
+
+    pub fn add_converted(&mut self, app_id: &str) {
+        self.converted.push(app_id.to_string());
+    }
+
+    pub fn add_skipped(&mut self, app_id: &str, reason: &str) {
+        self.skipped.insert(app_id.to_string(), reason.to_string());
+    }
+
+    pub fn set_port_changes(&mut self, changes: &[PortChange]) {
+        self.port_changes = changes.to_vec();
+    }
+
+    /// Records an app that failed to convert
+    pub fn add_failure(&mut self, app_id: &str, stage: &str, error: &str) {
+        self.failures.push(AppFailure {
+            app: app_id.to_string(),
+            stage: stage.to_string(),
+            error: error.to_string(),
+        });
+    }
+
+    pub fn report(&self) -> ConversionReport {
+        let counters = Counters::now().since(self.counters);
+        ConversionReport {
+            total_millis: self.started.elapsed().as_millis() as u64,
+            stages: self.stages.clone(),
+            apps: self
+                .apps
+                .iter()
+                .map(|(app_id, duration)| (app_id.clone(), duration.as_millis() as u64))
+                .collect(),
+            template_cache: CacheStats {
+                hits: counters.hits,
+                misses: counters.misses,
+            },
+            bytes_written: counters.bytes,
+            converted: self.converted.clone(),
+            skipped: self.skipped.clone(),
+            failures: self.failures.clone(),
+            port_changes: self.port_changes.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{record_template_lookup, record_write, ConversionMetrics};
+    use std::time::Duration;
+
+    #[test]
+    fn collects_conversion_metrics() {
+        let mut metrics = ConversionMetrics::start();
+        record_template_lookup(false);
+        record_template_lookup(true);
+        record_write(1024);
+        metrics.finish_stage("convert");
+        metrics.add_app_time("lnd", Duration::from_millis(30));
+        metrics.add_app_time("lnd", Duration::from_millis(12));
+        let report = metrics.report();
+        assert_eq!(report.stages.len(), 1);
+        assert_eq!(report.stages[0].name, "convert");
+        assert_eq!(report.apps["lnd"], 42);
+        // Other tests run in parallel and may add to the counters
+        assert!(report.template_cache.hits >= 1);
+        assert!(report.template_cache.misses >= 1);
+        assert!(report.bytes_written >= 1024);
+    }
+}
@@ -0,0 +1,196 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::composegenerator::{
+    ir::AppDefinition,
+    output::types::{ComposeSpecification, Network, NetworkEntry},
+    v4::utils::get_main_container,
+};
+
+/// The network all of Citadel's containers are on, app containers get their fixed IPs in it
+const SHARED_NETWORK: &str = "default";
+/// The network only the containers of one app are on
+pub const APP_NETWORK: &str = "app";
+
+/// Isolation of apps from each other, in app-manager.toml
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct NetworkIsolationConfig {
+    /// Give every app its own network and only keep the containers Caddy and Tor need to reach
+    /// on the shared network. Apps can talk to each other if both set allow_connections_to.
+    pub enabled: bool,
+}
+
+/// Apps that allow connections to each other, app -> the apps it shares a network with
+pub fn links(app_ymls: &HashMap<String, AppDefinition>) -> BTreeMap<String, BTreeSet<String>> {
+    let mut links: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+    for (app_id, app_yml) in app_ymls {
+        for peer in &app_yml.metadata.allow_connections_to {
+            let consents = app_ymls
+                .get(peer)
+                .is_some_and(|peer| peer.metadata.allow_connections_to.contains(app_id));
+            if consents {
+                links
+                    .entry(app_id.clone())
+                    .or_default()
+                    .insert(peer.clone());
+            } else {
+                tracing::debug!(
+                    "App {} allows connections to {}, but {} does not allow them back",
+                    app_id,
+                    peer,
+                    peer
+                );
+            }
+        }
+    }
+    links
+}
+
+/// The Docker network two linked apps share, the same for both of them
+fn link_network_name(app_id: &str, peer: &str) -> String {
+    let (first, second) = if app_id < peer {
+        (app_id, peer)
+    } else {
+        (peer, app_id)
+    };
+    format!("citadel-link-{first}-{second}")
+}
+
+/// Containers that stay on the shared network: the main container, containers serving a UI entry
+/// or hidden service and containers that explicitly set assign_fixed_ip
+pub fn shared_containers(app_yml: &AppDefinition) -> Result<BTreeSet<String>> {
+    let main_container = get_main_container(
+        &app_yml.services,
+        app_yml.metadata.main_container.as_deref(),
+    )?;
+    let mut shared: BTreeSet<String> = app_yml
+        .services
+        .iter()
+        .filter(|(_, container)| {
+            container.assign_fixed_ip == Some(true) || container.hidden_services.is_some()
+        })
+        .map(|(name, _)| name.clone())
+        .collect();
+    shared.insert(main_container.to_string());
+    shared.extend(
+        app_yml
+            .metadata
+            .entries
+            .iter()
+            .map(|entry| entry.container.clone()),
+    );
+    Ok(shared)
+}
+
+/// Moves the containers of an app to its own network and joins them to the networks of linked apps.
+/// Only `shared` containers stay on the shared network.
+pub fn isolate(
+    app_id: &str,
+    shared: &BTreeSet<String>,
+    peers: Option<&BTreeSet<String>>,
+    spec: &mut ComposeSpecification,
+) {
+    let peers = peers.into_iter().flatten();
+    for (service_name, service) in spec.services.iter_mut().flatten() {
+        // Containers using the network of the host or another container can't join networks
+        if service.network_mode.is_some() {
+            continue;
+        }
+        let mut networks = service.networks.take().unwrap_or_default();
+        if !shared.contains(service_name) {
+            networks.remove(SHARED_NETWORK);
+        }
+        networks.insert(APP_NETWORK.to_string(), NetworkEntry::default());
+        for peer in peers.clone() {
+            networks.insert(format!("link-{peer}"), NetworkEntry::default());
+        }
+        service.networks = Some(networks);
+    }
+    spec.networks
+        .insert(APP_NETWORK.to_string(), Network::default());
+    for peer in peers {
+        spec.networks.insert(
+            format!("link-{peer}"),
+            Network {
+                name: Some(link_network_name(app_id, peer)),
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{isolate, links, shared_containers};
+    use crate::{
+        bmap,
+        composegenerator::{
+            ir::AppDefinition,
+            output::types::{ComposeSpecification, NetworkEntry, Service},
+        },
+    };
+    use std::collections::HashMap;
+
+    fn app(main_container: &str, allow_connections_to: &[&str]) -> AppDefinition {
+        let mut app_yml = AppDefinition::default();
+        app_yml.metadata.main_container = Some(main_container.to_string());
+        app_yml.metadata.allow_connections_to = allow_connections_to
+            .iter()
+            .map(|app| app.to_string())
+            .collect();
+        app_yml
+            .services
+            .insert(main_container.to_string(), Default::default());
+        app_yml
+            .services
+            .insert("db".to_string(), Default::default());
+        app_yml
+    }
+
+    #[test]
+    fn isolates_apps_with_explicit_links() {
+        let app_ymls = HashMap::from([
+            ("mempool".to_string(), app("web", &["electrs"])),
+            ("electrs".to_string(), app("app", &["mempool", "lnd"])),
+            ("lnd".to_string(), app("lnd", &[])),
+        ]);
+        let links = links(&app_ymls);
+        assert_eq!(links.len(), 2);
+        assert!(links["electrs"].contains("mempool") && !links["electrs"].contains("lnd"));
+
+        let shared_network = || {
+            Some(bmap! {
+                "default" => NetworkEntry {
+                    ipv4_address: Some("$APP_MEMPOOL_IP".to_string())
+                }
+            })
+        };
+        let mut spec = ComposeSpecification {
+            services: Some(bmap! {
+                "web" => Service {
+                    networks: shared_network(),
+                    ..Default::default()
+                },
+                "db" => Service {
+                    networks: shared_network(),
+                    ..Default::default()
+                }
+            }),
+            ..Default::default()
+        };
+        let shared = shared_containers(&app_ymls["mempool"]).unwrap();
+        isolate("mempool", &shared, links.get("mempool"), &mut spec);
+        let services = spec.services.unwrap();
+        let web_networks: Vec<&String> =
+            services["web"].networks.as_ref().unwrap().keys().collect();
+        assert_eq!(web_networks, vec!["app", "default", "link-electrs"]);
+        let db_networks: Vec<&String> = services["db"].networks.as_ref().unwrap().keys().collect();
+        assert_eq!(db_networks, vec!["app", "link-electrs"]);
+        assert_eq!(
+            spec.networks["link-electrs"].name.as_deref(),
+            Some("citadel-link-electrs-mempool")
+        );
+    }
+}
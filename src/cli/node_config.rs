@@ -0,0 +1,169 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    base_services::BaseServicesConfig, caddy_adapt::CaddyValidationConfig,
+    disk_space::DiskSpaceConfig, dns::DnsConfig, exposure::PortBindingConfig,
+    failures::FailureConfig, features::FeatureFlags, firewall::FirewallConfig,
+    host_ports::HostPortsConfig, http_cache::HttpCacheConfig, image_arch::ImageChecksConfig,
+    interface_health::InterfaceProbeConfig, ip_assignment::IpAssignmentConfig,
+    lan_tls::LanTlsConfig, limits::ConversionLimits, locale::LocaleConfig,
+    network_isolation::NetworkIsolationConfig, paths::CitadelPaths,
+    port_forwarding::PortForwardingConfig, rate_limits::RateLimitConfig,
+    redaction::RedactionConfig, registries::RegistriesConfig, rollout::RolloutConfig,
+    runtime::RuntimeConfig, security::SecurityConfig, static_assets::StaticAssetsConfig,
+    template_env::TemplateEnvConfig, webhooks::WebhookConfig,
+};
+use crate::composegenerator::{ir::AppDefinition, v4::types::Logging};
+
+/// Node-wide settings of the app manager, loaded from app-manager.toml in the Citadel root
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct NodeConfig {
+    /// Defaults for the logging configuration of all containers
+    pub logging: Option<Logging>,
+    /// Security options and trusted stores
+    pub security: SecurityConfig,
+    /// Compatibility settings for nerdctl, rootless Docker and userns-remap
+    pub runtime: RuntimeConfig,
+    /// Ports used by other processes on the host
+    pub host_ports: HostPortsConfig,
+    /// Port forwarding on the router via UPnP or NAT-PMP
+    pub port_forwarding: PortForwardingConfig,
+    /// Firewall ruleset generation
+    pub firewall: FirewallConfig,
+    /// Certificates for accessing apps via HTTPS on the LAN
+    pub lan_tls: LanTlsConfig,
+    /// Health probes for interfaces like electrum
+    pub interface_probes: InterfaceProbeConfig,
+    /// Fetching icons and other remote resources referenced by apps
+    pub http_cache: HttpCacheConfig,
+    /// How IPs are assigned to app containers
+    pub ip_assignment: IpAssignmentConfig,
+    /// Limits for converting a single app
+    pub conversion: ConversionLimits,
+    /// Serving static assets of apps through Caddy
+    pub static_assets: StaticAssetsConfig,
+    /// Host addresses published ports are bound to
+    pub port_binding: PortBindingConfig,
+    /// Checking the Caddyfile with Caddy before loading it
+    pub caddy_validation: CaddyValidationConfig,
+    /// Time zone and locale passed to apps
+    pub locale: LocaleConfig,
+    /// DNS servers of app containers
+    pub dns: DnsConfig,
+    /// Checks of the images used by apps
+    pub image_checks: ImageChecksConfig,
+    /// The disk space check before installing or updating apps
+    pub disk_space: DiskSpaceConfig,
+    /// Limits for requests to apps through Caddy
+    pub rate_limits: RateLimitConfig,
+    /// Feature flags app templates can adapt to, like low_power
+    pub features: FeatureFlags,
+    /// Registry mirrors and credentials
+    pub registries: RegistriesConfig,
+    /// Masking of secrets in logs and reports
+    pub redaction: RedactionConfig,
+    /// Separate networks for apps, so they can only reach the apps they are linked with
+    pub network_isolation: NetworkIsolationConfig,
+    /// Env vars shared with the templates of all apps
+    pub template_env: TemplateEnvConfig,
+    /// Services like bitcoind the node provides without an app
+    pub base_services: BaseServicesConfig,
+    /// Whether a conversion fails if an app fails to convert
+    pub failures: FailureConfig,
+    /// Webhooks from app stores that trigger a sync and conversion during serve
+    pub webhooks: WebhookConfig,
+    /// How long apply-updates waits for an updated app to become healthy
+    pub rollout: RolloutConfig,
+}
+
+impl NodeConfig {
+    /// Loads app-manager.toml, or returns the default config if it does not exist
+    pub fn load(paths: &CitadelPaths, citadel_root: &Path) -> Result<Self> {
+        let config_file = citadel_root.join("app-manager.toml");
+        if !paths.exists(&config_file) {
+            return Ok(Self::default());
+        }
+        toml::from_str(&paths.read_to_string(&config_file)?)
+            .with_context(|| format!("Failed to parse {}", config_file.display()))
+    }
+
+    /// Applies the node-wide defaults to an app
+    pub fn apply_defaults(&self, app_yml: &mut AppDefinition) {
+        if let Some(logging) = &self.logging {
+            for service in app_yml.services.values_mut() {
+                service.logging = Some(
+                    service
+                        .logging
+                        .as_ref()
+                        .map_or_else(|| logging.clone(), |own| own.with_defaults(logging)),
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::NodeConfig;
+    use crate::composegenerator::{
+        ir::AppDefinition,
+        v4::types::{Container, Logging},
+    };
+    use std::collections::HashMap;
+
+    #[test]
+    fn applies_logging_defaults() {
+        let config: NodeConfig = toml::from_str(
+            "[logging]
+max_size = \"10m\"
+max_files = 3
+",
+        )
+        .unwrap();
+        let mut app_yml = AppDefinition {
+            services: HashMap::from([
+                (
+                    "main".to_string(),
+                    Container {
+                        logging: Some(Logging {
+                            max_files: Some(1),
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    },
+                ),
+                (
+                    "db".to_string(),
+                    Container {
+                        logging: Some(Logging {
+                            driver: Some("journald".to_string()),
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    },
+                ),
+            ]),
+            ..Default::default()
+        };
+        config.apply_defaults(&mut app_yml);
+        assert_eq!(
+            app_yml.services["main"].logging,
+            Some(Logging {
+                driver: None,
+                max_size: Some("10m".to_string()),
+                max_files: Some(1),
+            })
+        );
+        assert_eq!(
+            app_yml.services["db"].logging,
+            Some(Logging {
+                driver: Some("journald".to_string()),
+                ..Default::default()
+            })
+        );
+    }
+}
@@ -0,0 +1,224 @@
+//! Machine-readable results of the subcommands, printed with --output json
+
+use std::{any::Any, path::PathBuf};
+
+use serde::Serialize;
+
+use crate::composegenerator::v4::{deprecations::Deprecation, diagnostics::Diagnostic};
+
+/// How results are printed
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// For people, the format can change between versions
+    #[default]
+    Text,
+    /// One JSON document on stdout, logs still go to stderr
+    Json,
+}
+
+impl OutputFormat {
+    pub fn is_json(self) -> bool {
+        self == OutputFormat::Json
+    }
+}
+
+/// Prints a result as JSON on stdout
+pub fn print_json(value: &impl Serialize) {
+    println!(
+        "{}",
+        serde_json::to_string_pretty(value).expect("Failed to serialize the result")
+    );
+}
+
+/// The message of a panic, like the one of a failed `.expect()`, without the backtrace of the error
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    let message = payload
+        .downcast_ref::<String>()
+        .map(String::as_str)
+        .or_else(|| payload.downcast_ref::<&str>().copied())
+        .unwrap_or("Unknown error");
+    message
+        .split_once("\n\nStack backtrace:")
+        .map_or(message, |(message, _)| message)
+}
+
+/// Prints failures as `{"error": "..."}` on stdout instead of a panic message,
+/// so programs reading the JSON output can tell them apart. The exit code stays non-zero.
+pub fn print_panics_as_json() {
+    std::panic::set_hook(Box::new(|info| {
+        print_json(&serde_json::json!({ "error": panic_message(info.payload()) }));
+    }));
+}
+
+/// Prints an error as JSON or text and exits with 1
+pub fn exit_with_error(format: OutputFormat, message: &str) -> ! {
+    if format.is_json() {
+        print_json(&serde_json::json!({ "error": message }));
+    } else {
+        eprintln!("{message}");
+    }
+    std::process::exit(1);
+}
+
+/// The result of validate
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct ValidationReport {
+    pub file: PathBuf,
+    /// False if any diagnostic is an error
+    pub valid: bool,
+    pub diagnostics: Vec<Diagnostic>,
+    /// Files that will cause problems on other operating systems
+    pub portability_warnings: Vec<String>,
+}
+
+/// The deprecated fields lint found in a file
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct LintResult {
+    pub file: PathBuf,
+    pub deprecations: Vec<Deprecation>,
+}
+
+/// Results that are a list of differences, like those of roundtrip and render-config --check
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Differences {
+    pub differences: Vec<String>,
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::BTreeMap, path::PathBuf};
+
+    use serde_json::json;
+
+    use super::panic_message;
+    use crate::cli::{
+        app_list::{AppState, ConversionStatus},
+        dry_run::{ChangeKind, FileChange},
+        metrics::{AppFailure, CacheStats, ConversionReport, StageTiming},
+        port_review::PortChange,
+    };
+
+    #[test]
+    fn convert_output_is_stable() {
+        let report = ConversionReport {
+            total_millis: 120,
+            stages: vec![StageTiming {
+                name: "convert".to_string(),
+                millis: 80,
+            }],
+            apps: BTreeMap::from([("lnd".to_string(), 42)]),
+            template_cache: CacheStats { hits: 3, misses: 1 },
+            bytes_written: 2048,
+            converted: vec!["lnd".to_string()],
+            skipped: BTreeMap::from([("electrs".to_string(), "not installed".to_string())]),
+            failures: vec![AppFailure {
+                app: "mempool".to_string(),
+                stage: "convert".to_string(),
+                error: "Failed to load app.yml".to_string(),
+            }],
+            port_changes: vec![PortChange {
+                app: "lnd".to_string(),
+                container: "web".to_string(),
+                old_port: 3000,
+                new_port: 3001,
+                taken_by: "btcpay".to_string(),
+            }],
+        };
+        assert_eq!(
+            serde_json::to_value(&report).unwrap(),
+            json!({
+                "totalMillis": 120,
+                "stages": [{ "name": "convert", "millis": 80 }],
+                "apps": { "lnd": 42 },
+                "templateCache": { "hits": 3, "misses": 1 },
+                "bytesWritten": 2048,
+                "converted": ["lnd"],
+                "skipped": { "electrs": "not installed" },
+                "failures": [{
+                    "app": "mempool",
+                    "stage": "convert",
+                    "error": "Failed to load app.yml",
+                }],
+                "portChanges": [{
+                    "app": "lnd",
+                    "container": "web",
+                    "oldPort": 3000,
+                    "newPort": 3001,
+                    "takenBy": "btcpay",
+                }],
+            })
+        );
+
+        // convert --dry-run
+        let changes = vec![FileChange {
+            path: PathBuf::from("apps/lnd/docker-compose.yml"),
+            kind: ChangeKind::Modified,
+        }];
+        assert_eq!(
+            serde_json::to_value(&changes).unwrap(),
+            json!([{ "path": "apps/lnd/docker-compose.yml", "kind": "modified" }])
+        );
+    }
+
+    #[test]
+    fn list_output_is_stable() {
+        let apps = vec![
+            AppState {
+                id: "lnd".to_string(),
+                version: Some("0.17.0".to_string()),
+                installed: true,
+                ip: Some("10.21.22.2".to_string()),
+                conversion: ConversionStatus::Converted,
+            },
+            AppState {
+                id: "mempool".to_string(),
+                version: None,
+                installed: false,
+                ip: None,
+                conversion: ConversionStatus::Failed("convert".to_string()),
+            },
+        ];
+        assert_eq!(
+            serde_json::to_value(&apps).unwrap(),
+            json!([
+                {
+                    "id": "lnd",
+                    "version": "0.17.0",
+                    "installed": true,
+                    "ip": "10.21.22.2",
+                    "conversion": "converted",
+                },
+                {
+                    "id": "mempool",
+                    "version": null,
+                    "installed": false,
+                    "ip": null,
+                    "conversion": { "failed": "convert" },
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn reports_failures() {
+        let payload = std::panic::catch_unwind(|| {
+            Err::<(), _>("no such app").expect("Failed to get the app's details")
+        })
+        .unwrap_err();
+        assert_eq!(
+            panic_message(&*payload),
+            "Failed to get the app's details: \"no such app\""
+        );
+        let payload = std::panic::catch_unwind(|| {
+            panic!("Failed to list apps: No such file\n\nStack backtrace:\n   0: main")
+        })
+        .unwrap_err();
+        assert_eq!(
+            panic_message(&*payload),
+            "Failed to list apps: No such file"
+        );
+        let payload = std::panic::catch_unwind(|| panic!("Not implemented yet")).unwrap_err();
+        assert_eq!(panic_message(&*payload), "Not implemented yet");
+    }
+}
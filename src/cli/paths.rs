@@ -0,0 +1,228 @@
+use std::{
+    fs::File,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+
+/// The directory in the state directory that records removed files, relative to it
+pub const TOMBSTONES_DIR: &str = ".deleted";
+
+/// Resolves the paths app-manager reads state from and writes outputs to.
+///
+/// By default, everything happens inside the Citadel root.
+/// If a state directory is set (for example because the Citadel root is mounted read-only),
+/// all writes below the Citadel root are redirected into the state directory,
+/// and reads prefer files from the state directory if they exist there.
+/// Files and directories removed from the root are recorded as tombstones in the state directory,
+/// so reads don't fall back to them.
+#[derive(Debug, Clone)]
+pub struct CitadelPaths {
+    root: PathBuf,
+    state_dir: Option<PathBuf>,
+}
+
+impl CitadelPaths {
+    pub fn new(root: &Path, state_dir: Option<&Path>) -> Self {
+        Self {
+            root: root.to_path_buf(),
+            state_dir: state_dir.map(Path::to_path_buf),
+        }
+    }
+
+    /// The Citadel root directory
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// The path inside the state directory for a path below the Citadel root,
+    /// None if there is no state directory or the path is outside the root
+    fn in_state_dir(&self, path: &Path) -> Option<PathBuf> {
+        let state_dir = self.state_dir.as_ref()?;
+        let relative = path.strip_prefix(&self.root).ok()?;
+        Some(state_dir.join(relative))
+    }
+
+    /// The tombstone recording that a path below the Citadel root was removed
+    fn tombstone(&self, path: &Path) -> Option<PathBuf> {
+        let state_dir = self.state_dir.as_ref()?;
+        let relative = path.strip_prefix(&self.root).ok()?;
+        let mut tombstone = state_dir
+            .join(TOMBSTONES_DIR)
+            .join(relative)
+            .into_os_string();
+        tombstone.push(".deleted");
+        Some(tombstone.into())
+    }
+
+    /// Whether a path or one of its parent directories was removed
+    fn is_removed(&self, path: &Path) -> bool {
+        path.ancestors()
+            .take_while(|ancestor| *ancestor != self.root)
+            .filter_map(|ancestor| self.tombstone(ancestor))
+            .any(|tombstone| tombstone.exists())
+    }
+
+    /// Where to read a file from
+    pub fn read_path(&self, path: &Path) -> PathBuf {
+        match self.in_state_dir(path) {
+            Some(state_path) if state_path.exists() || self.is_removed(path) => state_path,
+            _ => path.to_path_buf(),
+        }
+    }
+
+    /// Where to write a file to, parent directories are created if necessary
+    pub fn write_path(&self, path: &Path) -> Result<PathBuf> {
+        let Some(state_path) = self.in_state_dir(path) else {
+            return Ok(path.to_path_buf());
+        };
+        if let Some(parent) = state_path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create {}", parent.display()))?;
+        }
+        Ok(state_path)
+    }
+
+    pub fn exists(&self, path: &Path) -> bool {
+        self.read_path(path).exists()
+    }
+
+    pub fn open(&self, path: &Path) -> Result<File> {
+        let path = self.read_path(path);
+        File::open(&path).with_context(|| format!("Failed to open {}", path.display()))
+    }
+
+    pub fn read_to_string(&self, path: &Path) -> Result<String> {
+        let path = self.read_path(path);
+        std::fs::read_to_string(&path).with_context(|| format!("Failed to read {}", path.display()))
+    }
+
+    pub fn create(&self, path: &Path) -> Result<File> {
+        let path = self.write_path(path)?;
+        File::create(&path).with_context(|| format!("Failed to create {}", path.display()))
+    }
+
+    pub fn write(&self, path: &Path, contents: impl AsRef<[u8]>) -> Result<()> {
+        let path = self.write_path(path)?;
+        let contents = contents.as_ref();
+        std::fs::write(&path, contents)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        super::metrics::record_write(contents.len());
+        Ok(())
+    }
+
+    /// Writes a file through a temporary file that replaces it, so readers never see a partial write
+    pub fn write_atomic(&self, path: &Path, contents: impl AsRef<[u8]>) -> Result<()> {
+        let path = self.write_path(path)?;
+        let mut tmp_path = path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let contents = contents.as_ref();
+        std::fs::write(&tmp_path, contents)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        std::fs::rename(&tmp_path, &path)
+            .with_context(|| format!("Failed to replace {}", path.display()))?;
+        super::metrics::record_write(contents.len());
+        Ok(())
+    }
+
+    /// Creates a directory (and its parents) at the path it would be written to
+    pub fn create_dir_all(&self, path: &Path) -> Result<PathBuf> {
+        let path = self.write_path(path)?;
+        std::fs::create_dir_all(&path)
+            .with_context(|| format!("Failed to create {}", path.display()))?;
+        Ok(path)
+    }
+
+    /// Marks a path in the Citadel root as removed, if it exists there
+    fn add_tombstone(&self, path: &Path) -> Result<()> {
+        let Some(tombstone) = self.tombstone(path).filter(|_| path.exists()) else {
+            return Ok(());
+        };
+        if let Some(parent) = tombstone.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create {}", parent.display()))?;
+        }
+        std::fs::write(&tombstone, "")
+            .with_context(|| format!("Failed to write {}", tombstone.display()))
+    }
+
+    /// Removes a generated file, if it exists
+    pub fn remove_file(&self, path: &Path) -> Result<()> {
+        let state_path = self.write_path(path)?;
+        if state_path.exists() {
+            std::fs::remove_file(&state_path)
+                .with_context(|| format!("Failed to delete {}", state_path.display()))?;
+        }
+        self.add_tombstone(path)
+    }
+
+    /// Removes a directory and everything in it, if it exists
+    pub fn remove_dir_all(&self, path: &Path) -> Result<()> {
+        let state_path = self.write_path(path)?;
+        if state_path.exists() {
+            std::fs::remove_dir_all(&state_path)
+                .with_context(|| format!("Failed to delete {}", state_path.display()))?;
+        }
+        self.add_tombstone(path)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::CitadelPaths;
+    use std::path::Path;
+
+    #[test]
+    fn redirects_writes_to_state_dir() {
+        let paths = CitadelPaths::new(Path::new("/citadel"), Some(Path::new("/nonexistent-state")));
+        assert_eq!(
+            paths.in_state_dir(Path::new("/citadel/apps/ports.yml")),
+            Some(Path::new("/nonexistent-state/apps/ports.yml").to_path_buf())
+        );
+        assert_eq!(paths.in_state_dir(Path::new("/tmp/apps/ports.yml")), None);
+        // Reads fall back to the root if the state dir does not contain the file
+        assert_eq!(
+            paths.read_path(Path::new("/citadel/apps/ports.yml")),
+            Path::new("/citadel/apps/ports.yml")
+        );
+    }
+
+    #[test]
+    fn removed_files_are_not_read_from_root() {
+        let root = tempdir::TempDir::new("paths-root").unwrap();
+        let state_dir = tempdir::TempDir::new("paths-state").unwrap();
+        let paths = CitadelPaths::new(root.path(), Some(state_dir.path()));
+        let app_dir = root.path().join("apps").join("lnd");
+        std::fs::create_dir_all(&app_dir).unwrap();
+        std::fs::write(app_dir.join("docker-compose.yml"), "services: {}\n").unwrap();
+        std::fs::write(app_dir.join("app.yml"), "version: 4\n").unwrap();
+
+        paths
+            .remove_file(&app_dir.join("docker-compose.yml"))
+            .unwrap();
+        assert!(!paths.exists(&app_dir.join("docker-compose.yml")));
+        assert!(paths.exists(&app_dir.join("app.yml")));
+        // The root is never written to
+        assert!(app_dir.join("docker-compose.yml").exists());
+
+        // Writing the file again makes it visible
+        paths
+            .write(&app_dir.join("docker-compose.yml"), "services: {}\n")
+            .unwrap();
+        assert!(paths.exists(&app_dir.join("docker-compose.yml")));
+
+        paths.remove_dir_all(&app_dir).unwrap();
+        assert!(!paths.exists(&app_dir.join("app.yml")));
+        assert!(!paths.exists(&app_dir.join("docker-compose.yml")));
+        assert!(app_dir.join("app.yml").exists());
+    }
+
+    #[test]
+    fn writes_in_place_without_state_dir() {
+        let paths = CitadelPaths::new(Path::new("/citadel"), None);
+        assert_eq!(
+            paths.write_path(Path::new("/citadel/.env")).unwrap(),
+            Path::new("/citadel/.env")
+        );
+    }
+}
@@ -0,0 +1,232 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+
+use super::{
+    app_list::app_ids, paths::CitadelPaths, PortCacheMap, PortCacheMapEntry, RESERVED_PORTS,
+};
+use crate::composegenerator::v4::types::PortPriority;
+
+/// An outside port in ports.cache.yml
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct PortEntry {
+    pub port: u16,
+    /// The app, or the name of the service the port is reserved for
+    pub app: String,
+    pub container: String,
+    pub internal_port: u16,
+    pub priority: PortPriority,
+    /// Reserved by the operator for a service outside of the app manager
+    pub reserved: bool,
+    /// The app no longer exists, the port can be released
+    pub stale: bool,
+}
+
+fn cache_file(citadel_root: &Path) -> PathBuf {
+    citadel_root.join("apps").join("ports.cache.yml")
+}
+
+fn load(paths: &CitadelPaths, citadel_root: &Path) -> Result<PortCacheMap> {
+    let cache_file = cache_file(citadel_root);
+    if !paths.exists(&cache_file) {
+        return Ok(HashMap::new());
+    }
+    serde_yaml::from_reader(paths.open(&cache_file)?).context("Failed to load ports.cache.yml")
+}
+
+fn save(paths: &CitadelPaths, citadel_root: &Path, cache: &PortCacheMap) -> Result<()> {
+    paths.write_atomic(&cache_file(citadel_root), serde_yaml::to_string(cache)?)
+}
+
+/// Whether an entry belongs to an app that was removed from the apps dir
+fn is_stale(entry: &PortCacheMapEntry, app_ids: &BTreeSet<String>) -> bool {
+    !entry.reserved && !app_ids.contains(&entry.app)
+}
+
+/// Lists all outside ports in the port cache, ordered by port
+pub fn list(citadel_root: &str, state_dir: &Option<String>) -> Result<Vec<PortEntry>> {
+    let citadel_root = Path::new(citadel_root);
+    let paths = CitadelPaths::new(citadel_root, state_dir.as_deref().map(Path::new));
+    let app_ids = app_ids(&citadel_root.join("apps"))?;
+    let mut entries: Vec<PortEntry> = load(&paths, citadel_root)?
+        .into_iter()
+        .map(|(port, entry)| PortEntry {
+            port,
+            stale: is_stale(&entry, &app_ids),
+            app: entry.app,
+            container: entry.container,
+            internal_port: entry.internal_port,
+            priority: entry.priority,
+            reserved: entry.reserved,
+        })
+        .collect();
+    entries.sort_by_key(|entry| entry.port);
+    Ok(entries)
+}
+
+/// Reserves an outside port for a service outside of the app manager, apps won't get it anymore
+pub fn reserve(
+    citadel_root: &str,
+    state_dir: &Option<String>,
+    port: u16,
+    name: &str,
+) -> Result<()> {
+    let citadel_root = Path::new(citadel_root);
+    let paths = CitadelPaths::new(citadel_root, state_dir.as_deref().map(Path::new));
+    if port == 0 {
+        bail!("Port 0 can't be reserved");
+    }
+    if RESERVED_PORTS.contains(&port) {
+        bail!("Port {port} is always reserved for the node");
+    }
+    if app_ids(&citadel_root.join("apps"))?.contains(name) {
+        bail!("{name} is an app, reservations need a different name");
+    }
+    let mut cache = load(&paths, citadel_root)?;
+    if let Some(entry) = cache.get(&port) {
+        if entry.reserved && entry.app == name {
+            return Ok(());
+        }
+        bail!(
+            "Port {port} is already used by {} (container {}), release it first",
+            entry.app,
+            entry.container
+        );
+    }
+    cache.insert(
+        port,
+        PortCacheMapEntry {
+            app: name.to_string(),
+            internal_port: port,
+            container: "external".to_string(),
+            dynamic: false,
+            implements: None,
+            priority: PortPriority::Required,
+            reserved: true,
+        },
+    );
+    save(&paths, citadel_root, &cache)
+}
+
+/// Removes ports from the port cache, and with `stale` all ports of apps that no longer exist.
+/// Ports of existing apps are only released with `force`, they get a new port on the next conversion.
+/// Returns the released ports.
+pub fn release(
+    citadel_root: &str,
+    state_dir: &Option<String>,
+    ports: &[u16],
+    stale: bool,
+    force: bool,
+) -> Result<Vec<u16>> {
+    let citadel_root = Path::new(citadel_root);
+    let paths = CitadelPaths::new(citadel_root, state_dir.as_deref().map(Path::new));
+    let app_ids = app_ids(&citadel_root.join("apps"))?;
+    let mut cache = load(&paths, citadel_root)?;
+    let mut released = BTreeSet::new();
+    for port in ports {
+        let Some(entry) = cache.get(port) else {
+            bail!("Port {port} is not assigned");
+        };
+        if !force && !entry.reserved && !is_stale(entry, &app_ids) {
+            bail!(
+                "Port {port} belongs to {}, pass --force to assign it a new port",
+                entry.app
+            );
+        }
+        released.insert(*port);
+    }
+    if stale {
+        released.extend(
+            cache
+                .iter()
+                .filter(|(_, entry)| is_stale(entry, &app_ids))
+                .map(|(port, _)| *port),
+        );
+    }
+    if !released.is_empty() {
+        cache.retain(|port, _| !released.contains(port));
+        save(&paths, citadel_root, &cache)?;
+    }
+    Ok(released.into_iter().collect())
+}
+
+/// Formats the ports as a table
+pub fn format_table(entries: &[PortEntry]) -> String {
+    let mut table = format!(
+        "{:>5}  {:<24} {:<16} {:>8}  {:<11} {}\n",
+        "PORT", "APP", "CONTAINER", "INTERNAL", "PRIORITY", "NOTE"
+    );
+    for entry in entries {
+        let note = if entry.reserved {
+            "reserved"
+        } else if entry.stale {
+            "stale"
+        } else {
+            ""
+        };
+        // Debug of the priority ignores the width
+        let priority = format!("{:?}", entry.priority);
+        let _ = writeln!(
+            table,
+            "{:>5}  {:<24} {:<16} {:>8}  {:<11} {}",
+            entry.port, entry.app, entry.container, entry.internal_port, priority, note
+        );
+    }
+    table
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use tempdir::TempDir;
+
+    use super::{list, release, reserve};
+
+    #[test]
+    fn reserves_and_releases_ports() {
+        let root = TempDir::new("port-cache").unwrap();
+        std::fs::create_dir_all(root.path().join("apps").join("lnd")).unwrap();
+        std::fs::write(root.path().join("apps").join("lnd").join("app.yml"), "").unwrap();
+        std::fs::write(
+            root.path().join("apps").join("ports.cache.yml"),
+            "3001:\n  app: lnd\n  internal_port: 3001\n  container: web\n  dynamic: false\n  implements: null\n  priority: Optional\n\
+             3002:\n  app: removed\n  internal_port: 80\n  container: web\n  dynamic: false\n  implements: null\n  priority: Optional\n",
+        )
+        .unwrap();
+        let citadel_root = root.path().to_str().unwrap();
+
+        reserve(citadel_root, &None, 9000, "nginx").unwrap();
+        assert!(reserve(citadel_root, &None, 3001, "nginx").is_err());
+        assert!(reserve(citadel_root, &None, 9001, "lnd").is_err());
+        let entries = list(citadel_root, &None).unwrap();
+        let notes: HashMap<u16, (bool, bool)> = entries
+            .iter()
+            .map(|entry| (entry.port, (entry.reserved, entry.stale)))
+            .collect();
+        assert_eq!(
+            notes,
+            HashMap::from([
+                (3001, (false, false)),
+                (3002, (false, true)),
+                (9000, (true, false))
+            ])
+        );
+
+        assert!(release(citadel_root, &None, &[3001], false, false).is_err());
+        assert_eq!(
+            release(citadel_root, &None, &[9000], true, false).unwrap(),
+            vec![3002, 9000]
+        );
+        let ports: Vec<u16> = list(citadel_root, &None)
+            .unwrap()
+            .iter()
+            .map(|entry| entry.port)
+            .collect();
+        assert_eq!(ports, vec![3001]);
+    }
+}
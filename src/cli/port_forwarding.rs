@@ -0,0 +1,391 @@
+use std::{
+    collections::BTreeMap,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket},
+    path::PathBuf,
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use super::{node_config::NodeConfig, paths::CitadelPaths};
+use crate::composegenerator::types::{ForwardedPort, Protocol};
+
+const NAT_PMP_PORT: u16 = 5351;
+/// NAT-PMP has no permanent forwards, they are requested for this long instead
+const NAT_PMP_LIFETIME: u32 = 86400;
+
+/// Router port forwarding settings in app-manager.toml
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct PortForwardingConfig {
+    /// Request port forwards for installed apps via UPnP or NAT-PMP
+    pub enabled: bool,
+    /// How long port forwards are valid in seconds, 0 means forever.
+    /// NAT-PMP does not support permanent forwards, they are requested for a day instead.
+    /// serve and watch request forwards again at half their lifetime, so they don't expire.
+    pub lease_duration: u32,
+    /// The router to use for NAT-PMP, defaults to the default gateway
+    pub gateway: Option<Ipv4Addr>,
+}
+
+impl PortForwardingConfig {
+    /// How often forwards need to be requested again, None if forwarding is disabled
+    pub fn renew_interval(&self) -> Option<Duration> {
+        if !self.enabled {
+            return None;
+        }
+        let lifetime = if self.lease_duration == 0 {
+            NAT_PMP_LIFETIME
+        } else {
+            self.lease_duration
+        };
+        Some(Duration::from_secs(u64::from(lifetime / 2).max(60)))
+    }
+}
+
+/// Reads the default gateway from /proc/net/route
+fn parse_default_gateway(route_table: &str) -> Option<Ipv4Addr> {
+    route_table.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.get(1) != Some(&"00000000") {
+            return None;
+        }
+        let gateway = u32::from_str_radix(fields.get(2)?, 16).ok()?;
+        // The address is stored in network byte order, but printed as a host integer
+        Some(Ipv4Addr::from(gateway.to_le_bytes()))
+    })
+}
+
+enum Gateway {
+    Upnp(igd::Gateway, Ipv4Addr),
+    NatPmp(SocketAddrV4),
+}
+
+impl Gateway {
+    fn find(config: &PortForwardingConfig) -> Result<Self> {
+        let options = igd::SearchOptions {
+            timeout: Some(Duration::from_secs(3)),
+            ..Default::default()
+        };
+        if let Ok(gateway) = igd::search_gateway(options) {
+            // The address the router can reach us on
+            let socket = UdpSocket::bind("0.0.0.0:0")?;
+            socket.connect(gateway.addr)?;
+            let SocketAddr::V4(local_addr) = socket.local_addr()? else {
+                bail!("Failed to determine the local IPv4 address");
+            };
+            return Ok(Gateway::Upnp(gateway, *local_addr.ip()));
+        }
+        let gateway = match config.gateway {
+            Some(gateway) => gateway,
+            None => parse_default_gateway(&std::fs::read_to_string("/proc/net/route")?)
+                .context("No UPnP router found and no default gateway for NAT-PMP")?,
+        };
+        Ok(Gateway::NatPmp(SocketAddrV4::new(gateway, NAT_PMP_PORT)))
+    }
+
+    fn nat_pmp_request(gateway: &SocketAddrV4, request: &[u8]) -> Result<Vec<u8>> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.set_read_timeout(Some(Duration::from_secs(3)))?;
+        socket.connect(gateway)?;
+        socket.send(request)?;
+        let mut response = [0u8; 16];
+        let len = socket
+            .recv(&mut response)
+            .context("No response from the NAT-PMP gateway")?;
+        if len < 8 || response[1] != request[1] + 128 {
+            bail!("Invalid NAT-PMP response");
+        }
+        let result = u16::from_be_bytes([response[2], response[3]]);
+        if result != 0 {
+            bail!("NAT-PMP request failed with result code {result}");
+        }
+        Ok(response[..len].to_vec())
+    }
+
+    fn nat_pmp_mapping(
+        gateway: &SocketAddrV4,
+        protocol: Protocol,
+        port: u16,
+        lifetime: u32,
+    ) -> Result<()> {
+        let opcode = match protocol {
+            Protocol::Udp => 1,
+            Protocol::Tcp => 2,
+        };
+        let external_port = if lifetime == 0 { 0 } else { port };
+        let mut request = vec![0, opcode, 0, 0];
+        request.extend(port.to_be_bytes());
+        request.extend(external_port.to_be_bytes());
+        request.extend(lifetime.to_be_bytes());
+        let response = Self::nat_pmp_request(gateway, &request)?;
+        if lifetime != 0 && response.get(10..12) != Some(&port.to_be_bytes()[..]) {
+            bail!("The router assigned a different external port");
+        }
+        Ok(())
+    }
+
+    /// Forwards a port and returns the external IP
+    fn add(
+        &self,
+        protocol: Protocol,
+        port: u16,
+        lease_duration: u32,
+        description: &str,
+    ) -> Result<Ipv4Addr> {
+        match self {
+            Gateway::Upnp(gateway, local_ip) => {
+                let upnp_protocol = match protocol {
+                    Protocol::Tcp => igd::PortMappingProtocol::TCP,
+                    Protocol::Udp => igd::PortMappingProtocol::UDP,
+                };
+                gateway.add_port(
+                    upnp_protocol,
+                    port,
+                    SocketAddrV4::new(*local_ip, port),
+                    lease_duration,
+                    description,
+                )?;
+                Ok(gateway.get_external_ip()?)
+            }
+            Gateway::NatPmp(gateway) => {
+                let lifetime = if lease_duration == 0 {
+                    NAT_PMP_LIFETIME
+                } else {
+                    lease_duration
+                };
+                Self::nat_pmp_mapping(gateway, protocol, port, lifetime)?;
+                let response = Self::nat_pmp_request(gateway, &[0, 0])?;
+                let ip: [u8; 4] = response
+                    .get(8..12)
+                    .and_then(|ip| ip.try_into().ok())
+                    .context("Invalid NAT-PMP response")?;
+                Ok(Ipv4Addr::from(ip))
+            }
+        }
+    }
+
+    fn remove(&self, protocol: Protocol, port: u16) -> Result<()> {
+        match self {
+            Gateway::Upnp(gateway, _) => {
+                let upnp_protocol = match protocol {
+                    Protocol::Tcp => igd::PortMappingProtocol::TCP,
+                    Protocol::Udp => igd::PortMappingProtocol::UDP,
+                };
+                Ok(gateway.remove_port(upnp_protocol, port)?)
+            }
+            Gateway::NatPmp(gateway) => Self::nat_pmp_mapping(gateway, protocol, port, 0),
+        }
+    }
+}
+
+/// Requests port forwards for installed apps and removes the ones no longer needed.
+/// Active forwards are tracked in apps/port-forwards.json, so they can be removed when an app is uninstalled.
+pub struct PortForwarder<'a> {
+    paths: &'a CitadelPaths,
+    config: &'a PortForwardingConfig,
+    state_file: PathBuf,
+    previous: BTreeMap<String, Vec<ForwardedPort>>,
+    current: BTreeMap<String, Vec<ForwardedPort>>,
+    /// Only searched for once it's needed, None if it could not be found
+    gateway: Option<Option<Gateway>>,
+}
+
+impl<'a> PortForwarder<'a> {
+    pub fn new(paths: &'a CitadelPaths, config: &'a PortForwardingConfig) -> Result<Self> {
+        let state_file = paths.root().join("apps").join("port-forwards.json");
+        let previous = if paths.exists(&state_file) {
+            serde_json::from_str(&paths.read_to_string(&state_file)?)
+                .context("Failed to load port-forwards.json")?
+        } else {
+            BTreeMap::new()
+        };
+        Ok(Self {
+            paths,
+            config,
+            state_file,
+            previous,
+            current: BTreeMap::new(),
+            gateway: None,
+        })
+    }
+
+    fn gateway(&mut self) -> Option<&Gateway> {
+        let config = self.config;
+        self.gateway
+            .get_or_insert_with(|| {
+                Gateway::find(config)
+                    .map_err(|err| tracing::warn!("Port forwarding is not available: {:#}", err))
+                    .ok()
+            })
+            .as_ref()
+    }
+
+    /// Forwards the ports of an installed app, the external IP is set for all successful forwards.
+    /// Failed forwards are kept in port-forwards.json, so they are tried again on renewal.
+    pub fn forward(&mut self, app_id: &str, port_forwards: &mut [ForwardedPort]) {
+        if !self.config.enabled {
+            return;
+        }
+        if port_forwards.is_empty() {
+            self.current.insert(app_id.to_string(), Vec::new());
+            return;
+        }
+        let lease_duration = self.config.lease_duration;
+        let Some(gateway) = self.gateway() else {
+            return;
+        };
+        let description = format!("citadel-{app_id}");
+        for forward in port_forwards.iter_mut() {
+            match gateway.add(forward.protocol, forward.port, lease_duration, &description) {
+                Ok(external_ip) => forward.external_ip = Some(external_ip.to_string()),
+                Err(err) => {
+                    forward.external_ip = None;
+                    tracing::warn!(
+                        "Failed to forward port {} ({}) for app {}: {:#}",
+                        forward.port,
+                        forward.protocol,
+                        app_id,
+                        err
+                    )
+                }
+            }
+        }
+        self.current
+            .insert(app_id.to_string(), port_forwards.to_vec());
+    }
+
+    /// Requests all forwards in port-forwards.json again before they expire
+    fn renew(mut self) -> Result<()> {
+        let previous = self.previous.clone();
+        let apps: Vec<String> = previous.keys().cloned().collect();
+        for (app_id, mut forwards) in previous {
+            self.forward(&app_id, &mut forwards);
+        }
+        self.finish(&apps)
+    }
+
+    /// Removes forwards that are no longer needed and saves the active ones.
+    /// Forwards are only removed for apps that are no longer installed, or ports an app no longer forwards.
+    /// Does nothing if port forwarding is disabled.
+    pub fn finish(mut self, installed: &[String]) -> Result<()> {
+        if !self.config.enabled {
+            return Ok(());
+        }
+        let previous = std::mem::take(&mut self.previous);
+        for (app_id, forward) in stale_forwards(previous, &mut self.current, installed) {
+            let Some(gateway) = self.gateway() else {
+                // Keep it, so removing it can be tried again later
+                self.current.entry(app_id).or_default().push(forward);
+                continue;
+            };
+            if let Err(err) = gateway.remove(forward.protocol, forward.port) {
+                tracing::warn!(
+                    "Failed to remove port forward {} ({}) of app {}: {:#}",
+                    forward.port,
+                    forward.protocol,
+                    app_id,
+                    err
+                );
+            }
+        }
+        self.current.retain(|_, forwards| !forwards.is_empty());
+        self.paths
+            .write(&self.state_file, serde_json::to_string(&self.current)?)?;
+        Ok(())
+    }
+}
+
+/// Moves the previous forwards of installed apps that were not forwarded again to `current`,
+/// for example because they failed to convert, and returns the forwards to remove
+fn stale_forwards(
+    previous: BTreeMap<String, Vec<ForwardedPort>>,
+    current: &mut BTreeMap<String, Vec<ForwardedPort>>,
+    installed: &[String],
+) -> Vec<(String, ForwardedPort)> {
+    let mut stale = Vec::new();
+    for (app_id, forwards) in previous {
+        let Some(current_forwards) = current.get(&app_id) else {
+            if installed.contains(&app_id) {
+                current.insert(app_id, forwards);
+            } else {
+                stale.extend(
+                    forwards
+                        .into_iter()
+                        .map(|forward| (app_id.clone(), forward)),
+                );
+            }
+            continue;
+        };
+        let removed: Vec<ForwardedPort> = forwards
+            .into_iter()
+            .filter(|forward| {
+                !current_forwards
+                    .iter()
+                    .any(|other| other.port == forward.port && other.protocol == forward.protocol)
+            })
+            .collect();
+        stale.extend(removed.into_iter().map(|forward| (app_id.clone(), forward)));
+    }
+    stale
+}
+
+/// Requests the forwards of the last conversion again, so they don't expire before the next one.
+/// Does nothing if port forwarding is disabled.
+pub fn renew(paths: &CitadelPaths) -> Result<()> {
+    let config = NodeConfig::load(paths, paths.root())?.port_forwarding;
+    if !config.enabled {
+        return Ok(());
+    }
+    PortForwarder::new(paths, &config)?.renew()
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::BTreeMap, net::Ipv4Addr};
+
+    use super::{parse_default_gateway, stale_forwards};
+    use crate::composegenerator::types::{ForwardedPort, Protocol};
+
+    #[test]
+    fn finds_default_gateway() {
+        let route_table =
+            "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT
+eth0\t0002A8C0\t00000000\t0001\t0\t0\t0\t00FFFFFF\t0\t0\t0
+eth0\t00000000\t0102A8C0\t0003\t0\t0\t0\t00000000\t0\t0\t0
+";
+        assert_eq!(
+            parse_default_gateway(route_table),
+            Some(Ipv4Addr::new(192, 168, 2, 1))
+        );
+    }
+
+    #[test]
+    fn only_removes_forwards_of_uninstalled_apps() {
+        let forward = |port| ForwardedPort {
+            container: "main".to_string(),
+            port,
+            protocol: Protocol::Tcp,
+            external_ip: Some("203.0.113.1".to_string()),
+        };
+        let previous = BTreeMap::from([
+            ("lnd".to_string(), vec![forward(9735), forward(10009)]),
+            ("electrs".to_string(), vec![forward(50001)]),
+            ("removed".to_string(), vec![forward(8333)]),
+        ]);
+        // electrs failed to convert
+        let mut current = BTreeMap::from([("lnd".to_string(), vec![forward(9735)])]);
+        let installed = ["lnd".to_string(), "electrs".to_string()];
+        let stale: Vec<(String, u16)> = stale_forwards(previous, &mut current, &installed)
+            .into_iter()
+            .map(|(app_id, forward)| (app_id, forward.port))
+            .collect();
+        assert_eq!(
+            stale,
+            vec![("lnd".to_string(), 10009), ("removed".to_string(), 8333)]
+        );
+        assert_eq!(current["electrs"], vec![forward(50001)]);
+        assert_eq!(current["lnd"], vec![forward(9735)]);
+    }
+}
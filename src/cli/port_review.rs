@@ -0,0 +1,87 @@
+use std::fmt::Write as _;
+use std::io::{BufRead, Write};
+
+use serde::{Deserialize, Serialize};
+
+/// A public port of an app that is moved to make room for another app's required port
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct PortChange {
+    pub app: String,
+    pub container: String,
+    pub old_port: u16,
+    pub new_port: u16,
+    /// The app that requires the old port
+    pub taken_by: String,
+}
+
+/// What to do if ports of installed apps would change
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum PortChangePolicy {
+    /// Apply the changes
+    #[default]
+    Apply,
+    /// Show the changes and ask for confirmation
+    Ask,
+    /// Show the changes and abort
+    Reject,
+}
+
+/// Formats port changes for the operator
+pub fn report(changes: &[PortChange]) -> String {
+    let mut report = String::from("The following ports of installed apps would change:\n");
+    for change in changes {
+        let _ = writeln!(
+            report,
+            "  {} (container {}): {} -> {} (required by {})",
+            change.app, change.container, change.old_port, change.new_port, change.taken_by
+        );
+    }
+    report
+}
+
+/// Decides whether port changes of installed apps may be applied.
+/// With [PortChangePolicy::Ask], the operator is asked on `output` and answers on `input`.
+pub fn confirm(
+    policy: PortChangePolicy,
+    changes: &[PortChange],
+    mut input: impl BufRead,
+    mut output: impl Write,
+) -> std::io::Result<bool> {
+    if changes.is_empty() || policy == PortChangePolicy::Apply {
+        return Ok(true);
+    }
+    write!(output, "{}", report(changes))?;
+    if policy == PortChangePolicy::Reject {
+        return Ok(false);
+    }
+    write!(output, "Apply these changes? [y/N] ")?;
+    output.flush()?;
+    let mut answer = String::new();
+    input.read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{confirm, PortChange, PortChangePolicy};
+
+    #[test]
+    fn asks_before_changing_ports() {
+        let changes = vec![PortChange {
+            app: "lnd".to_string(),
+            container: "main".to_string(),
+            old_port: 9735,
+            new_port: 9736,
+            taken_by: "core-lightning".to_string(),
+        }];
+        let mut output = Vec::new();
+        assert!(confirm(PortChangePolicy::Ask, &changes, &b"y\n"[..], &mut output).unwrap());
+        assert!(String::from_utf8(output)
+            .unwrap()
+            .contains("lnd (container main): 9735 -> 9736 (required by core-lightning)"));
+        assert!(!confirm(PortChangePolicy::Ask, &changes, &b"\n"[..], Vec::new()).unwrap());
+        assert!(!confirm(PortChangePolicy::Reject, &changes, &b""[..], Vec::new()).unwrap());
+        assert!(confirm(PortChangePolicy::Apply, &changes, &b""[..], Vec::new()).unwrap());
+    }
+}
@@ -1,21 +1,51 @@
-use std::{collections::HashMap, io::Read, path::Path};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+    sync::Arc,
+};
 
-use anyhow::Result;
+use anyhow::{bail, Result};
+use serde::Deserialize;
 
 #[cfg(feature = "umbrel")]
 use super::umbrel::convert;
-use super::{tera, UserJson};
+use super::{
+    app_settings, bundles, host_facts,
+    limits::run_with_timeout,
+    node_config::NodeConfig,
+    paths::CitadelPaths,
+    redaction::Redactor,
+    stores::load_stores,
+    template_env::{EnvScope, TemplateEnvConfig},
+    tera::{self, TemplateVars},
+    UserJson,
+};
+use crate::composegenerator::v4::{settings, types::SettingRule};
 
-pub fn preprocess_apps(citadel_root: &Path, app_dir: &Path) -> Result<()> {
+/// Decides which env vars the templates of the apps can read
+fn env_scope(apps: &[std::fs::DirEntry], config: &TemplateEnvConfig) -> EnvScope {
+    let app_ids: Vec<String> = apps
+        .iter()
+        .map(|app| app.file_name().to_string_lossy().to_string())
+        .collect();
+    EnvScope::new(app_ids.iter().map(String::as_str), config)
+}
+
+/// Renders the app.yml templates of all apps in app_dir, or only of the app `only`.
+/// Returns the apps that failed, app ID -> error.
+pub fn preprocess_apps(
+    paths: &CitadelPaths,
+    app_dir: &Path,
+    only: Option<&str>,
+) -> Result<BTreeMap<String, String>> {
+    let citadel_root = paths.root();
+    let node_config = NodeConfig::load(paths, citadel_root)?;
     let mut citadel_seed = None;
 
     let citadel_seed_file = citadel_root.join("db").join("citadel-seed").join("seed");
 
-    if citadel_seed_file.exists() {
-        let mut citadel_seed_file = std::fs::File::open(citadel_seed_file).unwrap();
-        let mut citadel_seed_str = String::new();
-        citadel_seed_file.read_to_string(&mut citadel_seed_str)?;
-        citadel_seed = Some(citadel_seed_str);
+    if paths.exists(&citadel_seed_file) {
+        citadel_seed = Some(paths.read_to_string(&citadel_seed_file)?);
     }
 
     let apps = std::fs::read_dir(app_dir)?;
@@ -29,58 +59,90 @@ pub fn preprocess_apps(citadel_root: &Path, app_dir: &Path) -> Result<()> {
             false
         }
     });
+    let apps = apps.collect::<std::io::Result<Vec<_>>>()?;
+    let scope = env_scope(&apps, &node_config.template_env);
 
     let mut env_vars = HashMap::new();
+    let mut redactor = Redactor::new(&node_config.redaction);
 
     #[allow(deprecated)]
-    if let Ok(dot_env) = dotenv::from_filename_iter(citadel_root.join(".env")) {
-        env_vars = HashMap::from_iter(dot_env.filter_map(|res| {
-            if let Ok(res) = res {
-                Some(res)
-            } else {
-                tracing::error!("{}", res.unwrap_err());
+    if let Ok(dot_env) = dotenv::from_filename_iter(paths.read_path(&citadel_root.join(".env"))) {
+        env_vars = HashMap::from_iter(dot_env.filter_map(|res| match res {
+            Ok(res) => Some(res),
+            Err(err) => {
+                tracing::error!("{}", redactor.env_error(&err));
                 None
             }
         }));
     }
+    redactor.add_env(&env_vars);
+    if let Some(seed) = &citadel_seed {
+        redactor.add_secret("APP_SEED", seed.trim());
+    }
 
     if env_vars.is_empty() && citadel_seed.is_none() {
         tracing::warn!("Citadel does not seem to be set up yet!");
     }
 
     let mut services = Vec::<String>::new();
-    let user_json = std::fs::File::open(citadel_root.join("db").join("user.json"));
+    let user_json = paths.open(&citadel_root.join("db").join("user.json"));
     if let Ok(user_json) = user_json {
         let user_json = serde_json::from_reader::<_, UserJson>(user_json);
         if let Ok(user_json) = user_json {
             services = user_json.installed_apps;
         }
     }
-    services.append(&mut vec!["bitcoind".to_string()]);
+    node_config.base_services.add_to(&mut services);
+    let stores = load_stores(paths, citadel_root)?;
+    let bundle_values = bundles::load_values(paths, citadel_root)?;
+    let setting_values = app_settings::load_values(paths, citadel_root)?;
+    let host = host_facts::load(paths, citadel_root)?;
+    let shared_context = tera::shared_context(&services, &node_config.features, &host);
+    let env_vars = Arc::new(env_vars);
 
+    let mut failed = BTreeMap::new();
     for app in apps {
-        let app = app?;
         let app_id = app.file_name();
         let app_id = app_id.to_str().unwrap();
+        if only.is_some_and(|only| only != app_id) {
+            continue;
+        }
+        let mut app_env_vars = TemplateVars {
+            node: env_vars.clone(),
+            app: bundles::app_env_vars(&stores, &bundle_values, app_id),
+        };
+        let settings = app_settings::app_env_vars(&setting_values, app_id);
+        redactor.add_env(&settings);
+        app_env_vars.app.extend(settings);
 
-        if let Err(tera_error) =
-            tera::convert_app_yml(&app.path(), &services, &env_vars, &citadel_seed)
-        {
-            tracing::error!("Error converting app jinja files: {:?}", tera_error);
+        if let Err(tera_error) = tera::convert_app_yml(
+            &app.path(),
+            &paths.write_path(&app.path())?,
+            &shared_context,
+            &app_env_vars,
+            &scope,
+            &citadel_seed,
+            &node_config.conversion,
+        ) {
+            // Template errors can quote the values of env vars
+            let error = redactor.text(&format!("{tera_error:#}"));
+            tracing::error!("Error converting app jinja files: {}", error);
+            failed.insert(app_id.to_string(), error);
             continue;
         }
 
         let app_yml = app.path().join("app.yml");
-        if !app_yml.exists() {
+        if !paths.exists(&app_yml) {
             #[cfg(feature = "umbrel")]
             {
                 let umbrel_app_yml = app.path().join("umbrel-app.yml");
                 if umbrel_app_yml.exists() {
-                    if let Err(convert_error) = convert(&app.path()) {
+                    if let Err(convert_error) = convert(paths, &app.path()) {
                         tracing::error!(
                             "Error converting Umbrel app to Citadel app: {:?}",
                             convert_error
                         );
+                        failed.insert(app_id.to_string(), format!("{convert_error:#}"));
                         continue;
                     }
                 } else {
@@ -94,22 +156,75 @@ pub fn preprocess_apps(citadel_root: &Path, app_dir: &Path) -> Result<()> {
                 continue;
             }
         }
+
+        if let Err(err) = check_settings(paths, &app_yml, app_id, &app_env_vars, &scope) {
+            // The values of settings can be secrets
+            let error = redactor.text(&format!("{err:#}"));
+            tracing::error!("Invalid settings for {}: {}", app_id, error);
+            failed.insert(app_id.to_string(), error);
+        }
     }
 
+    Ok(failed)
+}
+
+/// Checks the values the operator set against the rules the rendered app.yml declares
+fn check_settings(
+    paths: &CitadelPaths,
+    app_yml: &Path,
+    app_id: &str,
+    env_vars: &TemplateVars,
+    scope: &EnvScope,
+) -> Result<()> {
+    #[derive(Deserialize)]
+    struct Settings {
+        #[serde(default)]
+        settings: BTreeMap<String, SettingRule>,
+    }
+    if !paths.exists(app_yml) {
+        return Ok(());
+    }
+    // Other problems with the file are reported when it is loaded
+    let Ok(Settings { settings }) = serde_yaml::from_str(&paths.read_to_string(app_yml)?) else {
+        return Ok(());
+    };
+    if let Some((key, owner)) = settings.keys().find_map(|key| {
+        scope
+            .owner(key)
+            .filter(|owner| *owner != app_id)
+            .map(|owner| (key, owner))
+    }) {
+        bail!("{key} is a setting of {owner}, apps can only have rules for their own settings");
+    }
+    let values: HashMap<String, String> = settings
+        .keys()
+        .filter_map(|key| Some((key.clone(), env_vars.get(key)?.clone())))
+        .collect();
+    let problems = settings::check_values(&settings, &values);
+    if !problems.is_empty() {
+        bail!("Invalid settings: {}", problems.join("; "));
+    }
     Ok(())
 }
 
-pub fn preprocess_config_files(citadel_root: &Path, app_dir: &Path) -> Result<()> {
+/// Renders the other jinja files of all apps in app_dir, or only of the app `only`.
+/// Returns the apps that failed, app ID -> error.
+pub fn preprocess_config_files(
+    paths: &CitadelPaths,
+    app_dir: &Path,
+    only: Option<&str>,
+) -> Result<BTreeMap<String, String>> {
+    let citadel_root = paths.root();
+    let node_config = NodeConfig::load(paths, citadel_root)?;
+    let limits = node_config.conversion;
     let mut citadel_seed = None;
 
     let citadel_seed_file = citadel_root.join("db").join("citadel-seed").join("seed");
     let tor_dir = citadel_root.join("tor").join("data");
+    let i2p_dir = citadel_root.join("i2p");
 
-    if citadel_seed_file.exists() {
-        let mut citadel_seed_file = std::fs::File::open(citadel_seed_file)?;
-        let mut citadel_seed_str = String::new();
-        citadel_seed_file.read_to_string(&mut citadel_seed_str)?;
-        citadel_seed = Some(citadel_seed_str);
+    if paths.exists(&citadel_seed_file) {
+        citadel_seed = Some(paths.read_to_string(&citadel_seed_file)?);
     }
 
     let apps = std::fs::read_dir(app_dir)?;
@@ -123,11 +238,13 @@ pub fn preprocess_config_files(citadel_root: &Path, app_dir: &Path) -> Result<()
             false
         }
     });
+    let apps = apps.collect::<std::io::Result<Vec<_>>>()?;
+    let scope = env_scope(&apps, &node_config.template_env);
 
     let mut env_vars = Vec::new();
 
     #[allow(deprecated)]
-    if let Ok(dot_env) = dotenv::from_filename_iter(citadel_root.join(".env")) {
+    if let Ok(dot_env) = dotenv::from_filename_iter(paths.read_path(&citadel_root.join(".env"))) {
         env_vars = dot_env.collect();
     }
 
@@ -136,46 +253,95 @@ pub fn preprocess_config_files(citadel_root: &Path, app_dir: &Path) -> Result<()
     }
 
     let mut services = Vec::<String>::new();
-    let user_json = std::fs::File::open(citadel_root.join("db").join("user.json"));
+    let user_json = paths.open(&citadel_root.join("db").join("user.json"));
     if let Ok(user_json) = user_json {
         let user_json = serde_json::from_reader::<_, UserJson>(user_json);
         if let Ok(user_json) = user_json {
             services = user_json.installed_apps;
         }
     }
-    services.append(&mut vec!["bitcoind".to_string()]);
+    node_config.base_services.add_to(&mut services);
 
     // Collect the env vars into an hashmap, logging errors
+    let mut redactor = Redactor::new(&node_config.redaction);
     let env_vars: HashMap<String, String> = env_vars
         .into_iter()
-        .filter_map(|result| {
-            if let Ok((key, value)) = result {
-                Some((key, value))
-            } else {
-                tracing::warn!("Failed to parse env var: {:?}", result);
+        .filter_map(|result| match result {
+            Ok((key, value)) => Some((key, value)),
+            Err(err) => {
+                tracing::warn!("Failed to parse env var: {}", redactor.env_error(&err));
                 None
             }
         })
         .collect();
+    redactor.add_env(&env_vars);
+    if let Some(seed) = &citadel_seed {
+        redactor.add_secret("APP_SEED", seed.trim());
+    }
+    let stores = load_stores(paths, citadel_root)?;
+    let bundle_values = bundles::load_values(paths, citadel_root)?;
+    let setting_values = app_settings::load_values(paths, citadel_root)?;
+    let host = host_facts::load(paths, citadel_root)?;
+    let shared_context = tera::shared_context(&services, &node_config.features, &host);
+    let tor_hostnames = tera::load_tor_hostnames(&tor_dir)?;
+    // Shared by the rendering threads of all apps
+    let services = Arc::new(services);
+    let citadel_seed = Arc::new(citadel_seed);
+    let env_vars = Arc::new(env_vars);
 
+    let mut failed = BTreeMap::new();
     for app in apps {
-        let app = app?;
-
-        if let Err(tera_error) = tera::convert_app_config_files(
-            &app.path(),
-            &services,
-            &citadel_seed,
-            &Some(env_vars.clone()),
-            &tor_dir,
-        ) {
+        if only.is_some_and(|only| app.file_name() != only) {
+            continue;
+        }
+        let app_path = app.path();
+        let output_dir = paths.write_path(&app_path)?;
+        let services = services.clone();
+        let citadel_seed = citadel_seed.clone();
+        let app_id = app.file_name().to_string_lossy().to_string();
+        let mut env_vars = TemplateVars {
+            node: env_vars.clone(),
+            app: bundles::app_env_vars(&stores, &bundle_values, &app_id),
+        };
+        let settings = app_settings::app_env_vars(&setting_values, &app_id);
+        redactor.add_env(&settings);
+        env_vars.app.extend(settings);
+        let shared_context = shared_context.clone();
+        let tor_hostnames = tor_hostnames.clone();
+        let i2p_dir = i2p_dir.clone();
+        let scope = scope.clone();
+        let task_limits = limits.clone();
+        let result = run_with_timeout(
+            &format!("Rendering the config files of {}", app_path.display()),
+            &app_id,
+            limits.timeout(),
+            move || {
+                tera::render_app_config_files(
+                    &app_path,
+                    &output_dir,
+                    &services,
+                    &shared_context,
+                    &citadel_seed,
+                    Some(&env_vars),
+                    &scope,
+                    &tor_hostnames,
+                    &i2p_dir,
+                    &task_limits,
+                )
+            },
+        );
+        // Files are only written if rendering finished in time
+        if let Err(tera_error) = result.and_then(tera::write_rendered_files) {
+            let error = redactor.text(&format!("{tera_error:#}"));
             tracing::error!(
-                "Error converting app jinja files for {}: {:?}",
+                "Error converting app jinja files for {}: {}",
                 app.path().display(),
-                tera_error
+                error
             );
+            failed.insert(app.file_name().to_string_lossy().to_string(), error);
             continue;
         }
     }
 
-    Ok(())
+    Ok(failed)
 }
@@ -0,0 +1,149 @@
+use std::{collections::BTreeMap, path::Path};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use super::paths::CitadelPaths;
+use crate::{
+    composegenerator::output::{labels::compose_project_name, types::ComposeSpecification},
+    naming,
+};
+
+/// The docker compose project of an app, so external tools don't need to parse its compose files
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct AppProject {
+    /// The compose project name
+    pub project: String,
+    /// Service -> name of its container
+    pub containers: BTreeMap<String, String>,
+    /// Networks the containers join, as named in the compose file
+    pub networks: Vec<String>,
+    /// Names of the Docker volumes of the app
+    pub volumes: Vec<String>,
+    /// Host paths mounted into the containers, as written in the compose file
+    pub bind_mounts: Vec<String>,
+}
+
+fn projects_file(citadel_root: &Path) -> std::path::PathBuf {
+    citadel_root.join("apps").join("projects.json")
+}
+
+/// Loads apps/projects.json as written by the last conversion, app ID -> project
+pub fn load(paths: &CitadelPaths, citadel_root: &Path) -> Result<BTreeMap<String, AppProject>> {
+    let projects_file = projects_file(citadel_root);
+    if !paths.exists(&projects_file) {
+        return Ok(BTreeMap::new());
+    }
+    serde_json::from_str(&paths.read_to_string(&projects_file)?)
+        .context("Failed to load projects.json")
+}
+
+/// Replaces apps/projects.json, readers never see a partially written file
+pub fn save(
+    paths: &CitadelPaths,
+    citadel_root: &Path,
+    projects: &BTreeMap<String, AppProject>,
+) -> Result<()> {
+    paths.write_atomic(
+        &projects_file(citadel_root),
+        serde_json::to_string_pretty(projects)?,
+    )
+}
+
+/// Describes the compose project generated for an app
+pub fn describe(app_id: &str, spec: &ComposeSpecification) -> AppProject {
+    let project = compose_project_name(app_id);
+    let mut networks = Vec::new();
+    let mut bind_mounts = Vec::new();
+    let mut containers = BTreeMap::new();
+    for (service_name, service) in spec.services.iter().flatten() {
+        containers.insert(
+            service_name.clone(),
+            naming::container_name(app_id, service_name),
+        );
+        if service.network_mode.as_deref() == Some("host") {
+            networks.push("host".to_string());
+        }
+        networks.extend(
+            service
+                .networks
+                .iter()
+                .flat_map(|networks| networks.keys().cloned()),
+        );
+        bind_mounts.extend(service.volumes.iter().filter_map(|volume| {
+            let source = volume.split(':').next()?;
+            // Everything else refers to a named volume
+            (source.starts_with('/') || source.starts_with('.') || source.starts_with('$'))
+                .then(|| source.to_string())
+        }));
+    }
+    networks.sort();
+    networks.dedup();
+    bind_mounts.sort();
+    bind_mounts.dedup();
+    AppProject {
+        volumes: spec
+            .volumes
+            .keys()
+            .map(|volume| format!("{project}_{volume}"))
+            .collect(),
+        project,
+        containers,
+        networks,
+        bind_mounts,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::describe;
+    use crate::bmap;
+    use crate::composegenerator::output::types::{
+        ComposeSpecification, NetworkEntry, Service, Volume,
+    };
+
+    #[test]
+    fn describes_compose_projects() {
+        let spec = ComposeSpecification {
+            services: Some(bmap! {
+                "web" => Service {
+                    networks: Some(bmap! {
+                        "default" => NetworkEntry {
+                            ipv4_address: Some("$APP_EXAMPLE_WEB_IP".to_string())
+                        }
+                    }),
+                    volumes: vec![
+                        "${APP_DATA_DIR}/data:/data".to_string(),
+                        "cache:/cache".to_string(),
+                    ],
+                    ..Default::default()
+                },
+                "tor" => Service {
+                    network_mode: Some("host".to_string()),
+                    volumes: vec!["${TOR_DATA_DIR}:/var/lib/tor:ro".to_string()],
+                    ..Default::default()
+                }
+            }),
+            volumes: bmap! {
+                "cache" => Volume::default()
+            },
+            ..Default::default()
+        };
+        let project = describe("example", &spec);
+        assert_eq!(project.project, "example");
+        assert_eq!(
+            project.containers,
+            bmap! {
+                "tor" => "example-tor-1".to_string(),
+                "web" => "example-web-1".to_string()
+            }
+        );
+        assert_eq!(project.networks, vec!["default", "host"]);
+        assert_eq!(project.volumes, vec!["example_cache"]);
+        assert_eq!(
+            project.bind_mounts,
+            vec!["${APP_DATA_DIR}/data", "${TOR_DATA_DIR}"]
+        );
+    }
+}
@@ -0,0 +1,107 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde::{Deserialize, Serialize};
+
+use crate::naming;
+
+/// Limits for requests to apps through Caddy, in app-manager.toml
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    /// Limits for apps without their own entry
+    pub default: AppLimits,
+    /// App ID -> limits
+    pub apps: BTreeMap<String, AppLimits>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct AppLimits {
+    /// Maximum number of requests per second and client IP, requires Caddy's rate_limit module
+    pub requests_per_second: Option<u32>,
+    /// Maximum number of connections Caddy opens to the app, further requests wait for one to be free
+    pub max_connections: Option<u32>,
+}
+
+impl RateLimitConfig {
+    pub fn for_app(&self, app_id: &str) -> &AppLimits {
+        self.apps.get(app_id).unwrap_or(&self.default)
+    }
+
+    /// The rate limiting directives of each app that has them, app ID -> directives
+    pub fn directives<'a>(
+        &self,
+        app_ids: impl Iterator<Item = &'a String>,
+    ) -> HashMap<String, String> {
+        app_ids
+            .filter_map(|app_id| {
+                let directives = self.for_app(app_id).directives(app_id);
+                (!directives.is_empty()).then(|| (app_id.clone(), directives))
+            })
+            .collect()
+    }
+
+    /// The reverse_proxy directive for an app
+    pub fn reverse_proxy(&self, app_id: &str, upstream: &str) -> String {
+        format!(
+            "reverse_proxy {upstream}{}",
+            self.for_app(app_id).transport()
+        )
+    }
+}
+
+impl AppLimits {
+    /// Directives for the site block of an app, empty if requests are not limited.
+    /// They are wrapped in a route, so they work without ordering rate_limit globally.
+    pub fn directives(&self, app_id: &str) -> String {
+        let Some(requests_per_second) = self.requests_per_second else {
+            return String::new();
+        };
+        format!(
+            "route {{\nrate_limit {{\nzone app_{} {{\nkey {{remote_host}}\nevents {}\nwindow 1s\n}}\n}}\n}}\n",
+            naming::env_name(app_id).to_lowercase(),
+            requests_per_second
+        )
+    }
+
+    /// Options to append to the reverse_proxy directive, empty if connections are not limited
+    pub fn transport(&self) -> String {
+        match self.max_connections {
+            Some(max_connections) => {
+                format!(" {{\ntransport http {{\nmax_conns_per_host {max_connections}\n}}\n}}")
+            }
+            None => String::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::RateLimitConfig;
+
+    #[test]
+    fn renders_limits() {
+        let config: RateLimitConfig = toml::from_str(
+            r#"
+[default]
+max_connections = 64
+[apps.mempool]
+requests_per_second = 20
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.reverse_proxy("lnd", "10.21.21.9:3000"),
+            "reverse_proxy 10.21.21.9:3000 {\ntransport http {\nmax_conns_per_host 64\n}\n}"
+        );
+        assert_eq!(
+            config.reverse_proxy("mempool", "10.21.21.10:3006"),
+            "reverse_proxy 10.21.21.10:3006"
+        );
+        let apps = ["lnd".to_string(), "mempool".to_string()];
+        let directives = config.directives(apps.iter());
+        assert_eq!(directives.len(), 1);
+        assert!(directives["mempool"].contains("zone app_mempool {"));
+        assert!(directives["mempool"].contains("events 20\nwindow 1s"));
+    }
+}
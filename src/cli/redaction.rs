@@ -0,0 +1,180 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde::{Deserialize, Serialize};
+
+/// Parts of env var names that mark their values as secret, matched case-insensitively
+pub const SECRET_PATTERNS: [&str; 9] = [
+    "SEED",
+    "SECRET",
+    "PASS",
+    "TOKEN",
+    "KEY",
+    "PRIVATE",
+    "MACAROON",
+    "CREDENTIAL",
+    "AUTH",
+];
+
+/// Shown instead of a secret value
+pub const REDACTED: &str = "<redacted>";
+
+/// Shorter values are not masked in free text, they would match too much
+const MIN_SECRET_LEN: usize = 6;
+
+/// Masking of secrets in logs and reports, in app-manager.toml
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct RedactionConfig {
+    /// Additional parts of env var names that mark their values as secret
+    pub patterns: Vec<String>,
+}
+
+/// Masks the values of secret env vars before they end up in logs, reports or diffs
+#[derive(Debug, Clone)]
+pub struct Redactor {
+    patterns: Vec<String>,
+    /// Secret value -> name of the env var it belongs to, longest values are replaced first
+    secrets: Vec<(String, String)>,
+}
+
+impl Redactor {
+    pub fn new(config: &RedactionConfig) -> Self {
+        Self {
+            patterns: SECRET_PATTERNS
+                .iter()
+                .map(|pattern| pattern.to_string())
+                .chain(config.patterns.iter().map(|pattern| pattern.to_uppercase()))
+                .collect(),
+            secrets: Vec::new(),
+        }
+    }
+
+    pub fn is_secret(&self, key: &str) -> bool {
+        let key = key.to_uppercase();
+        self.patterns.iter().any(|pattern| key.contains(pattern))
+    }
+
+    /// Remembers a secret value, so it is masked wherever it appears in text
+    pub fn add_secret(&mut self, key: &str, value: &str) {
+        if value.len() < MIN_SECRET_LEN {
+            return;
+        }
+        self.secrets.push((value.to_string(), key.to_string()));
+        self.secrets
+            .sort_by(|(a, _), (b, _)| b.len().cmp(&a.len()).then_with(|| a.cmp(b)));
+        self.secrets.dedup_by(|(a, _), (b, _)| a == b);
+    }
+
+    /// Remembers the values of all secret env vars
+    pub fn add_env(&mut self, env: &HashMap<String, String>) {
+        for (key, value) in env {
+            if self.is_secret(key) {
+                self.add_secret(key, value);
+            }
+        }
+    }
+
+    /// The value of an env var as it can be shown
+    pub fn value<'a>(&self, key: &str, value: &'a str) -> &'a str {
+        if self.is_secret(key) {
+            REDACTED
+        } else {
+            value
+        }
+    }
+
+    /// Replaces the known secret values in a message with the name of their env var
+    pub fn text(&self, text: &str) -> String {
+        let mut text = text.to_string();
+        for (secret, key) in &self.secrets {
+            if text.contains(secret.as_str()) {
+                text = text.replace(secret.as_str(), &format!("<redacted {key}>"));
+            }
+        }
+        text
+    }
+
+    /// Describes an error reading the .env file, which contains the line that failed to parse
+    pub fn env_error(&self, error: &dotenv::Error) -> String {
+        match error {
+            dotenv::Error::LineParse(line, index) => {
+                let key = line.split('=').next().unwrap_or_default().trim();
+                if self.is_secret(key) {
+                    format!("Error parsing the line of {key}, error at line index: {index}")
+                } else {
+                    self.text(&error.to_string())
+                }
+            }
+            error => self.text(&error.to_string()),
+        }
+    }
+
+    /// Describes how an env file changed, one entry per changed variable.
+    /// Secret values are masked, but changes to them are still listed.
+    pub fn env_changes(&self, old: &str, new: &str) -> Vec<String> {
+        let old = parse_env(old);
+        let new = parse_env(new);
+        let mut changes = Vec::new();
+        for (key, value) in &new {
+            match old.get(key) {
+                None => changes.push(format!("{key}={} was added", self.value(key, value))),
+                Some(old_value) if old_value == value => {}
+                Some(_) if self.is_secret(key) => changes.push(format!("{key} changed")),
+                Some(old_value) => {
+                    changes.push(format!("{key} changed from {old_value} to {value}"))
+                }
+            }
+        }
+        for key in old.keys().filter(|key| !new.contains_key(*key)) {
+            changes.push(format!("{key} was removed"));
+        }
+        changes
+    }
+}
+
+/// KEY=VALUE lines of an env file, later lines win like when the file is loaded
+fn parse_env(env: &str) -> BTreeMap<&str, &str> {
+    env.lines()
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| (key.trim(), value.trim()))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{RedactionConfig, Redactor};
+    use std::collections::HashMap;
+
+    #[test]
+    fn masks_secret_values() {
+        let mut redactor = Redactor::new(&RedactionConfig {
+            patterns: vec!["rpcauth".to_string()],
+        });
+        assert!(redactor.is_secret("APP_LND_PASSWORD"));
+        assert!(redactor.is_secret("BITCOIN_RPCAUTH"));
+        assert!(!redactor.is_secret("APP_LND_WEB_IP"));
+        redactor.add_env(&HashMap::from([
+            ("BITCOIN_RPC_PASS".to_string(), "hunter2hunter2".to_string()),
+            ("BITCOIN_RPC_USER".to_string(), "citadel".to_string()),
+        ]));
+        assert_eq!(
+            redactor.text("Failed to log in as citadel with hunter2hunter2"),
+            "Failed to log in as citadel with <redacted BITCOIN_RPC_PASS>"
+        );
+        assert_eq!(
+            redactor.env_changes(
+                "BITCOIN_RPC_PASS=old-password\nAPP_LND_WEB_IP=10.21.22.2\nTOR_PROXY_IP=10.21.21.11\n",
+                "BITCOIN_RPC_PASS=new-password\nAPP_LND_WEB_IP=10.21.22.3\nAPP_LND_SEED=abcdef\n"
+            ),
+            vec![
+                "APP_LND_SEED=<redacted> was added",
+                "APP_LND_WEB_IP changed from 10.21.22.2 to 10.21.22.3",
+                "BITCOIN_RPC_PASS changed",
+                "TOR_PROXY_IP was removed",
+            ]
+        );
+        let error = dotenv::Error::LineParse("BITCOIN_RPC_PASS=\"hunter2".to_string(), 17);
+        assert!(!redactor.env_error(&error).contains("hunter2"));
+    }
+}
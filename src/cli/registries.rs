@@ -0,0 +1,160 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use super::image_registry::ImageReference;
+use crate::composegenerator::output::types::ComposeSpecification;
+
+/// Registry mirrors and credentials, in app-manager.toml
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct RegistriesConfig {
+    /// Registry -> mirror images are pulled from instead, like docker.io = "mirror.gcr.io".
+    /// A mirror can include a path, like "harbor.local/dockerhub".
+    pub mirrors: BTreeMap<String, String>,
+    /// Registry -> credentials for it, used when the registry asks for authentication
+    pub auth: BTreeMap<String, RegistryCredentials>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RegistryCredentials {
+    pub username: String,
+    pub password: String,
+}
+
+/// Docker Hub has several names, the registry API is served by registry-1.docker.io
+fn normalize_registry(registry: &str) -> &str {
+    match registry {
+        "docker.io" | "index.docker.io" => "registry-1.docker.io",
+        registry => registry,
+    }
+}
+
+/// Splits an image into its registry and the rest, None for images from Docker Hub without one
+fn split_registry(image: &str) -> (Option<&str>, &str) {
+    match image.split_once('/') {
+        Some((first, rest))
+            if first.contains('.') || first.contains(':') || first == "localhost" =>
+        {
+            (Some(first), rest)
+        }
+        _ => (None, image),
+    }
+}
+
+impl RegistriesConfig {
+    fn mirror_of(&self, registry: &str) -> Option<&str> {
+        let registry = normalize_registry(registry);
+        self.mirrors
+            .iter()
+            .find(|(mirrored, _)| normalize_registry(mirrored) == registry)
+            .map(|(_, mirror)| {
+                let mirror = mirror.trim_end_matches('/');
+                mirror
+                    .strip_prefix("https://")
+                    .or_else(|| mirror.strip_prefix("http://"))
+                    .unwrap_or(mirror)
+            })
+    }
+
+    /// The image to pull instead of `image`, which is returned unchanged if its registry has no mirror
+    pub fn mirror_image(&self, image: &str) -> String {
+        let (registry, rest) = split_registry(image);
+        let registry = normalize_registry(registry.unwrap_or("docker.io"));
+        let Some(mirror) = self.mirror_of(registry) else {
+            return image.to_string();
+        };
+        if registry == "registry-1.docker.io" && !rest.contains('/') {
+            // Official images are stored under library/
+            return format!("{mirror}/library/{rest}");
+        }
+        format!("{mirror}/{rest}")
+    }
+
+    /// Where the registry API for an image is queried, its mirror if there is one
+    pub fn mirror(&self, image: &ImageReference) -> ImageReference {
+        let Some(mirror) = self.mirror_of(&image.registry) else {
+            return image.clone();
+        };
+        let (registry, repository) = match mirror.split_once('/') {
+            Some((host, prefix)) => (host, format!("{prefix}/{}", image.repository)),
+            None => (mirror, image.repository.clone()),
+        };
+        ImageReference {
+            registry: registry.to_string(),
+            repository,
+            reference: image.reference.clone(),
+        }
+    }
+
+    /// The credentials for a registry, if they are configured
+    pub fn credentials(&self, registry: &str) -> Option<&RegistryCredentials> {
+        let registry = normalize_registry(registry);
+        self.auth
+            .iter()
+            .find(|(configured, _)| normalize_registry(configured) == registry)
+            .map(|(_, credentials)| credentials)
+    }
+
+    /// Makes all containers of an app pull their images from the configured mirrors
+    pub fn apply_mirrors(&self, spec: &mut ComposeSpecification) {
+        if self.mirrors.is_empty() {
+            return;
+        }
+        for service in spec
+            .services
+            .iter_mut()
+            .flat_map(|services| services.values_mut())
+        {
+            if let Some(image) = &mut service.image {
+                *image = self.mirror_image(image);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::RegistriesConfig;
+    use crate::cli::image_registry::ImageReference;
+
+    #[test]
+    fn rewrites_images_to_mirrors() {
+        let config: RegistriesConfig = toml::from_str(
+            r#"
+[mirrors]
+"docker.io" = "https://mirror.example.com/"
+"ghcr.io" = "harbor.local/ghcr"
+
+[auth."harbor.local"]
+username = "citadel"
+password = "secret"
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.mirror_image("nginx:1.25"),
+            "mirror.example.com/library/nginx:1.25"
+        );
+        assert_eq!(
+            config.mirror_image("docker.io/lncm/bitcoind:v25.0@sha256:abc"),
+            "mirror.example.com/lncm/bitcoind:v25.0@sha256:abc"
+        );
+        assert_eq!(
+            config.mirror_image("ghcr.io/runcitadel/lnd:v0.16"),
+            "harbor.local/ghcr/runcitadel/lnd:v0.16"
+        );
+        assert_eq!(
+            config.mirror_image("quay.io/example/app:1"),
+            "quay.io/example/app:1"
+        );
+        let mirrored = config.mirror(&ImageReference::parse("ghcr.io/runcitadel/lnd:v0.16"));
+        assert_eq!(mirrored.registry, "harbor.local");
+        assert_eq!(mirrored.repository, "ghcr/runcitadel/lnd");
+        assert_eq!(
+            config.credentials(&mirrored.registry).unwrap().username,
+            "citadel"
+        );
+        assert!(config.credentials("registry-1.docker.io").is_none());
+    }
+}
@@ -0,0 +1,115 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::composegenerator::types::OutputMetadata;
+
+/// Location of a single app inside registry.json
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegistryIndexEntry {
+    /// Byte offset of the app's JSON object
+    pub offset: u64,
+    /// Length of the app's JSON object in bytes
+    pub length: u64,
+}
+
+/// Writes registry.json one app at a time.
+///
+/// The file is a valid JSON array after every `push`, so a crash during conversion
+/// still leaves a parsable (partial) registry behind.
+pub struct RegistryWriter {
+    file: File,
+    /// Current length of the file, including the closing bracket
+    len: u64,
+    index: BTreeMap<String, RegistryIndexEntry>,
+    index_path: PathBuf,
+}
+
+impl RegistryWriter {
+    pub fn create(registry_path: &Path, index_path: &Path) -> Result<Self> {
+        let mut file = File::create(registry_path)?;
+        file.write_all(b"[]")?;
+        Ok(Self {
+            file,
+            len: 2,
+            index: BTreeMap::new(),
+            index_path: index_path.to_path_buf(),
+        })
+    }
+
+    pub fn push(&mut self, metadata: &OutputMetadata) -> Result<()> {
+        let entry = serde_json::to_vec(metadata)?;
+        // Overwrite the closing bracket
+        let mut offset = self.len - 1;
+        self.file.seek(SeekFrom::Start(offset))?;
+        if !self.index.is_empty() {
+            self.file.write_all(b",")?;
+            offset += 1;
+        }
+        self.file.write_all(&entry)?;
+        self.file.write_all(b"]")?;
+        self.file.flush()?;
+        self.len = offset + entry.len() as u64 + 1;
+        self.index.insert(
+            metadata.id.clone(),
+            RegistryIndexEntry {
+                offset,
+                length: entry.len() as u64,
+            },
+        );
+        Ok(())
+    }
+
+    /// Writes the index of all apps, this must be called after all apps have been added
+    pub fn finish(self) -> Result<()> {
+        let index_file = File::create(&self.index_path)?;
+        serde_json::to_writer(index_file, &self.index)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{RegistryIndexEntry, RegistryWriter};
+    use crate::composegenerator::types::OutputMetadata;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn registry_is_valid_after_every_entry() {
+        let dir = tempdir::TempDir::new("registry").unwrap();
+        let registry_path = dir.path().join("registry.json");
+        let index_path = dir.path().join("registry.index.json");
+        let mut writer = RegistryWriter::create(&registry_path, &index_path).unwrap();
+        let read_registry = || -> Vec<OutputMetadata> {
+            serde_json::from_str(&std::fs::read_to_string(&registry_path).unwrap()).unwrap()
+        };
+        assert!(read_registry().is_empty());
+        for id in ["app-a", "app-b"] {
+            writer
+                .push(&OutputMetadata {
+                    id: id.to_string(),
+                    ..Default::default()
+                })
+                .unwrap();
+        }
+        assert_eq!(read_registry().len(), 2);
+        assert!(!index_path.exists());
+        writer.finish().unwrap();
+
+        let registry = std::fs::read(&registry_path).unwrap();
+        let index: BTreeMap<String, RegistryIndexEntry> =
+            serde_json::from_str(&std::fs::read_to_string(&index_path).unwrap()).unwrap();
+        let entry = index.get("app-b").unwrap();
+        let app: OutputMetadata = serde_json::from_slice(
+            &registry[entry.offset as usize..(entry.offset + entry.length) as usize],
+        )
+        .unwrap();
+        assert_eq!(app.id, "app-b");
+    }
+}
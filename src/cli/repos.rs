@@ -4,15 +4,17 @@ use std::{
     path::Path,
 };
 
-use super::{preprocessing::preprocess_apps, UserJson};
-use anyhow::Result;
+use super::{
+    bundles, paths::CitadelPaths, preprocessing::preprocess_apps, stores::AppStoreInfo, UserJson,
+};
+use anyhow::{bail, Context, Result};
 use semver::Version;
 use serde::{Deserialize, Serialize};
 use tempdir::TempDir;
 
 #[cfg(feature = "umbrel")]
 use crate::map;
-use crate::{composegenerator::load_config_as_v4, constants::MINIMUM_COMPATIBLE_APP_MANAGER};
+use crate::{composegenerator::load_definition_file, constants::MINIMUM_COMPATIBLE_APP_MANAGER};
 
 mod git;
 
@@ -36,21 +38,6 @@ struct AppStoreV1 {
     content: HashMap<String, String>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-struct AppStoreInfo {
-    id: String,
-    name: String,
-    tagline: String,
-    icon: String,
-    developers: String,
-    license: String,
-    apps: HashMap<String, String>,
-    commit: String,
-    repo: String,
-    branch: String,
-    subdir: String,
-}
-
 #[derive(Debug, Serialize, Deserialize, Clone)]
 struct AppUpdateInfo {
     id: String,
@@ -151,9 +138,9 @@ pub fn download_apps(citadel_root: &str) -> Result<()> {
                     continue;
                 };
                 let Some(subdir) = get_subdir(&app_store) else {
-                        eprintln!("No compatible version found for {}", source.repo);
-                        continue;
-                    };
+                    eprintln!("No compatible version found for {}", source.repo);
+                    continue;
+                };
                 let mut out_app_store = AppStoreInfo {
                     id: app_store.id,
                     name: app_store.name,
@@ -166,6 +153,7 @@ pub fn download_apps(citadel_root: &str) -> Result<()> {
                     repo: source.repo,
                     branch: source.branch,
                     subdir: subdir.clone(),
+                    bundles: bundles::load_dir(&tmp_dir.path().join("bundles")),
                 };
                 let subdir_path = Path::new(&subdir);
                 // Copy all dirs from the subdir to the apps dir
@@ -267,9 +255,9 @@ pub fn list_updates(citadel_root: &str) -> Result<()> {
                         continue;
                     };
                     let Some(subdir) = get_subdir(&app_store) else {
-                            eprintln!("No compatible version found for {}", store.repo);
-                            continue;
-                        };
+                        eprintln!("No compatible version found for {}", store.repo);
+                        continue;
+                    };
                     let mut all_store_updatable_apps: Vec<String>;
                     if subdir != store.subdir {
                         all_store_updatable_apps = store.apps.clone().into_keys().collect();
@@ -291,16 +279,15 @@ pub fn list_updates(citadel_root: &str) -> Result<()> {
                     }
                     let subdir_path = tmp_dir.path().join(subdir);
                     all_store_updatable_apps.retain(|v| subdir_path.join(v).exists());
-                    preprocess_apps(citadel_root, &subdir_path)?;
+                    preprocess_apps(&CitadelPaths::new(citadel_root, None), &subdir_path, None)?;
                     for app_id in all_store_updatable_apps {
                         let app_dir = subdir_path.join(&app_id);
                         let app_yml = app_dir.join("app.yml");
-                        let app_yml = std::fs::File::open(app_yml);
-                        let Ok(app_yml) = app_yml else {
+                        if !app_yml.exists() {
                             eprintln!("No app.yml found for app {app_id}");
                             continue;
-                        };
-                        let app_config = load_config_as_v4(app_yml, &Some(&services));
+                        }
+                        let app_config = load_definition_file(&app_yml, Some(&services));
                         let Ok(app_config) = app_config else {
                             eprintln!("Failed to load app.yml for app {app_id}");
                             continue;
@@ -331,6 +318,17 @@ pub fn list_updates(citadel_root: &str) -> Result<()> {
     Ok(())
 }
 
+/// The apps in apps/updates.yml, which check-updates writes
+pub(super) fn pending_updates(citadel_root: &Path) -> Result<Vec<String>> {
+    let updates_yml = citadel_root.join("apps").join("updates.yml");
+    if !updates_yml.exists() {
+        return Ok(Vec::new());
+    }
+    let updates: Vec<AppUpdateInfo> =
+        serde_yaml::from_reader(File::open(updates_yml)?).context("Failed to load updates.yml")?;
+    Ok(updates.into_iter().map(|update| update.id).collect())
+}
+
 pub fn download_app(citadel_root: &str, app: &str) -> Result<()> {
     let citadel_root = Path::new(citadel_root);
     let stores_yml = citadel_root.join("apps").join("stores.yml");
@@ -366,8 +364,8 @@ pub fn download_app(citadel_root: &str, app: &str) -> Result<()> {
             };
             let Some(subdir) = get_subdir(&app_store) else {
                 tracing::error!("No compatible version found for {}", app_src.repo);
-                    return Ok(());
-                };
+                return Ok(());
+            };
             // Check if app exists in store
             let app_dir = tmp_dir.path().join(subdir).join(app);
             if !app_dir.exists() {
@@ -498,9 +496,9 @@ pub fn download_new_apps(citadel_root: &str) -> Result<()> {
                     continue;
                 };
                 let Some(subdir) = get_subdir(&app_store) else {
-                        eprintln!("No compatible version found for {}", source.repo);
-                        continue;
-                    };
+                    eprintln!("No compatible version found for {}", source.repo);
+                    continue;
+                };
                 let mut out_app_store = stores
                     .iter_mut()
                     .find(|s| s.repo == source.repo && s.branch == source.branch);
@@ -517,6 +515,7 @@ pub fn download_new_apps(citadel_root: &str) -> Result<()> {
                         repo: source.repo.clone(),
                         branch: source.branch.clone(),
                         subdir: subdir.clone(),
+                        bundles: bundles::load_dir(&tmp_dir.path().join("bundles")),
                     });
                     out_app_store = stores
                         .iter_mut()
@@ -573,3 +572,71 @@ pub fn download_new_apps(citadel_root: &str) -> Result<()> {
 
     Ok(())
 }
+
+/// Downloads the latest version of the apps of one store in stores.yml, for push-based updates.
+/// Apps another store provides are left alone.
+pub fn sync_store(citadel_root: &str, store_id: &str) -> Result<()> {
+    let citadel_root = Path::new(citadel_root);
+    let stores_yml = citadel_root.join("apps").join("stores.yml");
+    let mut stores = serde_yaml::from_reader::<File, Vec<AppStoreInfo>>(
+        File::open(&stores_yml).context("No stores have been downloaded yet")?,
+    )
+    .context("Failed to load stores.yml")?;
+    let other_stores_apps: Vec<String> = stores
+        .iter()
+        .filter(|store| store.id != store_id)
+        .flat_map(|store| store.apps.keys().cloned())
+        .collect();
+    let Some(store) = stores.iter_mut().find(|store| store.id == store_id) else {
+        bail!("Store {store_id} is not in stores.yml");
+    };
+    let tmp_dir = TempDir::new("citadel_app")?;
+    git::clone(&store.repo, &store.branch, tmp_dir.path())?;
+    // Umbrel stores have no app-store.yml, they keep the subdir they were downloaded with
+    let app_store = std::fs::File::open(tmp_dir.path().join("app-store.yml"))
+        .ok()
+        .and_then(|file| serde_yaml::from_reader::<File, AppStoreV1>(file).ok());
+    if let Some(app_store) = app_store {
+        let Some(subdir) = get_subdir(&app_store) else {
+            bail!("No compatible version found for {}", store.repo);
+        };
+        store.subdir = subdir;
+    }
+    let mut store_apps = vec![];
+    for entry in std::fs::read_dir(tmp_dir.path().join(&store.subdir))? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        let app_id = entry.file_name().to_string_lossy().to_string();
+        if other_stores_apps.contains(&app_id) {
+            tracing::warn!(
+                "App store {} tries to install app {} which is already installed by another store.",
+                store.id,
+                app_id
+            );
+            continue;
+        }
+        let citadel_app_dir = citadel_root.join("apps").join(&app_id);
+        if citadel_app_dir.exists() {
+            std::fs::remove_dir_all(&citadel_app_dir)?;
+        }
+        fs_extra::dir::copy(
+            entry.path(),
+            citadel_root.join("apps"),
+            &fs_extra::dir::CopyOptions {
+                overwrite: true,
+                ..Default::default()
+            },
+        )?;
+        store_apps.push(app_id);
+    }
+    store.commit = git::get_commit(tmp_dir.path())?;
+    store.apps = git::get_latest_commit_for_apps(tmp_dir.path(), &store.subdir, &store_apps)?;
+    store.bundles = bundles::load_dir(&tmp_dir.path().join("bundles"));
+    tracing::info!("Synced {} apps from store {}", store_apps.len(), store_id);
+
+    let mut file = File::create(stores_yml)?;
+    serde_yaml::to_writer(&mut file, &stores)?;
+    Ok(())
+}
@@ -0,0 +1,261 @@
+use std::{collections::HashMap, process::Command};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use super::runtime::ContainerEngine;
+use crate::composegenerator::output::labels::APP_LABEL;
+
+/// What to sort the output of top by
+#[derive(clap::ValueEnum, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SortBy {
+    #[default]
+    Cpu,
+    Memory,
+    Io,
+}
+
+/// One line of `docker stats --format '{{json .}}'`
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct ContainerStats {
+    #[serde(rename = "ID")]
+    id: String,
+    #[serde(rename = "CPUPerc")]
+    cpu_perc: String,
+    mem_usage: String,
+    #[serde(rename = "BlockIO")]
+    block_io: String,
+}
+
+/// Resource usage of all containers of an app
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct AppUsage {
+    pub app: String,
+    pub containers: usize,
+    /// CPU usage in percent of one core, so it can be above 100
+    pub cpu_percent: f64,
+    pub memory_bytes: u64,
+    /// Bytes read from and written to disk since the containers were started
+    pub block_read_bytes: u64,
+    pub block_written_bytes: u64,
+}
+
+/// Parses sizes like 1.5GiB or 20kB, as printed by docker stats
+fn parse_size(size: &str) -> Option<u64> {
+    let size = size.trim();
+    let unit_start = size
+        .find(|c: char| c.is_ascii_alphabetic())
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(unit_start);
+    let factor: f64 = match unit {
+        "" | "B" => 1.0,
+        "kB" | "KB" => 1e3,
+        "MB" => 1e6,
+        "GB" => 1e9,
+        "TB" => 1e12,
+        "KiB" => 1024.0,
+        "MiB" => 1024.0 * 1024.0,
+        "GiB" => 1024.0 * 1024.0 * 1024.0,
+        "TiB" => 1024.0 * 1024.0 * 1024.0 * 1024.0,
+        _ => return None,
+    };
+    Some((number.trim().parse::<f64>().ok()? * factor) as u64)
+}
+
+/// Parses pairs like "used / total" or "read / written"
+fn parse_pair(pair: &str) -> Option<(u64, u64)> {
+    let (first, second) = pair.split_once('/')?;
+    Some((parse_size(first)?, parse_size(second)?))
+}
+
+/// Runs a docker or nerdctl command and returns its output
+pub(super) fn run_engine(engine: ContainerEngine, args: &[&str]) -> Result<String> {
+    let binary = engine.binary();
+    let output = Command::new(binary)
+        .args(args)
+        .output()
+        .with_context(|| format!("Failed to run {binary}"))?;
+    if !output.status.success() {
+        bail!(
+            "{binary} {} failed: {}",
+            args[0],
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+/// Parses a listing printed with `--format '{{json .}}'` into the value of `name_field`
+/// and of the label `label` of every entry that has the label.
+/// nerdctl has no .Label template function, and prints labels as an object where Docker
+/// prints "key=value,...", so both are read from the JSON.
+pub(super) fn labeled_entries(
+    listing: &str,
+    name_field: &str,
+    label: &str,
+) -> Result<Vec<(String, String)>> {
+    let mut entries = Vec::new();
+    for line in listing.lines().filter(|line| !line.trim().is_empty()) {
+        let entry: serde_json::Value =
+            serde_json::from_str(line).context("Failed to parse the listing")?;
+        let value = match &entry["Labels"] {
+            serde_json::Value::Object(labels) => labels
+                .get(label)
+                .and_then(|value| value.as_str())
+                .map(str::to_string),
+            serde_json::Value::String(labels) => labels
+                .split(',')
+                .filter_map(|pair| pair.split_once('='))
+                .find(|(key, _)| *key == label)
+                .map(|(_, value)| value.to_string()),
+            _ => None,
+        };
+        if let (Some(name), Some(value)) = (entry[name_field].as_str(), value) {
+            entries.push((name.to_string(), value));
+        }
+    }
+    Ok(entries)
+}
+
+/// Adds up the stats of all containers per app, containers are matched to apps by their ID
+fn aggregate(stats: &str, container_apps: &HashMap<String, String>) -> Result<Vec<AppUsage>> {
+    let mut usage: HashMap<String, AppUsage> = HashMap::new();
+    for line in stats.lines().filter(|line| !line.trim().is_empty()) {
+        let stats: ContainerStats =
+            serde_json::from_str(line).context("Failed to parse docker stats output")?;
+        let Some(app) = container_apps.get(&stats.id) else {
+            continue;
+        };
+        let app_usage = usage.entry(app.clone()).or_insert_with(|| AppUsage {
+            app: app.clone(),
+            ..Default::default()
+        });
+        app_usage.containers += 1;
+        app_usage.cpu_percent += stats
+            .cpu_perc
+            .trim_end_matches('%')
+            .parse::<f64>()
+            .unwrap_or_default();
+        if let Some((memory, _)) = parse_pair(&stats.mem_usage) {
+            app_usage.memory_bytes += memory;
+        }
+        if let Some((read, written)) = parse_pair(&stats.block_io) {
+            app_usage.block_read_bytes += read;
+            app_usage.block_written_bytes += written;
+        }
+    }
+    Ok(usage.into_values().collect())
+}
+
+/// Samples the resource usage of all running app containers once
+pub fn sample(engine: ContainerEngine, sort_by: SortBy) -> Result<Vec<AppUsage>> {
+    let containers = run_engine(
+        engine,
+        &[
+            "ps",
+            "--no-trunc",
+            "--filter",
+            &format!("label={APP_LABEL}"),
+            "--format",
+            "{{json .}}",
+        ],
+    )?;
+    let container_apps: HashMap<String, String> = labeled_entries(&containers, "ID", APP_LABEL)?
+        .into_iter()
+        .collect();
+    if container_apps.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut args = vec![
+        "stats",
+        "--no-stream",
+        "--no-trunc",
+        "--format",
+        "{{json .}}",
+    ];
+    args.extend(container_apps.keys().map(String::as_str));
+    let mut usage = aggregate(&run_engine(engine, &args)?, &container_apps)?;
+    match sort_by {
+        SortBy::Cpu => usage.sort_by(|a, b| b.cpu_percent.total_cmp(&a.cpu_percent)),
+        SortBy::Memory => usage.sort_by_key(|app| std::cmp::Reverse(app.memory_bytes)),
+        SortBy::Io => usage
+            .sort_by_key(|app| std::cmp::Reverse(app.block_read_bytes + app.block_written_bytes)),
+    }
+    Ok(usage)
+}
+
+pub(super) fn format_size(bytes: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{size:.1}{}", units[unit])
+}
+
+/// Formats the usage of all apps as a table
+pub fn format_table(usage: &[AppUsage]) -> String {
+    let mut table = format!(
+        "{:<24} {:>10} {:>8} {:>12} {:>12} {:>12}\n",
+        "APP", "CONTAINERS", "CPU", "MEMORY", "DISK READ", "DISK WRITE"
+    );
+    for app in usage {
+        table.push_str(&format!(
+            "{:<24} {:>10} {:>7.1}% {:>12} {:>12} {:>12}\n",
+            app.app,
+            app.containers,
+            app.cpu_percent,
+            format_size(app.memory_bytes),
+            format_size(app.block_read_bytes),
+            format_size(app.block_written_bytes)
+        ));
+    }
+    table
+}
+
+#[cfg(test)]
+mod test {
+    use super::{aggregate, labeled_entries, parse_size};
+    use std::collections::HashMap;
+
+    #[test]
+    fn aggregates_container_stats() {
+        assert_eq!(parse_size("1.5GiB"), Some(1610612736));
+        assert_eq!(parse_size("20kB"), Some(20000));
+        assert_eq!(parse_size("0B"), Some(0));
+        assert_eq!(parse_size("1.5 parsecs"), None);
+
+        let stats = r#"{"BlockIO":"1MB / 2MB","CPUPerc":"50.5%","ID":"aaa","MemUsage":"100MiB / 1.9GiB","Name":"lnd-main-1"}
+{"BlockIO":"1MB / 0B","CPUPerc":"10.0%","ID":"bbb","MemUsage":"50MiB / 1.9GiB","Name":"lnd-tor-1"}
+{"BlockIO":"0B / 0B","CPUPerc":"1.0%","ID":"ccc","MemUsage":"1MiB / 1.9GiB","Name":"other"}
+"#;
+        let container_apps = HashMap::from([
+            ("aaa".to_string(), "lnd".to_string()),
+            ("bbb".to_string(), "lnd".to_string()),
+        ]);
+        let usage = aggregate(stats, &container_apps).unwrap();
+        assert_eq!(usage.len(), 1);
+        assert_eq!(usage[0].containers, 2);
+        assert!((usage[0].cpu_percent - 60.5).abs() < 0.01);
+        assert_eq!(usage[0].memory_bytes, 150 * 1024 * 1024);
+        assert_eq!(usage[0].block_read_bytes, 2_000_000);
+        assert_eq!(usage[0].block_written_bytes, 2_000_000);
+
+        // Docker prints labels as a string, nerdctl as an object
+        let listing = r#"{"ID":"aaa","Labels":"citadel.app=lnd,com.docker.compose.service=main"}
+{"ID":"bbb","Labels":{"citadel.app":"lnd"}}
+{"ID":"ccc","Labels":""}
+"#;
+        assert_eq!(
+            labeled_entries(listing, "ID", "citadel.app").unwrap(),
+            vec![
+                ("aaa".to_string(), "lnd".to_string()),
+                ("bbb".to_string(), "lnd".to_string())
+            ]
+        );
+    }
+}
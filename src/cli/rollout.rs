@@ -0,0 +1,305 @@
+use std::time::Duration;
+#[cfg(feature = "git")]
+use std::{path::Path, thread, time::Instant};
+
+#[cfg(feature = "git")]
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "git")]
+use super::{
+    app_filter::AppFilter, app_list, compose, node_config::NodeConfig, paths::CitadelPaths,
+    port_review::PortChangePolicy, repos, stores,
+};
+
+/// Staged updates in app-manager.toml
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct RolloutConfig {
+    /// How long to wait for an updated app to become healthy, in seconds
+    pub health_timeout: u64,
+    /// How often the containers of an updated app are checked, in seconds
+    pub poll_interval: u64,
+}
+
+impl Default for RolloutConfig {
+    fn default() -> Self {
+        Self {
+            health_timeout: 300,
+            poll_interval: 5,
+        }
+    }
+}
+
+impl RolloutConfig {
+    pub fn health_timeout(&self) -> Duration {
+        Duration::from_secs(self.health_timeout)
+    }
+
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_secs(self.poll_interval)
+    }
+}
+
+/// A container as printed by `compose ps --format json`
+#[cfg(feature = "git")]
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct ContainerStatus {
+    service: String,
+    state: String,
+    /// Empty if the container has no healthcheck
+    #[serde(default)]
+    health: String,
+    #[serde(default)]
+    exit_code: i32,
+}
+
+#[cfg(feature = "git")]
+#[derive(Debug, PartialEq, Eq)]
+enum AppHealth {
+    Healthy,
+    /// Not healthy yet, and why
+    Starting(String),
+    Failed(String),
+}
+
+/// Docker Compose prints one container per line, older versions and nerdctl a JSON array
+#[cfg(feature = "git")]
+fn parse_ps(output: &str) -> Result<Vec<ContainerStatus>> {
+    if output.trim_start().starts_with('[') {
+        return Ok(serde_json::from_str(output)?);
+    }
+    output
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| Ok(serde_json::from_str(line)?))
+        .collect()
+}
+
+/// Containers without a healthcheck are healthy once they run, init containers once they exited with 0
+#[cfg(feature = "git")]
+fn app_health(containers: &[ContainerStatus]) -> AppHealth {
+    if containers.is_empty() {
+        return AppHealth::Failed("No containers were started".to_string());
+    }
+    let mut starting = None;
+    for container in containers {
+        let service = &container.service;
+        match (container.state.as_str(), container.health.as_str()) {
+            ("exited", _) if container.exit_code == 0 => {}
+            ("exited" | "dead", _) => {
+                return AppHealth::Failed(format!(
+                    "{service} exited with code {}",
+                    container.exit_code
+                ))
+            }
+            (_, "unhealthy") => return AppHealth::Failed(format!("{service} is unhealthy")),
+            ("restarting", _) => {
+                starting.get_or_insert_with(|| format!("{service} is restarting"));
+            }
+            (_, "starting") | ("created", _) => {
+                starting.get_or_insert_with(|| format!("{service} is not healthy yet"));
+            }
+            _ => {}
+        }
+    }
+    starting.map_or(AppHealth::Healthy, AppHealth::Starting)
+}
+
+/// Waits until all containers of an app are healthy, fails if one fails or the timeout is reached
+#[cfg(feature = "git")]
+fn wait_healthy(paths: &CitadelPaths, app_id: &str, config: &RolloutConfig) -> Result<()> {
+    let deadline = Instant::now() + config.health_timeout();
+    loop {
+        let mut command = compose::compose_command(paths, app_id)?;
+        command.args(["ps", "--all", "--format", "json"]);
+        let output = command.output().context("Failed to run docker compose")?;
+        if !output.status.success() {
+            bail!(
+                "Failed to list the containers of {app_id}: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        match app_health(&parse_ps(&String::from_utf8_lossy(&output.stdout))?) {
+            AppHealth::Healthy => return Ok(()),
+            AppHealth::Failed(reason) => bail!(reason),
+            AppHealth::Starting(reason) if Instant::now() >= deadline => {
+                bail!("{reason} after {} seconds", config.health_timeout)
+            }
+            AppHealth::Starting(reason) => {
+                tracing::debug!("Waiting for {}: {}", app_id, reason);
+                thread::sleep(config.poll_interval());
+            }
+        }
+    }
+}
+
+#[cfg(feature = "git")]
+fn convert(
+    citadel_root: &str,
+    caddy_url: &Option<String>,
+    state_dir: &Option<String>,
+    app_id: &str,
+) -> Result<()> {
+    // Other apps keep running with their ports, so moving them fails the update
+    let report = super::failures::convert_dir(
+        citadel_root,
+        caddy_url,
+        state_dir,
+        PortChangePolicy::Reject,
+        &AppFilter {
+            only: vec![app_id.to_string()],
+            ..Default::default()
+        },
+        None,
+        None,
+    )?;
+    if let Some(failure) = report.failures.iter().find(|failure| failure.app == app_id) {
+        bail!("{app_id} failed to convert: {}", failure.error);
+    }
+    Ok(())
+}
+
+#[cfg(feature = "git")]
+fn start(paths: &CitadelPaths, app_id: &str) -> Result<()> {
+    let mut command = compose::compose_command(paths, app_id)?;
+    command.arg("up").arg("--detach").arg("--remove-orphans");
+    if compose::run(command)? != 0 {
+        bail!("Failed to start {app_id}");
+    }
+    Ok(())
+}
+
+/// Applies updates one app after another. An installed app is started after its update,
+/// and the next app is only updated once all its containers are healthy.
+/// If an app fails, its previous version is restored and started again, and the remaining apps
+/// are not updated. An update that would move the ports of other installed apps fails.
+/// Without `apps`, all apps in updates.yml are updated. Returns the updated apps.
+#[cfg(feature = "git")]
+pub fn apply_updates(
+    citadel_root: &str,
+    caddy_url: &Option<String>,
+    state_dir: &Option<String>,
+    apps: &[String],
+) -> Result<Vec<String>> {
+    let paths = CitadelPaths::new(Path::new(citadel_root), state_dir.as_deref().map(Path::new));
+    let root = paths.root();
+    let config = NodeConfig::load(&paths, root)?.rollout;
+    let apps = if apps.is_empty() {
+        repos::pending_updates(root)?
+    } else {
+        apps.to_vec()
+    };
+    let stores = stores::load_stores(&paths, root)?;
+    if let Some(app_id) = apps
+        .iter()
+        .find(|app_id| !stores.iter().any(|store| store.apps.contains_key(*app_id)))
+    {
+        bail!("App {app_id} is not in any store");
+    }
+    let installed_apps = app_list::installed_apps(&paths, root);
+
+    let mut updated = Vec::new();
+    for (index, app_id) in apps.iter().enumerate() {
+        tracing::info!("Updating {} ({}/{})", app_id, index + 1, apps.len());
+        let installed = installed_apps.contains(app_id);
+        let app_dir = root.join("apps").join(app_id);
+        let backup = tempdir::TempDir::new("citadel-rollout")?;
+        fs_extra::dir::copy(&app_dir, backup.path(), &fs_extra::dir::CopyOptions::new())
+            .with_context(|| format!("Failed to back up {app_id}"))?;
+        // With a state dir, the compose files of the app are written there
+        let state_app_dir = Some(paths.write_path(&app_dir)?)
+            .filter(|state_app_dir| *state_app_dir != app_dir && state_app_dir.is_dir());
+        let state_backup = tempdir::TempDir::new("citadel-rollout-state")?;
+        if let Some(state_app_dir) = &state_app_dir {
+            fs_extra::dir::copy(
+                state_app_dir,
+                state_backup.path(),
+                &fs_extra::dir::CopyOptions::new(),
+            )
+            .with_context(|| format!("Failed to back up the compose files of {app_id}"))?;
+        }
+
+        let result = repos::download_app(citadel_root, app_id)
+            .and_then(|_| convert(citadel_root, caddy_url, state_dir, app_id))
+            .and_then(|_| {
+                // Apps that are not installed are not started
+                if installed {
+                    start(&paths, app_id)?;
+                    wait_healthy(&paths, app_id, &config)?;
+                }
+                Ok(())
+            });
+        if let Err(err) = result {
+            tracing::error!("Update of {} failed, rolling it back: {:#}", app_id, err);
+            std::fs::remove_dir_all(&app_dir)?;
+            fs_extra::dir::copy(
+                backup.path().join(app_id),
+                root.join("apps"),
+                &fs_extra::dir::CopyOptions::new(),
+            )
+            .with_context(|| format!("Failed to restore {app_id}"))?;
+            if let Some(state_app_dir) = &state_app_dir {
+                if state_app_dir.exists() {
+                    std::fs::remove_dir_all(state_app_dir)?;
+                }
+                fs_extra::dir::copy(
+                    state_backup.path().join(app_id),
+                    state_app_dir.parent().context("Invalid state dir")?,
+                    &fs_extra::dir::CopyOptions::new(),
+                )
+                .with_context(|| format!("Failed to restore the compose files of {app_id}"))?;
+            }
+            convert(citadel_root, caddy_url, state_dir, app_id)
+                .with_context(|| format!("Failed to convert the previous version of {app_id}"))?;
+            if installed {
+                start(&paths, app_id)?;
+            }
+            let remaining = &apps[index + 1..];
+            if remaining.is_empty() {
+                bail!("Update of {app_id} failed and was rolled back: {err:#}");
+            }
+            bail!(
+                "Update of {app_id} failed and was rolled back: {err:#}. Not updated: {}",
+                remaining.join(", ")
+            );
+        }
+        updated.push(app_id.clone());
+    }
+    Ok(updated)
+}
+
+#[cfg(all(test, feature = "git"))]
+mod test {
+    use super::{app_health, parse_ps, AppHealth};
+
+    #[test]
+    fn checks_container_health() {
+        let health = |output: &str| app_health(&parse_ps(output).unwrap());
+        assert_eq!(
+            health(
+                r#"{"Service":"web","State":"running","Health":"healthy","ExitCode":0}
+{"Service":"init","State":"exited","Health":"","ExitCode":0}
+{"Service":"api","State":"running","Health":"","ExitCode":0}"#
+            ),
+            AppHealth::Healthy
+        );
+        assert_eq!(
+            health(r#"[{"Service":"web","State":"running","Health":"starting","ExitCode":0}]"#),
+            AppHealth::Starting("web is not healthy yet".to_string())
+        );
+        assert_eq!(
+            health(
+                r#"{"Service":"web","State":"running","Health":"starting","ExitCode":0}
+{"Service":"db","State":"exited","Health":"","ExitCode":1}"#
+            ),
+            AppHealth::Failed("db exited with code 1".to_string())
+        );
+        assert_eq!(
+            health(r#"{"Service":"web","State":"running","Health":"unhealthy","ExitCode":0}"#),
+            AppHealth::Failed("web is unhealthy".to_string())
+        );
+        assert!(matches!(health(""), AppHealth::Failed(_)));
+    }
+}
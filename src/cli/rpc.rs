@@ -0,0 +1,456 @@
+use std::{
+    collections::BTreeMap,
+    io::{BufRead, BufReader, Write},
+    os::unix::{
+        fs::PermissionsExt,
+        net::{UnixListener, UnixStream},
+    },
+    path::Path,
+    sync::{mpsc, Mutex, MutexGuard, PoisonError},
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use super::{
+    app_filter::AppFilter,
+    app_list, app_settings, capacity, compose,
+    config_reload::{self, ConfigReloader, ReloadEvent},
+    maintenance,
+    node_config::NodeConfig,
+    paths::CitadelPaths,
+    port_forwarding,
+    port_review::PortChangePolicy,
+    projects, validation, webhooks,
+};
+
+// Error codes defined by JSON-RPC 2.0
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// A method failed, the message contains the reason
+const METHOD_FAILED: i64 = -32000;
+
+/// How often to check if port forwarding was enabled while it is disabled
+const PORT_FORWARDING_CHECK_INTERVAL: Duration = Duration::from_secs(3600);
+
+#[derive(Deserialize, Debug)]
+struct Request {
+    jsonrpc: String,
+    /// Notifications don't have an ID and get no response
+    #[serde(default)]
+    id: Option<Value>,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct Response {
+    jsonrpc: &'static str,
+    id: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<RpcError>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct ConvertParams {
+    /// Only convert this app, like convert --app
+    app: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct InstallParams {
+    app: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct SettingsParams {
+    app: String,
+    /// Settings to change, env var -> value. An empty value unsets a setting.
+    #[serde(default)]
+    values: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct NoParams {}
+
+/// Serves convert, install, settings, status and capacity as JSON-RPC 2.0 over a unix socket,
+/// one request per line.
+/// Access is controlled by the permissions of the socket file, only its owner and group can connect.
+/// If webhooks are configured, they are received on another thread.
+/// Changes to app-manager.toml and apps/sources.yml are reloaded without a restart and all apps are converted again.
+/// Port forwards are requested again before they expire.
+pub struct RpcServer {
+    citadel_root: String,
+    caddy_url: Option<String>,
+    state_dir: Option<String>,
+    /// Held while a method, webhook or reload runs, so conversions never run concurrently
+    running: Mutex<()>,
+}
+
+impl RpcServer {
+    pub fn new(citadel_root: String, caddy_url: Option<String>, state_dir: Option<String>) -> Self {
+        Self {
+            citadel_root,
+            caddy_url,
+            state_dir,
+            running: Mutex::new(()),
+        }
+    }
+
+    /// Listens on `socket`, and for webhooks if they are configured, until the process is stopped.
+    /// The configuration is reloaded on another thread.
+    pub fn serve(&self, socket: &Path) -> Result<()> {
+        let config = NodeConfig::load(&self.paths(), Path::new(&self.citadel_root))?.webhooks;
+        std::thread::scope(|scope| {
+            if config.listen.is_some() {
+                scope.spawn(|| {
+                    if let Err(err) = webhooks::serve(&config, |store| self.sync_store(store)) {
+                        tracing::error!("Stopped receiving webhooks: {:#}", err);
+                    }
+                });
+            }
+            let (stop, stopped) = mpsc::channel();
+            scope.spawn(move || {
+                config_reload::watch(self.paths(), &stopped, |config, events| {
+                    self.config_reloaded(config, events)
+                })
+            });
+            scope.spawn(|| self.renew_port_forwards());
+            let result = self.serve_rpc(socket);
+            drop(stop);
+            result
+        })
+    }
+
+    /// Connections are handled one after another
+    fn serve_rpc(&self, socket: &Path) -> Result<()> {
+        if socket.exists() {
+            // Left behind by a previous run
+            std::fs::remove_file(socket)
+                .with_context(|| format!("Failed to remove {}", socket.display()))?;
+        }
+        let listener = UnixListener::bind(socket)
+            .with_context(|| format!("Failed to listen on {}", socket.display()))?;
+        std::fs::set_permissions(socket, std::fs::Permissions::from_mode(0o660))?;
+        tracing::info!("Listening on {}", socket.display());
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if let Err(err) = self.handle_connection(stream) {
+                        tracing::warn!("RPC connection failed: {:#}", err);
+                    }
+                }
+                Err(err) => tracing::warn!("Failed to accept RPC connection: {}", err),
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_connection(&self, stream: UnixStream) -> Result<()> {
+        let mut writer = stream.try_clone()?;
+        for line in BufReader::new(stream).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            if let Some(response) = self.handle_line(&line) {
+                writeln!(writer, "{}", serde_json::to_string(&response)?)?;
+                writer.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_line(&self, line: &str) -> Option<Response> {
+        let request: Request = match serde_json::from_str::<Value>(line) {
+            Ok(value) => match serde_json::from_value(value) {
+                Ok(request) => request,
+                Err(err) => return Some(error_response(Value::Null, INVALID_REQUEST, err)),
+            },
+            Err(err) => return Some(error_response(Value::Null, PARSE_ERROR, err)),
+        };
+        if request.jsonrpc != "2.0" {
+            let id = request.id.unwrap_or_default();
+            return Some(error_response(id, INVALID_REQUEST, "jsonrpc must be 2.0"));
+        }
+        tracing::debug!("RPC call {}", request.method);
+        let result = self.call(&request.method, request.params);
+        let id = request.id?;
+        Some(match result {
+            Ok(result) => Response {
+                jsonrpc: "2.0",
+                id,
+                result: Some(result),
+                error: None,
+            },
+            Err(error) => Response {
+                jsonrpc: "2.0",
+                id,
+                result: None,
+                error: Some(error),
+            },
+        })
+    }
+
+    fn call(&self, method: &str, params: Value) -> Result<Value, RpcError> {
+        let _running = self.lock();
+        let result = match method {
+            "convert" => {
+                let params: ConvertParams = parse_params(params)?;
+                self.convert(params.app.as_deref())
+            }
+            "install" => {
+                let params: InstallParams = parse_params(params)?;
+                self.install(&params.app)
+            }
+            "settings" => {
+                let params: SettingsParams = parse_params(params)?;
+                self.settings(&params.app, params.values)
+            }
+            "status" => {
+                parse_params::<NoParams>(params)?;
+                self.status()
+            }
+            "capacity" => {
+                parse_params::<NoParams>(params)?;
+                self.capacity()
+            }
+            _ => {
+                return Err(RpcError::new(
+                    METHOD_NOT_FOUND,
+                    format!("Unknown method {method}"),
+                ))
+            }
+        };
+        result.map_err(|err| RpcError::new(METHOD_FAILED, format!("{err:#}")))
+    }
+
+    /// Waits until no other method or webhook runs.
+    /// A panicking conversion leaves nothing behind that needs the lock, so a poisoned lock is still used.
+    fn lock(&self) -> MutexGuard<'_, ()> {
+        self.running.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn paths(&self) -> CitadelPaths {
+        CitadelPaths::new(
+            Path::new(&self.citadel_root),
+            self.state_dir.as_deref().map(Path::new),
+        )
+    }
+
+    fn convert(&self, app: Option<&str>) -> Result<Value> {
+        super::failures::convert_dir(
+            &self.citadel_root,
+            &self.caddy_url,
+            &self.state_dir,
+            PortChangePolicy::default(),
+            &AppFilter::default(),
+            app,
+            None,
+        )?;
+        Ok(Value::Null)
+    }
+
+    /// Applies a reloaded configuration and converts all apps with it
+    fn config_reloaded(&self, config: &ConfigReloader, events: &[ReloadEvent]) {
+        let _running = self.lock();
+        config.apply(events);
+        if let Err(err) = self.convert(None) {
+            tracing::error!(
+                "Failed to convert after reloading the configuration: {:#}",
+                err
+            );
+        }
+    }
+
+    /// Requests the port forwards again before they expire, the lease duration can change on reload
+    fn renew_port_forwards(&self) {
+        loop {
+            let interval = NodeConfig::load(&self.paths(), Path::new(&self.citadel_root))
+                .ok()
+                .and_then(|config| config.port_forwarding.renew_interval());
+            std::thread::sleep(interval.unwrap_or(PORT_FORWARDING_CHECK_INTERVAL));
+            if interval.is_none() {
+                continue;
+            }
+            let _running = self.lock();
+            if let Err(err) = port_forwarding::renew(&self.paths()) {
+                tracing::error!("Failed to renew port forwards: {:#}", err);
+            }
+        }
+    }
+
+    /// Downloads the latest apps of a store and converts all apps, after a webhook
+    fn sync_store(&self, store: &str) -> Result<()> {
+        let _running = self.lock();
+        #[cfg(feature = "git")]
+        super::repos::sync_store(&self.citadel_root, store)?;
+        #[cfg(not(feature = "git"))]
+        tracing::warn!("Not syncing store {store}, the app manager was built without git support");
+        self.convert(None)?;
+        Ok(())
+    }
+
+    /// Adds an app to the installed apps in user.json, converts all apps and starts it
+    fn install(&self, app_id: &str) -> Result<Value> {
+        validation::validate_app_id(app_id)?;
+        let paths = self.paths();
+        let citadel_root = paths.root();
+        if !citadel_root
+            .join("apps")
+            .join(app_id)
+            .join("app.yml")
+            .exists()
+        {
+            bail!("App {app_id} does not exist");
+        }
+        let user_json_file = citadel_root.join("db").join("user.json");
+        // Other fields of user.json are kept as they are
+        let mut user_json: Value = serde_json::from_str(&paths.read_to_string(&user_json_file)?)
+            .context("Failed to load user.json")?;
+        let Some(installed_apps) = user_json
+            .get_mut("installedApps")
+            .and_then(Value::as_array_mut)
+        else {
+            bail!("user.json has no list of installed apps");
+        };
+        if !installed_apps.iter().any(|app| app == app_id) {
+            installed_apps.push(json!(app_id));
+            paths.write(&user_json_file, serde_json::to_string_pretty(&user_json)?)?;
+        }
+        // Ports and IPs of the new app are assigned by converting all apps
+        self.convert(None)?;
+        let mut command = compose::compose_command(&paths, app_id)?;
+        command.arg("up").arg("--detach");
+        if compose::run(command)? != 0 {
+            bail!("Failed to start {app_id}");
+        }
+        Ok(Value::Null)
+    }
+
+    /// Changes the settings of an app and applies them, an installed app is restarted if they changed.
+    /// Returns the app's settings.
+    fn settings(&self, app_id: &str, values: BTreeMap<String, String>) -> Result<Value> {
+        validation::validate_app_id(app_id)?;
+        let changed = !values.is_empty();
+        let settings = app_settings::set(&self.citadel_root, &self.state_dir, app_id, values)?;
+        if changed {
+            self.convert(Some(app_id))?;
+            let paths = self.paths();
+            if app_list::installed_apps(&paths, paths.root())
+                .iter()
+                .any(|app| app == app_id)
+            {
+                let mut command = compose::compose_command(&paths, app_id)?;
+                command.arg("up").arg("--detach");
+                if compose::run(command)? != 0 {
+                    bail!("Failed to restart {app_id}");
+                }
+            }
+        }
+        Ok(serde_json::to_value(settings)?)
+    }
+
+    /// The installed apps, apps that can't run on this node and apps in maintenance mode,
+    /// as of the last conversion
+    fn status(&self) -> Result<Value> {
+        let paths = self.paths();
+        let citadel_root = paths.root();
+        let unsupported_file = citadel_root.join("apps").join("unsupported.json");
+        let unsupported: Value = if paths.exists(&unsupported_file) {
+            serde_json::from_str(&paths.read_to_string(&unsupported_file)?)
+                .context("Failed to load unsupported.json")?
+        } else {
+            json!({})
+        };
+        Ok(json!({
+            "apps": projects::load(&paths, citadel_root)?,
+            "unsupported": unsupported,
+            "maintenance": maintenance::load(&paths, citadel_root)?,
+        }))
+    }
+
+    /// Usage of the IP and port pools, as of the last conversion
+    fn capacity(&self) -> Result<Value> {
+        Ok(serde_json::to_value(capacity::load(
+            &self.citadel_root,
+            &self.state_dir,
+        )?)?)
+    }
+}
+
+/// Parses the named params of a method, params can be left out if none are required
+fn parse_params<T: serde::de::DeserializeOwned>(mut params: Value) -> Result<T, RpcError> {
+    if params.is_null() {
+        params = json!({});
+    }
+    serde_json::from_value(params).map_err(|err| RpcError::new(INVALID_PARAMS, err.to_string()))
+}
+
+fn error_response(id: Value, code: i64, message: impl ToString) -> Response {
+    Response {
+        jsonrpc: "2.0",
+        id,
+        result: None,
+        error: Some(RpcError::new(code, message.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{RpcServer, INVALID_PARAMS, METHOD_NOT_FOUND, PARSE_ERROR};
+
+    #[test]
+    fn answers_json_rpc_requests() {
+        let dir = tempdir::TempDir::new("rpc").unwrap();
+        let server = RpcServer::new(dir.path().to_string_lossy().to_string(), None, None);
+        let error_code = |line: &str| server.handle_line(line).unwrap().error.unwrap().code;
+        assert_eq!(error_code("{"), PARSE_ERROR);
+        assert_eq!(
+            error_code(r#"{"jsonrpc":"2.0","id":1,"method":"restart"}"#),
+            METHOD_NOT_FOUND
+        );
+        assert_eq!(
+            error_code(r#"{"jsonrpc":"2.0","id":2,"method":"install","params":{}}"#),
+            INVALID_PARAMS
+        );
+        let response = server
+            .handle_line(r#"{"jsonrpc":"2.0","id":"status","method":"status"}"#)
+            .unwrap();
+        assert_eq!(response.id, "status");
+        assert_eq!(
+            serde_json::to_value(response.result).unwrap(),
+            serde_json::json!({ "apps": {}, "unsupported": {}, "maintenance": [] })
+        );
+        // Notifications get no response
+        assert!(server
+            .handle_line(r#"{"jsonrpc":"2.0","method":"status"}"#)
+            .is_none());
+    }
+}
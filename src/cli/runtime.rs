@@ -0,0 +1,251 @@
+use serde::{Deserialize, Serialize};
+
+use crate::composegenerator::output::{labels::HOST_OWNER_LABEL, types::ComposeSpecification};
+
+/// How the Docker daemon on this node is running
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RuntimeMode {
+    #[default]
+    Default,
+    /// Docker running as an unprivileged user
+    Rootless,
+    /// Docker with userns-remap enabled
+    UsernsRemap,
+}
+
+/// The CLI containers are managed with
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ContainerEngine {
+    #[default]
+    Docker,
+    /// nerdctl with containerd
+    Nerdctl,
+}
+
+impl ContainerEngine {
+    pub fn binary(&self) -> &'static str {
+        match self {
+            ContainerEngine::Docker => "docker",
+            ContainerEngine::Nerdctl => "nerdctl",
+        }
+    }
+}
+
+/// Log drivers nerdctl supports, others are Docker-only
+const NERDCTL_LOG_DRIVERS: [&str; 5] = ["json-file", "journald", "fluentd", "syslog", "none"];
+
+/// Container runtime settings in app-manager.toml
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct RuntimeConfig {
+    pub engine: ContainerEngine,
+    pub mode: RuntimeMode,
+    /// The first subordinate UID/GID of the user containers are mapped to
+    pub subuid_base: u32,
+    /// Added to published ports below 1024, which can't be bound without root
+    pub privileged_port_offset: u16,
+}
+
+impl Default for RuntimeConfig {
+    fn default() -> Self {
+        Self {
+            engine: ContainerEngine::Docker,
+            mode: RuntimeMode::Default,
+            subuid_base: 100000,
+            privileged_port_offset: 10000,
+        }
+    }
+}
+
+impl RuntimeConfig {
+    /// The ID an ID inside a container has on the host
+    fn host_id(&self, id: u32) -> u32 {
+        match self.mode {
+            RuntimeMode::Default => id,
+            // The user running Docker is root inside the container
+            RuntimeMode::Rootless if id == 0 => 0,
+            RuntimeMode::Rootless => self.subuid_base + id - 1,
+            RuntimeMode::UsernsRemap => self.subuid_base + id,
+        }
+    }
+
+    fn host_owner(&self, user: &str) -> Option<String> {
+        let (uid, gid) = user.split_once(':').unwrap_or((user, user));
+        let uid: u32 = uid.parse().ok()?;
+        let gid: u32 = gid.parse().ok()?;
+        Some(format!("{}:{}", self.host_id(uid), self.host_id(gid)))
+    }
+}
+
+/// Adjusts a generated compose file for rootless Docker or userns-remap.
+///
+/// Published ports below 1024 are moved up by `privileged_port_offset`,
+/// containers are labeled with the owner their bind mounts need on the host,
+/// and features that can't work are removed or warned about.
+pub fn adjust_for_runtime(config: &RuntimeConfig, app_id: &str, spec: &mut ComposeSpecification) {
+    if config.mode == RuntimeMode::Default {
+        return;
+    }
+    for (service_name, service) in spec.services.iter_mut().flatten() {
+        if config.mode == RuntimeMode::Rootless {
+            for port in service.ports.iter_mut() {
+                let Some((host_port, container_port)) = port.split_once(':') else {
+                    continue;
+                };
+                let Ok(host_port) = host_port.parse::<u16>() else {
+                    continue;
+                };
+                if host_port >= 1024 {
+                    continue;
+                }
+                let new_port = host_port.saturating_add(config.privileged_port_offset);
+                tracing::warn!(
+                    "App {} (container {}) publishes privileged port {}, using {} instead",
+                    app_id,
+                    service_name,
+                    host_port,
+                    new_port
+                );
+                *port = format!("{new_port}:{container_port}");
+            }
+            service.security_opt.retain(|opt| {
+                let supported = !opt.starts_with("apparmor=");
+                if !supported {
+                    tracing::warn!(
+                        "App {} (container {}) uses an AppArmor profile, which is not supported with rootless Docker",
+                        app_id,
+                        service_name
+                    );
+                }
+                supported
+            });
+        }
+        if service.network_mode.as_deref() == Some("host") {
+            tracing::warn!(
+                "App {} (container {}) uses host networking, which may not work with {:?} Docker",
+                app_id,
+                service_name,
+                config.mode
+            );
+        }
+        if !service.volumes.is_empty() {
+            match service.user.as_deref().map(|user| config.host_owner(user)) {
+                Some(Some(owner)) => {
+                    service.labels.insert(HOST_OWNER_LABEL.to_string(), owner);
+                }
+                Some(None) => tracing::warn!(
+                    "App {} (container {}) runs as non-numeric user, its data may need to be owned by a different user on the host",
+                    app_id,
+                    service_name
+                ),
+                None => {}
+            }
+        }
+    }
+}
+
+/// Removes the Docker-only extensions nerdctl compose can't handle from a generated compose file
+pub fn adjust_for_engine(config: &RuntimeConfig, app_id: &str, spec: &mut ComposeSpecification) {
+    if config.engine != ContainerEngine::Nerdctl {
+        return;
+    }
+    for (service_name, service) in spec.services.iter_mut().flatten() {
+        service.security_opt.retain(|opt| {
+            // SELinux labels
+            let supported = !opt.starts_with("label=") && !opt.starts_with("label:");
+            if !supported {
+                tracing::warn!(
+                    "App {} (container {}) uses {}, which is not supported by nerdctl",
+                    app_id,
+                    service_name,
+                    opt
+                );
+            }
+            supported
+        });
+        if let Some(logging) = &service.logging {
+            if !NERDCTL_LOG_DRIVERS.contains(&logging.driver.as_str()) {
+                tracing::warn!(
+                    "App {} (container {}) uses the log driver {}, which is not supported by nerdctl, using the default instead",
+                    app_id,
+                    service_name,
+                    logging.driver
+                );
+                service.logging = None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        adjust_for_engine, adjust_for_runtime, ContainerEngine, RuntimeConfig, RuntimeMode,
+    };
+    use crate::composegenerator::output::{
+        labels::HOST_OWNER_LABEL,
+        types::{ComposeSpecification, Logging, Service},
+    };
+    use std::collections::BTreeMap;
+
+    fn spec() -> ComposeSpecification {
+        ComposeSpecification {
+            services: Some(BTreeMap::from([(
+                "main".to_string(),
+                Service {
+                    user: Some("1000:1000".to_string()),
+                    ports: vec!["80:80".to_string(), "9735:9735".to_string()],
+                    volumes: vec!["${APP_DATA_DIR}/data:/data".to_string()],
+                    security_opt: vec!["apparmor=citadel-example-main".to_string()],
+                    ..Default::default()
+                },
+            )])),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn adjusts_for_rootless() {
+        let config = RuntimeConfig {
+            mode: RuntimeMode::Rootless,
+            ..Default::default()
+        };
+        let mut spec = spec();
+        adjust_for_runtime(&config, "example", &mut spec);
+        let service = &spec.services.unwrap()["main"];
+        assert_eq!(service.ports, vec!["10080:80", "9735:9735"]);
+        assert!(service.security_opt.is_empty());
+        assert_eq!(service.labels[HOST_OWNER_LABEL], "100999:100999");
+    }
+
+    #[test]
+    fn keeps_output_by_default() {
+        let mut spec = spec();
+        adjust_for_runtime(&RuntimeConfig::default(), "example", &mut spec);
+        adjust_for_engine(&RuntimeConfig::default(), "example", &mut spec);
+        assert_eq!(spec, self::spec());
+    }
+
+    #[test]
+    fn removes_docker_only_options_for_nerdctl() {
+        let config = RuntimeConfig {
+            engine: ContainerEngine::Nerdctl,
+            ..Default::default()
+        };
+        let mut spec = spec();
+        let service = spec.services.as_mut().unwrap().get_mut("main").unwrap();
+        service
+            .security_opt
+            .push("label=type:container_t".to_string());
+        service.logging = Some(Logging {
+            driver: "local".to_string(),
+            options: BTreeMap::from([("max-size".to_string(), "10m".to_string())]),
+        });
+        adjust_for_engine(&config, "example", &mut spec);
+        let service = &spec.services.unwrap()["main"];
+        assert_eq!(service.security_opt, vec!["apparmor=citadel-example-main"]);
+        assert!(service.logging.is_none());
+    }
+}
@@ -0,0 +1,53 @@
+use anyhow::{bail, Result};
+use schemars::{schema::RootSchema, schema_for};
+
+#[cfg(feature = "umbrel")]
+use crate::composegenerator::umbrel::types::Metadata as UmbrelMetadata;
+use crate::composegenerator::{
+    compose::types::ComposeSpecification, types::ResultYml, v3::types::SchemaItemContainers,
+    v4::types::AppYml,
+};
+
+/// How a schema is printed
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum SchemaFormat {
+    #[default]
+    Yaml,
+    Json,
+}
+
+/// The JSON schema of an app.yml version ("3" or "4"), of umbrel-app.yml ("umbrel"),
+/// of result.yml ("result") or of a generated compose file ("compose")
+pub fn schema(version: &str) -> Result<RootSchema> {
+    Ok(match version {
+        "3" => schema_for!(SchemaItemContainers),
+        "4" => schema_for!(AppYml),
+        #[cfg(feature = "umbrel")]
+        "umbrel" => schema_for!(UmbrelMetadata),
+        "result" => schema_for!(ResultYml),
+        "compose" => schema_for!(ComposeSpecification),
+        _ => bail!("Unsupported schema version {version}"),
+    })
+}
+
+pub fn render(schema: &RootSchema, format: SchemaFormat) -> Result<String> {
+    Ok(match format {
+        SchemaFormat::Yaml => serde_yaml::to_string(schema)?,
+        SchemaFormat::Json => serde_json::to_string_pretty(schema)? + "\n",
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{render, schema, SchemaFormat};
+
+    /// App stores point editors and CI at schemas/app.yml.v4.schema.json
+    #[test]
+    fn published_schema_is_up_to_date() {
+        let current = render(&schema("4").unwrap(), SchemaFormat::Json).unwrap();
+        assert!(
+            current == include_str!("../../schemas/app.yml.v4.schema.json"),
+            "Run app-cli schema --format json > schemas/app.yml.v4.schema.json"
+        );
+    }
+}
@@ -0,0 +1,243 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use super::paths::CitadelPaths;
+use crate::composegenerator::v4::utils::derive_entropy;
+
+/// Files larger than this are not searched for secrets
+const MAX_FILE_SIZE: u64 = 10 * 1024 * 1024;
+
+/// Apps whose data still contains credentials from a previous seed, app ID -> the oldest such seed.
+/// Written by verify-secrets, an app is removed once its credentials were rotated.
+fn rotation_file(citadel_root: &Path) -> PathBuf {
+    citadel_root.join("db").join("secret-rotation.json")
+}
+
+/// The apps that still need their credentials rotated, with the oldest seed they use
+pub fn load_rotation(paths: &CitadelPaths, citadel_root: &Path) -> Result<BTreeMap<String, usize>> {
+    let file = rotation_file(citadel_root);
+    if !paths.exists(&file) {
+        return Ok(BTreeMap::new());
+    }
+    serde_json::from_str(&paths.read_to_string(&file)?)
+        .context("Failed to load secret-rotation.json")
+}
+
+/// Forgets that an app needs its credentials rotated, for example because its data was deleted
+pub fn clear_rotation(paths: &CitadelPaths, citadel_root: &Path, app_id: &str) -> Result<()> {
+    let mut rotation = load_rotation(paths, citadel_root)?;
+    if rotation.remove(app_id).is_some() {
+        paths.write(
+            &rotation_file(citadel_root),
+            serde_json::to_string_pretty(&rotation)?,
+        )?;
+    }
+    Ok(())
+}
+
+/// Loads the seeds of the node, newest first.
+/// The current seed is in db/citadel-seed/seed, seeds used before the node was restored with a new one
+/// are in db/citadel-seed/previous-seeds, one per line with the most recent one last.
+pub fn load_seeds(paths: &CitadelPaths, citadel_root: &Path) -> Result<Vec<String>> {
+    let seed_dir = citadel_root.join("db").join("citadel-seed");
+    let mut seeds = Vec::new();
+    let seed_file = seed_dir.join("seed");
+    if paths.exists(&seed_file) {
+        seeds.push(paths.read_to_string(&seed_file)?);
+    }
+    let previous_seeds_file = seed_dir.join("previous-seeds");
+    if paths.exists(&previous_seeds_file) {
+        let previous_seeds = paths.read_to_string(&previous_seeds_file)?;
+        seeds.extend(
+            previous_seeds
+                .lines()
+                .rev()
+                .filter(|seed| !seed.trim().is_empty())
+                .map(str::to_string),
+        );
+    }
+    Ok(seeds)
+}
+
+/// The secrets derived for an app from a seed, APP_SEED and APP_SEED_1 to APP_SEED_5
+fn app_secrets(seed: &str, app_id: &str) -> Vec<String> {
+    let mut secrets = vec![derive_entropy(seed, &format!("app-{app_id}-seed"))];
+    secrets.extend((1..6).map(|i| derive_entropy(seed, &format!("app-{app_id}-seed{i}"))));
+    secrets
+}
+
+/// Which seed the files of an app contain secrets from
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct SecretUsage {
+    pub app: String,
+    /// Index into the seeds, 0 is the current seed
+    pub seed: usize,
+    pub files: Vec<PathBuf>,
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in
+        std::fs::read_dir(dir).with_context(|| format!("Failed to read {}", dir.display()))?
+    {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            collect_files(&entry.path(), files)?;
+        } else if file_type.is_file() && entry.metadata()?.len() <= MAX_FILE_SIZE {
+            files.push(entry.path());
+        }
+    }
+    Ok(())
+}
+
+/// Searches the data directory of an app for secrets derived from any of the seeds
+fn find_secret_usage(app_id: &str, data_dir: &Path, seeds: &[String]) -> Result<Vec<SecretUsage>> {
+    let secrets: Vec<Vec<String>> = seeds.iter().map(|seed| app_secrets(seed, app_id)).collect();
+    let mut files = Vec::new();
+    collect_files(data_dir, &mut files)?;
+    files.sort();
+    let mut usage: BTreeMap<usize, Vec<PathBuf>> = BTreeMap::new();
+    for file in files {
+        let Ok(contents) = std::fs::read(&file) else {
+            continue;
+        };
+        let contents = String::from_utf8_lossy(&contents);
+        for (seed, seed_secrets) in secrets.iter().enumerate() {
+            if seed_secrets
+                .iter()
+                .any(|secret| contents.contains(secret.as_str()))
+            {
+                usage.entry(seed).or_default().push(file.clone());
+            }
+        }
+    }
+    Ok(usage
+        .into_iter()
+        .map(|(seed, files)| SecretUsage {
+            app: app_id.to_string(),
+            seed,
+            files,
+        })
+        .collect())
+}
+
+/// Checks the data of every app for secrets derived from the current or a previous seed.
+/// Returns the apps whose data contains secrets from a previous seed, they are saved to db/secret-rotation.json.
+pub fn verify_secrets(citadel_root: &str, state_dir: &Option<String>) -> Result<Vec<SecretUsage>> {
+    let paths = CitadelPaths::new(Path::new(citadel_root), state_dir.as_deref().map(Path::new));
+    let citadel_root = paths.root();
+    let seeds = load_seeds(&paths, citadel_root)?;
+    let app_data_dir = citadel_root.join("app-data");
+    if seeds.len() < 2 || !app_data_dir.is_dir() {
+        save_rotation(&paths, citadel_root, &[])?;
+        return Ok(Vec::new());
+    }
+    let mut apps: Vec<PathBuf> = std::fs::read_dir(&app_data_dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_dir())
+        .collect();
+    apps.sort();
+    let mut outdated = Vec::new();
+    for data_dir in apps {
+        let Some(app_id) = data_dir.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        for usage in find_secret_usage(app_id, &data_dir, &seeds)? {
+            if usage.seed > 0 {
+                tracing::warn!(
+                    "App {} still uses credentials derived from a previous seed",
+                    app_id
+                );
+                outdated.push(usage);
+            }
+        }
+    }
+    save_rotation(&paths, citadel_root, &outdated)?;
+    Ok(outdated)
+}
+
+fn save_rotation(
+    paths: &CitadelPaths,
+    citadel_root: &Path,
+    outdated: &[SecretUsage],
+) -> Result<()> {
+    let mut rotation: BTreeMap<String, usize> = BTreeMap::new();
+    for usage in outdated {
+        let seed = rotation.entry(usage.app.clone()).or_default();
+        *seed = usage.seed.max(*seed);
+    }
+    paths.write(
+        &rotation_file(citadel_root),
+        serde_json::to_string_pretty(&rotation)?,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use super::{
+        app_secrets, clear_rotation, find_secret_usage, load_rotation, save_rotation, SecretUsage,
+    };
+    use crate::cli::paths::CitadelPaths;
+
+    #[test]
+    fn finds_secrets_of_previous_seeds() {
+        let dir = tempdir::TempDir::new("secrets").unwrap();
+        let seeds = vec!["new-seed".to_string(), "old-seed".to_string()];
+        let old_password = &app_secrets("old-seed", "lnd")[2];
+        std::fs::create_dir(dir.path().join("config")).unwrap();
+        std::fs::write(
+            dir.path().join("config").join("lnd.conf"),
+            format!("rpcpass={old_password}\n"),
+        )
+        .unwrap();
+        std::fs::write(dir.path().join("other.conf"), "nothing here").unwrap();
+
+        let usage = find_secret_usage("lnd", dir.path(), &seeds).unwrap();
+        assert_eq!(usage.len(), 1);
+        assert_eq!(usage[0].seed, 1);
+        assert_eq!(
+            usage[0].files,
+            vec![dir.path().join("config").join("lnd.conf")]
+        );
+
+        // Secrets of other apps are not matched
+        assert!(find_secret_usage("btcpay", dir.path(), &seeds)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn clears_rotation_of_reset_apps() {
+        let root = tempdir::TempDir::new("secrets").unwrap();
+        std::fs::create_dir(root.path().join("db")).unwrap();
+        let paths = CitadelPaths::new(root.path(), None);
+        let usage = |app: &str, seed| SecretUsage {
+            app: app.to_string(),
+            seed,
+            files: vec![PathBuf::from("config")],
+        };
+        save_rotation(
+            &paths,
+            root.path(),
+            &[usage("lnd", 1), usage("lnd", 2), usage("btcpay", 1)],
+        )
+        .unwrap();
+        assert_eq!(
+            load_rotation(&paths, root.path()).unwrap(),
+            [("btcpay".to_string(), 1), ("lnd".to_string(), 2)].into()
+        );
+
+        clear_rotation(&paths, root.path(), "lnd").unwrap();
+        assert_eq!(
+            load_rotation(&paths, root.path()).unwrap(),
+            [("btcpay".to_string(), 1)].into()
+        );
+    }
+}
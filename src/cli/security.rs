@@ -0,0 +1,314 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use super::paths::CitadelPaths;
+use crate::composegenerator::{output::types::ComposeSpecification, v4::types::SecurityProfiles};
+
+/// Security settings in app-manager.toml
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct SecurityConfig {
+    /// IDs of stores whose apps may ship their own security profiles
+    pub trusted_stores: Vec<String>,
+    /// Run all containers with no-new-privileges
+    pub no_new_privileges: bool,
+    /// Where AppArmor profiles are installed to
+    pub apparmor_dir: PathBuf,
+    /// Host directories apps may bind as local named volumes.
+    /// Apps from trusted stores may bind any directory.
+    pub volume_devices: Vec<PathBuf>,
+}
+
+impl Default for SecurityConfig {
+    fn default() -> Self {
+        Self {
+            trusted_stores: Vec::new(),
+            no_new_privileges: false,
+            apparmor_dir: PathBuf::from("/etc/apparmor.d"),
+            volume_devices: Vec::new(),
+        }
+    }
+}
+
+/// Resolves a file in the app directory, files outside of it are rejected
+fn resolve_profile(app_dir: &Path, profile: &str) -> Result<PathBuf> {
+    let app_dir = app_dir
+        .canonicalize()
+        .with_context(|| format!("Failed to resolve {}", app_dir.display()))?;
+    let path = app_dir
+        .join(profile)
+        .canonicalize()
+        .with_context(|| format!("Security profile {profile} does not exist"))?;
+    if !path.starts_with(&app_dir) {
+        bail!("Security profile {profile} is outside of the app directory");
+    }
+    Ok(path)
+}
+
+/// Installs an AppArmor profile and loads it into the kernel, a dry run only checks its name
+fn install_apparmor_profile(
+    paths: &CitadelPaths,
+    config: &SecurityConfig,
+    profile: &Path,
+    name: &str,
+    dry_run: bool,
+) -> Result<()> {
+    let contents = paths.read_to_string(profile)?;
+    if !contents.contains(&format!("profile {name} ")) {
+        bail!(
+            "AppArmor profile {} must be named {name}",
+            profile.display()
+        );
+    }
+    if dry_run {
+        return Ok(());
+    }
+    let target = config.apparmor_dir.join(name);
+    paths.write(&target, contents)?;
+    let status = Command::new("apparmor_parser")
+        .arg("--replace")
+        .arg(&target)
+        .status()
+        .context("Failed to run apparmor_parser")?;
+    if !status.success() {
+        bail!("Failed to load AppArmor profile {name}");
+    }
+    Ok(())
+}
+
+/// Checks that the local named volumes of an app only bind directories in `allowed`.
+/// Symlinks are resolved, so a device can't point outside of them.
+fn check_volume_devices(allowed: &[PathBuf], spec: &ComposeSpecification) -> Result<()> {
+    for (name, volume) in &spec.volumes {
+        if volume.driver_opts.get("type").map(String::as_str) != Some("none") {
+            continue;
+        }
+        let Some(device) = volume.driver_opts.get("device") else {
+            continue;
+        };
+        let resolved = Path::new(device)
+            .canonicalize()
+            .with_context(|| format!("Device {device} of volume {name} does not exist"))?;
+        if !allowed.iter().any(|dir| {
+            dir.canonicalize()
+                .is_ok_and(|dir| resolved.starts_with(dir))
+        }) {
+            bail!("Volume {name} binds {device}, which is not in the volume_devices allowed in app-manager.toml");
+        }
+    }
+    Ok(())
+}
+
+/// Adds security_opt entries to the generated compose file.
+///
+/// Operator-enforced options are applied to all containers,
+/// profiles requested by the app only if its store is trusted.
+/// Local named volumes of apps from other stores must bind a directory allowed in `volume_devices`.
+/// A `dry_run` doesn't install AppArmor profiles.
+pub fn apply_security_options(
+    paths: &CitadelPaths,
+    config: &SecurityConfig,
+    app_id: &str,
+    store: Option<&str>,
+    requested: &HashMap<String, SecurityProfiles>,
+    spec: &mut ComposeSpecification,
+    dry_run: bool,
+) -> Result<()> {
+    let trusted =
+        store.is_some_and(|store| config.trusted_stores.iter().any(|trusted| trusted == store));
+    if !trusted && !requested.is_empty() {
+        tracing::warn!(
+            "App {} requests security profiles, but its store is not trusted, ignoring them",
+            app_id
+        );
+    }
+    if !trusted {
+        check_volume_devices(&config.volume_devices, spec)?;
+    }
+    let app_dir = paths.read_path(&paths.root().join("apps").join(app_id));
+    for (service_name, service) in spec.services.iter_mut().flatten() {
+        if config.no_new_privileges {
+            service
+                .security_opt
+                .push("no-new-privileges:true".to_string());
+        }
+        let Some(profiles) = requested.get(service_name).filter(|_| trusted) else {
+            continue;
+        };
+        if let Some(apparmor) = &profiles.apparmor {
+            let name = format!("citadel-{app_id}-{service_name}");
+            install_apparmor_profile(
+                paths,
+                config,
+                &resolve_profile(&app_dir, apparmor)?,
+                &name,
+                dry_run,
+            )?;
+            service.security_opt.push(format!("apparmor={name}"));
+        }
+        if let Some(seccomp) = &profiles.seccomp {
+            let profile = resolve_profile(&app_dir, seccomp)?;
+            service
+                .security_opt
+                .push(format!("seccomp={}", profile.display()));
+        }
+        if let Some(selinux_type) = &profiles.selinux_type {
+            if !selinux_type
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_')
+            {
+                bail!("Invalid SELinux type {selinux_type}");
+            }
+            service
+                .security_opt
+                .push(format!("label=type:{selinux_type}"));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{apply_security_options, check_volume_devices, SecurityConfig};
+    use crate::cli::paths::CitadelPaths;
+    use crate::composegenerator::{
+        output::types::{ComposeSpecification, Service, Volume},
+        v4::types::SecurityProfiles,
+    };
+    use std::collections::{BTreeMap, HashMap};
+    use std::path::Path;
+
+    #[test]
+    fn only_applies_profiles_of_trusted_stores() {
+        let paths = CitadelPaths::new(Path::new("/nonexistent"), None);
+        let config = SecurityConfig {
+            trusted_stores: vec!["citadel".to_string()],
+            no_new_privileges: true,
+            ..Default::default()
+        };
+        let requested = HashMap::from([(
+            "main".to_string(),
+            SecurityProfiles {
+                selinux_type: Some("citadel_app_t".to_string()),
+                ..Default::default()
+            },
+        )]);
+        let spec = ComposeSpecification {
+            services: Some(BTreeMap::from([("main".to_string(), Service::default())])),
+            ..Default::default()
+        };
+
+        let mut trusted = spec.clone();
+        apply_security_options(
+            &paths,
+            &config,
+            "example",
+            Some("citadel"),
+            &requested,
+            &mut trusted,
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            trusted.services.unwrap()["main"].security_opt,
+            vec!["no-new-privileges:true", "label=type:citadel_app_t"]
+        );
+
+        let mut untrusted = spec;
+        apply_security_options(
+            &paths,
+            &config,
+            "example",
+            Some("other"),
+            &requested,
+            &mut untrusted,
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            untrusted.services.unwrap()["main"].security_opt,
+            vec!["no-new-privileges:true"]
+        );
+    }
+
+    #[test]
+    fn dry_run_does_not_install_apparmor_profiles() {
+        let root = tempdir::TempDir::new("security").unwrap();
+        let app_dir = root.path().join("apps").join("example");
+        std::fs::create_dir_all(&app_dir).unwrap();
+        std::fs::write(
+            app_dir.join("apparmor"),
+            "profile citadel-example-main flags=(attach_disconnected) {}",
+        )
+        .unwrap();
+        let paths = CitadelPaths::new(root.path(), None);
+        let config = SecurityConfig {
+            trusted_stores: vec!["citadel".to_string()],
+            apparmor_dir: root.path().join("apparmor.d"),
+            ..Default::default()
+        };
+        let requested = HashMap::from([(
+            "main".to_string(),
+            SecurityProfiles {
+                apparmor: Some("apparmor".to_string()),
+                ..Default::default()
+            },
+        )]);
+        let mut spec = ComposeSpecification {
+            services: Some(BTreeMap::from([("main".to_string(), Service::default())])),
+            ..Default::default()
+        };
+        apply_security_options(
+            &paths,
+            &config,
+            "example",
+            Some("citadel"),
+            &requested,
+            &mut spec,
+            true,
+        )
+        .unwrap();
+        assert_eq!(
+            spec.services.unwrap()["main"].security_opt,
+            vec!["apparmor=citadel-example-main"]
+        );
+        assert!(!config.apparmor_dir.exists());
+    }
+
+    #[test]
+    fn only_binds_allowed_volume_devices() {
+        let dir = tempdir::TempDir::new("volumes").unwrap();
+        let media = dir.path().join("media");
+        let secrets = dir.path().join("secrets");
+        std::fs::create_dir_all(media.join("movies")).unwrap();
+        std::fs::create_dir(&secrets).unwrap();
+        std::os::unix::fs::symlink(&secrets, media.join("escape")).unwrap();
+        let spec = |device: &std::path::Path| ComposeSpecification {
+            volumes: BTreeMap::from([(
+                "media".to_string(),
+                Volume {
+                    driver: Some("local".to_string()),
+                    driver_opts: BTreeMap::from([
+                        ("type".to_string(), "none".to_string()),
+                        ("o".to_string(), "bind".to_string()),
+                        ("device".to_string(), device.to_string_lossy().to_string()),
+                    ]),
+                    labels: BTreeMap::new(),
+                },
+            )]),
+            ..Default::default()
+        };
+        let allowed = vec![media.clone()];
+        assert!(check_volume_devices(&allowed, &spec(&media.join("movies"))).is_ok());
+        assert!(check_volume_devices(&allowed, &spec(&secrets)).is_err());
+        assert!(check_volume_devices(&allowed, &spec(&media.join("escape"))).is_err());
+        assert!(check_volume_devices(&allowed, &spec(&media.join("missing"))).is_err());
+        assert!(check_volume_devices(&[], &spec(&media)).is_err());
+    }
+}
@@ -0,0 +1,124 @@
+use std::{
+    collections::HashMap,
+    io::{Read, Write},
+    path::Path,
+};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::composegenerator::{
+    convert_config, output::labels::add_labels, v4::types::PortMapElement,
+};
+
+/// Context for converting a single app outside of a Citadel root
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct ConversionContext {
+    /// Same format as apps/ports.yml: app -> container -> ports
+    pub port_map: Option<HashMap<String, HashMap<String, Vec<PortMapElement>>>>,
+    /// Same format as apps/ips.yml: env var -> IP
+    #[serde(default)]
+    pub ip_addresses: HashMap<String, String>,
+    /// Apps and services that are installed. Required to convert app.yml v3 files
+    pub installed_services: Option<Vec<String>>,
+}
+
+impl ConversionContext {
+    /// Loads a JSON context file
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = std::fs::File::open(path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        serde_json::from_reader(file).with_context(|| format!("Failed to parse {}", path.display()))
+    }
+
+    /// Adds an IP address in the form NAME=IP
+    pub fn add_ip(&mut self, ip: &str) -> Result<()> {
+        let Some((name, ip)) = ip.split_once('=') else {
+            bail!("Invalid IP address {ip}, expected NAME=IP");
+        };
+        self.ip_addresses.insert(name.to_string(), ip.to_string());
+        Ok(())
+    }
+
+    /// Adds a port mapping for an app in the form container:internal_port:public_port
+    pub fn add_port(&mut self, app_id: &str, port: &str) -> Result<()> {
+        let parts: Vec<&str> = port.split(':').collect();
+        let [container, internal_port, public_port] = parts[..] else {
+            bail!("Invalid port {port}, expected container:internal_port:public_port");
+        };
+        let element = PortMapElement {
+            dynamic: false,
+            internal_port: internal_port
+                .parse()
+                .with_context(|| format!("Invalid internal port in {port}"))?,
+            public_port: public_port
+                .parse()
+                .with_context(|| format!("Invalid public port in {port}"))?,
+        };
+        self.port_map
+            .get_or_insert_with(HashMap::new)
+            .entry(app_id.to_string())
+            .or_default()
+            .entry(container.to_string())
+            .or_default()
+            .push(element);
+        Ok(())
+    }
+}
+
+/// Converts a single app.yml read from `reader` and writes the resulting docker-compose.yml to `writer`
+pub fn convert_single_app<R: Read, W: Write>(
+    app_id: &str,
+    reader: R,
+    context: &ConversionContext,
+    writer: W,
+) -> Result<()> {
+    let mut result = convert_config(
+        app_id,
+        reader,
+        context.port_map.as_ref(),
+        context.installed_services.as_deref(),
+        Some(&context.ip_addresses),
+    )?;
+    add_labels(&mut result.spec, app_id, &result.metadata.version, None);
+    serde_yaml::to_writer(writer, &result.spec)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{convert_single_app, ConversionContext};
+
+    #[test]
+    fn converts_from_reader() {
+        let app_yml = "citadel_version: 4
+metadata:
+  name: Example
+  version: 1.0.0
+  category: Example
+  tagline: Example
+  developers:
+    Citadel: https://runcitadel.space
+  permissions: []
+  repo:
+    Public: https://github.com/runcitadel/example
+  support: https://t.me/citadeldevelopers
+  description: Example
+services:
+  main:
+    image: example:1.0.0
+    port: 3000
+";
+        let mut context = ConversionContext::default();
+        context.add_ip("APP_EXAMPLE_MAIN_IP=10.21.21.20").unwrap();
+        context.add_port("example", "main:3000:3005").unwrap();
+        assert!(context.add_port("example", "main:3000").is_err());
+        let mut output = Vec::new();
+        convert_single_app("example", app_yml.as_bytes(), &context, &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("image: example:1.0.0"));
+        assert!(output.contains("$APP_EXAMPLE_MAIN_IP"));
+        assert!(output.contains("citadel.app: example"));
+    }
+}
@@ -0,0 +1,115 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use serde::{Deserialize, Serialize};
+
+/// The order in which installed apps should be started, written to apps/start-order.json
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct StartOrder {
+    /// Apps in the same stage don't depend on each other and can be started in parallel.
+    /// A stage should only be started once all apps of the previous stage are running.
+    pub stages: Vec<Vec<String>>,
+    /// App -> installed apps it needs to be started after
+    pub dependencies: BTreeMap<String, BTreeSet<String>>,
+}
+
+/// Computes the start order of apps from their permissions.
+///
+/// `permissions` maps every installed app to its (flattened) permissions.
+/// Permissions for virtual apps are resolved to the installed apps implementing them.
+pub fn compute(
+    permissions: &HashMap<String, Vec<String>>,
+    virtual_apps: &HashMap<String, Vec<String>>,
+) -> StartOrder {
+    let mut dependencies = BTreeMap::new();
+    for (app_id, app_permissions) in permissions {
+        let mut app_dependencies = BTreeSet::new();
+        for permission in app_permissions {
+            if permissions.contains_key(permission) {
+                app_dependencies.insert(permission.clone());
+            }
+            if let Some(implementations) = virtual_apps.get(permission) {
+                app_dependencies.extend(
+                    implementations
+                        .iter()
+                        .filter(|implementation| permissions.contains_key(*implementation))
+                        .cloned(),
+                );
+            }
+        }
+        app_dependencies.remove(app_id);
+        dependencies.insert(app_id.clone(), app_dependencies);
+    }
+
+    let mut stages = Vec::new();
+    let mut started = BTreeSet::new();
+    let mut remaining: BTreeSet<&String> = dependencies.keys().collect();
+    while !remaining.is_empty() {
+        let stage: Vec<String> = remaining
+            .iter()
+            .filter(|app_id| dependencies[**app_id].is_subset(&started))
+            .map(|app_id| app_id.to_string())
+            .collect();
+        if stage.is_empty() {
+            let cycle: Vec<String> = remaining.iter().map(|app_id| app_id.to_string()).collect();
+            tracing::warn!(
+                "Apps {} depend on each other, starting them at the same time",
+                cycle.join(", ")
+            );
+            stages.push(cycle);
+            break;
+        }
+        for app_id in &stage {
+            remaining.remove(app_id);
+        }
+        started.extend(stage.iter().cloned());
+        stages.push(stage);
+    }
+
+    StartOrder {
+        stages,
+        dependencies,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::compute;
+    use std::collections::HashMap;
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[test]
+    fn orders_apps_by_dependencies() {
+        let permissions = HashMap::from([
+            ("lnd".to_string(), strings(&["bitcoind", "network"])),
+            ("btc-rpc-explorer".to_string(), strings(&["electrum"])),
+            ("electrs".to_string(), strings(&["bitcoind"])),
+            ("ride-the-lightning".to_string(), strings(&["lnd"])),
+        ]);
+        let virtual_apps =
+            HashMap::from([("electrum".to_string(), strings(&["electrs", "fulcrum"]))]);
+        let order = compute(&permissions, &virtual_apps);
+        assert_eq!(
+            order.stages,
+            vec![
+                strings(&["electrs", "lnd"]),
+                strings(&["btc-rpc-explorer", "ride-the-lightning"])
+            ]
+        );
+        assert!(order.dependencies["btc-rpc-explorer"].contains("electrs"));
+        assert!(!order.dependencies["btc-rpc-explorer"].contains("fulcrum"));
+    }
+
+    #[test]
+    fn handles_cycles() {
+        let permissions = HashMap::from([
+            ("a".to_string(), strings(&["b"])),
+            ("b".to_string(), strings(&["a"])),
+            ("c".to_string(), strings(&[])),
+        ]);
+        let order = compute(&permissions, &HashMap::new());
+        assert_eq!(order.stages, vec![strings(&["c"]), strings(&["a", "b"])]);
+    }
+}
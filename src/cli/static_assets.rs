@@ -0,0 +1,119 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::composegenerator::v4::utils::{is_valid_static_assets_dir, static_assets_path};
+
+/// Settings for serving static assets of apps in app-manager.toml
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct StaticAssetsConfig {
+    /// Where Caddy sees the apps directory of the Citadel root, if it is mounted somewhere else
+    pub caddy_dir: Option<PathBuf>,
+}
+
+/// The static assets directory of an app relative to the app's directory, with symlinks resolved.
+/// None if it does not exist, is outside the app's directory or is the app's directory itself.
+fn resolve_dir(apps_dir: &Path, app_id: &str, dir: &str) -> Option<PathBuf> {
+    if !is_valid_static_assets_dir(dir) {
+        tracing::warn!(
+            "The static assets directory {} of app {} is invalid",
+            dir,
+            app_id
+        );
+        return None;
+    }
+    let app_dir = apps_dir.join(app_id).canonicalize().ok()?;
+    let Ok(assets_dir) = app_dir.join(dir).canonicalize() else {
+        tracing::warn!(
+            "The static assets directory {} of app {} does not exist",
+            dir,
+            app_id
+        );
+        return None;
+    };
+    let Some(relative) = assets_dir
+        .strip_prefix(&app_dir)
+        .ok()
+        .filter(|relative| !relative.as_os_str().is_empty())
+    else {
+        tracing::warn!(
+            "The static assets directory {} of app {} is not inside the app's directory",
+            dir,
+            app_id
+        );
+        return None;
+    };
+    assets_dir.is_dir().then(|| relative.to_path_buf())
+}
+
+/// Resolves the static asset directories of apps, app ID -> directory as seen by Caddy.
+/// `assets` are app ID -> directory relative to the app.
+pub fn resolve_dirs(
+    config: &StaticAssetsConfig,
+    apps_dir: &Path,
+    assets: &BTreeMap<String, String>,
+) -> BTreeMap<String, PathBuf> {
+    let caddy_dir = config.caddy_dir.as_deref().unwrap_or(apps_dir);
+    assets
+        .iter()
+        .filter_map(|(app_id, dir)| {
+            let relative = resolve_dir(apps_dir, app_id, dir)?;
+            Some((app_id.clone(), caddy_dir.join(app_id).join(relative)))
+        })
+        .collect()
+}
+
+/// Generates the Caddy directives serving the static assets of apps.
+/// They are meant to be included in the dashboard's site block by Caddyfile.jinja.
+pub fn generate_caddy_config(dirs: &BTreeMap<String, PathBuf>) -> String {
+    let mut config = String::new();
+    for (app_id, dir) in dirs {
+        let _ = writeln!(config, "handle_path {}* {{", static_assets_path(app_id));
+        // Caddy's path to the apps directory is set by the operator and can contain spaces
+        let root = dir.display().to_string();
+        let _ = writeln!(
+            config,
+            "root * \"{}\"",
+            root.replace('\\', "\\\\").replace('"', "\\\"")
+        );
+        let _ = writeln!(config, "file_server");
+        let _ = writeln!(config, "}}");
+    }
+    config
+}
+
+#[cfg(test)]
+mod test {
+    use super::{generate_caddy_config, resolve_dirs, StaticAssetsConfig};
+    use std::{collections::BTreeMap, path::PathBuf};
+
+    #[test]
+    fn serves_static_assets() {
+        let apps_dir = tempdir::TempDir::new("static-assets").unwrap();
+        std::fs::create_dir_all(apps_dir.path().join("docs").join("public")).unwrap();
+        std::fs::create_dir_all(apps_dir.path().join("escape")).unwrap();
+        std::os::unix::fs::symlink("/etc", apps_dir.path().join("escape").join("public")).unwrap();
+        std::fs::create_dir_all(apps_dir.path().join("itself")).unwrap();
+        std::os::unix::fs::symlink(".", apps_dir.path().join("itself").join("public")).unwrap();
+        let assets = BTreeMap::from([
+            ("docs".to_string(), "public".to_string()),
+            ("broken".to_string(), "public".to_string()),
+            ("escape".to_string(), "public".to_string()),
+            ("itself".to_string(), "public".to_string()),
+            ("dot".to_string(), ".".to_string()),
+        ]);
+        let config = StaticAssetsConfig {
+            caddy_dir: Some(PathBuf::from("/srv/apps")),
+        };
+        let dirs = resolve_dirs(&config, apps_dir.path(), &assets);
+        assert_eq!(
+            generate_caddy_config(&dirs),
+            "handle_path /apps/docs/assets/* {\nroot * \"/srv/apps/docs/public\"\nfile_server\n}\n"
+        );
+    }
+}
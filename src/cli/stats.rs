@@ -0,0 +1,116 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::Serialize;
+
+use crate::composegenerator::{output::types::ComposeSpecification, types::OutputMetadata};
+use crate::utils::flatten;
+
+/// Aggregate statistics about the apps of all stores, written to apps/stats.json
+#[derive(Serialize, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct StoreStats {
+    /// Number of converted apps
+    pub apps: usize,
+    /// Apps that can't be installed on this node
+    pub unsupported: usize,
+    /// Category -> number of apps
+    pub categories: BTreeMap<String, usize>,
+    /// Dependency -> number of apps requiring it
+    pub permissions: BTreeMap<String, usize>,
+    /// Registry -> number of images pulled from it
+    pub registries: BTreeMap<String, usize>,
+}
+
+/// The registry of an image reference, docker.io for images without one
+fn image_registry(image: &str) -> &str {
+    match image.split_once('/') {
+        Some((first, _)) if first.contains('.') || first.contains(':') || first == "localhost" => {
+            first
+        }
+        _ => "docker.io",
+    }
+}
+
+impl StoreStats {
+    pub fn add_app(&mut self, metadata: &OutputMetadata, spec: &ComposeSpecification) {
+        self.apps += 1;
+        if !metadata.unsupported.is_empty() {
+            self.unsupported += 1;
+        }
+        *self
+            .categories
+            .entry(metadata.category.clone())
+            .or_default() += 1;
+        // Alternatives of the same dependency only count once
+        let permissions: BTreeSet<&String> = flatten(&metadata.permissions).into_iter().collect();
+        for permission in permissions {
+            *self.permissions.entry(permission.clone()).or_default() += 1;
+        }
+        for image in spec
+            .services
+            .iter()
+            .flat_map(|services| services.values())
+            .filter_map(|service| service.image.as_deref())
+        {
+            *self
+                .registries
+                .entry(image_registry(image).to_string())
+                .or_default() += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{image_registry, StoreStats};
+    use crate::{
+        bmap,
+        composegenerator::{
+            output::types::{ComposeSpecification, Service},
+            types::{OutputMetadata, Permissions},
+        },
+    };
+
+    #[test]
+    fn counts_apps() {
+        assert_eq!(image_registry("nginx:1.25"), "docker.io");
+        assert_eq!(image_registry("lncm/bitcoind:v25.0"), "docker.io");
+        assert_eq!(image_registry("ghcr.io/runcitadel/lnd:v0.16"), "ghcr.io");
+        assert_eq!(image_registry("localhost:5000/app"), "localhost:5000");
+
+        let mut stats = StoreStats::default();
+        let spec = ComposeSpecification {
+            services: Some(bmap! {
+                "web" => Service {
+                    image: Some("ghcr.io/example/web".to_string()),
+                    ..Default::default()
+                },
+                "db" => Service {
+                    image: Some("postgres:15".to_string()),
+                    ..Default::default()
+                }
+            }),
+            ..Default::default()
+        };
+        stats.add_app(
+            &OutputMetadata {
+                category: "Finance".to_string(),
+                permissions: vec![
+                    Permissions::OneDependency("lnd".to_string()),
+                    Permissions::AlternativeDependency(vec![
+                        "lnd".to_string(),
+                        "core-ln".to_string(),
+                    ]),
+                ],
+                ..Default::default()
+            },
+            &spec,
+        );
+        assert_eq!(stats.apps, 1);
+        assert_eq!(stats.categories["Finance"], 1);
+        assert_eq!(stats.permissions["lnd"], 1);
+        assert_eq!(stats.permissions["core-ln"], 1);
+        assert_eq!(stats.registries["ghcr.io"], 1);
+        assert_eq!(stats.registries["docker.io"], 1);
+    }
+}
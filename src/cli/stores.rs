@@ -0,0 +1,38 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use super::{bundles::Bundle, paths::CitadelPaths};
+
+/// An app store as saved in apps/stores.yml
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub(crate) struct AppStoreInfo {
+    pub id: String,
+    pub name: String,
+    pub tagline: String,
+    pub icon: String,
+    pub developers: String,
+    pub license: String,
+    /// App ID -> latest commit that changed the app
+    pub apps: HashMap<String, String>,
+    pub commit: String,
+    pub repo: String,
+    pub branch: String,
+    pub subdir: String,
+    /// Bundle ID -> bundle
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub bundles: BTreeMap<String, Bundle>,
+}
+
+/// Loads apps/stores.yml, returns an empty list if no stores have been downloaded yet
+pub(crate) fn load_stores(paths: &CitadelPaths, citadel_root: &Path) -> Result<Vec<AppStoreInfo>> {
+    let stores_yml = citadel_root.join("apps").join("stores.yml");
+    if !paths.exists(&stores_yml) {
+        return Ok(Vec::new());
+    }
+    serde_yaml::from_reader(paths.open(&stores_yml)?).context("Failed to load stores.yml")
+}
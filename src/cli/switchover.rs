@@ -0,0 +1,92 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::Path,
+};
+
+use anyhow::{Context, Result};
+
+use super::paths::CitadelPaths;
+
+/// Loads which app currently serves each interface, interface -> app ID
+pub fn load_owners(paths: &CitadelPaths, citadel_root: &Path) -> Result<BTreeMap<String, String>> {
+    let owners_file = citadel_root.join("apps").join("interface-owners.json");
+    if !paths.exists(&owners_file) {
+        return Ok(BTreeMap::new());
+    }
+    serde_json::from_str(&paths.read_to_string(&owners_file)?)
+        .context("Failed to load interface-owners.json")
+}
+
+/// Decides which installed app serves each interface.
+/// `implementations` are interface -> installed apps implementing it.
+/// If another app is installed for an interface that is already served, it only takes over
+/// once `is_ready` confirms it responds, so clients like wallets are never left without a server.
+pub fn select_owners(
+    previous: &BTreeMap<String, String>,
+    implementations: &BTreeMap<String, BTreeSet<String>>,
+    mut is_ready: impl FnMut(&str, &str) -> bool,
+) -> BTreeMap<String, String> {
+    let mut owners = BTreeMap::new();
+    for (interface, apps) in implementations {
+        let Some(first) = apps.first() else {
+            continue;
+        };
+        let owner = match previous.get(interface) {
+            Some(current) if apps.contains(current) => {
+                match apps
+                    .iter()
+                    .filter(|app| *app != current)
+                    .find(|app| is_ready(app, interface))
+                {
+                    Some(next) => {
+                        tracing::info!("Switching {} from {} to {}", interface, current, next);
+                        next
+                    }
+                    None => {
+                        if apps.len() > 1 {
+                            tracing::info!(
+                                "{} stays with {} until another implementation is ready",
+                                interface,
+                                current
+                            );
+                        }
+                        current
+                    }
+                }
+            }
+            // Nothing serves the interface yet, so there is nothing to wait for
+            _ => first,
+        };
+        owners.insert(interface.clone(), owner.clone());
+    }
+    owners
+}
+
+#[cfg(test)]
+mod test {
+    use super::select_owners;
+    use std::collections::{BTreeMap, BTreeSet};
+
+    #[test]
+    fn waits_for_readiness() {
+        let previous = BTreeMap::from([("electrum".to_string(), "electrs".to_string())]);
+        let implementations = BTreeMap::from([(
+            "electrum".to_string(),
+            BTreeSet::from(["electrs".to_string(), "fulcrum".to_string()]),
+        )]);
+        let owners = select_owners(&previous, &implementations, |_, _| false);
+        assert_eq!(owners["electrum"], "electrs");
+        let owners = select_owners(&previous, &implementations, |app, interface| {
+            app == "fulcrum" && interface == "electrum"
+        });
+        assert_eq!(owners["electrum"], "fulcrum");
+
+        // The previous owner was uninstalled
+        let implementations = BTreeMap::from([(
+            "electrum".to_string(),
+            BTreeSet::from(["fulcrum".to_string()]),
+        )]);
+        let owners = select_owners(&previous, &implementations, |_, _| false);
+        assert_eq!(owners["electrum"], "fulcrum");
+    }
+}
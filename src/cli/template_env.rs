@@ -0,0 +1,125 @@
+use std::collections::{BTreeSet, HashMap};
+
+use serde::{Deserialize, Serialize};
+
+use super::{bundles, redaction::Redactor};
+use crate::{
+    composegenerator::v4::permissions::{is_allowed_by_permissions, ALWAYS_ALLOWED_ENV_VARS},
+    naming,
+};
+
+/// Env vars passed to templates, in app-manager.toml
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct TemplateEnvConfig {
+    /// Env vars from the .env file the templates of all apps and the Caddyfile can read
+    pub shared: BTreeSet<String>,
+}
+
+/// Decides which env vars the templates of an app can read
+#[derive(Debug, Default, Clone)]
+pub struct EnvScope {
+    /// Prefixes of the env vars generated for each app, like APP_LND_TOOLS_, longest first
+    app_prefixes: Vec<(String, String)>,
+    shared: BTreeSet<String>,
+}
+
+impl EnvScope {
+    pub fn new<'a>(app_ids: impl IntoIterator<Item = &'a str>, config: &TemplateEnvConfig) -> Self {
+        let mut app_prefixes: Vec<(String, String)> = app_ids
+            .into_iter()
+            .map(|app_id| (naming::env_prefix(app_id), app_id.to_string()))
+            .collect();
+        app_prefixes.sort_by(|(a, _), (b, _)| b.len().cmp(&a.len()).then_with(|| a.cmp(b)));
+        Self {
+            app_prefixes,
+            shared: config.shared.clone(),
+        }
+    }
+
+    /// The app an env var was generated for, APP_LND_TOOLS_IP belongs to lnd-tools, not lnd
+    pub(super) fn owner(&self, key: &str) -> Option<&str> {
+        self.app_prefixes
+            .iter()
+            .find(|(prefix, _)| key.starts_with(prefix.as_str()))
+            .map(|(_, app_id)| app_id.as_str())
+    }
+
+    /// Whether the templates of an app can read an env var.
+    /// Without permissions (while its app.yml is rendered), only vars every app can read are allowed.
+    pub fn allows(&self, app_id: &str, key: &str, permissions: Option<&[&String]>) -> bool {
+        if self.shared.contains(key) || key.starts_with(bundles::ENV_PREFIX) {
+            return true;
+        }
+        if ALWAYS_ALLOWED_ENV_VARS.contains(&key) {
+            return true;
+        }
+        let Some(permissions) = permissions else {
+            return false;
+        };
+        if !is_allowed_by_permissions(app_id, key, permissions) {
+            return false;
+        }
+        self.owner(key).is_none_or(|owner| {
+            owner == app_id || permissions.iter().any(|permission| *permission == owner)
+        })
+    }
+}
+
+/// The env vars the Caddyfile template can read, secrets are only passed if they are shared
+pub fn for_caddyfile(
+    env_vars: impl IntoIterator<Item = (String, String)>,
+    redactor: &Redactor,
+    config: &TemplateEnvConfig,
+) -> HashMap<String, String> {
+    env_vars
+        .into_iter()
+        .filter(|(key, _)| !redactor.is_secret(key) || config.shared.contains(key))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{for_caddyfile, EnvScope, TemplateEnvConfig};
+    use crate::cli::redaction::{RedactionConfig, Redactor};
+
+    #[test]
+    fn limits_templates_to_their_apps_vars() {
+        let config = TemplateEnvConfig {
+            shared: [
+                "DEVICE_HOSTNAME".to_string(),
+                "BITCOIN_RPC_PASS".to_string(),
+            ]
+            .into(),
+        };
+        let scope = EnvScope::new(["lnd", "lnd-tools", "mempool"], &config);
+        let lnd = "lnd".to_string();
+        let permissions = [&lnd];
+
+        assert!(scope.allows("lnd", "APP_LND_SERVICE_IP", Some(&[])));
+        // Matches APP_LND_ too, but belongs to lnd-tools
+        assert!(!scope.allows("lnd", "APP_LND_TOOLS_SECRET", Some(&[])));
+        assert!(scope.allows("lnd-tools", "APP_LND_TOOLS_SECRET", Some(&[])));
+        assert!(scope.allows("mempool", "APP_LND_SERVICE_IP", Some(&permissions)));
+        assert!(!scope.allows("mempool", "APP_LND_TOOLS_SECRET", Some(&permissions)));
+        assert!(scope.allows("mempool", "DEVICE_HOSTNAME", Some(&[])));
+        // app.yml templates can only read what every app can read
+        assert!(!scope.allows("lnd", "APP_LND_SERVICE_IP", None));
+        assert!(scope.allows("lnd", "BITCOIN_NETWORK", None));
+        assert!(scope.allows("lnd", "DEVICE_HOSTNAME", None));
+
+        let redactor = Redactor::new(&RedactionConfig::default());
+        let caddyfile_env = for_caddyfile(
+            [
+                ("APP_LND_SERVICE_IP".to_string(), "10.21.22.3".to_string()),
+                ("APP_LND_TOOLS_SECRET".to_string(), "abc".to_string()),
+                ("BITCOIN_RPC_PASS".to_string(), "def".to_string()),
+            ],
+            &redactor,
+            &config,
+        );
+        let mut keys: Vec<&String> = caddyfile_env.keys().collect();
+        keys.sort();
+        assert_eq!(keys, vec!["APP_LND_SERVICE_IP", "BITCOIN_RPC_PASS"]);
+    }
+}
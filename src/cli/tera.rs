@@ -1,25 +1,36 @@
 use std::{
-    collections::HashMap,
-    io::{Read, Write},
-    path::Path,
+    collections::{BTreeMap, HashMap},
+    io::Read,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, OnceLock},
 };
 
 use rand::RngCore;
-use tera::{renderer::processor::Processor, Tera};
+use tera::{renderer::processor::Processor, Template, Tera};
 
+use super::{
+    features::FeatureFlags,
+    host_facts::HostFacts,
+    i2p,
+    limits::{run_with_timeout, ConversionLimits},
+    metrics,
+    template_env::EnvScope,
+};
 use crate::{
     composegenerator::{
-        load_config_as_v4,
+        load_definition_file_with,
         v4::{
-            permissions::{is_allowed_by_permissions, ALWAYS_ALLOWED_ENV_VARS},
+            permissions::ALWAYS_ALLOWED_ENV_VARS,
+            types::HiddenServices,
             utils::{derive_entropy, get_main_container},
         },
     },
     constants::NO_SEED_FOUND_FALLBACK_MSG,
+    naming,
     utils::flatten,
 };
 
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
 use sha1::Digest;
 
 // Creates a S2K hash like used by Tor
@@ -46,6 +57,132 @@ fn tor_hash(input: &str, salt: [u8; 8]) -> String {
     )
 }
 
+/// Compiled templates by the hash of their source, shared by all apps.
+/// Apps often contain identical templates, and as the key is the source, changed files are compiled again.
+static TEMPLATE_CACHE: OnceLock<Mutex<HashMap<[u8; 32], Template>>> = OnceLock::new();
+
+/// Adds a template to a Tera instance, compiling it only if the same source has not been compiled before
+fn add_cached_template(tera: &mut Tera, name: &str, source: &str) -> tera::Result<()> {
+    let cache = TEMPLATE_CACHE.get_or_init(Default::default);
+    let key = hmac_sha256::Hash::hash(source.as_bytes());
+    let cached = cache.lock().unwrap().get(&key).cloned();
+    metrics::record_template_lookup(cached.is_some());
+    let mut template = match cached {
+        Some(template) => template,
+        None => {
+            let template = Template::new(name, None, source)
+                .map_err(|e| tera::Error::chain(format!("Failed to parse '{name}'"), e))?;
+            cache.lock().unwrap().insert(key, template.clone());
+            template
+        }
+    };
+    template.name = name.to_string();
+    tera.templates.insert(name.to_string(), template);
+    tera.build_inheritance_chains()
+}
+
+const TEMPLATE_NAME: &str = "__citadel_template";
+
+/// Like `Tera::render_str`, but uses the template cache
+pub fn render_cached(
+    tera: &mut Tera,
+    source: &str,
+    context: &tera::Context,
+) -> tera::Result<String> {
+    add_cached_template(tera, TEMPLATE_NAME, source)?;
+    let result = tera.render(TEMPLATE_NAME, context);
+    tera.templates.remove(TEMPLATE_NAME);
+    result
+}
+
+/// Like `render_cached`, but rendering stops as soon as the output exceeds the size limit
+fn render_cached_limited(
+    tera: &mut Tera,
+    source: &str,
+    context: &tera::Context,
+    limits: &ConversionLimits,
+) -> Result<String> {
+    add_cached_template(tera, TEMPLATE_NAME, source)?;
+    let mut output = limits.output();
+    let result = tera.render_to(TEMPLATE_NAME, context, &mut output);
+    tera.templates.remove(TEMPLATE_NAME);
+    result?;
+    output.into_string()
+}
+
+/// The env vars templates can read: the node's, which are the same for all apps,
+/// and the app's own, like its settings, which take precedence
+#[derive(Debug, Clone, Default)]
+pub struct TemplateVars {
+    pub node: Arc<HashMap<String, String>>,
+    pub app: HashMap<String, String>,
+}
+
+impl TemplateVars {
+    pub fn get(&self, key: &str) -> Option<&String> {
+        self.app.get(key).or_else(|| self.node.get(key))
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (&String, &String)> {
+        self.node
+            .iter()
+            .filter(|(key, _)| !self.app.contains_key(*key))
+            .chain(&self.app)
+    }
+}
+
+/// Rendered files and where to write them
+pub type RenderedFiles = Vec<(PathBuf, String)>;
+
+pub fn write_rendered_files(files: RenderedFiles) -> Result<()> {
+    for (path, contents) in files {
+        std::fs::write(&path, contents)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// The part of the template context that is the same for all apps, built once per conversion
+pub fn shared_context(
+    services: &[String],
+    features: &FeatureFlags,
+    host: &HostFacts,
+) -> tera::Context {
+    let mut context = tera::Context::new();
+    context.insert("services", services);
+    features.insert_into(&mut context);
+    host.insert_into(&mut context);
+    context
+}
+
+/// Hidden service directory name -> onion address, None if Tor has not created its data dir yet.
+/// Loaded once per conversion instead of listing the Tor data dir for every app.
+pub type TorHostnames = Option<Arc<BTreeMap<String, String>>>;
+
+pub fn load_tor_hostnames(tor_dir: &Path) -> Result<TorHostnames> {
+    if !tor_dir.is_dir() {
+        return Ok(None);
+    }
+    let mut hostnames = BTreeMap::new();
+    for dir in std::fs::read_dir(tor_dir)? {
+        let Ok(dir) = dir else {
+            continue;
+        };
+        let path = dir.path();
+        if !path.is_dir() {
+            continue;
+        }
+        let hostname_file = path.join("hostname");
+        let hostname = if hostname_file.exists() {
+            std::fs::read_to_string(hostname_file)?.trim().to_string()
+        } else {
+            String::new()
+        };
+        hostnames.insert(dir.file_name().to_string_lossy().to_string(), hostname);
+    }
+    Ok(Some(Arc::new(hostnames)))
+}
+
 fn random_hex_string(len: usize) -> String {
     let mut rng = rand::thread_rng();
     let mut bytes = vec![0u8; len];
@@ -53,34 +190,61 @@ fn random_hex_string(len: usize) -> String {
     hex::encode(bytes)
 }
 
+/// Renders app.yml.jinja in app_path (if it exists) to app.yml in output_dir.
+/// Rendering is aborted if it exceeds the limits, app.yml is only written if it finished in time.
+#[allow(clippy::too_many_arguments)]
 pub fn convert_app_yml(
     app_path: &Path,
-    services: &[String],
-    env_vars: &HashMap<String, String>,
+    output_dir: &Path,
+    shared_context: &tera::Context,
+    env_vars: &TemplateVars,
+    scope: &EnvScope,
     citadel_seed: &Option<String>,
+    limits: &ConversionLimits,
 ) -> Result<()> {
     let app_yml_jinja = app_path.to_path_buf().join("app.yml.jinja");
     if app_yml_jinja.exists() {
-        convert_app_yml_internal(
-            &app_yml_jinja,
-            app_path.file_name().unwrap().to_str().unwrap(),
-            services,
-            env_vars,
-            citadel_seed.to_owned(),
+        let app_id = app_path.file_name().unwrap().to_str().unwrap().to_string();
+        let jinja_file = app_yml_jinja.clone();
+        let context = shared_context.clone();
+        let env_vars = env_vars.clone();
+        let scope = scope.clone();
+        let citadel_seed = citadel_seed.to_owned();
+        let task_app_id = app_id.clone();
+        let task_limits = limits.clone();
+        let app_yml = run_with_timeout(
+            &format!("Rendering {}", app_yml_jinja.display()),
+            &app_id,
+            limits.timeout(),
+            move || {
+                // We can't know the permissions at this stage, so only the env vars all apps can read are passed
+                let env_vars: Vec<(&String, &String)> = env_vars
+                    .iter()
+                    .filter(|(key, _)| scope.allows(&task_app_id, key, None))
+                    .collect();
+                render_app_yml(
+                    &jinja_file,
+                    &task_app_id,
+                    context,
+                    &env_vars,
+                    citadel_seed,
+                    &task_limits,
+                )
+            },
         )?;
+        std::fs::write(output_dir.join("app.yml"), app_yml)?;
     }
     Ok(())
 }
 
-fn convert_app_yml_internal(
+fn render_app_yml(
     jinja_file: &Path,
     app_id: &str,
-    services: &[String],
-    env_vars: &HashMap<String, String>,
+    mut context: tera::Context,
+    env_vars: &[(&String, &String)],
     citadel_seed: Option<String>,
-) -> Result<()> {
-    let mut context = tera::Context::new();
-    context.insert("services", services);
+    limits: &ConversionLimits,
+) -> Result<String> {
     context.insert("app_name", app_id);
     let mut tmpl = String::new();
     std::fs::File::open(jinja_file)?.read_to_string(&mut tmpl)?;
@@ -88,10 +252,7 @@ fn convert_app_yml_internal(
     let app_id = app_id.to_string();
     let app_id_clone = app_id.clone();
     for (key, val) in env_vars {
-        // We can't know the permissions at this stage, so we only allow the env vars here that are always allowed
-        if ALWAYS_ALLOWED_ENV_VARS.contains(&key.as_str()) {
-            context.insert(key, &val);
-        }
+        context.insert(key.as_str(), val);
     }
     tera.register_function(
         "derive_entropy",
@@ -112,7 +273,7 @@ fn convert_app_yml_internal(
             if let Some(citadel_seed) = &citadel_seed {
                 Ok(tera::to_value(derive_entropy(
                     citadel_seed,
-                    format!("app-{}-{}", app_id.replace('-', "_"), identifier).as_str(),
+                    &naming::entropy_identifier(&app_id, identifier),
                 ))
                 .expect("Failed to serialize value"))
             } else {
@@ -126,8 +287,8 @@ fn convert_app_yml_internal(
          _args: &HashMap<String, tera::Value>|
          -> Result<tera::Value, tera::Error> {
             let Some(input) = val.as_str() else {
-            return Err(tera::Error::msg("Identifier must be a string"));
-        };
+                return Err(tera::Error::msg("Identifier must be a string"));
+            };
             let mut salt = [0u8; 8];
             rand::thread_rng().fill_bytes(&mut salt);
             Ok(tera::to_value(tor_hash(input, salt)).expect("Failed to serialize value"))
@@ -145,9 +306,13 @@ fn convert_app_yml_internal(
             Ok(tera::to_value(random_hex_string(len as usize)).expect("Failed to serialize value"))
         },
     );
-    let tmpl_result = tera.render_str(tmpl.as_str(), &context);
+    let tmpl_result = render_cached_limited(&mut tera, &tmpl, &context, limits);
     if let Err(e) = tmpl_result {
-        bail!("Error processing template {}: {}", jinja_file.display(), e);
+        bail!(
+            "Error processing template {}: {:#}",
+            jinja_file.display(),
+            e
+        );
     }
     let tmpl_result = tmpl_result.unwrap();
     if tmpl_result.contains(NO_SEED_FOUND_FALLBACK_MSG) {
@@ -156,9 +321,7 @@ fn convert_app_yml_internal(
             app_id_clone
         );
     }
-    let mut writer = std::fs::File::create(jinja_file.to_path_buf().with_extension(""))?;
-    writer.write_all(tmpl_result.as_bytes())?;
-    Ok(())
+    Ok(tmpl_result)
 }
 
 pub fn convert_app_yml_for_update(jinja_file: &Path, app_id: &str) -> Result<String> {
@@ -183,8 +346,8 @@ pub fn convert_app_yml_for_update(jinja_file: &Path, app_id: &str) -> Result<Str
          _args: &HashMap<String, tera::Value>|
          -> Result<tera::Value, tera::Error> {
             let Some(input) = val.as_str() else {
-            return Err(tera::Error::msg("Identifier must be a string"));
-        };
+                return Err(tera::Error::msg("Identifier must be a string"));
+            };
             let mut salt = [0u8; 8];
             rand::thread_rng().fill_bytes(&mut salt);
             Ok(tera::to_value(tor_hash(input, salt)).expect("Failed to serialize value"))
@@ -202,7 +365,7 @@ pub fn convert_app_yml_for_update(jinja_file: &Path, app_id: &str) -> Result<Str
             Ok(tera::to_value(random_hex_string(len as usize)).expect("Failed to serialize value"))
         },
     );
-    let tmpl_result = tera.render_str(tmpl.as_str(), &context);
+    let tmpl_result = render_cached(&mut tera, &tmpl, &context);
     if let Err(e) = tmpl_result {
         bail!("Error processing template {}: {}", jinja_file.display(), e);
     }
@@ -214,18 +377,20 @@ fn generate_tera(
     app_id: &str,
     app_version: &str,
     permissions: &[&String],
-    services: &[String],
+    shared_context: &tera::Context,
     services_with_hs: &[&String],
-    env_vars: &HashMap<String, String>,
+    env_vars: &TemplateVars,
+    scope: &EnvScope,
     citadel_seed: Option<String>,
-    tor_dir: &Path,
+    tor_hostnames: &TorHostnames,
+    i2p_destinations: &[Option<&String>],
+    i2p_dir: &Path,
 ) -> Result<(Tera, tera::Context)> {
-    let mut context = tera::Context::new();
-    context.insert("services", &services);
+    let mut context = shared_context.clone();
     context.insert("app_name", app_id);
 
-    for (key, val) in env_vars {
-        if is_allowed_by_permissions(app_id, key, permissions) {
+    for (key, val) in env_vars.iter() {
+        if scope.allows(app_id, key, Some(permissions)) {
             context.insert(key, &val);
         }
     }
@@ -248,58 +413,42 @@ fn generate_tera(
     }
     context.insert("APP_VERSION", app_version);
 
-    if tor_dir.is_dir() {
-        let subdirs = std::fs::read_dir(tor_dir)?.filter_map(|dir| {
-            if let Ok(dir) = dir {
-                let path = dir.path();
-                if path.is_dir() {
-                    Some(path)
-                } else {
-                    None
+    if let Some(tor_hostnames) = tor_hostnames {
+        let app_name = naming::hidden_service_name(app_id, None);
+        let app_prefix = format!("{app_name}-");
+        // The app's own directory and those of its containers sort next to each other
+        for (dir_name, hostname) in tor_hostnames.range(app_name.clone()..) {
+            if *dir_name != app_name && !dir_name.starts_with(&app_prefix) {
+                if *dir_name > app_prefix {
+                    break;
                 }
-            } else {
-                None
-            }
-        });
-        let app_name = format!("app-{app_id}");
-        let app_prefix = format!("app-{app_id}-");
-        for dir in subdirs {
-            let dir_name = dir.file_name().unwrap().to_str().unwrap();
-            if dir_name != app_name && !dir_name.starts_with(&app_prefix) {
                 continue;
             }
-            let hostname_file = dir.join("hostname");
-            let mut hostname = String::with_capacity(62);
-            if hostname_file.exists() {
-                let mut hostname_file = std::fs::File::open(hostname_file)?;
-                hostname_file.read_to_string(&mut hostname)?;
-            }
             context.insert(
-                if dir_name == app_name {
-                    "APP_HIDDEN_SERVICE".to_string()
-                } else {
-                    format!(
-                        "APP_HIDDEN_SERVICE_{}",
-                        &dir_name[app_prefix.len()..]
-                            .to_uppercase()
-                            .replace('-', "_")
-                    )
-                },
-                &hostname.trim(),
+                naming::hidden_service_env_var(
+                    (*dir_name != app_name).then(|| &dir_name[app_prefix.len()..]),
+                ),
+                hostname,
             );
         }
     } else {
         context.insert("APP_HIDDEN_SERVICE", "notyetgenerated.onion");
     }
     for service in services_with_hs {
-        let key = format!(
-            "APP_HIDDEN_SERVICE_{}",
-            service.to_uppercase().replace('-', "_")
-        );
+        let key = naming::hidden_service_env_var(Some(service));
         if context.get(&key).is_none() {
             context.insert(key, "notyetgenerated.onion");
         }
     }
+    for (key, address) in i2p::app_addresses(app_id, i2p_dir)? {
+        context.insert(key, &address);
+    }
+    for destination in i2p_destinations {
+        let key = i2p::env_var(app_id, destination.map(String::as_str));
+        if context.get(&key).is_none() {
+            context.insert(key, "notyetgenerated.b32.i2p");
+        }
+    }
     let mut tera = Tera::default();
     let app_id = app_id.to_string();
     tera.register_function(
@@ -321,7 +470,7 @@ fn generate_tera(
             if let Some(citadel_seed) = &citadel_seed {
                 Ok(tera::to_value(derive_entropy(
                     citadel_seed,
-                    format!("app-{}-{}", app_id.replace('-', "_"), identifier).as_str(),
+                    &naming::entropy_identifier(&app_id, identifier),
                 ))
                 .expect("Failed to serialize value"))
             } else {
@@ -335,8 +484,8 @@ fn generate_tera(
          _args: &HashMap<String, tera::Value>|
          -> Result<tera::Value, tera::Error> {
             let Some(input) = val.as_str() else {
-            return Err(tera::Error::msg("Identifier must be a string"));
-        };
+                return Err(tera::Error::msg("Identifier must be a string"));
+            };
             let mut salt = [0u8; 8];
             rand::thread_rng().fill_bytes(&mut salt);
             Ok(tera::to_value(tor_hash(input, salt)).expect("Failed to serialize value"))
@@ -357,37 +506,57 @@ fn generate_tera(
     Ok((tera, context))
 }
 
-pub fn convert_app_config_files(
+/// Renders all other jinja files in app_path, returns the results and where to write them in output_dir.
+/// Rendering a file stops as soon as it exceeds the size limit.
+#[allow(clippy::too_many_arguments)]
+pub fn render_app_config_files(
     app_path: &Path,
+    output_dir: &Path,
     services: &[String],
+    shared_context: &tera::Context,
     citadel_seed: &Option<String>,
-    env_vars: &Option<HashMap<String, String>>,
-    tor_dir: &Path,
-) -> Result<()> {
+    env_vars: Option<&TemplateVars>,
+    scope: &EnvScope,
+    tor_hostnames: &TorHostnames,
+    i2p_dir: &Path,
+    limits: &ConversionLimits,
+) -> Result<RenderedFiles> {
+    let mut rendered = RenderedFiles::new();
+    let other_jinja_files: Vec<_> = std::fs::read_dir(app_path)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().unwrap_or_default() == "jinja")
+        .collect();
+    // Apps without templates don't need their app.yml to be loaded again
+    if other_jinja_files.is_empty() {
+        return Ok(rendered);
+    }
     if let Some(env_vars) = env_vars {
-        let app_yml = app_path.join("app.yml");
-        if !app_yml.exists() {
+        // A generated app.yml is stored in the output dir, the files it includes in the app dir
+        let read_path = |path: &Path| {
+            path.strip_prefix(app_path)
+                .ok()
+                .map(|relative| output_dir.join(relative))
+                .filter(|output_path| output_path.exists())
+                .unwrap_or_else(|| path.to_path_buf())
+        };
+        let app_yml_path = read_path(&app_path.join("app.yml"));
+        if !app_yml_path.exists() {
             bail!("app.yml not found in {}", app_path.display());
         }
-        let app_yml = std::fs::File::open(app_yml)?;
-        let app_yml = load_config_as_v4(app_yml, &Some(&services.to_vec()));
+        let app_yml =
+            load_definition_file_with(&app_path.join("app.yml"), Some(services), &read_path);
         if let Err(e) = app_yml {
-            bail!(
-                "Error processing app.yml {}: {}",
-                app_path.join("app.yml").display(),
-                e
-            );
+            bail!("Error processing app.yml {}: {}", app_yml_path.display(), e);
         }
         let app_yml = app_yml.unwrap();
         let app_version = app_yml.metadata.version;
         let perms = flatten(&app_yml.metadata.permissions);
 
-        let other_jinja_files = std::fs::read_dir(app_path)?
-            .filter_map(|entry| entry.ok())
-            .filter(|entry| entry.path().extension().unwrap_or_default() == "jinja")
-            .map(|entry| entry.path());
-
-        let main_container = get_main_container(&app_yml.services)?;
+        let main_container = get_main_container(
+            &app_yml.services,
+            app_yml.metadata.main_container.as_deref(),
+        )?;
         let services_with_hs = app_yml.services.iter().filter_map(|(name, service)| {
             if name == main_container || service.hidden_services.is_none() {
                 None
@@ -408,20 +577,40 @@ pub fn convert_app_config_files(
             }
         }
 
+        let mut i2p_destinations = Vec::new();
+        if app_yml.metadata.i2p {
+            i2p_destinations.push(None);
+        }
+        for (container, service) in &app_yml.services {
+            match &service.i2p_tunnels {
+                Some(HiddenServices::PortMap(_)) if container == main_container => {
+                    i2p_destinations.push(None)
+                }
+                Some(HiddenServices::PortMap(_)) => i2p_destinations.push(Some(container)),
+                Some(HiddenServices::LayeredMap(map)) => {
+                    i2p_destinations.extend(map.keys().map(Some))
+                }
+                None => {}
+            }
+        }
+
         let (mut tera, mut context) = generate_tera(
             app_path.file_name().unwrap().to_str().unwrap(),
             &app_version,
             &perms,
-            services,
+            shared_context,
             &existing_hs,
             env_vars,
+            scope,
             citadel_seed.to_owned(),
-            tor_dir,
+            tor_hostnames,
+            &i2p_destinations,
+            i2p_dir,
         )?;
 
         // Sort other_jinja_files alphabetically so that we can process them in a deterministic order
         // But files called _vars.jinja must be processed first
-        let mut other_jinja_files: Vec<_> = other_jinja_files.collect();
+        let mut other_jinja_files = other_jinja_files;
         other_jinja_files.sort();
         other_jinja_files.sort_by_key(|path| {
             if path.file_name().unwrap() == "_vars.jinja" {
@@ -435,8 +624,8 @@ pub fn convert_app_config_files(
                 let mut file = std::fs::File::open(&jinja_file)?;
                 let mut tmpl = String::new();
                 file.read_to_string(&mut tmpl)?;
-                tera.add_raw_template("_vars", &tmpl)?;
-                let mut output = Vec::with_capacity(2000);
+                add_cached_template(&mut tera, "_vars", &tmpl)?;
+                let mut output = limits.output();
                 let tmpl = tera.get_template("_vars")?;
                 let mut processor = Processor::new(tmpl, &tera, &context, false);
                 processor.render(&mut output)?;
@@ -446,13 +635,18 @@ pub fn convert_app_config_files(
                     context.insert(key, &value);
                 }
             } else {
-                let output_file = jinja_file.with_extension("");
+                let output_file =
+                    output_dir.join(jinja_file.with_extension("").file_name().unwrap());
                 let mut file = std::fs::File::open(&jinja_file)?;
                 let mut tmpl = String::new();
                 file.read_to_string(&mut tmpl)?;
-                let tmpl_result = tera.render_str(tmpl.as_str(), &context);
+                let tmpl_result = render_cached_limited(&mut tera, &tmpl, &context, limits);
                 if let Err(e) = tmpl_result {
-                    bail!("Error processing template {}: {}", jinja_file.display(), e);
+                    bail!(
+                        "Error processing template {}: {:#}",
+                        jinja_file.display(),
+                        e
+                    );
                 }
                 let tmpl_result = tmpl_result.unwrap();
                 if tmpl_result.contains(NO_SEED_FOUND_FALLBACK_MSG) {
@@ -461,18 +655,19 @@ pub fn convert_app_config_files(
                         app_path.file_name().unwrap().to_str().unwrap()
                     );
                 }
-                let mut writer = std::fs::File::create(output_file)?;
-                writer.write_all(tmpl_result.as_bytes())?;
+                rendered.push((output_file, tmpl_result));
             }
         }
     }
 
-    Ok(())
+    Ok(rendered)
 }
 
 #[cfg(test)]
 mod test {
-    use super::tor_hash;
+    use std::path::Path;
+
+    use super::{generate_tera, load_tor_hostnames, render_cached, tor_hash, TEMPLATE_CACHE};
 
     #[test]
     fn hash_matches_tor() {
@@ -481,4 +676,61 @@ mod test {
             "16:3E6BF3DCEC50FE5160DBD0C3A9132DB0118AFA5104FE8DA29ADC20A65E"
         );
     }
+
+    #[test]
+    fn reuses_compiled_templates() {
+        let source = "{{ app_name }} uses the template cache";
+        let key = hmac_sha256::Hash::hash(source.as_bytes());
+        let mut context = tera::Context::new();
+        for app_name in ["lnd", "btcpay"] {
+            context.insert("app_name", app_name);
+            let mut tera = tera::Tera::default();
+            assert_eq!(
+                render_cached(&mut tera, source, &context).unwrap(),
+                format!("{app_name} uses the template cache")
+            );
+            assert!(TEMPLATE_CACHE
+                .get()
+                .unwrap()
+                .lock()
+                .unwrap()
+                .contains_key(&key));
+        }
+        let mut tera = tera::Tera::default();
+        assert!(render_cached(&mut tera, "{{ unclosed", &context).is_err());
+    }
+
+    #[test]
+    fn finds_hidden_services_of_app() {
+        let tor_dir = tempdir::TempDir::new("tor").unwrap();
+        for (dir, hostname) in [
+            ("app-lnd", "lnd.onion\n"),
+            ("app-lnd-rest", "rest.onion"),
+            ("app-lnd-tools", "tools.onion"),
+            ("app-lndhub", "lndhub.onion"),
+        ] {
+            std::fs::create_dir(tor_dir.path().join(dir)).unwrap();
+            std::fs::write(tor_dir.path().join(dir).join("hostname"), hostname).unwrap();
+        }
+        let tor_hostnames = load_tor_hostnames(tor_dir.path()).unwrap();
+        let (_, context) = generate_tera(
+            "lnd",
+            "1.0.0",
+            &[],
+            &tera::Context::new(),
+            &[],
+            &Default::default(),
+            &Default::default(),
+            None,
+            &tor_hostnames,
+            &[],
+            Path::new("/nonexistent"),
+        )
+        .unwrap();
+        let value = |key: &str| context.get(key).and_then(|value| value.as_str());
+        assert_eq!(value("APP_HIDDEN_SERVICE"), Some("lnd.onion"));
+        assert_eq!(value("APP_HIDDEN_SERVICE_REST"), Some("rest.onion"));
+        assert_eq!(value("APP_HIDDEN_SERVICE_TOOLS"), Some("tools.onion"));
+        assert_eq!(context.get("APP_HIDDEN_SERVICE_LNDHUB"), None);
+    }
 }
@@ -0,0 +1,230 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::io::IsTerminal;
+
+use super::{metrics::ConversionReport, port_review};
+
+/// When to color the output
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ColorChoice {
+    /// Only if stdout is a terminal and NO_COLOR is not set
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+/// How much is printed, set with --quiet or --verbose
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Verbosity {
+    /// Only errors and the summary
+    Quiet,
+    #[default]
+    Normal,
+    /// Debug logs and the time spent on each stage
+    Verbose,
+}
+
+impl Verbosity {
+    pub fn new(quiet: bool, verbose: bool) -> Self {
+        match (quiet, verbose) {
+            (true, _) => Verbosity::Quiet,
+            (false, true) => Verbosity::Verbose,
+            (false, false) => Verbosity::Normal,
+        }
+    }
+
+    /// The most detailed level logged to stderr
+    pub fn log_level(self) -> tracing::Level {
+        match self {
+            Verbosity::Quiet => tracing::Level::ERROR,
+            Verbosity::Normal => tracing::Level::INFO,
+            Verbosity::Verbose => tracing::Level::DEBUG,
+        }
+    }
+}
+
+/// Colors text with ANSI escape codes, or leaves it as it is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Style {
+    color: bool,
+}
+
+impl Style {
+    pub fn new(choice: ColorChoice) -> Self {
+        let color = match choice {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => {
+                std::env::var_os("NO_COLOR").is_none() && std::io::stdout().is_terminal()
+            }
+        };
+        Self { color }
+    }
+
+    fn paint(&self, text: &str, code: &str) -> String {
+        if self.color {
+            format!("\x1b[{code}m{text}\x1b[0m")
+        } else {
+            text.to_string()
+        }
+    }
+
+    pub fn bold(&self, text: &str) -> String {
+        self.paint(text, "1")
+    }
+
+    pub fn green(&self, text: &str) -> String {
+        self.paint(text, "32")
+    }
+
+    pub fn yellow(&self, text: &str) -> String {
+        self.paint(text, "33")
+    }
+
+    pub fn red(&self, text: &str) -> String {
+        self.paint(text, "31")
+    }
+}
+
+/// What happened to an app during a conversion
+enum Outcome<'a> {
+    Converted(Option<u64>),
+    Skipped(&'a str),
+    Failed(&'a str, &'a str),
+}
+
+/// The outcome of every app, an app that failed in a later stage counts as failed
+fn outcomes(report: &ConversionReport) -> BTreeMap<&str, Outcome<'_>> {
+    let mut outcomes = BTreeMap::new();
+    for app_id in &report.converted {
+        outcomes.insert(
+            app_id.as_str(),
+            Outcome::Converted(report.apps.get(app_id).copied()),
+        );
+    }
+    for (app_id, reason) in &report.skipped {
+        outcomes.insert(app_id.as_str(), Outcome::Skipped(reason));
+    }
+    for failure in &report.failures {
+        // Keep the first stage an app failed in
+        if !matches!(
+            outcomes.get(failure.app.as_str()),
+            Some(Outcome::Failed(..))
+        ) {
+            outcomes.insert(
+                failure.app.as_str(),
+                Outcome::Failed(&failure.stage, &failure.error),
+            );
+        }
+    }
+    outcomes
+}
+
+/// Formats a conversion for operators: a status line per app, followed by a summary table
+pub fn format_conversion(report: &ConversionReport, verbosity: Verbosity, style: Style) -> String {
+    let outcomes = outcomes(report);
+    let mut output = String::new();
+    let (mut converted, mut skipped, mut failed) = (0, 0, 0);
+    for (app_id, outcome) in &outcomes {
+        let line = match outcome {
+            Outcome::Converted(millis) => {
+                converted += 1;
+                let time = match (verbosity, millis) {
+                    (Verbosity::Verbose, Some(millis)) => format!(" ({millis} ms)"),
+                    _ => String::new(),
+                };
+                format!("{} {app_id}{time}", style.green("ok     "))
+            }
+            Outcome::Skipped(reason) => {
+                skipped += 1;
+                format!("{} {app_id}: {reason}", style.yellow("skipped"))
+            }
+            Outcome::Failed(stage, error) => {
+                failed += 1;
+                format!("{} {app_id} ({stage}): {error}", style.red("failed "))
+            }
+        };
+        if verbosity != Verbosity::Quiet || matches!(outcome, Outcome::Failed(..)) {
+            let _ = writeln!(output, "{line}");
+        }
+    }
+    if verbosity == Verbosity::Verbose {
+        let _ = writeln!(output);
+        for stage in &report.stages {
+            let _ = writeln!(output, "{:<16} {:>8} ms", stage.name, stage.millis);
+        }
+    }
+    if !report.port_changes.is_empty() && verbosity != Verbosity::Quiet {
+        let _ = write!(output, "\n{}", port_review::report(&report.port_changes));
+    }
+
+    let _ = writeln!(output);
+    let count = |count: usize, color: fn(&Style, &str) -> String| {
+        let text = format!("{count:>6}");
+        if count == 0 {
+            text
+        } else {
+            color(&style, &text)
+        }
+    };
+    let _ = writeln!(output, "{}", style.bold("Summary"));
+    let _ = writeln!(output, "  Converted     {}", count(converted, Style::green));
+    let _ = writeln!(output, "  Skipped       {}", count(skipped, Style::yellow));
+    let _ = writeln!(output, "  Failed        {}", count(failed, Style::red));
+    let _ = writeln!(
+        output,
+        "  Ports changed {}",
+        count(report.port_changes.len(), Style::yellow)
+    );
+    let _ = writeln!(output, "  Took          {:>6} ms", report.total_millis);
+    output
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use super::{format_conversion, ColorChoice, Style, Verbosity};
+    use crate::cli::metrics::{AppFailure, CacheStats, ConversionReport};
+
+    #[test]
+    fn formats_conversion_summary() {
+        let report = ConversionReport {
+            total_millis: 120,
+            stages: Vec::new(),
+            apps: BTreeMap::from([("lnd".to_string(), 30), ("mempool".to_string(), 12)]),
+            template_cache: CacheStats::default(),
+            bytes_written: 0,
+            converted: vec!["lnd".to_string(), "mempool".to_string()],
+            skipped: BTreeMap::from([("electrs".to_string(), "filtered out".to_string())]),
+            failures: vec![AppFailure {
+                app: "mempool".to_string(),
+                stage: "config_files".to_string(),
+                error: "Template not found".to_string(),
+            }],
+            port_changes: Vec::new(),
+        };
+        let style = Style::new(ColorChoice::Never);
+        assert_eq!(
+            format_conversion(&report, Verbosity::Normal, style),
+            "skipped electrs: filtered out
+ok      lnd
+failed  mempool (config_files): Template not found
+
+Summary
+  Converted          1
+  Skipped            1
+  Failed             1
+  Ports changed      0
+  Took             120 ms
+"
+        );
+        let quiet = format_conversion(&report, Verbosity::Quiet, style);
+        assert!(quiet.starts_with("failed  mempool"));
+
+        let colored =
+            format_conversion(&report, Verbosity::Normal, Style::new(ColorChoice::Always));
+        assert!(colored.contains("\x1b[31mfailed \x1b[0m mempool"));
+    }
+}
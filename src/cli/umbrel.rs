@@ -8,8 +8,12 @@ use std::path::Path;
 use crate::composegenerator::compose::types::ComposeSpecification;
 use crate::composegenerator::umbrel::convert::convert_compose;
 use crate::composegenerator::umbrel::types::Metadata;
+use crate::composegenerator::v4::types::AppYml;
 use crate::conch::lexer::Lexer;
 use crate::conch::parse::DefaultParser;
+use crate::naming;
+
+use super::paths::CitadelPaths;
 
 use lazy_static::lazy_static;
 
@@ -47,9 +51,9 @@ const ALLOWED_KEYS_FOR_UMBREL_APPS: [&str; 19] = [
 ];
 
 /// Takes a directory that contains an Umbrel app and check if it can run on Citadel, if possible, port it to Citadel
-/// The app.yml will be written to the same directory
+/// The app.yml will be written to the same directory, or to its place in the state directory of `paths`
 /// The result will indicate success or failure
-pub fn convert(dir: &Path) -> Result<()> {
+pub fn convert(paths: &CitadelPaths, dir: &Path) -> Result<()> {
     let umbrel_app_yml = std::fs::File::open(dir.join("umbrel-app.yml"))?;
     let umbrel_app_yml = serde_yaml::from_reader(umbrel_app_yml)?;
     let metadata: Metadata = serde_yaml::from_value(umbrel_app_yml)?;
@@ -268,7 +272,10 @@ pub fn convert(dir: &Path) -> Result<()> {
                                                                                                 // This should have the format APP_{APP_NAME}_{SERVICE_NAME}_IP
                                                                                                 // Extract the service name
                                                                                                 // App name is metadata.id.to_uppercase()
-                                                                                                let service_name = var.trim_start_matches(format!("APP_{}_", metadata.id.to_uppercase().replace('-', "_")).as_str()).trim_end_matches("_IP").to_lowercase().replace('_', "-");
+                                                                                                let service_name = naming::slug(
+                                                                                                    var.trim_start_matches(naming::env_prefix(&metadata.id).as_str())
+                                                                                                        .trim_end_matches("_IP"),
+                                                                                                );
                                                                                                 // This difference in names is used by some umbrel apps, including Suredbits
                                                                                                 if !services.contains_key(&service_name) && services.contains_key(&service_name.replace('-', "")) {
                                                                                                     key = key.replace('_', "");
@@ -392,7 +399,7 @@ pub fn convert(dir: &Path) -> Result<()> {
                                                                                                                                                                         match thing {
                                                                                                                                                                             LocalEnvVar::Literal(_) => bail!("Not implemented yet"),
                                                                                                                                                                             LocalEnvVar::TorDataDir(tor_data_dir) => {
-                                                                                                                                                                                env_var_value = Some(format!("$APP_HIDDEN_SERVICE_{}", tor_data_dir.to_uppercase().replace('-', "_")));
+                                                                                                                                                                                env_var_value = Some(format!("${}", naming::hidden_service_env_var(Some(tor_data_dir))));
                                                                                                                                                                             },
                                                                                                                                                                         }
                                                                                                                                                                     } else {
@@ -524,48 +531,33 @@ pub fn convert(dir: &Path) -> Result<()> {
                     // This should have the format APP_{APP_NAME}_{SERVICE_NAME}_IP
                     // Extract the service name
                     // App name is metadata.id.to_uppercase()
-                    let service_name = env_var_name
-                        .trim_start_matches(
-                            format!("APP_{}_", metadata.id.to_uppercase().replace('-', "_"))
-                                .as_str(),
-                        )
-                        .trim_end_matches("_IP")
-                        .to_lowercase()
-                        .replace('_', "-");
-                    let uppercase_id = metadata.id.clone().to_uppercase();
+                    let service_name = naming::slug(
+                        env_var_name
+                            .trim_start_matches(naming::env_prefix(&metadata.id).as_str())
+                            .trim_end_matches("_IP"),
+                    );
                     // This difference in names is not used in practice I think, but I only realized that after implementing this
                     if !services.contains_key(&service_name)
                         && services.contains_key(&service_name.replace('-', ""))
                     {
-                        key = format!(
-                            "APP_{}_{}_IP",
-                            uppercase_id.replace('-', "_"),
-                            service_name.replace('-', "")
-                        );
+                        key = naming::ip_env_var(&metadata.id, &service_name.replace('-', ""));
                     }
                     // A way that is actually used is leaving everything after the first - of the app name out of the app name and env var
-                    let app_name_short = uppercase_id.split('-').next().unwrap();
-                    let alt_service_name = env_var_name
-                        .trim_start_matches(format!("APP_{app_name_short}_").as_str())
-                        .trim_end_matches("_IP")
-                        .to_lowercase()
-                        .replace('_', "-");
+                    let app_name_short = metadata.id.split('-').next().unwrap();
+                    let alt_service_name = naming::slug(
+                        env_var_name
+                            .trim_start_matches(naming::env_prefix(app_name_short).as_str())
+                            .trim_end_matches("_IP"),
+                    );
                     if !services.contains_key(&service_name)
                         && services.contains_key(&alt_service_name)
                     {
-                        key = format!(
-                            "APP_{}_{}_IP",
-                            app_name_short,
-                            alt_service_name.to_uppercase().replace('-', "_")
-                        );
+                        key = naming::ip_env_var(app_name_short, &alt_service_name);
                     } else if !services.contains_key(&service_name)
                         && services.contains_key(&alt_service_name.replace('-', ""))
                     {
-                        key = format!(
-                            "APP_{}_{}_IP",
-                            app_name_short,
-                            alt_service_name.to_uppercase().replace('-', "")
-                        );
+                        key =
+                            naming::ip_env_var(app_name_short, &alt_service_name.replace('-', ""));
                     }
                     env_vars.insert(env_var_name, format!("${{{key}}}"));
                 }
@@ -575,7 +567,7 @@ pub fn convert(dir: &Path) -> Result<()> {
 
     println!("env_vars: {env_vars:#?}");
     let citadel_app_yml = convert_compose(compose_yml, metadata, &env_vars)?;
-    let writer = std::fs::File::create(dir.join("app.yml"))?;
-    serde_yaml::to_writer(writer, &citadel_app_yml)?;
+    let writer = paths.create(&dir.join("app.yml"))?;
+    serde_yaml::to_writer(writer, &AppYml::from(citadel_app_yml))?;
     Ok(())
 }
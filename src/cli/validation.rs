@@ -0,0 +1,134 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, Result};
+use lazy_static::lazy_static;
+use regex::Regex;
+
+use super::stores::AppStoreInfo;
+
+lazy_static! {
+    // Lowercase letters and numbers, separated by single dashes
+    static ref APP_ID_REGEX: Regex = Regex::new(r"^[a-z0-9]+(-[a-z0-9]+)*$").unwrap();
+}
+
+/// App IDs that can't be used because they are used by Citadel itself,
+/// or because the env vars generated for them would collide with built-in ones
+pub const RESERVED_APP_IDS: [&str; 9] = [
+    "bitcoind",
+    "service",
+    "seed",
+    "hidden-service",
+    "domain",
+    "version",
+    "data-dir",
+    "shared-subdir",
+    // Used as mount name for named volumes
+    "volumes",
+];
+
+fn invalid_app_id(app_id: &str) -> String {
+    format!(
+        "App ID {app_id} is invalid, app IDs may only contain lowercase letters, numbers and single dashes"
+    )
+}
+
+/// Checks that an app ID passed by a user is valid before it is used in paths
+pub fn validate_app_id(app_id: &str) -> Result<()> {
+    if !APP_ID_REGEX.is_match(app_id) {
+        bail!(invalid_app_id(app_id));
+    }
+    Ok(())
+}
+
+/// Checks that all app IDs are valid, not reserved and only provided by one store
+pub fn validate_app_ids(app_ids: &[String], stores: &[AppStoreInfo]) -> Result<()> {
+    let mut problems = Vec::new();
+    for app_id in app_ids {
+        if !APP_ID_REGEX.is_match(app_id) {
+            problems.push(invalid_app_id(app_id));
+        } else if RESERVED_APP_IDS.contains(&app_id.as_str()) {
+            problems.push(format!("App ID {app_id} is reserved"));
+        }
+    }
+
+    let mut providers: HashMap<&str, Vec<&str>> = HashMap::new();
+    for store in stores {
+        for app_id in store.apps.keys() {
+            providers.entry(app_id).or_default().push(&store.id);
+        }
+    }
+    let mut duplicates: Vec<_> = providers
+        .into_iter()
+        .filter(|(_, stores)| stores.len() > 1)
+        .collect();
+    duplicates.sort();
+    for (app_id, stores) in duplicates {
+        problems.push(format!(
+            "App {app_id} is provided by multiple stores: {}",
+            stores.join(", ")
+        ));
+    }
+
+    if !problems.is_empty() {
+        bail!(
+            "Found {} problem(s) with app IDs:\n{}",
+            problems.len(),
+            problems.join("\n")
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{validate_app_id, validate_app_ids};
+    use crate::cli::stores::AppStoreInfo;
+    use std::collections::{BTreeMap, HashMap};
+
+    fn store(id: &str, apps: &[&str]) -> AppStoreInfo {
+        AppStoreInfo {
+            id: id.to_string(),
+            name: id.to_string(),
+            tagline: String::new(),
+            icon: String::new(),
+            developers: String::new(),
+            license: String::new(),
+            apps: HashMap::from_iter(apps.iter().map(|app| (app.to_string(), String::new()))),
+            commit: String::new(),
+            repo: String::new(),
+            branch: String::new(),
+            subdir: String::new(),
+            bundles: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn accepts_valid_ids() {
+        let ids = vec!["btcpay-server".to_string(), "lnd".to_string()];
+        let stores = vec![store("a", &["btcpay-server"]), store("b", &["lnd"])];
+        assert!(validate_app_ids(&ids, &stores).is_ok());
+    }
+
+    #[test]
+    fn reports_all_problems() {
+        let ids = vec![
+            "My_App".to_string(),
+            "bitcoind".to_string(),
+            "lnd".to_string(),
+        ];
+        let stores = vec![store("a", &["lnd"]), store("b", &["lnd"])];
+        let err = validate_app_ids(&ids, &stores).unwrap_err().to_string();
+        assert!(err.starts_with("Found 3 problem(s)"));
+        assert!(err.contains("My_App is invalid"));
+        assert!(err.contains("bitcoind is reserved"));
+        assert!(err.contains("lnd is provided by multiple stores"));
+    }
+
+    #[test]
+    fn rejects_paths_as_app_id() {
+        assert!(validate_app_id("btcpay-server").is_ok());
+        for app_id in ["", "..", "../lnd", "lnd/../..", "/etc", "Lnd", "lnd-"] {
+            assert!(validate_app_id(app_id).is_err(), "{app_id}");
+        }
+    }
+}
@@ -0,0 +1,155 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use super::{paths::CitadelPaths, switchover::load_owners, UserJson};
+use crate::{composegenerator::v4::types::PortMapElement, naming};
+
+/// The app currently backing an interface like electrum
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct InterfaceBackend {
+    pub interface: String,
+    pub app: String,
+    /// The IP of the app's service container
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ip: Option<String>,
+    /// The ports of the interface, internal ports are reachable on the IP
+    pub ports: Vec<PortMapElement>,
+}
+
+/// Finds the app backing an interface.
+/// The app recorded as its owner is used, otherwise the first installed implementation.
+fn resolve(
+    interface: &str,
+    owners: &BTreeMap<String, String>,
+    virtual_apps: &HashMap<String, Vec<String>>,
+    installed_apps: &[String],
+    ip_map: &HashMap<String, String>,
+    port_map: &HashMap<String, HashMap<String, Vec<PortMapElement>>>,
+) -> Option<InterfaceBackend> {
+    let app = owners.get(interface).or_else(|| {
+        virtual_apps
+            .get(interface)?
+            .iter()
+            .find(|app| installed_apps.contains(app))
+    })?;
+    let ip_var = naming::ip_env_var(app, "service");
+    Some(InterfaceBackend {
+        interface: interface.to_string(),
+        app: app.clone(),
+        ip: ip_map.get(&ip_var).cloned(),
+        ports: port_map
+            .get(interface)
+            .and_then(|containers| containers.get("service"))
+            .cloned()
+            .unwrap_or_default(),
+    })
+}
+
+/// Answers which app currently backs an interface, None if no installed app implements it
+pub fn which(
+    citadel_root: &str,
+    state_dir: &Option<String>,
+    interface: &str,
+) -> Result<Option<InterfaceBackend>> {
+    let citadel_root = Path::new(citadel_root);
+    let paths = CitadelPaths::new(citadel_root, state_dir.as_deref().map(Path::new));
+    let apps_dir = citadel_root.join("apps");
+    let virtual_apps: HashMap<String, Vec<String>> =
+        serde_json::from_str(&paths.read_to_string(&apps_dir.join("virtual-apps.json"))?)
+            .context("Failed to load virtual-apps.json")?;
+    let port_map: HashMap<String, HashMap<String, Vec<PortMapElement>>> =
+        serde_yaml::from_reader(paths.open(&apps_dir.join("ports.yml"))?)
+            .context("Failed to load ports.yml")?;
+    let ip_map: HashMap<String, String> =
+        serde_yaml::from_reader(paths.open(&apps_dir.join("ips.yml"))?)
+            .context("Failed to load ips.yml")?;
+    let installed_apps = paths
+        .open(&citadel_root.join("db").join("user.json"))
+        .ok()
+        .and_then(|user_json| serde_json::from_reader::<_, UserJson>(user_json).ok())
+        .map(|user_json| user_json.installed_apps)
+        .unwrap_or_default();
+    Ok(resolve(
+        interface,
+        &load_owners(&paths, citadel_root)?,
+        &virtual_apps,
+        &installed_apps,
+        &ip_map,
+        &port_map,
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::resolve;
+    use crate::composegenerator::v4::types::PortMapElement;
+    use std::collections::{BTreeMap, HashMap};
+
+    #[test]
+    fn resolves_interfaces() {
+        let virtual_apps = HashMap::from([(
+            "electrum".to_string(),
+            vec!["electrs".to_string(), "fulcrum".to_string()],
+        )]);
+        let ip_map = HashMap::from([
+            (
+                "APP_ELECTRS_SERVICE_IP".to_string(),
+                "10.21.21.10".to_string(),
+            ),
+            (
+                "APP_FULCRUM_SERVICE_IP".to_string(),
+                "10.21.21.11".to_string(),
+            ),
+        ]);
+        let port = PortMapElement {
+            dynamic: false,
+            internal_port: 50001,
+            public_port: 50001,
+        };
+        let port_map = HashMap::from([(
+            "electrum".to_string(),
+            HashMap::from([("service".to_string(), vec![port.clone()])]),
+        )]);
+        let installed = vec!["fulcrum".to_string()];
+
+        let backend = resolve(
+            "electrum",
+            &BTreeMap::new(),
+            &virtual_apps,
+            &installed,
+            &ip_map,
+            &port_map,
+        )
+        .unwrap();
+        assert_eq!(backend.app, "fulcrum");
+        assert_eq!(backend.ip.as_deref(), Some("10.21.21.11"));
+        assert_eq!(backend.ports, vec![port]);
+
+        let owners = BTreeMap::from([("electrum".to_string(), "electrs".to_string())]);
+        let backend = resolve(
+            "electrum",
+            &owners,
+            &virtual_apps,
+            &installed,
+            &ip_map,
+            &port_map,
+        )
+        .unwrap();
+        assert_eq!(backend.app, "electrs");
+        assert!(resolve(
+            "lightning",
+            &owners,
+            &virtual_apps,
+            &installed,
+            &ip_map,
+            &port_map
+        )
+        .is_none());
+    }
+}
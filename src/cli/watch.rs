@@ -0,0 +1,242 @@
+use std::{
+    collections::BTreeSet,
+    path::{Component, Path},
+    sync::mpsc::{self, RecvTimeoutError},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use notify::{event::ModifyKind, Event, EventKind, RecursiveMode, Watcher};
+
+use super::{
+    app_filter::AppFilter, config_reload::ConfigReloader, metrics::ConversionReport,
+    paths::CitadelPaths, port_forwarding, port_review::PortChangePolicy,
+};
+
+/// Changes within this time of each other are handled by one conversion, editors often write a file several times
+const DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// What a changed file requires to be converted
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Change {
+    App(String),
+    All,
+    /// app-manager.toml or apps/sources.yml, all apps are converted if reloading them changed something
+    Config,
+}
+
+/// Whether a file defines an app. app.yml is not watched if it is generated from app.yml.jinja
+/// or umbrel-app.yml, the conversion rewrites it.
+fn is_app_source(app_dir: &Path, file: &str) -> bool {
+    match file {
+        "app.yml.jinja" | "umbrel-app.yml" => true,
+        "app.yml" => {
+            !app_dir.join("app.yml.jinja").exists() && !app_dir.join("umbrel-app.yml").exists()
+        }
+        _ => false,
+    }
+}
+
+/// What a change to `path` requires, None if the conversion doesn't read it
+fn classify(citadel_root: &Path, path: &Path) -> Option<Change> {
+    let relative = path.strip_prefix(citadel_root).ok()?;
+    let components: Vec<&str> = relative
+        .components()
+        .map(|component| match component {
+            Component::Normal(name) => name.to_str(),
+            _ => None,
+        })
+        .collect::<Option<_>>()?;
+    match components.as_slice() {
+        ["apps", app_id, file] if is_app_source(&citadel_root.join("apps").join(app_id), file) => {
+            Some(Change::App(app_id.to_string()))
+        }
+        ["db", "user.json"] | ["templates", ..] => Some(Change::All),
+        ["app-manager.toml"] | ["apps", "sources.yml"] => Some(Change::Config),
+        _ => None,
+    }
+}
+
+/// Converts only the changed app if it was the only change, and all apps otherwise
+fn convert(
+    citadel_root: &str,
+    caddy_url: &Option<String>,
+    state_dir: &Option<String>,
+    changes: &BTreeSet<Change>,
+) -> Result<ConversionReport> {
+    let convert_dir = |single_app| {
+        super::failures::convert_dir(
+            citadel_root,
+            caddy_url,
+            state_dir,
+            PortChangePolicy::default(),
+            &AppFilter::default(),
+            single_app,
+            None,
+        )
+    };
+    if let [Change::App(app_id)] = Vec::from_iter(changes).as_slice() {
+        match convert_dir(Some(app_id)) {
+            Ok(report) => return Ok(report),
+            // New apps and apps that need new ports or IPs
+            Err(err) => tracing::info!(
+                "Converting all apps, {} can't be converted on its own: {:#}",
+                app_id,
+                err
+            ),
+        }
+    }
+    convert_dir(None)
+}
+
+/// Converts all apps, then converts them again whenever an app.yml, db/user.json or a file in
+/// the templates directory changes, or app-manager.toml or apps/sources.yml change in a way that
+/// affects the apps. Caddy is reloaded after each conversion if `caddy_url` is set.
+/// Port forwards are requested again before they expire if no conversion ran in the meantime.
+/// Runs until watching fails, `converted` is called with the report of every conversion.
+pub fn watch(
+    citadel_root: &str,
+    caddy_url: &Option<String>,
+    state_dir: &Option<String>,
+    mut converted: impl FnMut(&ConversionReport),
+) -> Result<()> {
+    let root = Path::new(citadel_root);
+    let apps_dir = root.join("apps");
+    let paths = CitadelPaths::new(root, state_dir.as_deref().map(Path::new));
+    let mut config = ConfigReloader::new(paths.clone())?;
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender).context("Failed to watch for changes")?;
+    // App directories are watched on their own, they can contain large directories
+    watcher.watch(root, RecursiveMode::NonRecursive)?;
+    watcher.watch(&apps_dir, RecursiveMode::NonRecursive)?;
+    for app_dir in super::read_app_dirs(&apps_dir)? {
+        watcher.watch(&app_dir.path(), RecursiveMode::NonRecursive)?;
+    }
+    for (dir, mode) in [
+        (root.join("db"), RecursiveMode::NonRecursive),
+        (root.join("templates"), RecursiveMode::Recursive),
+    ] {
+        if dir.is_dir() {
+            watcher.watch(&dir, mode)?;
+        }
+    }
+
+    let mut changes = BTreeSet::from([Change::All]);
+    let mut next_renewal = None;
+    loop {
+        if changes.remove(&Change::Config) {
+            match config.reload() {
+                Ok(events) if !events.is_empty() => {
+                    config.apply(&events);
+                    changes.insert(Change::All);
+                }
+                Ok(_) => {}
+                Err(err) => tracing::error!("Failed to reload the configuration: {:#}", err),
+            }
+        }
+        if !changes.is_empty() {
+            match convert(citadel_root, caddy_url, state_dir, &changes) {
+                Ok(report) => converted(&report),
+                Err(err) => tracing::error!("Failed to convert: {:#}", err),
+            }
+            tracing::info!("Watching {} for changes", citadel_root);
+            // The conversion requested the forwards again
+            next_renewal = config
+                .node_config()
+                .port_forwarding
+                .renew_interval()
+                .map(|interval| Instant::now() + interval);
+        }
+        changes.clear();
+        let received = match next_renewal {
+            Some(at) => receiver.recv_timeout(at.saturating_duration_since(Instant::now())),
+            None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        let mut event = match received {
+            Ok(event) => event,
+            Err(RecvTimeoutError::Timeout) => {
+                if let Err(err) = port_forwarding::renew(&paths) {
+                    tracing::error!("Failed to renew port forwards: {:#}", err);
+                }
+                next_renewal = config
+                    .node_config()
+                    .port_forwarding
+                    .renew_interval()
+                    .map(|interval| Instant::now() + interval);
+                continue;
+            }
+            Err(RecvTimeoutError::Disconnected) => bail!("Stopped watching for changes"),
+        };
+        loop {
+            match event {
+                Ok(Event {
+                    kind: EventKind::Modify(ModifyKind::Metadata(_)),
+                    ..
+                }) => {}
+                Ok(Event {
+                    kind:
+                        kind @ (EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)),
+                    paths,
+                    ..
+                }) => {
+                    for path in paths {
+                        if matches!(kind, EventKind::Create(_))
+                            && path.parent() == Some(apps_dir.as_path())
+                            && path.is_dir()
+                        {
+                            watcher.watch(&path, RecursiveMode::NonRecursive)?;
+                        }
+                        if let Some(change) = classify(root, &path) {
+                            tracing::debug!("{} changed", path.display());
+                            changes.insert(change);
+                        }
+                    }
+                }
+                Ok(_) => {}
+                Err(err) => tracing::warn!("Failed to watch for changes: {}", err),
+            }
+            match receiver.recv_timeout(DEBOUNCE) {
+                Ok(next) => event = next,
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tempdir::TempDir;
+
+    use super::{classify, Change};
+
+    #[test]
+    fn classifies_changes() {
+        let root = TempDir::new("watch").unwrap();
+        let root = root.path();
+        for app in ["lnd", "mempool"] {
+            std::fs::create_dir_all(root.join("apps").join(app)).unwrap();
+        }
+        std::fs::write(root.join("apps/mempool/app.yml.jinja"), "").unwrap();
+        let classify = |path: &str| classify(root, &root.join(path));
+
+        assert_eq!(
+            classify("apps/lnd/app.yml"),
+            Some(Change::App("lnd".to_string()))
+        );
+        assert_eq!(
+            classify("apps/mempool/app.yml.jinja"),
+            Some(Change::App("mempool".to_string()))
+        );
+        // Rendered from app.yml.jinja
+        assert_eq!(classify("apps/mempool/app.yml"), None);
+        assert_eq!(classify("apps/lnd/docker-compose.yml"), None);
+        assert_eq!(classify("apps/registry.json"), None);
+        assert_eq!(classify("db/user.json"), Some(Change::All));
+        assert_eq!(classify("templates/Caddyfile.jinja"), Some(Change::All));
+        assert_eq!(classify("templates/tor/torrc.jinja"), Some(Change::All));
+        assert_eq!(classify("db/citadel-seed/seed"), None);
+        assert_eq!(classify("app-manager.toml"), Some(Change::Config));
+        assert_eq!(classify("apps/sources.yml"), Some(Change::Config));
+        assert_eq!(classify("apps/stores.yml"), None);
+    }
+}
@@ -0,0 +1,282 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// GitHub sends up to 25 MB, but push payloads are far smaller
+const MAX_REQUEST_SIZE: usize = 5 * 1024 * 1024;
+
+/// Webhooks that sync a store and convert all apps, in app-manager.toml
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct WebhookConfig {
+    /// The address serve receives webhooks on, like 127.0.0.1:8999. Webhooks are off without it.
+    pub listen: Option<String>,
+    /// Store ID -> the secret its webhooks are signed with, stores without one can't be synced
+    pub secrets: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Default)]
+struct HttpRequest {
+    method: String,
+    path: String,
+    /// Lowercase header name -> value
+    headers: HashMap<String, String>,
+    body: Vec<u8>,
+}
+
+/// The response to a webhook, and the store to sync if it was accepted
+#[derive(Debug, PartialEq, Eq)]
+struct Outcome {
+    status: u16,
+    message: &'static str,
+    sync: Option<String>,
+}
+
+impl Outcome {
+    fn without_sync(status: u16, message: &'static str) -> Self {
+        Self {
+            status,
+            message,
+            sync: None,
+        }
+    }
+}
+
+fn read_request(reader: &mut impl BufRead) -> Result<HttpRequest> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+        bail!("Invalid request line");
+    };
+    let mut request = HttpRequest {
+        method: method.to_string(),
+        path: path.to_string(),
+        ..Default::default()
+    };
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            bail!("Connection closed before the end of the headers");
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let Some((name, value)) = header.split_once(':') else {
+            bail!("Invalid header {header}");
+        };
+        request
+            .headers
+            .insert(name.trim().to_lowercase(), value.trim().to_string());
+    }
+    let length: usize = match request.headers.get("content-length") {
+        Some(length) => length.parse().context("Invalid Content-Length")?,
+        None => 0,
+    };
+    if length > MAX_REQUEST_SIZE {
+        bail!("Request body is too large");
+    }
+    request.body = vec![0; length];
+    reader.read_exact(&mut request.body)?;
+    Ok(request)
+}
+
+/// Compares all bytes, so the time taken doesn't reveal how much of a secret is right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Whether a request was sent by someone who knows the secret.
+/// GitHub and Gitea sign the body, GitLab sends the secret as a token.
+fn is_authentic(request: &HttpRequest, secret: &str) -> bool {
+    if let Some(signature) = request.headers.get("x-hub-signature-256") {
+        let Some(signature) = signature
+            .strip_prefix("sha256=")
+            .and_then(|signature| hex::decode(signature).ok())
+        else {
+            return false;
+        };
+        let expected = hmac_sha256::HMAC::mac(&request.body, secret.as_bytes());
+        return constant_time_eq(&signature, &expected);
+    }
+    request
+        .headers
+        .get("x-gitlab-token")
+        .is_some_and(|token| constant_time_eq(token.as_bytes(), secret.as_bytes()))
+}
+
+/// Decides what to do with a webhook sent to /webhooks/<store ID>
+fn handle(request: &HttpRequest, config: &WebhookConfig) -> Outcome {
+    let Some(store) = request.path.strip_prefix("/webhooks/") else {
+        return Outcome::without_sync(404, "Not Found");
+    };
+    // Stores without a secret are treated like unknown stores
+    let Some(secret) = config.secrets.get(store) else {
+        return Outcome::without_sync(404, "Not Found");
+    };
+    if request.method != "POST" {
+        return Outcome::without_sync(405, "Method Not Allowed");
+    }
+    if !is_authentic(request, secret) {
+        tracing::warn!("Rejected a webhook for store {store} with an invalid signature");
+        return Outcome::without_sync(401, "Unauthorized");
+    }
+    // Sent by GitHub when the webhook is created
+    if request.headers.get("x-github-event").map(String::as_str) == Some("ping") {
+        return Outcome::without_sync(200, "OK");
+    }
+    Outcome {
+        status: 202,
+        message: "Accepted",
+        sync: Some(store.to_string()),
+    }
+}
+
+fn respond(mut stream: &TcpStream, outcome: &Outcome) -> Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        outcome.status, outcome.message
+    )?;
+    stream.flush()?;
+    Ok(())
+}
+
+/// Syncs the stores in `queue` one after another until all senders are dropped.
+/// Webhooks that arrive during a sync are combined, so each store is synced once afterwards.
+fn sync_queued(queue: mpsc::Receiver<String>, sync: impl Fn(&str) -> Result<()>) {
+    while let Ok(store) = queue.recv() {
+        let stores: BTreeSet<String> = std::iter::once(store).chain(queue.try_iter()).collect();
+        for store in stores {
+            tracing::info!("Syncing store {store} after a webhook");
+            if let Err(err) = sync(&store) {
+                tracing::error!("Failed to sync store {store}: {:#}", err);
+            }
+        }
+    }
+}
+
+/// Receives webhooks on `config.listen` until the process is stopped.
+/// Stores are synced on a worker thread, so webhooks are answered while a sync runs
+/// and senders with short timeouts don't give up.
+pub(super) fn serve(
+    config: &WebhookConfig,
+    sync: impl Fn(&str) -> Result<()> + Send,
+) -> Result<()> {
+    let Some(address) = &config.listen else {
+        return Ok(());
+    };
+    let listener =
+        TcpListener::bind(address).with_context(|| format!("Failed to listen on {address}"))?;
+    tracing::info!("Receiving webhooks on {address}");
+    std::thread::scope(|scope| {
+        let (queue, queued) = mpsc::channel();
+        scope.spawn(move || sync_queued(queued, sync));
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    tracing::warn!("Failed to accept webhook connection: {}", err);
+                    continue;
+                }
+            };
+            if let Err(err) = stream.set_read_timeout(Some(Duration::from_secs(10))) {
+                tracing::warn!("Failed to set a timeout for a webhook connection: {}", err);
+                continue;
+            }
+            let limited = (&stream).take(MAX_REQUEST_SIZE as u64);
+            let outcome = match read_request(&mut BufReader::new(limited)) {
+                Ok(request) => handle(&request, config),
+                Err(err) => {
+                    tracing::warn!("Failed to read webhook: {:#}", err);
+                    Outcome::without_sync(400, "Bad Request")
+                }
+            };
+            if let Err(err) = respond(&stream, &outcome) {
+                tracing::warn!("Failed to respond to webhook: {:#}", err);
+            }
+            drop(stream);
+            if let Some(store) = outcome.sync {
+                if queue.send(store).is_err() {
+                    bail!("The sync worker stopped");
+                }
+            }
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::{cell::RefCell, io::BufReader, sync::mpsc};
+
+    use super::{handle, read_request, sync_queued, WebhookConfig};
+
+    #[test]
+    fn accepts_signed_webhooks() {
+        let config = WebhookConfig {
+            listen: None,
+            secrets: [(
+                "citadel".to_string(),
+                "It's a Secret to Everybody".to_string(),
+            )]
+            .into(),
+        };
+        let request = |path: &str, headers: &str| {
+            let body = "Hello, World!";
+            let raw = format!(
+                "POST {path} HTTP/1.1\r\n{headers}Content-Length: {}\r\n\r\n{body}",
+                body.len()
+            );
+            read_request(&mut BufReader::new(raw.as_bytes())).unwrap()
+        };
+        // The example from GitHub's documentation
+        let signature = "X-Hub-Signature-256: sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17\r\n";
+
+        let outcome = handle(&request("/webhooks/citadel", signature), &config);
+        assert_eq!(outcome.status, 202);
+        assert_eq!(outcome.sync.as_deref(), Some("citadel"));
+        let outcome = handle(
+            &request(
+                "/webhooks/citadel",
+                "X-Gitlab-Token: It's a Secret to Everybody\r\n",
+            ),
+            &config,
+        );
+        assert_eq!(outcome.sync.as_deref(), Some("citadel"));
+        let ping = format!("{signature}X-GitHub-Event: ping\r\n");
+        let outcome = handle(&request("/webhooks/citadel", &ping), &config);
+        assert_eq!((outcome.status, outcome.sync), (200, None));
+
+        let wrong_signature = signature.replace("757107", "857107");
+        let outcome = handle(&request("/webhooks/citadel", &wrong_signature), &config);
+        assert_eq!((outcome.status, outcome.sync), (401, None));
+        let outcome = handle(&request("/webhooks/citadel", ""), &config);
+        assert_eq!(outcome.status, 401);
+        let outcome = handle(&request("/webhooks/umbrel", signature), &config);
+        assert_eq!(outcome.status, 404);
+    }
+
+    #[test]
+    fn combines_queued_syncs() {
+        let (queue, queued) = mpsc::channel();
+        for store in ["citadel", "community", "citadel"] {
+            queue.send(store.to_string()).unwrap();
+        }
+        drop(queue);
+        let synced = RefCell::new(Vec::new());
+        sync_queued(queued, |store| {
+            synced.borrow_mut().push(store.to_string());
+            Ok(())
+        });
+        assert_eq!(synced.into_inner(), vec!["citadel", "community"]);
+    }
+}
@@ -1,4 +1,6 @@
 pub mod compose;
+pub mod includes;
+pub mod ir;
 pub mod types;
 #[cfg(feature = "umbrel")]
 pub mod umbrel;
@@ -7,101 +9,165 @@ pub mod v4;
 // A subset of compose
 pub mod output;
 
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
 
+use self::ir::{AppDefinition, SchemaVersion};
 use self::types::ResultYml;
-use self::v3::convert::v3_to_v4;
+use self::v3::convert::v3_to_definition;
 use self::v3::types::Schema as AppYmlV3;
 use self::v4::types::{AppYml as AppYmlV4, PortMapElement};
 use anyhow::{bail, Result};
 
+#[allow(clippy::large_enum_variant)]
 pub enum AppYmlFile {
     V3(AppYmlV3),
     V4(AppYmlV4),
 }
 
+impl AppYmlFile {
+    pub fn schema_version(&self) -> SchemaVersion {
+        match self {
+            AppYmlFile::V3(_) => SchemaVersion::V3,
+            AppYmlFile::V4(_) => SchemaVersion::V4,
+        }
+    }
+
+    /// Runs the front-end of the app's version to get the internal representation
+    pub fn into_definition(self, installed_services: Option<&[String]>) -> AppDefinition {
+        match self {
+            AppYmlFile::V3(app_yml) => v3_to_definition(app_yml, installed_services),
+            AppYmlFile::V4(app_yml) => app_yml.into(),
+        }
+    }
+}
+
 pub fn load_config<R>(app_reader: R) -> Result<AppYmlFile>
+where
+    R: std::io::Read,
+{
+    let (app_yml, unknown_fields) = load_config_with_unknown_fields(app_reader)?;
+    warn_unknown_fields(&unknown_fields);
+    Ok(app_yml)
+}
+
+/// Loads an app.yml and returns it together with the paths of all fields that were ignored
+/// because this version of the app manager doesn't know them (e.g. `services.main.foo`).
+pub fn load_config_with_unknown_fields<R>(app_reader: R) -> Result<(AppYmlFile, Vec<String>)>
 where
     R: std::io::Read,
 {
     let app_yml = serde_yaml::from_reader::<R, serde_yaml::Value>(app_reader)?;
-    if !app_yml.is_mapping() {
-        bail!("App.yml is not a map!");
-    }
-    let version: u64;
-    if app_yml.get("citadel_version").is_none() || !app_yml.get("citadel_version").unwrap().is_u64()
-    {
-        if app_yml.get("version").is_some() && app_yml.get("version").unwrap().is_u64() {
-            version = app_yml.get("version").unwrap().as_u64().unwrap();
-        } else {
-            bail!("Citadel file format is not set or not a number!");
-        }
-    } else {
-        version = app_yml.get("citadel_version").unwrap().as_u64().unwrap();
-    }
-    match version {
-        3 => {
-            let app_definition: AppYmlV3 = serde_yaml::from_value(app_yml)?;
-            Ok(AppYmlFile::V3(app_definition))
-        }
-        4 => {
-            let app_definition: AppYmlV4 = serde_yaml::from_value(app_yml)?;
-            Ok(AppYmlFile::V4(app_definition))
-        }
-        _ => bail!("Version {} of app.yml not supported", version),
-    }
+    parse_app_yml(app_yml)
+}
+
+/// Loads an app.yml of any supported version as [`AppDefinition`]
+pub fn load_definition<R>(
+    app_reader: R,
+    installed_services: Option<&[String]>,
+) -> Result<AppDefinition>
+where
+    R: std::io::Read,
+{
+    let app_yml = serde_yaml::from_reader::<R, serde_yaml::Value>(app_reader)?;
+    value_as_definition(app_yml, installed_services)
+}
+
+/// Loads an app.yml file as [`AppDefinition`], resolving `include:` directives.
+/// Included files must be in the same directory as the app.yml or a subdirectory.
+pub fn load_definition_file(
+    app_yml: &Path,
+    installed_services: Option<&[String]>,
+) -> Result<AppDefinition> {
+    load_definition_file_with(app_yml, installed_services, &Path::to_path_buf)
+}
+
+/// Like [`load_definition_file`], but the app.yml and every included file are read from
+/// `read_path(path)`, for example to prefer files in a state directory
+pub fn load_definition_file_with(
+    app_yml: &Path,
+    installed_services: Option<&[String]>,
+    read_path: &dyn Fn(&Path) -> PathBuf,
+) -> Result<AppDefinition> {
+    let app_dir = app_yml
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."));
+    let app_yml = includes::load_with_includes(app_yml, app_dir, read_path)?;
+    value_as_definition(app_yml, installed_services)
 }
 
+#[deprecated(note = "use load_definition")]
 pub fn load_config_as_v4<R>(
     app_reader: R,
-    installed_services: &Option<&Vec<String>>,
+    installed_services: Option<&[String]>,
 ) -> Result<AppYmlV4>
 where
     R: std::io::Read,
 {
-    let app_yml = serde_yaml::from_reader::<R, serde_yaml::Value>(app_reader)?;
+    load_definition(app_reader, installed_services).map(AppYmlV4::from)
+}
+
+#[deprecated(note = "use load_definition_file")]
+pub fn load_config_file_as_v4(
+    app_yml: &Path,
+    installed_services: Option<&[String]>,
+) -> Result<AppYmlV4> {
+    load_definition_file(app_yml, installed_services).map(AppYmlV4::from)
+}
+
+fn value_as_definition(
+    app_yml: serde_yaml::Value,
+    installed_services: Option<&[String]>,
+) -> Result<AppDefinition> {
+    let (app_yml, unknown_fields) = parse_app_yml(app_yml)?;
+    warn_unknown_fields(&unknown_fields);
+    Ok(app_yml.into_definition(installed_services))
+}
+
+fn parse_app_yml(app_yml: serde_yaml::Value) -> Result<(AppYmlFile, Vec<String>)> {
     if !app_yml.is_mapping() {
         bail!("App.yml is not a map!");
     }
-    let version: u64;
-    if app_yml.get("citadel_version").is_none() || !app_yml.get("citadel_version").unwrap().is_u64()
-    {
-        if app_yml.get("version").is_some() && app_yml.get("version").unwrap().is_u64() {
-            version = app_yml.get("version").unwrap().as_u64().unwrap();
-        } else {
-            bail!("Citadel file format is not set or not a number!");
-        }
-    } else {
-        version = app_yml.get("citadel_version").unwrap().as_u64().unwrap();
-    }
-    match version {
-        3 => {
-            let app_definition: AppYmlV3 = serde_yaml::from_value(app_yml)?;
-            Ok(v3_to_v4(app_definition, installed_services))
-        }
-        4 => {
-            let app_definition: AppYmlV4 = serde_yaml::from_value(app_yml)?;
-            Ok(app_definition)
-        }
-        _ => bail!("Version {} of app.yml not supported", version),
+    let mut unknown_fields = Vec::new();
+    let app_yml = match SchemaVersion::negotiate(&app_yml)? {
+        SchemaVersion::V3 => AppYmlFile::V3(serde_ignored::deserialize(app_yml, |path| {
+            unknown_fields.push(path.to_string())
+        })?),
+        SchemaVersion::V4 => AppYmlFile::V4(serde_ignored::deserialize(app_yml, |path| {
+            unknown_fields.push(path.to_string())
+        })?),
+    };
+    Ok((app_yml, unknown_fields))
+}
+
+fn warn_unknown_fields(unknown_fields: &[String]) {
+    for field in unknown_fields {
+        tracing::warn!(
+            field = field.as_str(),
+            "Ignoring unknown field {} in app.yml",
+            field
+        );
     }
 }
 
 pub fn convert_config<R>(
     app_name: &str,
     app_reader: R,
-    port_map: &Option<HashMap<String, HashMap<String, Vec<PortMapElement>>>>,
-    installed_services: &Option<Vec<String>>,
-    ip_addresses: &Option<HashMap<String, String>>,
+    port_map: Option<&HashMap<String, HashMap<String, Vec<PortMapElement>>>>,
+    installed_services: Option<&[String]>,
+    ip_addresses: Option<&HashMap<String, String>>,
 ) -> Result<ResultYml>
 where
     R: std::io::Read,
 {
     let app_yml = load_config(app_reader)?;
     match app_yml {
-        AppYmlFile::V4(app_definition) => v4::convert::convert_config(
+        AppYmlFile::V4(app_yml) => v4::convert::convert_config(
             app_name,
-            app_definition,
+            app_yml.into(),
             port_map,
             installed_services,
             ip_addresses,
@@ -121,3 +187,38 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{load_config_with_unknown_fields, AppYmlFile};
+
+    #[test]
+    fn collects_unknown_fields() {
+        let app_yml = "citadel_version: 4
+future_field: true
+metadata:
+  name: Example
+  version: 1.0.0
+  category: Example
+  tagline: Example
+  developers:
+    Citadel: https://runcitadel.space
+  permissions: []
+  repo:
+    Public: https://github.com/runcitadel/example
+  support: https://t.me/citadeldevelopers
+  description: Example
+services:
+  main:
+    image: example:1.0.0
+    new_option: 1
+";
+        let (app_yml, unknown_fields) =
+            load_config_with_unknown_fields(app_yml.as_bytes()).unwrap();
+        assert!(matches!(app_yml, AppYmlFile::V4(_)));
+        assert_eq!(
+            unknown_fields,
+            vec!["future_field", "services.main.new_option"]
+        );
+    }
+}
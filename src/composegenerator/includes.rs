@@ -0,0 +1,250 @@
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use serde_yaml::{Mapping, Value};
+
+/// Loads a YAML file and resolves its `include:` directive.
+///
+/// `include:` can be a single path or a list of paths relative to the file containing it.
+/// Included files are merged in order, and the including file is merged on top of them.
+/// Maps are merged recursively, all other values are replaced.
+/// Included files must be inside `root_dir`.
+/// Every file is read from `read_path(file)`, which can redirect it to a state directory.
+pub fn load_with_includes(
+    file: &Path,
+    root_dir: &Path,
+    read_path: &dyn Fn(&Path) -> PathBuf,
+) -> Result<Value> {
+    let root_dir = normalize(root_dir)?;
+    // Symlinks must not point outside of the app directory in the root or the state directory
+    let allowed_dirs: Vec<PathBuf> = [root_dir.clone(), read_path(&root_dir)]
+        .iter()
+        .filter_map(|dir| dir.canonicalize().ok())
+        .collect();
+    if allowed_dirs.is_empty() {
+        bail!("Failed to resolve {}", root_dir.display());
+    }
+    let loader = Loader {
+        root_dir,
+        allowed_dirs,
+        read_path,
+    };
+    loader.load(&normalize(file)?, &mut Vec::new())
+}
+
+/// Makes a path absolute and removes `.` and `..` without following symlinks
+fn normalize(path: &Path) -> Result<PathBuf> {
+    let path = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        std::env::current_dir()
+            .context("Failed to get the current directory")?
+            .join(path)
+    };
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    Ok(normalized)
+}
+
+struct Loader<'a> {
+    root_dir: PathBuf,
+    allowed_dirs: Vec<PathBuf>,
+    read_path: &'a dyn Fn(&Path) -> PathBuf,
+}
+
+impl Loader<'_> {
+    fn load(&self, file: &Path, stack: &mut Vec<PathBuf>) -> Result<Value> {
+        if !file.starts_with(&self.root_dir) {
+            bail!("{} is outside of the app directory", file.display());
+        }
+        if stack.iter().any(|included| included == file) {
+            bail!("{} includes itself", file.display());
+        }
+        let source = (self.read_path)(file);
+        let resolved = source
+            .canonicalize()
+            .with_context(|| format!("Failed to resolve {}", source.display()))?;
+        if !self
+            .allowed_dirs
+            .iter()
+            .any(|dir| resolved.starts_with(dir))
+        {
+            bail!("{} is outside of the app directory", resolved.display());
+        }
+        let reader = std::fs::File::open(&source)
+            .with_context(|| format!("Failed to open {}", source.display()))?;
+        let mut value: Value = serde_yaml::from_reader(reader)
+            .with_context(|| format!("Failed to parse {}", source.display()))?;
+        let Some(map) = value.as_mapping_mut() else {
+            return Ok(value);
+        };
+        let Some(include) = map.remove("include") else {
+            return Ok(value);
+        };
+        let includes = match include {
+            Value::String(path) => vec![path],
+            Value::Sequence(paths) => paths
+                .into_iter()
+                .map(|path| match path {
+                    Value::String(path) => Ok(path),
+                    _ => bail!("include: in {} must only contain paths", file.display()),
+                })
+                .collect::<Result<Vec<String>>>()?,
+            _ => bail!(
+                "include: in {} must be a path or a list of paths",
+                file.display()
+            ),
+        };
+
+        let base_dir = file.parent().unwrap_or(&self.root_dir).to_path_buf();
+        stack.push(file.to_path_buf());
+        let mut result = Value::Mapping(Mapping::new());
+        for include in includes {
+            if include.contains('\\') {
+                bail!("include: {} must use / as path separator", include);
+            }
+            warn_on_case_mismatch(&(self.read_path)(&base_dir), &include);
+            let included = self.load(&normalize(&base_dir.join(include))?, stack)?;
+            merge(&mut result, included);
+        }
+        stack.pop();
+        merge(&mut result, value);
+        Ok(result)
+    }
+}
+
+/// Warns if a path only resolves because the file system is case-insensitive (like on macOS or Windows)
+fn warn_on_case_mismatch(base_dir: &Path, include: &str) {
+    let mut dir = base_dir.to_path_buf();
+    for component in include.split('/').filter(|c| !c.is_empty() && *c != ".") {
+        if component != ".." {
+            let Ok(entries) = std::fs::read_dir(&dir) else {
+                return;
+            };
+            let names: Vec<_> = entries
+                .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+                .collect();
+            if !names.iter().any(|name| name == component) {
+                if let Some(name) = names
+                    .iter()
+                    .find(|name| name.eq_ignore_ascii_case(component))
+                {
+                    tracing::warn!(
+                        "include: {} refers to {}, which will not be found on case-sensitive file systems",
+                        include,
+                        name
+                    );
+                }
+                return;
+            }
+        }
+        dir.push(component);
+    }
+}
+
+/// Merges `other` into `base`, values in `other` win
+fn merge(base: &mut Value, other: Value) {
+    match (base, other) {
+        (Value::Mapping(base), Value::Mapping(other)) => {
+            for (key, value) in other {
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, other) => *base = other,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::load_with_includes;
+    use std::path::{Path, PathBuf};
+    use tempdir::TempDir;
+
+    fn write(dir: &Path, name: &str, contents: &str) {
+        std::fs::write(dir.join(name), contents).unwrap();
+    }
+
+    #[test]
+    fn merges_included_files() {
+        let dir = TempDir::new("includes").unwrap();
+        let dir = dir.path();
+        write(
+            dir,
+            "app.yml",
+            "include: [services.yml]\ncitadel_version: 4\nservices:\n  main:\n    image: main:2\n",
+        );
+        write(
+            dir,
+            "services.yml",
+            "services:\n  main:\n    image: main:1\n    user: 1000:1000\n  db:\n    image: db\n",
+        );
+        let value = load_with_includes(&dir.join("app.yml"), dir, &Path::to_path_buf).unwrap();
+        let expected: serde_yaml::Value = serde_yaml::from_str(
+            "citadel_version: 4\nservices:\n  main:\n    image: main:2\n    user: 1000:1000\n  db:\n    image: db\n",
+        )
+        .unwrap();
+        assert_eq!(value, expected);
+    }
+
+    #[test]
+    fn reads_included_files_through_read_path() {
+        let root = TempDir::new("includes").unwrap();
+        let state_dir = TempDir::new("includes-state").unwrap();
+        // The app.yml was rewritten into the state dir, the fragments are only in the root
+        write(root.path(), "app.yml", "citadel_version: 4\n");
+        write(
+            root.path(),
+            "services.yml",
+            "services:\n  db:\n    image: db\n",
+        );
+        write(
+            state_dir.path(),
+            "app.yml",
+            "include: services.yml\ncitadel_version: 4\n",
+        );
+        let read_path = |path: &Path| -> PathBuf {
+            let state_path = state_dir
+                .path()
+                .join(path.strip_prefix(root.path()).unwrap());
+            if state_path.exists() {
+                state_path
+            } else {
+                path.to_path_buf()
+            }
+        };
+        let value =
+            load_with_includes(&root.path().join("app.yml"), root.path(), &read_path).unwrap();
+        let expected: serde_yaml::Value =
+            serde_yaml::from_str("citadel_version: 4\nservices:\n  db:\n    image: db\n").unwrap();
+        assert_eq!(value, expected);
+    }
+
+    #[test]
+    fn rejects_cycles_and_escapes() {
+        let dir = TempDir::new("includes").unwrap();
+        let dir = dir.path();
+        write(dir, "a.yml", "include: b.yml\n");
+        write(dir, "b.yml", "include: a.yml\n");
+        assert!(load_with_includes(&dir.join("a.yml"), dir, &Path::to_path_buf).is_err());
+
+        let app_dir = dir.join("app");
+        std::fs::create_dir(&app_dir).unwrap();
+        write(&app_dir, "app.yml", "include: ../a.yml\n");
+        assert!(
+            load_with_includes(&app_dir.join("app.yml"), &app_dir, &Path::to_path_buf).is_err()
+        );
+    }
+}
@@ -0,0 +1,150 @@
+//! The internal representation (IR) of apps.
+//!
+//! Every on-disk format has a front-end producing an [`AppDefinition`]: app.yml v3 and v4 in
+//! this module's parent, Umbrel apps in the umbrel module. Conversion, templates and repository
+//! checks only work with the IR, so a new app.yml version only needs a new front-end.
+
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::{bail, Result};
+
+use super::v4::types::{AppYml, Container, InputMetadata, NamedVolume, SettingRule};
+
+/// An app, independent of the app.yml version or format it was loaded from.
+/// Containers, volumes and settings use the types of app.yml v4, the newest version.
+#[derive(Clone, Default, Debug, PartialEq, Eq)]
+pub struct AppDefinition {
+    pub metadata: InputMetadata,
+    pub services: HashMap<String, Container>,
+    /// Named volumes, which can be mounted using the `volumes` mount
+    pub volumes: BTreeMap<String, NamedVolume>,
+    /// Values for other apps, name -> value
+    pub exports: BTreeMap<String, String>,
+    /// Settings the operator can change, env var -> rule
+    pub settings: BTreeMap<String, SettingRule>,
+}
+
+/// The app.yml v4 front-end
+impl From<AppYml> for AppDefinition {
+    fn from(app_yml: AppYml) -> Self {
+        Self {
+            metadata: app_yml.metadata,
+            services: app_yml.services,
+            volumes: app_yml.volumes,
+            exports: app_yml.exports,
+            settings: app_yml.settings,
+        }
+    }
+}
+
+/// Writes an app as app.yml v4, for example after converting it from another format
+impl From<AppDefinition> for AppYml {
+    fn from(app: AppDefinition) -> Self {
+        Self {
+            citadel_version: SchemaVersion::V4.number() as u8,
+            metadata: app.metadata,
+            services: app.services,
+            volumes: app.volumes,
+            exports: app.exports,
+            settings: app.settings,
+        }
+    }
+}
+
+/// The app.yml versions this app manager can read
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SchemaVersion {
+    V3,
+    V4,
+}
+
+impl SchemaVersion {
+    pub const SUPPORTED: [SchemaVersion; 2] = [SchemaVersion::V3, SchemaVersion::V4];
+
+    pub fn number(self) -> u64 {
+        match self {
+            SchemaVersion::V3 => 3,
+            SchemaVersion::V4 => 4,
+        }
+    }
+
+    /// Picks the front-end for the version an app.yml declares.
+    /// Newer apps set citadel_version, older ones only set version.
+    pub fn negotiate(app_yml: &serde_yaml::Value) -> Result<Self> {
+        let Some(number) = app_yml
+            .get("citadel_version")
+            .and_then(serde_yaml::Value::as_u64)
+            .or_else(|| app_yml.get("version").and_then(serde_yaml::Value::as_u64))
+        else {
+            bail!("Citadel file format is not set or not a number!");
+        };
+        let Some(version) = Self::SUPPORTED
+            .into_iter()
+            .find(|version| version.number() == number)
+        else {
+            bail!(
+                "Version {} of app.yml not supported, this app manager supports versions {}",
+                number,
+                Self::SUPPORTED
+                    .map(|version| version.to_string())
+                    .join(", ")
+            );
+        };
+        Ok(version)
+    }
+}
+
+impl std::fmt::Display for SchemaVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.number())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AppDefinition, SchemaVersion};
+    use crate::composegenerator::v4::types::AppYml;
+
+    #[test]
+    fn negotiates_versions() {
+        let version = |yaml: &str| SchemaVersion::negotiate(&serde_yaml::from_str(yaml).unwrap());
+        assert_eq!(version("citadel_version: 4").unwrap(), SchemaVersion::V4);
+        assert_eq!(version("version: 3").unwrap(), SchemaVersion::V3);
+        assert_eq!(
+            version("citadel_version: 4\nversion: 3").unwrap(),
+            SchemaVersion::V4
+        );
+        assert_eq!(
+            version("citadel_version: 5").unwrap_err().to_string(),
+            "Version 5 of app.yml not supported, this app manager supports versions 3, 4"
+        );
+        assert!(version("name: test").is_err());
+    }
+
+    #[test]
+    fn writes_definitions_as_v4() {
+        let app_yml: AppYml = serde_yaml::from_str(
+            "citadel_version: 4
+metadata:
+  name: Example
+  version: 1.0.0
+  category: Example
+  tagline: Example
+  developers: {}
+  permissions: []
+  repo: {}
+  support: https://t.me/citadeldevelopers
+  description: Example
+services:
+  main:
+    image: example:1.0.0
+exports:
+  api: http://example
+",
+        )
+        .unwrap();
+        let definition = AppDefinition::from(app_yml.clone());
+        assert_eq!(definition.exports["api"], "http://example");
+        assert_eq!(AppYml::from(definition), app_yml);
+    }
+}
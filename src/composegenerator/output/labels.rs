@@ -0,0 +1,86 @@
+use super::types::ComposeSpecification;
+
+pub const APP_LABEL: &str = "citadel.app";
+pub const STORE_LABEL: &str = "citadel.store";
+pub const VERSION_LABEL: &str = "citadel.version";
+pub const GENERATION_LABEL: &str = "citadel.generation";
+/// The UID:GID the data of a container needs to be owned by on the host
+pub const HOST_OWNER_LABEL: &str = "citadel.host-owner";
+
+pub use crate::naming::compose_project_name;
+
+/// A short hash of the generated compose file, changes whenever the generated containers change
+pub fn generation(spec: &ComposeSpecification) -> String {
+    let serialized = serde_yaml::to_string(spec).unwrap_or_default();
+    hex::encode(&hmac_sha256::Hash::hash(serialized.as_bytes())[..8])
+}
+
+/// Sets the project name of a generated compose file and labels all its containers and volumes
+pub fn add_labels(
+    spec: &mut ComposeSpecification,
+    app_id: &str,
+    version: &str,
+    store: Option<&str>,
+) {
+    let generation = generation(spec);
+    spec.name = Some(compose_project_name(app_id));
+    for service in spec
+        .services
+        .iter_mut()
+        .flat_map(|services| services.values_mut())
+    {
+        service
+            .labels
+            .insert(APP_LABEL.to_string(), app_id.to_string());
+        if let Some(store) = store {
+            service
+                .labels
+                .insert(STORE_LABEL.to_string(), store.to_string());
+        }
+        service
+            .labels
+            .insert(VERSION_LABEL.to_string(), version.to_string());
+        service
+            .labels
+            .insert(GENERATION_LABEL.to_string(), generation.clone());
+    }
+    // Volumes are labeled too, so they can be found once the app is gone
+    for volume in spec.volumes.values_mut() {
+        volume
+            .labels
+            .insert(APP_LABEL.to_string(), app_id.to_string());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{add_labels, generation, APP_LABEL, GENERATION_LABEL, STORE_LABEL};
+    use crate::{
+        bmap,
+        composegenerator::output::types::{ComposeSpecification, Service, Volume},
+    };
+
+    #[test]
+    fn labels_all_services() {
+        let mut spec = ComposeSpecification {
+            services: Some(bmap! {
+                "main" => Service {
+                    image: Some("example:1.0.0".to_string()),
+                    ..Default::default()
+                }
+            }),
+            volumes: bmap! {
+                "data" => Volume::default()
+            },
+            ..Default::default()
+        };
+        let expected_generation = generation(&spec);
+        add_labels(&mut spec, "example", "1.0.0", None);
+        assert_eq!(spec.name, Some("example".to_string()));
+        assert_eq!(spec.volumes["data"].labels[APP_LABEL], "example");
+        let labels = &spec.services.unwrap()["main"].labels;
+        assert_eq!(labels[APP_LABEL], "example");
+        assert_eq!(labels[GENERATION_LABEL], expected_generation);
+        assert!(!labels.contains_key(STORE_LABEL));
+    }
+}
@@ -1 +1,2 @@
+pub mod labels;
 pub mod types;
@@ -11,6 +11,30 @@ pub struct NetworkEntry {
     pub ipv4_address: Option<String>,
 }
 
+#[derive(Clone, Deserialize, Serialize, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[serde(untagged)]
+pub enum DependsOn {
+    List(Vec<String>),
+    /// Service -> when it counts as ready
+    Conditions(BTreeMap<String, DependsOnCondition>),
+}
+
+#[derive(Clone, Deserialize, Serialize, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct DependsOnCondition {
+    /// service_started, service_healthy or service_completed_successfully
+    pub condition: String,
+}
+
+#[derive(Clone, Default, Deserialize, Serialize, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct Logging {
+    pub driver: String,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty", default)]
+    pub options: BTreeMap<String, String>,
+}
+
 #[derive(Clone, Default, Deserialize, Serialize, PartialEq, Eq, Debug)]
 #[cfg_attr(feature = "schema", derive(JsonSchema))]
 #[serde(rename = "service")]
@@ -20,7 +44,11 @@ pub struct Service {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub command: Option<Command>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub depends_on: Option<Vec<String>>,
+    pub depends_on: Option<DependsOn>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub dns: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub dns_search: Vec<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub entrypoint: Option<Command>,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -33,6 +61,10 @@ pub struct Service {
     pub image: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub init: Option<bool>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty", default)]
+    pub labels: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logging: Option<Logging>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub network_mode: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -41,6 +73,8 @@ pub struct Service {
     pub ports: Vec<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub restart: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub security_opt: Vec<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub stop_grace_period: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -59,6 +93,32 @@ pub struct Service {
 #[cfg_attr(feature = "schema", derive(JsonSchema))]
 #[serde(rename = "Compose Specification")]
 pub struct ComposeSpecification {
+    /// The compose project name
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub services: Option<BTreeMap<String, Service>>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty", default)]
+    pub volumes: BTreeMap<String, Volume>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty", default)]
+    pub networks: BTreeMap<String, Network>,
+}
+
+#[derive(Clone, Default, Deserialize, Serialize, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct Network {
+    /// The name of the Docker network, networks with the same name are shared between projects
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+#[derive(Clone, Default, Deserialize, Serialize, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct Volume {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub driver: Option<String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty", default)]
+    pub driver_opts: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty", default)]
+    pub labels: BTreeMap<String, String>,
 }
@@ -4,6 +4,8 @@ use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
 
 use crate::composegenerator::output::types::ComposeSpecification;
+use crate::composegenerator::v4::types::SettingRule;
+use crate::utils::is_false;
 
 // General types also relevant for the output
 // Can be re-used by schemas
@@ -51,6 +53,9 @@ pub struct OutputMetadata {
     pub default_username: Option<String>,
     /// The app's default password. Can also be $APP_SEED for a random password
     pub default_password: Option<String>,
+    /// True if the app needs to be set up in its UI before it can be used
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub setup_required: bool,
     #[serde(default = "bool::default")]
     /// True if the app only works over Tor
     pub tor_only: bool,
@@ -73,6 +78,282 @@ pub struct OutputMetadata {
     pub release_notes: Option<BTreeMap<String, String>>,
     pub supports_https: bool,
     pub hidden_services: Vec<String>,
+    /// Additional web UIs of the app
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub entries: Vec<OutputUiEntry>,
+    /// If this is not empty, the app can't be installed on this node
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub unsupported: Vec<UnsupportedReason>,
+    /// Ports the app wants to be forwarded on the router
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub port_forwards: Vec<ForwardedPort>,
+    /// Dependencies provided by apps from other stores
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cross_store_dependencies: Vec<CrossStoreDependency>,
+    /// For installed virtual app implementations, the result of the last interface probe
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub health: Option<InterfaceHealth>,
+    /// The URL path the app's static assets are served under, if it has any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub static_assets: Option<String>,
+    /// The bundles of its store the app is part of
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub bundles: Vec<String>,
+    /// Settings the operator can change, for the app's settings form on the dashboard
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub settings: Vec<OutputSetting>,
+}
+
+/// A setting of an app, with the value set on this node
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct OutputSetting {
+    /// The env var the app gets the value in
+    pub id: String,
+    #[serde(flatten)]
+    pub rule: SettingRule,
+    /// None if the operator has not set the setting, or if it is a secret
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+}
+
+/// A dependency of an app that resolves to an app from another store
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct CrossStoreDependency {
+    /// The app or interface that is required
+    pub dependency: String,
+    /// The app providing it
+    pub app: String,
+    /// The store of the providing app
+    pub store: String,
+    /// Whether that store is trusted on this node
+    pub trusted: bool,
+    /// Whether the providing app is installed
+    pub installed: bool,
+}
+
+/// Whether an app responds on the interface it implements
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct InterfaceHealth {
+    /// The interface that was probed, for example electrum
+    pub interface: String,
+    pub healthy: bool,
+    /// Why the probe failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// When the probe ran, in seconds since the Unix epoch
+    pub checked_at: u64,
+}
+
+/// Why an app can't be installed on a node
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[serde(tag = "reason", rename_all = "camelCase")]
+pub enum UnsupportedReason {
+    /// A port the app requires is already used by another app
+    #[serde(rename_all = "camelCase")]
+    PortConflict {
+        container: String,
+        port: u16,
+        protocol: String,
+        used_by: String,
+    },
+    /// The app mounts a share that does not exist
+    #[serde(rename_all = "camelCase")]
+    MissingShare {
+        container: String,
+        app: String,
+        share: String,
+    },
+    /// The app mounts a read-only share read-write
+    #[serde(rename_all = "camelCase")]
+    ReadOnlyShare {
+        container: String,
+        app: String,
+        share: String,
+    },
+    /// The app mounts a socket directory of another app that does not exist
+    #[serde(rename_all = "camelCase")]
+    MissingSocket {
+        container: String,
+        app: String,
+        socket: String,
+    },
+    /// An image of the app is not available for the node's architecture
+    #[serde(rename_all = "camelCase")]
+    ArchitectureMismatch {
+        container: String,
+        image: String,
+        architecture: String,
+        available: Vec<String>,
+    },
+    /// The app's templates failed to render or exceeded the conversion limits
+    ConversionFailed { error: String },
+    /// The node has less RAM than the app needs, in MiB
+    InsufficientMemory { required: u64, available: u64 },
+    /// The node lacks hardware the app needs
+    MissingHardware { capability: HardwareCapability },
+    /// The node provides the service the app would provide itself, for example through a remote bitcoind
+    ProvidedByNode,
+    /// No app on the node and no service of the node provides a dependency of the app,
+    /// alternatives are joined with " or "
+    MissingDependency { dependency: String },
+}
+
+impl std::fmt::Display for UnsupportedReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UnsupportedReason::PortConflict {
+                container,
+                port,
+                protocol,
+                used_by,
+            } => write!(
+                f,
+                "container {container} requires port {port} (on {protocol}), which is already used by {used_by}"
+            ),
+            UnsupportedReason::MissingShare {
+                container,
+                app,
+                share,
+            } => write!(
+                f,
+                "container {container} mounts share {share} of {app}, which does not exist"
+            ),
+            UnsupportedReason::ReadOnlyShare {
+                container,
+                app,
+                share,
+            } => write!(
+                f,
+                "container {container} mounts share {share} of {app} read-write, but it is read-only"
+            ),
+            UnsupportedReason::MissingSocket {
+                container,
+                app,
+                socket,
+            } => write!(
+                f,
+                "container {container} mounts socket directory {socket} of {app}, which does not exist"
+            ),
+            UnsupportedReason::ArchitectureMismatch {
+                container,
+                image,
+                architecture,
+                available,
+            } => write!(
+                f,
+                "container {container} uses image {image}, which is not available for {architecture} (only for {})",
+                available.join(", ")
+            ),
+            UnsupportedReason::ConversionFailed { error } => {
+                write!(f, "the app could not be converted: {error}")
+            }
+            UnsupportedReason::InsufficientMemory {
+                required,
+                available,
+            } => write!(
+                f,
+                "the app needs {required} MiB of RAM, but the node only has {available} MiB"
+            ),
+            UnsupportedReason::MissingHardware { capability } => {
+                write!(f, "the app needs {capability}, which the node does not have")
+            }
+            UnsupportedReason::ProvidedByNode => {
+                write!(f, "the node already provides this service")
+            }
+            UnsupportedReason::MissingDependency { dependency } => {
+                write!(f, "the app depends on {dependency}, which no app on this node provides")
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct OutputUiEntry {
+    pub name: String,
+    pub container: String,
+    /// The port this UI is available on
+    pub port: u16,
+    pub internal_port: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+}
+
+#[derive(
+    Serialize, Deserialize, Clone, Copy, Default, Debug, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    #[default]
+    Tcp,
+    Udp,
+}
+
+impl std::fmt::Display for Protocol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Protocol::Tcp => write!(f, "TCP"),
+            Protocol::Udp => write!(f, "UDP"),
+        }
+    }
+}
+
+/// Hardware an app can require, detected on the node by the probe
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum HardwareCapability {
+    /// Hardware virtualization, /dev/kvm
+    Kvm,
+    /// A GPU containers can use
+    Gpu,
+    /// A CPU with AVX instructions
+    Avx,
+    /// A CPU with AVX2 instructions
+    Avx2,
+    /// App data is stored on an SSD
+    Ssd,
+}
+
+impl std::fmt::Display for HardwareCapability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HardwareCapability::Kvm => write!(f, "KVM"),
+            HardwareCapability::Gpu => write!(f, "a GPU"),
+            HardwareCapability::Avx => write!(f, "AVX support"),
+            HardwareCapability::Avx2 => write!(f, "AVX2 support"),
+            HardwareCapability::Ssd => write!(f, "an SSD"),
+        }
+    }
+}
+
+/// A required port that should be forwarded on the router, so the app can be reached from the internet
+#[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct PortForward {
+    pub port: u16,
+    #[serde(default)]
+    pub protocol: Protocol,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct ForwardedPort {
+    pub container: String,
+    pub port: u16,
+    pub protocol: Protocol,
+    /// The external IP of the router, only set once the port has been forwarded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub external_ip: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
@@ -82,6 +363,22 @@ pub struct CaddyEntry {
     pub internal_port: u16,
     pub container_name: String,
     pub is_primary: bool,
+    /// Where apps share a host, routes with a higher priority come first
+    #[serde(default)]
+    pub priority: i32,
+}
+
+impl CaddyEntry {
+    /// The order entries are written to the Caddyfile in:
+    /// higher priorities first, then the primary entry, then by port
+    pub fn route_cmp(&self, other: &Self) -> std::cmp::Ordering {
+        other
+            .priority
+            .cmp(&self.priority)
+            .then(other.is_primary.cmp(&self.is_primary))
+            .then(self.public_port.cmp(&other.public_port))
+            .then_with(|| self.container_name.cmp(&other.container_name))
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
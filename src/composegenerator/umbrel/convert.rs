@@ -1,4 +1,4 @@
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 
 use anyhow::{bail, Result};
 use tracing::warn;
@@ -7,11 +7,13 @@ use crate::bmap;
 use crate::composegenerator::compose::types::{
     Command, ComposeSpecification, EnvVars, StringOrIntOrBool,
 };
+use crate::composegenerator::ir::AppDefinition;
 use crate::composegenerator::types::Permissions;
 use crate::composegenerator::umbrel::types::Metadata;
 use crate::composegenerator::v4::types::{
-    AppYml, Container, InputMetadata as CitadelMetadata, PortsDefinition, StringOrMap,
+    Container, ContainerKind, InputMetadata as CitadelMetadata, PortsDefinition, StringOrMap,
 };
+use crate::naming;
 use crate::utils::find_env_vars;
 
 pub fn convert_metadata(metadata: Metadata) -> CitadelMetadata {
@@ -48,7 +50,9 @@ pub fn convert_metadata(metadata: Metadata) -> CitadelMetadata {
         } else {
             metadata.default_password
         },
+        setup_required: false,
         tor_only: metadata.tor_only,
+        i2p: false,
         update_containers: None,
         description: metadata.description,
         implements: None,
@@ -58,6 +62,16 @@ pub fn convert_metadata(metadata: Metadata) -> CitadelMetadata {
         } else {
             None
         },
+        main_container: None,
+        entries: Vec::new(),
+        shares: BTreeMap::new(),
+        sockets: BTreeSet::new(),
+        route_priority: 0,
+        static_assets: None,
+        required_storage: None,
+        required_memory: None,
+        required_hardware: Vec::new(),
+        allow_connections_to: Vec::new(),
     }
 }
 
@@ -111,7 +125,7 @@ pub fn convert_compose(
     compose: ComposeSpecification,
     metadata: Metadata,
     env_vars: &HashMap<String, String>,
-) -> Result<AppYml> {
+) -> Result<AppDefinition> {
     let services = compose.services.unwrap();
     let mut result_services: HashMap<String, Container> = HashMap::new();
     let has_main = services.contains_key("main");
@@ -279,8 +293,7 @@ pub fn convert_compose(
                     continue;
                 }
                 if container_env_vars.len() == 1
-                    && container_env_vars[0]
-                        == format!("APP_{}_PORT", metadata.id.to_uppercase().replace('-', "_"))
+                    && container_env_vars[0] == format!("{}PORT", naming::env_prefix(&metadata.id))
                 {
                     let real_main_port = env_vars.get(container_env_vars[0]).unwrap();
                     main_port = real_main_port.parse::<u16>().unwrap();
@@ -294,8 +307,7 @@ pub fn convert_compose(
                     }
                 }
                 if host_env_vars.len() == 1
-                    && host_env_vars[0]
-                        != format!("APP_{}_PORT", metadata.id.to_uppercase().replace('-', "_"))
+                    && host_env_vars[0] != format!("{}PORT", naming::env_prefix(&metadata.id))
                 {
                     #[allow(unused_assignments)]
                     if env_vars.contains_key(host_env_vars[0]) {
@@ -320,6 +332,7 @@ pub fn convert_compose(
             }
         }
         let new_service = Container {
+            kind: ContainerKind::Service,
             image: service_def.image.unwrap(),
             user: service_def.user,
             stop_grace_period: service_def.stop_grace_period,
@@ -354,6 +367,7 @@ pub fn convert_compose(
                         Some(required_udp_ports)
                     },
                     http: None,
+                    ranges: Vec::new(),
                 })
             },
             mounts: Some(mounts),
@@ -363,15 +377,23 @@ pub fn convert_compose(
                 Some(false)
             },
             hidden_services: None,
+            i2p_tunnels: None,
             cap_add: service_def.cap_add,
             direct_tcp: false,
             shm_size: service_def.shm_size,
+            shared_mounts: Vec::new(),
+            sockets: Vec::new(),
+            logging: None,
+            security_profiles: None,
+            port_forwards: Vec::new(),
         };
         result_services.insert(service_name, new_service);
     }
-    Ok(AppYml {
-        citadel_version: 4,
+    Ok(AppDefinition {
         metadata: convert_metadata(metadata),
         services: result_services,
+        volumes: BTreeMap::new(),
+        exports: BTreeMap::new(),
+        settings: BTreeMap::new(),
     })
 }
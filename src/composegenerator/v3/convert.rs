@@ -1,4 +1,5 @@
 use super::types::Schema as AppYmlV3;
+use crate::composegenerator::ir::AppDefinition;
 use crate::composegenerator::types::ResultYml;
 use crate::composegenerator::v4::types::{PortMapElement, StringOrMap};
 use crate::composegenerator::v4::{
@@ -6,9 +7,15 @@ use crate::composegenerator::v4::{
 };
 use crate::utils::flatten;
 use anyhow::Result;
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 
-pub fn v3_to_v4(app: AppYmlV3, installed_services: &Option<&Vec<String>>) -> types_v4::AppYml {
+/// Upgrades an app.yml v3 to v4
+pub fn v3_to_v4(app: AppYmlV3, installed_services: Option<&[String]>) -> types_v4::AppYml {
+    v3_to_definition(app, installed_services).into()
+}
+
+/// The app.yml v3 front-end
+pub fn v3_to_definition(app: AppYmlV3, installed_services: Option<&[String]>) -> AppDefinition {
     let repo = match app.metadata.repo {
         super::types::RepoDefinition::RepoUrl(url) => BTreeMap::from([("Public".to_string(), url)]),
         super::types::RepoDefinition::MultiRepo(map) => map,
@@ -26,12 +33,24 @@ pub fn v3_to_v4(app: AppYmlV3, installed_services: &Option<&Vec<String>>) -> typ
         path: app.metadata.path,
         default_username: None,
         default_password: app.metadata.default_password,
+        setup_required: false,
         tor_only: app.metadata.tor_only.unwrap_or(false),
+        i2p: false,
         update_containers: None,
         description: app.metadata.description,
         implements: None,
         version_control: None,
         release_notes: None,
+        main_container: None,
+        entries: Vec::new(),
+        shares: BTreeMap::new(),
+        sockets: BTreeSet::new(),
+        route_priority: 0,
+        static_assets: None,
+        required_storage: None,
+        required_memory: None,
+        required_hardware: Vec::new(),
+        allow_connections_to: Vec::new(),
     };
     let mut services = HashMap::<String, types_v4::Container>::with_capacity(app.containers.len());
     let deps = app.metadata.dependencies.unwrap_or_default();
@@ -57,6 +76,7 @@ pub fn v3_to_v4(app: AppYmlV3, installed_services: &Option<&Vec<String>>) -> typ
                 udp: None,
                 tcp: None,
                 http: None,
+                ranges: Vec::new(),
             };
             if let Some(tcp_ports) = container.required_ports {
                 let mut map = HashMap::<u16, u16>::with_capacity(tcp_ports.capacity());
@@ -124,6 +144,7 @@ pub fn v3_to_v4(app: AppYmlV3, installed_services: &Option<&Vec<String>>) -> typ
         services.insert(
             container.name,
             types_v4::Container {
+                kind: types_v4::ContainerKind::Service,
                 image: container.image,
                 user: container.user,
                 stop_grace_period: container.stop_grace_period,
@@ -170,31 +191,39 @@ pub fn v3_to_v4(app: AppYmlV3, installed_services: &Option<&Vec<String>>) -> typ
                         types_v4::HiddenServices::LayeredMap(HashMap::from_iter(new_values))
                     }
                 }),
+                i2p_tunnels: None,
                 cap_add: None,
                 direct_tcp: false,
                 shm_size: None,
+                shared_mounts: Vec::new(),
+                sockets: Vec::new(),
+                logging: None,
+                security_profiles: None,
+                port_forwards: Vec::new(),
             },
         );
     }
-    types_v4::AppYml {
-        citadel_version: 4,
+    AppDefinition {
         metadata,
         services,
+        volumes: BTreeMap::new(),
+        exports: BTreeMap::new(),
+        settings: BTreeMap::new(),
     }
 }
 
 pub fn convert_config(
     app_name: &str,
     app: AppYmlV3,
-    port_map: &Option<HashMap<String, HashMap<String, Vec<PortMapElement>>>>,
-    installed_services: &Vec<String>,
-    ip_addresses: &Option<HashMap<String, String>>,
+    port_map: Option<&HashMap<String, HashMap<String, Vec<PortMapElement>>>>,
+    installed_services: &[String],
+    ip_addresses: Option<&HashMap<String, String>>,
 ) -> Result<ResultYml> {
     convert_config_v4(
         app_name,
-        v3_to_v4(app, &Some(installed_services)),
+        v3_to_definition(app, Some(installed_services)),
         port_map,
-        &Some(installed_services.clone()),
+        Some(installed_services),
         ip_addresses,
     )
 }
@@ -161,7 +161,6 @@ pub struct SchemaMetadata {
 }
 #[derive(Clone, PartialEq, Eq, Debug, Deserialize, Serialize)]
 #[cfg_attr(feature = "schema", derive(JsonSchema))]
-#[serde(deny_unknown_fields)]
 pub struct Schema {
     /// The version of the app.yml format you're using.
     pub version: u64,
@@ -1,28 +1,38 @@
 use super::{
     permissions, types,
     types::{PortMapElement, StringOrMap},
-    utils::{get_host_port, get_main_container, validate_cmd},
+    utils::{
+        get_host_port, get_main_container, is_valid_static_assets_dir, share_env_var,
+        static_assets_path, validate_cmd,
+    },
 };
 use crate::{
     bmap,
     composegenerator::{
         compose::types::StringOrIntOrBool,
-        output::types::{ComposeSpecification, NetworkEntry, Service},
-        types::{CaddyEntry, Permissions},
+        ir::AppDefinition,
+        output::types::{
+            ComposeSpecification, DependsOn, DependsOnCondition, Logging, NetworkEntry, Service,
+            Volume,
+        },
+        types::{CaddyEntry, ForwardedPort, OutputUiEntry, Permissions, Protocol},
     },
+    naming,
 };
 use crate::{
-    composegenerator::types::OutputMetadata,
+    composegenerator::types::{OutputMetadata, OutputSetting},
     utils::{find_env_vars, flatten},
 };
 use lazy_static::lazy_static;
-use std::collections::{BTreeMap, HashMap};
+use regex::Regex;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 
 use crate::composegenerator::types::ResultYml;
 use anyhow::{bail, Result};
 
 lazy_static! {
     static ref NET_PERMISSION: String = "network".to_string();
+    static ref LOG_SIZE_REGEX: Regex = Regex::new(r"^[0-9]+[kmg]?$").unwrap();
 }
 
 fn get_main_port(
@@ -133,6 +143,7 @@ fn configure_ports(
                         public_port: port_map_elem.public_port,
                         container_name: service_name.to_owned(),
                         is_primary: true,
+                        priority: 0,
                     });
                 }
             } else {
@@ -163,6 +174,33 @@ fn configure_ports(
                     service.ports.push(format!("{}:{}/udp", port.0, port.1));
                 }
             }
+            for range in &required_ports.ranges {
+                if range.start > range.end {
+                    bail!(
+                        "The port range {}-{} of container {} is empty",
+                        range.start,
+                        range.end,
+                        service_name
+                    );
+                }
+                let internal_start = range.internal_start.unwrap_or(range.start);
+                let Some(internal_end) = internal_start.checked_add(range.end - range.start) else {
+                    bail!(
+                        "The port range {}-{} of container {} does not fit into the container's ports",
+                        range.start,
+                        range.end,
+                        service_name
+                    );
+                };
+                let suffix = match range.protocol {
+                    Protocol::Tcp => "",
+                    Protocol::Udp => "/udp",
+                };
+                service.ports.push(format!(
+                    "{}-{}:{}-{}{}",
+                    range.start, range.end, internal_start, internal_end, suffix
+                ));
+            }
             if let Some(http_ports) = &required_ports.http {
                 for (public_port, internal_port) in http_ports {
                     caddy_entries.push(CaddyEntry {
@@ -170,6 +208,7 @@ fn configure_ports(
                         public_port: *public_port,
                         container_name: service_name.to_owned(),
                         is_primary: false,
+                        priority: 0,
                     })
                 }
             }
@@ -179,6 +218,57 @@ fn configure_ports(
     Ok(caddy_entries)
 }
 
+fn configure_ui_entries(
+    entries: &[types::UiEntry],
+    containers: &HashMap<String, types::Container>,
+    port_map: &Option<HashMap<String, Vec<PortMapElement>>>,
+    caddy_entries: &mut Vec<CaddyEntry>,
+) -> Result<Vec<OutputUiEntry>> {
+    let mut result = Vec::with_capacity(entries.len());
+    for entry in entries {
+        if result
+            .iter()
+            .any(|other: &OutputUiEntry| other.name == entry.name)
+        {
+            bail!("UI entry {} is defined multiple times", entry.name);
+        }
+        if !containers.contains_key(&entry.container) {
+            bail!(
+                "Container {} of UI entry {} does not exist",
+                entry.container,
+                entry.name
+            );
+        }
+        let public_port = match port_map {
+            Some(port_map) => {
+                let Some(port_map_elem) = port_map
+                    .get(&entry.container)
+                    .and_then(|ports| get_host_port(ports, entry.port))
+                else {
+                    bail!("Port of UI entry {} not found in port map", entry.name);
+                };
+                port_map_elem.public_port
+            }
+            None => entry.port,
+        };
+        caddy_entries.push(CaddyEntry {
+            public_port,
+            internal_port: entry.port,
+            container_name: entry.container.clone(),
+            is_primary: false,
+            priority: 0,
+        });
+        result.push(OutputUiEntry {
+            name: entry.name.clone(),
+            container: entry.container.clone(),
+            port: public_port,
+            internal_port: entry.port,
+            path: entry.path.clone(),
+        });
+    }
+    Ok(result)
+}
+
 fn define_ip_addresses(
     app_name: &str,
     containers: &HashMap<String, types::Container>,
@@ -195,7 +285,7 @@ fn define_ip_addresses(
         {
             service.networks = Some(bmap! {
                 "default" => NetworkEntry {
-                    ipv4_address: Some(format!("$APP_{}_{}_IP", app_name.to_string().to_uppercase().replace('-', "_"), service_name.to_uppercase().replace('-', "_")))
+                    ipv4_address: Some(format!("${}", naming::ip_env_var(app_name, service_name)))
                 }
             })
         } else if service_name == main_container {
@@ -277,11 +367,127 @@ fn validate_service(
         }
         result.cap_add = Some(cap_add);
     }
+    if let Some(logging) = &service.logging {
+        result.logging = Some(convert_logging(logging)?);
+    }
     Ok(())
 }
 
+fn convert_logging(logging: &types::Logging) -> Result<Logging> {
+    let driver = logging.driver.as_deref().unwrap_or("json-file");
+    let supports_rotation = match driver {
+        "json-file" | "local" => true,
+        "journald" | "syslog" | "none" => false,
+        _ => bail!("Log driver {} is not supported", driver),
+    };
+    let mut options = BTreeMap::new();
+    if let Some(max_size) = &logging.max_size {
+        if !LOG_SIZE_REGEX.is_match(max_size) {
+            bail!("Invalid max_size {}, expected a size like 10m", max_size);
+        }
+        options.insert("max-size".to_string(), max_size.clone());
+    }
+    if let Some(max_files) = logging.max_files {
+        if max_files == 0 {
+            bail!("max_files must be at least 1");
+        }
+        options.insert("max-file".to_string(), max_files.to_string());
+    }
+    if !supports_rotation && !options.is_empty() {
+        bail!(
+            "Log driver {} does not support max_size or max_files",
+            driver
+        );
+    }
+    Ok(Logging {
+        driver: driver.to_string(),
+        options,
+    })
+}
+
+fn convert_named_volumes(
+    volumes: &BTreeMap<String, types::NamedVolume>,
+) -> Result<BTreeMap<String, Volume>> {
+    let mut result = BTreeMap::new();
+    for (name, volume) in volumes {
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            bail!("Volume name {} is invalid", name);
+        }
+        let driver_opts = match volume {
+            types::NamedVolume::Nfs {
+                address,
+                device,
+                options,
+            }
+            | types::NamedVolume::Cifs {
+                address,
+                device,
+                options,
+            } => {
+                let is_nfs = matches!(volume, types::NamedVolume::Nfs { .. });
+                if address.is_empty() || address.contains([',', ' ']) {
+                    bail!("Address of volume {} is invalid", name);
+                }
+                if options.iter().any(|option| option.contains(',')) {
+                    bail!("Options of volume {} may not contain ','", name);
+                }
+                if is_nfs && !device.starts_with('/') {
+                    bail!("Device of NFS volume {} must be an absolute path", name);
+                }
+                if !is_nfs && !device.starts_with("//") {
+                    bail!("Device of CIFS volume {} must be //server/share", name);
+                }
+                let mut mount_options = vec![format!("addr={address}")];
+                mount_options.extend(options.iter().cloned());
+                BTreeMap::from([
+                    (
+                        "type".to_string(),
+                        if is_nfs { "nfs" } else { "cifs" }.to_string(),
+                    ),
+                    ("o".to_string(), mount_options.join(",")),
+                    (
+                        "device".to_string(),
+                        if is_nfs {
+                            format!(":{device}")
+                        } else {
+                            device.clone()
+                        },
+                    ),
+                ])
+            }
+            types::NamedVolume::Local { device } => {
+                if !device.starts_with('/') || device.contains("..") {
+                    bail!(
+                        "Device of volume {} must be an absolute path without '..'",
+                        name
+                    );
+                }
+                BTreeMap::from([
+                    ("type".to_string(), "none".to_string()),
+                    ("o".to_string(), "bind".to_string()),
+                    ("device".to_string(), device.clone()),
+                ])
+            }
+        };
+        result.insert(
+            name.clone(),
+            Volume {
+                driver: Some("local".to_string()),
+                driver_opts,
+                labels: BTreeMap::new(),
+            },
+        );
+    }
+    Ok(result)
+}
+
 fn convert_volumes(
     containers: &HashMap<String, types::Container>,
+    named_volumes: &BTreeMap<String, types::NamedVolume>,
     permissions: &[&String],
     output: &mut ComposeSpecification,
 ) -> Result<()> {
@@ -324,6 +530,17 @@ fn convert_volumes(
                             bail!("bitcoin mount defined as map, but only string is supported");
                         }
                     }
+                    "volumes" => {
+                        let StringOrMap::Map(volume_mounts) = value else {
+                            bail!("Volume mounts must be a map");
+                        };
+                        for (volume, container_path) in volume_mounts {
+                            if !named_volumes.contains_key(volume) {
+                                bail!("Volume {} is not defined", volume);
+                            }
+                            service.volumes.push(format!("{volume}:{container_path}"));
+                        }
+                    }
                     "jwt-public-key" => {
                         if let StringOrMap::String(jwt_pubkey_mount) = value {
                             service
@@ -337,9 +554,9 @@ fn convert_volumes(
                         if permissions.contains(&key) {
                             if let StringOrMap::String(string) = value {
                                 service.volumes.push(format!(
-                                    "${{CITADEL_APP_DATA}}/{}/${{APP_{}_SHARED_SUBDIR}}:{}",
+                                    "${{CITADEL_APP_DATA}}/{}/${{{}}}:{}",
                                     key,
-                                    key.to_uppercase().replace('-', "_"),
+                                    naming::shared_subdir_env_var(key),
                                     string
                                 ));
                             } else {
@@ -355,11 +572,127 @@ fn convert_volumes(
                 }
             }
         }
+        for mount in &original_definition.shared_mounts {
+            if !permissions.contains(&&mount.app) {
+                bail!(
+                    "App mounts share {} of {}, but {} is not specified as a permission",
+                    mount.share,
+                    mount.app,
+                    mount.app
+                );
+            }
+            service.volumes.push(format!(
+                "${{CITADEL_APP_DATA}}/{}/${{{}}}:{}{}",
+                mount.app,
+                share_env_var(&mount.app, &mount.share),
+                mount.path,
+                if mount.writable { "" } else { ":ro" }
+            ));
+        }
     }
 
     Ok(())
 }
 
+/// Mounts the socket directories containers use to talk to each other without a port.
+/// They are subdirectories of the providing app's data dir, so they survive restarts of either side.
+fn convert_sockets(
+    app_name: &str,
+    app_sockets: &BTreeSet<String>,
+    containers: &HashMap<String, types::Container>,
+    permissions: &[&String],
+    output: &mut ComposeSpecification,
+) -> Result<()> {
+    for name in app_sockets {
+        if name.is_empty() || name.contains('/') || name.contains("..") {
+            bail!("Invalid socket directory name {}", name);
+        }
+    }
+    let services = output.services.as_mut().unwrap();
+    for (service_name, service) in services {
+        let original_definition = containers.get(service_name).unwrap();
+        for socket in &original_definition.sockets {
+            let app = match &socket.app {
+                Some(app) if app != app_name => {
+                    if !permissions.contains(&app) {
+                        bail!(
+                            "App mounts socket directory {} of {}, but {} is not specified as a permission",
+                            socket.name,
+                            app,
+                            app
+                        );
+                    }
+                    if socket.name.contains('/') || socket.name.contains("..") {
+                        bail!("Invalid socket directory name {}", socket.name);
+                    }
+                    app
+                }
+                _ => {
+                    if !app_sockets.contains(&socket.name) {
+                        bail!(
+                            "Container {} mounts socket directory {}, which is not defined in the metadata",
+                            service_name,
+                            socket.name
+                        );
+                    }
+                    app_name
+                }
+            };
+            service.volumes.push(format!(
+                "${{CITADEL_APP_DATA}}/{}/.sockets/{}:{}",
+                app, socket.name, socket.path
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn get_port_forwards(services: &HashMap<String, types::Container>) -> Result<Vec<ForwardedPort>> {
+    let mut port_forwards = Vec::new();
+    for (service_name, service) in services {
+        for forward in &service.port_forwards {
+            let required_ports =
+                service
+                    .required_ports
+                    .as_ref()
+                    .and_then(|ports| match forward.protocol {
+                        Protocol::Tcp => ports.tcp.as_ref(),
+                        Protocol::Udp => ports.udp.as_ref(),
+                    });
+            let in_range = service.required_ports.as_ref().is_some_and(|ports| {
+                ports.ranges.iter().any(|range| {
+                    range.protocol == forward.protocol && range.ports().contains(&forward.port)
+                })
+            });
+            if !in_range && !required_ports.is_some_and(|ports| ports.contains_key(&forward.port)) {
+                bail!(
+                    "Container {} forwards port {} ({}), which is not one of its required ports",
+                    service_name,
+                    forward.port,
+                    forward.protocol
+                );
+            }
+            port_forwards.push(ForwardedPort {
+                container: service_name.clone(),
+                port: forward.port,
+                protocol: forward.protocol,
+                external_ip: None,
+            });
+        }
+    }
+    port_forwards.sort_by(|a, b| (&a.container, a.port).cmp(&(&b.container, b.port)));
+    Ok(port_forwards)
+}
+
+/// Appends HiddenServicePort lines for raw TCP ports, sorted by their onion port
+fn push_hidden_service_ports(result: &mut String, ports: &HashMap<u16, u16>, target: &str) {
+    let mut ports: Vec<(&u16, &u16)> = ports.iter().collect();
+    ports.sort();
+    for (onion_port, internal_port) in ports {
+        *result += &format!("HiddenServicePort {onion_port} {target}:{internal_port}\n");
+    }
+}
+
 fn get_hidden_services(
     app_name: &str,
     containers: &HashMap<String, types::Container>,
@@ -367,106 +700,96 @@ fn get_hidden_services(
     main_port: u16,
     ip_addresses: &HashMap<String, String>,
     primary_caddy_entry: &Option<&CaddyEntry>,
-) -> (String, Vec<String>) {
+) -> Result<(String, Vec<String>)> {
     let mut result = String::new();
     let mut service_list = Vec::new();
-    for service_name in containers.keys() {
-        let original_definition = containers.get(service_name).unwrap();
-        if original_definition.network_mode == Some("host".to_string()) {
-            continue;
+    let mut service_names: Vec<&String> = containers.keys().collect();
+    // The main hidden service has to come first, the others are sorted to keep torrc stable
+    service_names.sort_by_key(|name| (*name != main_container, *name));
+    let app_name_slug = naming::slug(app_name);
+    let mut add_service = |name: String, result: &mut String| -> Result<()> {
+        if service_list.contains(&name) {
+            bail!("Hidden service {} is defined multiple times", name);
         }
-        let app_name_uppercase = app_name.to_uppercase().replace('-', "_");
-        let service_name_uppercase = service_name.to_uppercase().replace('-', "_");
-        let app_name_slug = app_name.to_lowercase().replace('_', "-");
-        let service_name_slug = service_name.to_lowercase().replace('_', "-");
+        *result += &format!("HiddenServiceDir /var/lib/tor/{name}\n");
+        service_list.push(name);
+        Ok(())
+    };
+    for service_name in service_names {
+        let original_definition = containers.get(service_name).unwrap();
+        let service_name_slug = naming::slug(service_name);
+        // Containers on the host network are reached through the host instead of their own IP
+        let target = if original_definition.network_mode == Some("host".to_string()) {
+            "host.docker.internal".to_string()
+        } else {
+            ip_addresses
+                .get(&naming::ip_env_var(app_name, service_name))
+                .cloned()
+                .unwrap_or_else(|| format!("<app-{app_name_slug}-{service_name_slug}-ip>"))
+        };
         if service_name == main_container {
+            add_service(naming::hidden_service_name(app_name, None), &mut result)?;
             if let Some(primary_caddy_entry) = primary_caddy_entry {
                 debug_assert!(
                     primary_caddy_entry.is_primary,
                     "Caddy entry that is not primary was passed to get_hidden_servies"
                 );
-                let hidden_service_string = format!(
-                    "HiddenServiceDir /var/lib/tor/app-{}\nHiddenServicePort 80 host.docker.internal:{}\n",
-                    app_name_slug,
+                result += &format!(
+                    "HiddenServicePort 80 host.docker.internal:{}\n",
                     primary_caddy_entry.public_port
                 );
-                result += hidden_service_string.as_str();
             } else {
-                let hidden_service_string = format!(
-                    "HiddenServiceDir /var/lib/tor/app-{}\nHiddenServicePort 80 {}:{}\n",
-                    app_name_slug,
-                    ip_addresses
-                        .get(&format!(
-                            "APP_{app_name_uppercase}_{service_name_uppercase}_IP"
-                        ))
-                        .unwrap_or(&format!("<app-{app_name_slug}-{service_name_slug}-ip>")),
-                    main_port
-                );
-                result += hidden_service_string.as_str();
+                result += &format!("HiddenServicePort 80 {target}:{main_port}\n");
             }
-            service_list.push("app-".to_owned() + &app_name_slug);
         }
-        if let Some(hidden_services) = &original_definition.hidden_services {
-            match hidden_services {
-                types::HiddenServices::PortMap(simple_map) => {
-                    if service_name != main_container {
-                        let hidden_service_string = format!(
-                            "HiddenServiceDir /var/lib/tor/app-{app_name_slug}-{service_name_slug}\n"
-                        );
-                        service_list.push(format!("app-{app_name_slug}-{service_name_slug}"));
-                        result += hidden_service_string.as_str();
-                    }
-                    for port in simple_map {
-                        let port_string = format!(
-                            "HiddenServicePort {} {}:{}\n",
-                            port.0,
-                            ip_addresses
-                                .get(&format!(
-                                    "APP_{app_name_uppercase}_{service_name_uppercase}_IP"
-                                ))
-                                .unwrap_or(&format!(
-                                    "<app-{app_name_slug}-{service_name_slug}-ip>"
-                                )),
-                            port.1
+        match &original_definition.hidden_services {
+            Some(types::HiddenServices::PortMap(simple_map)) => {
+                if service_name == main_container {
+                    if simple_map.contains_key(&80) {
+                        bail!(
+                            "Onion port 80 of the main hidden service is reserved for the app's UI"
                         );
-                        result += port_string.as_str();
                     }
+                } else {
+                    add_service(
+                        naming::hidden_service_name(app_name, Some(service_name)),
+                        &mut result,
+                    )?;
                 }
-                types::HiddenServices::LayeredMap(layered_map) => {
-                    for element in layered_map {
-                        let hidden_service_string = format!(
-                            "HiddenServiceDir /var/lib/tor/app-{}-{}\n",
-                            app_name_slug,
-                            element.0.to_lowercase().replace('_', "-")
-                        );
-                        service_list.push(format!(
-                            "app-{}-{}",
-                            app_name_slug,
-                            element.0.to_lowercase().replace('_', "-")
-                        ));
-                        result += hidden_service_string.as_str();
-                        for port in element.1 {
-                            let port_string = format!(
-                                "HiddenServicePort {} {}:{}\n",
-                                port.0,
-                                ip_addresses
-                                    .get(&format!(
-                                        "APP_{app_name_uppercase}_{service_name_uppercase}_IP"
-                                    ))
-                                    .unwrap_or(&format!(
-                                        "<app-{app_name_slug}-{service_name_slug}-ip>"
-                                    )),
-                                port.1
-                            );
-                            result += port_string.as_str();
-                        }
-                    }
+                push_hidden_service_ports(&mut result, simple_map, &target);
+            }
+            Some(types::HiddenServices::LayeredMap(layered_map)) => {
+                let mut layered_map: Vec<_> = layered_map.iter().collect();
+                layered_map.sort_by_key(|(name, _)| *name);
+                for (name, ports) in layered_map {
+                    add_service(
+                        naming::hidden_service_name(app_name, Some(name)),
+                        &mut result,
+                    )?;
+                    push_hidden_service_ports(&mut result, ports, &target);
                 }
             }
+            None => {}
         }
     }
 
-    (result, service_list)
+    Ok((result, service_list))
+}
+
+/// Appends a server tunnel for each port, all of them sharing the keys of the destination
+fn push_i2p_tunnels(
+    result: &mut String,
+    destination: &str,
+    ports: &HashMap<u16, u16>,
+    target: &str,
+) {
+    let mut ports: Vec<(&u16, &u16)> = ports.iter().collect();
+    ports.sort();
+    for (in_port, internal_port) in ports {
+        *result += &format!(
+            "[{destination}-{in_port}]\nhost = {target}\nport = {internal_port}\ninport = {in_port}\nkeys = {destination}.dat\n"
+        );
+    }
 }
 
 fn get_i2p_tunnels(
@@ -476,55 +799,71 @@ fn get_i2p_tunnels(
     main_port: u16,
     ip_addresses: &HashMap<String, String>,
     primary_caddy_entry: &Option<&CaddyEntry>,
-) -> String {
+    expose_ui: bool,
+) -> Result<String> {
     let mut result = String::new();
-    for service_name in containers.keys() {
+    let mut destinations = Vec::new();
+    let mut service_names: Vec<&String> = containers.keys().collect();
+    service_names.sort_by_key(|name| (*name != main_container, *name));
+    let app_name_slug = naming::slug(app_name);
+    let main_destination = naming::hidden_service_name(app_name, None);
+    let mut add_destination = |name: String| -> Result<String> {
+        if destinations.contains(&name) {
+            bail!("I2P destination {} is defined multiple times", name);
+        }
+        destinations.push(name.clone());
+        Ok(name)
+    };
+    for service_name in service_names {
         let original_definition = containers.get(service_name).unwrap();
-        if original_definition.network_mode == Some("host".to_string()) {
-            continue;
+        let service_name_slug = naming::slug(service_name);
+        let target = if original_definition.network_mode == Some("host".to_string()) {
+            "host.docker.internal".to_string()
+        } else {
+            ip_addresses
+                .get(&naming::ip_env_var(app_name, service_name))
+                .cloned()
+                .unwrap_or_else(|| format!("<app-{app_name_slug}-{service_name_slug}-ip>"))
+        };
+        let is_main = service_name == main_container;
+        if is_main && (expose_ui || original_definition.i2p_tunnels.is_some()) {
+            add_destination(main_destination.clone())?;
         }
-        let app_name_uppercase = app_name.to_uppercase().replace('-', "_");
-        let service_name_uppercase = service_name.to_uppercase().replace('-', "_");
-        let app_name_slug = app_name.to_lowercase().replace('_', "-");
-        let service_name_slug = service_name.to_lowercase().replace('_', "-");
-        if service_name == main_container {
-            if let Some(primary_caddy_entry) = primary_caddy_entry {
-                debug_assert!(
-                    primary_caddy_entry.is_primary,
-                    "Caddy entry that is not primary was passed to get_hidden_servies"
-                );
-                let hidden_service_string = format!(
-                    "[app-{}-{}]\nhost = host.docker.internal\nport = {}\nkeys = app-{}-{}.dat\n",
-                    app_name_slug,
-                    service_name_slug,
+        if is_main && expose_ui {
+            let (host, port) = match primary_caddy_entry {
+                Some(primary_caddy_entry) => (
+                    "host.docker.internal".to_string(),
                     primary_caddy_entry.public_port,
-                    app_name_slug,
-                    service_name_slug
-                );
-                result += hidden_service_string.as_str();
-            } else {
-                let hidden_service_string = format!(
-                    "[app-{}-{}]\nhost = {}\nport = {}\nkeys = app-{}-{}.dat\n",
-                    app_name_slug,
-                    service_name_slug,
-                    ip_addresses
-                        .get(&format!(
-                            "APP_{app_name_uppercase}_{service_name_uppercase}_IP"
-                        ))
-                        .unwrap_or(&format!("<app-{app_name_slug}-{service_name_slug}-ip>")),
-                    main_port,
-                    app_name_slug,
-                    service_name_slug
-                );
-                result += hidden_service_string.as_str();
-            }
+                ),
+                None => (target.clone(), main_port),
+            };
+            result += &format!(
+                "[{main_destination}]\nhost = {host}\nport = {port}\nkeys = {main_destination}.dat\n"
+            );
         }
-        if original_definition.hidden_services.is_some() {
-            tracing::info!("Multi-port hidden services are not yet supported for I2P on Citadel!");
+        match &original_definition.i2p_tunnels {
+            Some(types::HiddenServices::PortMap(ports)) => {
+                let destination = if is_main {
+                    main_destination.clone()
+                } else {
+                    add_destination(naming::hidden_service_name(app_name, Some(service_name)))?
+                };
+                push_i2p_tunnels(&mut result, &destination, ports, &target);
+            }
+            Some(types::HiddenServices::LayeredMap(layered_map)) => {
+                let mut layered_map: Vec<_> = layered_map.iter().collect();
+                layered_map.sort_by_key(|(name, _)| *name);
+                for (name, ports) in layered_map {
+                    let destination =
+                        add_destination(naming::hidden_service_name(app_name, Some(name)))?;
+                    push_i2p_tunnels(&mut result, &destination, ports, &target);
+                }
+            }
+            None => {}
         }
     }
 
-    result
+    Ok(result)
 }
 
 fn get_missing_dependencies(required: &[Permissions], installed: &[String]) -> Vec<Permissions> {
@@ -546,20 +885,104 @@ fn get_missing_dependencies(required: &[Permissions], installed: &[String]) -> V
     missing
 }
 
+/// Makes init containers run once, and the other containers wait until they completed.
+/// Containers an init container (indirectly) depends on are started before it instead.
+fn configure_init_containers(
+    containers: &HashMap<String, types::Container>,
+    main_container: &str,
+    output: &mut ComposeSpecification,
+) -> Result<()> {
+    let mut init_containers: Vec<&String> = containers
+        .iter()
+        .filter(|(_, container)| container.kind == types::ContainerKind::Init)
+        .map(|(name, _)| name)
+        .collect();
+    init_containers.sort();
+    let services = output.services.as_mut().unwrap();
+    for init_container in &init_containers {
+        if *init_container == main_container {
+            bail!("The main container can not be an init container");
+        }
+        if containers[*init_container]
+            .restart
+            .as_ref()
+            .is_some_and(|restart| restart != "no")
+        {
+            bail!("Init container {} can not be restarted", init_container);
+        }
+        services.get_mut(*init_container).unwrap().restart = Some("no".to_string());
+    }
+    for init_container in &init_containers {
+        let mut dependencies = BTreeSet::new();
+        let mut queue = vec![*init_container];
+        while let Some(container) = queue.pop() {
+            for dependency in containers
+                .get(container)
+                .and_then(|container| container.depends_on.as_ref())
+                .into_iter()
+                .flatten()
+            {
+                if dependencies.insert(dependency) {
+                    queue.push(dependency);
+                }
+            }
+        }
+        for (service_name, service) in services.iter_mut() {
+            if init_containers.contains(&service_name) || dependencies.contains(service_name) {
+                continue;
+            }
+            let mut conditions = match service.depends_on.take() {
+                Some(DependsOn::Conditions(conditions)) => conditions,
+                Some(DependsOn::List(list)) => list
+                    .into_iter()
+                    .map(|dependency| {
+                        (
+                            dependency,
+                            DependsOnCondition {
+                                condition: "service_started".to_string(),
+                            },
+                        )
+                    })
+                    .collect(),
+                None => BTreeMap::new(),
+            };
+            conditions.insert(
+                init_container.to_string(),
+                DependsOnCondition {
+                    condition: "service_completed_successfully".to_string(),
+                },
+            );
+            service.depends_on = Some(DependsOn::Conditions(conditions));
+        }
+    }
+    Ok(())
+}
+
 pub fn convert_config(
     app_name: &str,
-    app: types::AppYml,
-    port_map: &Option<HashMap<String, HashMap<String, Vec<PortMapElement>>>>,
-    installed_services: &Option<Vec<String>>,
-    ip_addresses: &Option<HashMap<String, String>>,
+    app: AppDefinition,
+    port_map: Option<&HashMap<String, HashMap<String, Vec<PortMapElement>>>>,
+    installed_services: Option<&[String]>,
+    ip_addresses: Option<&HashMap<String, String>>,
 ) -> Result<ResultYml> {
     let mut spec: ComposeSpecification = ComposeSpecification {
         services: Some(BTreeMap::new()),
+        ..Default::default()
     };
     let spec_services = spec.services.get_or_insert(BTreeMap::new());
     let mut permissions = flatten(&app.metadata.permissions);
 
-    let main_service = get_main_container(&app.services)?;
+    let main_service = get_main_container(&app.services, app.metadata.main_container.as_deref())?;
+    for (name, share) in &app.metadata.shares {
+        if share.path.contains("..") {
+            bail!("The path of share {} is not allowed to contain '..'", name);
+        }
+    }
+    if let Some(static_assets) = &app.metadata.static_assets {
+        if !is_valid_static_assets_dir(static_assets) {
+            bail!("The static assets directory must be a relative path inside the app's directory, made of letters, numbers, '-', '_' and '.'");
+        }
+    }
     let mut app_port_map: Option<HashMap<String, Vec<PortMapElement>>> = None;
     if let Some(port_map) = port_map {
         if let Some(app_port_map_entry) = port_map.get(app_name) {
@@ -581,11 +1004,7 @@ pub fn convert_config(
     let main_port = get_main_port(&app.services, main_service, &app_port_map)?;
 
     // Required for dynamic ports
-    let env_var = format!(
-        "APP_{}_{}_PORT",
-        app_name.replace('-', "_").to_uppercase(),
-        main_service.to_uppercase()
-    );
+    let env_var = naming::port_env_var(app_name, main_service);
 
     let replace_env_vars = HashMap::<String, String>::from([
         (env_var, main_port.to_string()),
@@ -607,7 +1026,7 @@ pub fn convert_config(
             stop_signal: service.stop_signal.clone(),
             user: service.user.clone(),
             init: service.init,
-            depends_on: service.depends_on.clone(),
+            depends_on: service.depends_on.clone().map(DependsOn::List),
             extra_hosts: service.extra_hosts.clone(),
             working_dir: service.working_dir.clone(),
             shm_size: service.shm_size.clone(),
@@ -625,11 +1044,32 @@ pub fn convert_config(
         )?;
     }
     // We can now finalize the process by parsing some of the remaining values
-    let caddy_entries = configure_ports(&app.services, main_service, &mut spec, &app_port_map)?;
+    let mut caddy_entries = configure_ports(&app.services, main_service, &mut spec, &app_port_map)?;
+    let ui_entries = configure_ui_entries(
+        &app.metadata.entries,
+        &app.services,
+        &app_port_map,
+        &mut caddy_entries,
+    )?;
+
+    for entry in &mut caddy_entries {
+        entry.priority = app.metadata.route_priority;
+    }
+    caddy_entries.sort_by(CaddyEntry::route_cmp);
 
     define_ip_addresses(app_name, &app.services, main_service, &mut spec)?;
+    configure_init_containers(&app.services, main_service, &mut spec)?;
 
-    convert_volumes(&app.services, &permissions, &mut spec)?;
+    convert_volumes(&app.services, &app.volumes, &permissions, &mut spec)?;
+    convert_sockets(
+        app_name,
+        &app.metadata.sockets,
+        &app.services,
+        &permissions,
+        &mut spec,
+    )?;
+    spec.volumes = convert_named_volumes(&app.volumes)?;
+    let port_forwards = get_port_forwards(&app.services)?;
 
     let mut main_port_host: Option<u16> = None;
     if let Some(converted_map) = app_port_map {
@@ -642,13 +1082,11 @@ pub fn convert_config(
 
     let missing_deps = get_missing_dependencies(
         &app.metadata.permissions,
-        installed_services.as_ref().unwrap_or(&vec![]),
+        installed_services.unwrap_or_default(),
     );
 
-    let mut ips = HashMap::new();
-    if let Some(ip_addresses) = ip_addresses {
-        ips = ip_addresses.clone();
-    }
+    let no_ips = HashMap::new();
+    let ips = ip_addresses.unwrap_or(&no_ips);
 
     let primary_caddy_entry = caddy_entries.iter().find(|entry| entry.is_primary);
     let (new_tor_entries, hidden_services) = get_hidden_services(
@@ -656,9 +1094,9 @@ pub fn convert_config(
         &app.services,
         main_service,
         main_port,
-        &ips,
+        ips,
         &primary_caddy_entry,
-    );
+    )?;
     let mut metadata = OutputMetadata {
         id: app_name.to_string(),
         name: app.metadata.name,
@@ -671,9 +1109,17 @@ pub fn convert_config(
         repo: app.metadata.repo,
         support: app.metadata.support,
         gallery: app.metadata.gallery,
-        path: app.metadata.path,
+        // The dashboard appends the path to the app's URL
+        path: app.metadata.path.map(|path| {
+            if path.starts_with('/') {
+                path
+            } else {
+                format!("/{path}")
+            }
+        }),
         default_username: app.metadata.default_username,
         default_password: app.metadata.default_password,
+        setup_required: app.metadata.setup_required,
         tor_only: app.metadata.tor_only,
         update_containers: app.metadata.update_containers,
         implements: app.metadata.implements,
@@ -685,22 +1131,44 @@ pub fn convert_config(
         release_notes: app.metadata.release_notes,
         supports_https: caddy_entries.iter().any(|entry| entry.is_primary),
         hidden_services,
+        entries: ui_entries,
+        unsupported: Vec::new(),
+        port_forwards,
+        cross_store_dependencies: Vec::new(),
+        health: None,
+        static_assets: app
+            .metadata
+            .static_assets
+            .as_ref()
+            .map(|_| static_assets_path(app_name)),
+        bundles: Vec::new(),
+        settings: app
+            .settings
+            .into_iter()
+            .map(|(id, rule)| OutputSetting {
+                id,
+                rule,
+                value: None,
+            })
+            .collect(),
     };
     if !missing_deps.is_empty() {
         metadata.missing_dependencies = Some(missing_deps);
     }
 
+    let new_i2p_entries = get_i2p_tunnels(
+        app_name,
+        &app.services,
+        main_service,
+        main_port,
+        ips,
+        &primary_caddy_entry,
+        app.metadata.i2p,
+    )?;
     let result = ResultYml {
         spec,
         new_tor_entries,
-        new_i2p_entries: get_i2p_tunnels(
-            app_name,
-            &app.services,
-            main_service,
-            main_port,
-            &ips,
-            &primary_caddy_entry,
-        ),
+        new_i2p_entries,
         metadata,
         caddy_entries,
     };
@@ -715,19 +1183,26 @@ mod test {
     use crate::{
         bmap,
         composegenerator::{
-            output::types::{ComposeSpecification, NetworkEntry, Service},
-            types::{CaddyEntry, OutputMetadata, Permissions, ResultYml},
-            v4::types::{AppYml, Container, InputMetadata},
+            ir::AppDefinition,
+            output::types::{
+                ComposeSpecification, DependsOn, DependsOnCondition, NetworkEntry, Service,
+            },
+            types::{CaddyEntry, OutputMetadata, OutputUiEntry, Permissions, Protocol, ResultYml},
+            v4::types::{
+                Container, ContainerKind, HiddenServices, InputMetadata, NamedVolume,
+                PortMapElement, PortRange, PortsDefinition, SharedMount, SocketMount, StringOrMap,
+                UiEntry,
+            },
         },
         map,
     };
+    use std::collections::{BTreeSet, HashMap};
 
     use pretty_assertions::assert_eq;
 
     #[test]
     fn test_simple_app() {
-        let example_app = AppYml {
-            citadel_version: 4,
+        let example_app = AppDefinition {
             metadata: InputMetadata {
                 name: "Example app".to_string(),
                 version: "1.0.0".to_string(),
@@ -757,9 +1232,10 @@ mod test {
                     user: Some("1000:1000".to_string()),
                     ..Default::default()
                 }
-            }
+            },
+            ..Default::default()
         };
-        let result = convert_config("example-app", example_app, &None, &None, &None);
+        let result = convert_config("example-app", example_app, None, None, None);
         assert!(result.is_ok());
         let expected_result = ResultYml {
             spec: ComposeSpecification {
@@ -767,7 +1243,7 @@ mod test {
                     "main" => Service {
                         image: Some("ghcr.io/runcitadel/example:main".to_string()),
                         user: Some("1000:1000".to_string()),
-                        depends_on: Some(vec!["database".to_string()]),
+                        depends_on: Some(DependsOn::List(vec!["database".to_string()])),
                         ports: vec![],
                         networks: Some(bmap! {
                             "default" => NetworkEntry {
@@ -786,8 +1262,8 @@ mod test {
                         }),
                         ..Default::default()
                     }
-                })
-
+                }),
+                ..Default::default()
             },
             metadata: OutputMetadata {
                 id: "example-app".to_string(),
@@ -813,9 +1289,422 @@ mod test {
                 ..Default::default()
             },
             new_tor_entries: "HiddenServiceDir /var/lib/tor/app-example-app\nHiddenServicePort 80 host.docker.internal:3000\n".to_string(),
-            new_i2p_entries: "[app-example-app-main]\nhost = host.docker.internal\nport = 3000\nkeys = app-example-app-main.dat\n".to_string(),
-            caddy_entries: vec![CaddyEntry { public_port: 3000, internal_port: 3000, container_name: "main".to_string(), is_primary: true }],
+            new_i2p_entries: String::new(),
+            caddy_entries: vec![CaddyEntry { public_port: 3000, internal_port: 3000, container_name: "main".to_string(), is_primary: true, priority: 0 }],
         };
         assert_eq!(expected_result, result.unwrap());
     }
+
+    #[test]
+    fn test_main_container_and_ui_entries() {
+        let example_app = AppDefinition {
+            metadata: InputMetadata {
+                name: "Example app".to_string(),
+                main_container: Some("frontend".to_string()),
+                entries: vec![UiEntry {
+                    name: "Admin".to_string(),
+                    container: "backend".to_string(),
+                    port: 8080,
+                    path: Some("/admin".to_string()),
+                }],
+                ..Default::default()
+            },
+            services: map! {
+                "frontend" => Container {
+                    image: "ghcr.io/runcitadel/example:main".to_string(),
+                    port: Some(3000),
+                    ..Default::default()
+                },
+                "backend" => Container {
+                    image: "ghcr.io/runcitadel/example-backend:main".to_string(),
+                    ..Default::default()
+                }
+            },
+            ..Default::default()
+        };
+        let port_map = HashMap::from([(
+            "example-app".to_string(),
+            HashMap::from([
+                (
+                    "frontend".to_string(),
+                    vec![PortMapElement {
+                        dynamic: false,
+                        internal_port: 3000,
+                        public_port: 3000,
+                    }],
+                ),
+                (
+                    "backend".to_string(),
+                    vec![PortMapElement {
+                        dynamic: false,
+                        internal_port: 8080,
+                        public_port: 8081,
+                    }],
+                ),
+            ]),
+        )]);
+        let result =
+            convert_config("example-app", example_app, Some(&port_map), None, None).unwrap();
+        assert_eq!(result.metadata.port, 3000);
+        assert_eq!(
+            result.metadata.entries,
+            vec![OutputUiEntry {
+                name: "Admin".to_string(),
+                container: "backend".to_string(),
+                port: 8081,
+                internal_port: 8080,
+                path: Some("/admin".to_string()),
+            }]
+        );
+        assert!(result.caddy_entries.contains(&CaddyEntry {
+            public_port: 8081,
+            internal_port: 8080,
+            container_name: "backend".to_string(),
+            is_primary: false,
+            priority: 0,
+        }));
+    }
+
+    #[test]
+    fn test_onboarding_hints() {
+        let example_app = AppDefinition {
+            metadata: InputMetadata {
+                name: "Example app".to_string(),
+                path: Some("admin".to_string()),
+                default_username: Some("admin".to_string()),
+                setup_required: true,
+                ..Default::default()
+            },
+            services: map! {
+                "main" => Container {
+                    image: "ghcr.io/runcitadel/example:main".to_string(),
+                    port: Some(3000),
+                    ..Default::default()
+                }
+            },
+            ..Default::default()
+        };
+        let metadata = convert_config("example-app", example_app, None, None, None)
+            .unwrap()
+            .metadata;
+        assert_eq!(metadata.path.as_deref(), Some("/admin"));
+        assert_eq!(metadata.default_username.as_deref(), Some("admin"));
+        assert!(metadata.setup_required);
+    }
+
+    #[test]
+    fn test_shared_mounts() {
+        let mut example_app = AppDefinition {
+            metadata: InputMetadata {
+                name: "Example app".to_string(),
+                permissions: vec![Permissions::OneDependency("example-store".to_string())],
+                ..Default::default()
+            },
+            services: map! {
+                "main" => Container {
+                    image: "ghcr.io/runcitadel/example:main".to_string(),
+                    port: Some(3000),
+                    shared_mounts: vec![SharedMount {
+                        app: "example-store".to_string(),
+                        share: "files".to_string(),
+                        path: "/files".to_string(),
+                        writable: false,
+                    }],
+                    ..Default::default()
+                }
+            },
+            ..Default::default()
+        };
+        let result = convert_config("example-app", example_app.clone(), None, None, None).unwrap();
+        assert_eq!(
+            result.spec.services.unwrap()["main"].volumes,
+            vec![
+                "${CITADEL_APP_DATA}/example-store/${APP_EXAMPLE_STORE_SHARE_FILES_DIR}:/files:ro"
+            ]
+        );
+
+        example_app.metadata.permissions = vec![];
+        assert!(convert_config("example-app", example_app, None, None, None).is_err());
+    }
+
+    #[test]
+    fn test_sockets() {
+        let mut example_app = AppDefinition {
+            metadata: InputMetadata {
+                name: "Example app".to_string(),
+                permissions: vec![Permissions::OneDependency("bitcoin".to_string())],
+                sockets: BTreeSet::from(["api".to_string()]),
+                ..Default::default()
+            },
+            services: map! {
+                "main" => Container {
+                    image: "ghcr.io/runcitadel/example:main".to_string(),
+                    port: Some(3000),
+                    sockets: vec![
+                        SocketMount {
+                            app: None,
+                            name: "api".to_string(),
+                            path: "/run/api".to_string(),
+                        },
+                        SocketMount {
+                            app: Some("bitcoin".to_string()),
+                            name: "rpc".to_string(),
+                            path: "/run/bitcoin".to_string(),
+                        },
+                    ],
+                    ..Default::default()
+                }
+            },
+            ..Default::default()
+        };
+        let result = convert_config("example-app", example_app.clone(), None, None, None).unwrap();
+        assert_eq!(
+            result.spec.services.unwrap()["main"].volumes,
+            vec![
+                "${CITADEL_APP_DATA}/example-app/.sockets/api:/run/api",
+                "${CITADEL_APP_DATA}/bitcoin/.sockets/rpc:/run/bitcoin"
+            ]
+        );
+
+        example_app.metadata.sockets.clear();
+        assert!(convert_config("example-app", example_app, None, None, None).is_err());
+    }
+
+    #[test]
+    fn test_named_volumes() {
+        let mut example_app = AppDefinition {
+            metadata: InputMetadata {
+                name: "Example app".to_string(),
+                ..Default::default()
+            },
+            services: map! {
+                "main" => Container {
+                    image: "ghcr.io/runcitadel/example:main".to_string(),
+                    port: Some(3000),
+                    mounts: Some(bmap! {
+                        "volumes" => StringOrMap::Map(bmap! {
+                            "media" => "/media".to_string()
+                        })
+                    }),
+                    ..Default::default()
+                }
+            },
+            volumes: bmap! {
+                "media" => NamedVolume::Nfs {
+                    address: "192.168.1.10".to_string(),
+                    device: "/export/media".to_string(),
+                    options: vec!["nfsvers=4".to_string()],
+                }
+            },
+            ..Default::default()
+        };
+        let result = convert_config("example-app", example_app.clone(), None, None, None).unwrap();
+        assert_eq!(
+            result.spec.services.unwrap()["main"].volumes,
+            vec!["media:/media"]
+        );
+        assert_eq!(
+            result.spec.volumes["media"].driver_opts,
+            bmap! {
+                "type" => "nfs".to_string(),
+                "o" => "addr=192.168.1.10,nfsvers=4".to_string(),
+                "device" => ":/export/media".to_string()
+            }
+        );
+
+        example_app.volumes = bmap! {
+            "media" => NamedVolume::Local {
+                device: "/mnt/../etc".to_string(),
+            }
+        };
+        assert!(convert_config("example-app", example_app, None, None, None).is_err());
+    }
+
+    #[test]
+    fn test_port_ranges() {
+        let mut example_app = AppDefinition {
+            metadata: InputMetadata {
+                name: "Example app".to_string(),
+                ..Default::default()
+            },
+            services: map! {
+                "main" => Container {
+                    image: "ghcr.io/runcitadel/example:main".to_string(),
+                    port: Some(3000),
+                    required_ports: Some(PortsDefinition {
+                        ranges: vec![
+                            PortRange {
+                                start: 6881,
+                                end: 6889,
+                                internal_start: None,
+                                protocol: Protocol::Udp,
+                            },
+                            PortRange {
+                                start: 27015,
+                                end: 27016,
+                                internal_start: Some(7000),
+                                protocol: Protocol::Tcp,
+                            },
+                        ],
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }
+            },
+            ..Default::default()
+        };
+        let result = convert_config("example-app", example_app.clone(), None, None, None).unwrap();
+        assert_eq!(
+            result.spec.services.unwrap()["main"].ports,
+            vec!["6881-6889:6881-6889/udp", "27015-27016:7000-7001"]
+        );
+
+        example_app
+            .services
+            .get_mut("main")
+            .unwrap()
+            .required_ports
+            .as_mut()
+            .unwrap()
+            .ranges[0]
+            .end = 6880;
+        assert!(convert_config("example-app", example_app, None, None, None).is_err());
+    }
+
+    #[test]
+    fn test_raw_tcp_hidden_services() {
+        let mut example_app = AppDefinition {
+            metadata: InputMetadata {
+                name: "Example app".to_string(),
+                ..Default::default()
+            },
+            services: map! {
+                "main" => Container {
+                    image: "ghcr.io/runcitadel/example:main".to_string(),
+                    port: Some(3000),
+                    hidden_services: Some(HiddenServices::PortMap(HashMap::from([(8332, 8332)]))),
+                    ..Default::default()
+                },
+                "shell" => Container {
+                    image: "ghcr.io/runcitadel/example-shell:main".to_string(),
+                    hidden_services: Some(HiddenServices::LayeredMap(HashMap::from([
+                        ("ssh".to_string(), HashMap::from([(22, 2222)])),
+                        ("rpc".to_string(), HashMap::from([(9000, 9000), (9001, 9001)])),
+                    ]))),
+                    ..Default::default()
+                }
+            },
+            ..Default::default()
+        };
+        let ips = HashMap::from([(
+            "APP_EXAMPLE_APP_SHELL_IP".to_string(),
+            "10.21.21.20".to_string(),
+        )]);
+        let result =
+            convert_config("example-app", example_app.clone(), None, None, Some(&ips)).unwrap();
+        assert_eq!(
+            result.new_tor_entries,
+            "HiddenServiceDir /var/lib/tor/app-example-app\nHiddenServicePort 80 host.docker.internal:3000\nHiddenServicePort 8332 <app-example-app-main-ip>:8332\nHiddenServiceDir /var/lib/tor/app-example-app-rpc\nHiddenServicePort 9000 10.21.21.20:9000\nHiddenServicePort 9001 10.21.21.20:9001\nHiddenServiceDir /var/lib/tor/app-example-app-ssh\nHiddenServicePort 22 10.21.21.20:2222\n"
+        );
+        assert_eq!(
+            result.metadata.hidden_services,
+            vec![
+                "app-example-app",
+                "app-example-app-rpc",
+                "app-example-app-ssh"
+            ]
+        );
+
+        example_app
+            .services
+            .get_mut("main")
+            .unwrap()
+            .hidden_services = Some(HiddenServices::PortMap(HashMap::from([(80, 8080)])));
+        assert!(convert_config("example-app", example_app, None, None, None).is_err());
+    }
+
+    #[test]
+    fn test_i2p_tunnels() {
+        let mut example_app = AppDefinition {
+            metadata: InputMetadata {
+                name: "Example app".to_string(),
+                i2p: true,
+                ..Default::default()
+            },
+            services: map! {
+                "main" => Container {
+                    image: "ghcr.io/runcitadel/example:main".to_string(),
+                    port: Some(3000),
+                    ..Default::default()
+                },
+                "node" => Container {
+                    image: "ghcr.io/runcitadel/example-node:main".to_string(),
+                    i2p_tunnels: Some(HiddenServices::PortMap(HashMap::from([(8333, 8333)]))),
+                    ..Default::default()
+                }
+            },
+            ..Default::default()
+        };
+        let result = convert_config("example-app", example_app.clone(), None, None, None).unwrap();
+        assert_eq!(
+            result.new_i2p_entries,
+            "[app-example-app]\nhost = host.docker.internal\nport = 3000\nkeys = app-example-app.dat\n[app-example-app-node-8333]\nhost = <app-example-app-node-ip>\nport = 8333\ninport = 8333\nkeys = app-example-app-node.dat\n"
+        );
+
+        example_app.metadata.i2p = false;
+        example_app.services.get_mut("main").unwrap().i2p_tunnels =
+            Some(HiddenServices::LayeredMap(HashMap::from([(
+                "node".to_string(),
+                HashMap::from([(18333, 18333)]),
+            )])));
+        assert!(convert_config("example-app", example_app, None, None, None).is_err());
+    }
+
+    #[test]
+    fn test_init_containers() {
+        let mut example_app = AppDefinition {
+            metadata: InputMetadata {
+                name: "Example app".to_string(),
+                ..Default::default()
+            },
+            services: map! {
+                "main" => Container {
+                    image: "ghcr.io/runcitadel/example:main".to_string(),
+                    depends_on: Some(vec!["database".to_string()]),
+                    port: Some(3000),
+                    ..Default::default()
+                },
+                "database" => Container {
+                    image: "ghcr.io/runcitadel/example-db:main".to_string(),
+                    ..Default::default()
+                },
+                "migrate" => Container {
+                    kind: ContainerKind::Init,
+                    image: "ghcr.io/runcitadel/example:main".to_string(),
+                    depends_on: Some(vec!["database".to_string()]),
+                    ..Default::default()
+                }
+            },
+            ..Default::default()
+        };
+        let result = convert_config("example-app", example_app.clone(), None, None, None).unwrap();
+        let services = result.spec.services.unwrap();
+        assert_eq!(services["migrate"].restart.as_deref(), Some("no"));
+        // The database is needed by the init container, so it does not wait for it
+        assert_eq!(services["database"].depends_on, None);
+        assert_eq!(
+            services["main"].depends_on,
+            Some(DependsOn::Conditions(bmap! {
+                "database" => DependsOnCondition {
+                    condition: "service_started".to_string()
+                },
+                "migrate" => DependsOnCondition {
+                    condition: "service_completed_successfully".to_string()
+                }
+            }))
+        );
+
+        example_app.services.get_mut("migrate").unwrap().restart = Some("always".to_string());
+        assert!(convert_config("example-app", example_app, None, None, None).is_err());
+    }
 }
@@ -0,0 +1,258 @@
+//! Deprecated app.yml fields and a mechanical migration to their replacements.
+//!
+//! The migration works on the text of the file instead of the parsed YAML,
+//! so comments, key order and formatting are preserved.
+
+use serde::Serialize;
+
+/// A key that is still accepted, but should be replaced by another one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeprecatedField {
+    /// The keys of the mappings containing the field, list items are "-"
+    pub parent: &'static [&'static str],
+    pub name: &'static str,
+    pub replacement: &'static str,
+}
+
+/// All deprecated fields of the app.yml v4 format
+pub const DEPRECATED_FIELDS: &[DeprecatedField] = &[DeprecatedField {
+    parent: &["metadata"],
+    name: "main_container",
+    replacement: "mainContainer",
+}];
+
+/// A deprecated field found in an app.yml
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Deprecation {
+    /// 1-based line number
+    pub line: usize,
+    /// The full path of the field, like metadata.main_container
+    pub path: String,
+    pub replacement: String,
+}
+
+impl std::fmt::Display for Deprecation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "line {}: {} is deprecated, use {} instead",
+            self.line, self.path, self.replacement
+        )
+    }
+}
+
+/// A mapping key on a line and its byte range in the line, the start is also its indentation
+struct Key<'a> {
+    name: &'a str,
+    start: usize,
+    end: usize,
+    block_scalar: bool,
+}
+
+/// Splits a line into list markers and the mapping key it contains, if any.
+/// Returns the indentation of the list items and the key.
+fn parse_line(line: &str) -> (Vec<usize>, Option<Key<'_>>) {
+    let mut pos = line.len() - line.trim_start_matches(' ').len();
+    let mut list_items = Vec::new();
+    while line[pos..].starts_with("- ") || &line[pos..] == "-" {
+        list_items.push(pos);
+        pos += 1;
+        pos += line[pos..].len() - line[pos..].trim_start_matches(' ').len();
+    }
+    let rest = &line[pos..];
+    let (name, len) = match rest.chars().next() {
+        Some(quote @ ('"' | '\'')) => match rest[1..].find(quote) {
+            Some(end) => (&rest[1..end + 1], end + 2),
+            None => return (list_items, None),
+        },
+        Some(c) if c != '#' && c != '{' && c != '[' => {
+            let len = rest.find(": ").unwrap_or_else(|| {
+                if rest.trim_end().ends_with(':') {
+                    rest.trim_end().len() - 1
+                } else {
+                    rest.len()
+                }
+            });
+            (&rest[..len], len)
+        }
+        _ => return (list_items, None),
+    };
+    let after = &rest[len..];
+    if !(after.starts_with(": ") || after.trim_end() == ":") {
+        return (list_items, None);
+    }
+    let value = after[1..].trim_start();
+    (
+        list_items,
+        Some(Key {
+            name,
+            start: pos,
+            end: pos + len,
+            block_scalar: value.starts_with('|') || value.starts_with('>'),
+        }),
+    )
+}
+
+/// Walks all block mapping keys of a YAML document and calls `visit` with the path
+/// of the mappings containing the key and the key itself
+fn visit_keys(contents: &str, mut visit: impl FnMut(usize, &[&str], &Key)) {
+    let mut stack: Vec<(usize, &str)> = Vec::new();
+    // Lines more indented than this belong to a block scalar
+    let mut block_scalar_indent = None;
+    for (index, line) in contents.lines().enumerate() {
+        let trimmed = line.trim_start_matches(' ');
+        let indent = line.len() - trimmed.len();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        if let Some(block_indent) = block_scalar_indent {
+            if indent > block_indent {
+                continue;
+            }
+            block_scalar_indent = None;
+        }
+        if trimmed.starts_with("---") || trimmed.starts_with("...") {
+            stack.clear();
+            continue;
+        }
+        let (list_items, key) = parse_line(line);
+        let first_indent = list_items
+            .first()
+            .copied()
+            .or(key.as_ref().map(|key| key.start));
+        if let Some(first_indent) = first_indent {
+            while stack
+                .last()
+                .is_some_and(|(indent, _)| *indent >= first_indent)
+            {
+                stack.pop();
+            }
+        }
+        for item_indent in list_items {
+            stack.push((item_indent, "-"));
+        }
+        let Some(key) = key else {
+            continue;
+        };
+        let parent: Vec<&str> = stack.iter().map(|(_, name)| *name).collect();
+        visit(index, &parent, &key);
+        if key.block_scalar {
+            block_scalar_indent = Some(key.start);
+        }
+        stack.push((key.start, key.name));
+    }
+}
+
+fn find_deprecated_field(parent: &[&str], name: &str) -> Option<&'static DeprecatedField> {
+    DEPRECATED_FIELDS
+        .iter()
+        .find(|field| field.name == name && field.parent == parent)
+}
+
+/// Finds deprecated fields, with the byte range of the key in its line
+fn find_with_positions(contents: &str) -> Vec<(Deprecation, usize, usize)> {
+    let mut deprecations = Vec::new();
+    visit_keys(contents, |index, parent, key| {
+        if let Some(field) = find_deprecated_field(parent, key.name) {
+            let mut path = parent.to_vec();
+            path.push(key.name);
+            deprecations.push((
+                Deprecation {
+                    line: index + 1,
+                    path: path.join("."),
+                    replacement: field.replacement.to_string(),
+                },
+                key.start,
+                key.end,
+            ));
+        }
+    });
+    deprecations
+}
+
+/// Finds all deprecated fields in an app.yml
+pub fn find_deprecations(contents: &str) -> Vec<Deprecation> {
+    find_with_positions(contents)
+        .into_iter()
+        .map(|(deprecation, ..)| deprecation)
+        .collect()
+}
+
+/// Finds the 1-based line of a field, like services.main.mounts.
+/// If the field itself is not in the file, the line of its closest parent is returned.
+pub fn find_line(contents: &str, field: &str) -> Option<usize> {
+    let path: Vec<&str> = field.split('.').collect();
+    let mut best: Option<(usize, usize)> = None;
+    visit_keys(contents, |index, parent, key| {
+        let parent: Vec<&str> = parent.iter().copied().filter(|name| *name != "-").collect();
+        let depth = parent.len() + 1;
+        if depth <= path.len()
+            && parent == path[..depth - 1]
+            && key.name == path[depth - 1]
+            && best.is_none_or(|(best_depth, _)| depth > best_depth)
+        {
+            best = Some((depth, index + 1));
+        }
+    });
+    best.map(|(_, line)| line)
+}
+
+/// Replaces deprecated fields in an app.yml by their replacements,
+/// without changing anything else in the file
+pub fn fix_deprecations(contents: &str) -> (String, Vec<Deprecation>) {
+    let found = find_with_positions(contents);
+    let mut fixed = String::with_capacity(contents.len());
+    for (index, line) in contents.split_inclusive('\n').enumerate() {
+        match found
+            .iter()
+            .find(|(deprecation, ..)| deprecation.line == index + 1)
+        {
+            Some((deprecation, start, end)) => {
+                fixed.push_str(&line[..*start]);
+                fixed.push_str(&deprecation.replacement);
+                fixed.push_str(&line[*end..]);
+            }
+            None => fixed.push_str(line),
+        }
+    }
+    (
+        fixed,
+        found
+            .into_iter()
+            .map(|(deprecation, ..)| deprecation)
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::{find_deprecations, fix_deprecations};
+
+    #[test]
+    fn migrates_deprecated_fields() {
+        let app_yml = r#"citadel_version: 4
+metadata:
+  name: Example
+  # The UI is served by web
+  main_container: web # not the API
+  description: |
+    main_container: this is not a key
+services:
+  web:
+    image: example/web
+    environment:
+      - main_container: not in metadata
+"#;
+        let deprecations = find_deprecations(app_yml);
+        assert_eq!(deprecations.len(), 1);
+        assert_eq!(deprecations[0].line, 5);
+        assert_eq!(deprecations[0].path, "metadata.main_container");
+
+        let (fixed, _) = fix_deprecations(app_yml);
+        assert_eq!(
+            fixed,
+            app_yml.replace("  main_container: web", "  mainContainer: web")
+        );
+        assert!(find_deprecations(&fixed).is_empty());
+    }
+}
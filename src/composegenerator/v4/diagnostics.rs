@@ -0,0 +1,277 @@
+//! Semantic checks of an app.yml that go beyond what the types enforce,
+//! reported with the field they are about instead of failing on the first problem.
+
+use std::collections::{BTreeMap, HashMap};
+
+use serde::Serialize;
+
+use super::deprecations::find_line;
+use super::types::{PortsDefinition, StringOrMap};
+use super::utils::get_main_container;
+use crate::composegenerator::ir::AppDefinition;
+use crate::composegenerator::types::Protocol;
+use crate::utils::flatten;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl std::fmt::Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+/// A problem found in an app.yml
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// The full path of the field, like services.main.mounts.data
+    pub field: String,
+    /// 1-based line number of the field, if it could be found
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: ", self.severity)?;
+        if !self.field.is_empty() {
+            write!(f, "{}: ", self.field)?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+impl Diagnostic {
+    /// A problem the conversion fails on, an empty field means the whole file
+    pub fn error(field: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Error,
+            field: field.into(),
+            line: None,
+            message: message.into(),
+        }
+    }
+
+    pub fn warning(field: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Warning,
+            ..Self::error(field, message)
+        }
+    }
+}
+
+/// Public ports required by a container, with the field they are defined in
+fn public_ports(container: &str, ports: &PortsDefinition) -> Vec<(Protocol, u16, String)> {
+    let mut result = Vec::new();
+    let field = |kind: &str| format!("services.{container}.required_ports.{kind}");
+    for port in ports.tcp.iter().flat_map(HashMap::keys) {
+        result.push((Protocol::Tcp, *port, field("tcp")));
+    }
+    for port in ports.http.iter().flat_map(HashMap::keys) {
+        result.push((Protocol::Tcp, *port, field("http")));
+    }
+    for port in ports.udp.iter().flat_map(HashMap::keys) {
+        result.push((Protocol::Udp, *port, field("udp")));
+    }
+    for range in &ports.ranges {
+        for port in range.start..=range.end {
+            result.push((range.protocol, port, field("ranges")));
+        }
+    }
+    result
+}
+
+fn check_ports(app_yml: &AppDefinition, diagnostics: &mut Vec<Diagnostic>) {
+    let mut used: BTreeMap<(Protocol, u16), String> = BTreeMap::new();
+    let mut containers: Vec<_> = app_yml.services.iter().collect();
+    containers.sort_by_key(|(name, _)| *name);
+    for (name, container) in containers {
+        let Some(ports) = &container.required_ports else {
+            continue;
+        };
+        let mut ports = public_ports(name, ports);
+        ports.sort_by(|a, b| a.1.cmp(&b.1).then_with(|| a.2.cmp(&b.2)));
+        for (protocol, port, field) in ports {
+            if let Some(other) = used.get(&(protocol, port)) {
+                diagnostics.push(Diagnostic::error(
+                    field,
+                    format!("Port {port} is also required by {other}"),
+                ));
+            } else {
+                used.insert((protocol, port), field);
+            }
+        }
+    }
+}
+
+fn check_mounts(app_yml: &AppDefinition, diagnostics: &mut Vec<Diagnostic>) {
+    let permissions = flatten(&app_yml.metadata.permissions);
+    let has_permission = |app: &str| permissions.iter().any(|permission| *permission == app);
+    for (name, container) in &app_yml.services {
+        for (key, value) in container.mounts.iter().flatten() {
+            let field = format!("services.{name}.mounts.{key}");
+            let problem = match (key.as_str(), value) {
+                ("data" | "shared_data", StringOrMap::Map(mounts)) => mounts
+                    .keys()
+                    .find(|host_path| host_path.contains(".."))
+                    .map(|host_path| format!("{host_path} must not contain '..'")),
+                ("data" | "shared_data", StringOrMap::String(_)) => {
+                    Some("Data mounts must be a map".to_string())
+                }
+                ("bitcoin", _) if !has_permission("bitcoind") => {
+                    Some("The bitcoin mount requires the bitcoind permission".to_string())
+                }
+                ("bitcoin" | "jwt-public-key", StringOrMap::Map(_)) => {
+                    Some(format!("The {key} mount must be a string"))
+                }
+                ("bitcoin" | "jwt-public-key", StringOrMap::String(_)) => None,
+                ("volumes", StringOrMap::Map(mounts)) => mounts
+                    .keys()
+                    .find(|volume| !app_yml.volumes.contains_key(*volume))
+                    .map(|volume| format!("Volume {volume} is not defined")),
+                ("volumes", StringOrMap::String(_)) => {
+                    Some("Volume mounts must be a map".to_string())
+                }
+                (app, _) if !has_permission(app) => Some(format!(
+                    "{app} is not a known mount and not listed in the permissions"
+                )),
+                (_, StringOrMap::Map(_)) => Some(format!("The {key} mount must be a string")),
+                (_, StringOrMap::String(_)) => None,
+            };
+            if let Some(problem) = problem {
+                diagnostics.push(Diagnostic::error(field, problem));
+            }
+        }
+        for (index, mount) in container.shared_mounts.iter().enumerate() {
+            if !has_permission(&mount.app) {
+                diagnostics.push(Diagnostic::error(
+                    format!("services.{name}.shared_mounts.{index}"),
+                    format!("{} is not listed in the permissions", mount.app),
+                ));
+            }
+        }
+    }
+}
+
+fn check_containers(app_yml: &AppDefinition, diagnostics: &mut Vec<Diagnostic>) {
+    if app_yml.services.is_empty() {
+        diagnostics.push(Diagnostic::error("services", "The app has no containers"));
+        return;
+    }
+    if let Err(err) = get_main_container(
+        &app_yml.services,
+        app_yml.metadata.main_container.as_deref(),
+    ) {
+        let field = if app_yml.metadata.main_container.is_some() {
+            "metadata.mainContainer"
+        } else {
+            "services"
+        };
+        diagnostics.push(Diagnostic::error(field, err.to_string()));
+    }
+    for (name, container) in &app_yml.services {
+        for dependency in container.depends_on.iter().flatten() {
+            if !app_yml.services.contains_key(dependency) {
+                diagnostics.push(Diagnostic::error(
+                    format!("services.{name}.depends_on"),
+                    format!("Container {dependency} does not exist"),
+                ));
+            }
+        }
+    }
+}
+
+fn check_settings(app_yml: &AppDefinition, diagnostics: &mut Vec<Diagnostic>) {
+    for (key, rule) in &app_yml.settings {
+        for problem in rule.problems() {
+            diagnostics.push(Diagnostic::error(format!("settings.{key}"), problem));
+        }
+    }
+}
+
+/// Checks an app.yml for problems the conversion would fail on.
+/// `contents` is the file the app was loaded from, to find the lines of the fields.
+pub fn check(app_yml: &AppDefinition, contents: &str) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    check_containers(app_yml, &mut diagnostics);
+    check_ports(app_yml, &mut diagnostics);
+    check_mounts(app_yml, &mut diagnostics);
+    check_settings(app_yml, &mut diagnostics);
+    for diagnostic in &mut diagnostics {
+        diagnostic.line = find_line(contents, &diagnostic.field);
+    }
+    diagnostics.sort_by(|a, b| {
+        a.line
+            .cmp(&b.line)
+            .then_with(|| a.field.cmp(&b.field))
+            .then_with(|| a.message.cmp(&b.message))
+    });
+    diagnostics
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check, Severity};
+    use crate::composegenerator::v4::types::AppYml;
+
+    #[test]
+    fn finds_semantic_problems() {
+        let contents = "citadel_version: 4
+metadata:
+  name: Example
+  version: 1.0.0
+  category: Example
+  tagline: Example
+  developers: {}
+  permissions: []
+  repo: {}
+  support: https://t.me/citadeldevelopers
+  description: Example
+  mainContainer: web
+services:
+  main:
+    image: example:1.0.0
+    mounts:
+      data:
+        ../secrets: /secrets
+      bitcoin: /bitcoin
+    required_ports:
+      tcp:
+        8333: 8333
+  worker:
+    image: example:1.0.0
+    depends_on:
+      - db
+    required_ports:
+      http:
+        8333: 80
+";
+        let app_yml: AppYml = serde_yaml::from_str(contents).unwrap();
+        let diagnostics = check(&app_yml.into(), contents);
+        let found: Vec<String> = diagnostics
+            .iter()
+            .map(|diagnostic| format!("{}: {}", diagnostic.line.unwrap(), diagnostic))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                "12: error: metadata.mainContainer: Main container web does not exist",
+                "17: error: services.main.mounts.data: ../secrets must not contain '..'",
+                "19: error: services.main.mounts.bitcoin: The bitcoin mount requires the bitcoind permission",
+                "25: error: services.worker.depends_on: Container db does not exist",
+                "28: error: services.worker.required_ports.http: Port 8333 is also required by services.main.required_ports.tcp",
+            ]
+        );
+        assert!(diagnostics
+            .iter()
+            .all(|diagnostic| diagnostic.severity == Severity::Error));
+    }
+}
@@ -1,5 +1,8 @@
 pub mod convert;
+pub mod deprecations;
+pub mod diagnostics;
 pub mod permissions;
+pub mod settings;
 pub mod types;
 #[cfg(feature = "docker")]
 pub mod update;
@@ -0,0 +1,181 @@
+//! Checks of the values operators set for the settings an app.yml declares rules for
+
+use std::collections::{BTreeMap, HashMap};
+
+use regex::Regex;
+
+use super::types::{SettingRule, SettingType};
+
+impl SettingRule {
+    /// The pattern, anchored so it has to match the whole value
+    fn regex(&self) -> Option<Result<Regex, regex::Error>> {
+        self.pattern
+            .as_ref()
+            .map(|pattern| Regex::new(&format!("^(?:{pattern})$")))
+    }
+
+    /// Problems with the rule itself, which no value could fix
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        // Errors quote the pattern, so it is checked as written
+        if let Some(Err(err)) = self.pattern.as_deref().map(Regex::new) {
+            problems.push(format!("The pattern is invalid: {err}"));
+        }
+        if let (Some(min), Some(max)) = (self.min, self.max) {
+            if min > max {
+                problems.push(format!(
+                    "The minimum {min} is larger than the maximum {max}"
+                ));
+            }
+        }
+        if let Some(problem) = self
+            .default
+            .as_deref()
+            .and_then(|default| self.check(default))
+        {
+            problems.push(format!("The default {problem}"));
+        }
+        problems
+    }
+
+    /// Why a value breaks the rule, None if it doesn't
+    pub fn check(&self, value: &str) -> Option<String> {
+        if let Some(Ok(regex)) = self.regex() {
+            if !regex.is_match(value) {
+                return Some(format!(
+                    "does not match {}",
+                    self.pattern.as_deref().unwrap_or_default()
+                ));
+            }
+        }
+        if !self.values.is_empty() && !self.values.iter().any(|allowed| allowed == value) {
+            return Some(format!("must be one of {}", self.values.join(", ")));
+        }
+        if self.kind == SettingType::Boolean && value != "true" && value != "false" {
+            return Some("must be true or false".to_string());
+        }
+        if self.kind == SettingType::Number || self.min.is_some() || self.max.is_some() {
+            let Ok(number) = value.trim().parse::<f64>() else {
+                return Some("must be a number".to_string());
+            };
+            if let Some(min) = self.min.filter(|min| number < *min as f64) {
+                return Some(format!("must be at least {min}"));
+            }
+            if let Some(max) = self.max.filter(|max| number > *max as f64) {
+                return Some(format!("must be at most {max}"));
+            }
+        }
+        None
+    }
+}
+
+/// Checks the values of all settings in `env_vars`, returns what is wrong with them.
+/// Empty values count as not set, settings that are not set have their default.
+pub fn check_values(
+    settings: &BTreeMap<String, SettingRule>,
+    env_vars: &HashMap<String, String>,
+) -> Vec<String> {
+    let mut problems = Vec::new();
+    for (key, rule) in settings {
+        let name = match &rule.description {
+            Some(description) => format!("{key} ({description})"),
+            None => key.clone(),
+        };
+        if let Some(problem) = rule.problems().first() {
+            problems.push(format!("The rule for {name} is invalid: {problem}"));
+            continue;
+        }
+        match env_vars
+            .get(key)
+            .filter(|value| !value.is_empty())
+            .or(rule.default.as_ref())
+        {
+            Some(value) => {
+                if let Some(problem) = rule.check(value) {
+                    problems.push(format!("{name} is \"{value}\", but it {problem}"));
+                }
+            }
+            None if rule.required => problems.push(format!("{name} is not set")),
+            None => {}
+        }
+    }
+    problems
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::{BTreeMap, HashMap};
+
+    use super::check_values;
+    use crate::composegenerator::v4::types::SettingRule;
+
+    #[test]
+    fn checks_setting_values() {
+        let settings: BTreeMap<String, SettingRule> = serde_yaml::from_str(
+            "
+APP_LND_ALIAS:
+  description: The name of the node
+  pattern: '[a-z]+'
+APP_LND_NETWORK:
+  values: [mainnet, testnet]
+APP_LND_FEE_RATE:
+  min: 1
+  max: 100
+APP_LND_PEERS:
+  required: true
+APP_LND_AUTOPILOT:
+  type: boolean
+  default: false
+APP_LND_BROKEN:
+  pattern: '[a-z'
+",
+        )
+        .unwrap();
+        let env_vars = |values: &[(&str, &str)]| -> HashMap<String, String> {
+            values
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect()
+        };
+        let valid = env_vars(&[
+            ("APP_LND_ALIAS", "citadel"),
+            ("APP_LND_NETWORK", "mainnet"),
+            ("APP_LND_FEE_RATE", "2.5"),
+            ("APP_LND_PEERS", "3"),
+        ]);
+        let problems = check_values(&settings, &valid);
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0]
+            .starts_with("The rule for APP_LND_BROKEN is invalid: The pattern is invalid"));
+
+        let settings: BTreeMap<String, SettingRule> = settings
+            .into_iter()
+            .filter(|(key, _)| key != "APP_LND_BROKEN")
+            .collect();
+        assert!(check_values(&settings, &valid).is_empty());
+        let invalid = env_vars(&[
+            ("APP_LND_ALIAS", "Citadel"),
+            ("APP_LND_NETWORK", "regtest"),
+            ("APP_LND_FEE_RATE", "fast"),
+            ("APP_LND_PEERS", ""),
+            ("APP_LND_AUTOPILOT", "yes"),
+        ]);
+        assert_eq!(
+            check_values(&settings, &invalid),
+            vec![
+                "APP_LND_ALIAS (The name of the node) is \"Citadel\", but it does not match [a-z]+",
+                "APP_LND_AUTOPILOT is \"yes\", but it must be true or false",
+                "APP_LND_FEE_RATE is \"fast\", but it must be a number",
+                "APP_LND_NETWORK is \"regtest\", but it must be one of mainnet, testnet",
+                "APP_LND_PEERS is not set",
+            ]
+        );
+        let too_high = env_vars(&[("APP_LND_FEE_RATE", "101"), ("APP_LND_PEERS", "3")]);
+        assert_eq!(
+            check_values(&settings, &too_high),
+            vec!["APP_LND_FEE_RATE is \"101\", but it must be at most 100"]
+        );
+        let rule: SettingRule = serde_yaml::from_str("{type: number, default: fast}").unwrap();
+        assert_eq!(rule.problems(), vec!["The default must be a number"]);
+    }
+}
@@ -1,11 +1,11 @@
 #[cfg(feature = "schema")]
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 
-use crate::composegenerator::compose::types::{Command, StringOrIntOrBool, StringOrInt};
-use crate::composegenerator::types::Permissions;
-use crate::utils::is_false;
+use crate::composegenerator::compose::types::{Command, StringOrInt, StringOrIntOrBool};
+use crate::composegenerator::types::{HardwareCapability, Permissions, PortForward, Protocol};
+use crate::utils::{is_false, is_zero};
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 #[cfg_attr(feature = "schema", derive(JsonSchema))]
@@ -21,6 +21,31 @@ pub struct PortsDefinition {
     pub tcp: Option<HashMap<u16, u16>>,
     pub http: Option<HashMap<u16, u16>>,
     pub udp: Option<HashMap<u16, u16>>,
+    /// Contiguous port ranges, which are reserved as a whole
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ranges: Vec<PortRange>,
+}
+
+/// A range of ports like 6881-6889/udp, for apps like torrent clients or game servers
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct PortRange {
+    /// The first public port
+    pub start: u16,
+    /// The last public port, inclusive
+    pub end: u16,
+    /// The port in the container the range is mapped to, defaults to the start of the range
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub internal_start: Option<u16>,
+    #[serde(default)]
+    pub protocol: Protocol,
+}
+
+impl PortRange {
+    /// The public ports of the range
+    pub fn ports(&self) -> std::ops::RangeInclusive<u16> {
+        self.start..=self.end
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
@@ -42,9 +67,30 @@ pub enum StringOrMap {
     Map(BTreeMap<String, String>),
 }
 
+#[derive(Serialize, Deserialize, Clone, Copy, Default, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum ContainerKind {
+    /// A long-running container
+    #[default]
+    Service,
+    /// A one-shot setup job, like initializing a database or generating keys.
+    /// The other containers of the app wait until it completed successfully.
+    Init,
+}
+
+impl ContainerKind {
+    pub fn is_service(&self) -> bool {
+        *self == ContainerKind::Service
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq, Eq)]
 #[cfg_attr(feature = "schema", derive(JsonSchema))]
 pub struct Container {
+    /// Whether this is a normal container or a one-shot init job
+    #[serde(default, skip_serializing_if = "ContainerKind::is_service")]
+    pub kind: ContainerKind,
     // These can be copied directly without validation
     pub image: String,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -88,12 +134,113 @@ pub struct Container {
     pub assign_fixed_ip: Option<bool>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub hidden_services: Option<HiddenServices>,
+    /// Ports to expose over I2P, in the same format as hidden_services
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub i2p_tunnels: Option<HiddenServices>,
     #[serde(default = "bool::default")]
     #[serde(skip_serializing_if = "is_false")]
     /// Set this to true to avoid having Caddy in front
     pub direct_tcp: bool,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub shm_size: Option<StringOrInt>,
+    /// Shares of other apps to mount into this container
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub shared_mounts: Vec<SharedMount>,
+    /// Socket directories to mount into this container
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sockets: Vec<SocketMount>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logging: Option<Logging>,
+    /// Security profiles to confine this container with.
+    /// These are only applied if the app comes from a trusted store.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub security_profiles: Option<SecurityProfiles>,
+    /// Required ports to forward on the router via UPnP or NAT-PMP, if the node allows it
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub port_forwards: Vec<PortForward>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct SecurityProfiles {
+    /// An AppArmor profile in the app directory.
+    /// The profile must be named citadel-<app id>-<container>.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub apparmor: Option<String>,
+    /// A seccomp profile (JSON) in the app directory
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seccomp: Option<String>,
+    /// The SELinux type to run the container as
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub selinux_type: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct Logging {
+    /// The log driver, defaults to json-file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub driver: Option<String>,
+    /// Maximum size of a log file before it is rotated, like 10m
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_size: Option<String>,
+    /// Maximum number of rotated log files to keep
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_files: Option<u32>,
+}
+
+impl Logging {
+    /// Fills in unset values from `defaults`.
+    /// If a different driver than the default one is used, the default options are ignored.
+    pub fn with_defaults(&self, defaults: &Logging) -> Logging {
+        let driver = self.driver.as_deref().unwrap_or("json-file");
+        let default_driver = defaults.driver.as_deref().unwrap_or("json-file");
+        if driver != default_driver {
+            return self.clone();
+        }
+        Logging {
+            driver: self.driver.clone().or_else(|| defaults.driver.clone()),
+            max_size: self.max_size.clone().or_else(|| defaults.max_size.clone()),
+            max_files: self.max_files.or(defaults.max_files),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct SharedMount {
+    /// The app exporting the share, needs to be listed in the permissions
+    pub app: String,
+    /// The name of the share
+    pub share: String,
+    /// Where to mount the share inside the container
+    pub path: String,
+    /// Mount the share read-write, only possible if the share is writable
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub writable: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct SocketMount {
+    /// The app providing the socket directory, defaults to this app.
+    /// Other apps need to be listed in the permissions.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub app: Option<String>,
+    /// The name of the socket directory
+    pub name: String,
+    /// Where to mount the directory inside the container
+    pub path: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct ShareDefinition {
+    /// The directory to share, relative to the app's data dir
+    pub path: String,
+    /// Allow other apps to mount this share read-write
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub writable: bool,
 }
 
 #[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq, Eq, Hash)]
@@ -129,10 +276,16 @@ pub struct InputMetadata {
     pub default_username: Option<String>,
     /// The app's default password. Can also be $APP_SEED for a random password
     pub default_password: Option<String>,
+    /// True if the app needs to be set up in its UI before it can be used, like creating an admin account
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub setup_required: bool,
     #[serde(default = "bool::default")]
     #[serde(skip_serializing_if = "is_false")]
     /// True if the app only works over Tor
     pub tor_only: bool,
+    /// Expose the app's UI over I2P
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub i2p: bool,
     /// A list of containers to update automatically (still validated by the Citadel team)
     #[serde(skip_serializing_if = "Option::is_none")]
     pub update_containers: Option<Vec<String>>,
@@ -143,6 +296,59 @@ pub struct InputMetadata {
     pub version_control: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub release_notes: Option<BTreeMap<String, String>>,
+    /// The container serving the app's main UI.
+    /// If this is not set, it is determined from the container names.
+    #[serde(skip_serializing_if = "Option::is_none", alias = "main_container")]
+    pub main_container: Option<String>,
+    /// Additional web UIs of the app, each of them gets its own port
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub entries: Vec<UiEntry>,
+    /// Where the routes of several apps share a host, the ones with a higher priority come first
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub route_priority: i32,
+    /// Directories other apps can mount, share name -> definition
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub shares: BTreeMap<String, ShareDefinition>,
+    /// Directories for unix sockets, which containers of this app and other apps can mount
+    /// to talk to each other without a port
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub sockets: BTreeSet<String>,
+    /// A directory of the app with static files, which are served under /apps/<app id>/assets/.
+    /// A relative path whose components only contain letters, numbers, '-', '_' and '.' and don't start with '.'
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub static_assets: Option<String>,
+    /// The disk space the app's data needs, in MiB, checked before installing the app
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub required_storage: Option<u64>,
+    /// The RAM the app needs, in MiB. The app is marked as unsupported on nodes with less.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub required_memory: Option<u64>,
+    /// Hardware the app needs. The app is marked as unsupported on nodes without it.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub required_hardware: Vec<HardwareCapability>,
+    /// Apps this app may connect to when apps are isolated from each other.
+    /// The containers of two apps share a network if both of them list each other.
+    #[serde(
+        default,
+        skip_serializing_if = "Vec::is_empty",
+        alias = "allow_connections_to"
+    )]
+    pub allow_connections_to: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct UiEntry {
+    /// A unique name for this UI, shown on the dashboard
+    pub name: String,
+    /// The container serving this UI
+    pub container: String,
+    /// The port this UI listens on inside the container
+    pub port: u16,
+    /// The path the "Open" link on the dashboard should lead to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq, Eq)]
@@ -152,6 +358,100 @@ pub struct AppYml {
     pub citadel_version: u8,
     pub metadata: InputMetadata,
     pub services: HashMap<String, Container>,
+    /// Named volumes, which can be mounted using the `volumes` mount
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub volumes: BTreeMap<String, NamedVolume>,
+    /// Values for other apps, like API URLs or tokens, name -> value.
+    /// Apps with a permission for this app get them as APP_<APP ID>_EXPORT_<NAME>.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub exports: BTreeMap<String, String>,
+    /// Settings the operator can change, env var -> rule.
+    /// Their values are set on the dashboard, in the .env file or in bundle settings.
+    /// The app fails to convert if a value breaks its rule.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub settings: BTreeMap<String, SettingRule>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct SettingRule {
+    /// What the setting is for, shown in errors about it and on the dashboard
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// The kind of value, which decides the field the dashboard shows
+    #[serde(
+        rename = "type",
+        default,
+        skip_serializing_if = "SettingType::is_string"
+    )]
+    pub kind: SettingType,
+    /// The value used while the operator has not set one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default: Option<String>,
+    /// Fail if the setting is not set
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub required: bool,
+    /// A regular expression the whole value has to match
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pattern: Option<String>,
+    /// The values the setting can have
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub values: Vec<String>,
+    /// The smallest number the setting can be
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min: Option<i64>,
+    /// The largest number the setting can be
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum SettingType {
+    #[default]
+    String,
+    /// An integer or decimal number
+    Number,
+    /// true or false
+    Boolean,
+}
+
+impl SettingType {
+    fn is_string(&self) -> bool {
+        *self == SettingType::String
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum NamedVolume {
+    /// An NFS export
+    Nfs {
+        /// The address of the NFS server
+        address: String,
+        /// The exported path on the server
+        device: String,
+        /// Additional mount options, like nfsvers=4
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        options: Vec<String>,
+    },
+    /// A CIFS/SMB share
+    Cifs {
+        /// The address of the SMB server
+        address: String,
+        /// The share, in the form //server/share
+        device: String,
+        /// Additional mount options, like username=... or vers=3.0
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        options: Vec<String>,
+    },
+    /// A directory on the host
+    Local {
+        /// The absolute path of the directory
+        device: String,
+    },
 }
 
 #[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq, Eq)]
@@ -5,6 +5,7 @@ use serde_json::{Map, Value};
 use super::permissions;
 use super::types::PortMapElement;
 use crate::composegenerator::compose::types::Command;
+use crate::naming;
 use crate::utils::find_env_vars;
 use anyhow::{bail, Result};
 use hex;
@@ -18,6 +19,23 @@ pub fn derive_entropy(seed: &str, identifier: &str) -> String {
     hex::encode(result)
 }
 
+/// The URL path the static assets of an app are served under
+pub fn static_assets_path(app_id: &str) -> String {
+    format!("/apps/{app_id}/assets/")
+}
+
+/// Whether a static assets directory is a relative path inside the app's directory.
+/// Its components may only contain letters, numbers, '-', '_' and '.', and can't start with '.'.
+pub fn is_valid_static_assets_dir(dir: &str) -> bool {
+    dir.split('/').all(|component| {
+        !component.is_empty()
+            && !component.starts_with('.')
+            && component
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+    })
+}
+
 pub fn validate_cmd(app_name: &str, command: &Command, permissions: &[&String]) -> Result<()> {
     match command {
         Command::SimpleCommand(simple_command) => {
@@ -42,6 +60,15 @@ pub fn validate_cmd(app_name: &str, command: &Command, permissions: &[&String])
     Ok(())
 }
 
+/// The env var containing the directory of a share, relative to the exporting app's data dir
+pub fn share_env_var(app_id: &str, share: &str) -> String {
+    format!(
+        "{}SHARE_{}_DIR",
+        naming::env_prefix(app_id),
+        naming::env_name(share)
+    )
+}
+
 pub fn get_host_port(port_map: &[PortMapElement], internal_port: u16) -> Option<&PortMapElement> {
     return port_map
         .iter()
@@ -56,7 +83,18 @@ pub fn validate_port_map_app(
     >(Object(port_map_app.to_owned()))?)
 }
 
-pub fn get_main_container(services: &HashMap<String, super::types::Container>) -> Result<&str> {
+/// Returns the main container of an app.
+/// If `main_container` is set in the app's metadata, it is used, otherwise it is determined from the container names.
+pub fn get_main_container<'a>(
+    services: &'a HashMap<String, super::types::Container>,
+    main_container: Option<&str>,
+) -> Result<&'a str> {
+    if let Some(main_container) = main_container {
+        let Some((name, _)) = services.get_key_value(main_container) else {
+            bail!("Main container {} does not exist", main_container);
+        };
+        return Ok(name);
+    }
     if services.len() == 1 {
         return Ok(services.keys().next().unwrap());
     }
@@ -108,6 +146,27 @@ mod tests {
         assert!(result.is_ok());
     }
 
+    #[test]
+    fn validates_static_assets_dir() {
+        for dir in ["public", "web/dist", "v1.2_assets"] {
+            assert!(super::is_valid_static_assets_dir(dir), "{dir}");
+        }
+        for dir in [
+            "",
+            ".",
+            "..",
+            "./public",
+            "public/..",
+            "/srv",
+            "public/",
+            "my assets",
+            "a\"b",
+            "{env.X}",
+        ] {
+            assert!(!super::is_valid_static_assets_dir(dir), "{dir}");
+        }
+    }
+
     #[test]
     fn derive_entropy() {
         let result = super::derive_entropy("seed", "identifier");
@@ -11,6 +11,7 @@ pub mod github;
 pub mod gitlab;
 #[cfg(feature = "dev-tools")]
 pub mod hosted_git;
+pub mod naming;
 #[cfg(feature = "dev-tools")]
 pub mod updates;
 pub mod utils;
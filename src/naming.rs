@@ -0,0 +1,205 @@
+//! Names and paths the app manager derives from app IDs and container names.
+//! Tools working with converted apps should use these instead of building the names themselves.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+/// An app ID or container name as it appears in env vars, like LND_TOOLS for lnd-tools
+pub fn env_name(name: &str) -> String {
+    name.to_uppercase().replace('-', "_")
+}
+
+/// An app ID or container name as it appears in Tor and I2P names, like lnd-tools for lnd_tools
+pub fn slug(name: &str) -> String {
+    name.to_lowercase().replace('_', "-")
+}
+
+/// The identifier secrets of an app are derived from with derive_entropy, like app-lnd_tools-password.
+/// Changing it changes the secrets of existing apps.
+pub fn entropy_identifier(app_id: &str, identifier: &str) -> String {
+    format!("app-{}-{identifier}", env_name(app_id).to_lowercase())
+}
+
+/// The prefix of all env vars generated for an app, like APP_LND_
+pub fn env_prefix(app_id: &str) -> String {
+    format!("APP_{}_", env_name(app_id))
+}
+
+/// The env var and key in ips.yml with the IP of a container, like APP_LND_WEB_IP
+pub fn ip_env_var(app_id: &str, container: &str) -> String {
+    format!("{}{}_IP", env_prefix(app_id), env_name(container))
+}
+
+/// The env var with the public port of a container, like APP_LND_WEB_PORT
+pub fn port_env_var(app_id: &str, container: &str) -> String {
+    format!("{}{}_PORT", env_prefix(app_id), env_name(container))
+}
+
+/// The env var with the subdir of an app's data dir that is mounted as shared data, like APP_LND_SHARED_SUBDIR
+pub fn shared_subdir_env_var(app_id: &str) -> String {
+    format!("{}SHARED_SUBDIR", env_prefix(app_id))
+}
+
+/// The template variable with the onion address of a hidden service,
+/// None is the app's main hidden service
+pub fn hidden_service_env_var(service: Option<&str>) -> String {
+    match service {
+        Some(service) => format!("APP_HIDDEN_SERVICE_{}", env_name(service)),
+        None => "APP_HIDDEN_SERVICE".to_string(),
+    }
+}
+
+/// The name of a hidden service's dir, like app-lnd-rpc. None is the app's main hidden service.
+pub fn hidden_service_name(app_id: &str, service: Option<&str>) -> String {
+    match service {
+        Some(service) => format!("app-{}-{}", slug(app_id), slug(service)),
+        None => format!("app-{}", slug(app_id)),
+    }
+}
+
+/// The dir Tor stores a hidden service's keys and hostname in
+pub fn hidden_service_dir(citadel_root: &Path, app_id: &str, service: Option<&str>) -> PathBuf {
+    citadel_root
+        .join("tor")
+        .join("data")
+        .join(hidden_service_name(app_id, service))
+}
+
+/// The dir an app's data is stored in, APP_DATA_DIR
+pub fn data_dir(citadel_root: &Path, app_id: &str) -> PathBuf {
+    citadel_root.join("app-data").join(app_id)
+}
+
+/// The docker compose project name used for an app
+pub fn compose_project_name(app_id: &str) -> String {
+    app_id.to_string()
+}
+
+/// The name Docker Compose gives the (first) container of a service, <project>-<service>-<replica>
+pub fn container_name(app_id: &str, container: &str) -> String {
+    format!("{}-{container}-1", compose_project_name(app_id))
+}
+
+/// The names derived for a container of an app
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct ContainerNames {
+    pub container_name: String,
+    pub ip_env_var: String,
+    pub port_env_var: String,
+    /// The container's own hidden service, the main container uses the app's
+    pub hidden_service_dir: PathBuf,
+    pub hidden_service_env_var: String,
+}
+
+/// The names and paths derived for an app
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct AppNames {
+    pub app: String,
+    pub compose_project: String,
+    pub data_dir: PathBuf,
+    pub env_prefix: String,
+    pub hidden_service_dir: PathBuf,
+    pub hidden_service_env_var: String,
+    /// Container name -> its names
+    pub containers: BTreeMap<String, ContainerNames>,
+}
+
+impl AppNames {
+    pub fn new<'a>(
+        citadel_root: &Path,
+        app_id: &str,
+        containers: impl IntoIterator<Item = &'a str>,
+    ) -> Self {
+        Self {
+            app: app_id.to_string(),
+            compose_project: compose_project_name(app_id),
+            data_dir: data_dir(citadel_root, app_id),
+            env_prefix: env_prefix(app_id),
+            hidden_service_dir: hidden_service_dir(citadel_root, app_id, None),
+            hidden_service_env_var: hidden_service_env_var(None),
+            containers: containers
+                .into_iter()
+                .map(|container| {
+                    (
+                        container.to_string(),
+                        ContainerNames {
+                            container_name: container_name(app_id, container),
+                            ip_env_var: ip_env_var(app_id, container),
+                            port_env_var: port_env_var(app_id, container),
+                            hidden_service_dir: hidden_service_dir(
+                                citadel_root,
+                                app_id,
+                                Some(container),
+                            ),
+                            hidden_service_env_var: hidden_service_env_var(Some(container)),
+                        },
+                    )
+                })
+                .collect(),
+        }
+    }
+}
+
+impl std::fmt::Display for AppNames {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Compose project:        {}", self.compose_project)?;
+        writeln!(f, "Data dir:               {}", self.data_dir.display())?;
+        writeln!(f, "Env var prefix:         {}", self.env_prefix)?;
+        writeln!(
+            f,
+            "Hidden service dir:     {}",
+            self.hidden_service_dir.display()
+        )?;
+        writeln!(f, "Hidden service env var: {}", self.hidden_service_env_var)?;
+        for (name, container) in &self.containers {
+            writeln!(f, "\nContainer {name}:")?;
+            writeln!(f, "  Container name:         {}", container.container_name)?;
+            writeln!(f, "  IP env var:             {}", container.ip_env_var)?;
+            writeln!(f, "  Port env var:           {}", container.port_env_var)?;
+            writeln!(
+                f,
+                "  Hidden service dir:     {}",
+                container.hidden_service_dir.display()
+            )?;
+            writeln!(
+                f,
+                "  Hidden service env var: {}",
+                container.hidden_service_env_var
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use super::{entropy_identifier, hidden_service_dir, ip_env_var, port_env_var, AppNames};
+
+    #[test]
+    fn derives_names() {
+        assert_eq!(ip_env_var("lnd-tools", "web-ui"), "APP_LND_TOOLS_WEB_UI_IP");
+        assert_eq!(port_env_var("lnd", "main"), "APP_LND_MAIN_PORT");
+        assert_eq!(
+            entropy_identifier("lnd-tools", "password"),
+            "app-lnd_tools-password"
+        );
+        assert_eq!(
+            hidden_service_dir(Path::new("/citadel"), "lnd_tools", Some("rpc_server")),
+            Path::new("/citadel/tor/data/app-lnd-tools-rpc-server")
+        );
+
+        let names = AppNames::new(Path::new("/citadel"), "mempool", ["api"]);
+        assert_eq!(names.data_dir, Path::new("/citadel/app-data/mempool"));
+        assert_eq!(names.containers["api"].container_name, "mempool-api-1");
+        assert_eq!(
+            names.containers["api"].hidden_service_env_var,
+            "APP_HIDDEN_SERVICE_API"
+        );
+    }
+}
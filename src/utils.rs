@@ -165,3 +165,8 @@ mod test_flatten {
 pub(crate) fn is_false(b: impl std::borrow::Borrow<bool>) -> bool {
     !b.borrow()
 }
+
+#[inline]
+pub(crate) fn is_zero(n: &i32) -> bool {
+    *n == 0
+}